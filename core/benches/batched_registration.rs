@@ -0,0 +1,124 @@
+//! Compares registering downloaded files in the database one statement at a
+//! time, as each download thread does by default, with registering them in
+//! batches of one transaction, as the write-behind thread of an SFTP source
+//! does.
+//!
+//! Run with `cargo bench -p cortex-core --bench batched_registration`,
+//! optionally with the number of files as argument.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rusqlite::{params, Connection};
+
+/// Number of download threads registering files at once
+const THREADS: usize = 4;
+
+const BATCH_SIZE: usize = 100;
+
+fn open(path: &std::path::Path) -> Connection {
+    let _ = std::fs::remove_file(path);
+
+    let mut conn = Connection::open(path).unwrap();
+    cortex_core::run_migrations(&mut conn).unwrap();
+
+    conn
+}
+
+/// The statements of the registration of one downloaded file
+fn register(conn: &Connection, id: usize) {
+    let file_id: i64 = conn
+        .prepare_cached(
+            "insert into file (source, path, modified, size, hash)
+             values ('red', ?1, '2026-10-01T12:00:00+00:00', 64, 'abc')
+             on conflict(source, path) where deleted is null do update set
+               modified=excluded.modified, size=excluded.size, hash=excluded.hash
+             returning id",
+        )
+        .unwrap()
+        .query_row(params![format!("/storage/red/{id}.csv")], |row| row.get(0))
+        .unwrap();
+
+    conn.execute(
+        "update sftp_download set file_id = ?2 where id = ?1",
+        params![id as i64, file_id],
+    )
+    .unwrap();
+
+    conn.execute(
+        "update file set discovered = '2026-10-01T11:59:00+00:00' where id = ?1",
+        params![file_id],
+    )
+    .unwrap();
+}
+
+/// Each thread registers its files with a statement per write
+fn direct(conn: Arc<Mutex<Connection>>, files: usize) {
+    let handles: Vec<_> = (0..THREADS)
+        .map(|n| {
+            let conn = conn.clone();
+
+            thread::spawn(move || {
+                for id in (n..files).step_by(THREADS) {
+                    register(&conn.lock().unwrap(), id);
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+/// One thread registers all files in transactions of a batch each
+fn batched(conn: Arc<Mutex<Connection>>, files: usize) {
+    let ids: Vec<usize> = (0..files).collect();
+
+    for batch in ids.chunks(BATCH_SIZE) {
+        let mut conn = conn.lock().unwrap();
+        let transaction = conn.transaction().unwrap();
+
+        for id in batch {
+            register(&transaction, *id);
+        }
+
+        transaction.commit().unwrap();
+    }
+}
+
+fn measure(name: &str, files: usize, run: fn(Arc<Mutex<Connection>>, usize)) -> Duration {
+    let path = std::env::temp_dir().join(format!("cortex-bench-{}.db", std::process::id()));
+
+    let conn = Arc::new(Mutex::new(open(&path)));
+
+    let start = Instant::now();
+    run(conn, files);
+    let elapsed = start.elapsed();
+
+    std::fs::remove_file(&path).unwrap();
+
+    println!(
+        "{:<14} {:>8.0} files/s",
+        name,
+        files as f64 / elapsed.as_secs_f64()
+    );
+
+    elapsed
+}
+
+fn main() {
+    let files: usize = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(2000);
+
+    let direct = measure("direct", files, direct);
+    let batched = measure("batched", files, batched);
+
+    println!(
+        "speedup        {:>8.2}x",
+        direct.as_secs_f64() / batched.as_secs_f64()
+    );
+}
@@ -0,0 +1,149 @@
+//! Compares copying a file on a single thread, hashing every buffer before
+//! writing it, with `pipelined_copy`, which hashes and writes on separate
+//! threads.
+//!
+//! Run with `cargo bench -p cortex-core --bench pipelined_copy`, optionally
+//! with the size in MiB of the copied data as argument.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use cortex_core::copy::pipelined_copy;
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+const ROUNDS: usize = 3;
+
+/// Writer that hashes everything written to it
+struct HashSink(Sha256);
+
+impl Write for HashSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader of pseudo-random data, which stands in for the network
+struct Source {
+    block: Vec<u8>,
+    remaining: usize,
+}
+
+impl Source {
+    fn new(size: usize) -> Source {
+        let mut state: u64 = 1;
+
+        let block = (0..BUFFER_SIZE)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+
+        Source {
+            block,
+            remaining: size,
+        }
+    }
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Read in chunks of the size that SFTP reads return
+        let size = buf.len().min(self.remaining).min(32 * 1024);
+        let offset = self.remaining % (self.block.len() - size);
+
+        buf[..size].copy_from_slice(&self.block[offset..offset + size]);
+
+        self.remaining -= size;
+
+        Ok(size)
+    }
+}
+
+fn single_thread(source: &mut Source, file: &mut File) -> io::Result<u64> {
+    let mut hash_sink = HashSink(Sha256::new());
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut bytes_copied = 0;
+
+    loop {
+        let size = source.read(&mut buffer)?;
+
+        if size == 0 {
+            break;
+        }
+
+        hash_sink.write_all(&buffer[..size])?;
+        file.write_all(&buffer[..size])?;
+        bytes_copied += size as u64;
+    }
+
+    file.flush()?;
+
+    Ok(bytes_copied)
+}
+
+fn pipelined(source: &mut Source, file: &mut File) -> io::Result<u64> {
+    pipelined_copy(source, file, &mut HashSink(Sha256::new()), BUFFER_SIZE)
+}
+
+fn measure(
+    name: &str,
+    size: usize,
+    copy: fn(&mut Source, &mut File) -> io::Result<u64>,
+) -> Duration {
+    let path = std::env::temp_dir().join(format!("cortex-bench-{}", std::process::id()));
+
+    let best = (0..ROUNDS)
+        .map(|_| {
+            let mut source = Source::new(size);
+            let mut file = File::create(&path).unwrap();
+
+            let start = Instant::now();
+            let bytes_copied = copy(&mut source, &mut file).unwrap();
+            let elapsed = start.elapsed();
+
+            assert_eq!(bytes_copied, size as u64);
+
+            elapsed
+        })
+        .min()
+        .unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+
+    println!(
+        "{:<14} {:>8.1} MB/s",
+        name,
+        size as f64 / best.as_secs_f64() / 1_000_000.0
+    );
+
+    best
+}
+
+fn main() {
+    let size_mib: usize = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(256);
+
+    let size = size_mib * 1024 * 1024;
+
+    let single_thread = measure("single thread", size, single_thread);
+    let pipelined = measure("pipelined", size, pipelined);
+
+    println!(
+        "speedup        {:>8.2}x",
+        single_thread.as_secs_f64() / pipelined.as_secs_f64()
+    );
+}
@@ -0,0 +1,5 @@
+fn main() {
+    // The migrations are embedded at compile time, so new migration files
+    // must trigger a rebuild
+    println!("cargo:rerun-if-changed=migrations");
+}
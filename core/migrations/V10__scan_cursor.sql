@@ -0,0 +1,10 @@
+-- Position of the incremental scans of SFTP sources
+CREATE TABLE IF NOT EXISTS scan_cursor (
+  source TEXT PRIMARY KEY,
+  -- Newest modification time of a matching file in the last completed scan,
+  -- in seconds since the epoch
+  max_mtime INTEGER NOT NULL,
+  -- Number of incremental scans since the last full scan
+  incremental_scans INTEGER NOT NULL DEFAULT 0,
+  modified TEXT NOT NULL DEFAULT (datetime('now'))
+);
@@ -0,0 +1,8 @@
+-- Files removed from internal storage by the retention cleanup keep their row
+-- for auditing, marked with the time of removal
+ALTER TABLE file ADD COLUMN deleted TEXT;
+
+-- A file that is ingested again after its removal gets a new row
+DROP INDEX IF EXISTS file_index;
+
+CREATE UNIQUE INDEX IF NOT EXISTS file_index ON file (source, path) WHERE deleted IS NULL;
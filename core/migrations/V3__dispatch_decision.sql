@@ -0,0 +1,12 @@
+-- Decisions on dispatching files to the targets connected to their source,
+-- recorded when auditing is enabled
+CREATE TABLE IF NOT EXISTS dispatch_decision (
+  file_id INTEGER NOT NULL,
+  source TEXT NOT NULL,
+  target TEXT NOT NULL,
+  filter_matched INTEGER NOT NULL,
+  timestamp TEXT NOT NULL,
+  FOREIGN KEY (file_id) REFERENCES file(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS dispatch_decision_file_index ON dispatch_decision (file_id);
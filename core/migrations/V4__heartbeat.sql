@@ -0,0 +1,9 @@
+-- When each component of the dispatchers and scanners last reported that it
+-- is alive, per host
+CREATE TABLE IF NOT EXISTS heartbeat (
+  component TEXT NOT NULL,
+  instance TEXT NOT NULL,
+  last_seen TEXT NOT NULL,
+  details TEXT,
+  PRIMARY KEY (component, instance)
+);
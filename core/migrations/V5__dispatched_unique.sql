@@ -0,0 +1,8 @@
+-- A file is dispatched to a target at most once, so that redispatching it
+-- after a crash does not register it again
+DELETE FROM dispatched
+WHERE rowid NOT IN (
+  SELECT min(rowid) FROM dispatched GROUP BY file_id, target
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS dispatched_file_target_index ON dispatched (file_id, target);
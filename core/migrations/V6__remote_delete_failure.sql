@@ -0,0 +1,10 @@
+-- Remote files of SFTP sources that could not be removed after they were
+-- downloaded, for retrying the removal
+CREATE TABLE IF NOT EXISTS remote_delete_failure (
+  source TEXT NOT NULL,
+  path TEXT NOT NULL,
+  timestamp TEXT NOT NULL DEFAULT (datetime('now')),
+  attempts INTEGER NOT NULL DEFAULT 1,
+  error TEXT NOT NULL,
+  PRIMARY KEY (source, path)
+);
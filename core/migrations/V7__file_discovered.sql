@@ -0,0 +1,3 @@
+-- When files downloaded from SFTP sources were discovered by the scanner, for
+-- measuring the latency from discovery to delivery
+ALTER TABLE file ADD COLUMN discovered TEXT;
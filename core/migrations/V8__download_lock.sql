@@ -0,0 +1,7 @@
+-- Downloads in progress per remote file, shared by the dispatcher instances
+-- on the database when distributed locks are enabled
+CREATE TABLE IF NOT EXISTS download_lock (
+  key INTEGER PRIMARY KEY,
+  holder TEXT NOT NULL,
+  acquired TEXT NOT NULL DEFAULT (datetime('now'))
+);
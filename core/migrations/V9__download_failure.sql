@@ -0,0 +1,10 @@
+-- Downloads of SFTP sources that failed after their retries, for reporting
+CREATE TABLE IF NOT EXISTS download_failure (
+  timestamp TEXT NOT NULL DEFAULT (datetime('now')),
+  source TEXT NOT NULL,
+  path TEXT NOT NULL,
+  code TEXT NOT NULL,
+  error TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS download_failure_timestamp_index ON download_failure (timestamp);
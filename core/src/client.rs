@@ -0,0 +1,153 @@
+//! Publishing of download commands to the command queues of the dispatcher
+
+use std::collections::HashMap;
+
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties};
+use log::{debug, warn};
+
+use crate::secret::Secret;
+use crate::{
+    command_payload, sftp_source_routing_key, HttpDownload, SftpDownload, DEFAULT_COMMAND_EXCHANGE,
+};
+
+/// Routing key of HTTP download commands by default
+pub const HTTP_DOWNLOAD_ROUTING_KEY: &str = "http_download";
+
+/// Where download commands are published
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandRoute {
+    pub exchange: String,
+    pub routing_key: String,
+}
+
+impl CommandRoute {
+    /// Default route of the download commands of an SFTP source
+    pub fn sftp_source(sftp_source: &str) -> CommandRoute {
+        CommandRoute {
+            exchange: DEFAULT_COMMAND_EXCHANGE.to_string(),
+            routing_key: sftp_source_routing_key(sftp_source),
+        }
+    }
+}
+
+/// Publishes download commands with publisher confirms
+///
+/// A command is published once more over a new connection when publishing
+/// fails, e.g. after the broker closed the connection.
+pub struct CommandPublisher {
+    address: Secret,
+    connection: Option<(Connection, Channel)>,
+    routes: HashMap<String, CommandRoute>,
+    http_route: CommandRoute,
+}
+
+impl CommandPublisher {
+    pub async fn connect(address: Secret) -> Result<CommandPublisher, String> {
+        let mut publisher = CommandPublisher {
+            address,
+            connection: None,
+            routes: HashMap::new(),
+            http_route: CommandRoute {
+                exchange: DEFAULT_COMMAND_EXCHANGE.to_string(),
+                routing_key: HTTP_DOWNLOAD_ROUTING_KEY.to_string(),
+            },
+        };
+
+        publisher.channel().await?;
+
+        Ok(publisher)
+    }
+
+    /// Publish the commands of an SFTP source with another route than the
+    /// default
+    pub fn with_route(mut self, sftp_source: &str, route: CommandRoute) -> CommandPublisher {
+        self.routes.insert(sftp_source.to_string(), route);
+        self
+    }
+
+    /// Publish HTTP download commands with another route than the default
+    pub fn with_http_route(mut self, route: CommandRoute) -> CommandPublisher {
+        self.http_route = route;
+        self
+    }
+
+    pub async fn publish_sftp_download(&mut self, command: &SftpDownload) -> Result<(), String> {
+        let route = self
+            .routes
+            .get(&command.sftp_source)
+            .cloned()
+            .unwrap_or_else(|| CommandRoute::sftp_source(&command.sftp_source));
+
+        self.publish(&route, &command_payload(command)).await
+    }
+
+    pub async fn publish_http_download(&mut self, command: &HttpDownload) -> Result<(), String> {
+        let route = self.http_route.clone();
+
+        self.publish(&route, &command_payload(command)).await
+    }
+
+    async fn publish(&mut self, route: &CommandRoute, payload: &[u8]) -> Result<(), String> {
+        if let Err(e) = self.try_publish(route, payload).await {
+            warn!("{e}, reconnecting");
+
+            self.connection = None;
+
+            self.try_publish(route, payload).await?;
+        }
+
+        debug!(
+            "Published command with routing key '{}'",
+            &route.routing_key
+        );
+
+        Ok(())
+    }
+
+    async fn try_publish(&mut self, route: &CommandRoute, payload: &[u8]) -> Result<(), String> {
+        let channel = self.channel().await?;
+
+        let confirmation = channel
+            .basic_publish(
+                route.exchange.as_str().into(),
+                route.routing_key.as_str().into(),
+                BasicPublishOptions::default(),
+                payload,
+                BasicProperties::default().with_content_type("application/json".into()),
+            )
+            .await
+            .map_err(|e| format!("Error publishing command: {e}"))?
+            .await
+            .map_err(|e| format!("Error publishing command: {e}"))?;
+
+        if confirmation.is_nack() {
+            return Err("Command was not accepted by the broker".to_string());
+        }
+
+        Ok(())
+    }
+
+    async fn channel(&mut self) -> Result<&Channel, String> {
+        if self.connection.is_none() {
+            let connection =
+                Connection::connect(self.address.expose(), ConnectionProperties::default())
+                    .await
+                    .map_err(|e| format!("Error connecting to AMQP server: {e}"))?;
+
+            let channel = connection
+                .create_channel()
+                .await
+                .map_err(|e| format!("Error creating AMQP channel: {e}"))?;
+
+            channel
+                .confirm_select(ConfirmSelectOptions::default())
+                .await
+                .map_err(|e| format!("Error enabling publisher confirms: {e}"))?;
+
+            self.connection = Some((connection, channel));
+        }
+
+        Ok(&self.connection.as_ref().unwrap().1)
+    }
+}
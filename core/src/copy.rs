@@ -0,0 +1,154 @@
+use std::io::{self, Read, Write};
+use std::panic;
+use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
+use std::thread;
+
+/// Number of buffers that can wait for each of the writing threads, so that
+/// reading continues while the previous buffers are written
+const PIPELINE_DEPTH: usize = 2;
+
+/// Copy everything from `reader` to both `writer` and `tee`, such as a hasher,
+/// and return the number of bytes copied
+///
+/// Reading, writing and writing to the tee each happen on their own thread,
+/// in buffers of `buffer_size` bytes, so that a slow hasher does not hold up
+/// the disk and the network.
+pub fn pipelined_copy<R, W, T>(
+    reader: &mut R,
+    writer: &mut W,
+    tee: &mut T,
+    buffer_size: usize,
+) -> io::Result<u64>
+where
+    R: Read,
+    W: Write + Send,
+    T: Write + Send,
+{
+    let buffer_size = buffer_size.max(1);
+
+    thread::scope(|scope| {
+        let (write_sender, write_receiver) = sync_channel::<Arc<Vec<u8>>>(PIPELINE_DEPTH);
+        let (tee_sender, tee_receiver) = sync_channel::<Arc<Vec<u8>>>(PIPELINE_DEPTH);
+
+        let write_thread = scope.spawn(move || -> io::Result<()> {
+            for buffer in write_receiver {
+                writer.write_all(&buffer)?;
+            }
+
+            writer.flush()
+        });
+
+        let tee_thread = scope.spawn(move || -> io::Result<()> {
+            for buffer in tee_receiver {
+                tee.write_all(&buffer)?;
+            }
+
+            tee.flush()
+        });
+
+        let mut bytes_copied: u64 = 0;
+
+        let read_result = loop {
+            let mut buffer = vec![0; buffer_size];
+
+            let size = match fill(reader, &mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(size) => size,
+                Err(e) => break Err(e),
+            };
+
+            buffer.truncate(size);
+
+            let buffer = Arc::new(buffer);
+
+            // Sending only fails when a writing thread stopped on an error,
+            // which is returned below
+            if write_sender.send(buffer.clone()).is_err() || tee_sender.send(buffer).is_err() {
+                break Ok(());
+            }
+
+            bytes_copied += size as u64;
+        };
+
+        drop(write_sender);
+        drop(tee_sender);
+
+        let write_result = write_thread
+            .join()
+            .unwrap_or_else(|e| panic::resume_unwind(e));
+        let tee_result = tee_thread
+            .join()
+            .unwrap_or_else(|e| panic::resume_unwind(e));
+
+        read_result?;
+        write_result?;
+        tee_result?;
+
+        Ok(bytes_copied)
+    })
+}
+
+/// Read until the buffer is full or the reader is at its end, returning the
+/// number of bytes read
+fn fill<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(size) => filled += size,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writer that fails after accepting a number of bytes
+    struct FailingWriter(usize);
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 < buf.len() {
+                return Err(io::Error::other("disk full"));
+            }
+
+            self.0 -= buf.len();
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn copies_to_writer_and_tee() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut written = Vec::new();
+        let mut teed = Vec::new();
+
+        let bytes_copied =
+            pipelined_copy(&mut data.as_slice(), &mut written, &mut teed, 64).unwrap();
+
+        let failed = pipelined_copy(
+            &mut data.as_slice(),
+            &mut FailingWriter(1000),
+            &mut Vec::new(),
+            64,
+        );
+
+        assert_eq!(bytes_copied, data.len() as u64);
+        assert_eq!(written, data);
+        assert_eq!(teed, data);
+        assert_eq!(failed.unwrap_err().to_string(), "disk full");
+    }
+}
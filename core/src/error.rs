@@ -18,4 +18,17 @@ pub enum DispatcherError {
     DatabaseError(String),
     #[error("Other dispatcher error: {0}")]
     OtherError(String),
+    #[error("Insufficient space: {0}")]
+    InsufficientSpace(String),
+    #[error("Unsafe path: {0}")]
+    UnsafePath(String),
+}
+
+/// Reasons why a command message from the command queue cannot be used
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum CommandParseError {
+    #[error("Unknown command version {version}, supported up to {supported}")]
+    UnknownVersion { version: u32, supported: u32 },
+    #[error("Malformed command: {0}")]
+    Malformed(String),
 }
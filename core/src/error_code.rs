@@ -0,0 +1,200 @@
+//! Codes of the classes of failures, which are included in log lines and in
+//! the `code` label of the `errors_total` metric
+//!
+//! The codes are stable, so that operators can look them up and alert on
+//! them. New classes get a new code, and codes of removed classes are not
+//! reused.
+
+use std::fmt;
+
+use crate::error::DispatcherError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    SftpConnect,
+    ConnectionInterrupted,
+    Download,
+    TargetHardlink,
+    TargetCopy,
+    Storage,
+    TargetSymlink,
+    UnsafePath,
+    InsufficientSpace,
+    File,
+    NoSuchFile,
+    Corruption,
+    LocalChannelSend,
+    DispatchSend,
+    DirectoryWatch,
+    Disconnected,
+    DownloadChannelClosed,
+    DirectoryRecursion,
+    Persistence,
+    Database,
+    Notify,
+    EventPublish,
+    CommandPublish,
+    ScanNotify,
+    Scan,
+    Other,
+}
+
+impl ErrorCode {
+    /// All codes, in the order of their code
+    pub const ALL: [ErrorCode; 26] = [
+        ErrorCode::SftpConnect,
+        ErrorCode::ConnectionInterrupted,
+        ErrorCode::Download,
+        ErrorCode::TargetHardlink,
+        ErrorCode::TargetCopy,
+        ErrorCode::Storage,
+        ErrorCode::TargetSymlink,
+        ErrorCode::UnsafePath,
+        ErrorCode::InsufficientSpace,
+        ErrorCode::File,
+        ErrorCode::NoSuchFile,
+        ErrorCode::Corruption,
+        ErrorCode::LocalChannelSend,
+        ErrorCode::DispatchSend,
+        ErrorCode::DirectoryWatch,
+        ErrorCode::Disconnected,
+        ErrorCode::DownloadChannelClosed,
+        ErrorCode::DirectoryRecursion,
+        ErrorCode::Persistence,
+        ErrorCode::Database,
+        ErrorCode::Notify,
+        ErrorCode::EventPublish,
+        ErrorCode::CommandPublish,
+        ErrorCode::ScanNotify,
+        ErrorCode::Scan,
+        ErrorCode::Other,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorCode::SftpConnect => "E01001",
+            ErrorCode::ConnectionInterrupted => "E01002",
+            ErrorCode::Download => "E01003",
+            ErrorCode::TargetHardlink => "E01004",
+            ErrorCode::TargetCopy => "E01005",
+            ErrorCode::Storage => "E01006",
+            ErrorCode::TargetSymlink => "E01007",
+            ErrorCode::UnsafePath => "E01008",
+            ErrorCode::InsufficientSpace => "E01009",
+            ErrorCode::File => "E01010",
+            ErrorCode::NoSuchFile => "E01011",
+            ErrorCode::Corruption => "E01012",
+            ErrorCode::LocalChannelSend => "E02001",
+            ErrorCode::DispatchSend => "E02002",
+            ErrorCode::DirectoryWatch => "E02003",
+            ErrorCode::Disconnected => "E02004",
+            ErrorCode::DownloadChannelClosed => "E02005",
+            ErrorCode::DirectoryRecursion => "E02011",
+            ErrorCode::Persistence => "E03001",
+            ErrorCode::Database => "E03002",
+            ErrorCode::Notify => "E04001",
+            ErrorCode::EventPublish => "E04002",
+            ErrorCode::CommandPublish => "E04003",
+            ErrorCode::ScanNotify => "E04004",
+            ErrorCode::Scan => "E05001",
+            ErrorCode::Other => "E09999",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorCode::SftpConnect => "Could not connect to or log in on an SFTP server",
+            ErrorCode::ConnectionInterrupted => "SFTP connection was lost during a download",
+            ErrorCode::Download => "Could not download a file from an SFTP source",
+            ErrorCode::TargetHardlink => "Could not hardlink a file into a directory target",
+            ErrorCode::TargetCopy => "Could not copy a file into a directory target",
+            ErrorCode::Storage => "Could not store a file in internal storage",
+            ErrorCode::TargetSymlink => "Could not symlink a file into a directory target",
+            ErrorCode::UnsafePath => {
+                "Path would be placed outside the directory of its source or target"
+            }
+            ErrorCode::InsufficientSpace => "Not enough space left in internal storage",
+            ErrorCode::File => "Could not read or write a local file",
+            ErrorCode::NoSuchFile => "File no longer exists on the SFTP server",
+            ErrorCode::Corruption => {
+                "File in internal storage no longer matches its registered hash or size"
+            }
+            ErrorCode::LocalChannelSend => "Could not send a file event of a directory source",
+            ErrorCode::DispatchSend => "Could not send a file event to a target",
+            ErrorCode::DirectoryWatch => "Could not watch a directory of a directory source",
+            ErrorCode::Disconnected => "Channel between components of the dispatcher was closed",
+            ErrorCode::DownloadChannelClosed => {
+                "Channel of download commands of an SFTP source was closed"
+            }
+            ErrorCode::DirectoryRecursion => {
+                "Could not walk the subdirectories of a directory source"
+            }
+            ErrorCode::Persistence => "Could not register a file or download in the database",
+            ErrorCode::Database => "Could not open or query the database",
+            ErrorCode::Notify => "Could not notify about a file placed in a target",
+            ErrorCode::EventPublish => "Could not publish a file event of an event publisher",
+            ErrorCode::CommandPublish => "Could not publish a download command of the scanner",
+            ErrorCode::ScanNotify => "Could not publish the scan summary of the scanner",
+            ErrorCode::Scan => "Could not scan an SFTP source",
+            ErrorCode::Other => "Other error",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Message with its code in front, in the form used in log lines
+pub fn tagged(code: ErrorCode, message: impl fmt::Display) -> String {
+    format!("[{code}] {message}")
+}
+
+impl DispatcherError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            DispatcherError::ConnectionError(_) => ErrorCode::SftpConnect,
+            DispatcherError::DisconnectedError(_) => ErrorCode::Disconnected,
+            DispatcherError::NoSuchFile => ErrorCode::NoSuchFile,
+            DispatcherError::ConnectionInterrupted(_) => ErrorCode::ConnectionInterrupted,
+            DispatcherError::PersistenceError(_) => ErrorCode::Persistence,
+            DispatcherError::FileError(_) => ErrorCode::File,
+            DispatcherError::DatabaseError(_) => ErrorCode::Database,
+            DispatcherError::OtherError(_) => ErrorCode::Other,
+            DispatcherError::InsufficientSpace(_) => ErrorCode::InsufficientSpace,
+            DispatcherError::UnsafePath(_) => ErrorCode::UnsafePath,
+        }
+    }
+}
+
+/// Log an error with its code in front
+#[macro_export]
+macro_rules! coded_error {
+    ($code:expr, $($arg:tt)+) => {
+        ::log::error!("{}", $crate::error_code::tagged($code, format_args!($($arg)+)))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn codes_are_unique_and_ordered() {
+        let codes: Vec<&str> = ErrorCode::ALL.iter().map(|code| code.code()).collect();
+
+        let mut sorted = codes.clone();
+        sorted.sort();
+
+        assert_eq!(codes, sorted);
+        assert_eq!(codes.iter().collect::<HashSet<_>>().len(), codes.len());
+        assert_eq!(
+            tagged(ErrorCode::Download, "Error downloading 'a.csv'"),
+            "[E01003] Error downloading 'a.csv'"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Filters that select files by their attributes, evaluated the same way on
+//! files on disk and on file events.
+
+use std::path::Path;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// A file that a filter is evaluated on
+pub trait Filterable {
+    fn path(&self) -> &Path;
+
+    /// Size in bytes, None when it cannot be determined
+    fn size(&self) -> Option<u64>;
+}
+
+/// A file on disk, of which the size is read when a filter needs it
+impl Filterable for Path {
+    fn path(&self) -> &Path {
+        self
+    }
+
+    fn size(&self) -> Option<u64> {
+        std::fs::metadata(self).ok().map(|metadata| metadata.len())
+    }
+}
+
+/// Matches files with a name matching the regular expression
+///
+/// Names that are not valid UTF-8 are matched with the invalid bytes replaced
+/// by U+FFFD, so that e.g. `^.*\.csv$` matches a Latin-1 encoded name.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegexFilter {
+    #[serde(with = "serde_regex")]
+    pub pattern: Regex,
+}
+
+impl RegexFilter {
+    fn matches<F: Filterable + ?Sized>(&self, file: &F) -> bool {
+        file.path()
+            .file_name()
+            .is_some_and(|file_name| self.pattern.is_match(&file_name.to_string_lossy()))
+    }
+}
+
+/// Matches files with a size in bytes within the bounds
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SizeFilter {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+impl SizeFilter {
+    fn matches<F: Filterable + ?Sized>(&self, file: &F) -> bool {
+        file.size().is_some_and(|size| {
+            self.min_bytes.is_none_or(|min_bytes| size >= min_bytes)
+                && self.max_bytes.is_none_or(|max_bytes| size <= max_bytes)
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Filter {
+    Regex(RegexFilter),
+    Size(SizeFilter),
+    All,
+}
+
+impl Filter {
+    pub fn matches<F: Filterable + ?Sized>(&self, file: &F) -> bool {
+        match self {
+            Filter::Regex(r) => r.matches(file),
+            Filter::Size(s) => s.matches(file),
+            Filter::All => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    /// A file as it is known from an event, without reading from disk
+    struct Event {
+        path: PathBuf,
+        size: u64,
+    }
+
+    impl Filterable for Event {
+        fn path(&self) -> &Path {
+            &self.path
+        }
+
+        fn size(&self) -> Option<u64> {
+            Some(self.size)
+        }
+    }
+
+    #[test]
+    fn files_on_disk_and_events_match_alike() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-core-filter-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let files: Vec<(PathBuf, u64)> = [("a.csv", 5), ("b.csv", 50), ("c.xml", 50)]
+            .into_iter()
+            .map(|(name, size)| {
+                let path = directory.join(name);
+                std::fs::write(&path, vec![b'x'; size]).unwrap();
+                (path, size as u64)
+            })
+            .collect();
+
+        let filters: Vec<Filter> = [
+            r#"{"Regex": {"pattern": "^.*\\.csv$"}}"#,
+            r#"{"Size": {"min_bytes": 10}}"#,
+            r#"{"Size": {"max_bytes": 10}}"#,
+            r#""All""#,
+        ]
+        .iter()
+        .map(|filter| serde_json::from_str(filter).unwrap())
+        .collect();
+
+        let results: Vec<(bool, bool)> = filters
+            .iter()
+            .flat_map(|filter| {
+                files.iter().map(move |(path, size)| {
+                    let event = Event {
+                        path: path.clone(),
+                        size: *size,
+                    };
+
+                    (filter.matches(path.as_path()), filter.matches(&event))
+                })
+            })
+            .collect();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let expected = [
+            true, true, false, // Regex
+            false, true, true, // min_bytes
+            true, false, false, // max_bytes
+            true, true, true, // All
+        ];
+
+        for (index, (on_disk, event)) in results.into_iter().enumerate() {
+            assert_eq!(on_disk, expected[index], "file on disk, case {index}");
+            assert_eq!(event, expected[index], "event, case {index}");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn names_that_are_not_utf8_match_lossily() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let filter: Filter =
+            serde_json::from_str(r#"{"Regex": {"pattern": "^r.sum.\\.csv$"}}"#).unwrap();
+
+        // "résumé.csv" in Latin-1
+        let path = Path::new("/data").join(OsStr::from_bytes(b"r\xe9sum\xe9.csv"));
+
+        let event = Event { path, size: 1 };
+
+        assert!(filter.matches(&event));
+    }
+
+    #[test]
+    fn missing_file_does_not_match_size() {
+        let filter = Filter::Size(SizeFilter {
+            min_bytes: None,
+            max_bytes: None,
+        });
+
+        assert!(!filter.matches(Path::new("/nonexistent/cortex/a.csv")));
+    }
+}
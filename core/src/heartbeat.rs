@@ -0,0 +1,100 @@
+//! Heartbeats of components, for monitoring whether they are alive from the
+//! database
+
+use std::time::{Duration, Instant};
+
+use log::warn;
+use rusqlite::{params, Connection};
+
+/// Name of the instance of a component, which is the host name
+pub fn instance_name() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Set the last time the component was seen on the instance to now, with
+/// details of its progress as JSON
+pub fn upsert_heartbeat(
+    conn: &Connection,
+    component: &str,
+    instance: &str,
+    details: &serde_json::Value,
+) -> Result<(), String> {
+    conn.execute(
+        "insert into heartbeat (component, instance, last_seen, details)
+         values (?1, ?2, datetime('now'), ?3)
+         on conflict(component, instance) do update set
+           last_seen = excluded.last_seen, details = excluded.details",
+        params![component, instance, details.to_string()],
+    )
+    .map(|_| ())
+    .map_err(|e| format!("Error updating heartbeat of {component}: {e}"))
+}
+
+/// Heartbeat of a component in a loop, which is written at most once per
+/// interval
+pub struct Heartbeat {
+    component: String,
+    instance: String,
+    interval: Duration,
+    last_beat: Option<Instant>,
+}
+
+impl Heartbeat {
+    pub fn new(component: &str, interval: Duration) -> Heartbeat {
+        Heartbeat {
+            component: component.to_string(),
+            instance: instance_name(),
+            interval,
+            last_beat: None,
+        }
+    }
+
+    /// Write the heartbeat when the interval has passed since the last one
+    ///
+    /// Failures are only logged, so that a briefly unavailable database does
+    /// not stop the component.
+    pub fn beat(&mut self, conn: &Connection, details: &serde_json::Value) {
+        if self
+            .last_beat
+            .is_some_and(|last_beat| last_beat.elapsed() < self.interval)
+        {
+            return;
+        }
+
+        self.last_beat = Some(Instant::now());
+
+        if let Err(e) = upsert_heartbeat(conn, &self.component, &self.instance, details) {
+            warn!("{e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeat_is_written_once_per_interval() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::run_migrations(&mut conn).unwrap();
+
+        let mut heartbeat = Heartbeat::new("sftp_scanner:red", Duration::from_secs(60));
+
+        heartbeat.beat(&conn, &serde_json::json!({"scans": 1}));
+        heartbeat.beat(&conn, &serde_json::json!({"scans": 2}));
+
+        let (count, details): (i64, String) = conn
+            .query_row("select count(*), max(details) from heartbeat", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(details, r#"{"scans":1}"#);
+    }
+}
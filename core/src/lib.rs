@@ -1,45 +1,190 @@
 use std::fmt;
+use std::path::{Component, Path};
 use std::thread;
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use refinery::embed_migrations;
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
 
 use chrono::prelude::*;
 
 use log::{error, info};
 
+#[cfg(feature = "amqp")]
+pub mod client;
+pub mod copy;
 pub mod error;
+pub mod error_code;
+pub mod filter;
+pub mod heartbeat;
+pub mod log_throttle;
+#[cfg(feature = "openssh")]
+pub mod openssh_fs;
+pub mod remote_fs;
+pub mod secret;
+pub mod settings;
 pub mod sftp_connection;
 
+use error::CommandParseError;
+
 embed_migrations!("migrations");
 
-pub fn run_migrations(conn: &mut Connection) -> Result<(), String> {
+/// Run the Cortex migrations and return the names of the migrations that were
+/// applied, which is empty when the schema was already up to date.
+pub fn run_migrations(conn: &mut Connection) -> Result<Vec<String>, String> {
     migrations::runner()
         .run(conn)
-        .map(|_| ())
+        .map(|report| {
+            report
+                .applied_migrations()
+                .iter()
+                .map(|m| m.to_string())
+                .collect()
+        })
         .map_err(|e| format!("Error running Cortex migrations: {e}"))
 }
 
+/// Return the name and SQL of the migrations that have not been applied to the
+/// database yet, without modifying the database.
+pub fn pending_migrations(conn: &Connection) -> Result<Vec<(String, String)>, String> {
+    let history_exists = conn
+        .query_row(
+            "select name from sqlite_master where type = 'table' and name = 'refinery_schema_history'",
+            [],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()
+        .map_err(|e| format!("Error querying migration history: {e}"))?
+        .is_some();
+
+    let applied_versions: Vec<i64> = if history_exists {
+        let mut stmt = conn
+            .prepare("select version from refinery_schema_history")
+            .map_err(|e| format!("Error querying migration history: {e}"))?;
+
+        let rows = stmt
+            .query_map([], |row| row.get::<_, i64>(0))
+            .map_err(|e| format!("Error querying migration history: {e}"))?;
+
+        rows.collect::<Result<Vec<i64>, rusqlite::Error>>()
+            .map_err(|e| format!("Error reading migration history: {e}"))?
+    } else {
+        Vec::new()
+    };
+
+    Ok(migrations::runner()
+        .get_migrations()
+        .iter()
+        .filter(|m| !applied_versions.contains(&i64::from(m.version())))
+        .map(|m| (m.to_string(), m.sql().unwrap_or_default().to_string()))
+        .collect())
+}
+
+/// Version of the command messages that this release produces and understands
+pub const COMMAND_VERSION: u32 = 1;
+
+/// Exchange to which download commands are published by default
+pub const DEFAULT_COMMAND_EXCHANGE: &str = "amq.direct";
+
+/// Routing key of the download commands of an SFTP source by default, which
+/// is also the name of the queue the dispatcher consumes them from
+pub fn sftp_source_routing_key(sftp_source: &str) -> String {
+    format!("source.{sftp_source}")
+}
+
+/// Messages without a version field predate versioning
+fn default_command_version() -> u32 {
+    1
+}
+
 /// The set of commands that can be sent over the command queue
-#[derive(Debug, Deserialize, Clone, Serialize)]
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
 pub struct SftpDownload {
+    #[serde(default = "default_command_version")]
+    pub version: u32,
     pub id: i64,
     pub created: DateTime<Utc>,
     pub size: Option<u64>,
     pub sftp_source: String,
     pub path: String,
     pub remove: bool,
+    /// Directory under the storage directory of the source to place the file
+    /// in, instead of the directory of the remote path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_subpath: Option<String>,
+}
+
+impl SftpDownload {
+    /// The local subpath, checked to stay inside the storage directory of
+    /// the source
+    pub fn local_subpath(&self) -> Result<Option<&Path>, String> {
+        let Some(local_subpath) = &self.local_subpath else {
+            return Ok(None);
+        };
+
+        check_local_subpath(local_subpath)?;
+
+        Ok(Some(Path::new(local_subpath)))
+    }
+}
+
+/// Check that a local subpath is relative and does not contain `..`
+pub fn check_local_subpath(local_subpath: &str) -> Result<(), String> {
+    let safe = Path::new(local_subpath)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+    if safe {
+        Ok(())
+    } else {
+        Err(format!(
+            "local_subpath '{local_subpath}' is not a relative path without '..'"
+        ))
+    }
 }
 
-#[derive(Debug, Deserialize, Clone, Serialize)]
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
 pub struct HttpDownload {
+    #[serde(default = "default_command_version")]
+    pub version: u32,
     pub created: DateTime<Utc>,
     pub size: Option<u64>,
     pub url: String,
 }
 
+#[derive(Deserialize)]
+struct CommandVersion {
+    #[serde(default = "default_command_version")]
+    version: u32,
+}
+
+/// Deserialize a command message, checking its version before its content
+///
+/// Fields that are unknown to this version are ignored, so that newer senders
+/// can add optional fields without bumping the version.
+pub fn parse_command<T: DeserializeOwned>(data: &[u8]) -> Result<T, CommandParseError> {
+    let command_version: CommandVersion =
+        serde_json::from_slice(data).map_err(|e| CommandParseError::Malformed(e.to_string()))?;
+
+    if command_version.version > COMMAND_VERSION {
+        return Err(CommandParseError::UnknownVersion {
+            version: command_version.version,
+            supported: COMMAND_VERSION,
+        });
+    }
+
+    serde_json::from_slice(data).map_err(|e| CommandParseError::Malformed(e.to_string()))
+}
+
+/// Serialize a command into a message, in the format that `parse_command`
+/// reads
+pub fn command_payload<T: Serialize>(command: &T) -> Vec<u8> {
+    // The commands consist of fields that always serialize
+    serde_json::to_vec(command).unwrap()
+}
+
 impl fmt::Display for SftpDownload {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.size {
@@ -82,3 +227,111 @@ pub fn wait_for<T>(join_handle: thread::JoinHandle<T>, thread_name: &str) {
 }
 
 pub type StopCmd = Box<dyn FnOnce() + Send + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sftp_download() -> SftpDownload {
+        SftpDownload {
+            version: COMMAND_VERSION,
+            id: 42,
+            created: Utc.with_ymd_and_hms(2026, 10, 1, 12, 0, 0).unwrap(),
+            size: Some(1024),
+            sftp_source: "red".to_string(),
+            path: "upload/data.csv".to_string(),
+            remove: false,
+            local_subpath: None,
+        }
+    }
+
+    #[test]
+    fn sftp_download_round_trip() {
+        let data = command_payload(&sftp_download());
+
+        let command: SftpDownload = parse_command(&data).unwrap();
+
+        assert_eq!(command, sftp_download());
+    }
+
+    #[test]
+    fn http_download_round_trip() {
+        let http_download = HttpDownload {
+            version: COMMAND_VERSION,
+            created: Utc.with_ymd_and_hms(2026, 10, 1, 12, 0, 0).unwrap(),
+            size: None,
+            url: "https://example.com/data.csv".to_string(),
+        };
+
+        let data = command_payload(&http_download);
+
+        let command: HttpDownload = parse_command(&data).unwrap();
+
+        assert_eq!(command, http_download);
+    }
+
+    #[test]
+    fn unversioned_command_is_version_1() {
+        let data = br#"{"id": 1, "created": "2026-10-01T12:00:00Z", "size": null, "sftp_source": "red", "path": "a.csv", "remove": true}"#;
+
+        let command: SftpDownload = parse_command(data).unwrap();
+
+        assert_eq!(command.version, 1);
+    }
+
+    #[test]
+    fn future_version_is_rejected() {
+        let mut value = serde_json::to_value(sftp_download()).unwrap();
+        value["version"] = 2.into();
+        value["checksum"] = "sha256:abc".into();
+
+        let data = serde_json::to_vec(&value).unwrap();
+
+        assert_eq!(
+            parse_command::<SftpDownload>(&data).unwrap_err(),
+            CommandParseError::UnknownVersion {
+                version: 2,
+                supported: COMMAND_VERSION
+            }
+        );
+    }
+
+    #[test]
+    fn extra_fields_are_ignored() {
+        let mut value = serde_json::to_value(sftp_download()).unwrap();
+        value["checksum"] = "sha256:abc".into();
+
+        let data = serde_json::to_vec(&value).unwrap();
+
+        assert_eq!(parse_command::<SftpDownload>(&data).unwrap().id, 42);
+    }
+
+    #[test]
+    fn local_subpath_stays_in_source_directory() {
+        let mut command = sftp_download();
+
+        assert_eq!(command.local_subpath(), Ok(None));
+
+        command.local_subpath = Some("batch/1234".to_string());
+
+        assert_eq!(command.local_subpath(), Ok(Some(Path::new("batch/1234"))));
+
+        for unsafe_subpath in ["/etc", "batch/../..", ".."] {
+            command.local_subpath = Some(unsafe_subpath.to_string());
+
+            assert!(command.local_subpath().is_err(), "{unsafe_subpath}");
+        }
+    }
+
+    #[test]
+    fn malformed_command() {
+        assert!(matches!(
+            parse_command::<SftpDownload>(b"{\"id\": "),
+            Err(CommandParseError::Malformed(_))
+        ));
+        assert!(matches!(
+            parse_command::<SftpDownload>(br#"{"id": 1}"#),
+            Err(CommandParseError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,137 @@
+//! Throttling of error messages that repeat, e.g. on every scan and retry
+//! while an SFTP server rejects expired credentials
+//!
+//! The first error with a code for a source or target is logged, after which
+//! errors with the same code and source or target are suppressed for the rest
+//! of the window. The next error after the window is logged with the number
+//! of errors that were suppressed.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::error_code::ErrorCode;
+
+/// Window in milliseconds during which repeated errors are suppressed by
+/// default
+pub const DEFAULT_WINDOW: u64 = 60_000;
+
+/// Called for every error, also the suppressed ones, with the code of the
+/// error and the name of the source or target
+pub type Observer = Box<dyn Fn(&str, &str) + Send + Sync>;
+
+struct Window {
+    start: Instant,
+    suppressed: u64,
+}
+
+static WINDOW: AtomicU64 = AtomicU64::new(DEFAULT_WINDOW);
+static WINDOWS: Mutex<BTreeMap<(String, String), Window>> = Mutex::new(BTreeMap::new());
+static OBSERVER: OnceLock<Observer> = OnceLock::new();
+
+/// Set the window in milliseconds during which repeated errors are
+/// suppressed, where 0 logs every error
+pub fn set_window(window: u64) {
+    WINDOW.store(window, Ordering::Relaxed);
+}
+
+pub fn window() -> Duration {
+    Duration::from_millis(WINDOW.load(Ordering::Relaxed))
+}
+
+/// Set the observer of all errors, e.g. for counting them in a metric, which
+/// can only be set once
+pub fn set_observer(observer: Observer) -> Result<(), String> {
+    OBSERVER
+        .set(observer)
+        .map_err(|_| "Error log observer is already set".to_string())
+}
+
+/// Register an error with a code for a source or target, returning the
+/// number of errors suppressed in the previous window when it is to be logged
+/// and `None` when it is suppressed
+pub fn register(code: ErrorCode, name: &str) -> Option<u64> {
+    if let Some(observer) = OBSERVER.get() {
+        observer(code.code(), name);
+    }
+
+    register_at(code.code(), name, Instant::now(), window())
+}
+
+fn register_at(error: &str, name: &str, now: Instant, window: Duration) -> Option<u64> {
+    let mut windows = WINDOWS.lock().unwrap_or_else(|e| e.into_inner());
+
+    match windows.get_mut(&(error.to_string(), name.to_string())) {
+        Some(current) if now.duration_since(current.start) < window => {
+            current.suppressed += 1;
+
+            None
+        }
+        Some(current) => {
+            let suppressed = current.suppressed;
+
+            *current = Window {
+                start: now,
+                suppressed: 0,
+            };
+
+            Some(suppressed)
+        }
+        None => {
+            windows.insert(
+                (error.to_string(), name.to_string()),
+                Window {
+                    start: now,
+                    suppressed: 0,
+                },
+            );
+
+            Some(0)
+        }
+    }
+}
+
+/// Log an error with its code, unless an error with the same code for the
+/// same source or target was logged within the window
+#[macro_export]
+macro_rules! throttled_error {
+    ($code:expr, $name:expr, $($arg:tt)+) => {
+        if let Some(suppressed) = $crate::log_throttle::register($code, $name) {
+            if suppressed > 0 {
+                $crate::coded_error!(
+                    $code,
+                    "Errors of '{}': suppressed {} identical errors in the last {}s",
+                    $name,
+                    suppressed,
+                    $crate::log_throttle::window().as_secs()
+                );
+            }
+
+            $crate::coded_error!($code, $($arg)+);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_errors_are_suppressed_within_the_window() {
+        let start = Instant::now();
+        let window = Duration::from_secs(60);
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        assert_eq!(register_at("test", "red", at(0), window), Some(0));
+        assert_eq!(register_at("test", "red", at(10), window), None);
+        assert_eq!(register_at("test", "red", at(59), window), None);
+        // Other sources have their own window
+        assert_eq!(register_at("test", "blue", at(30), window), Some(0));
+        assert_eq!(register_at("test", "red", at(60), window), Some(2));
+        assert_eq!(register_at("test", "red", at(61), window), None);
+        assert_eq!(register_at("test", "red", at(200), window), Some(1));
+        // Without a window every error is logged
+        assert_eq!(register_at("test", "red", at(200), Duration::ZERO), Some(0));
+    }
+}
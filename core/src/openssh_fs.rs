@@ -0,0 +1,272 @@
+//! SFTP file system on a connection of the OpenSSH client, as an alternative
+//! to libssh2
+//!
+//! The connection is made by the `ssh` binary of the system, so it uses the
+//! same ciphers, key types and agent as an interactive session. The SFTP
+//! requests are asynchronous and are driven by a runtime of the file system
+//! itself, so that the file system can be used in the same way as
+//! [`SftpFs`](crate::remote_fs::SftpFs).
+
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use openssh::{KnownHosts, SessionBuilder};
+use openssh_sftp_client::error::SftpErrorKind;
+use openssh_sftp_client::file::{File, TokioCompatFile};
+use openssh_sftp_client::fs::ReadDir;
+use openssh_sftp_client::metadata::MetaData;
+use openssh_sftp_client::{Error, Sftp, SftpOptions};
+use ssh2::FileStat;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+
+use crate::remote_fs::{RemoteError, RemoteFs};
+use crate::sftp_connection::{address_host, HostKeyCheck, SftpConfig};
+
+/// Minimum number of bytes requested per read
+const READ_BUFFER_SIZE: NonZeroUsize = match NonZeroUsize::new(64 * 1024) {
+    Some(size) => size,
+    None => unreachable!(),
+};
+
+impl From<Error> for RemoteError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::SftpError(SftpErrorKind::NoSuchFile, _) => RemoteError::NoSuchFile,
+            Error::SftpError(_, _) => RemoteError::Other(e.to_string()),
+            // Anything else is a fault in the connection or the ssh process
+            _ => RemoteError::Disconnected(e.to_string()),
+        }
+    }
+}
+
+/// SFTP file system on an OpenSSH connection
+pub struct OpensshFs {
+    runtime: Arc<Runtime>,
+    sftp: Sftp,
+}
+
+impl SftpConfig {
+    /// Connect with the OpenSSH client, which only authenticates with the
+    /// key file or the ssh agent
+    pub fn connect_openssh(&self) -> Result<OpensshFs> {
+        if self.password.is_some() {
+            return Err(anyhow!(
+                "Password authentication is not supported with the openssh backend"
+            ));
+        }
+
+        if self.key_passphrase.is_some() || self.key_passphrase_file.is_some() {
+            return Err(anyhow!(
+                "Key passphrases are not supported with the openssh backend, add the key to the ssh agent instead"
+            ));
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| anyhow!("Could not create runtime: {}", e))?;
+
+        let mut builder = SessionBuilder::default();
+
+        builder
+            .user(self.username.clone())
+            .known_hosts_check(match self.host_key_check {
+                HostKeyCheck::Strict => KnownHosts::Strict,
+                HostKeyCheck::AcceptNew => KnownHosts::Add,
+            })
+            .connect_timeout(Duration::from_secs(self.connect_timeout_seconds))
+            .compression(self.compress);
+
+        if let Some(port) = address_port(&self.address)? {
+            builder.port(port);
+        }
+
+        if let Some(key_file) = &self.key_file {
+            info!("Authorizing using key {}", &key_file.to_string_lossy());
+            builder.keyfile(key_file);
+        } else {
+            info!("Authorizing using ssh agent");
+        }
+
+        if self.keepalive_interval_seconds > 0 {
+            builder.server_alive_interval(Duration::from_secs(self.keepalive_interval_seconds));
+        }
+
+        let host = address_host(&self.address);
+
+        let sftp = runtime.block_on(async {
+            // Bound the handshake and authentication, but not the transfers
+            // afterwards
+            tokio::time::timeout(Duration::from_secs(self.handshake_timeout_seconds), async {
+                let session = builder
+                    .connect(host)
+                    .await
+                    .map_err(|e| anyhow!("SSH connection failed: {}", e))?;
+
+                Sftp::from_session(session, SftpOptions::default())
+                    .await
+                    .map_err(|e| anyhow!("SFTP session setup failed: {}", e))
+            })
+            .await
+            .map_err(|_| anyhow!("SSH connection timed out"))?
+        })?;
+
+        Ok(OpensshFs {
+            runtime: Arc::new(runtime),
+            sftp,
+        })
+    }
+
+    /// Connect with the OpenSSH client until it succeeds, the stop flag is
+    /// set, or the maximum number of attempts is reached
+    pub fn connect_openssh_loop(&self, stop: Arc<AtomicBool>) -> Result<OpensshFs> {
+        self.retry_connect(stop, || self.connect_openssh())
+    }
+}
+
+/// Port of a host:port address, `None` when the address has no port
+fn address_port(address: &str) -> Result<Option<u16>> {
+    let port = match address.strip_prefix('[') {
+        Some(rest) => rest.split_once("]:").map(|(_, port)| port),
+        // Only one colon, so not a bare IPv6 address
+        None => address
+            .rsplit_once(':')
+            .filter(|(host, _)| !host.contains(':'))
+            .map(|(_, port)| port),
+    };
+
+    port.map(|port| {
+        port.parse()
+            .map_err(|e| anyhow!("Invalid port in address '{}': {}", address, e))
+    })
+    .transpose()
+}
+
+fn file_stat(metadata: &MetaData) -> FileStat {
+    let file_type = metadata.file_type().map(|file_type| {
+        if file_type.is_dir() {
+            0o040000
+        } else if file_type.is_symlink() {
+            0o120000
+        } else {
+            0o100000
+        }
+    });
+
+    let permissions = metadata.permissions().map(|p| {
+        [
+            (p.read_by_owner(), 0o400),
+            (p.write_by_owner(), 0o200),
+            (p.execute_by_owner(), 0o100),
+            (p.read_by_group(), 0o040),
+            (p.write_by_group(), 0o020),
+            (p.execute_by_group(), 0o010),
+            (p.read_by_other(), 0o004),
+            (p.write_by_other(), 0o002),
+            (p.execute_by_other(), 0o001),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |mode, (_, bit)| mode | bit)
+    });
+
+    FileStat {
+        size: metadata.len(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        perm: match (file_type, permissions) {
+            (None, None) => None,
+            (file_type, permissions) => Some(file_type.unwrap_or(0) | permissions.unwrap_or(0)),
+        },
+        atime: metadata.accessed().map(|t| u64::from(t.into_raw())),
+        mtime: metadata.modified().map(|t| u64::from(t.into_raw())),
+    }
+}
+
+/// Open file of an [`OpensshFs`]
+pub struct OpensshFile {
+    runtime: Arc<Runtime>,
+    /// Handle on the same remote file for requests other than reads
+    handle: File,
+    file: Pin<Box<TokioCompatFile>>,
+}
+
+impl io::Read for OpensshFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.file.read(buf))
+    }
+}
+
+/// Open directory of an [`OpensshFs`]
+pub struct OpensshDir(Pin<Box<ReadDir>>);
+
+impl RemoteFs for OpensshFs {
+    type File = OpensshFile;
+    type Dir = OpensshDir;
+
+    fn open(&self, path: &Path) -> Result<OpensshFile, RemoteError> {
+        let file = self.runtime.block_on(self.sftp.open(path))?;
+
+        Ok(OpensshFile {
+            runtime: self.runtime.clone(),
+            handle: file.clone(),
+            file: Box::pin(TokioCompatFile::with_capacity(file, READ_BUFFER_SIZE)),
+        })
+    }
+
+    fn stat(&self, file: &mut OpensshFile) -> Result<FileStat, RemoteError> {
+        let metadata = self.runtime.block_on(file.handle.metadata())?;
+
+        Ok(file_stat(&metadata))
+    }
+
+    fn opendir(&self, path: &Path) -> Result<OpensshDir, RemoteError> {
+        let dir = self.runtime.block_on(self.sftp.fs().open_dir(path))?;
+
+        Ok(OpensshDir(Box::pin(dir.read_dir())))
+    }
+
+    fn readdir(&self, dir: &mut OpensshDir) -> Result<Option<(PathBuf, FileStat)>, RemoteError> {
+        let entry = self.runtime.block_on(std::future::poll_fn(|cx| {
+            futures_core::Stream::poll_next(dir.0.as_mut(), cx)
+        }));
+
+        match entry {
+            Some(Ok(entry)) => Ok(Some((
+                entry.filename().to_path_buf(),
+                file_stat(&entry.metadata()),
+            ))),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(None),
+        }
+    }
+
+    fn unlink(&self, path: &Path) -> Result<(), RemoteError> {
+        Ok(self.runtime.block_on(self.sftp.fs().remove_file(path))?)
+    }
+
+    fn rename(&self, src: &Path, dst: &Path) -> Result<(), RemoteError> {
+        Ok(self.runtime.block_on(self.sftp.fs().rename(src, dst))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ports_are_taken_from_the_address() {
+        assert_eq!(address_port("sftp.example.com:2222").unwrap(), Some(2222));
+        assert_eq!(address_port("[::1]:22").unwrap(), Some(22));
+        assert_eq!(address_port("sftp.example.com").unwrap(), None);
+        assert!(address_port("sftp.example.com:ssh").is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use ssh2::{ErrorCode, FileStat, Session, Sftp};
+use thiserror::Error;
+
+use crate::sftp_connection::{compression_negotiated, send_keepalive};
+
+/// Error code with which libssh2 signals the end of a directory listing
+const LIBSSH2_ERROR_FILE: i32 = -16;
+
+/// SFTP status code of a file that does not exist
+const SSH_FX_NO_SUCH_FILE: i32 = 2;
+
+/// Failure of an operation on a remote file system
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum RemoteError {
+    /// The connection failed, so reconnecting may help
+    #[error("Disconnected: {0}")]
+    Disconnected(String),
+    #[error("No such file")]
+    NoSuchFile,
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<ssh2::Error> for RemoteError {
+    fn from(e: ssh2::Error) -> Self {
+        match e.code() {
+            // Probably a fault in the SFTP connection
+            ErrorCode::Session(_) => RemoteError::Disconnected(e.to_string()),
+            ErrorCode::SFTP(SSH_FX_NO_SUCH_FILE) => RemoteError::NoSuchFile,
+            ErrorCode::SFTP(_) => RemoteError::Other(e.to_string()),
+        }
+    }
+}
+
+/// File system that files are scanned on and downloaded from
+pub trait RemoteFs {
+    type File: io::Read;
+    type Dir;
+
+    fn open(&self, path: &Path) -> Result<Self::File, RemoteError>;
+    fn stat(&self, file: &mut Self::File) -> Result<FileStat, RemoteError>;
+    fn opendir(&self, path: &Path) -> Result<Self::Dir, RemoteError>;
+    /// Name and stat of the next entry of the directory, `None` at the end
+    fn readdir(&self, dir: &mut Self::Dir) -> Result<Option<(PathBuf, FileStat)>, RemoteError>;
+    fn unlink(&self, path: &Path) -> Result<(), RemoteError>;
+    fn rename(&self, src: &Path, dst: &Path) -> Result<(), RemoteError>;
+    /// Keep the connection alive while it is idle
+    fn keepalive(&self) {}
+}
+
+/// SFTP file system on an SSH session
+pub struct SftpFs {
+    sftp: Sftp,
+    session: Session,
+}
+
+impl SftpFs {
+    pub fn new(session: Session) -> Result<SftpFs> {
+        let sftp = session.sftp()?;
+
+        Ok(SftpFs { sftp, session })
+    }
+
+    /// Whether the session compresses the traffic in both directions
+    pub fn compressed(&self) -> bool {
+        compression_negotiated(&self.session)
+    }
+}
+
+impl RemoteFs for SftpFs {
+    type File = ssh2::File;
+    type Dir = ssh2::File;
+
+    fn open(&self, path: &Path) -> Result<ssh2::File, RemoteError> {
+        Ok(self.sftp.open(path)?)
+    }
+
+    fn stat(&self, file: &mut ssh2::File) -> Result<FileStat, RemoteError> {
+        Ok(file.stat()?)
+    }
+
+    fn opendir(&self, path: &Path) -> Result<ssh2::File, RemoteError> {
+        Ok(self.sftp.opendir(path)?)
+    }
+
+    fn readdir(&self, dir: &mut ssh2::File) -> Result<Option<(PathBuf, FileStat)>, RemoteError> {
+        match dir.readdir() {
+            Ok(entry) => Ok(Some(entry)),
+            Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_FILE) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn unlink(&self, path: &Path) -> Result<(), RemoteError> {
+        Ok(self.sftp.unlink(path)?)
+    }
+
+    fn rename(&self, src: &Path, dst: &Path) -> Result<(), RemoteError> {
+        Ok(self.sftp.rename(src, dst, None)?)
+    }
+
+    fn keepalive(&self) {
+        send_keepalive(&self.session)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MemoryFile {
+    content: Vec<u8>,
+    mtime: u64,
+}
+
+/// File system in memory, standing in for an SFTP server in tests
+///
+/// Directories exist implicitly for the files in them. Reads can be slowed
+/// down to make concurrent downloads overlap, and the number of files open at
+/// the same time is tracked.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFs {
+    files: Arc<Mutex<BTreeMap<PathBuf, MemoryFile>>>,
+    failure: Arc<Mutex<Option<RemoteError>>>,
+    protected: Arc<Mutex<BTreeSet<PathBuf>>>,
+    read_delay: Option<Duration>,
+    open_files: Arc<AtomicU64>,
+    max_open_files: Arc<AtomicU64>,
+}
+
+impl MemoryFs {
+    pub fn add_file(&self, path: &Path, content: &[u8], mtime: u64) {
+        self.files.lock().unwrap().insert(
+            path.to_path_buf(),
+            MemoryFile {
+                content: content.to_vec(),
+                mtime,
+            },
+        );
+    }
+
+    pub fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    /// Fail the next operation with the error
+    pub fn fail_next(&self, error: RemoteError) {
+        *self.failure.lock().unwrap() = Some(error);
+    }
+
+    /// Fail removing the file as if it lacks permission, or allow it again
+    pub fn set_protected(&self, path: &Path, protected: bool) {
+        let mut paths = self.protected.lock().unwrap();
+
+        if protected {
+            paths.insert(path.to_path_buf());
+        } else {
+            paths.remove(path);
+        }
+    }
+
+    pub fn set_read_delay(&mut self, delay: Duration) {
+        self.read_delay = Some(delay);
+    }
+
+    /// Largest number of files that were open at the same time
+    pub fn max_open_files(&self) -> u64 {
+        self.max_open_files.load(Ordering::SeqCst)
+    }
+
+    fn check_failure(&self) -> Result<(), RemoteError> {
+        match self.failure.lock().unwrap().take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+fn file_stat(size: Option<u64>, perm: u32, mtime: u64) -> FileStat {
+    FileStat {
+        size,
+        uid: None,
+        gid: None,
+        perm: Some(perm),
+        atime: None,
+        mtime: Some(mtime),
+    }
+}
+
+/// Open file of a [`MemoryFs`]
+pub struct MemoryFsFile {
+    content: io::Cursor<Vec<u8>>,
+    mtime: u64,
+    read_delay: Option<Duration>,
+    open_files: Arc<AtomicU64>,
+}
+
+impl io::Read for MemoryFsFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.read_delay {
+            Some(delay) => {
+                thread::sleep(delay);
+
+                // Small reads, so that a download takes multiple delays
+                let len = buf.len().min(4);
+
+                self.content.read(&mut buf[..len])
+            }
+            None => self.content.read(buf),
+        }
+    }
+}
+
+impl Drop for MemoryFsFile {
+    fn drop(&mut self) {
+        self.open_files.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl RemoteFs for MemoryFs {
+    type File = MemoryFsFile;
+    type Dir = std::vec::IntoIter<(PathBuf, FileStat)>;
+
+    fn open(&self, path: &Path) -> Result<MemoryFsFile, RemoteError> {
+        self.check_failure()?;
+
+        let file = self
+            .files
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or(RemoteError::NoSuchFile)?;
+
+        let open_files = self.open_files.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_open_files.fetch_max(open_files, Ordering::SeqCst);
+
+        Ok(MemoryFsFile {
+            content: io::Cursor::new(file.content),
+            mtime: file.mtime,
+            read_delay: self.read_delay,
+            open_files: self.open_files.clone(),
+        })
+    }
+
+    fn stat(&self, file: &mut MemoryFsFile) -> Result<FileStat, RemoteError> {
+        self.check_failure()?;
+
+        Ok(file_stat(
+            Some(file.content.get_ref().len() as u64),
+            0o100644,
+            file.mtime,
+        ))
+    }
+
+    fn opendir(&self, path: &Path) -> Result<Self::Dir, RemoteError> {
+        self.check_failure()?;
+
+        let files = self.files.lock().unwrap();
+
+        let mut entries: Vec<(PathBuf, FileStat)> = Vec::new();
+
+        for (file_path, file) in files.iter() {
+            let Ok(relative) = file_path.strip_prefix(path) else {
+                continue;
+            };
+
+            let mut components = relative.components();
+
+            let Some(name) = components.next() else {
+                continue;
+            };
+
+            let name = PathBuf::from(name.as_os_str());
+
+            if components.next().is_some() {
+                if !entries.iter().any(|(entry_name, _)| entry_name == &name) {
+                    entries.push((name, file_stat(None, 0o040755, 0)));
+                }
+            } else {
+                entries.push((
+                    name,
+                    file_stat(Some(file.content.len() as u64), 0o100644, file.mtime),
+                ));
+            }
+        }
+
+        if entries.is_empty() {
+            return Err(RemoteError::NoSuchFile);
+        }
+
+        Ok(entries.into_iter())
+    }
+
+    fn readdir(&self, dir: &mut Self::Dir) -> Result<Option<(PathBuf, FileStat)>, RemoteError> {
+        self.check_failure()?;
+
+        Ok(dir.next())
+    }
+
+    fn unlink(&self, path: &Path) -> Result<(), RemoteError> {
+        self.check_failure()?;
+
+        if self.protected.lock().unwrap().contains(path) {
+            return Err(RemoteError::Other("Permission denied".to_string()));
+        }
+
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(|_| ())
+            .ok_or(RemoteError::NoSuchFile)
+    }
+
+    fn rename(&self, src: &Path, dst: &Path) -> Result<(), RemoteError> {
+        self.check_failure()?;
+
+        let mut files = self.files.lock().unwrap();
+
+        let file = files.remove(src).ok_or(RemoteError::NoSuchFile)?;
+
+        files.insert(dst.to_path_buf(), file);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssh2_errors_are_mapped() {
+        let disconnected = ssh2::Error::new(ErrorCode::Session(-7), "socket disconnect");
+        let missing = ssh2::Error::new(ErrorCode::SFTP(2), "no such file");
+        let denied = ssh2::Error::new(ErrorCode::SFTP(3), "permission denied");
+
+        assert!(matches!(
+            RemoteError::from(disconnected),
+            RemoteError::Disconnected(_)
+        ));
+        assert_eq!(RemoteError::from(missing), RemoteError::NoSuchFile);
+        assert!(matches!(RemoteError::from(denied), RemoteError::Other(_)));
+    }
+
+    #[test]
+    fn directories_list_files_and_subdirectories() {
+        let fs = MemoryFs::default();
+        fs.add_file(Path::new("upload/a.xml"), b"a", 1);
+        fs.add_file(Path::new("upload/sub/b.xml"), b"b", 2);
+        fs.add_file(Path::new("upload/sub/c.xml"), b"c", 3);
+
+        let mut dir = fs.opendir(Path::new("upload")).unwrap();
+
+        let mut entries = Vec::new();
+
+        while let Some((name, stat)) = fs.readdir(&mut dir).unwrap() {
+            entries.push((name, stat.is_dir()));
+        }
+
+        assert_eq!(
+            entries,
+            vec![
+                (PathBuf::from("a.xml"), false),
+                (PathBuf::from("sub"), true)
+            ]
+        );
+    }
+}
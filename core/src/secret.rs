@@ -0,0 +1,148 @@
+use std::cell::Cell;
+use std::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+/// Text that replaces secrets in debug output and serialized settings
+const REDACTED: &str = "***";
+
+thread_local! {
+    static SERIALIZE_SECRETS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Setting that holds a credential, such as a password or an address with a
+/// user and password in it
+///
+/// The value is hidden from debug output and from serialization, and is only
+/// available through `expose`.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new<S: Into<String>>(value: S) -> Secret {
+        Secret(value.into())
+    }
+
+    /// The actual value, for where the credential is used
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret(value.to_string())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if SERIALIZE_SECRETS.get() {
+            serializer.serialize_str(&self.0)
+        } else {
+            serializer.serialize_str(REDACTED)
+        }
+    }
+}
+
+/// Run `f` with the actual values of secrets serialized instead of `***`, for
+/// writing settings that are read back later
+pub fn serialize_secrets<T, F: FnOnce() -> T>(f: F) -> T {
+    let previous = SERIALIZE_SECRETS.replace(true);
+
+    let result = f();
+
+    SERIALIZE_SECRETS.set(previous);
+
+    result
+}
+
+/// Read a secret from a file, without the trailing newline
+pub fn read_secret_file(path: &Path) -> Result<Secret, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        format!(
+            "Could not read secret file '{}': {}",
+            path.to_string_lossy(),
+            e
+        )
+    })?;
+
+    let secret = content.strip_suffix('\n').unwrap_or(&content);
+    let secret = secret.strip_suffix('\r').unwrap_or(secret);
+
+    Ok(Secret::from(secret))
+}
+
+/// Resolve a value that can be specified inline or through a `_file` field
+pub fn resolve_secret(
+    name: &str,
+    value: Option<&Secret>,
+    file: Option<&Path>,
+) -> Result<Option<Secret>, String> {
+    match (value, file) {
+        (Some(_), Some(_)) => Err(format!("Both {name} and {name}_file are set")),
+        (None, Some(file)) => read_secret_file(file).map(Some),
+        (value, None) => Ok(value.cloned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Credentials {
+        password: Option<Secret>,
+    }
+
+    #[test]
+    fn secrets_are_redacted_unless_exposed() {
+        let credentials: Credentials = serde_json::from_str(r#"{"password": "hunter2"}"#).unwrap();
+
+        let password = credentials.password.as_ref().unwrap();
+
+        assert_eq!(password.expose(), "hunter2");
+        assert_eq!(
+            format!("{credentials:?}"),
+            "Credentials { password: Some(***) }"
+        );
+        assert_eq!(password.to_string(), "***");
+        assert_eq!(
+            serde_json::to_string(&credentials).unwrap(),
+            r#"{"password":"***"}"#
+        );
+        assert_eq!(
+            serialize_secrets(|| serde_json::to_string(&credentials).unwrap()),
+            r#"{"password":"hunter2"}"#
+        );
+        // Secrets are redacted again afterwards
+        assert_eq!(
+            serde_json::to_string(&credentials).unwrap(),
+            r#"{"password":"***"}"#
+        );
+    }
+}
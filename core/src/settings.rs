@@ -0,0 +1,187 @@
+//! Settings shared by the dispatcher and the SFTP scanner, which embed them in
+//! their own settings
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::secret::{resolve_secret, Secret};
+use crate::sftp_connection::{
+    default_connect_timeout_seconds, default_handshake_timeout_seconds,
+    default_keepalive_interval_seconds, HostKeyCheck, SessionLimits, SftpConfig,
+};
+
+/// How to connect and log in to an SFTP server, flattened into the settings
+/// of an SFTP source
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SftpConnectionSettings {
+    pub address: String,
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
+    /// File to read the password from, instead of specifying it inline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+    /// Passphrase of an encrypted key file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_passphrase: Option<Secret>,
+    /// File to read the key passphrase from, instead of specifying it inline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_passphrase_file: Option<PathBuf>,
+    #[serde(default, deserialize_with = "parsed_or_value")]
+    pub compress: bool,
+    /// Maximum time in seconds for the TCP connection to be established
+    #[serde(
+        default = "default_connect_timeout_seconds",
+        deserialize_with = "parsed_or_value"
+    )]
+    pub connect_timeout_seconds: u64,
+    /// Maximum time in seconds for the SSH handshake and authentication
+    #[serde(
+        default = "default_handshake_timeout_seconds",
+        deserialize_with = "parsed_or_value"
+    )]
+    pub handshake_timeout_seconds: u64,
+    /// Interval in seconds between SSH keepalives, 0 disables them
+    #[serde(
+        default = "default_keepalive_interval_seconds",
+        deserialize_with = "parsed_or_value"
+    )]
+    pub keepalive_interval_seconds: u64,
+}
+
+/// Deserialize a value that can also be given as text, such as the values of
+/// environment overrides, which are not converted by the `config` crate for
+/// flattened settings
+fn parsed_or_value<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+    T::Err: fmt::Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ParsedOrValue<T> {
+        Value(T),
+        Text(String),
+    }
+
+    match ParsedOrValue::<T>::deserialize(deserializer)? {
+        ParsedOrValue::Value(value) => Ok(value),
+        ParsedOrValue::Text(text) => text.parse().map_err(de::Error::custom),
+    }
+}
+
+impl SftpConnectionSettings {
+    /// Connection settings with a password and the default timeouts
+    pub fn with_password(address: &str, username: &str, password: &str) -> SftpConnectionSettings {
+        SftpConnectionSettings {
+            address: address.to_string(),
+            username: username.to_string(),
+            password: Some(Secret::from(password)),
+            password_file: None,
+            key_file: None,
+            key_passphrase: None,
+            key_passphrase_file: None,
+            compress: false,
+            connect_timeout_seconds: default_connect_timeout_seconds(),
+            handshake_timeout_seconds: default_handshake_timeout_seconds(),
+            keepalive_interval_seconds: default_keepalive_interval_seconds(),
+        }
+    }
+
+    /// Read the password and key passphrase from their files, when they are
+    /// configured through a `_file` field
+    ///
+    /// The `_file` fields are cleared once their content has been read.
+    pub fn resolve_secret_files(&mut self, name: &str) -> Result<(), String> {
+        self.password = resolve_secret(
+            &format!("{name}.password"),
+            self.password.as_ref(),
+            self.password_file.take().as_deref(),
+        )?;
+
+        self.key_passphrase = resolve_secret(
+            &format!("{name}.key_passphrase"),
+            self.key_passphrase.as_ref(),
+            self.key_passphrase_file.take().as_deref(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Configuration of a connection that is tried until it succeeds, without
+    /// session limits
+    pub fn sftp_config(&self) -> SftpConfig {
+        SftpConfig {
+            address: self.address.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            key_file: self.key_file.clone(),
+            key_passphrase: self.key_passphrase.clone(),
+            key_passphrase_file: self.key_passphrase_file.clone(),
+            compress: self.compress,
+            connect_timeout_seconds: self.connect_timeout_seconds,
+            handshake_timeout_seconds: self.handshake_timeout_seconds,
+            keepalive_interval_seconds: self.keepalive_interval_seconds,
+            max_attempts: None,
+            session_limits: SessionLimits::default(),
+            host_key_check: HostKeyCheck::default(),
+        }
+    }
+}
+
+/// Attributes of a registered file that new versions of it are compared with
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    pub modified: DateTime<Utc>,
+    pub size: i64,
+    pub hash: Option<String>,
+}
+
+/// Attributes that must be equal for a file to be a duplicate
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileComparison {
+    pub size: bool,
+    pub modified: bool,
+    pub hash: bool,
+}
+
+impl FileComparison {
+    pub fn equal(
+        &self,
+        file_info: &FileInfo,
+        size: u64,
+        modified: DateTime<Utc>,
+        hash: Option<String>,
+    ) -> bool {
+        if self.size && (file_info.size as u64) != size {
+            return false;
+        }
+
+        if self.modified && file_info.modified != modified {
+            return false;
+        }
+
+        if self.hash && file_info.hash != hash {
+            return false;
+        }
+
+        true
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Deduplication {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "check")]
+    Check(FileComparison),
+    #[serde(rename = "name")]
+    Name,
+}
@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use testcontainers::core::{Mount, WaitFor};
 use testcontainers::{runners::AsyncRunner, ContainerAsync, ContainerRequest, ImageExt};
 
@@ -9,10 +11,23 @@ use thiserror::Error;
 const RABBITMQ_NAME: &str = "rabbitmq";
 const RABBITMQ_TAG: &str = "3.11.9-management";
 
+const SFTP_NAME: &str = "atmoz/sftp";
+const SFTP_TAG: &str = "alpine";
+
+/// User of the SFTP server, of which the files are in `upload` in its home
+/// directory
+pub const SFTP_USER: &str = "cortex";
+
+/// Container and host name of RabbitMQ when its state is persisted, fixed
+/// because RabbitMQ stores its data per node name.
+const PERSISTENT_RABBITMQ_NAME: &str = "cortex-dev-rabbitmq";
+
 #[derive(Error, Debug)]
 pub enum DevStackError {
     #[error("Container issue with dev stack: {0}")]
     Testcontainer(#[from] testcontainers::TestcontainersError),
+    #[error("Could not prepare persist directory: {0}")]
+    PersistDir(#[from] std::io::Error),
 }
 
 pub struct DevStack {
@@ -43,11 +58,74 @@ pub fn print_stdout<
 
 impl DevStack {
     pub async fn start(print_output: bool) -> Result<DevStack, DevStackError> {
-        let rabbitmq_name = format!("rabbitmq-{}", generate_name(8));
-        let rabbitmq_container = create_rabbitmq_container(&rabbitmq_name)
-            .start()
-            .await
-            .unwrap();
+        DevStack::start_with(print_output, None).await
+    }
+
+    /// Start the dev stack, keeping the RabbitMQ state in `persist_dir` when
+    /// specified so that it survives restarts.
+    pub async fn start_with(
+        print_output: bool,
+        persist_dir: Option<&Path>,
+    ) -> Result<DevStack, DevStackError> {
+        let container_request = match persist_dir {
+            Some(persist_dir) => {
+                let rabbitmq_dir = persist_dir.join("rabbitmq");
+
+                std::fs::create_dir_all(&rabbitmq_dir)?;
+
+                // The RabbitMQ user in the container must be able to write
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+
+                    std::fs::set_permissions(
+                        &rabbitmq_dir,
+                        std::fs::Permissions::from_mode(0o777),
+                    )?;
+                }
+
+                let rabbitmq_dir = std::fs::canonicalize(rabbitmq_dir)?;
+
+                create_rabbitmq_container(PERSISTENT_RABBITMQ_NAME)
+                    .with_hostname(PERSISTENT_RABBITMQ_NAME)
+                    .with_mount(Mount::bind_mount(
+                        rabbitmq_dir.to_string_lossy(),
+                        "/var/lib/rabbitmq",
+                    ))
+            }
+            None => create_rabbitmq_container(&format!("rabbitmq-{}", generate_name(8))),
+        };
+
+        DevStack::run(print_output, container_request).await
+    }
+
+    /// Start the dev stack with an additional TLS listener on port 5671
+    ///
+    /// `tls_dir` must contain `ca.pem`, `server.pem` and `server-key.pem`,
+    /// readable by the RabbitMQ user in the container.
+    pub async fn start_tls(print_output: bool, tls_dir: &Path) -> Result<DevStack, DevStackError> {
+        let conf_path = concat!(env!("CARGO_MANIFEST_DIR"), "/rabbitmq-tls.conf");
+        let tls_dir = std::fs::canonicalize(tls_dir)?;
+
+        let container_request =
+            create_rabbitmq_container(&format!("rabbitmq-{}", generate_name(8)))
+                .with_mount(Mount::bind_mount(
+                    conf_path,
+                    "/etc/rabbitmq/conf.d/20-tls.conf",
+                ))
+                .with_mount(Mount::bind_mount(
+                    tls_dir.to_string_lossy(),
+                    "/etc/rabbitmq/tls",
+                ));
+
+        DevStack::run(print_output, container_request).await
+    }
+
+    async fn run(
+        print_output: bool,
+        container_request: ContainerRequest<RabbitMq>,
+    ) -> Result<DevStack, DevStackError> {
+        let rabbitmq_container = container_request.start().await?;
 
         if print_output {
             print_stdout("rabbitmq - ".to_string(), rabbitmq_container.stdout(true));
@@ -69,6 +147,13 @@ impl DevStack {
             .await
             .map_err(DevStackError::Testcontainer)
     }
+
+    pub async fn rabbitmq_tls_port(&self) -> Result<u16, DevStackError> {
+        self.rabbitmq_container
+            .get_host_port_ipv4(5671)
+            .await
+            .map_err(DevStackError::Testcontainer)
+    }
 }
 
 pub fn generate_name(len: usize) -> String {
@@ -106,3 +191,51 @@ pub fn create_rabbitmq_container(name: &str) -> ContainerRequest<RabbitMq> {
             "/etc/rabbitmq/definitions.json",
         ))
 }
+
+#[derive(Debug, Default, Clone)]
+pub struct SftpServer;
+
+impl testcontainers::Image for SftpServer {
+    fn name(&self) -> &str {
+        SFTP_NAME
+    }
+
+    fn tag(&self) -> &str {
+        SFTP_TAG
+    }
+
+    fn ready_conditions(&self) -> Vec<WaitFor> {
+        vec![WaitFor::message_on_stderr(
+            "Server listening on 0.0.0.0 port 22",
+        )]
+    }
+}
+
+/// Start an SFTP server on which `upload_dir` is the `upload` directory of
+/// [`SFTP_USER`], who logs in with the key of `public_key_file`
+///
+/// `upload_dir` must be writable for the user in the container to remove and
+/// rename files.
+pub async fn start_sftp_server(
+    upload_dir: &Path,
+    public_key_file: &Path,
+) -> Result<ContainerAsync<SftpServer>, DevStackError> {
+    let upload_dir = std::fs::canonicalize(upload_dir)?;
+    let public_key_file = std::fs::canonicalize(public_key_file)?;
+
+    let container = ContainerRequest::from(SftpServer)
+        .with_container_name(format!("sftp-{}", generate_name(8)))
+        .with_mount(Mount::bind_mount(
+            upload_dir.to_string_lossy(),
+            format!("/home/{SFTP_USER}/upload"),
+        ))
+        .with_mount(Mount::bind_mount(
+            public_key_file.to_string_lossy(),
+            format!("/home/{SFTP_USER}/.ssh/keys/id.pub"),
+        ))
+        .with_cmd([format!("{SFTP_USER}::1001:100")])
+        .start()
+        .await?;
+
+    Ok(container)
+}
@@ -0,0 +1,191 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use deadpool_lapin::lapin::tcp::{HandshakeResult, RustlsConnector, TcpStream};
+use deadpool_lapin::lapin::uri::{AMQPScheme, AMQPUri};
+use deadpool_lapin::lapin::{Connection, ConnectionProperties};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, RootCertStore};
+
+use crate::settings::AmqpTls;
+
+/// Connect to an AMQP server
+///
+/// For amqps:// addresses the server certificate is verified against the CA
+/// of the TLS settings, or against the system roots when there are none.
+// The handshake error type is defined by lapin
+#[allow(clippy::result_large_err)]
+pub async fn connect(address: &str, tls: Option<&AmqpTls>) -> Result<Connection, String> {
+    let uri: AMQPUri = address
+        .parse()
+        .map_err(|e| format!("Invalid AMQP address: {e}"))?;
+
+    let connector = match uri.scheme {
+        AMQPScheme::AMQP => None,
+        AMQPScheme::AMQPS => Some(tls_connector(tls)?),
+    };
+
+    let properties = ConnectionProperties::default()
+        .with_executor(tokio_executor_trait::Tokio::current())
+        .with_reactor(tokio_reactor_trait::Tokio::current());
+
+    Connection::connector(
+        uri,
+        Box::new(move |uri| connect_stream(uri, connector.as_ref())),
+        properties,
+    )
+    .await
+    .map_err(|e| format!("Error connecting to AMQP server: {e}"))
+}
+
+#[allow(clippy::result_large_err)]
+fn connect_stream(uri: &AMQPUri, connector: Option<&RustlsConnector>) -> HandshakeResult {
+    let address = (uri.authority.host.as_str(), uri.authority.port);
+
+    let stream = match uri.query.connection_timeout {
+        Some(timeout) => TcpStream::connect_timeout(address, Duration::from_millis(timeout)),
+        None => TcpStream::connect(address),
+    }?;
+
+    let stream = match connector {
+        Some(connector) => stream.into_rustls(connector, &uri.authority.host)?,
+        None => stream,
+    };
+
+    stream.set_nonblocking(true)?;
+
+    Ok(stream)
+}
+
+/// Build the TLS connector for the TLS settings
+pub fn tls_connector(tls: Option<&AmqpTls>) -> Result<RustlsConnector, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let mut roots = RootCertStore::empty();
+
+    match tls {
+        Some(tls) => {
+            for cert in read_certs(&tls.ca_cert)? {
+                roots.add(cert).map_err(|e| {
+                    format!(
+                        "Invalid CA certificate in '{}': {}",
+                        tls.ca_cert.to_string_lossy(),
+                        e
+                    )
+                })?;
+            }
+        }
+        None => {
+            let native_certs = rustls_native_certs::load_native_certs();
+
+            roots.add_parsable_certificates(native_certs.certs);
+        }
+    }
+
+    let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| format!("Error creating TLS certificate verifier: {e}"))?;
+
+    let builder = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Error creating TLS configuration: {e}"))?;
+
+    let builder = match tls {
+        Some(tls) if !tls.verify_hostname => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoHostnameVerification(verifier))),
+        _ => builder.with_webpki_verifier(verifier),
+    };
+
+    let config = match tls.and_then(|tls| tls.client_cert.as_ref().zip(tls.client_key.as_ref())) {
+        Some((client_cert, client_key)) => {
+            let key = PrivateKeyDer::from_pem_file(client_key).map_err(|e| {
+                format!(
+                    "Error reading client key '{}': {}",
+                    client_key.to_string_lossy(),
+                    e
+                )
+            })?;
+
+            builder
+                .with_client_auth_cert(read_certs(client_cert)?, key)
+                .map_err(|e| format!("Invalid client certificate: {e}"))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(RustlsConnector::from(config))
+}
+
+fn read_certs(path: &std::path::Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            format!(
+                "Error reading certificates from '{}': {}",
+                path.to_string_lossy(),
+                e
+            )
+        })?;
+
+    if certs.is_empty() {
+        return Err(format!(
+            "No certificates found in '{}'",
+            path.to_string_lossy()
+        ));
+    }
+
+    Ok(certs)
+}
+
+/// Verifies the server certificate against the CA, but accepts it for any
+/// host name
+#[derive(Debug)]
+struct NoHostnameVerification(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for NoHostnameVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self
+            .0
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp, now)
+        {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
@@ -0,0 +1,447 @@
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use digest_io::HashWriter;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, error, info};
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::event::{FileEvent, FileEventReceiver, FileEventSender};
+use crate::persistence::SqliteAsyncPersistence;
+use crate::settings::{self, ArchiveCompression};
+
+/// File an archive is written to, hashing what is written
+enum ArchiveFile {
+    Plain(HashWriter<Sha256, File>),
+    Gzip(GzEncoder<HashWriter<Sha256, File>>),
+}
+
+impl ArchiveFile {
+    fn finish(self) -> io::Result<HashWriter<Sha256, File>> {
+        match self {
+            ArchiveFile::Plain(writer) => Ok(writer),
+            ArchiveFile::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl Write for ArchiveFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ArchiveFile::Plain(writer) => writer.write(buf),
+            ArchiveFile::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveFile::Plain(writer) => writer.flush(),
+            ArchiveFile::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Archive that files are appended to, under a temporary name
+struct OpenArchive {
+    /// Final name of the archive
+    file_name: String,
+    part_path: PathBuf,
+    builder: tar::Builder<ArchiveFile>,
+    /// When the archive is completed because of the roll interval
+    deadline: Option<Instant>,
+    file_ids: Vec<i64>,
+    bytes: u64,
+}
+
+/// An archive that has been completed and moved to its final name
+#[derive(Debug)]
+pub struct Archive {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: String,
+    pub modified: DateTime<Utc>,
+    /// Files that are in the archive
+    pub file_ids: Vec<i64>,
+}
+
+/// Appends files to tar archives in the directory of an archive target,
+/// completing an archive when one of the limits of the target is reached
+pub struct ArchiveWriter {
+    target: settings::ArchiveTarget,
+    open: Option<OpenArchive>,
+}
+
+impl ArchiveWriter {
+    pub fn new(target: settings::ArchiveTarget) -> ArchiveWriter {
+        ArchiveWriter { target, open: None }
+    }
+
+    /// Append the file of an event, returning the archive when this completes
+    /// it
+    pub fn append(&mut self, file_event: &FileEvent) -> Result<Option<Archive>, String> {
+        let file_name = file_event.path.file_name().ok_or_else(|| {
+            format!(
+                "No file name from file event path '{}'",
+                file_event.path.to_string_lossy()
+            )
+        })?;
+
+        if self.open.is_none() {
+            self.open = Some(self.start()?);
+        }
+
+        let open = self.open.as_mut().unwrap();
+
+        open.builder
+            .append_path_with_name(&file_event.path, file_name)
+            .map_err(|e| {
+                format!(
+                    "Error appending '{}' to archive '{}': {}",
+                    file_event.path.to_string_lossy(),
+                    open.part_path.to_string_lossy(),
+                    e
+                )
+            })?;
+
+        open.file_ids.push(file_event.file_id);
+        open.bytes += file_event.size;
+
+        let full = self
+            .target
+            .max_files
+            .is_some_and(|max_files| open.file_ids.len() >= max_files)
+            || self
+                .target
+                .max_bytes
+                .is_some_and(|max_bytes| open.bytes >= max_bytes);
+
+        if full {
+            self.roll()
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// When the open archive must be completed because of the roll interval
+    pub fn deadline(&self) -> Option<Instant> {
+        self.open.as_ref().and_then(|open| open.deadline)
+    }
+
+    /// Complete the open archive, if any, and move it to its final name
+    pub fn roll(&mut self) -> Result<Option<Archive>, String> {
+        let open = match self.open.take() {
+            Some(open) => open,
+            None => return Ok(None),
+        };
+
+        let part_path_str = open.part_path.to_string_lossy();
+
+        let (hasher, file) = open
+            .builder
+            .into_inner()
+            .and_then(ArchiveFile::finish)
+            .map(HashWriter::into_parts)
+            .map_err(|e| format!("Error completing archive '{part_path_str}': {e}"))?;
+
+        file.sync_all()
+            .map_err(|e| format!("Error syncing archive '{part_path_str}': {e}"))?;
+
+        let size = file
+            .metadata()
+            .map_err(|e| format!("Error reading metadata of archive '{part_path_str}': {e}"))?
+            .len();
+
+        let path = unique_path(&self.target.directory, &open.file_name);
+
+        fs::rename(&open.part_path, &path).map_err(|e| {
+            format!(
+                "Error renaming archive '{}' to '{}': {}",
+                part_path_str,
+                path.to_string_lossy(),
+                e
+            )
+        })?;
+
+        Ok(Some(Archive {
+            path,
+            size,
+            hash: hex::encode(hasher.finalize()),
+            modified: Utc::now(),
+            file_ids: open.file_ids,
+        }))
+    }
+
+    fn start(&self) -> Result<OpenArchive, String> {
+        let mut file_name = String::new();
+
+        write!(file_name, "{}", Utc::now().format(&self.target.file_name)).map_err(|_| {
+            format!(
+                "Invalid file_name '{}' for archive target '{}'",
+                self.target.file_name, self.target.name
+            )
+        })?;
+
+        let part_path = self.target.directory.join(format!(".{file_name}.part"));
+
+        let file = File::create(&part_path).map_err(|e| {
+            format!(
+                "Error creating archive '{}': {}",
+                part_path.to_string_lossy(),
+                e
+            )
+        })?;
+
+        let writer = HashWriter::<Sha256, File>::new(file);
+
+        let archive_file = match self.target.compression {
+            ArchiveCompression::None => ArchiveFile::Plain(writer),
+            ArchiveCompression::Gzip => {
+                ArchiveFile::Gzip(GzEncoder::new(writer, Compression::default()))
+            }
+        };
+
+        Ok(OpenArchive {
+            file_name,
+            part_path,
+            builder: tar::Builder::new(archive_file),
+            deadline: self
+                .target
+                .roll_interval
+                .map(|interval| Instant::now() + Duration::from_millis(interval)),
+            file_ids: Vec::new(),
+            bytes: 0,
+        })
+    }
+}
+
+/// Path in the directory with the file name, numbered when a file with that
+/// name already exists
+fn unique_path(directory: &Path, file_name: &str) -> PathBuf {
+    let path = directory.join(file_name);
+
+    if !path.exists() {
+        return path;
+    }
+
+    let (stem, extension) = match file_name.split_once('.') {
+        Some((stem, extension)) => (stem, format!(".{extension}")),
+        None => (file_name, String::new()),
+    };
+
+    (1..)
+        .map(|number| directory.join(format!("{stem}-{number}{extension}")))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Append the files of the events of an archive target to archives, and
+/// dispatch every completed archive from the source with the name of the
+/// target
+///
+/// On stop, the open archive is completed, so that no partial archive is
+/// left behind. The done sender is dropped when the handler ends.
+pub async fn handle_archive_events(
+    mut writer: ArchiveWriter,
+    receiver: FileEventReceiver,
+    sender: FileEventSender,
+    persistence: SqliteAsyncPersistence,
+    mut stop_receiver: watch::Receiver<()>,
+    _done_sender: mpsc::Sender<()>,
+) {
+    let target_name = writer.target.name.clone();
+
+    loop {
+        let deadline = writer.deadline();
+
+        let result = tokio::select!(
+            file_event = receiver.recv() => match file_event {
+                Ok(file_event) => writer.append(&file_event),
+                Err(_) => break,
+            },
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => writer.roll(),
+            _ = stop_receiver.changed() => break,
+        );
+
+        complete(result, &target_name, &sender, &persistence).await;
+    }
+
+    complete(writer.roll(), &target_name, &sender, &persistence).await;
+
+    debug!("Archive target '{target_name}' ended");
+}
+
+/// Log the files of the events of an archive target in a dry run, without
+/// archiving them
+pub async fn log_archive_events(
+    target_name: String,
+    receiver: FileEventReceiver,
+    mut stop_receiver: watch::Receiver<()>,
+    _done_sender: mpsc::Sender<()>,
+) {
+    loop {
+        tokio::select!(
+            file_event = receiver.recv() => match file_event {
+                Ok(file_event) => info!(
+                    "Would archive '{}' in archive target '{}'",
+                    file_event.path.to_string_lossy(),
+                    &target_name
+                ),
+                Err(_) => break,
+            },
+            _ = stop_receiver.changed() => break,
+        );
+    }
+
+    debug!("Archive target '{target_name}' ended");
+}
+
+async fn complete(
+    result: Result<Option<Archive>, String>,
+    target_name: &str,
+    sender: &FileEventSender,
+    persistence: &SqliteAsyncPersistence,
+) {
+    let archive = match result {
+        Ok(Some(archive)) => archive,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Error handling event for archive target: {e}");
+            return;
+        }
+    };
+
+    info!(
+        "Completed archive '{}' with {} files",
+        archive.path.to_string_lossy(),
+        archive.file_ids.len()
+    );
+
+    if let Err(e) = dispatch_archive(archive, target_name, sender, persistence).await {
+        error!("{e}");
+    }
+}
+
+/// Register a completed archive and dispatch it from the source of the
+/// target
+async fn dispatch_archive(
+    archive: Archive,
+    target_name: &str,
+    sender: &FileEventSender,
+    persistence: &SqliteAsyncPersistence,
+) -> Result<(), String> {
+    let path_str = archive.path.to_string_lossy().to_string();
+
+    for file_id in &archive.file_ids {
+        persistence
+            .insert_dispatched(target_name, *file_id)
+            .await
+            .map_err(|e| format!("Error registering file {file_id} as archived: {e}"))?;
+    }
+
+    let file_id = persistence
+        .insert_file(
+            target_name,
+            &path_str,
+            &archive.modified,
+            archive.size as i64,
+            Some(archive.hash.clone()),
+        )
+        .await
+        .map_err(|e| format!("Error registering archive '{path_str}': {e}"))?;
+
+    sender
+        .send(FileEvent {
+            file_id,
+            source_name: target_name.to_string(),
+            relative_path: archive.path.file_name().map(PathBuf::from),
+            path: archive.path,
+            hash: archive.hash,
+            content_hash: true,
+            size: archive.size,
+            modified: archive.modified,
+            created: Utc::now(),
+            discovered: None,
+            ack: None,
+            confirmation: None,
+        })
+        .await
+        .map_err(|e| format!("Error dispatching archive '{path_str}': {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::read::GzDecoder;
+
+    #[test]
+    fn archive_rolls_at_max_files() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-archive-target-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let file_event = |file_id: i64| {
+            let path = directory.join(format!("{file_id}.csv"));
+            fs::write(&path, format!("file {file_id}")).unwrap();
+
+            FileEvent {
+                file_id,
+                source_name: "red".to_string(),
+                path,
+                relative_path: None,
+                hash: String::new(),
+                content_hash: false,
+                size: 6,
+                modified: Utc::now(),
+                created: Utc::now(),
+                discovered: None,
+                ack: None,
+                confirmation: None,
+            }
+        };
+
+        let mut writer = ArchiveWriter::new(settings::ArchiveTarget {
+            name: "hourly".to_string(),
+            directory: directory.clone(),
+            roll_interval: None,
+            max_files: Some(2),
+            max_bytes: None,
+            file_name: "archive.tar.gz".to_string(),
+            compression: ArchiveCompression::Gzip,
+        });
+
+        assert!(writer.append(&file_event(1)).unwrap().is_none());
+        assert!(directory.join(".archive.tar.gz.part").exists());
+
+        let archive = writer.append(&file_event(2)).unwrap().unwrap();
+
+        // The next archive gets a numbered name
+        writer.append(&file_event(3)).unwrap();
+        let next_archive = writer.roll().unwrap().unwrap();
+
+        let mut tar = tar::Archive::new(GzDecoder::new(File::open(&archive.path).unwrap()));
+        let names: Vec<PathBuf> = tar
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_path_buf())
+            .collect();
+
+        let next_path = next_archive.path.clone();
+        let part_exists = directory.join(".archive.tar.gz.part").exists();
+
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(archive.path, directory.join("archive.tar.gz"));
+        assert_eq!(archive.file_ids, vec![1, 2]);
+        assert_eq!(names, vec![PathBuf::from("1.csv"), PathBuf::from("2.csv")]);
+        assert_eq!(next_path, directory.join("archive-1.tar.gz"));
+        assert!(!part_exists);
+    }
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use log::{debug, error};
+use tokio::sync::{mpsc, watch};
+
+use crate::persistence::{DispatchDecision, SqliteAsyncPersistence};
+
+/// Number of decisions at which they are written right away
+const BATCH_SIZE: usize = 500;
+/// Maximum time that decisions wait before they are written
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Sends dispatch decisions to the audit writer, without waiting for them to
+/// be written
+#[derive(Debug, Clone)]
+pub struct AuditSender {
+    sender: mpsc::UnboundedSender<DispatchDecision>,
+}
+
+impl AuditSender {
+    pub fn record(&self, decision: DispatchDecision) {
+        if self.sender.send(decision).is_err() {
+            error!("Could not record dispatch decision, the audit writer has stopped");
+        }
+    }
+}
+
+/// Start the task that writes dispatch decisions in batches, at least every
+/// flush interval and on stop
+pub fn start_audit_writer(
+    persistence: SqliteAsyncPersistence,
+    mut stop_receiver: watch::Receiver<()>,
+) -> (AuditSender, tokio::task::JoinHandle<()>) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    let join_handle = tokio::spawn(async move {
+        let mut batch: Vec<DispatchDecision> = Vec::new();
+        let mut flush_interval = tokio::time::interval(FLUSH_INTERVAL);
+
+        loop {
+            tokio::select!(
+                decision = receiver.recv() => match decision {
+                    Some(decision) => {
+                        batch.push(decision);
+
+                        if batch.len() >= BATCH_SIZE {
+                            flush(&persistence, &mut batch).await;
+                        }
+                    }
+                    None => break,
+                },
+                _ = flush_interval.tick() => flush(&persistence, &mut batch).await,
+                _ = stop_receiver.changed() => break,
+            )
+        }
+
+        // Keep the decisions that were made before the stop
+        while let Ok(decision) = receiver.try_recv() {
+            batch.push(decision);
+        }
+
+        flush(&persistence, &mut batch).await;
+
+        debug!("Audit writer ended");
+    });
+
+    (AuditSender { sender }, join_handle)
+}
+
+async fn flush(persistence: &SqliteAsyncPersistence, batch: &mut Vec<DispatchDecision>) {
+    if batch.is_empty() {
+        return;
+    }
+
+    let decisions = std::mem::take(batch);
+    let count = decisions.len();
+
+    match persistence.insert_dispatch_decisions(decisions).await {
+        Ok(()) => debug!("Wrote {count} dispatch decisions"),
+        Err(e) => error!("Error writing {count} dispatch decisions: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use chrono::{SubsecRound, Utc};
+
+    use crate::persistence::{Persistence, SqlitePersistence};
+
+    #[tokio::test]
+    async fn decisions_are_written_on_stop() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+
+        let file_id = SqlitePersistence::from_arc(conn.clone())
+            .insert_file("red", "/cortex/storage/red/a.csv", &Utc::now(), 4, None)
+            .unwrap();
+
+        let persistence = SqliteAsyncPersistence::new(conn);
+        let (stop_sender, stop_receiver) = watch::channel(());
+
+        let (audit_sender, join_handle) = start_audit_writer(persistence.clone(), stop_receiver);
+
+        let decision = |target: &str, filter_matched| DispatchDecision {
+            file_id,
+            source: "red".to_string(),
+            target: target.to_string(),
+            filter_matched,
+            timestamp: Utc::now().trunc_subsecs(0),
+        };
+
+        let decisions = vec![decision("blue", true), decision("green", false)];
+
+        decisions
+            .iter()
+            .for_each(|decision| audit_sender.record(decision.clone()));
+
+        stop_sender.send(()).unwrap();
+        join_handle.await.unwrap();
+
+        assert_eq!(
+            persistence.dispatch_decisions(file_id).await.unwrap(),
+            decisions
+        );
+    }
+}
@@ -1,136 +1,23 @@
-use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use tera::{Context, Tera};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-
-use chrono::prelude::{DateTime, Utc};
-
-use deadpool_lapin::{Config, Runtime};
-use serde_json::json;
-
-use log::error;
-
-use crate::event::FileEvent;
-use crate::settings::{self, RabbitMQNotify};
-use deadpool_lapin::lapin::options::BasicPublishOptions;
-use deadpool_lapin::lapin::{BasicProperties, Channel};
-
-pub struct RabbitMQNotifier {
-    pub address: String,
-    pub message_template: String,
-    pub exchange: String,
-    pub routing_key: String,
-    channel: Option<Channel>,
-}
-
-impl From<&RabbitMQNotify> for RabbitMQNotifier {
-    fn from(value: &RabbitMQNotify) -> Self {
-        RabbitMQNotifier {
-            address: value.address.clone(),
-            message_template: value.message_template.clone(),
-            exchange: value.exchange.clone(),
-            routing_key: value.routing_key.clone(),
-            channel: None,
-        }
-    }
-}
-
-impl RabbitMQNotifier {
-    async fn connect(&mut self) -> Result<Channel, String> {
-        let cfg = Config {
-            url: Some(self.address.clone()),
-            ..Default::default()
-        };
-        let pool = cfg
-            .create_pool(Some(Runtime::Tokio1))
-            .map_err(|e| format!("Error creating pool for AMQP server: {e}"))?;
-
-        let connection = pool
-            .get()
-            .await
-            .map_err(|e| format!("Error connecting to AMQP server: {e}"))?;
-
-        let amqp_channel = connection
-            .create_channel()
-            .await
-            .map_err(|e| format!("Error creating AMQP channel: {e}"))?;
-
-        Ok(amqp_channel)
-    }
-
-    async fn publish(&mut self, message: &str) -> Result<(), String> {
-        self.channel
-            .as_ref()
-            .unwrap()
-            .basic_publish(
-                &self.exchange.clone(),
-                &self.routing_key.clone(),
-                BasicPublishOptions::default(),
-                message.as_bytes(),
-                BasicProperties::default(),
-            )
-            .await
-            .map_err(|e| format!("Error publishing notification: {}", e))?;
-
-        Ok(())
-    }
-
-    async fn reconnect(&mut self) -> Result<(), String> {
-        self.channel = None;
-
-        while self.channel.is_none() {
-            match self.connect().await {
-                Ok(channel) => {
-                    self.channel = Some(channel);
-                }
-                Err(e) => {
-                    error!("{e}");
-                }
-            }
-        }
-
-        Ok(())
-    }
-
-    pub async fn notify(&mut self, file_event: FileEvent) -> Result<(), String> {
-        if self.channel.is_none() {
-            self.channel = Some(self.connect().await?);
-        }
-
-        let context = Context::from_serialize(&json!({"file_path": &file_event.path}))
-            .map_err(|e| format!("Could not create context: {e}"))?;
-
-        let message = Tera::one_off(&self.message_template, &context, true)
-            .map_err(|e| format!("Error rendering template: {}", e))?;
-
-        let mut published = false;
-
-        while !published {
-            match self.publish(&message).await {
-                Ok(_) => {
-                    published = true;
-                }
-                Err(e) => {
-                    error!("{e}");
-                    self.reconnect().await?;
-                }
-            }
-        }
-
-        Ok(())
-    }
-}
+use crate::event::{FileEventReceiver, FileEventSender};
+use crate::settings;
 
 #[derive(Debug)]
 pub struct Source {
     pub name: String,
-    pub receiver: UnboundedReceiver<FileEvent>,
+    pub receiver: FileEventReceiver,
 }
 
 #[derive(Debug)]
 pub struct Target {
     pub name: String,
-    pub sender: UnboundedSender<FileEvent>,
+    pub sender: FileEventSender,
+    /// File events taken from the channel that are not completely dispatched
+    /// yet, including the dispatches that are retried
+    pub in_progress: Arc<AtomicUsize>,
 }
 
 #[derive(Debug, Clone)]
@@ -138,16 +25,45 @@ pub struct Connection {
     pub source_name: String,
     pub target: Arc<Target>,
     pub filter: Option<settings::Filter>,
+    pub priority: u8,
+    /// Whether the download command of a file waits for the target to handle
+    /// the file
+    pub require_delivery: bool,
+    pub delivery_timeout: Duration,
 }
 
-#[derive(Debug, Clone)]
-pub enum MessageResponse {
-    Ack {},
-    Nack {},
+impl Connection {
+    pub fn new(conn_conf: &settings::Connection, target: Arc<Target>) -> Connection {
+        Connection {
+            source_name: conn_conf.source.clone(),
+            target,
+            filter: conn_conf.filter.clone(),
+            priority: conn_conf.priority,
+            require_delivery: conn_conf.require_delivery,
+            delivery_timeout: Duration::from_secs(conn_conf.delivery_timeout_seconds),
+        }
+    }
 }
 
-pub struct FileInfo {
-    pub modified: DateTime<Utc>,
-    pub size: i64,
-    pub hash: Option<String>,
+/// Connections of all sources, which can be changed at runtime
+pub type Connections = Arc<RwLock<Vec<Connection>>>;
+
+/// Outcome of a download command, to acknowledge its message to the broker
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageResponse {
+    Ack {
+        delivery_tag: u64,
+    },
+    /// Requeue the message after the delay, for failures that may pass
+    Nack {
+        delivery_tag: u64,
+        delay: Duration,
+    },
+    /// Do not requeue the message, for failures that will not pass
+    Reject {
+        delivery_tag: u64,
+        reason: String,
+    },
 }
+
+pub use cortex_core::settings::FileInfo;
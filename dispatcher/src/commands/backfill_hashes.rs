@@ -0,0 +1,158 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use clap::Parser;
+
+use crate::commands::files::{open_persistence, parse_timestamp};
+use crate::commands::{Cmd, CmdResult};
+use crate::hash_backfill::{
+    backfill_hashes, read_last_id, write_last_id, Backfilled, ReadRateLimit,
+};
+use crate::persistence::{FileQuery, FileRecord};
+use crate::settings;
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct BackfillHashesOpt {
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
+    #[arg(short, long)]
+    config: Vec<String>,
+
+    /// Only files of this source
+    #[arg(long)]
+    source: Option<String>,
+
+    /// Only files registered at or after this time (RFC 3339, 'YYYY-MM-DD HH:MM:SS' or 'YYYY-MM-DD', UTC)
+    #[arg(long, value_parser = parse_timestamp)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only files registered before this time (RFC 3339, 'YYYY-MM-DD HH:MM:SS' or 'YYYY-MM-DD', UTC)
+    #[arg(long, value_parser = parse_timestamp)]
+    until: Option<DateTime<Utc>>,
+
+    /// Number of files hashed at the same time
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
+
+    /// Maximum number of bytes read from internal storage per second
+    #[arg(long)]
+    max_bytes_per_second: Option<u64>,
+
+    /// Number of files selected from the database at a time
+    #[arg(long, default_value_t = 1000)]
+    batch_size: usize,
+
+    /// File in which the progress is kept, so that an interrupted backfill
+    /// continues where it stopped
+    #[arg(long, default_value = "backfill-hashes.state")]
+    state_file: PathBuf,
+
+    /// Mark files that no longer exist in internal storage as deleted
+    #[arg(long)]
+    mark_missing: bool,
+}
+
+impl Cmd for BackfillHashesOpt {
+    fn run(&self) -> CmdResult {
+        let config_files = settings::config_paths(&self.config);
+
+        let settings =
+            settings::load_settings_files(&config_files).map_err(DispatcherError::Runtime)?;
+
+        let persistence = open_persistence(&settings)?;
+
+        let read_rate_limit = self
+            .max_bytes_per_second
+            .map(|bytes_per_second| ReadRateLimit::new(bytes_per_second as f64));
+
+        let mut last_id = read_last_id(&self.state_file).map_err(DispatcherError::Runtime)?;
+
+        let mut query = FileQuery {
+            source: self.source.clone(),
+            since: self.since,
+            until: self.until,
+            without_hash: true,
+            after_id: Some(last_id),
+            ..Default::default()
+        };
+
+        let mut remaining = persistence
+            .count_files(&query)
+            .map_err(|e| DispatcherError::Runtime(e.to_string()))?;
+
+        query.limit = Some(self.batch_size.max(1));
+
+        let mut processed = 0;
+        let mut hashed = 0;
+        let mut missing = 0;
+        let mut unverifiable = 0;
+        let mut failed = 0;
+
+        loop {
+            query.after_id = Some(last_id);
+
+            let files = persistence
+                .query_files(&query)
+                .map_err(|e| DispatcherError::Runtime(e.to_string()))?;
+
+            let Some(batch_last_id) = files.last().map(|file| file.id) else {
+                break;
+            };
+
+            let results = backfill_hashes(
+                &persistence,
+                &settings,
+                files,
+                self.concurrency,
+                read_rate_limit.as_ref(),
+            );
+
+            for (file, backfilled) in &results {
+                match backfilled {
+                    Backfilled::Hashed(_) => hashed += 1,
+                    Backfilled::Missing => {
+                        missing += 1;
+                        print_file("missing", file, "");
+
+                        if self.mark_missing {
+                            persistence
+                                .mark_file_deleted(file.id)
+                                .map_err(|e| DispatcherError::Runtime(e.to_string()))?;
+                        }
+                    }
+                    Backfilled::Unverifiable => {
+                        unverifiable += 1;
+                        print_file("unverifiable", file, "");
+                    }
+                    Backfilled::Failed(e) => {
+                        failed += 1;
+                        print_file("failed", file, e);
+                    }
+                }
+            }
+
+            last_id = batch_last_id;
+
+            write_last_id(&self.state_file, last_id).map_err(DispatcherError::Runtime)?;
+
+            processed += results.len();
+            remaining = (remaining - results.len() as i64).max(0);
+
+            println!("{processed} files processed, {remaining} remaining");
+        }
+
+        println!(
+            "{hashed} files hashed, {missing} missing, {unverifiable} unverifiable, {failed} failed"
+        );
+
+        Ok(())
+    }
+}
+
+fn print_file(status: &str, file: &FileRecord, message: &str) {
+    println!(
+        "{:>10}  {:<14}  {:<20}  {}  {}",
+        file.id, status, file.source, file.path, message
+    );
+}
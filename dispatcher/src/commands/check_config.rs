@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use clap::Parser;
+
+use crate::commands::{Cmd, CmdResult};
+use crate::probe::{self, Check};
+use crate::settings::{self, Settings};
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct CheckConfigOpt {
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
+    #[arg(short, long)]
+    config: Vec<String>,
+
+    /// Also probe connectivity of the AMQP server, the database and SFTP sources
+    #[arg(long)]
+    probe: bool,
+
+    /// Timeout in seconds for each connectivity probe
+    #[arg(long, default_value_t = 10)]
+    probe_timeout: u64,
+
+    /// Print the findings as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+impl Cmd for CheckConfigOpt {
+    fn run(&self) -> CmdResult {
+        let config_files = settings::config_paths(&self.config);
+
+        let mut checks: Vec<Check> = Vec::new();
+
+        match settings::load_settings_files(&config_files) {
+            Ok(settings) => {
+                checks.push(Check::from_result("load configuration", Ok(())));
+                checks.append(&mut check_settings(&settings));
+
+                if self.probe {
+                    let rt = tokio::runtime::Runtime::new().unwrap();
+
+                    let timeout = Duration::from_secs(self.probe_timeout);
+
+                    checks.append(&mut rt.block_on(probe_settings(&settings, timeout)));
+
+                    // Do not wait for probes that are stuck in blocking calls
+                    rt.shutdown_timeout(Duration::from_secs(0));
+                }
+            }
+            Err(e) => {
+                checks.push(Check::from_result("load configuration", Err(e)));
+            }
+        }
+
+        print_checks(&checks, self.json);
+
+        let failed = checks.iter().filter(|c| !c.passed).count();
+
+        if failed > 0 {
+            return Err(DispatcherError::Runtime(format!(
+                "{} of {} checks failed",
+                failed,
+                checks.len()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that do not require network connectivity
+pub fn check_settings(settings: &Settings) -> Vec<Check> {
+    let mut checks: Vec<Check> = settings
+        .validate()
+        .into_iter()
+        .map(|problem| Check::from_result("settings", Err(problem)))
+        .collect();
+
+    if checks.is_empty() {
+        checks.push(Check::from_result("settings", Ok(())));
+    }
+
+    if let Some(parent) = settings.sqlite.path.parent() {
+        checks.push(Check::from_result(
+            "sqlite directory",
+            probe::check_directory(parent),
+        ));
+    }
+
+    // The same checks as on startup of the service, without creating the
+    // missing directories
+    checks.append(&mut probe::check_directories(settings, false));
+
+    for archive_target in &settings.archive_targets {
+        checks.push(Check::from_result(
+            format!("archive target '{}'", archive_target.name),
+            probe::check_directory(&archive_target.directory),
+        ));
+    }
+
+    checks
+}
+
+async fn probe_settings(settings: &Settings, timeout: Duration) -> Vec<Check> {
+    let mut checks: Vec<Check> = vec![
+        Check::from_result(
+            "amqp command queue",
+            probe::probe_amqp(
+                settings.command_queue.address.expose(),
+                settings.command_queue.amqp_tls.as_ref(),
+                timeout,
+            )
+            .await,
+        ),
+        Check::from_result(
+            "sqlite database",
+            probe::probe_sqlite(&settings.sqlite.path, timeout).await,
+        ),
+    ];
+
+    for sftp_source in &settings.sftp_sources {
+        checks.push(Check::from_result(
+            format!("sftp source '{}'", sftp_source.name),
+            probe::probe_sftp(sftp_source, timeout).await,
+        ));
+    }
+
+    checks
+}
+
+fn print_checks(checks: &[Check], json: bool) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(checks).unwrap());
+        return;
+    }
+
+    for check in checks {
+        match &check.message {
+            Some(message) if check.passed => println!("WARN  {}: {}", check.name, message),
+            Some(message) => println!("FAIL  {}: {}", check.name, message),
+            None => println!("PASS  {}", check.name),
+        }
+    }
+}
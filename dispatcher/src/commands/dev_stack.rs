@@ -6,6 +6,7 @@ use clap::Parser;
 use tokio::signal;
 
 use crate::commands::{Cmd, CmdResult};
+use crate::DispatcherError;
 
 use dev_stack::dev_stack::DevStack;
 
@@ -20,6 +21,23 @@ pub struct DevStackOpt {
         default_value = "tmp"
     )]
     root_dir: String,
+    #[arg(
+        long,
+        help = "Keep RabbitMQ, database and data directories in this directory between runs",
+        conflicts_with = "root_dir"
+    )]
+    persist_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Remove the persisted state instead of starting the stack",
+        requires = "persist_dir"
+    )]
+    reset: bool,
+    #[arg(
+        long,
+        help = "Load files and dispatched records from a YAML or JSON fixture"
+    )]
+    seed: Option<PathBuf>,
 }
 
 impl Cmd for DevStackOpt {
@@ -31,11 +49,27 @@ impl Cmd for DevStackOpt {
 
         env_logger_builder.init();
 
+        if self.reset {
+            if let Some(persist_dir) = &self.persist_dir {
+                return reset(persist_dir);
+            }
+        }
+
+        let root_dir = match &self.persist_dir {
+            Some(persist_dir) => persist_dir.to_string_lossy().to_string(),
+            None => self.root_dir.clone(),
+        };
+
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         println!("Starting development stack");
 
-        rt.block_on(start_dev_stack(self.data_generator, &self.root_dir));
+        rt.block_on(start_dev_stack(
+            self.data_generator,
+            &root_dir,
+            self.persist_dir.as_deref(),
+            self.seed.as_deref(),
+        ))?;
 
         println!("Done");
 
@@ -43,12 +77,69 @@ impl Cmd for DevStackOpt {
     }
 }
 
-async fn start_dev_stack(data_generator: bool, root_dir: &str) {
-    let dev_stack = DevStack::start(false).await.unwrap();
+fn reset(persist_dir: &Path) -> CmdResult {
+    if persist_dir.exists() {
+        std::fs::remove_dir_all(persist_dir).map_err(|e| {
+            DispatcherError::Runtime(format!(
+                "Could not remove '{}': {}",
+                persist_dir.to_string_lossy(),
+                e
+            ))
+        })?;
+
+        println!("Removed '{}'", persist_dir.to_string_lossy());
+    } else {
+        println!("Nothing to remove at '{}'", persist_dir.to_string_lossy());
+    }
+
+    Ok(())
+}
+
+/// Create the database schema, or migrate it when it already exists
+fn create_schema(db_path: &Path) -> CmdResult {
+    let mut conn = rusqlite::Connection::open(db_path)
+        .map_err(|e| DispatcherError::Runtime(format!("Could not open database: {e}")))?;
+
+    let applied = cortex_core::run_migrations(&mut conn).map_err(DispatcherError::Runtime)?;
+
+    if applied.is_empty() {
+        println!("Database schema is up to date");
+    }
+
+    for name in applied {
+        println!("Applied migration {name}");
+    }
+
+    Ok(())
+}
+
+async fn start_dev_stack(
+    data_generator: bool,
+    root_dir: &str,
+    persist_dir: Option<&Path>,
+    seed: Option<&Path>,
+) -> CmdResult {
+    let dev_stack = DevStack::start_with(false, persist_dir)
+        .await
+        .map_err(|e| DispatcherError::Runtime(e.to_string()))?;
 
     let data_dir: PathBuf = [root_dir, "incoming"].iter().collect();
 
     std::fs::create_dir_all(&data_dir).unwrap();
+    std::fs::create_dir_all([root_dir, "storage"].iter().collect::<PathBuf>()).unwrap();
+
+    let db_path: PathBuf = [root_dir, "cortex.db"].iter().collect();
+
+    create_schema(&db_path)?;
+
+    if let Some(fixture_path) = seed {
+        let storage_dir: PathBuf = [root_dir, "storage"].iter().collect();
+
+        let count = crate::seed::seed_from_file(&db_path, &storage_dir, fixture_path)
+            .map_err(DispatcherError::Runtime)?;
+
+        println!("Seeded {count} files");
+    }
 
     if data_generator {
         println!("Starting data generator");
@@ -83,11 +174,22 @@ async fn start_dev_stack(data_generator: bool, root_dir: &str) {
         cortex_config_file_path.to_string_lossy()
     );
 
+    if let Some(persist_dir) = persist_dir {
+        println!();
+        println!(
+            "State is persisted in '{}', remove it with: cortex-dispatcher dev-stack --persist-dir {} --reset",
+            persist_dir.to_string_lossy(),
+            persist_dir.to_string_lossy()
+        );
+    }
+
     println!("Development stack is running, press Ctrl-C to stop");
 
     signal::ctrl_c().await.unwrap();
 
     println!("Stopping development stack");
+
+    Ok(())
 }
 
 async fn generate_data<S: AsRef<Path>>(data_dir: S) {
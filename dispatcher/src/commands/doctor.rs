@@ -0,0 +1,189 @@
+use std::time::Duration;
+
+use clap::Parser;
+
+use crate::commands::{Cmd, CmdResult};
+use crate::probe::{self, Check};
+use crate::settings::{self, Settings, DEFAULT_STORAGE_AREA};
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct DoctorOpt {
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
+    #[arg(short, long)]
+    config: Vec<String>,
+
+    /// Timeout in seconds for each check
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+
+    /// Only run checks matching this selector, e.g. 'sftp' or 'sftp:red'; can
+    /// be specified multiple times
+    #[arg(long)]
+    only: Vec<String>,
+}
+
+impl DoctorOpt {
+    fn selected(&self, check_id: &str) -> bool {
+        self.only.is_empty()
+            || self.only.iter().any(|selector| {
+                check_id == selector
+                    || check_id.starts_with(&format!("{selector}:"))
+                    || selector.starts_with(&format!("{check_id}:"))
+            })
+    }
+}
+
+impl Cmd for DoctorOpt {
+    fn run(&self) -> CmdResult {
+        let config_files = settings::config_paths(&self.config);
+
+        let settings =
+            settings::load_settings_files(&config_files).map_err(DispatcherError::Runtime)?;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let checks = rt.block_on(self.run_checks(&settings, Duration::from_secs(self.timeout)));
+
+        // Do not wait for checks that are stuck in blocking calls
+        rt.shutdown_timeout(Duration::from_secs(0));
+
+        print_table(&checks);
+
+        let failed = checks.iter().filter(|c| !c.passed).count();
+
+        if failed > 0 {
+            return Err(DispatcherError::Runtime(format!(
+                "{} of {} checks failed",
+                failed,
+                checks.len()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl DoctorOpt {
+    async fn run_checks(&self, settings: &Settings, timeout: Duration) -> Vec<Check> {
+        let mut checks: Vec<Check> = Vec::new();
+
+        let address = settings.command_queue.address.expose();
+
+        if self.selected("amqp") {
+            match probe::amqp_host_port(address) {
+                Ok((host, port)) => {
+                    checks.push(Check::from_result(
+                        "amqp:dns",
+                        probe::probe_dns(&host, port, timeout).await,
+                    ));
+                    checks.push(Check::from_result(
+                        "amqp:tcp",
+                        probe::probe_tcp(&host, port, timeout).await,
+                    ));
+                }
+                Err(e) => checks.push(Check::from_result("amqp:address", Err(e))),
+            }
+        }
+
+        for sftp_source in &settings.sftp_sources {
+            let check_id = format!("amqp:queue:{}", sftp_source.name);
+
+            if self.selected(&check_id) {
+                let queue_name = sftp_source.command_route().queue;
+
+                checks.push(Check::from_result(
+                    check_id,
+                    probe::probe_amqp_queue(
+                        address,
+                        settings.command_queue.amqp_tls.as_ref(),
+                        &queue_name,
+                        timeout,
+                    )
+                    .await,
+                ));
+            }
+        }
+
+        if self.selected("sqlite") {
+            checks.push(Check::from_result(
+                "sqlite",
+                probe::probe_sqlite(&settings.sqlite.path, timeout).await,
+            ));
+        }
+
+        for sftp_source in &settings.sftp_sources {
+            let check_id = format!("sftp:{}", sftp_source.name);
+
+            if self.selected(&check_id) {
+                checks.push(Check::from_result(
+                    check_id,
+                    probe::probe_sftp_readdir(sftp_source, timeout).await,
+                ));
+            }
+        }
+
+        for area in &settings.storage {
+            let check_id = match area.name.as_str() {
+                DEFAULT_STORAGE_AREA => "storage".to_string(),
+                name => format!("storage:{name}"),
+            };
+
+            if self.selected(&check_id) {
+                checks.push(Check::from_result(
+                    check_id,
+                    probe::probe_write(&area.directory),
+                ));
+            }
+        }
+
+        for directory_target in &settings.directory_targets {
+            let check_id = format!("target:{}", directory_target.name);
+
+            if self.selected(&check_id) {
+                checks.push(Check::from_result(
+                    check_id,
+                    probe::probe_write(&directory_target.directory),
+                ));
+            }
+        }
+
+        for archive_target in &settings.archive_targets {
+            let check_id = format!("target:{}", archive_target.name);
+
+            if self.selected(&check_id) {
+                checks.push(Check::from_result(
+                    check_id,
+                    probe::probe_write(&archive_target.directory),
+                ));
+            }
+        }
+
+        checks
+    }
+}
+
+fn print_table(checks: &[Check]) {
+    let width = checks
+        .iter()
+        .map(|c| c.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("CHECK".len());
+
+    println!("{:width$}  RESULT  ERROR", "CHECK");
+
+    for check in checks {
+        let result = if check.passed { "PASS" } else { "FAIL" };
+
+        let line = format!(
+            "{:width$}  {:6}  {}",
+            check.name,
+            result,
+            check.message.as_deref().unwrap_or("")
+        );
+
+        println!("{}", line.trim_end());
+    }
+}
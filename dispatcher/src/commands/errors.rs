@@ -0,0 +1,54 @@
+use clap::Parser;
+
+use cortex_core::error_code::ErrorCode;
+
+use crate::commands::{Cmd, CmdResult};
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct ErrorsOpt {
+    /// Describe only this code, e.g. E01003
+    code: Option<String>,
+}
+
+impl Cmd for ErrorsOpt {
+    fn run(&self) -> CmdResult {
+        let codes = matching_codes(self.code.as_deref());
+
+        if codes.is_empty() {
+            return Err(DispatcherError::Runtime(format!(
+                "No error code '{}'",
+                self.code.as_deref().unwrap_or_default()
+            )));
+        }
+
+        for code in codes {
+            println!("{}  {}", code, code.description());
+        }
+
+        Ok(())
+    }
+}
+
+/// All codes, or the code as written in log lines, with or without brackets
+fn matching_codes(code: Option<&str>) -> Vec<ErrorCode> {
+    let code = code.map(|code| code.trim_matches(|c| c == '[' || c == ']'));
+
+    ErrorCode::ALL
+        .into_iter()
+        .filter(|error_code| code.is_none_or(|code| error_code.code().eq_ignore_ascii_case(code)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_looked_up_as_logged() {
+        assert_eq!(matching_codes(None).len(), ErrorCode::ALL.len());
+        assert_eq!(matching_codes(Some("[E01003]")), [ErrorCode::Download]);
+        assert_eq!(matching_codes(Some("e01003")), [ErrorCode::Download]);
+        assert!(matching_codes(Some("E00000")).is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+use clap::Parser;
+
+use crate::commands::{Cmd, CmdResult};
+use crate::DispatcherError;
+
+/// Annotated example configuration with all fields
+const EXAMPLE_CONFIG: &str = include_str!("../../example-config.yaml");
+
+/// Example configuration with only the required fields
+const MINIMAL_CONFIG: &str = include_str!("../../example-config-minimal.yaml");
+
+#[derive(Parser, Debug)]
+pub struct ExampleConfigOpt {
+    /// Print only this top-level section, e.g. sftp_sources or directory_targets
+    #[arg(long)]
+    section: Option<String>,
+
+    /// Print only the required fields
+    #[arg(long, conflicts_with = "section")]
+    minimal: bool,
+}
+
+impl Cmd for ExampleConfigOpt {
+    fn run(&self) -> CmdResult {
+        if self.minimal {
+            print!("{MINIMAL_CONFIG}");
+            return Ok(());
+        }
+
+        match &self.section {
+            Some(name) => {
+                let sections = sections(EXAMPLE_CONFIG);
+
+                match sections.iter().find(|(n, _)| n == name) {
+                    Some((_, content)) => print!("{content}"),
+                    None => {
+                        let names: Vec<&str> = sections.iter().map(|(n, _)| n.as_str()).collect();
+
+                        return Err(DispatcherError::Runtime(format!(
+                            "No section '{}', available sections: {}",
+                            name,
+                            names.join(", ")
+                        )));
+                    }
+                }
+            }
+            None => print!("{EXAMPLE_CONFIG}"),
+        }
+
+        Ok(())
+    }
+}
+
+/// Split a configuration template into its top-level sections
+///
+/// The comment block directly above a top-level key is part of its section.
+fn sections(template: &str) -> Vec<(String, String)> {
+    let mut sections: Vec<(String, String)> = Vec::new();
+    let mut comments = String::new();
+
+    for line in template.lines() {
+        if line.trim().is_empty() {
+            comments.clear();
+            continue;
+        }
+
+        if line.starts_with('#') {
+            comments.push_str(line);
+            comments.push('\n');
+            continue;
+        }
+
+        if !line.starts_with(' ') && !line.starts_with('-') {
+            if let Some((key, _)) = line.split_once(':') {
+                sections.push((key.to_string(), std::mem::take(&mut comments)));
+            }
+        }
+
+        if let Some((_, content)) = sections.last_mut() {
+            content.push_str(&comments);
+            content.push_str(line);
+            content.push('\n');
+        }
+
+        comments.clear();
+    }
+
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::commands::check_config::check_settings;
+    use crate::settings::load_settings_files;
+
+    fn check_round_trip(name: &str, config: &str) {
+        let path =
+            std::env::temp_dir().join(format!("cortex-{}-{}.yaml", name, std::process::id()));
+        std::fs::write(&path, config).unwrap();
+
+        let result = load_settings_files(&[path.to_str().unwrap().to_string()]);
+
+        std::fs::remove_file(&path).unwrap();
+
+        let settings = result.unwrap();
+
+        // Only the checks that do not depend on the local file system
+        let failed: Vec<String> = check_settings(&settings)
+            .into_iter()
+            .filter(|c| c.name == "settings" && !c.passed)
+            .filter_map(|c| c.message)
+            .collect();
+
+        assert!(failed.is_empty(), "{failed:?}");
+    }
+
+    #[test]
+    fn example_config_round_trips() {
+        check_round_trip("example", EXAMPLE_CONFIG);
+    }
+
+    #[test]
+    fn minimal_config_round_trips() {
+        check_round_trip("minimal", MINIMAL_CONFIG);
+    }
+
+    #[test]
+    fn split_sections() {
+        let sections = sections(EXAMPLE_CONFIG);
+
+        let names: Vec<&str> = sections.iter().map(|(n, _)| n.as_str()).collect();
+
+        assert_eq!(
+            names,
+            vec![
+                "storage",
+                "sqlite",
+                "command_queue",
+                "http_server",
+                "scan_interval",
+                "channels",
+                "audit",
+                "heartbeat_interval",
+                "error_log_window",
+                "logging",
+                "directory_sources",
+                "sftp_sources",
+                "directory_targets",
+                "notifiers",
+                "archive_targets",
+                "connections",
+                "event_publishers",
+                "reports"
+            ]
+        );
+
+        let (_, sftp_sources) = &sections[11];
+
+        assert!(sftp_sources.starts_with("# SFTP servers"));
+        assert!(sftp_sources.contains("    name: red\n"));
+        assert!(!sftp_sources.contains("directory_targets"));
+    }
+}
@@ -0,0 +1,378 @@
+use clap::{Args, Parser, Subcommand};
+
+use deadpool_lapin::lapin::message::Delivery;
+use deadpool_lapin::lapin::options::{BasicAckOptions, BasicGetOptions};
+use deadpool_lapin::lapin::types::AMQPValue;
+use deadpool_lapin::lapin::{BasicProperties, Channel};
+
+use cortex_core::{parse_command, SftpDownload};
+
+use crate::commands::{Cmd, CmdResult};
+use crate::control;
+use crate::settings::{self, DeadLetter, Settings};
+use crate::sftp_command_consumer::{self, ConfirmedPublish, FAILURE_REASON_HEADER};
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct FailedCommandsOpt {
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
+    #[arg(short, long, global = true)]
+    config: Vec<String>,
+
+    #[command(subcommand)]
+    command: FailedCommandsCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum FailedCommandsCommand {
+    #[command(about = "List the download commands in the dead-letter queue")]
+    List,
+    #[command(
+        about = "Publish download commands from the dead-letter queue to their command queue again"
+    )]
+    Retry(RetryOpt),
+}
+
+#[derive(Args, Debug)]
+struct RetryOpt {
+    /// Only retry commands of this SFTP source
+    #[arg(long)]
+    source: Option<String>,
+
+    /// Only show which commands would be retried
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// A message taken from the dead-letter queue, but not acknowledged yet
+struct FailedCommand {
+    delivery: Delivery,
+    command: Option<SftpDownload>,
+    reason: Option<String>,
+}
+
+impl FailedCommand {
+    fn from_delivery(delivery: Delivery) -> FailedCommand {
+        let command = parse_command(&delivery.data).ok();
+
+        let reason = delivery.properties.headers().as_ref().and_then(|headers| {
+            headers
+                .inner()
+                .iter()
+                .find(|(key, _)| key.as_str() == FAILURE_REASON_HEADER)
+                .and_then(|(_, value)| match value {
+                    AMQPValue::LongString(reason) => {
+                        Some(String::from_utf8_lossy(reason.as_bytes()).to_string())
+                    }
+                    _ => None,
+                })
+        });
+
+        FailedCommand {
+            delivery,
+            command,
+            reason,
+        }
+    }
+
+    fn print(&self) {
+        let reason = self.reason.as_deref().unwrap_or("-");
+
+        match &self.command {
+            Some(command) => println!(
+                "{:>10}  {}  {}  {}",
+                command.id, command.sftp_source, command.path, reason
+            ),
+            None => println!("{:>10}  <invalid message>  {}", "-", reason),
+        }
+    }
+}
+
+impl Cmd for FailedCommandsOpt {
+    fn run(&self) -> CmdResult {
+        let config_files = settings::config_paths(&self.config);
+
+        let settings =
+            settings::load_settings_files(&config_files).map_err(DispatcherError::Runtime)?;
+
+        let dead_letter = settings.command_queue.dead_letter.as_ref().ok_or_else(|| {
+            DispatcherError::Runtime(
+                "No command_queue.dead_letter settings in configuration".to_string(),
+            )
+        })?;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let channel = control::connect_channel(
+                settings.command_queue.address.expose(),
+                settings.command_queue.amqp_tls.as_ref(),
+            )
+            .await?;
+
+            sftp_command_consumer::declare_dead_letter(&channel, dead_letter).await?;
+
+            // Retried commands are only removed from the dead-letter queue
+            // once their command queue has them
+            sftp_command_consumer::enable_confirms(&channel).await?;
+
+            let failed_commands = fetch(&channel, dead_letter).await?;
+
+            match &self.command {
+                FailedCommandsCommand::List => {
+                    for failed_command in &failed_commands {
+                        failed_command.print();
+                    }
+
+                    println!("{} failed commands", failed_commands.len());
+
+                    Ok(())
+                }
+                FailedCommandsCommand::Retry(opt) => {
+                    retry(&channel, &settings, failed_commands, opt).await
+                }
+            }
+        })
+        .map_err(DispatcherError::Runtime)
+    }
+}
+
+/// Take all messages from the dead-letter queue
+///
+/// The messages are not acknowledged, so the broker puts back those that are
+/// not retried when the channel is closed.
+async fn fetch(channel: &Channel, dead_letter: &DeadLetter) -> Result<Vec<FailedCommand>, String> {
+    let mut failed_commands: Vec<FailedCommand> = Vec::new();
+
+    while let Some(message) = channel
+        .basic_get(&dead_letter.queue, BasicGetOptions { no_ack: false })
+        .await
+        .map_err(|e| {
+            format!(
+                "Error reading dead-letter queue '{}': {}",
+                &dead_letter.queue, e
+            )
+        })?
+    {
+        failed_commands.push(FailedCommand::from_delivery(message.delivery));
+    }
+
+    Ok(failed_commands)
+}
+
+async fn retry<P: ConfirmedPublish>(
+    publisher: &P,
+    settings: &Settings,
+    failed_commands: Vec<FailedCommand>,
+    opt: &RetryOpt,
+) -> Result<(), String> {
+    let mut retried: usize = 0;
+
+    for failed_command in failed_commands {
+        let command = match &failed_command.command {
+            Some(command) => command,
+            None => continue,
+        };
+
+        if opt
+            .source
+            .as_ref()
+            .is_some_and(|source| source != &command.sftp_source)
+        {
+            continue;
+        }
+
+        let route = match settings
+            .sftp_sources
+            .iter()
+            .find(|s| s.name == command.sftp_source)
+        {
+            Some(sftp_source) => sftp_source.command_route(),
+            None => {
+                println!(
+                    "{:>10}  skipped, no SFTP source named '{}' in configuration",
+                    command.id, command.sftp_source
+                );
+                continue;
+            }
+        };
+
+        failed_command.print();
+
+        if !opt.dry_run {
+            publisher
+                .publish_confirmed(
+                    &route.exchange,
+                    &route.routing_key,
+                    &failed_command.delivery.data,
+                    BasicProperties::default(),
+                )
+                .await?;
+
+            failed_command
+                .delivery
+                .acker
+                .ack(BasicAckOptions::default())
+                .await
+                .map_err(|e| format!("Error acknowledging failed command: {e}"))?;
+        }
+
+        retried += 1;
+    }
+
+    if opt.dry_run {
+        println!("{retried} failed commands to retry");
+    } else {
+        println!("Retried {retried} failed commands");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use chrono::Utc;
+    use deadpool_lapin::lapin::acker::Acker;
+
+    use crate::sftp_command_consumer::dead_letter_properties;
+
+    /// Publisher that records the routes of the published messages
+    #[derive(Default)]
+    struct RecordingPublisher {
+        published: Mutex<Vec<(String, String)>>,
+    }
+
+    #[async_trait]
+    impl ConfirmedPublish for RecordingPublisher {
+        async fn publish_confirmed(
+            &self,
+            exchange: &str,
+            routing_key: &str,
+            _data: &[u8],
+            _properties: BasicProperties,
+        ) -> Result<(), String> {
+            self.published
+                .lock()
+                .unwrap()
+                .push((exchange.to_string(), routing_key.to_string()));
+
+            Ok(())
+        }
+    }
+
+    fn delivery(data: Vec<u8>, properties: BasicProperties) -> Delivery {
+        Delivery {
+            delivery_tag: 1,
+            exchange: "cortex-dispatcher.dead-letter".into(),
+            routing_key: "".into(),
+            redelivered: false,
+            properties,
+            data,
+            acker: Acker::mock(),
+        }
+    }
+
+    /// A command as it is dead-lettered by the consumer
+    fn failed_command(id: i64, sftp_source: &str) -> FailedCommand {
+        let command = SftpDownload {
+            version: cortex_core::COMMAND_VERSION,
+            id,
+            created: Utc::now(),
+            size: None,
+            sftp_source: sftp_source.to_string(),
+            path: format!("file_{id}.csv"),
+            remove: false,
+            local_subpath: None,
+        };
+
+        let original = delivery(
+            cortex_core::command_payload(&command),
+            BasicProperties::default(),
+        );
+        let properties = dead_letter_properties(&original, "No such file");
+
+        FailedCommand::from_delivery(delivery(original.data, properties))
+    }
+
+    #[test]
+    fn dead_lettered_commands_are_listed_with_their_reason() {
+        let failed_command = failed_command(7, "red");
+
+        assert_eq!(failed_command.command.unwrap().id, 7);
+        assert_eq!(failed_command.reason.as_deref(), Some("No such file"));
+
+        let invalid = FailedCommand::from_delivery(delivery(
+            b"not a command".to_vec(),
+            BasicProperties::default(),
+        ));
+
+        assert!(invalid.command.is_none());
+        assert!(invalid.reason.is_none());
+    }
+
+    #[tokio::test]
+    async fn retried_commands_are_published_to_their_command_queue() {
+        let settings = Settings::default();
+        let publisher = RecordingPublisher::default();
+
+        let failed_commands = vec![
+            failed_command(1, "red"),
+            failed_command(2, "green"),
+            failed_command(3, "blue"),
+        ];
+        let ackers: Vec<Acker> = failed_commands
+            .iter()
+            .map(|c| c.delivery.acker.clone())
+            .collect();
+
+        retry(
+            &publisher,
+            &settings,
+            failed_commands,
+            &RetryOpt {
+                source: Some("red".to_string()),
+                dry_run: false,
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            *publisher.published.lock().unwrap(),
+            vec![("amq.direct".to_string(), "source.red".to_string())]
+        );
+        // Only the retried command is removed from the dead-letter queue
+        assert_eq!(
+            ackers.iter().map(Acker::usable).collect::<Vec<_>>(),
+            vec![false, true, true]
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_run_retries_nothing() {
+        let publisher = RecordingPublisher::default();
+        let failed_command = failed_command(1, "red");
+        let acker = failed_command.delivery.acker.clone();
+
+        retry(
+            &publisher,
+            &Settings::default(),
+            vec![failed_command],
+            &RetryOpt {
+                source: None,
+                dry_run: true,
+            },
+        )
+        .await
+        .unwrap();
+
+        assert!(publisher.published.lock().unwrap().is_empty());
+        assert!(acker.usable());
+    }
+}
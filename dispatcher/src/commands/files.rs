@@ -0,0 +1,268 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+
+use crate::commands::{Cmd, CmdResult};
+use crate::control::{self, ControlCommand};
+use crate::persistence::{FileQuery, FileRecord, SqlitePersistence};
+use crate::settings::{self, Settings};
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct FilesOpt {
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
+    #[arg(short, long, global = true)]
+    config: Vec<String>,
+
+    #[command(subcommand)]
+    command: FilesCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum FilesCommand {
+    #[command(about = "List files in internal storage")]
+    List(ListOpt),
+    #[command(about = "Dispatch files from internal storage again")]
+    Redispatch(RedispatchOpt),
+}
+
+#[derive(Args, Debug, Default)]
+struct FileFilter {
+    /// Only files from this source
+    #[arg(long)]
+    source: Option<String>,
+
+    /// Only files registered at or after this time (RFC 3339, 'YYYY-MM-DD HH:MM:SS' or 'YYYY-MM-DD', UTC)
+    #[arg(long, value_parser = parse_timestamp)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only files with a path matching this SQL LIKE pattern
+    #[arg(long)]
+    path_like: Option<String>,
+
+    /// Only files that have not been dispatched to this target
+    #[arg(long)]
+    undispatched_to: Option<String>,
+}
+
+impl FileFilter {
+    fn is_empty(&self) -> bool {
+        self.source.is_none()
+            && self.since.is_none()
+            && self.path_like.is_none()
+            && self.undispatched_to.is_none()
+    }
+
+    fn query(&self) -> FileQuery {
+        FileQuery {
+            ids: None,
+            source: self.source.clone(),
+            since: self.since,
+            path_like: self.path_like.clone(),
+            undispatched_to: self.undispatched_to.clone(),
+            include_deleted: false,
+            until: None,
+            without_hash: false,
+            after_id: None,
+            limit: None,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct ListOpt {
+    #[command(flatten)]
+    filter: FileFilter,
+
+    /// Also list files that have been removed from internal storage
+    #[arg(long)]
+    include_deleted: bool,
+
+    /// Print the files as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct RedispatchOpt {
+    /// Id of a file to redispatch, can be given multiple times
+    #[arg(long = "id")]
+    ids: Vec<i64>,
+
+    /// File with one file id per line
+    #[arg(long)]
+    ids_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    filter: FileFilter,
+
+    /// Only show which files would be redispatched
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl Cmd for FilesOpt {
+    fn run(&self) -> CmdResult {
+        let config_files = settings::config_paths(&self.config);
+
+        let settings =
+            settings::load_settings_files(&config_files).map_err(DispatcherError::Runtime)?;
+
+        match &self.command {
+            FilesCommand::List(opt) => list(&settings, opt),
+            FilesCommand::Redispatch(opt) => redispatch(&settings, opt),
+        }
+    }
+}
+
+/// Parse a timestamp given on the command line, interpreted as UTC when no
+/// offset is given.
+pub fn parse_timestamp(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+
+    if let Ok(timestamp) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+        return Ok(timestamp.and_utc());
+    }
+
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("Invalid timestamp '{value}'"))
+}
+
+/// Open the database of the service without running migrations
+pub fn open_persistence(settings: &Settings) -> Result<SqlitePersistence, DispatcherError> {
+    if !settings.sqlite.path.exists() {
+        return Err(DispatcherError::Runtime(format!(
+            "Database '{}' does not exist",
+            settings.sqlite.path.to_string_lossy()
+        )));
+    }
+
+    let conn = rusqlite::Connection::open(&settings.sqlite.path)
+        .map_err(|e| DispatcherError::Runtime(format!("Could not open database: {e}")))?;
+
+    Ok(SqlitePersistence::from_arc(Arc::new(Mutex::new(conn))))
+}
+
+fn list(settings: &Settings, opt: &ListOpt) -> CmdResult {
+    let persistence = open_persistence(settings)?;
+
+    let query = FileQuery {
+        include_deleted: opt.include_deleted,
+        ..opt.filter.query()
+    };
+
+    let files = persistence
+        .query_files(&query)
+        .map_err(|e| DispatcherError::Runtime(format!("Could not query files: {e}")))?;
+
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&files).unwrap());
+    } else {
+        print_files(&files);
+    }
+
+    Ok(())
+}
+
+fn print_files(files: &[FileRecord]) {
+    println!(
+        "{:>10}  {:<19}  {:<20}  {:>12}  PATH",
+        "ID", "TIMESTAMP", "SOURCE", "SIZE"
+    );
+
+    for file in files {
+        println!(
+            "{:>10}  {:<19}  {:<20}  {:>12}  {}{}",
+            file.id,
+            file.timestamp,
+            file.source,
+            file.size,
+            file.path,
+            if file.deleted.is_some() {
+                " (deleted)"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+fn read_ids_file(path: &PathBuf) -> Result<Vec<i64>, DispatcherError> {
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        DispatcherError::Runtime(format!(
+            "Could not read '{}': {}",
+            path.to_string_lossy(),
+            e
+        ))
+    })?;
+
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.parse::<i64>()
+                .map_err(|e| DispatcherError::Runtime(format!("Invalid file id '{line}': {e}")))
+        })
+        .collect()
+}
+
+fn redispatch(settings: &Settings, opt: &RedispatchOpt) -> CmdResult {
+    let mut ids = opt.ids.clone();
+
+    if let Some(ids_file) = &opt.ids_file {
+        ids.append(&mut read_ids_file(ids_file)?);
+    }
+
+    if ids.is_empty() && opt.filter.is_empty() {
+        return Err(DispatcherError::Runtime(
+            "No files selected, specify ids or at least one filter".to_string(),
+        ));
+    }
+
+    let mut query = opt.filter.query();
+
+    if !ids.is_empty() {
+        query.ids = Some(ids);
+    }
+
+    let persistence = open_persistence(settings)?;
+
+    let files = persistence
+        .query_files(&query)
+        .map_err(|e| DispatcherError::Runtime(format!("Could not query files: {e}")))?;
+
+    if opt.dry_run {
+        print_files(&files);
+        return Ok(());
+    }
+
+    if files.is_empty() {
+        println!("No files to redispatch");
+        return Ok(());
+    }
+
+    let command = ControlCommand::Redispatch {
+        file_ids: files.iter().map(|file| file.id).collect(),
+    };
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    rt.block_on(control::send_command(
+        settings.command_queue.address.expose(),
+        settings.command_queue.amqp_tls.as_ref(),
+        &settings.command_queue.control_queue,
+        &command,
+    ))
+    .map_err(DispatcherError::Runtime)?;
+
+    println!("Requested redispatch of {} files", files.len());
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use rusqlite::OpenFlags;
+
+use crate::commands::{Cmd, CmdResult};
+use crate::settings::{self, Settings};
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct InitDatabaseOpt {
+    /// Path to config file to take the database path from, or to a directory
+    /// of *.yaml files, which can be given multiple times to merge the files
+    /// in order
+    #[arg(short, long, conflicts_with = "path")]
+    config: Vec<String>,
+
+    /// Path to the SQLite database file
+    #[arg(short, long)]
+    path: Option<PathBuf>,
+
+    /// Print the SQL of the pending migrations without applying them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Load files and dispatched records from a YAML or JSON fixture, creating
+    /// the files in the configured storage directory
+    #[arg(long, conflicts_with_all = ["path", "dry_run"])]
+    seed: Option<PathBuf>,
+}
+
+impl InitDatabaseOpt {
+    fn settings(&self) -> Result<Settings, DispatcherError> {
+        let config_files = settings::config_paths(&self.config);
+
+        settings::load_settings_files(&config_files).map_err(DispatcherError::Runtime)
+    }
+
+    fn database_path(&self) -> Result<PathBuf, DispatcherError> {
+        if let Some(path) = &self.path {
+            return Ok(path.clone());
+        }
+
+        self.settings().map(|settings| settings.sqlite.path)
+    }
+}
+
+impl Cmd for InitDatabaseOpt {
+    fn run(&self) -> CmdResult {
+        let db_path = self.database_path()?;
+
+        if self.dry_run {
+            let pending = if db_path.exists() {
+                let conn = rusqlite::Connection::open_with_flags(
+                    &db_path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY,
+                )
+                .map_err(|e| DispatcherError::Runtime(format!("Could not open database: {e}")))?;
+
+                cortex_core::pending_migrations(&conn).map_err(DispatcherError::Runtime)?
+            } else {
+                let conn = rusqlite::Connection::open_in_memory().map_err(|e| {
+                    DispatcherError::Runtime(format!("Could not open database: {e}"))
+                })?;
+
+                cortex_core::pending_migrations(&conn).map_err(DispatcherError::Runtime)?
+            };
+
+            if pending.is_empty() {
+                println!("-- Database schema is up to date");
+            }
+
+            for (name, sql) in pending {
+                println!("-- Migration {name}");
+                println!("{sql}");
+            }
+
+            return Ok(());
+        }
+
+        if let Some(parent) = db_path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent).map_err(|e| {
+                    DispatcherError::Runtime(format!(
+                        "Could not create directory '{}': {}",
+                        parent.to_string_lossy(),
+                        e
+                    ))
+                })?;
+            }
+        }
+
+        let mut conn = rusqlite::Connection::open(&db_path)
+            .map_err(|e| DispatcherError::Runtime(format!("Could not open database: {e}")))?;
+
+        let applied = cortex_core::run_migrations(&mut conn).map_err(DispatcherError::Runtime)?;
+
+        if applied.is_empty() {
+            println!(
+                "Database schema of '{}' is up to date",
+                db_path.to_string_lossy()
+            );
+        }
+
+        for name in applied {
+            println!("Applied migration {name}");
+        }
+
+        if let Some(fixture_path) = &self.seed {
+            // Fixtures are seeded into the first storage area
+            let storage_dir = self.settings()?.storage[0].directory.clone();
+
+            let count = crate::seed::seed_from_file(&db_path, &storage_dir, fixture_path)
+                .map_err(DispatcherError::Runtime)?;
+
+            println!("Seeded {count} files");
+        }
+
+        Ok(())
+    }
+}
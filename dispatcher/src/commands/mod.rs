@@ -1,7 +1,20 @@
 use thiserror::Error;
 
+pub mod backfill_hashes;
+pub mod check_config;
 pub mod dev_stack;
+pub mod doctor;
+pub mod errors;
+pub mod example_config;
+pub mod failed_commands;
+pub mod files;
+pub mod init_database;
+pub mod reconcile;
+pub mod run_once;
 pub mod service;
+pub mod sftp_downloads;
+pub mod sources;
+pub mod spool;
 
 #[derive(Error, Debug)]
 pub enum DispatcherError {
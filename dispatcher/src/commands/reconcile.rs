@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use clap::Parser;
+
+use crate::commands::files::open_persistence;
+use crate::commands::{Cmd, CmdResult};
+use crate::control::{self, ControlCommand};
+use crate::persistence::FileRecord;
+use crate::reconcile::reconcile;
+use crate::settings;
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct ReconcileOpt {
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
+    #[arg(short, long)]
+    config: Vec<String>,
+
+    /// Number of hours back in which registered files are checked, instead
+    /// of the reconcile.window_hours setting
+    #[arg(long)]
+    window_hours: Option<u64>,
+
+    /// Only show which files would be redispatched
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl Cmd for ReconcileOpt {
+    fn run(&self) -> CmdResult {
+        let config_files = settings::config_paths(&self.config);
+
+        let settings =
+            settings::load_settings_files(&config_files).map_err(DispatcherError::Runtime)?;
+
+        let reconcile_conf = settings.reconcile.clone().unwrap_or_default();
+
+        let window_hours = self.window_hours.unwrap_or(reconcile_conf.window_hours);
+
+        let since = Utc::now() - chrono::Duration::hours(window_hours as i64);
+
+        let persistence = open_persistence(&settings)?;
+
+        let reconciliation =
+            reconcile(&persistence, &settings, since).map_err(DispatcherError::Runtime)?;
+
+        for file in &reconciliation.missing {
+            print_file("missing", file, "");
+        }
+
+        for file in &reconciliation.mismatched {
+            print_file("hash mismatch", file, "");
+        }
+
+        for (file, targets) in &reconciliation.undispatched {
+            print_file("undispatched", file, &targets.join(", "));
+        }
+
+        if self.dry_run || reconciliation.undispatched.is_empty() {
+            println!(
+                "{} files to redispatch, {} missing, {} with a hash mismatch",
+                reconciliation.undispatched.len(),
+                reconciliation.missing.len(),
+                reconciliation.mismatched.len()
+            );
+
+            return Ok(());
+        }
+
+        let file_ids: Vec<i64> = reconciliation
+            .undispatched
+            .iter()
+            .map(|(file, _)| file.id)
+            .collect();
+
+        // The running dispatcher redispatches a batch of files per second
+        let batch_size = (reconcile_conf.files_per_second.ceil() as usize).max(1);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let queue = &settings.command_queue.control_queue;
+
+            let channel = control::connect_channel(
+                settings.command_queue.address.expose(),
+                settings.command_queue.amqp_tls.as_ref(),
+            )
+            .await?;
+
+            control::declare_control_queue(&channel, queue).await?;
+
+            for (index, batch) in file_ids.chunks(batch_size).enumerate() {
+                if index > 0 {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+
+                let command = ControlCommand::Redispatch {
+                    file_ids: batch.to_vec(),
+                };
+
+                let payload = serde_json::to_vec(&command)
+                    .map_err(|e| format!("Error serializing control command: {e}"))?;
+
+                control::publish(&channel, "", queue, &payload).await?;
+            }
+
+            Ok::<(), String>(())
+        })
+        .map_err(DispatcherError::Runtime)?;
+
+        println!(
+            "Requested redispatch of {} files, {} missing, {} with a hash mismatch",
+            file_ids.len(),
+            reconciliation.missing.len(),
+            reconciliation.mismatched.len()
+        );
+
+        Ok(())
+    }
+}
+
+fn print_file(status: &str, file: &FileRecord, targets: &str) {
+    println!(
+        "{:>10}  {:<14}  {:<20}  {}  {}",
+        file.id, status, file.source, file.path, targets
+    );
+}
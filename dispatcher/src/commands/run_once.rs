@@ -0,0 +1,150 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use clap::Parser;
+
+use cortex_core::remote_fs::{RemoteFs, SftpFs};
+
+use crate::commands::{Cmd, CmdResult};
+use crate::local_storage::{source_placements, storage_areas, LocalStorage};
+use crate::path_lock::PathLocks;
+use crate::persistence::{Persistence, SqliteAsyncPersistence, SqlitePersistence};
+use crate::readiness::Readiness;
+use crate::remote_removal::RemoteRemovals;
+use crate::run_once::{remote_files, RunOnce, Summary};
+use crate::settings::{self, SftpBackend};
+use crate::sftp_downloader::SftpDownloader;
+use crate::DispatcherError;
+
+/// Files that were downloaded but not dispatched are skipped by a next run,
+/// and are dispatched by `reconcile`.
+#[derive(Parser, Debug)]
+pub struct RunOnceOpt {
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
+    #[arg(short, long)]
+    config: Vec<String>,
+
+    /// Name of the SFTP source to download from
+    #[arg(long)]
+    source: String,
+
+    /// Remote directory of which the files are downloaded, including those
+    /// in subdirectories
+    #[arg(long, default_value = ".")]
+    remote_path: PathBuf,
+
+    /// Remove the remote files after they are downloaded
+    #[arg(long)]
+    remove: bool,
+}
+
+impl RunOnceOpt {
+    fn download<R, T>(
+        &self,
+        fs: &R,
+        run_once: &mut RunOnce<T>,
+        rt: &tokio::runtime::Runtime,
+    ) -> Result<Summary, DispatcherError>
+    where
+        R: RemoteFs,
+        T: Persistence + Send + Clone + 'static,
+    {
+        let files = remote_files(fs, &self.remote_path).map_err(DispatcherError::Runtime)?;
+
+        Ok(run_once.run(fs, files, self.remove, rt))
+    }
+}
+
+impl Cmd for RunOnceOpt {
+    fn run(&self) -> CmdResult {
+        let config_files = settings::config_paths(&self.config);
+
+        let settings =
+            settings::load_settings_files(&config_files).map_err(DispatcherError::Runtime)?;
+
+        let sftp_source = settings
+            .sftp_sources
+            .iter()
+            .find(|sftp_source| sftp_source.name == self.source)
+            .ok_or_else(|| {
+                DispatcherError::Runtime(format!("No SFTP source named '{}'", self.source))
+            })?
+            .clone();
+
+        let mut conn = rusqlite::Connection::open(&settings.sqlite.path)
+            .map_err(|e| DispatcherError::Runtime(format!("Could not open database: {e}")))?;
+
+        cortex_core::run_migrations(&mut conn).map_err(DispatcherError::Runtime)?;
+
+        let conn = Arc::new(Mutex::new(conn));
+        let persistence = SqlitePersistence::from_arc(conn.clone());
+
+        let local_storage = LocalStorage::new(
+            storage_areas(&settings, &Readiness::default()),
+            persistence.clone(),
+        )
+        .with_placements(source_placements(&settings));
+
+        let connection_error = |e: anyhow::Error| {
+            DispatcherError::Runtime(format!(
+                "Could not connect to SFTP source '{}': {e}",
+                sftp_source.name
+            ))
+        };
+
+        let sftp_config = sftp_source.sftp_config();
+        let backend = sftp_source.backend;
+
+        let downloader = SftpDownloader {
+            sftp_source: sftp_source.clone(),
+            persistence,
+            local_storage,
+            path_locks: PathLocks::default(),
+            remote_removals: RemoteRemovals::new(std::slice::from_ref(&sftp_source)),
+            write_behind: None,
+            connections: None,
+        };
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let mut run_once = RunOnce::new(&settings, downloader, SqliteAsyncPersistence::new(conn));
+
+        let summary = match backend {
+            SftpBackend::Libssh2 => {
+                let fs = sftp_config
+                    .connect()
+                    .and_then(SftpFs::new)
+                    .map_err(connection_error)?;
+
+                self.download(&fs, &mut run_once, &rt)?
+            }
+            #[cfg(feature = "openssh")]
+            SftpBackend::Openssh => {
+                let fs = sftp_config.connect_openssh().map_err(connection_error)?;
+
+                self.download(&fs, &mut run_once, &rt)?
+            }
+            #[cfg(not(feature = "openssh"))]
+            SftpBackend::Openssh => {
+                return Err(DispatcherError::Runtime(
+                    "The openssh backend requires the openssh feature".to_string(),
+                ))
+            }
+        };
+
+        println!(
+            "{} files downloaded, {} skipped as duplicates, {} failed",
+            summary.downloaded, summary.skipped, summary.failed
+        );
+
+        if summary.failed > 0 {
+            return Err(DispatcherError::Runtime(format!(
+                "{} files failed",
+                summary.failed
+            )));
+        }
+
+        Ok(())
+    }
+}
@@ -1,68 +1,63 @@
-use std::io::Write;
-
 use clap::Parser;
-use log::{error, info};
+use log::{info, warn};
 
 use crate::commands::{Cmd, CmdResult};
 use crate::dispatcher;
+use crate::logging;
 use crate::DispatcherError;
 
 #[derive(Parser, Debug)]
 pub struct ServiceOpt {
-    /// Path to config file
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
     #[arg(short, long)]
-    config: Option<String>,
+    config: Vec<String>,
 
-    /// Show example config
-    #[arg(short, long)]
-    example_config: bool,
+    /// Only log what would be done, without storing or placing files,
+    /// changing the database or publishing to AMQP
+    #[arg(long)]
+    dry_run: bool,
 }
 
 impl Cmd for ServiceOpt {
     fn run(&self) -> CmdResult {
-        let mut env_logger_builder = env_logger::builder();
+        let config_files = crate::settings::config_paths(&self.config);
 
-        env_logger_builder
-            .format(|buf, record| writeln!(buf, "{}  {}", record.level(), record.args()));
+        // The logger depends on the configuration, so it can only be
+        // initialized after loading it
+        let settings = match crate::settings::load_settings_files(&config_files) {
+            Ok(settings) => settings,
+            Err(e) => {
+                eprintln!("{}", e);
+                ::std::process::exit(1);
+            }
+        };
 
-        env_logger_builder.init();
+        let problems = settings.validate();
 
-        if self.example_config {
-            println!(
-                "{}",
-                serde_yaml_ng::to_string(&crate::settings::Settings::default()).unwrap()
-            );
-            ::std::process::exit(0);
+        if !problems.is_empty() {
+            for problem in &problems {
+                eprintln!("{}", problem);
+            }
+            ::std::process::exit(1);
         }
 
-        let config_file = self
-            .config
-            .clone()
-            .unwrap_or("/etc/cortex/cortex.yaml".into());
-
-        info!("Loading configuration");
+        logging::init(&settings.logging, self.dry_run);
 
-        let merge_result = config::Config::builder()
-            .add_source(config::File::new(&config_file, config::FileFormat::Yaml))
-            .build();
-
-        let settings = match merge_result {
-            Ok(config) => {
-                info!("Configuration loaded from file {}", config_file);
+        if self.dry_run {
+            warn!("****************************************************************");
+            warn!("DRY RUN: no files are stored or placed, the database is not");
+            warn!("changed and nothing is published to AMQP");
+            warn!("****************************************************************");
+        }
 
-                config.try_deserialize().unwrap()
-            }
-            Err(e) => {
-                error!("Error merging configuration: {}", e);
-                ::std::process::exit(1);
-            }
-        };
+        info!("Configuration loaded from {}", config_files.join(", "));
 
-        info!("Configuration loaded");
+        crate::settings::log_env_overrides();
 
         let rt = tokio::runtime::Runtime::new().unwrap();
 
-        let result = rt.block_on(dispatcher::run(settings));
+        let result = rt.block_on(dispatcher::run(settings, config_files, self.dry_run));
 
         match result {
             Ok(_) => Ok(()),
@@ -0,0 +1,129 @@
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+
+use cortex_core::{SftpDownload, COMMAND_VERSION};
+
+use crate::commands::files::{open_persistence, parse_timestamp};
+use crate::commands::{Cmd, CmdResult};
+use crate::control;
+use crate::settings;
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct SftpDownloadsOpt {
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
+    #[arg(short, long, global = true)]
+    config: Vec<String>,
+
+    #[command(subcommand)]
+    command: SftpDownloadsCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum SftpDownloadsCommand {
+    #[command(about = "Publish download commands again for downloads that did not complete")]
+    Requeue(RequeueOpt),
+}
+
+#[derive(Args, Debug)]
+struct RequeueOpt {
+    /// Name of the SFTP source
+    #[arg(long)]
+    source: String,
+
+    /// Only downloads registered at or after this time (RFC 3339, 'YYYY-MM-DD HH:MM:SS' or 'YYYY-MM-DD', UTC)
+    #[arg(long, value_parser = parse_timestamp)]
+    failed_since: Option<DateTime<Utc>>,
+
+    /// Remove the files from the SFTP server after download
+    #[arg(long)]
+    remove: bool,
+
+    /// Only show which downloads would be requeued
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl Cmd for SftpDownloadsOpt {
+    fn run(&self) -> CmdResult {
+        let config_files = settings::config_paths(&self.config);
+
+        let settings =
+            settings::load_settings_files(&config_files).map_err(DispatcherError::Runtime)?;
+
+        match &self.command {
+            SftpDownloadsCommand::Requeue(opt) => requeue(&settings, opt),
+        }
+    }
+}
+
+fn requeue(settings: &settings::Settings, opt: &RequeueOpt) -> CmdResult {
+    let sftp_source = settings
+        .sftp_sources
+        .iter()
+        .find(|s| s.name == opt.source)
+        .ok_or_else(|| {
+            DispatcherError::Runtime(format!(
+                "No SFTP source named '{}' in configuration",
+                opt.source
+            ))
+        })?;
+
+    let persistence = open_persistence(settings)?;
+
+    let downloads = persistence
+        .failed_sftp_downloads(&opt.source, opt.failed_since.as_ref())
+        .map_err(|e| DispatcherError::Runtime(format!("Could not query downloads: {e}")))?;
+
+    if opt.dry_run || downloads.is_empty() {
+        for download in &downloads {
+            println!(
+                "{:>10}  {}  {}",
+                download.id, download.timestamp, download.path
+            );
+        }
+
+        println!("{} downloads to requeue", downloads.len());
+
+        return Ok(());
+    }
+
+    let route = sftp_source.command_route();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    let published = rt
+        .block_on(async {
+            let channel = control::connect_channel(
+                settings.command_queue.address.expose(),
+                settings.command_queue.amqp_tls.as_ref(),
+            )
+            .await?;
+
+            for download in &downloads {
+                let command = SftpDownload {
+                    version: COMMAND_VERSION,
+                    id: download.id,
+                    created: Utc::now(),
+                    size: download.size.map(|size| size as u64),
+                    sftp_source: download.source.clone(),
+                    path: download.path.clone(),
+                    remove: opt.remove,
+                    local_subpath: None,
+                };
+
+                let payload = serde_json::to_vec(&command)
+                    .map_err(|e| format!("Error serializing download command: {e}"))?;
+
+                control::publish(&channel, &route.exchange, &route.routing_key, &payload).await?;
+            }
+
+            Ok::<usize, String>(downloads.len())
+        })
+        .map_err(DispatcherError::Runtime)?;
+
+    println!("Requeued {published} downloads");
+
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+
+use crate::commands::{Cmd, CmdResult};
+use crate::settings;
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct SourcesOpt {
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
+    #[arg(short, long, global = true)]
+    config: Vec<String>,
+
+    /// Base URL of the running dispatcher, by default derived from the http_server address in the configuration
+    #[arg(long, global = true)]
+    url: Option<String>,
+
+    #[command(subcommand)]
+    command: SourcesCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum SourcesCommand {
+    #[command(about = "List the sources of the running dispatcher")]
+    List(ListOpt),
+    #[command(about = "Pause intake from a source")]
+    Pause(SourceArg),
+    #[command(about = "Resume intake from a paused source")]
+    Resume(SourceArg),
+}
+
+#[derive(Args, Debug)]
+struct ListOpt {
+    /// Print the sources as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct SourceArg {
+    /// Name of the source
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SourceStatus {
+    name: String,
+    kind: String,
+    paused: bool,
+}
+
+impl Cmd for SourcesOpt {
+    fn run(&self) -> CmdResult {
+        let base_url = match &self.url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => {
+                let config_files = settings::config_paths(&self.config);
+
+                let settings = settings::load_settings_files(&config_files)
+                    .map_err(DispatcherError::Runtime)?;
+
+                base_url(settings.http_server.address)
+            }
+        };
+
+        match &self.command {
+            SourcesCommand::List(opt) => list(&base_url, opt),
+            SourcesCommand::Pause(arg) => set_paused(&base_url, &arg.name, "pause"),
+            SourcesCommand::Resume(arg) => set_paused(&base_url, &arg.name, "resume"),
+        }
+    }
+}
+
+/// URL at which the HTTP server is reachable from this host
+fn base_url(mut address: std::net::SocketAddr) -> String {
+    if address.ip().is_unspecified() {
+        address.set_ip(match address.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+
+    format!("http://{address}")
+}
+
+fn list(base_url: &str, opt: &ListOpt) -> CmdResult {
+    let body = request(ureq::get(format!("{base_url}/api/sources")).call())?;
+
+    if opt.json {
+        println!("{}", body.trim_end());
+        return Ok(());
+    }
+
+    let sources: Vec<SourceStatus> = serde_json::from_str(&body)
+        .map_err(|e| DispatcherError::Runtime(format!("Could not parse sources: {e}")))?;
+
+    println!("{:<20}  {:<9}  STATE", "SOURCE", "KIND");
+
+    for source in sources {
+        println!(
+            "{:<20}  {:<9}  {}",
+            source.name,
+            source.kind,
+            if source.paused { "paused" } else { "running" }
+        );
+    }
+
+    Ok(())
+}
+
+fn set_paused(base_url: &str, name: &str, action: &str) -> CmdResult {
+    let body = match ureq::post(format!("{base_url}/api/sources/{name}/{action}")).send_empty() {
+        Err(ureq::Error::StatusCode(404)) => {
+            return Err(DispatcherError::Runtime(format!(
+                "No source named '{name}' in the running dispatcher"
+            )))
+        }
+        result => request(result)?,
+    };
+
+    let source: SourceStatus = serde_json::from_str(&body)
+        .map_err(|e| DispatcherError::Runtime(format!("Could not parse source: {e}")))?;
+
+    println!(
+        "Source '{}' is {}",
+        source.name,
+        if source.paused { "paused" } else { "running" }
+    );
+
+    Ok(())
+}
+
+/// Body of a successful response
+fn request(
+    result: Result<ureq::http::Response<ureq::Body>, ureq::Error>,
+) -> Result<String, DispatcherError> {
+    let mut response =
+        result.map_err(|e| DispatcherError::Runtime(format!("Could not reach dispatcher: {e}")))?;
+
+    response
+        .body_mut()
+        .read_to_string()
+        .map_err(|e| DispatcherError::Runtime(format!("Could not read response: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_url_of_unspecified_address_is_localhost() {
+        assert_eq!(
+            base_url("0.0.0.0:56008".parse().unwrap()),
+            "http://127.0.0.1:56008"
+        );
+        assert_eq!(
+            base_url("10.1.2.3:56008".parse().unwrap()),
+            "http://10.1.2.3:56008"
+        );
+    }
+}
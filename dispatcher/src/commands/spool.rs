@@ -0,0 +1,131 @@
+use clap::{Args, Parser, Subcommand};
+
+use crate::commands::{Cmd, CmdResult};
+use crate::notification_spool::{spool_directory, Spool};
+use crate::notifier::RabbitMQNotifier;
+use crate::settings::{self, Notify, Settings};
+use crate::DispatcherError;
+
+#[derive(Parser, Debug)]
+pub struct SpoolOpt {
+    /// Path to config file, or to a directory of *.yaml files, which can be
+    /// given multiple times to merge the files in order
+    #[arg(short, long, global = true)]
+    config: Vec<String>,
+
+    #[command(subcommand)]
+    command: SpoolCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum SpoolCommand {
+    #[command(about = "Show the number of spooled notifications per target")]
+    Status,
+    #[command(about = "Publish the spooled notifications")]
+    Replay(ReplayOpt),
+}
+
+#[derive(Args, Debug)]
+struct ReplayOpt {
+    /// Only publish the notifications of this target
+    #[arg(long)]
+    target: Option<String>,
+}
+
+impl Cmd for SpoolOpt {
+    fn run(&self) -> CmdResult {
+        let config_files = settings::config_paths(&self.config);
+
+        let settings =
+            settings::load_settings_files(&config_files).map_err(DispatcherError::Runtime)?;
+
+        match &self.command {
+            SpoolCommand::Status => status(&settings),
+            SpoolCommand::Replay(opt) => replay(&settings, opt),
+        }
+        .map_err(DispatcherError::Runtime)
+    }
+}
+
+/// The spool and notifier of each directory target with RabbitMQ
+/// notifications
+fn spooled_targets(settings: &Settings) -> Result<Vec<(Spool, RabbitMQNotifier)>, String> {
+    let notification_spool = settings
+        .notification_spool
+        .as_ref()
+        .ok_or_else(|| "No notification_spool settings in configuration".to_string())?;
+
+    let directory = spool_directory(settings);
+
+    Ok(settings
+        .directory_targets
+        .iter()
+        .filter_map(
+            |target| match target.notify.as_ref()?.resolve(&settings.notifiers)? {
+                Notify::RabbitMQ(notify_conf) => {
+                    let spool = Spool::new(&directory, &target.name, notification_spool);
+
+                    let mut notifier = RabbitMQNotifier::from(&notify_conf);
+                    notifier.spool = Some(spool.clone());
+
+                    Some((spool, notifier))
+                }
+                _ => None,
+            },
+        )
+        .collect())
+}
+
+fn status(settings: &Settings) -> Result<(), String> {
+    println!("{:<20}  {:>13}  OLDEST", "TARGET", "NOTIFICATIONS");
+
+    for (spool, _) in spooled_targets(settings)? {
+        let notifications = spool.lock()?.notifications()?;
+
+        println!(
+            "{:<20}  {:>13}  {}",
+            spool.target,
+            notifications.len(),
+            notifications
+                .first()
+                .map(|notification| notification.spooled.to_rfc3339())
+                .unwrap_or("-".to_string())
+        );
+    }
+
+    Ok(())
+}
+
+fn replay(settings: &Settings, opt: &ReplayOpt) -> Result<(), String> {
+    let targets: Vec<(Spool, RabbitMQNotifier)> = spooled_targets(settings)?
+        .into_iter()
+        .filter(|(spool, _)| {
+            opt.target
+                .as_ref()
+                .is_none_or(|target| *target == spool.target)
+        })
+        .collect();
+
+    if let Some(target) = &opt.target {
+        if targets.is_empty() {
+            return Err(format!(
+                "No directory target '{target}' with RabbitMQ notifications"
+            ));
+        }
+    }
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    rt.block_on(async {
+        for (spool, mut notifier) in targets {
+            let (published, left) = notifier.replay_spool().await?;
+
+            println!(
+                "Published {published} notifications of target '{}', {left} left in the spool",
+                spool.target
+            );
+        }
+
+        Ok(())
+    })
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use deadpool_lapin::lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions,
+    BasicRejectOptions, QueueDeclareOptions,
+};
+use deadpool_lapin::lapin::types::FieldTable;
+use deadpool_lapin::lapin::{BasicProperties, Channel};
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::amqp;
+use crate::event::{FileEvent, FileEventSender};
+use crate::persistence::{FileQuery, SqliteAsyncPersistence};
+use crate::settings::AmqpTls;
+use crate::sftp_command_consumer::Backoff;
+
+/// Delay before a command of which the redispatch failed is delivered again
+const REDISPATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Commands that can be sent to a running dispatcher over the control queue
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ControlCommand {
+    /// Dispatch files from internal storage again to the targets connected to
+    /// their source
+    Redispatch { file_ids: Vec<i64> },
+}
+
+pub async fn connect_channel(address: &str, tls: Option<&AmqpTls>) -> Result<Channel, String> {
+    let connection = amqp::connect(address, tls).await?;
+
+    connection
+        .create_channel()
+        .await
+        .map_err(|e| format!("Error creating AMQP channel: {e}"))
+}
+
+pub async fn declare_control_queue(channel: &Channel, queue: &str) -> Result<(), String> {
+    channel
+        .queue_declare(
+            queue,
+            QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Error declaring control queue '{queue}': {e}"))
+}
+
+pub async fn publish(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    payload: &[u8],
+) -> Result<(), String> {
+    channel
+        .basic_publish(
+            exchange,
+            routing_key,
+            BasicPublishOptions::default(),
+            payload,
+            BasicProperties::default(),
+        )
+        .await
+        .map_err(|e| format!("Error publishing message: {e}"))?
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Error publishing message: {e}"))
+}
+
+/// Publish a control command to the control queue of a running dispatcher
+pub async fn send_command(
+    address: &str,
+    tls: Option<&AmqpTls>,
+    queue: &str,
+    command: &ControlCommand,
+) -> Result<(), String> {
+    let channel = connect_channel(address, tls).await?;
+
+    declare_control_queue(&channel, queue).await?;
+
+    let payload = serde_json::to_vec(command)
+        .map_err(|e| format!("Error serializing control command: {e}"))?;
+
+    publish(&channel, "", queue, &payload).await
+}
+
+/// Consume control commands and execute them, reconnecting with backoff
+/// when the connection is lost, until a stop is signalled
+///
+/// The senders are the file event channels of all sources, used for
+/// re-dispatching files from internal storage.
+pub async fn start_control_consumer(
+    address: String,
+    tls: Option<AmqpTls>,
+    queue: String,
+    senders: HashMap<String, FileEventSender>,
+    persistence: SqliteAsyncPersistence,
+    mut stop_receiver: watch::Receiver<()>,
+) {
+    let mut backoff = Backoff::new();
+    let mut connected = true;
+
+    loop {
+        let result = tokio::select!(
+            result = consume(&address, tls.as_ref(), &queue, &senders, &persistence, || {
+                if !connected {
+                    warn!("Reconnected to control queue '{queue}'");
+                    connected = true;
+                }
+
+                backoff.reset();
+            }) => result,
+            _ = stop_receiver.changed() => return,
+        );
+
+        match result {
+            Ok(()) => debug!("Control queue '{queue}' consumer ended"),
+            Err(e) if connected => {
+                warn!("Lost control queue '{queue}': {e}");
+                connected = false;
+            }
+            Err(e) => debug!("Could not reconnect to control queue '{queue}': {e}"),
+        }
+
+        tokio::select!(
+            _ = tokio::time::sleep(backoff.next_delay()) => (),
+            _ = stop_receiver.changed() => return,
+        );
+    }
+}
+
+/// Consume control commands until the stream ends, acknowledging each command
+/// once it is executed
+async fn consume(
+    address: &str,
+    tls: Option<&AmqpTls>,
+    queue: &str,
+    senders: &HashMap<String, FileEventSender>,
+    persistence: &SqliteAsyncPersistence,
+    mut consuming: impl FnMut(),
+) -> Result<(), String> {
+    let channel = connect_channel(address, tls).await?;
+
+    declare_control_queue(&channel, queue).await?;
+
+    let mut consumer = channel
+        .basic_consume(
+            queue,
+            "cortex-dispatcher-control",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .map_err(|e| format!("Error consuming control queue '{queue}': {e}"))?;
+
+    consuming();
+
+    info!("Consuming control commands from queue '{}'", queue);
+
+    while let Some(message) = consumer.next().await {
+        let delivery = message.map_err(|e| format!("Error reading control message: {e}"))?;
+
+        let result = match serde_json::from_slice::<ControlCommand>(&delivery.data) {
+            Ok(ControlCommand::Redispatch { file_ids }) => {
+                match redispatch(file_ids, senders, persistence).await {
+                    Ok(()) => delivery.acker.ack(BasicAckOptions::default()).await,
+                    Err(e) => {
+                        error!("Could not redispatch files: {e}");
+
+                        // Commands are rare, so the consumer can wait
+                        tokio::time::sleep(REDISPATCH_RETRY_DELAY).await;
+
+                        delivery
+                            .acker
+                            .nack(BasicNackOptions {
+                                requeue: true,
+                                ..Default::default()
+                            })
+                            .await
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Could not parse control command: {e}");
+
+                // The command will never be valid
+                delivery
+                    .acker
+                    .reject(BasicRejectOptions { requeue: false })
+                    .await
+            }
+        };
+
+        result.map_err(|e| format!("Error acknowledging control command: {e}"))?;
+    }
+
+    Ok(())
+}
+
+async fn redispatch(
+    file_ids: Vec<i64>,
+    senders: &HashMap<String, FileEventSender>,
+    persistence: &SqliteAsyncPersistence,
+) -> Result<(), String> {
+    let query = FileQuery {
+        ids: Some(file_ids),
+        ..Default::default()
+    };
+
+    let files = persistence
+        .query_files(query)
+        .await
+        .map_err(|e| format!("Could not query files for redispatch: {e}"))?;
+
+    for file in files {
+        let sender = match senders.get(&file.source) {
+            Some(sender) => sender,
+            None => {
+                warn!(
+                    "No source '{}' for redispatching file {}",
+                    &file.source, file.id
+                );
+                continue;
+            }
+        };
+
+        let file_event = FileEvent::from(&file);
+
+        if !file_event.path.exists() {
+            warn!(
+                "Not redispatching file {}: '{}' no longer exists",
+                file.id, &file.path
+            );
+            continue;
+        }
+
+        match sender.send(file_event).await {
+            Ok(_) => debug!("Redispatched file {} '{}'", file.id, &file.path),
+            Err(e) => error!("Could not redispatch file {}: {}", file.id, e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn consumer_keeps_reconnecting_until_stopped() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let (stop_sender, stop_receiver) = watch::channel(());
+
+        // Nothing listens on this port, so the consumer ends up in the backoff
+        let consumer = tokio::spawn(start_control_consumer(
+            "amqp://127.0.0.1:1/%2f".to_string(),
+            None,
+            "cortex-dispatcher.control".to_string(),
+            HashMap::new(),
+            SqliteAsyncPersistence::new(Arc::new(Mutex::new(conn))),
+            stop_receiver,
+        ));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert!(!consumer.is_finished());
+
+        stop_sender.send(()).unwrap();
+
+        tokio::time::timeout(Duration::from_millis(200), consumer)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
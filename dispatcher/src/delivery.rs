@@ -0,0 +1,342 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_channel::TrySendError;
+use crossbeam_channel::{Receiver, Sender};
+use log::{debug, error, warn};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+
+use crate::base_types::MessageResponse;
+
+/// Acknowledgment of the download command of a file that is held back until
+/// the targets of the connections that require delivery handled the file
+///
+/// The command is given back to the broker when the acknowledgment is dropped
+/// without being sent, e.g. when its file event is dropped from a full channel.
+#[derive(Debug, Clone)]
+pub struct PendingAck(Arc<AckState>);
+
+#[derive(Debug)]
+struct AckState {
+    delivery_tag: u64,
+    path: String,
+    ack_sender: async_channel::Sender<MessageResponse>,
+    sent: AtomicBool,
+    removal: Option<DeliveredRemovals>,
+    targets: Option<Vec<String>>,
+}
+
+impl PendingAck {
+    /// Acknowledgment that queues the removal of the remote file with
+    /// `removal` once the command is acknowledged, for a file event that is
+    /// only meant for `targets` when they are set
+    pub fn new(
+        delivery_tag: u64,
+        path: &str,
+        ack_sender: async_channel::Sender<MessageResponse>,
+        removal: Option<DeliveredRemovals>,
+        targets: Option<Vec<String>>,
+    ) -> PendingAck {
+        PendingAck(Arc::new(AckState {
+            delivery_tag,
+            path: path.to_string(),
+            ack_sender,
+            sent: AtomicBool::new(false),
+            removal,
+            targets,
+        }))
+    }
+
+    /// The targets a redelivered file event is meant for, instead of all
+    /// connected targets
+    pub fn targets(&self) -> Option<&[String]> {
+        self.0.targets.as_deref()
+    }
+
+    /// Send the response, unless a response was sent already, returning
+    /// whether it was sent
+    async fn send(&self, response: MessageResponse) -> bool {
+        if self.0.sent.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        if let Err(e) = self.0.ack_sender.send(response).await {
+            error!("Error sending message ack to channel: {}", e);
+        }
+
+        true
+    }
+
+    /// Acknowledge the command, after which the remote file can be removed
+    pub async fn ack(&self) {
+        let sent = self
+            .send(MessageResponse::Ack {
+                delivery_tag: self.0.delivery_tag,
+            })
+            .await;
+
+        if let (true, Some(removal)) = (sent, &self.0.removal) {
+            removal.queue(&self.0.path);
+        }
+    }
+
+    /// Give the command back to the broker, to download the file again
+    pub async fn nack(&self) {
+        self.send(MessageResponse::Nack {
+            delivery_tag: self.0.delivery_tag,
+            delay: Duration::ZERO,
+        })
+        .await;
+    }
+
+    /// Acknowledge the command once all targets confirmed that they handled
+    /// the file, or give it back when a target failed or the timeout passed
+    pub async fn wait_for(self, confirmations: Vec<oneshot::Receiver<()>>, timeout: Duration) {
+        let confirmed =
+            tokio::time::timeout(timeout, futures::future::try_join_all(confirmations)).await;
+
+        match confirmed {
+            Ok(Ok(_)) => {
+                debug!("'{}' is delivered to all targets", self.0.path);
+
+                self.ack().await
+            }
+            Ok(Err(_)) => {
+                warn!(
+                    "A target failed to handle '{}', requeueing its download command",
+                    self.0.path
+                );
+
+                self.nack().await
+            }
+            Err(_) => {
+                warn!(
+                    "'{}' is not delivered to all targets within {} seconds, requeueing its download command",
+                    self.0.path,
+                    timeout.as_secs()
+                );
+
+                self.nack().await
+            }
+        }
+    }
+}
+
+impl Drop for AckState {
+    fn drop(&mut self) {
+        if *self.sent.get_mut() {
+            return;
+        }
+
+        warn!(
+            "File event of '{}' was dropped before it was delivered, requeueing its download command",
+            self.path
+        );
+
+        let response = MessageResponse::Nack {
+            delivery_tag: self.delivery_tag,
+            delay: Duration::ZERO,
+        };
+
+        let response = match self.ack_sender.try_send(response) {
+            Ok(()) => return,
+            Err(TrySendError::Full(response)) => response,
+            Err(e) => {
+                error!("Error sending message nack to channel: {}", e);
+                return;
+            }
+        };
+
+        let ack_sender = self.ack_sender.clone();
+
+        match Handle::try_current() {
+            // Wait for room in the channel without blocking the runtime
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    if let Err(e) = ack_sender.send(response).await {
+                        error!("Error sending message nack to channel: {}", e);
+                    }
+                });
+            }
+            // Dropped on a download thread
+            Err(_) => {
+                if let Err(e) = ack_sender.send_blocking(response) {
+                    error!("Error sending message nack to channel: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Remote files of acknowledged download commands, to be removed by the
+/// downloader of their source
+#[derive(Debug, Clone)]
+pub struct DeliveredRemovals {
+    sender: Sender<String>,
+    receiver: Receiver<String>,
+}
+
+impl Default for DeliveredRemovals {
+    fn default() -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        DeliveredRemovals { sender, receiver }
+    }
+}
+
+impl DeliveredRemovals {
+    pub fn queue(&self, path: &str) {
+        // Both ends live in this struct, so sending cannot fail
+        let _ = self.sender.send(path.to_string());
+    }
+
+    /// The queued paths, in the order they were queued
+    pub fn take(&self) -> Vec<String> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+/// Confirmation by a target that it handled a file, for a held back
+/// acknowledgment
+///
+/// Dropping all clones without confirming counts as a failure of the target.
+#[derive(Debug, Clone)]
+pub struct Confirmation(Arc<Mutex<Option<oneshot::Sender<()>>>>);
+
+impl Confirmation {
+    pub fn new() -> (Confirmation, oneshot::Receiver<()>) {
+        let (sender, receiver) = oneshot::channel();
+
+        (Confirmation(Arc::new(Mutex::new(Some(sender)))), receiver)
+    }
+
+    /// Confirm the handling, which only has an effect the first time
+    pub fn confirm(&self) {
+        if let Some(sender) = self.0.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ack_waits_for_all_confirmations() {
+        let (ack_sender, ack_receiver) = async_channel::unbounded();
+
+        let (red, red_confirmed) = Confirmation::new();
+        let (blue, blue_confirmed) = Confirmation::new();
+
+        let waiting = tokio::spawn(
+            PendingAck::new(1, "/upload/a.xml", ack_sender.clone(), None, None)
+                .wait_for(vec![red_confirmed, blue_confirmed], Duration::from_secs(10)),
+        );
+
+        red.confirm();
+        red.confirm();
+        tokio::task::yield_now().await;
+
+        assert!(ack_receiver.is_empty());
+
+        blue.confirm();
+        waiting.await.unwrap();
+
+        assert_eq!(
+            ack_receiver.try_recv().unwrap(),
+            MessageResponse::Ack { delivery_tag: 1 }
+        );
+
+        // A target that drops the file event without confirming
+        let (red, red_confirmed) = Confirmation::new();
+        let failing = PendingAck::new(2, "/upload/b.xml", ack_sender.clone(), None, None);
+
+        drop(red);
+        failing
+            .wait_for(vec![red_confirmed], Duration::from_secs(10))
+            .await;
+
+        // A target that does not confirm in time
+        let (_blue, blue_confirmed) = Confirmation::new();
+        let stuck = PendingAck::new(3, "/upload/c.xml", ack_sender.clone(), None, None);
+
+        stuck
+            .wait_for(vec![blue_confirmed], Duration::from_millis(10))
+            .await;
+
+        // A file event that is dropped before it reached the targets
+        drop(PendingAck::new(4, "/upload/d.xml", ack_sender, None, None));
+
+        let nacked: Vec<MessageResponse> =
+            std::iter::from_fn(|| ack_receiver.try_recv().ok()).collect();
+
+        assert_eq!(
+            nacked,
+            [2, 3, 4]
+                .into_iter()
+                .map(|delivery_tag| MessageResponse::Nack {
+                    delivery_tag,
+                    delay: Duration::ZERO,
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_files_are_removed_only_after_an_ack() {
+        let (ack_sender, _ack_receiver) = async_channel::unbounded();
+        let removals = DeliveredRemovals::default();
+
+        let (red, red_confirmed) = Confirmation::new();
+        drop(red);
+        PendingAck::new(
+            1,
+            "/upload/a.xml",
+            ack_sender.clone(),
+            Some(removals.clone()),
+            None,
+        )
+        .wait_for(vec![red_confirmed], Duration::from_secs(10))
+        .await;
+
+        assert!(removals.take().is_empty());
+
+        let (red, red_confirmed) = Confirmation::new();
+        red.confirm();
+        PendingAck::new(1, "/upload/a.xml", ack_sender, Some(removals.clone()), None)
+            .wait_for(vec![red_confirmed], Duration::from_secs(10))
+            .await;
+
+        assert_eq!(removals.take(), vec!["/upload/a.xml".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn dropped_acks_wait_for_room_in_the_channel() {
+        let (ack_sender, ack_receiver) = async_channel::bounded(1);
+
+        ack_sender
+            .send(MessageResponse::Ack { delivery_tag: 1 })
+            .await
+            .unwrap();
+
+        drop(PendingAck::new(2, "/upload/a.xml", ack_sender, None, None));
+
+        assert_eq!(
+            ack_receiver.recv().await.unwrap(),
+            MessageResponse::Ack { delivery_tag: 1 }
+        );
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(5), ack_receiver.recv())
+                .await
+                .unwrap()
+                .unwrap(),
+            MessageResponse::Nack {
+                delivery_tag: 2,
+                delay: Duration::ZERO,
+            }
+        );
+    }
+}
@@ -10,7 +10,11 @@ use std::time::Duration;
 #[cfg(target_os = "linux")]
 use std::collections::HashMap;
 
-use log::{debug, error, info};
+use chrono::Utc;
+use log::{debug, error, info, warn};
+
+use cortex_core::coded_error;
+use cortex_core::error_code::{tagged, ErrorCode};
 
 #[cfg(target_os = "linux")]
 use inotify::{EventMask, Inotify, WatchMask};
@@ -18,15 +22,25 @@ use inotify::{EventMask, Inotify, WatchMask};
 use sha2::{Digest, Sha256};
 
 use crate::event::{EventDispatcher, FileEvent};
+use crate::intake_journal::IntakeJournal;
 use crate::local_storage::LocalStorage;
+use crate::metrics;
+use crate::pause::SourcePauses;
 use crate::persistence::Persistence;
 use crate::settings;
+use crate::source_activity::SourceActivities;
+
+/// Interval at which inotify is checked for events when there were none
+#[cfg(target_os = "linux")]
+const INOTIFY_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Debug, Clone)]
 pub struct LocalFileEvent {
     pub source_name: String,
     pub path: PathBuf,
     pub prefix: PathBuf,
+    /// Id of the event in the intake journal, when it was recorded there
+    pub journal_id: Option<u64>,
 }
 
 #[cfg(target_os = "linux")]
@@ -66,6 +80,28 @@ fn visit_files(dir: &Path, cb: &mut dyn FnMut(&Path), recurse: bool) -> io::Resu
     Ok(())
 }
 
+/// Whether a file can be taken in, counting and logging the files that cannot
+///
+/// Paths are stored as strings, so files of which the path is not valid UTF-8
+/// are skipped.
+fn valid_path(source_name: &str, path: &Path) -> bool {
+    if path.to_str().is_some() {
+        return true;
+    }
+
+    warn!(
+        "Skipping '{}' of <{}>, its path is not valid UTF-8",
+        path.to_string_lossy(),
+        source_name
+    );
+
+    metrics::INVALID_FILENAME_COUNTER
+        .with_label_values(&[source_name])
+        .inc();
+
+    false
+}
+
 #[cfg(target_os = "linux")]
 fn construct_watch_mask(events: Vec<settings::FileSystemEvent>) -> WatchMask {
     let mut watch_mask: WatchMask = WatchMask::empty();
@@ -92,18 +128,31 @@ pub fn start_directory_sweep(
     directory_sources: Vec<settings::DirectorySource>,
     local_intake_sender: Sender<LocalFileEvent>,
     scan_interval: u64,
+    source_pauses: SourcePauses,
+    source_activities: SourceActivities,
     stop_flag: Arc<AtomicBool>,
 ) -> thread::JoinHandle<()> {
     let timeout = std::time::Duration::from_millis(scan_interval);
 
     thread::spawn(move || {
+        let _thread = metrics::ComponentThread::start("sweep");
+
         while !stop_flag.load(Ordering::Relaxed) {
             directory_sources.iter().for_each(|directory_source| {
+                if source_pauses.is_paused(&directory_source.name) {
+                    debug!("Skipping sweep of paused source: {}", directory_source.name);
+                    return;
+                }
+
                 info!("Sweeping directory source: {}", directory_source.name);
 
                 let mut handle_file = |path: &Path| {
+                    if !valid_path(&directory_source.name, path) {
+                        return;
+                    }
+
                     let file_matches = match &directory_source.filter {
-                        Some(f) => f.file_matches(path),
+                        Some(f) => f.matches(path),
                         None => true,
                     };
 
@@ -112,6 +161,7 @@ pub fn start_directory_sweep(
                             source_name: directory_source.name.clone(),
                             path: PathBuf::from(path),
                             prefix: directory_source.directory.clone(),
+                            journal_id: None,
                         };
 
                         let send_result = local_intake_sender.send(local_file_event);
@@ -131,13 +181,15 @@ pub fn start_directory_sweep(
                     directory_source.recursive,
                 );
 
-                match visit_result {
-                    Ok(()) => (),
-                    Err(e) => error!(
+                if let Err(e) = visit_result {
+                    let message = format!(
                         "Error sweeping directory '{}': {}",
                         &directory_source.directory.to_string_lossy(),
                         e
-                    ),
+                    );
+
+                    error!("{message}");
+                    source_activities.set_error(&directory_source.name, message);
                 }
             });
 
@@ -152,6 +204,9 @@ pub fn start_directory_sweep(
 pub fn start_directory_sources(
     directory_sources: Vec<settings::DirectorySource>,
     local_intake_sender: Sender<LocalFileEvent>,
+    journal: Option<IntakeJournal>,
+    source_pauses: SourcePauses,
+    source_activities: SourceActivities,
     stop_flag: Arc<AtomicBool>,
 ) -> thread::JoinHandle<()> {
     let init_result = Inotify::init();
@@ -182,6 +237,7 @@ pub fn start_directory_sources(
             match watch_result {
                 Ok(w) => {
                     info!("Added watch on {}", path.to_string_lossy());
+                    source_activities.set_connected(&directory_source.name, true);
                     watch_mapping.insert(
                         w,
                         InotifyEventContext {
@@ -195,11 +251,14 @@ pub fn start_directory_sources(
                     );
                 }
                 Err(e) => {
-                    error!(
-                        "[E02003] Failed to add inotify watch on '{}': {}",
+                    let message = format!(
+                        "Failed to add inotify watch on '{}': {}",
                         &directory_source.directory.to_string_lossy(),
                         e
                     );
+
+                    coded_error!(ErrorCode::DirectoryWatch, "{message}");
+                    source_activities.set_error(&directory_source.name, message);
                 }
             };
         };
@@ -212,11 +271,22 @@ pub fn start_directory_sources(
 
         match visit_result {
             Ok(_) => (),
-            Err(e) => error!("[E02011] Error recursing directories: {}", e),
+            Err(e) => coded_error!(
+                ErrorCode::DirectoryRecursion,
+                "Error recursing directories: {}",
+                e
+            ),
         };
     });
 
-    start_inotify_event_thread(inotify, watch_mapping, local_intake_sender, stop_flag)
+    start_inotify_event_thread(
+        inotify,
+        watch_mapping,
+        local_intake_sender,
+        journal,
+        source_pauses,
+        stop_flag,
+    )
 }
 
 fn event_type_matches(watch_mask: WatchMask, event_mask: EventMask) -> bool {
@@ -228,23 +298,34 @@ fn event_type_matches(watch_mask: WatchMask, event_mask: EventMask) -> bool {
 /// Start thread for monitoring for new files using inotify
 ///
 /// When a new file is detected, an event is sent to the
-/// local_intake_sender channel.
+/// local_intake_sender channel, after it is recorded in the journal. Files of
+/// paused sources are left for the sweep after the source is resumed.
 #[cfg(target_os = "linux")]
 fn start_inotify_event_thread(
     mut inotify: Inotify,
     mut watch_mapping: HashMap<inotify::WatchDescriptor, InotifyEventContext>,
     local_intake_sender: Sender<LocalFileEvent>,
+    journal: Option<IntakeJournal>,
+    source_pauses: SourcePauses,
     stop_flag: Arc<AtomicBool>,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
+        let _thread = metrics::ComponentThread::start("inotify");
+
         let timeout = Duration::from_millis(500);
         let mut buffer: Vec<u8> = vec![0; 1024];
 
         while !stop_flag.load(Ordering::Relaxed) {
-            let read_result = inotify.read_events_blocking(&mut buffer);
+            // Read without blocking, so that the stop flag is checked while no
+            // events arrive
+            let read_result = inotify.read_events(&mut buffer);
 
             let events = match read_result {
                 Ok(events) => events,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(INOTIFY_POLL_INTERVAL);
+                    continue;
+                }
                 Err(e) => {
                     error!("Could not read inotify events: {}", e);
                     std::thread::sleep(timeout);
@@ -261,21 +342,11 @@ fn start_inotify_event_thread(
                     }
                 };
 
-                let to_str_result = name.to_str();
-
-                let file_name = match to_str_result {
-                    Some(name_str) => name_str,
-                    None => {
-                        debug!("Could not decode string from inotify event name");
-                        continue;
-                    }
-                };
-
                 let get_result = watch_mapping.get(&event.wd);
 
                 match get_result {
                     Some(event_context) => {
-                        let source_path = event_context.directory.join(file_name);
+                        let source_path = event_context.directory.join(name);
                         let source_path_str = source_path.to_string_lossy();
 
                         if source_path.is_dir() {
@@ -305,14 +376,15 @@ fn start_inotify_event_thread(
 
                                 info!("Registered extra watch on {}", &source_path_str);
                             }
-                        } else {
+                        } else if valid_path(&event_context.source_name, &source_path) {
                             let file_matches = match &event_context.filter {
-                                Some(f) => f.file_matches(&source_path),
+                                Some(f) => f.matches(source_path.as_path()),
                                 None => true,
                             };
 
                             if file_matches
                                 && event_type_matches(event_context.watch_mask, event.mask)
+                                && !source_pauses.is_paused(&event_context.source_name)
                             {
                                 debug!("Event for {} matches filter", &source_path_str);
 
@@ -320,6 +392,20 @@ fn start_inotify_event_thread(
                                     source_name: event_context.source_name.clone(),
                                     path: source_path,
                                     prefix: event_context.prefix.clone(),
+                                    journal_id: None,
+                                };
+
+                                // Without a journal entry, the file is only
+                                // rescued by the sweep after a crash
+                                let file_event = match &journal {
+                                    Some(journal) => match journal.record(file_event.clone()) {
+                                        Ok(file_event) => file_event,
+                                        Err(e) => {
+                                            error!("{e}");
+                                            file_event
+                                        }
+                                    },
+                                    None => file_event,
                                 };
 
                                 let send_result = local_intake_sender.send(file_event);
@@ -334,7 +420,10 @@ fn start_inotify_event_thread(
                         }
                     }
                     None => {
-                        error!("Could not find matching event context for {}", file_name);
+                        error!(
+                            "Could not find matching event context for {}",
+                            name.to_string_lossy()
+                        );
                     }
                 }
             }
@@ -349,12 +438,21 @@ fn start_inotify_event_thread(
 /// Events of available files from a local directory source are taken from the
 /// receiver channel and the file are ingested by Cortex for further
 /// dispatching.
+///
+/// The thread ends when all senders are dropped, so the sweep and inotify
+/// threads must be stopped first. Events that were queued before that are
+/// still ingested.
+///
+/// Journaled events are completed once they are ingested, or skipped because
+/// the source no longer exists. Events that failed stay in the journal and are
+/// replayed on the next start.
 pub fn start_local_intake_thread<T>(
     receiver: Receiver<LocalFileEvent>,
     mut event_dispatcher: EventDispatcher,
     local_storage: LocalStorage<T>,
     sources: HashMap<String, settings::DirectorySource>,
-    stop_flag: Arc<AtomicBool>,
+    journal: Option<IntakeJournal>,
+    source_activities: SourceActivities,
 ) -> thread::JoinHandle<()>
 where
     T: Persistence,
@@ -363,35 +461,47 @@ where
     T: 'static,
 {
     thread::spawn(move || {
-        let timeout = Duration::from_millis(500);
-
-        while !stop_flag.load(Ordering::Relaxed) {
-            let receive_result = receiver.recv_timeout(timeout);
-
-            if let Ok(file_event) = receive_result {
-                // Lookup the corresponding directory source
-                match sources.get(&file_event.source_name) {
-                    Some(source) => {
-                        if let Err(e) = process_file_event(
-                            &file_event,
-                            source,
-                            &mut event_dispatcher,
-                            &local_storage,
-                        ) {
+        let _thread = metrics::ComponentThread::start("intake");
+
+        for file_event in receiver {
+            // Lookup the corresponding directory source
+            let handled = match sources.get(&file_event.source_name) {
+                Some(source) => {
+                    match process_file_event(
+                        &file_event,
+                        source,
+                        &mut event_dispatcher,
+                        &local_storage,
+                    ) {
+                        Ok(Some(size)) => {
+                            source_activities.record_file(&file_event.source_name, size);
+                            true
+                        }
+                        Ok(None) => true,
+                        Err(e) => {
                             error!(
                                 "Error processing file event for '{}': {}",
                                 &file_event.path.to_string_lossy(),
                                 e
                             );
+                            source_activities.set_error(&file_event.source_name, e);
+                            false
                         }
                     }
-                    None => {
-                        error!(
-                            "No matching directory source found with name '{}'",
-                            &file_event.source_name
-                        );
-                    }
-                };
+                }
+                None => {
+                    error!(
+                        "No matching directory source found with name '{}'",
+                        &file_event.source_name
+                    );
+                    true
+                }
+            };
+
+            if let (true, Some(journal), Some(id)) = (handled, &journal, file_event.journal_id) {
+                if let Err(e) = journal.complete(id) {
+                    error!("{e}");
+                }
             }
         }
 
@@ -423,7 +533,7 @@ fn sha256_hash<R: std::io::Read>(mut reader: R) -> Result<String, std::io::Error
 ///
 /// The file is read until the end and the SHA265 hash is returned in the form
 /// of its hexadecimal representation string.
-fn sha256_hash_file(path: &Path, unpack: bool) -> Result<String, std::io::Error> {
+pub fn sha256_hash_file(path: &Path, unpack: bool) -> Result<String, std::io::Error> {
     let in_file = std::fs::File::open(path)?;
 
     if unpack {
@@ -442,13 +552,14 @@ fn sha256_hash_file(path: &Path, unpack: bool) -> Result<String, std::io::Error>
     }
 }
 
-/// Process event for a DirectorySource
+/// Process event for a DirectorySource, returning the size of the file when
+/// it was taken in
 fn process_file_event<T>(
     file_event: &LocalFileEvent,
     directory_source: &settings::DirectorySource,
     event_dispatcher: &mut EventDispatcher,
     local_storage: &LocalStorage<T>,
-) -> Result<(), String>
+) -> Result<Option<u64>, String>
 where
     T: Persistence,
     T: Send,
@@ -463,9 +574,14 @@ where
             "Skipping '{}': file no longer exists",
             file_event.path.to_string_lossy()
         );
-        return Ok(());
+        return Ok(None);
     }
 
+    // The file stays in the source directory for the next sweep
+    local_storage
+        .check_space(&directory_source.name)
+        .map_err(|e| e.to_string())?;
+
     let file_hash = sha256_hash_file(&file_event.path, directory_source.unpack_before_hash)
         .map_err(|e| format!("Error calculating file hash: {}", e))?;
 
@@ -488,7 +604,7 @@ where
                     &file_event.path.to_string_lossy()
                 );
 
-                if directory_source.delete {
+                if directory_source.delete && !local_storage.is_dry_run() {
                     fs::remove_file(&file_event.path).map_err(|e| {
                         format!(
                             "Error removing file '{}': {}",
@@ -498,7 +614,7 @@ where
                     })?;
                 }
 
-                return Ok(());
+                return Ok(None);
             }
             settings::Deduplication::Check(check) => {
                 let metadata = fs::metadata(&file_event.path).map_err(|e| {
@@ -527,7 +643,7 @@ where
                         &file_event.path.to_string_lossy()
                     );
 
-                    if directory_source.delete {
+                    if directory_source.delete && !local_storage.is_dry_run() {
                         fs::remove_file(&file_event.path).map_err(|e| {
                             format!(
                                 "Error removing file '{}': {}",
@@ -537,7 +653,7 @@ where
                         })?;
                     }
 
-                    return Ok(());
+                    return Ok(None);
                 }
             }
             settings::Deduplication::None => {}
@@ -546,7 +662,7 @@ where
 
     let source_path_str = file_event.path.to_string_lossy();
 
-    let (file_id, target_path) = local_storage
+    let stored_file = local_storage
         .ingest(
             &file_event.source_name,
             &file_event.path,
@@ -554,13 +670,32 @@ where
             Some(file_hash.clone()),
             directory_source.delete,
         )
-        .map_err(|e| format!("Error storing file '{}': {}", &source_path_str, &e))?;
+        .map_err(|e| {
+            tagged(
+                e.code(),
+                format_args!("Error storing file '{}': {}", &source_path_str, &e),
+            )
+        })?;
+
+    // Skipped because of a conflict with a stored file
+    let Some(stored_file) = stored_file else {
+        return Ok(None);
+    };
 
     let source_file_event = FileEvent {
-        file_id,
+        file_id: stored_file.file_id,
         source_name: file_event.source_name.clone(),
-        path: target_path,
+        path: stored_file.path,
+        relative_path: stored_file.relative_path,
         hash: file_hash,
+        content_hash: !(directory_source.unpack_before_hash
+            && file_event.path.extension() == Some("gz".as_ref())),
+        size: stored_file.size,
+        modified: stored_file.modified,
+        created: Utc::now(),
+        discovered: None,
+        ack: None,
+        confirmation: None,
     };
 
     info!(
@@ -570,7 +705,298 @@ where
 
     event_dispatcher
         .dispatch_event(&source_file_event)
-        .map_err(|e| format!("[E02001] Error sending file event on local channel: {}", e))?;
+        .map_err(|e| {
+            tagged(
+                ErrorCode::LocalChannelSend,
+                format_args!("Error sending file event on local channel: {}", e),
+            )
+        })?;
+
+    Ok(Some(source_file_event.size))
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::event::{file_event_channel, FileEventReceiver};
+    use crate::intake_journal::IntakeJournal;
+    use crate::local_storage::StorageArea;
+    use crate::persistence::SqlitePersistence;
+    use crate::readiness::Readiness;
+    use crate::settings::{Settings, DEFAULT_STORAGE_AREA};
+    use crate::storage_usage::StorageUsage;
+
+    /// Storage with an in-memory database, the default directory source on
+    /// the incoming directory and the file event channel of the source
+    fn test_intake(
+        directory: &Path,
+    ) -> (
+        LocalStorage<SqlitePersistence>,
+        settings::DirectorySource,
+        EventDispatcher,
+        FileEventReceiver,
+    ) {
+        let settings = Settings::default();
+        let incoming = directory.join("incoming");
+        let storage = directory.join("storage");
+
+        fs::create_dir_all(&incoming).unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let persistence = SqlitePersistence::from_arc(Arc::new(std::sync::Mutex::new(conn)));
+
+        let local_storage = LocalStorage::new(
+            vec![StorageArea::new(
+                DEFAULT_STORAGE_AREA,
+                &storage,
+                settings::StorageLayout::PerSource,
+                StorageUsage::new(&settings.storage[0], Readiness::default()),
+            )],
+            persistence,
+        );
+
+        let mut directory_source = settings.directory_sources[0].clone();
+        directory_source.directory = incoming.clone();
+
+        let (file_event_sender, file_event_receiver) =
+            file_event_channel("source:mixed-directory", &settings.channels);
+
+        let event_dispatcher = EventDispatcher {
+            senders: HashMap::from([(directory_source.name.clone(), file_event_sender)]),
+        };
+
+        (
+            local_storage,
+            directory_source,
+            event_dispatcher,
+            file_event_receiver,
+        )
+    }
+
+    #[test]
+    fn queued_files_are_ingested_on_shutdown() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-directory-source-{}", std::process::id()));
+        let incoming = directory.join("incoming");
+
+        let (local_storage, directory_source, event_dispatcher, file_event_receiver) =
+            test_intake(&directory);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        let file_count = 50;
+
+        for i in 0..file_count {
+            let path = incoming.join(format!("{i}.csv"));
+            fs::write(&path, format!("file {i}")).unwrap();
+
+            sender
+                .send(LocalFileEvent {
+                    source_name: directory_source.name.clone(),
+                    path,
+                    prefix: incoming.clone(),
+                    journal_id: None,
+                })
+                .unwrap();
+        }
+
+        let source_activities = SourceActivities::default();
+
+        let intake_handle = start_local_intake_thread(
+            receiver,
+            event_dispatcher,
+            local_storage,
+            HashMap::from([(directory_source.name.clone(), directory_source)]),
+            None,
+            source_activities.clone(),
+        );
+
+        // Shutdown stops the producers while the events are still queued
+        drop(sender);
+
+        intake_handle.join().unwrap();
+
+        let mut dispatched = Vec::new();
+
+        while let Ok(file_event) = file_event_receiver.try_recv() {
+            dispatched.push(file_event.path);
+        }
+
+        for i in 0..file_count {
+            let name = format!("{i}.csv");
+            let content = format!("file {i}");
+
+            let ingested = dispatched.iter().any(|path| {
+                path.ends_with(&name) && fs::read_to_string(path).ok().as_ref() == Some(&content)
+            });
+            let untouched =
+                fs::read_to_string(incoming.join(&name)).ok().as_ref() == Some(&content);
+
+            assert!(ingested || untouched, "{name} was lost");
+        }
+
+        assert_eq!(dispatched.len(), file_count);
+
+        let health = source_activities.health(crate::pause::SourceStatus {
+            name: "mixed-directory".to_string(),
+            kind: crate::pause::SourceKind::Directory,
+            paused: false,
+        });
+
+        assert_eq!(health.files_last_hour, file_count as u64);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn journaled_events_are_replayed_after_a_crash() {
+        let directory = std::env::temp_dir().join(format!(
+            "cortex-directory-source-journal-{}",
+            std::process::id()
+        ));
+        let incoming = directory.join("incoming");
+        let journal_path = directory.join("cortex.db.intake-journal");
+
+        let (local_storage, directory_source, event_dispatcher, file_event_receiver) =
+            test_intake(&directory);
+
+        let sources = HashMap::from([(directory_source.name.clone(), directory_source.clone())]);
+        let journal = IntakeJournal::open(&journal_path).unwrap();
+
+        let file_events: Vec<LocalFileEvent> = (0..10)
+            .map(|i| {
+                let path = incoming.join(format!("{i}.csv"));
+                fs::write(&path, format!("file {i}")).unwrap();
+
+                journal
+                    .record(LocalFileEvent {
+                        source_name: directory_source.name.clone(),
+                        path,
+                        prefix: incoming.clone(),
+                        journal_id: None,
+                    })
+                    .unwrap()
+            })
+            .collect();
+
+        // The intake stops halfway, losing the events still in the channel
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        for file_event in &file_events[..5] {
+            sender.send(file_event.clone()).unwrap();
+        }
+
+        drop(sender);
+
+        start_local_intake_thread(
+            receiver,
+            EventDispatcher {
+                senders: event_dispatcher.senders.clone(),
+            },
+            local_storage.clone(),
+            sources.clone(),
+            Some(journal.clone()),
+            SourceActivities::default(),
+        )
+        .join()
+        .unwrap();
+
+        drop(journal);
+
+        assert_eq!(file_event_receiver.len(), 5);
+
+        // The next start replays the lost events
+        let journal = IntakeJournal::open(&journal_path).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        assert_eq!(journal.replay(&sender).unwrap(), 5);
+
+        drop(sender);
+
+        start_local_intake_thread(
+            receiver,
+            event_dispatcher,
+            local_storage,
+            sources,
+            Some(journal.clone()),
+            SourceActivities::default(),
+        )
+        .join()
+        .unwrap();
+
+        let mut dispatched: Vec<String> =
+            std::iter::from_fn(|| file_event_receiver.try_recv().ok())
+                .map(|file_event| fs::read_to_string(file_event.path).unwrap())
+                .collect();
+
+        dispatched.sort();
+
+        let mut expected: Vec<String> = (0..10).map(|i| format!("file {i}")).collect();
+        expected.sort();
+
+        assert_eq!(dispatched, expected);
+
+        // Nothing is left to replay, and the journal is compacted on open
+        let (sender, _receiver) = std::sync::mpsc::channel();
+
+        assert_eq!(
+            IntakeJournal::open(&journal_path)
+                .unwrap()
+                .replay(&sender)
+                .unwrap(),
+            0
+        );
+        assert_eq!(fs::read_to_string(&journal_path).unwrap(), "");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sweep_skips_paths_that_are_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let directory =
+            std::env::temp_dir().join(format!("cortex-directory-sweep-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        // "é.csv" in Latin-1
+        fs::write(directory.join(OsStr::from_bytes(b"\xe9.csv")), "latin-1").unwrap();
+        fs::write(directory.join("a.csv"), "utf-8").unwrap();
+
+        let mut directory_source = Settings::default().directory_sources[0].clone();
+        directory_source.directory = directory.clone();
+        directory_source.recursive = true;
+        directory_source.filter = None;
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let stop_flag = Arc::new(AtomicBool::new(false));
+
+        let sweep_handle = start_directory_sweep(
+            vec![directory_source],
+            sender,
+            10,
+            SourcePauses::new(&Settings::default()),
+            SourceActivities::default(),
+            stop_flag.clone(),
+        );
+
+        let first = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        stop_flag.store(true, Ordering::Relaxed);
+        sweep_handle.join().unwrap();
+
+        let paths: Vec<PathBuf> = std::iter::once(first)
+            .chain(receiver.try_iter())
+            .map(|file_event| file_event.path)
+            .collect();
+
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(!paths.is_empty());
+        assert!(paths.iter().all(|path| *path == directory.join("a.csv")));
+    }
 }
@@ -1,41 +1,146 @@
-use std::fs::{copy, hard_link, set_permissions, Permissions};
+use std::ffi::OsString;
+use std::fs::{copy, hard_link, rename, set_permissions, File, Permissions};
+use std::io::{self, Read};
 use std::os::unix::fs::symlink;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use log::{debug, error, warn};
+use digest_io::HashWriter;
+use log::{debug, error, info, warn};
+
+use cortex_core::coded_error;
+use cortex_core::error_code::ErrorCode;
+use sha2::{Digest, Sha256, Sha512};
 
 use crate::event::FileEvent;
-use crate::persistence::SqliteAsyncPersistence;
+use crate::local_storage::{check_no_symlinks, sync_parent_directory};
+use crate::metrics;
+use crate::persistence::{PersistenceError, SqliteAsyncPersistence};
+use crate::settings::{ChecksumAlgorithm, ChecksumFormat, ChecksumSidecar, Fsync};
 use crate::{settings, settings::LocalTargetMethod};
 
+/// Path at which the file of an event is placed in the directory of a target
+///
+/// With `preserve_structure`, the file is placed below the directories of the
+/// relative path of the event. Relative paths that would lead outside of the
+/// directory are rejected.
+pub fn target_path(
+    settings: &settings::DirectoryTarget,
+    file_event: &FileEvent,
+) -> Result<PathBuf, String> {
+    if let Some(relative_path) = file_event
+        .relative_path
+        .as_ref()
+        .filter(|_| settings.preserve_structure)
+    {
+        let contained = relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+        if !contained || relative_path.file_name().is_none() {
+            return Err(format!(
+                "Relative path '{}' of '{}' is not inside the target directory",
+                relative_path.to_string_lossy(),
+                file_event.path.to_string_lossy()
+            ));
+        }
+
+        return Ok(settings.directory.join(relative_path));
+    }
+
+    match file_event.path.file_name() {
+        Some(file_name) => Ok(settings.directory.join(file_name)),
+        None => Err(format!(
+            "No file name from file event path '{}'",
+            file_event.path.to_string_lossy()
+        )),
+    }
+}
+
+/// Path of a placed file relative to the directory of the target
+fn relative_target_path(
+    settings: &settings::DirectoryTarget,
+    target_path: &Path,
+) -> Option<PathBuf> {
+    target_path
+        .strip_prefix(&settings.directory)
+        .ok()
+        .map(Path::to_path_buf)
+}
+
+/// Create the directories between the directory of the target and the path,
+/// failing when one of them is a symbolic link through which the file would
+/// be placed outside of the directory
+fn create_parent_directories(
+    settings: &settings::DirectoryTarget,
+    target_path: &Path,
+) -> Result<(), String> {
+    check_no_symlinks(&settings.directory, target_path)
+        .map_err(|e| format!("Error placing '{}': {}", target_path.to_string_lossy(), e))?;
+
+    if let Some(parent) = target_path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            format!(
+                "Error creating directory '{}': {}",
+                parent.to_string_lossy(),
+                e
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Number of attempts to register a dispatch before it is left to the retries
+/// of the target
+const RECORD_ATTEMPTS: u32 = 5;
+
+/// Delay after the first failed attempt to register a dispatch, doubled after
+/// every next attempt
+const RECORD_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Place the file of an event in the directory of the target, returning the
+/// event of the placed file
+///
+/// The dispatch is registered separately with [`record_dispatched`], before
+/// the target sends its notification.
 pub async fn handle_file_event(
     settings: &settings::DirectoryTarget,
     file_event: FileEvent,
-    persistence: SqliteAsyncPersistence,
 ) -> Result<FileEvent, String> {
     let overwrite = settings.overwrite;
     let target_name = settings.name.clone();
-    let target_directory = settings.directory.clone();
     let method = settings.method.clone();
     let target_permissions = Permissions::from_mode(settings.permissions);
 
     let source_path_str = file_event.path.to_string_lossy();
-    let file_name = match file_event.path.file_name() {
-        Some(f) => f,
-        None => {
-            return Err(format!(
-                "No file name from file event path '{}'",
-                &source_path_str
-            ));
-        }
-    };
-    let target_path = target_directory.join(file_name);
+    let target_path = target_path(settings, &file_event)?;
     let target_path_str = target_path.to_string_lossy();
     let target_perms = target_permissions.clone();
 
     debug!("FileEvent for {}: '{}'", &target_name, &source_path_str);
 
-    if overwrite {
+    if settings.preserve_structure {
+        create_parent_directories(settings, &target_path)?;
+    }
+
+    // A redelivered event can find the file already placed by an earlier
+    // dispatch that did not get to register it
+    let placed = already_placed(&method, &file_event, &target_path);
+
+    if placed {
+        debug!(
+            "'{}' is already placed as '{}'",
+            &source_path_str, &target_path_str
+        );
+    }
+
+    if settings.verify_hash_on_dispatch && !placed {
+        verify_stored_file(settings, &file_event).await?;
+    }
+
+    if overwrite && !placed {
         // If overwrite is enabled, we just always try to remove the target and
         // expect that a NotFound error might be returned.
         let remove_result = std::fs::remove_file(&target_path);
@@ -56,98 +161,119 @@ pub async fn handle_file_event(
         }
     }
 
-    let placement_result = match method {
-        LocalTargetMethod::Copy => {
-            let result = copy(&file_event.path, &target_path);
+    let placement_start = Instant::now();
 
-            match result {
-                Ok(size) => {
-                    debug!(
-                        "'{}' copied {} bytes to '{}'",
-                        &source_path_str, size, &target_path_str
-                    );
-                    Ok(())
-                }
-                Err(e) => {
-                    if overwrite {
-                        // When overwrite is enabled, this should not occur, because any existing
-                        // file should first be removed
-                        error!(
-                            "[E01005] Error copying '{}' to '{}': {}",
-                            &source_path_str, &target_path_str, &e
+    let placement_result = if placed {
+        Ok(())
+    } else {
+        match method {
+            LocalTargetMethod::Copy => {
+                let result = if settings.fsync == Fsync::None {
+                    copy(&file_event.path, &target_path)
+                } else {
+                    copy_synced(&file_event.path, &target_path)
+                };
+
+                match result {
+                    Ok(size) => {
+                        debug!(
+                            "'{}' copied {} bytes to '{}'",
+                            &source_path_str, size, &target_path_str
                         );
-                        Err(())
-                    } else {
                         Ok(())
                     }
+                    Err(e) => {
+                        if overwrite {
+                            // When overwrite is enabled, this should not occur, because any existing
+                            // file should first be removed
+                            coded_error!(
+                                ErrorCode::TargetCopy,
+                                "Error copying '{}' to '{}': {}",
+                                &source_path_str,
+                                &target_path_str,
+                                &e
+                            );
+                            Err(())
+                        } else {
+                            Ok(())
+                        }
+                    }
                 }
             }
-        }
-        LocalTargetMethod::Hardlink => {
-            let result = hard_link(&file_event.path, &target_path);
-
-            match result {
-                Ok(()) => {
-                    debug!(
-                        "Hardlinked '{}' to '{}'",
-                        &source_path_str, &target_path_str
-                    );
-                    Ok(())
-                }
-                Err(e) => {
-                    if overwrite {
-                        // When overwrite is enabled, this should not occur, because any existing
-                        // file should first be removed
-                        error!(
-                            "[E01004] Error hardlinking '{}' to '{}': {}",
-                            &source_path_str, &target_path_str, &e
-                        );
-                        Err(())
-                    } else {
-                        // When overwrite is disabled, this might occur and is not an error, but it
-                        // is reported
-                        warn!(
-                            "Could not hardlink '{}' to '{}': {}",
-                            &source_path_str, &target_path_str, e
+            LocalTargetMethod::Hardlink => {
+                let result = hard_link(&file_event.path, &target_path);
+
+                match result {
+                    Ok(()) => {
+                        debug!(
+                            "Hardlinked '{}' to '{}'",
+                            &source_path_str, &target_path_str
                         );
                         Ok(())
                     }
+                    Err(e) => {
+                        if overwrite {
+                            // When overwrite is enabled, this should not occur, because any existing
+                            // file should first be removed
+                            coded_error!(
+                                ErrorCode::TargetHardlink,
+                                "Error hardlinking '{}' to '{}': {}",
+                                &source_path_str,
+                                &target_path_str,
+                                &e
+                            );
+                            Err(())
+                        } else {
+                            // When overwrite is disabled, this might occur and is not an error, but it
+                            // is reported
+                            warn!(
+                                "Could not hardlink '{}' to '{}': {}",
+                                &source_path_str, &target_path_str, e
+                            );
+                            Ok(())
+                        }
+                    }
                 }
             }
-        }
-        LocalTargetMethod::Symlink => {
-            let result = symlink(&file_event.path, &target_path);
+            LocalTargetMethod::Symlink => {
+                let result = symlink(&file_event.path, &target_path);
 
-            match result {
-                Ok(()) => {
-                    debug!("Symlinked '{}' to '{}'", &source_path_str, &target_path_str);
-                    Ok(())
-                }
-                Err(e) => {
-                    if overwrite {
-                        // When overwrite is enabled, this should not occur, because any existing
-                        // file should first be removed
-                        error!(
-                            "[E01007] Error symlinking '{}' to '{}': {}",
-                            &source_path_str, &target_path_str, &e
-                        );
-                        Err(())
-                    } else {
-                        // When overwrite is disabled, this might occur and is not an error, but it
-                        // is reported
-                        warn!(
-                            "Could not symlink '{}' to '{}': {}",
-                            &source_path_str, &target_path_str, e
-                        );
+                match result {
+                    Ok(()) => {
+                        debug!("Symlinked '{}' to '{}'", &source_path_str, &target_path_str);
                         Ok(())
                     }
+                    Err(e) => {
+                        if overwrite {
+                            // When overwrite is enabled, this should not occur, because any existing
+                            // file should first be removed
+                            coded_error!(
+                                ErrorCode::TargetSymlink,
+                                "Error symlinking '{}' to '{}': {}",
+                                &source_path_str,
+                                &target_path_str,
+                                &e
+                            );
+                            Err(())
+                        } else {
+                            // When overwrite is disabled, this might occur and is not an error, but it
+                            // is reported
+                            warn!(
+                                "Could not symlink '{}' to '{}': {}",
+                                &source_path_str, &target_path_str, e
+                            );
+                            Ok(())
+                        }
+                    }
                 }
             }
         }
     };
 
+    metrics::add_component_time("target_copy", &target_name, placement_start.elapsed());
+
     if placement_result.is_ok() {
-        let set_result = set_permissions(&target_path, target_perms);
+        let set_result = set_permissions(&target_path, target_perms.clone());
 
         if let Err(e) = set_result {
             error!(
@@ -155,21 +281,707 @@ pub async fn handle_file_event(
                 &target_path_str, e
             )
         }
-    }
 
-    let insert_result = persistence
-        .insert_dispatched(&target_name, file_event.file_id)
-        .await;
+        if let Some(checksum_sidecar) = &settings.checksum_sidecar {
+            let write_result = write_checksum_sidecar(
+                checksum_sidecar,
+                &file_event,
+                &target_path,
+                overwrite,
+                target_perms,
+            );
+
+            if let Err(e) = write_result {
+                error!(
+                    "Error writing checksum sidecar of '{}': {}",
+                    &target_path_str, e
+                );
+            }
+        }
+
+        // Hardlinks and symlinks add no data, so only their directory entry
+        // is synced
+        if settings.fsync == Fsync::FileAndDir && !placed {
+            if let Err(e) = sync_parent_directory(&target_path) {
+                error!("Error syncing directory of '{}': {}", &target_path_str, e);
+            }
+        }
+    } else if let Some(checksum_sidecar) = &settings.checksum_sidecar {
+        // The sidecar of a file that was removed for overwriting would no
+        // longer describe the file in the target
+        let sidecar_path = checksum_sidecar_path(checksum_sidecar, &target_path);
 
-    match insert_result {
-        Ok(_) => debug!("Dispatched to directory"),
-        Err(e) => debug!("Error persisting dispatch: {}", &e),
+        match std::fs::remove_file(&sidecar_path) {
+            Ok(()) => debug!(
+                "Removed checksum sidecar '{}'",
+                sidecar_path.to_string_lossy()
+            ),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!(
+                "Error removing checksum sidecar '{}': {}",
+                sidecar_path.to_string_lossy(),
+                e
+            ),
+        }
     }
 
+    metrics::TARGET_LATENCY_HISTOGRAM
+        .with_label_values(&[&target_name])
+        .observe(file_event.age().as_secs_f64());
+
     Ok(FileEvent {
         file_id: file_event.file_id,
         source_name: target_name.clone(),
+        relative_path: relative_target_path(settings, &target_path),
         path: target_path,
         hash: file_event.hash.clone(),
+        content_hash: file_event.content_hash,
+        size: file_event.size,
+        modified: file_event.modified,
+        created: file_event.created,
+        discovered: file_event.discovered,
+        ack: None,
+        confirmation: file_event.confirmation,
     })
 }
+
+/// Register the dispatch of a placed file to the target, retrying with
+/// backoff when the database fails, e.g. because it is locked
+///
+/// The `dispatched_record_failures_total` metric counts the dispatches that
+/// could not be registered after all attempts.
+pub async fn record_dispatched(
+    persistence: &SqliteAsyncPersistence,
+    target_name: &str,
+    file_id: i64,
+) -> Result<(), PersistenceError> {
+    let mut delay = RECORD_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match persistence.insert_dispatched(target_name, file_id).await {
+            Ok(()) => {
+                debug!("Dispatched file {file_id} to directory target '{target_name}'");
+
+                return Ok(());
+            }
+            Err(e) if attempt < RECORD_ATTEMPTS => {
+                debug!("Attempt {attempt} to register dispatch failed: {e}");
+
+                tokio::time::sleep(delay).await;
+
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                metrics::DISPATCHED_RECORD_FAILURES_COUNTER
+                    .with_label_values(&[target_name])
+                    .inc();
+
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Handle a file event in a dry run, which only logs where the file would be
+/// placed
+pub fn dry_run_file_event(
+    settings: &settings::DirectoryTarget,
+    file_event: FileEvent,
+) -> Result<FileEvent, String> {
+    let target_path = target_path(settings, &file_event)?;
+
+    info!(
+        "Would place '{}' in directory target '{}' as '{}'",
+        file_event.path.to_string_lossy(),
+        &settings.name,
+        target_path.to_string_lossy()
+    );
+
+    metrics::TARGET_LATENCY_HISTOGRAM
+        .with_label_values(&[&settings.name])
+        .observe(file_event.age().as_secs_f64());
+
+    Ok(FileEvent {
+        source_name: settings.name.clone(),
+        relative_path: relative_target_path(settings, &target_path),
+        path: target_path,
+        ..file_event
+    })
+}
+
+/// Whether the target path is the file of the event, placed with the method
+/// of the target
+fn already_placed(method: &LocalTargetMethod, file_event: &FileEvent, target_path: &Path) -> bool {
+    let Ok(target_metadata) = std::fs::symlink_metadata(target_path) else {
+        return false;
+    };
+
+    if let LocalTargetMethod::Symlink = method {
+        return std::fs::read_link(target_path).is_ok_and(|link| link == file_event.path);
+    }
+
+    let Ok(source_metadata) = std::fs::metadata(&file_event.path) else {
+        return false;
+    };
+
+    if target_metadata.dev() == source_metadata.dev()
+        && target_metadata.ino() == source_metadata.ino()
+    {
+        return true;
+    }
+
+    if !target_metadata.is_file() || target_metadata.len() != source_metadata.len() {
+        return false;
+    }
+
+    if let LocalTargetMethod::Hardlink = method {
+        return false;
+    }
+
+    let source_checksum = if file_event.content_hash && !file_event.hash.is_empty() {
+        Ok(file_event.hash.clone())
+    } else {
+        file_checksum::<Sha256>(&file_event.path)
+    };
+
+    match (source_checksum, file_checksum::<Sha256>(target_path)) {
+        (Ok(source_checksum), Ok(target_checksum)) => source_checksum == target_checksum,
+        _ => false,
+    }
+}
+
+fn checksum_sidecar_path(checksum_sidecar: &ChecksumSidecar, target_path: &Path) -> PathBuf {
+    let mut file_name = target_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(checksum_sidecar.algorithm.extension());
+
+    target_path.with_file_name(file_name)
+}
+
+/// Write the checksum sidecar of a file placed in the target directory. The
+/// sidecar is written under a temporary name first, so that it appears
+/// atomically.
+fn write_checksum_sidecar(
+    checksum_sidecar: &ChecksumSidecar,
+    file_event: &FileEvent,
+    target_path: &Path,
+    overwrite: bool,
+    permissions: Permissions,
+) -> io::Result<()> {
+    let sidecar_path = checksum_sidecar_path(checksum_sidecar, target_path);
+
+    if !overwrite && sidecar_path.exists() {
+        return Ok(());
+    }
+
+    let checksum = match checksum_sidecar.algorithm {
+        ChecksumAlgorithm::Sha256 if file_event.content_hash && !file_event.hash.is_empty() => {
+            file_event.hash.clone()
+        }
+        ChecksumAlgorithm::Sha256 => file_checksum::<Sha256>(target_path)?,
+        ChecksumAlgorithm::Sha512 => file_checksum::<Sha512>(target_path)?,
+    };
+
+    let content = match checksum_sidecar.format {
+        ChecksumFormat::Hex => format!("{checksum}\n"),
+        ChecksumFormat::Coreutils => format!(
+            "{}  {}\n",
+            checksum,
+            target_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+        ),
+    };
+
+    let part_path = part_path(&sidecar_path);
+
+    std::fs::write(&part_path, content)?;
+    set_permissions(&part_path, permissions)?;
+    rename(&part_path, &sidecar_path)?;
+
+    debug!(
+        "Wrote checksum sidecar '{}'",
+        sidecar_path.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+/// Hidden temporary name in the same directory under which a file is written
+/// before it is renamed
+fn part_path(path: &Path) -> PathBuf {
+    let mut part_name = OsString::from(".");
+    part_name.push(path.file_name().unwrap_or_default());
+    part_name.push(".part");
+
+    path.with_file_name(part_name)
+}
+
+/// Copy a file under a temporary name and sync it to disk before renaming
+/// it, so that it only appears in the directory with its data on disk
+fn copy_synced(source_path: &Path, target_path: &Path) -> io::Result<u64> {
+    let part_path = part_path(target_path);
+
+    let result = copy(source_path, &part_path).and_then(|size| {
+        File::open(&part_path)?.sync_all()?;
+        rename(&part_path, target_path)?;
+
+        Ok(size)
+    });
+
+    if result.is_err() {
+        // Leave no partial file behind
+        let _ = std::fs::remove_file(&part_path);
+    }
+
+    result
+}
+
+fn file_checksum<D: Digest>(path: &Path) -> io::Result<String> {
+    checksum::<D>(&mut File::open(path)?)
+}
+
+fn checksum<D: Digest>(reader: &mut impl Read) -> io::Result<String> {
+    let mut writer = HashWriter::<D, io::Sink>::new(io::sink());
+
+    io::copy(reader, &mut writer)?;
+
+    Ok(hex::encode(writer.finalize()))
+}
+
+/// Reader that is slowed down to a maximum number of bytes per second
+struct RateLimitedReader<R> {
+    inner: R,
+    bytes_per_second: u64,
+    start: Instant,
+    bytes_read: u64,
+}
+
+impl<R: Read> Read for RateLimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let due = Duration::from_secs_f64(self.bytes_read as f64 / self.bytes_per_second as f64);
+
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            std::thread::sleep(ahead);
+        }
+
+        let size = self.inner.read(buf)?;
+        self.bytes_read += size as u64;
+
+        Ok(size)
+    }
+}
+
+/// Whether the stored file of an event differs from what was registered,
+/// with a description of the difference
+///
+/// Only the size is compared for files larger than `max_verify_size_bytes`
+/// and files of which the registered hash is not over their content.
+fn stored_file_difference(
+    settings: &settings::DirectoryTarget,
+    file_event: &FileEvent,
+) -> io::Result<Option<String>> {
+    let size = std::fs::metadata(&file_event.path)?.len();
+
+    if size != file_event.size {
+        return Ok(Some(format!("size {size} instead of {}", file_event.size)));
+    }
+
+    let hash_verifiable = file_event.content_hash
+        && !file_event.hash.is_empty()
+        && settings
+            .max_verify_size_bytes
+            .is_none_or(|max_verify_size| size <= max_verify_size);
+
+    if !hash_verifiable {
+        return Ok(None);
+    }
+
+    let mut file = File::open(&file_event.path)?;
+
+    let hash = match settings.verify_bytes_per_second {
+        Some(bytes_per_second) => checksum::<Sha256>(&mut RateLimitedReader {
+            inner: file,
+            bytes_per_second,
+            start: Instant::now(),
+            bytes_read: 0,
+        })?,
+        None => checksum::<Sha256>(&mut file)?,
+    };
+
+    Ok((hash != file_event.hash).then(|| format!("hash {hash} instead of {}", file_event.hash)))
+}
+
+/// Path to which a corrupted file in storage is moved, so that it is not
+/// dispatched again
+fn quarantine_path(path: &Path) -> PathBuf {
+    let mut quarantine_path = path.as_os_str().to_os_string();
+    quarantine_path.push(".corrupt");
+
+    PathBuf::from(quarantine_path)
+}
+
+/// Check that the stored file of an event still matches its registration,
+/// quarantining it when it does not
+async fn verify_stored_file(
+    settings: &settings::DirectoryTarget,
+    file_event: &FileEvent,
+) -> Result<(), String> {
+    let path_str = file_event.path.to_string_lossy().to_string();
+
+    let difference = {
+        let settings = settings.clone();
+        let file_event = file_event.clone();
+
+        tokio::task::spawn_blocking(move || stored_file_difference(&settings, &file_event))
+            .await
+            .map_err(|e| format!("Join error verifying '{path_str}': {e}"))?
+            .map_err(|e| format!("Error verifying '{path_str}': {e}"))?
+    };
+
+    let Some(difference) = difference else {
+        return Ok(());
+    };
+
+    metrics::CORRUPTION_DETECTED_COUNTER
+        .with_label_values(&[&file_event.source_name])
+        .inc();
+
+    let quarantine_path = quarantine_path(&file_event.path);
+
+    match rename(&file_event.path, &quarantine_path) {
+        Ok(()) => coded_error!(
+            ErrorCode::Corruption,
+            "'{}' of source '{}' has {}, moved it to '{}'",
+            path_str,
+            file_event.source_name,
+            difference,
+            quarantine_path.to_string_lossy()
+        ),
+        Err(e) => coded_error!(
+            ErrorCode::Corruption,
+            "'{}' of source '{}' has {}, could not move it to '{}': {}",
+            path_str,
+            file_event.source_name,
+            difference,
+            quarantine_path.to_string_lossy(),
+            e
+        ),
+    }
+
+    Err(format!(
+        "Not dispatching '{path_str}' to '{}', it no longer matches its registration",
+        settings.name
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_sidecar_uses_carried_hash_of_content() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-directory-target-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let target_path = directory.join("data.csv");
+        std::fs::write(&target_path, "a,b\n").unwrap();
+
+        let mut file_event = FileEvent {
+            file_id: 1,
+            source_name: "red".to_string(),
+            path: target_path.clone(),
+            relative_path: None,
+            hash: "carried".to_string(),
+            content_hash: true,
+            size: 4,
+            modified: chrono::Utc::now(),
+            created: chrono::Utc::now(),
+            discovered: None,
+            ack: None,
+            confirmation: None,
+        };
+
+        let mut checksum_sidecar = ChecksumSidecar {
+            algorithm: ChecksumAlgorithm::Sha256,
+            format: ChecksumFormat::Hex,
+        };
+
+        let sidecar_path = directory.join("data.csv.sha256");
+        let permissions = Permissions::from_mode(0o644);
+
+        let write = |checksum_sidecar: &ChecksumSidecar, file_event: &FileEvent, overwrite| {
+            write_checksum_sidecar(
+                checksum_sidecar,
+                file_event,
+                &target_path,
+                overwrite,
+                permissions.clone(),
+            )
+            .unwrap();
+
+            std::fs::read_to_string(&sidecar_path).unwrap()
+        };
+
+        let carried = write(&checksum_sidecar, &file_event, true);
+
+        file_event.content_hash = false;
+        let not_overwritten = write(&checksum_sidecar, &file_event, false);
+
+        checksum_sidecar.format = ChecksumFormat::Coreutils;
+        let recomputed = write(&checksum_sidecar, &file_event, true);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(carried, "carried\n");
+        assert_eq!(not_overwritten, "carried\n");
+        assert_eq!(
+            recomputed,
+            format!("{}  data.csv\n", hex::encode(Sha256::digest(b"a,b\n")))
+        );
+    }
+
+    #[tokio::test]
+    async fn redelivered_events_are_dispatched_once() {
+        let directory = std::env::temp_dir().join(format!(
+            "cortex-directory-target-redelivery-{}",
+            std::process::id()
+        ));
+        let target_directory = directory.join("target");
+        std::fs::create_dir_all(&target_directory).unwrap();
+
+        let source_path = directory.join("data.csv");
+        std::fs::write(&source_path, "a,b\n").unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = std::sync::Arc::new(std::sync::Mutex::new(conn));
+        let persistence = SqliteAsyncPersistence::new(conn.clone());
+
+        let file_id = persistence
+            .insert_file(
+                "red",
+                &source_path.to_string_lossy(),
+                &chrono::Utc::now(),
+                4,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let file_event = FileEvent {
+            file_id,
+            source_name: "red".to_string(),
+            path: source_path.clone(),
+            relative_path: None,
+            hash: String::new(),
+            content_hash: false,
+            size: 4,
+            modified: chrono::Utc::now(),
+            created: chrono::Utc::now(),
+            discovered: None,
+            ack: None,
+            confirmation: None,
+        };
+
+        let mut settings = settings::Settings::default().directory_targets[0].clone();
+        settings.name = "red-consumer".to_string();
+        settings.directory = target_directory.clone();
+        settings.overwrite = false;
+
+        let mut placed = Vec::new();
+        let mut deduplication_ids = Vec::new();
+
+        for method in [LocalTargetMethod::Hardlink, LocalTargetMethod::Copy] {
+            settings.method = method.clone();
+
+            for _ in 0..2 {
+                let result_event = handle_file_event(&settings, file_event.clone())
+                    .await
+                    .unwrap();
+
+                record_dispatched(&persistence, &settings.name, result_event.file_id)
+                    .await
+                    .unwrap();
+
+                placed.push(already_placed(&method, &file_event, &result_event.path));
+                deduplication_ids.push(crate::notifier::deduplication_id(
+                    &result_event,
+                    &settings.name,
+                ));
+            }
+
+            std::fs::remove_file(target_directory.join("data.csv")).unwrap();
+        }
+
+        let dispatched: i64 = conn
+            .lock()
+            .unwrap()
+            .query_row("select count(*) from dispatched", [], |row| row.get(0))
+            .unwrap();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(placed, vec![true; 4]);
+        assert_eq!(dispatched, 1);
+        assert!(deduplication_ids
+            .iter()
+            .all(|id| *id == format!("{file_id}:{}", settings.name)));
+        // Targets that share a notifier publish different ids for the file
+        assert_ne!(
+            crate::notifier::deduplication_id(&file_event, &settings.name),
+            crate::notifier::deduplication_id(&file_event, "other")
+        );
+    }
+
+    #[tokio::test]
+    async fn structure_is_preserved_inside_the_directory() {
+        let directory = std::env::temp_dir().join(format!(
+            "cortex-directory-target-structure-{}",
+            std::process::id()
+        ));
+        let target_directory = directory.join("target");
+        std::fs::create_dir_all(&target_directory).unwrap();
+
+        let source_path = directory.join("data.csv");
+        std::fs::write(&source_path, "a,b\n").unwrap();
+
+        let mut settings = settings::Settings::default().directory_targets[0].clone();
+        settings.directory = target_directory.clone();
+        settings.preserve_structure = true;
+
+        let file_event = |relative_path: &str| FileEvent {
+            file_id: 1,
+            source_name: "red".to_string(),
+            path: source_path.clone(),
+            relative_path: Some(PathBuf::from(relative_path)),
+            hash: String::new(),
+            content_hash: false,
+            size: 4,
+            modified: chrono::Utc::now(),
+            created: chrono::Utc::now(),
+            discovered: None,
+            ack: None,
+            confirmation: None,
+        };
+
+        let placed = handle_file_event(&settings, file_event("2024/06/data.csv"))
+            .await
+            .unwrap();
+
+        let escaped = handle_file_event(&settings, file_event("../data.csv")).await;
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(placed.path, target_directory.join("2024/06/data.csv"));
+        assert_eq!(
+            placed.relative_path,
+            Some(PathBuf::from("2024/06/data.csv"))
+        );
+        assert!(escaped.is_err());
+    }
+
+    #[tokio::test]
+    async fn synced_copies_appear_complete() {
+        let directory = std::env::temp_dir().join(format!(
+            "cortex-directory-target-fsync-{}",
+            std::process::id()
+        ));
+        let target_directory = directory.join("target");
+        std::fs::create_dir_all(&target_directory).unwrap();
+
+        let source_path = directory.join("data.csv");
+        std::fs::write(&source_path, "a,b\n").unwrap();
+
+        let mut settings = settings::Settings::default().directory_targets[0].clone();
+        settings.directory = target_directory.clone();
+        settings.method = LocalTargetMethod::Copy;
+        settings.permissions = 0o644;
+        settings.fsync = Fsync::FileAndDir;
+
+        let file_event = FileEvent {
+            file_id: 1,
+            source_name: "red".to_string(),
+            path: source_path,
+            relative_path: None,
+            hash: String::new(),
+            content_hash: false,
+            size: 4,
+            modified: chrono::Utc::now(),
+            created: chrono::Utc::now(),
+            discovered: None,
+            ack: None,
+            confirmation: None,
+        };
+
+        let placed = handle_file_event(&settings, file_event).await.unwrap();
+
+        let content = std::fs::read_to_string(&placed.path).unwrap();
+        let entries = std::fs::read_dir(&target_directory).unwrap().count();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(content, "a,b\n");
+        // No part file is left behind
+        assert_eq!(entries, 1);
+    }
+
+    #[tokio::test]
+    async fn corrupted_files_are_quarantined() {
+        let directory = std::env::temp_dir().join(format!(
+            "cortex-directory-target-verify-{}",
+            std::process::id()
+        ));
+        let target_directory = directory.join("target");
+        std::fs::create_dir_all(&target_directory).unwrap();
+
+        let mut settings = settings::Settings::default().directory_targets[0].clone();
+        settings.directory = target_directory.clone();
+        settings.method = LocalTargetMethod::Copy;
+        settings.permissions = 0o644;
+        settings.verify_hash_on_dispatch = true;
+        settings.verify_bytes_per_second = Some(1_000_000);
+
+        let file_event = |name: &str| {
+            let path = directory.join(name);
+            std::fs::write(&path, "a,b\n").unwrap();
+
+            FileEvent {
+                file_id: 1,
+                source_name: "red".to_string(),
+                path,
+                relative_path: None,
+                hash: hex::encode(Sha256::digest("a,b\n")),
+                content_hash: true,
+                size: 4,
+                modified: chrono::Utc::now(),
+                created: chrono::Utc::now(),
+                discovered: None,
+                ack: None,
+                confirmation: None,
+            }
+        };
+
+        let intact = handle_file_event(&settings, file_event("intact.csv")).await;
+
+        // A flipped bit, which leaves the size the same
+        let corrupted_event = file_event("corrupted.csv");
+        std::fs::write(&corrupted_event.path, "a,c\n").unwrap();
+
+        let corrupted = handle_file_event(&settings, corrupted_event).await;
+
+        let quarantined = directory.join("corrupted.csv.corrupt").exists();
+        let placed = target_directory.join("corrupted.csv").exists();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(intact.is_ok());
+        assert!(corrupted.is_err());
+        assert!(quarantined);
+        assert!(!placed);
+    }
+}
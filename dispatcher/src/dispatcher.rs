@@ -1,18 +1,20 @@
 use futures::future::join_all;
+use futures::stream::FuturesUnordered;
+use futures::Future;
 use rustls::client::danger::HandshakeSignatureValid;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs;
 use std::iter::Iterator;
-use std::ops::Deref;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
 use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
 use rustls::DigitallySignedStruct;
 
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::sync::watch;
 
 use futures::stream::StreamExt;
@@ -21,134 +23,668 @@ use signal_hook_tokio::Signals;
 
 use crossbeam_channel::{bounded, Receiver, Sender};
 
-use log::{debug, error, info};
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use serde_json::json;
 
-use cortex_core::{wait_for, SftpDownload};
+use cortex_core::error_code::ErrorCode;
+use cortex_core::log_throttle;
+use cortex_core::sftp_connection::{HostSessions, SessionLimits};
+use cortex_core::{coded_error, throttled_error, wait_for, SftpDownload};
 
-use crate::base_types::{Connection, RabbitMQNotifier, Source, Target};
+use crate::archive_target::{handle_archive_events, log_archive_events, ArchiveWriter};
+use crate::audit::{start_audit_writer, AuditSender};
+use crate::base_types::{Connection, Connections, MessageResponse, Source, Target};
+use crate::control;
+use crate::delivery::{Confirmation, DeliveredRemovals};
 
 #[cfg(target_os = "linux")]
 use crate::directory_source::start_directory_sources;
 use crate::directory_source::{start_directory_sweep, start_local_intake_thread};
-
-use crate::directory_target::handle_file_event;
-use crate::event::{EventDispatcher, FileEvent};
-use crate::local_storage::LocalStorage;
+use crate::intake_journal::{journal_path, IntakeJournal};
+use crate::remote_removal::RemoteRemovals;
+
+use crate::directory_target::{
+    dry_run_file_event, handle_file_event, record_dispatched, target_path,
+};
+use crate::drain::Drain;
+use crate::event::{
+    file_event_channel, EventDispatcher, FileEvent, FileEventReceiver, FileEventSender,
+};
+use crate::event_publisher::{start_event_publisher, EventPublisher};
+use crate::heartbeat::Heartbeats;
+use crate::http_server::start_http_server;
+use crate::local_storage::{source_placements, storage_areas, LocalStorage};
+use crate::logging;
+use crate::metrics;
+use crate::notification_spool::spool_directory;
+use crate::notifier::{NamedNotifiers, Notifier};
+use crate::path_lock::PathLocks;
+use crate::pause::SourcePauses;
 use crate::persistence::{self};
-use crate::persistence::{SqliteAsyncPersistence, SqlitePersistence};
+use crate::persistence::{
+    DispatchDecision, DryRunPersistence, SqliteAsyncPersistence, SqlitePersistence,
+};
+use crate::probe;
+use crate::rate_limit::TokenBucket;
+use crate::readiness::Readiness;
+use crate::reconcile::reconcile_on_startup;
+use crate::report::ReportSender;
+use crate::retention::RetentionCleanup;
+use crate::runtime_targets::{RuntimeOverrides, RuntimeTargets};
 use crate::settings;
 use crate::sftp_command_consumer;
 use crate::sftp_downloader;
+use crate::source_activity::SourceActivities;
+use crate::storage_usage::start_storage_usage_walker;
+use crate::systemd::{self, Startup};
+use crate::write_behind::WriteBehind;
 use cortex_core::error::DispatcherError;
 
-pub async fn target_directory_handler(
+/// Start the tasks that handle the file events of the directory targets
+pub fn target_directory_handler(
     tokio_persistence: SqliteAsyncPersistence,
     settings: settings::Settings,
     stop_receiver: watch::Receiver<()>,
     targets: Arc<Mutex<HashMap<String, Arc<Target>>>>,
+    heartbeats: Heartbeats,
+    notifiers: &NamedNotifiers,
+    dry_run: bool,
+) -> Vec<CriticalTask> {
+    settings
+        .directory_targets
+        .iter()
+        .map(|target_conf| {
+            let (target, join_handle) = start_directory_target(
+                target_conf.clone(),
+                tokio_persistence.clone(),
+                &settings.channels,
+                stop_receiver.clone(),
+                heartbeats.clone(),
+                notifiers,
+                dry_run,
+            );
+
+            match targets.lock() {
+                Ok(mut guard) => {
+                    guard.insert(target_conf.name.clone(), target);
+                }
+                Err(e) => error!(
+                    "Could not get lock on targets hash for adding Target: {}",
+                    e
+                ),
+            }
+
+            critical_task(
+                format!("directory target '{}'", target_conf.name),
+                join_handle,
+            )
+        })
+        .collect()
+}
+
+/// Start the task that handles the file events of a directory target
+///
+/// The task handles the events until the stop signal, or until the target is
+/// removed and its channel is closed. After a removal the task waits for the
+/// stop signal, so that it does not end as a failed critical task.
+///
+/// In a dry run, files are not placed and notifications are not published.
+pub fn start_directory_target(
+    target_conf: settings::DirectoryTarget,
+    persistence: SqliteAsyncPersistence,
+    channels: &settings::Channels,
+    mut stop_receiver: watch::Receiver<()>,
+    heartbeats: Heartbeats,
+    notifiers: &NamedNotifiers,
+    dry_run: bool,
+) -> (Arc<Target>, tokio::task::JoinHandle<()>) {
+    let (sender, receiver) = file_event_channel(&format!("target:{}", target_conf.name), channels);
+
+    let rate_limiter = target_conf.rate_limit.as_ref().map(TokenBucket::new);
+
+    let notifier = target_conf
+        .notify
+        .as_ref()
+        .map(|notify| notifiers.notifier(notify, &target_conf.name, dry_run));
+
+    let target = Arc::new(Target {
+        name: target_conf.name.clone(),
+        sender,
+        in_progress: Arc::default(),
+    });
+
+    let target_name = target_conf.name.clone();
+
+    let queued_receiver = receiver.clone();
+
+    let heartbeat = heartbeats.keep(
+        format!("directory_target:{target_name}"),
+        move || json!({ "queued_events": queued_receiver.len() }),
+    );
+
+    let fut = handle_target_events(
+        target_conf,
+        receiver,
+        rate_limiter,
+        notifier,
+        persistence,
+        dry_run,
+        DISPATCH_RETRY_INTERVAL,
+        target.in_progress.clone(),
+    );
+
+    let join_handle = tokio::spawn(async move {
+        tokio::select!(
+            _a = fut => info!("Directory target '{target_name}' removed"),
+            _b = stop_receiver.changed() => return,
+            _c = heartbeat => ()
+        );
+
+        let _ = stop_receiver.changed().await;
+    });
+
+    (target, join_handle)
+}
+
+/// Interval between retries of dispatches to a directory target that could
+/// not be registered or notified
+const DISPATCH_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Dispatch of a placed file that is retried without placing the file again,
+/// with the name of the source of the file
+#[derive(Debug)]
+enum PendingDispatch {
+    /// The dispatch is not registered yet, and not notified
+    Record(FileEvent, String),
+    /// The dispatch is registered, but not notified
+    Notify(FileEvent, String),
+}
+
+/// Complete the dispatch of a placed file by registering it and then sending
+/// the notification, returning the dispatch when a step failed
+///
+/// No notification is sent for a dispatch that is not registered, so that
+/// consumers are not told about files that the database does not know to be
+/// in the target.
+async fn complete_dispatch(
+    dispatch: PendingDispatch,
+    target_name: &str,
+    persistence: &SqliteAsyncPersistence,
+    notifier: &Option<tokio::sync::Mutex<Box<dyn Notifier + Send>>>,
+) -> Option<PendingDispatch> {
+    let (result_event, source) = match dispatch {
+        PendingDispatch::Record(result_event, source) => {
+            let record_result =
+                record_dispatched(persistence, target_name, result_event.file_id).await;
+
+            if let Err(e) = record_result {
+                coded_error!(
+                    e.code(),
+                    "Error registering dispatch of '{}' to '{}', retrying later: {}",
+                    result_event.path.to_string_lossy(),
+                    target_name,
+                    e
+                );
+
+                return Some(PendingDispatch::Record(result_event, source));
+            }
+
+            metrics::DELIVERY_LATENCY_HISTOGRAM
+                .with_label_values(&[&source, target_name])
+                .observe(result_event.age().as_secs_f64());
+
+            (result_event, source)
+        }
+        PendingDispatch::Notify(result_event, source) => (result_event, source),
+    };
+
+    // The file is handled once the dispatch is registered
+    if let Some(confirmation) = &result_event.confirmation {
+        confirmation.confirm();
+    }
+
+    if let Some(notifier) = notifier {
+        let mut notifier = notifier.lock().await;
+
+        let notify_start = Instant::now();
+        let notify_result = notifier.notify(&result_event, target_name).await;
+
+        metrics::add_component_time("notify", target_name, notify_start.elapsed());
+
+        if let Err(e) = notify_result {
+            throttled_error!(
+                ErrorCode::Notify,
+                target_name,
+                "Error notifying about '{}', retrying later: {}",
+                result_event.path.to_string_lossy(),
+                e
+            );
+
+            return Some(PendingDispatch::Notify(result_event, source));
+        }
+
+        debug!("Notified about '{}'", result_event.path.to_string_lossy());
+    }
+
+    if let Some(since_discovery) = result_event.since_discovery() {
+        metrics::END_TO_END_LATENCY_HISTOGRAM
+            .with_label_values(&[&source, target_name])
+            .observe(since_discovery.as_secs_f64());
+    }
+
+    None
+}
+
+/// Handle the file events of a directory target, placing up to the
+/// configured concurrency of files at the same time
+///
+/// Every placed file is registered as dispatched and then notified. The steps
+/// that fail are retried every retry interval, from memory, so after a
+/// restart the reconcile setting covers files that were never registered.
+/// When the channel is closed, the retries continue until they succeed.
+#[allow(clippy::too_many_arguments)]
+async fn handle_target_events(
+    target_conf: settings::DirectoryTarget,
+    receiver: FileEventReceiver,
+    rate_limiter: Option<TokenBucket>,
+    notifier: Option<Box<dyn Notifier + Send>>,
+    persistence: SqliteAsyncPersistence,
+    dry_run: bool,
+    retry_interval: Duration,
+    in_progress: Arc<AtomicUsize>,
 ) {
-    settings.directory_targets.iter().for_each(|target_conf| {
-        let persistence = tokio_persistence.clone();
-        let (sender, mut receiver) = unbounded_channel::<FileEvent>();
-
-        let c_target_conf = target_conf.clone();
-        let d_target_conf = target_conf.clone();
-
-        match c_target_conf.notify {
-            Some(conf) => match conf {
-                settings::Notify::RabbitMQ(notify_conf) => {
-                    let fut = async move {
-                        debug!("Connecting notifier to directory target stream");
-
-                        let mut notify = RabbitMQNotifier::from(&notify_conf);
-
-                        let routing_key = notify_conf.routing_key.clone();
-
-                        while let Some(file_event) = receiver.recv().await {
-                            match handle_file_event(&d_target_conf, file_event, persistence.clone())
-                                .await
-                            {
-                                Ok(result_event) => {
-                                    debug!("Notifying with AMQP routing key {}", &routing_key);
-
-                                    match notify.notify(result_event).await {
-                                        Err(e) => error!("{e}"),
-                                        Ok(_) => debug!("published"),
-                                    };
-                                }
-                                Err(e) => {
-                                    error!("Error handling event for directory target: {}", &e);
-                                }
-                            }
-                        }
-                    };
+    let target_name = target_conf.name.as_str();
 
-                    let mut stop_receiver_clone = stop_receiver.clone();
+    let file_events = futures::stream::unfold(
+        (receiver, rate_limiter),
+        |(receiver, mut rate_limiter)| async move {
+            let file_event = receive_file_event(&receiver, &mut rate_limiter, target_name).await?;
 
-                    tokio::spawn(async move {
-                        tokio::select!(
-                            _a = fut => (),
-                            _b = stop_receiver_clone.changed() => ()
-                        )
-                    })
-                }
-            },
-            None => {
-                let fut = async move {
-                    while let Some(file_event) = receiver.recv().await {
-                        if let Err(e) =
-                            handle_file_event(&d_target_conf, file_event, persistence.clone()).await
+            Some((file_event, (receiver, rate_limiter)))
+        },
+    );
+
+    let target_conf = &target_conf;
+    let notifier = &notifier.map(tokio::sync::Mutex::new);
+    let persistence = &persistence;
+    let path_locks = &PathLocks::default();
+    let pending: &Mutex<VecDeque<PendingDispatch>> = &Mutex::new(VecDeque::new());
+    let events_handled = &AtomicBool::new(false);
+    let in_progress = &in_progress;
+
+    let handle_events = async {
+        file_events
+            .for_each_concurrent(target_conf.concurrency, |file_event| async move {
+                in_progress.fetch_add(1, Ordering::SeqCst);
+
+                let target_path = match target_path(target_conf, &file_event) {
+                    Ok(target_path) => target_path,
+                    Err(e) => {
+                        error!("Error handling event for directory target: {}", &e);
+                        in_progress.fetch_sub(1, Ordering::SeqCst);
+                        return;
+                    }
+                };
+
+                // Files placed at the same path are handled one after the other,
+                // to avoid racing renames
+                let _path_guard = path_locks.lock(&target_path).await;
+
+                let source = file_event.source_name.clone();
+
+                // Nothing is registered in a dry run
+                let result = if dry_run {
+                    dry_run_file_event(target_conf, file_event)
+                        .map(|result_event| PendingDispatch::Notify(result_event, source))
+                } else {
+                    handle_file_event(target_conf, file_event)
+                        .await
+                        .map(|result_event| PendingDispatch::Record(result_event, source))
+                };
+
+                match result {
+                    Ok(dispatch) => {
+                        // The notification is sent after its own file is placed
+                        if let Some(dispatch) =
+                            complete_dispatch(dispatch, target_name, persistence, notifier).await
                         {
-                            error!("Error handling event for directory target: {}", &e);
+                            // Counted as in progress until the retry succeeds
+                            in_progress.fetch_add(1, Ordering::SeqCst);
+                            pending.lock().unwrap().push_back(dispatch);
                         }
                     }
-                };
+                    Err(e) => {
+                        error!("Error handling event for directory target: {}", &e);
+                    }
+                }
+
+                in_progress.fetch_sub(1, Ordering::SeqCst);
+            })
+            .await;
+
+        events_handled.store(true, Ordering::SeqCst);
+    };
+
+    let retry_pending = async {
+        loop {
+            tokio::time::sleep(retry_interval).await;
+
+            let retries: Vec<PendingDispatch> = pending.lock().unwrap().drain(..).collect();
+
+            for dispatch in retries {
+                match complete_dispatch(dispatch, target_name, persistence, notifier).await {
+                    Some(dispatch) => pending.lock().unwrap().push_back(dispatch),
+                    None => {
+                        in_progress.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+            }
+
+            if events_handled.load(Ordering::SeqCst) && pending.lock().unwrap().is_empty() {
+                break;
+            }
+        }
+    };
+
+    futures::join!(handle_events, retry_pending);
+}
+
+/// Start the tasks that bundle the files of the archive targets into archives
+///
+/// Every archive target gets a source with its own name, from which its
+/// completed archives are dispatched. The done sender is dropped by each task
+/// when it has completed its open archive.
+#[allow(clippy::too_many_arguments)]
+pub fn archive_target_handler(
+    tokio_persistence: SqliteAsyncPersistence,
+    settings: &settings::Settings,
+    stop_receiver: watch::Receiver<()>,
+    targets: Arc<Mutex<HashMap<String, Arc<Target>>>>,
+    sources: &mut Vec<Source>,
+    control_senders: &mut HashMap<String, FileEventSender>,
+    done_sender: tokio::sync::mpsc::Sender<()>,
+    heartbeats: Heartbeats,
+    dry_run: bool,
+) -> Vec<CriticalTask> {
+    settings
+        .archive_targets
+        .iter()
+        .map(|target_conf| {
+            let (sender, receiver) =
+                file_event_channel(&format!("target:{}", target_conf.name), &settings.channels);
+
+            let (source_sender, source_receiver) =
+                file_event_channel(&format!("source:{}", target_conf.name), &settings.channels);
+
+            sources.push(Source {
+                name: target_conf.name.clone(),
+                receiver: source_receiver,
+            });
+
+            control_senders.insert(target_conf.name.clone(), source_sender.clone());
 
-                let mut stop_receiver_clone = stop_receiver.clone();
+            let queued_receiver = receiver.clone();
+
+            let heartbeat = heartbeats.clone().keep(
+                format!("archive_target:{}", target_conf.name),
+                move || json!({ "queued_events": queued_receiver.len() }),
+            );
+
+            let join_handle = if dry_run {
+                tokio::spawn(log_archive_events(
+                    target_conf.name.clone(),
+                    receiver,
+                    stop_receiver.clone(),
+                    done_sender.clone(),
+                ))
+            } else {
+                let archive_events = handle_archive_events(
+                    ArchiveWriter::new(target_conf.clone()),
+                    receiver,
+                    source_sender,
+                    tokio_persistence.clone(),
+                    stop_receiver.clone(),
+                    done_sender.clone(),
+                );
 
                 tokio::spawn(async move {
                     tokio::select!(
-                        _a = fut => (),
-                        _b = stop_receiver_clone.changed()=> ()
+                        _a = archive_events => (),
+                        _b = heartbeat => ()
                     )
                 })
+            };
+
+            let target = Arc::new(Target {
+                name: target_conf.name.clone(),
+                sender,
+                in_progress: Arc::default(),
+            });
+
+            match targets.lock() {
+                Ok(mut guard) => {
+                    guard.insert(target_conf.name.clone(), target);
+                }
+                Err(e) => error!(
+                    "Could not get lock on targets hash for adding Target: {}",
+                    e
+                ),
             }
+
+            critical_task(
+                format!("archive target '{}'", target_conf.name),
+                join_handle,
+            )
+        })
+        .collect()
+}
+
+/// Receive the next file event for a directory target, at the rate limit of
+/// the target when it has one
+async fn receive_file_event(
+    receiver: &FileEventReceiver,
+    rate_limiter: &mut Option<TokenBucket>,
+    target_name: &str,
+) -> Option<FileEvent> {
+    let file_event = receiver.recv().await.ok()?;
+
+    if let Some(rate_limiter) = rate_limiter {
+        rate_limiter.acquire().await;
+    }
+
+    let backlog = if receiver.is_empty() {
+        0.0
+    } else {
+        file_event.age().as_secs_f64()
+    };
+
+    metrics::TARGET_BACKLOG_GAUGE
+        .with_label_values(&[target_name])
+        .set(backlog);
+
+    Some(file_event)
+}
+
+/// A task without which the dispatcher cannot do its work, with its name
+pub type CriticalTask =
+    Pin<Box<dyn Future<Output = (String, Result<(), tokio::task::JoinError>)> + Send>>;
+
+pub fn critical_task<T: Send + 'static>(
+    name: String,
+    join_handle: tokio::task::JoinHandle<T>,
+) -> CriticalTask {
+    Box::pin(async move { (name, join_handle.await.map(|_| ())) })
+}
+
+/// Wait for the stop signal, or for one of the critical tasks to end
+///
+/// Critical tasks only end on a stop, so one that ends before is a failure.
+async fn wait_for_stop<S: Future>(
+    stop_signal: S,
+    critical_tasks: Vec<CriticalTask>,
+) -> Result<(), String> {
+    let mut critical_tasks: FuturesUnordered<CriticalTask> = critical_tasks.into_iter().collect();
+
+    tokio::select!(
+        _ = stop_signal => Ok(()),
+        Some((name, result)) = critical_tasks.next() => Err(match result {
+            Ok(()) => format!("Critical task {name} ended unexpectedly"),
+            Err(e) if e.is_panic() => format!("Critical task {name} panicked"),
+            Err(e) => format!("Critical task {name} failed: {e}"),
+        })
+    )
+}
+
+type SftpJoinHandle = thread::JoinHandle<std::result::Result<(), DispatcherError>>;
+
+/// Download threads with the name of their SFTP source
+type SftpJoinHandles = Arc<Mutex<Vec<(String, SftpJoinHandle)>>>;
+
+/// Interval at which the download threads are checked for having stopped
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits the number of restarts within a time window
+struct RestartBudget {
+    max_restarts: u32,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartBudget {
+    fn new(max_restarts: u32, window: Duration) -> RestartBudget {
+        RestartBudget {
+            max_restarts,
+            window,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Register a restart at `now`, unless the budget is exhausted
+    fn try_restart(&mut self, now: Instant) -> bool {
+        while self
+            .restarts
+            .front()
+            .is_some_and(|restart| now.duration_since(*restart) >= self.window)
+        {
+            self.restarts.pop_front();
+        }
+
+        if self.restarts.len() >= self.max_restarts as usize {
+            return false;
+        }
+
+        self.restarts.push_back(now);
+
+        true
+    }
+}
+
+/// Restart the download threads of an SFTP source that stopped, until the
+/// stop flag is set or the restart budget is exhausted
+async fn supervise_downloaders<F>(
+    sftp_source: settings::SftpSource,
+    sftp_join_handles: SftpJoinHandles,
+    stop_flag: Arc<AtomicBool>,
+    start_downloader: F,
+) where
+    F: Fn() -> SftpJoinHandle,
+{
+    let mut budget = RestartBudget::new(
+        sftp_source.max_restarts,
+        Duration::from_secs(sftp_source.restart_window_minutes * 60),
+    );
+
+    let mut ticker = tokio::time::interval(SUPERVISE_INTERVAL);
+
+    metrics::SFTP_SOURCE_HEALTHY_GAUGE
+        .with_label_values(&[&sftp_source.name])
+        .set(1);
+
+    loop {
+        ticker.tick().await;
+
+        if stop_flag.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let finished: Vec<SftpJoinHandle> = {
+            let mut handles = sftp_join_handles.lock().unwrap();
+
+            let (finished, running) = std::mem::take(&mut *handles)
+                .into_iter()
+                .partition(|(name, handle)| name == &sftp_source.name && handle.is_finished());
+
+            *handles = running;
+
+            finished.into_iter().map(|(_, handle)| handle).collect()
         };
 
-        let target = Arc::new(Target {
-            name: c_target_conf.name.clone(),
-            sender,
-        });
+        for handle in finished {
+            let reason = match handle.join() {
+                Ok(Ok(())) => "stopped".to_string(),
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => "panicked".to_string(),
+            };
 
-        match targets.lock() {
-            Ok(mut guard) => {
-                guard.insert(target_conf.name.clone(), target);
+            // The threads stop by themselves on shutdown
+            if stop_flag.load(Ordering::Relaxed) {
+                return;
             }
-            Err(e) => error!(
-                "Could not get lock on targets hash for adding Target: {}",
-                e
-            ),
+
+            if !budget.try_restart(Instant::now()) {
+                error!(
+                    "SFTP download thread of source '{}' stopped ({}), not restarting after {} restarts in {} minutes",
+                    &sftp_source.name, reason, sftp_source.max_restarts, sftp_source.restart_window_minutes
+                );
+
+                metrics::SFTP_SOURCE_HEALTHY_GAUGE
+                    .with_label_values(&[&sftp_source.name])
+                    .set(0);
+
+                continue;
+            }
+
+            warn!(
+                "SFTP download thread of source '{}' stopped ({}), restarting",
+                &sftp_source.name, reason
+            );
+
+            metrics::DOWNLOADER_THREAD_RESTARTS_COUNTER
+                .with_label_values(&[&sftp_source.name])
+                .inc();
+
+            sftp_join_handles
+                .lock()
+                .unwrap()
+                .push((sftp_source.name.clone(), start_downloader()));
         }
-    });
+    }
 }
 
-type SftpJoinHandle = thread::JoinHandle<std::result::Result<(), DispatcherError>>;
-
 struct SftpSourceSend {
     pub sftp_source: settings::SftpSource,
     pub cmd_sender: Sender<(u64, SftpDownload)>,
     pub cmd_receiver: Receiver<(u64, SftpDownload)>,
-    pub file_event_sender: tokio::sync::mpsc::UnboundedSender<FileEvent>,
+    pub file_event_sender: FileEventSender,
     pub stop_receiver: tokio::sync::watch::Receiver<()>,
+    pub pause_receiver: tokio::sync::watch::Receiver<bool>,
+    pub source_activities: SourceActivities,
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn sftp_sources_handler<T>(
     settings: settings::Settings,
-    sftp_join_handles: Arc<Mutex<Vec<SftpJoinHandle>>>,
+    sftp_join_handles: SftpJoinHandles,
     sftp_source_senders: Vec<SftpSourceSend>,
     stop_flag: Arc<AtomicBool>,
     local_storage: LocalStorage<T>,
     persistence: T,
+    connections: Connections,
+    heartbeats: Heartbeats,
+    drain: Drain,
+    startup: Startup,
+    remote_removals: RemoteRemovals,
 ) -> Result<(), sftp_command_consumer::ConsumeError>
 where
     T: persistence::Persistence + Clone + Sync + Send + 'static,
@@ -164,13 +700,81 @@ where
         tokio::task::JoinHandle<Result<(), sftp_command_consumer::ConsumeError>>,
     > = Vec::new();
 
-    for mut channels in sftp_source_senders {
-        let (ack_sender, ack_receiver) = async_channel::bounded(100);
+    // Shared by the download threads of all SFTP sources
+    let session_limits = SessionLimits::new(settings.sftp_connection_limits.clone()).with_observer(
+        Arc::new(|sessions: &HostSessions| {
+            metrics::SFTP_SESSIONS_GAUGE
+                .with_label_values(&[&sessions.host])
+                .set(sessions.open as i64);
+            metrics::SFTP_SESSION_WAITERS_GAUGE
+                .with_label_values(&[&sessions.host])
+                .set(sessions.waiting as i64);
+        }),
+    );
+
+    for channels in sftp_source_senders {
+        // Download results, for acknowledging the commands to the broker
+        let (ack_sender, ack_receiver) = async_channel::bounded(settings.channels.ack_capacity);
 
-        // For now only log the ack messages
-        tokio::spawn(ack_receiver.for_each(|ack_message| async move {
-            debug!("Ack received from SftpDownloader: {:?}", &ack_message);
-        }));
+        let take_back = take_back_commands(
+            drain.clone(),
+            channels.cmd_receiver.clone(),
+            ack_sender.clone(),
+        );
+
+        let start_downloader = {
+            let stop_flag = stop_flag.clone();
+            let cmd_receiver = channels.cmd_receiver.clone();
+            let sftp_source = channels.sftp_source.clone();
+            let file_event_sender = channels.file_event_sender.clone();
+            let local_storage = local_storage.clone();
+            let persistence = persistence.clone();
+            let paused = channels.pause_receiver.clone();
+            let handles = sftp_downloader::SourceHandles {
+                path_locks: PathLocks::default(),
+                remote_removals: remote_removals.clone(),
+                // One writer thread per source, which ends when the download
+                // threads are no longer restarted
+                write_behind: channels
+                    .sftp_source
+                    .write_behind
+                    .as_ref()
+                    .map(|write_behind| {
+                        WriteBehind::start(
+                            persistence.clone(),
+                            &channels.sftp_source.name,
+                            write_behind,
+                        )
+                        .0
+                    }),
+                source_activities: channels.source_activities.clone(),
+                session_limits: session_limits.clone(),
+                connections: connections.clone(),
+                // Shared by the download threads of the source, so that any
+                // of them removes the remote files of delivered commands
+                delivered_removals: DeliveredRemovals::default(),
+            };
+            let max_retries = settings
+                .command_queue
+                .dead_letter
+                .as_ref()
+                .map_or(0, |dead_letter| dead_letter.max_retries);
+
+            move || {
+                sftp_downloader::SftpDownloader::start(
+                    stop_flag.clone(),
+                    cmd_receiver.clone(),
+                    ack_sender.clone(),
+                    sftp_source.clone(),
+                    file_event_sender.clone(),
+                    local_storage.clone(),
+                    persistence.clone(),
+                    max_retries,
+                    paused.clone(),
+                    handles.clone(),
+                )
+            }
+        };
 
         for n in 0..channels.sftp_source.thread_count {
             debug!(
@@ -178,19 +782,13 @@ where
                 &channels.sftp_source.name
             );
 
-            let join_handle = sftp_downloader::SftpDownloader::start(
-                stop_flag.clone(),
-                channels.cmd_receiver.clone(),
-                ack_sender.clone(),
-                channels.sftp_source.clone(),
-                channels.file_event_sender.clone(),
-                local_storage.clone(),
-                persistence.clone(),
-            );
+            let join_handle = start_downloader();
 
             let guard = sftp_join_handles.lock();
 
-            guard.unwrap().push(join_handle);
+            guard
+                .unwrap()
+                .push((channels.sftp_source.name.clone(), join_handle));
 
             info!(
                 "Started SFTP download thread for source '{}' ({}/{})",
@@ -203,22 +801,53 @@ where
         debug!("Spawning AMQP stream task '{}'", &channels.sftp_source.name);
 
         let consume_future = sftp_command_consumer::start(
-            settings.command_queue.address.clone(),
+            settings.command_queue.clone(),
             channels.sftp_source.name.clone(),
+            channels.sftp_source.command_route(),
+            channels.sftp_source.prefetch_count(),
             channels.cmd_sender.clone(),
+            ack_receiver,
+            channels.stop_receiver.clone(),
+            channels.pause_receiver.clone(),
+            channels.source_activities.clone(),
+            drain.clone(),
+            startup.clone(),
         );
 
-        let source_name = channels.sftp_source.name.clone();
+        stream_join_handles.push(tokio::spawn(consume_future));
+
+        let supervisor = supervise_downloaders(
+            channels.sftp_source.clone(),
+            sftp_join_handles.clone(),
+            stop_flag.clone(),
+            start_downloader,
+        );
+
+        let heartbeat = {
+            let sftp_join_handles = sftp_join_handles.clone();
+            let sftp_source_name = channels.sftp_source.name.clone();
+
+            heartbeats
+                .clone()
+                .keep(format!("sftp_source:{sftp_source_name}"), move || {
+                    let download_threads = sftp_join_handles
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .iter()
+                        .filter(|(name, handle)| name == &sftp_source_name && !handle.is_finished())
+                        .count();
+
+                    json!({ "download_threads": download_threads })
+                })
+        };
 
-        stream_join_handles.push(tokio::spawn(async move {
+        tokio::spawn(async move {
             tokio::select!(
-                a = consume_future => a,
-                _b = channels.stop_receiver.changed() => {
-                    debug!("Interrupted SFTP command consumer stream '{}'", &source_name);
-                    Ok(())
-                }
+                _a = supervisor => (),
+                _b = heartbeat => (),
+                _c = take_back => ()
             )
-        }));
+        });
     }
 
     // Await on futures so that the AMQP connection does not get destroyed.
@@ -227,42 +856,179 @@ where
     Ok::<(), sftp_command_consumer::ConsumeError>(())
 }
 
+/// Interval at which a drain checks for commands and file events that are
+/// still waiting
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// During a drain, give the commands that no download thread has taken yet
+/// back to the broker, e.g. when the download threads wait for the SFTP server
+async fn take_back_commands(
+    drain: Drain,
+    cmd_receiver: Receiver<(u64, SftpDownload)>,
+    ack_sender: async_channel::Sender<MessageResponse>,
+) {
+    drain.started().await;
+
+    loop {
+        while let Ok((delivery_tag, _)) = cmd_receiver.try_recv() {
+            let nack = MessageResponse::Nack {
+                delivery_tag,
+                delay: Duration::ZERO,
+            };
+
+            if ack_sender.send(nack).await.is_err() {
+                return;
+            }
+        }
+
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Wait until the file events of all sources and targets are handled, which
+/// must be the case at two consecutive checks, so that events passing from a
+/// source to a target are not missed
+async fn flush_file_events(
+    source_receivers: &[FileEventReceiver],
+    targets: &Mutex<HashMap<String, Arc<Target>>>,
+) {
+    let mut idle_checks = 0;
+
+    while idle_checks < 2 {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+
+        let idle = source_receivers.iter().all(|receiver| receiver.is_empty())
+            && targets.lock().unwrap().values().all(|target| {
+                target.sender.is_empty() && target.in_progress.load(Ordering::SeqCst) == 0
+            });
+
+        idle_checks = if idle { idle_checks + 1 } else { 0 };
+    }
+}
+
+/// Resolve the targets of the configured connections
+///
+/// Connections to a target that does not exist are returned separately, so
+/// that they can be made when a target with that name is added.
+pub fn resolve_connections(
+    conn_confs: &[settings::Connection],
+    targets: &Mutex<HashMap<String, Arc<Target>>>,
+) -> (Vec<Connection>, Vec<settings::Connection>) {
+    let targets = targets.lock().unwrap();
+
+    let mut connections = Vec::new();
+    let mut pending = Vec::new();
+
+    for conn_conf in conn_confs {
+        match targets.get(&conn_conf.target) {
+            Some(target) => connections.push(Connection::new(conn_conf, target.clone())),
+            None => {
+                warn!(
+                    "No target found matching name '{}', connection {} -> {} waits for it",
+                    conn_conf.target, conn_conf.source, conn_conf.target
+                );
+
+                pending.push(conn_conf.clone());
+            }
+        }
+    }
+
+    (connections, pending)
+}
+
 /// Start the streams that dispatch messages from sources to targets
 ///
-/// All connections from the same source are bundled into one stream that
+/// All connections from the same source are handled by one stream that
 /// dispatches to all targets of those connections, because there is only one
-/// receiver per source.
+/// receiver per source. The connections are looked up for every file event,
+/// so that connections added at runtime are used right away. Every file event
+/// is also offered to all event publishers.
 pub fn start_dispatch_streams(
     sources: Vec<Source>,
-    connections: Vec<Connection>,
+    connections: Connections,
+    event_publishers: Arc<Vec<EventPublisher>>,
+    audit: Option<AuditSender>,
 ) -> Vec<Option<tokio::task::JoinHandle<Result<(), ()>>>> {
     sources
         .into_iter()
         .map(
             |source| -> Option<tokio::task::JoinHandle<Result<(), ()>>> {
-                // Filter connections to this source
-                let source_connections: Vec<Connection> = connections
-                    .iter()
-                    .filter(|c| c.source_name == source.name)
-                    .cloned()
-                    .collect();
-
                 debug!(
                     "Spawing local event dispatcher task for source '{}'",
                     &source.name
                 );
 
-                Some(tokio::spawn(dispatch_stream(source, source_connections)))
+                Some(tokio::spawn(dispatch_stream(
+                    source,
+                    connections.clone(),
+                    event_publishers.clone(),
+                    audit.clone(),
+                )))
             },
         )
         .collect()
 }
 
-pub async fn run(settings: settings::Settings) -> Result<(), anyhow::Error> {
+/// Run the dispatcher until it is stopped by a signal or a critical task
+/// fails
+///
+/// In a dry run, downloads are discarded from a temporary storage directory,
+/// no files are placed in targets, the database is opened read-only and
+/// nothing is published to AMQP.
+pub async fn run(
+    mut settings: settings::Settings,
+    config_files: Vec<String>,
+    dry_run: bool,
+) -> Result<(), anyhow::Error> {
     rustls::crypto::ring::default_provider()
         .install_default()
         .map_err(|e| anyhow::anyhow!("Could not initialize default TLS provider: {e:?}"))?;
 
+    metrics::DRY_RUN_GAUGE.set(dry_run as i64);
+
+    // Errors are counted before repeated log messages are suppressed
+    log_throttle::set_window(settings.error_log_window);
+    log_throttle::set_observer(Box::new(|code, source| {
+        metrics::ERRORS_COUNTER
+            .with_label_values(&[code, source])
+            .inc()
+    }))
+    .map_err(anyhow::Error::msg)?;
+
+    let dry_run_directory =
+        std::env::temp_dir().join(format!("cortex-dry-run-{}", std::process::id()));
+
+    if dry_run {
+        for area in settings.storage.iter_mut() {
+            area.directory = dry_run_directory.join(&area.name);
+        }
+
+        // Failed commands are not published to the dead-letter exchange
+        settings.command_queue.dead_letter = None;
+
+        // The database is opened read-only
+        settings.heartbeat_interval = 0;
+
+        info!(
+            "Dry run downloads to '{}'",
+            dry_run_directory.to_string_lossy()
+        );
+    }
+
+    // Targets and connections changed through the HTTP API before a restart
+    let runtime_overrides = match &settings.runtime_overrides {
+        Some(path) => {
+            let runtime_overrides = RuntimeOverrides::load(path).map_err(anyhow::Error::msg)?;
+
+            runtime_overrides
+                .apply(&mut settings)
+                .map_err(anyhow::Error::msg)?;
+
+            runtime_overrides
+        }
+        None => RuntimeOverrides::default(),
+    };
+
     // List of targets with their file event channels
     let targets: Arc<Mutex<HashMap<String, Arc<Target>>>> = Arc::new(Mutex::new(HashMap::new()));
 
@@ -334,52 +1100,176 @@ pub async fn run(settings: settings::Settings) -> Result<(), anyhow::Error> {
         )));
 
     let db_path = &settings.sqlite.path;
-    // Ensure the parent directory for the SQLite database exists
-    if let Some(parent) = db_path.parent() {
-        if !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent)?;
+
+    let conn = if dry_run {
+        // Anything that would still write to the database fails
+        rusqlite::Connection::open_with_flags(db_path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| {
+            anyhow::anyhow!(
+                "Could not open database '{}' read-only for a dry run: {e}",
+                db_path.to_string_lossy()
+            )
+        })?
+    } else {
+        // Ensure the parent directory for the SQLite database exists
+        if let Some(parent) = db_path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let mut conn = rusqlite::Connection::open(db_path)?;
+        cortex_core::run_migrations(&mut conn).map_err(anyhow::Error::msg)?;
+
+        conn
+    };
+
+    if dry_run {
+        for area in &settings.storage {
+            fs::create_dir_all(&area.directory)?;
         }
     }
-    // Ensure the storage directory exists
-    fs::create_dir_all(&settings.storage.directory)?;
-    let mut conn = rusqlite::Connection::open(db_path)?;
-    cortex_core::run_migrations(&mut conn).map_err(anyhow::Error::msg)?;
+
+    // Unusable directories fail the startup, instead of the handling of the
+    // first file, where the directories are not created in a dry run
+    let unusable: Vec<String> = probe::check_directories(&settings, !dry_run)
+        .into_iter()
+        .filter_map(|check| match check.message {
+            Some(message) if check.passed => {
+                warn!("{}: {}", check.name, message);
+                None
+            }
+            Some(message) => Some(format!("{}: {}", check.name, message)),
+            None => None,
+        })
+        .collect();
+
+    if !unusable.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Unusable directories:\n  {}",
+            unusable.join("\n  ")
+        ));
+    }
 
     let conn_arc = Arc::new(Mutex::new(conn));
     let persistence = SqlitePersistence::from_arc(conn_arc.clone());
     let tokio_persistence = SqliteAsyncPersistence::new(conn_arc.clone());
+    let heartbeats = Heartbeats::new(tokio_persistence.clone(), settings.heartbeat_interval);
 
     let (stop_sender, stop_receiver) = watch::channel(());
 
-    tokio::spawn(target_directory_handler(
-        tokio_persistence,
-        settings.clone(),
+    let dispatcher_heartbeat = heartbeats.clone().keep(
+        "dispatcher".to_string(),
+        || json!({ "version": env!("CARGO_PKG_VERSION") }),
+    );
+
+    let mut heartbeat_stop_receiver = stop_receiver.clone();
+
+    tokio::spawn(async move {
+        tokio::select!(
+            _a = dispatcher_heartbeat => (),
+            _b = heartbeat_stop_receiver.changed() => ()
+        )
+    });
+
+    let source_pauses = SourcePauses::new(&settings);
+    let source_activities = SourceActivities::default();
+
+    let notifiers = match (&settings.notification_spool, dry_run) {
+        (Some(notification_spool), false) => NamedNotifiers::new(&settings.notifiers)
+            .with_spool(spool_directory(&settings), notification_spool.clone()),
+        _ => NamedNotifiers::new(&settings.notifiers),
+    };
+
+    let mut critical_tasks = target_directory_handler(
+        tokio_persistence.clone(),
+        settings.clone(),
         stop_receiver.clone(),
         targets.clone(),
-    ));
+        heartbeats.clone(),
+        &notifiers,
+        dry_run,
+    );
+
+    let readiness = Readiness::default();
+
+    let drain = Drain::new(
+        readiness.clone(),
+        source_pauses.clone(),
+        settings
+            .sftp_sources
+            .iter()
+            .map(|sftp_source| sftp_source.name.clone()),
+    );
+
+    let startup = Startup::new(
+        settings
+            .sftp_sources
+            .iter()
+            .map(|sftp_source| sftp_source.name.clone()),
+    );
+
+    let remote_removals = RemoteRemovals::new(&settings.sftp_sources);
 
-    let local_storage = LocalStorage::new(&settings.storage.directory, persistence.clone());
+    let status_pauses = source_pauses.clone();
+    let watchdog_persistence = tokio_persistence.clone();
+
+    let storage_areas = storage_areas(&settings, &readiness);
+
+    let local_storage = LocalStorage::new(storage_areas.clone(), persistence.clone())
+        .with_placements(source_placements(&settings));
+
+    // In a dry run, stored files are only registered in memory
+    let dry_run_storage = dry_run.then(|| {
+        let dry_run_persistence = DryRunPersistence::new(persistence.clone());
+
+        let local_storage = LocalStorage::new(storage_areas.clone(), dry_run_persistence.clone())
+            .with_placements(source_placements(&settings));
+
+        (local_storage.into_dry_run(), dry_run_persistence)
+    });
 
     let (local_intake_sender, local_intake_receiver) = std::sync::mpsc::channel();
 
-    let mut senders: HashMap<String, UnboundedSender<FileEvent>> = HashMap::new();
+    let mut senders: HashMap<String, FileEventSender> = HashMap::new();
+
+    // File event senders of all sources, used for re-dispatching files on request
+    let mut control_senders: HashMap<String, FileEventSender> = HashMap::new();
 
     settings
         .directory_sources
         .iter()
         .for_each(|directory_source| {
-            let (sender, receiver) = unbounded_channel();
+            let (sender, receiver) = file_event_channel(
+                &format!("source:{}", directory_source.name),
+                &settings.channels,
+            );
 
             sources.push(Source {
                 name: directory_source.name.clone(),
                 receiver,
             });
 
+            control_senders.insert(directory_source.name.clone(), sender.clone());
             senders.insert(directory_source.name.clone(), sender);
         });
 
     let event_dispatcher = EventDispatcher { senders };
 
+    let (archives_done_sender, mut archives_done_receiver) = tokio::sync::mpsc::channel::<()>(1);
+
+    critical_tasks.extend(archive_target_handler(
+        tokio_persistence.clone(),
+        &settings,
+        stop_receiver.clone(),
+        targets.clone(),
+        &mut sources,
+        &mut control_senders,
+        archives_done_sender,
+        heartbeats.clone(),
+        dry_run,
+    ));
+
     // Create a lookup table for directory sources that can be used by the intake
     // thread
     let directory_source_map: HashMap<String, settings::DirectorySource> = (settings
@@ -390,18 +1280,48 @@ pub async fn run(settings: settings::Settings) -> Result<(), anyhow::Error> {
 
     let stop_flag = Arc::new(AtomicBool::new(false));
 
-    let local_intake_handle = start_local_intake_thread(
-        local_intake_receiver,
-        event_dispatcher,
-        local_storage.clone(),
-        directory_source_map,
-        stop_flag.clone(),
-    );
+    // Events of a dry run are not journaled, because its files are not
+    // ingested
+    let intake_journal = match settings.directory_sources.is_empty() || dry_run {
+        true => None,
+        false => Some(
+            IntakeJournal::open(&journal_path(&settings.sqlite.path))
+                .map_err(anyhow::Error::msg)?,
+        ),
+    };
+
+    let local_intake_handle = match &dry_run_storage {
+        Some((dry_run_storage, _)) => start_local_intake_thread(
+            local_intake_receiver,
+            event_dispatcher,
+            dry_run_storage.clone(),
+            directory_source_map,
+            None,
+            source_activities.clone(),
+        ),
+        None => start_local_intake_thread(
+            local_intake_receiver,
+            event_dispatcher,
+            local_storage.clone(),
+            directory_source_map,
+            intake_journal.clone(),
+            source_activities.clone(),
+        ),
+    };
+
+    if let Some(intake_journal) = &intake_journal {
+        intake_journal
+            .replay(&local_intake_sender)
+            .map_err(anyhow::Error::msg)?;
+    }
 
     #[cfg(target_os = "linux")]
     let directory_sources_join_handle = start_directory_sources(
         settings.directory_sources.clone(),
         local_intake_sender.clone(),
+        intake_journal,
+        source_pauses.clone(),
+        source_activities.clone(),
         stop_flag.clone(),
     );
 
@@ -412,17 +1332,71 @@ pub async fn run(settings: settings::Settings) -> Result<(), anyhow::Error> {
         settings.directory_sources.clone(),
         local_intake_sender,
         settings.scan_interval,
+        source_pauses.clone(),
+        source_activities.clone(),
         stop_flag.clone(),
     );
 
-    let sftp_join_handles: Arc<Mutex<Vec<SftpJoinHandle>>> = Arc::new(Mutex::new(Vec::new()));
+    // Files are not removed in a dry run
+    let retention_join_handles: Vec<_> = settings
+        .storage
+        .iter()
+        .zip(&storage_areas)
+        .filter(|_| !dry_run)
+        .filter_map(|(storage, area)| {
+            storage.retention.clone().map(|retention| {
+                RetentionCleanup::new(retention, area, &settings.connections, persistence.clone())
+                    .start(stop_flag.clone())
+            })
+        })
+        .collect();
+
+    // Reports on a dry run would show the real stored files
+    let reports = if dry_run {
+        Vec::new()
+    } else {
+        settings.reports.clone()
+    };
+
+    let report_join_handles: Vec<_> = reports
+        .into_iter()
+        .filter_map(
+            |report| match ReportSender::new(report, persistence.clone()) {
+                Ok(sender) => Some(sender.start(stop_flag.clone())),
+                Err(e) => {
+                    error!("{e}");
+                    None
+                }
+            },
+        )
+        .collect();
+
+    let storage_usage_join_handles: Vec<_> = settings
+        .storage
+        .iter()
+        .zip(storage_areas)
+        .map(|(storage, area)| {
+            start_storage_usage_walker(
+                area.directory,
+                area.usage,
+                Duration::from_millis(storage.usage_interval),
+                stop_flag.clone(),
+            )
+        })
+        .collect();
+
+    let sftp_join_handles: SftpJoinHandles = Arc::new(Mutex::new(Vec::new()));
 
     let (sftp_source_senders, mut sftp_sources): (Vec<SftpSourceSend>, Vec<Source>) = settings
         .sftp_sources
         .iter()
         .map(|sftp_source| {
-            let (cmd_sender, cmd_receiver) = bounded::<(u64, SftpDownload)>(10);
-            let (file_event_sender, file_event_receiver) = unbounded_channel();
+            let (cmd_sender, cmd_receiver) =
+                bounded::<(u64, SftpDownload)>(settings.channels.sftp_command_capacity);
+            let (file_event_sender, file_event_receiver) =
+                file_event_channel(&format!("source:{}", sftp_source.name), &settings.channels);
+
+            control_senders.insert(sftp_source.name.clone(), file_event_sender.clone());
 
             let sftp_source_send = SftpSourceSend {
                 sftp_source: sftp_source.clone(),
@@ -430,6 +1404,8 @@ pub async fn run(settings: settings::Settings) -> Result<(), anyhow::Error> {
                 cmd_receiver,
                 file_event_sender,
                 stop_receiver: stop_receiver.clone(),
+                pause_receiver: source_pauses.subscribe(&sftp_source.name),
+                source_activities: source_activities.clone(),
             };
 
             let source = Source {
@@ -443,101 +1419,351 @@ pub async fn run(settings: settings::Settings) -> Result<(), anyhow::Error> {
 
     sources.append(&mut sftp_sources);
 
-    let _sftp_sources_join_handle = tokio::spawn(sftp_sources_handler(
-        settings.clone(),
-        sftp_join_handles.clone(),
-        sftp_source_senders,
-        stop_flag.clone(),
-        local_storage,
-        persistence,
+    // All directory and archive targets are registered by now, because they
+    // are inserted before their handlers return
+    let (connections, pending_connections) = resolve_connections(&settings.connections, &targets);
+
+    let connections: Connections = Arc::new(RwLock::new(connections));
+
+    let reconcile_persistence = persistence.clone();
+
+    let sftp_sources_join_handle = match dry_run_storage {
+        Some((dry_run_storage, dry_run_persistence)) => tokio::spawn(sftp_sources_handler(
+            settings.clone(),
+            sftp_join_handles.clone(),
+            sftp_source_senders,
+            stop_flag.clone(),
+            dry_run_storage,
+            dry_run_persistence,
+            connections.clone(),
+            heartbeats.clone(),
+            drain.clone(),
+            startup.clone(),
+            remote_removals.clone(),
+        )),
+        None => tokio::spawn(sftp_sources_handler(
+            settings.clone(),
+            sftp_join_handles.clone(),
+            sftp_source_senders,
+            stop_flag.clone(),
+            local_storage,
+            persistence,
+            connections.clone(),
+            heartbeats.clone(),
+            drain.clone(),
+            startup.clone(),
+            remote_removals.clone(),
+        )),
+    };
+
+    // Without SFTP sources, the handler ends right away
+    if !settings.sftp_sources.is_empty() {
+        critical_tasks.push(critical_task(
+            "SFTP sources handler".to_string(),
+            sftp_sources_join_handle,
+        ));
+    }
+
+    let (audit_sender, audit_join_handle) = if settings.audit && !dry_run {
+        let (audit_sender, join_handle) =
+            start_audit_writer(tokio_persistence.clone(), stop_receiver.clone());
+
+        (Some(audit_sender), Some(join_handle))
+    } else {
+        (None, None)
+    };
+
+    let runtime_targets = RuntimeTargets::new(
+        &settings,
+        runtime_overrides,
+        targets.clone(),
+        connections.clone(),
+        pending_connections,
+        tokio_persistence.clone(),
+        stop_receiver.clone(),
+        notifiers,
+        dry_run,
+    );
+
+    let http_server_address = settings.http_server.address;
+    let http_server_persistence = tokio_persistence.clone();
+    let http_server_drain = drain.clone();
+    let http_server_startup = startup.clone();
+
+    critical_tasks.push(critical_task(
+        "HTTP server".to_string(),
+        tokio::spawn(async move {
+            if let Err(e) = start_http_server(
+                http_server_address,
+                source_pauses,
+                source_activities,
+                readiness,
+                http_server_persistence,
+                runtime_targets,
+                http_server_drain,
+                http_server_startup,
+            )
+            .await
+            {
+                error!("Could not run HTTP server on {http_server_address}: {e}");
+            }
+        }),
     ));
 
-    let connections = settings
-        .connections
+    if let Some(reconcile_conf) = settings.reconcile.clone() {
+        tokio::spawn(reconcile_on_startup(
+            reconcile_conf,
+            settings.clone(),
+            reconcile_persistence,
+            control_senders.clone(),
+            stop_receiver.clone(),
+        ));
+    }
+
+    tokio::spawn(control::start_control_consumer(
+        settings.command_queue.address.expose().to_string(),
+        settings.command_queue.amqp_tls.clone(),
+        settings.command_queue.control_queue.clone(),
+        control_senders,
+        tokio_persistence,
+        stop_receiver.clone(),
+    ));
+
+    // Start the publishers of the file events of all sources, which are not
+    // critical so that a broken publisher does not stop the dispatching
+    let event_publishers: Vec<EventPublisher> = settings
+        .event_publishers
         .iter()
-        .filter_map(|conn_conf| -> Option<Connection> {
-            let target = match targets.lock() {
-                Ok(guard) => match guard.get(&conn_conf.target) {
-                    Some(target) => target.clone(),
-                    None => {
-                        error!("No target found matching name '{}'", &conn_conf.target);
-                        return None;
-                    }
-                },
-                Err(e) => {
-                    error!("Could not lock the targets Arc for getting a target: {}", e);
-                    return None;
-                }
-            };
+        .map(|publisher_conf| {
+            let (event_publisher, _join_handle) = start_event_publisher(
+                publisher_conf.clone(),
+                &settings.channels,
+                stop_receiver.clone(),
+                dry_run,
+            );
 
-            Some(Connection {
-                source_name: conn_conf.source.clone(),
-                target,
-                filter: conn_conf.filter.clone(),
-            })
+            event_publisher
         })
         .collect();
 
+    let source_receivers: Vec<FileEventReceiver> = sources
+        .iter()
+        .map(|source| source.receiver.clone())
+        .collect();
+
     // Start the streams that dispatch messages from sources to targets
-    let _stream_join_handles = start_dispatch_streams(sources, connections);
+    critical_tasks.extend(
+        start_dispatch_streams(
+            sources,
+            connections,
+            Arc::new(event_publishers),
+            audit_sender,
+        )
+        .into_iter()
+        .flatten()
+        .map(|join_handle| critical_task("dispatch stream".to_string(), join_handle)),
+    );
 
     let signals = Signals::new([
         signal_hook::consts::signal::SIGHUP,
         signal_hook::consts::signal::SIGTERM,
         signal_hook::consts::signal::SIGINT,
         signal_hook::consts::signal::SIGQUIT,
+        signal_hook::consts::signal::SIGUSR1,
     ])?;
 
+    let drain_targets = targets.clone();
+    let reloaded_removals = remote_removals.clone();
+
     let signal_handler_join_handle = tokio::spawn(async move {
         let mut signals = signals.fuse();
 
-        while let Some(signal) = signals.next().await {
-            match signal {
-                signal_hook::consts::signal::SIGHUP => {
-                    // Reload configuration
-                    // Reopen the log file
-                }
-                signal_hook::consts::signal::SIGTERM
-                | signal_hook::consts::signal::SIGINT
-                | signal_hook::consts::signal::SIGQUIT => {
-                    info!("Stopping dispatcher");
-                    stop_flag.swap(true, Ordering::Relaxed);
-                    if let Err(e) = stop_sender.send(()) {
-                        error!("Could not send stop signal: {e}");
+        // A drain stops once the consumers have given back their queued
+        // commands and the file events of the completed downloads are
+        // dispatched
+        let drained = async {
+            drain.consumers_drained().await;
+            flush_file_events(&source_receivers, &drain_targets).await;
+        };
+
+        tokio::pin!(drained);
+
+        loop {
+            tokio::select!(
+                signal = signals.next() => match signal {
+                    Some(signal_hook::consts::signal::SIGHUP) => {
+                        logging::reopen();
+
+                        // Only the settings that can change while running
+                        // are applied
+                        match settings::load_settings_files(&config_files) {
+                            Ok(reloaded) => {
+                                info!("Configuration reloaded from {}", config_files.join(", "));
+
+                                reloaded_removals.update(&reloaded.sftp_sources);
+                            }
+                            Err(e) => error!("Error reloading configuration: {e}"),
+                        }
+                    }
+                    Some(signal_hook::consts::signal::SIGUSR1) => {
+                        drain.start("SIGUSR1");
+                    }
+                    Some(
+                        signal_hook::consts::signal::SIGTERM
+                        | signal_hook::consts::signal::SIGINT
+                        | signal_hook::consts::signal::SIGQUIT,
+                    ) => {
+                        if drain.is_started() {
+                            warn!("Stopping dispatcher before the drain completed, commands in progress are redelivered by the broker");
+                        } else {
+                            info!("Stopping dispatcher without draining, commands in progress are redelivered by the broker");
+                        }
+                        break;
                     }
+                    Some(_) => unreachable!(),
+                    None => break,
+                },
+                _ = &mut drained => {
+                    info!("Drain completed, stopping dispatcher");
                     break;
                 }
-                _ => unreachable!(),
-            }
+            )
         }
     });
 
-    // Wait until all tasks have finished
-    let _result = signal_handler_join_handle.await;
+    let status_targets = targets.clone();
 
-    info!("Tokio runtime shutdown");
+    // Summary for systemd of the sources that are not paused and of the file
+    // events that the targets still have to handle
+    let status = move || {
+        let statuses = status_pauses.statuses();
+        let active = statuses.iter().filter(|status| !status.paused).count();
+
+        let backlog: usize = status_targets
+            .lock()
+            .unwrap()
+            .values()
+            .map(|target| target.sender.len() + target.in_progress.load(Ordering::SeqCst))
+            .sum();
+
+        format!(
+            "{active}/{} sources active, {backlog} file events in backlog",
+            statuses.len()
+        )
+    };
+
+    // The systemd supervision never ends, so the result comes from the stop
+    // signal or from a critical task
+    let result = tokio::select!(
+        result = wait_for_stop(signal_handler_join_handle, critical_tasks) => result,
+        _ = systemd::supervise(watchdog_persistence, status) => Ok(()),
+    );
+
+    systemd::notify_stopping();
+
+    if let Err(e) = &result {
+        error!("{e}, stopping dispatcher");
+    }
+
+    stop_flag.swap(true, Ordering::Relaxed);
 
+    // Stop the producers of the intake channel first, so that the intake
+    // thread ingests the files that are still queued and then ends. The
+    // sources are stopped after that to dispatch the ingested files.
     #[cfg(target_os = "linux")]
     wait_for(directory_sources_join_handle, "directory sources");
 
+    wait_for(directory_sweep_join_handle, "directory sweep");
+
     wait_for(local_intake_handle, "local intake");
 
-    wait_for(directory_sweep_join_handle, "directory sweep");
+    if let Err(e) = stop_sender.send(()) {
+        error!("Could not send stop signal: {e}");
+    }
 
-    Arc::try_unwrap(sftp_join_handles)
-        .expect("still users of handles")
-        .into_inner()
-        .unwrap()
-        .into_iter()
-        .for_each(|jh| {
-            wait_for(jh, "sftp download");
-        });
+    // Wait for the archive targets to complete their open archives
+    archives_done_receiver.recv().await;
 
-    Ok(())
+    if let Some(join_handle) = audit_join_handle {
+        if let Err(e) = join_handle.await {
+            error!("Error waiting for audit writer: {e}");
+        }
+    }
+
+    info!("Tokio runtime shutdown");
+
+    for join_handle in storage_usage_join_handles {
+        wait_for(join_handle, "storage usage");
+    }
+
+    for join_handle in retention_join_handles {
+        wait_for(join_handle, "retention cleanup");
+    }
+
+    for join_handle in report_join_handles {
+        wait_for(join_handle, "report");
+    }
+
+    // The supervisors may still hold a reference, but do not restart threads
+    // once the stop flag is set
+    let sftp_join_handles = std::mem::take(&mut *sftp_join_handles.lock().unwrap());
+
+    sftp_join_handles.into_iter().for_each(|(_, jh)| {
+        wait_for(jh, "sftp download");
+    });
+
+    if dry_run {
+        if let Err(e) = fs::remove_dir_all(&dry_run_directory) {
+            error!("Could not remove dry run storage directory: {e}");
+        }
+    }
+
+    result.map_err(anyhow::Error::msg)
 }
 
-async fn dispatch_stream(mut source: Source, connections: Vec<Connection>) -> Result<(), ()> {
-    while let Some(file_event) = source.receiver.recv().await {
+async fn dispatch_stream(
+    source: Source,
+    connections: Connections,
+    event_publishers: Arc<Vec<EventPublisher>>,
+    audit: Option<AuditSender>,
+) -> Result<(), ()> {
+    while let Ok(mut file_event) = source.receiver.recv().await {
+        // Only the targets of connections that require delivery get a
+        // confirmation, for which the acknowledgment waits
+        let ack = file_event.ack.take();
+        let mut confirmations = Vec::new();
+        let mut delivery_timeout = Duration::ZERO;
+
+        // A file that is delivered again only goes to the targets that missed
+        // it, and was published already
+        let redeliver_to: Option<Vec<String>> = ack
+            .as_ref()
+            .and_then(|ack| ack.targets())
+            .map(|targets| targets.to_vec());
+
+        if redeliver_to.is_none() {
+            for event_publisher in event_publishers.iter() {
+                event_publisher.dispatch(&file_event).await;
+            }
+        }
+
+        // Filter connections to this source
+        let mut connections: Vec<Connection> = connections
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|c| c.source_name == source.name)
+            .filter(|c| {
+                redeliver_to
+                    .as_ref()
+                    .is_none_or(|targets| targets.contains(&c.target.name))
+            })
+            .cloned()
+            .collect();
+
+        // Highest priority first, in the configured order for equal priorities
+        connections.sort_by_key(|c| std::cmp::Reverse(c.priority));
+
         debug!(
             "FileEvent for {} connections, from {}: {}",
             connections.len(),
@@ -545,30 +1771,658 @@ async fn dispatch_stream(mut source: Source, connections: Vec<Connection>) -> Re
             file_event.path.to_string_lossy()
         );
 
-        connections
-            .deref()
-            .iter()
-            .filter(|c| match &c.filter {
-                Some(f) => f.file_matches(&file_event.path),
+        metrics::DISPATCH_LATENCY_HISTOGRAM
+            .with_label_values(&[&source.name])
+            .observe(file_event.age().as_secs_f64());
+
+        for c in connections.iter() {
+            let filter_matched = match &c.filter {
+                Some(f) => f.matches(&file_event),
                 None => true,
-            })
-            .for_each(|c| {
-                info!("Sending FileEvent to target {}", &c.target.name);
+            };
+
+            if let Some(audit) = &audit {
+                audit.record(DispatchDecision {
+                    file_id: file_event.file_id,
+                    source: source.name.clone(),
+                    target: c.target.name.clone(),
+                    filter_matched,
+                    timestamp: Utc::now(),
+                });
+            }
 
-                let send_result = c.target.sender.send(file_event.clone());
+            if !filter_matched {
+                continue;
+            }
 
-                match send_result {
-                    Ok(_) => (),
-                    Err(e) => {
-                        // Could not send file event to target
-                        // TODO: Implement retry mechanism
-                        error!("Could not send event to target handler: {}", e);
-                    }
+            info!("Sending FileEvent to target {}", &c.target.name);
+
+            // Connections with a priority wait for room, so that the overflow
+            // policy only applies to the others
+            let waits = c.priority > 0 || c.target.sender.overflow() == settings::Overflow::Block;
+
+            if c.target.sender.is_full() {
+                metrics::DISPATCH_OVERFLOW_COUNTER
+                    .with_label_values(&[
+                        source.name.as_str(),
+                        c.target.name.as_str(),
+                        &c.priority.to_string(),
+                        if waits { "deferred" } else { "dropped" },
+                    ])
+                    .inc();
+            }
+
+            let mut target_event = file_event.clone();
+
+            if let (Some(_), true) = (&ack, c.require_delivery) {
+                let (confirmation, confirmed) = Confirmation::new();
+
+                target_event.confirmation = Some(confirmation);
+                confirmations.push(confirmed);
+                delivery_timeout = delivery_timeout.max(c.delivery_timeout);
+            }
+
+            let send_result = if waits {
+                c.target.sender.send_waiting(target_event).await
+            } else {
+                c.target.sender.send(target_event).await
+            };
+
+            match send_result {
+                Ok(_) => (),
+                Err(e) => {
+                    // Could not send file event to target
+                    // TODO: Implement retry mechanism
+                    throttled_error!(
+                        ErrorCode::DispatchSend,
+                        &c.target.name,
+                        "Could not send event to target handler: {}",
+                        e
+                    );
                 }
-            });
+            }
+        }
+
+        if let Some(ack) = ack {
+            if confirmations.is_empty() {
+                ack.ack().await;
+            } else {
+                tokio::spawn(ack.wait_for(confirmations, delivery_timeout));
+            }
+        }
     }
 
     debug!("End of dispatch stream '{}'", &source.name);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::delivery::PendingAck;
+
+    #[tokio::test]
+    async fn targets_are_registered_when_their_handlers_return() {
+        let mut settings = settings::Settings::default();
+        settings.directory_targets.truncate(1);
+        settings.connections = vec![settings::Connection {
+            source: "mixed-directory".to_string(),
+            target: settings.directory_targets[0].name.clone(),
+            filter: None,
+            priority: 0,
+            require_delivery: false,
+            delivery_timeout_seconds: 300,
+        }];
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let persistence = SqliteAsyncPersistence::new(Arc::new(Mutex::new(conn)));
+
+        let targets: Arc<Mutex<HashMap<String, Arc<Target>>>> = Arc::default();
+        let (_stop_sender, stop_receiver) = watch::channel(());
+
+        // The same order as on startup, without yielding to the spawned
+        // handlers in between
+        let _tasks = target_directory_handler(
+            persistence.clone(),
+            settings.clone(),
+            stop_receiver,
+            targets.clone(),
+            Heartbeats::new(persistence, 0),
+            &NamedNotifiers::default(),
+            true,
+        );
+
+        let (connections, pending) = resolve_connections(&settings.connections, &targets);
+
+        assert_eq!(connections.len(), 1);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn restart_budget_is_limited_within_window() {
+        let mut budget = RestartBudget::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(budget.try_restart(start));
+        assert!(budget.try_restart(start + Duration::from_secs(10)));
+        assert!(!budget.try_restart(start + Duration::from_secs(20)));
+
+        // The first restart has left the window
+        assert!(budget.try_restart(start + Duration::from_secs(60)));
+        assert!(!budget.try_restart(start + Duration::from_secs(65)));
+    }
+
+    #[tokio::test]
+    async fn panicking_critical_task_stops_dispatcher() {
+        let target_handler = tokio::spawn(async {
+            panic!("injected failure");
+        });
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            wait_for_stop(
+                futures::future::pending::<()>(),
+                vec![critical_task(
+                    "directory target 'red'".to_string(),
+                    target_handler,
+                )],
+            ),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            result.unwrap_err(),
+            "Critical task directory target 'red' panicked"
+        );
+    }
+
+    #[tokio::test]
+    async fn high_priority_connections_wait_for_room() {
+        let channels = settings::Channels {
+            file_event_capacity: Some(2),
+            overflow: settings::Overflow::Error,
+            ..settings::Settings::default().channels
+        };
+
+        let (source_sender, source_receiver) = file_event_channel("source:red", &channels);
+
+        let target = |name: &str| {
+            let (sender, receiver) = file_event_channel(&format!("target:{name}"), &channels);
+
+            let target = Arc::new(Target {
+                name: name.to_string(),
+                sender,
+                in_progress: Arc::default(),
+            });
+
+            (target, receiver)
+        };
+
+        let (archive, archive_receiver) = target("archive");
+        let (realtime, realtime_receiver) = target("realtime");
+
+        let connection = |target: &Arc<Target>, priority: u8| Connection {
+            source_name: "red".to_string(),
+            target: target.clone(),
+            filter: None,
+            priority,
+            require_delivery: false,
+            delivery_timeout: Duration::from_secs(300),
+        };
+
+        let connections = Arc::new(RwLock::new(vec![
+            connection(&archive, 0),
+            connection(&realtime, 1),
+        ]));
+
+        tokio::spawn(dispatch_stream(
+            Source {
+                name: "red".to_string(),
+                receiver: source_receiver,
+            },
+            connections,
+            Arc::default(),
+            None,
+        ));
+
+        for file_id in 1..=3 {
+            source_sender
+                .send_waiting(FileEvent {
+                    file_id,
+                    source_name: "red".to_string(),
+                    path: format!("/data/{file_id}.xml").into(),
+                    relative_path: None,
+                    hash: String::new(),
+                    content_hash: true,
+                    size: 0,
+                    modified: Utc::now(),
+                    created: Utc::now(),
+                    discovered: None,
+                    ack: None,
+                    confirmation: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        // The third event waits until there is room for the realtime target
+        let mut realtime_ids = Vec::new();
+
+        for _ in 0..3 {
+            let file_event = tokio::time::timeout(Duration::from_secs(1), realtime_receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+
+            realtime_ids.push(file_event.file_id);
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let archive_ids: Vec<i64> = std::iter::from_fn(|| archive_receiver.try_recv().ok())
+            .map(|file_event| file_event.file_id)
+            .collect();
+
+        assert_eq!(realtime_ids, vec![1, 2, 3]);
+        assert_eq!(archive_ids, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn acknowledgment_waits_for_targets_that_require_delivery() {
+        let channels = settings::Settings::default().channels;
+
+        let (source_sender, source_receiver) = file_event_channel("source:red", &channels);
+
+        let target = |name: &str| {
+            let (sender, receiver) = file_event_channel(&format!("target:{name}"), &channels);
+
+            let target = Arc::new(Target {
+                name: name.to_string(),
+                sender,
+                in_progress: Arc::default(),
+            });
+
+            (target, receiver)
+        };
+
+        let (archive, archive_receiver) = target("archive");
+        let (critical, critical_receiver) = target("critical");
+
+        let connection = |target: &Arc<Target>, require_delivery: bool| Connection {
+            source_name: "red".to_string(),
+            target: target.clone(),
+            filter: None,
+            priority: 0,
+            require_delivery,
+            delivery_timeout: Duration::from_secs(10),
+        };
+
+        let connections = Arc::new(RwLock::new(vec![
+            connection(&archive, false),
+            connection(&critical, true),
+        ]));
+
+        tokio::spawn(dispatch_stream(
+            Source {
+                name: "red".to_string(),
+                receiver: source_receiver,
+            },
+            connections,
+            Arc::default(),
+            None,
+        ));
+
+        let (ack_sender, ack_receiver) = async_channel::unbounded();
+
+        let mut file_event = test_event(1, PathBuf::from("/data/1.xml"));
+        file_event.ack = Some(PendingAck::new(7, "/upload/1.xml", ack_sender, None, None));
+
+        source_sender.send_waiting(file_event).await.unwrap();
+
+        let archive_event = archive_receiver.recv().await.unwrap();
+        let critical_event = critical_receiver.recv().await.unwrap();
+
+        assert!(archive_event.confirmation.is_none());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(ack_receiver.is_empty());
+
+        critical_event.confirmation.unwrap().confirm();
+
+        let response = tokio::time::timeout(Duration::from_secs(1), ack_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(response, MessageResponse::Ack { delivery_tag: 7 });
+    }
+
+    #[tokio::test]
+    async fn redelivered_files_only_reach_the_targets_that_missed_them() {
+        let channels = settings::Settings::default().channels;
+
+        let (source_sender, source_receiver) = file_event_channel("source:red", &channels);
+
+        let target = |name: &str| {
+            let (sender, receiver) = file_event_channel(&format!("target:{name}"), &channels);
+
+            let target = Arc::new(Target {
+                name: name.to_string(),
+                sender,
+                in_progress: Arc::default(),
+            });
+
+            (target, receiver)
+        };
+
+        let (archive, archive_receiver) = target("archive");
+        let (critical, critical_receiver) = target("critical");
+
+        let connection = |target: &Arc<Target>| Connection {
+            source_name: "red".to_string(),
+            target: target.clone(),
+            filter: None,
+            priority: 0,
+            require_delivery: true,
+            delivery_timeout: Duration::from_secs(10),
+        };
+
+        let connections = Arc::new(RwLock::new(vec![
+            connection(&archive),
+            connection(&critical),
+        ]));
+
+        tokio::spawn(dispatch_stream(
+            Source {
+                name: "red".to_string(),
+                receiver: source_receiver,
+            },
+            connections,
+            Arc::default(),
+            None,
+        ));
+
+        let (ack_sender, ack_receiver) = async_channel::unbounded();
+        let removals = DeliveredRemovals::default();
+
+        let mut file_event = test_event(1, PathBuf::from("/data/1.xml"));
+        file_event.ack = Some(PendingAck::new(
+            7,
+            "/upload/1.xml",
+            ack_sender,
+            Some(removals.clone()),
+            Some(vec!["critical".to_string()]),
+        ));
+
+        source_sender.send_waiting(file_event).await.unwrap();
+
+        critical_receiver
+            .recv()
+            .await
+            .unwrap()
+            .confirmation
+            .unwrap()
+            .confirm();
+
+        let response = tokio::time::timeout(Duration::from_secs(1), ack_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(response, MessageResponse::Ack { delivery_tag: 7 });
+        assert!(archive_receiver.is_empty());
+        assert_eq!(removals.take(), vec!["/upload/1.xml".to_string()]);
+    }
+
+    /// Notifier that records the notified files, and whether they were placed
+    /// at the time of the notification
+    struct RecordingNotifier {
+        notified: Arc<Mutex<Vec<(PathBuf, bool, String)>>>,
+        /// Number of notifications that fail, after removing their file
+        failures: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl Notifier for RecordingNotifier {
+        async fn notify(&mut self, event: &FileEvent, target: &str) -> Result<(), String> {
+            self.notified.lock().unwrap().push((
+                event.path.clone(),
+                event.path.exists(),
+                target.to_string(),
+            ));
+
+            if self.failures > 0 {
+                self.failures -= 1;
+
+                fs::remove_file(&event.path).unwrap();
+
+                return Err("Connection refused".to_string());
+            }
+
+            Ok(())
+        }
+    }
+
+    fn test_event(file_id: i64, path: PathBuf) -> FileEvent {
+        FileEvent {
+            file_id,
+            source_name: "red".to_string(),
+            path,
+            relative_path: None,
+            hash: String::new(),
+            content_hash: false,
+            size: 4,
+            modified: Utc::now(),
+            created: Utc::now(),
+            discovered: None,
+            ack: None,
+            confirmation: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_follow_placement() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-dispatcher-notify-{}", std::process::id()));
+        let target_directory = directory.join("target");
+        fs::create_dir_all(&target_directory).unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let persistence = SqliteAsyncPersistence::new(Arc::new(Mutex::new(conn)));
+
+        let mut target_conf = settings::Settings::default().directory_targets[0].clone();
+        target_conf.directory = target_directory.clone();
+        target_conf.notify = None;
+
+        let settings = settings::Settings::default();
+        let (sender, receiver) = file_event_channel("target:red", &settings.channels);
+
+        for file_id in 1..=3 {
+            let path = directory.join(format!("{file_id}.csv"));
+            fs::write(&path, "a,b\n").unwrap();
+
+            persistence
+                .insert_file("red", &path.to_string_lossy(), &Utc::now(), 4, None)
+                .await
+                .unwrap();
+
+            sender
+                .send_waiting(test_event(file_id, path))
+                .await
+                .unwrap();
+        }
+
+        // The handler ends when the channel is closed and empty
+        drop(sender);
+
+        let notified = Arc::new(Mutex::new(Vec::new()));
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            handle_target_events(
+                target_conf,
+                receiver,
+                None,
+                Some(Box::new(RecordingNotifier {
+                    notified: notified.clone(),
+                    failures: 0,
+                })),
+                persistence,
+                false,
+                Duration::from_millis(100),
+                Arc::default(),
+            ),
+        )
+        .await
+        .unwrap();
+
+        fs::remove_dir_all(&directory).unwrap();
+
+        let notified = notified.lock().unwrap().clone();
+
+        assert_eq!(
+            notified,
+            (1..=3)
+                .map(|file_id| (
+                    target_directory.join(format!("{file_id}.csv")),
+                    true,
+                    "red".to_string()
+                ))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn dispatches_are_registered_before_notification() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-dispatcher-register-{}", std::process::id()));
+        let target_directory = directory.join("target");
+        fs::create_dir_all(&target_directory).unwrap();
+
+        let source_path = directory.join("data.csv");
+        fs::write(&source_path, "a,b\n").unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+        let persistence = SqliteAsyncPersistence::new(conn.clone());
+
+        let file_id = persistence
+            .insert_file("red", &source_path.to_string_lossy(), &Utc::now(), 4, None)
+            .await
+            .unwrap();
+
+        // Registering dispatches fails until the trigger is dropped
+        conn.lock()
+            .unwrap()
+            .execute_batch(
+                "create trigger fail_dispatched before insert on dispatched
+                 begin select raise(fail, 'database is locked'); end",
+            )
+            .unwrap();
+
+        let mut target_conf = settings::Settings::default().directory_targets[0].clone();
+        target_conf.name = "registered-first".to_string();
+        target_conf.directory = target_directory.clone();
+        target_conf.notify = None;
+
+        let settings = settings::Settings::default();
+        let (sender, receiver) = file_event_channel("target:registered-first", &settings.channels);
+
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        let in_progress = Arc::new(AtomicUsize::new(0));
+
+        let handler = tokio::spawn(handle_target_events(
+            target_conf,
+            receiver,
+            None,
+            Some(Box::new(RecordingNotifier {
+                notified: notified.clone(),
+                failures: 1,
+            })),
+            persistence,
+            false,
+            Duration::from_millis(100),
+            in_progress.clone(),
+        ));
+
+        sender
+            .send_waiting(test_event(file_id, source_path))
+            .await
+            .unwrap();
+
+        let record_failures =
+            metrics::DISPATCHED_RECORD_FAILURES_COUNTER.with_label_values(&["registered-first"]);
+
+        while record_failures.get() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let notified_unregistered = notified.lock().unwrap().len();
+        let in_progress_while_failing = in_progress.load(Ordering::SeqCst);
+
+        conn.lock()
+            .unwrap()
+            .execute_batch("drop trigger fail_dispatched")
+            .unwrap();
+
+        drop(sender);
+
+        tokio::time::timeout(Duration::from_secs(5), handler)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let dispatched: i64 = conn
+            .lock()
+            .unwrap()
+            .query_row("select count(*) from dispatched", [], |row| row.get(0))
+            .unwrap();
+
+        let target_path = target_directory.join("data.csv");
+        let placed_again = target_path.exists();
+
+        fs::remove_dir_all(&directory).unwrap();
+
+        let notified = notified.lock().unwrap().clone();
+
+        assert_eq!(notified_unregistered, 0);
+        assert_eq!(dispatched, 1);
+        // The failed notification is retried without placing the file again,
+        // which the failing notifier removed
+        assert_eq!(
+            notified,
+            vec![
+                (target_path.clone(), true, "registered-first".to_string()),
+                (target_path, false, "registered-first".to_string()),
+            ]
+        );
+        assert!(!placed_again);
+        // Retried dispatches count as in progress, for draining
+        assert!(in_progress_while_failing > 0);
+        assert_eq!(in_progress.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn stop_signal_is_not_a_failure() {
+        let target_handler = tokio::spawn(futures::future::pending::<()>());
+
+        let result = wait_for_stop(
+            futures::future::ready(()),
+            vec![critical_task(
+                "directory target 'red'".to_string(),
+                target_handler,
+            )],
+        )
+        .await;
+
+        assert!(result.is_ok());
+    }
+}
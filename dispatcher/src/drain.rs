@@ -0,0 +1,149 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use tokio::sync::watch;
+
+use crate::pause::SourcePauses;
+use crate::readiness::{ComponentState, Readiness};
+
+/// Drain of the dispatcher before it stops, for handing over to a new
+/// instance without losing commands
+///
+/// A drain pauses all sources, so that the SFTP command consumers are
+/// cancelled and queued commands stay on the broker, and makes the
+/// dispatcher not ready. The consumers report when they have no commands in
+/// progress anymore.
+#[derive(Debug, Clone)]
+pub struct Drain {
+    readiness: Readiness,
+    source_pauses: SourcePauses,
+    started: Arc<watch::Sender<bool>>,
+    /// SFTP sources of which the consumer still has commands in progress
+    consuming: Arc<Mutex<BTreeSet<String>>>,
+    consumers_drained: Arc<watch::Sender<bool>>,
+}
+
+impl Drain {
+    pub fn new(
+        readiness: Readiness,
+        source_pauses: SourcePauses,
+        sftp_sources: impl IntoIterator<Item = String>,
+    ) -> Drain {
+        let consuming: BTreeSet<String> = sftp_sources.into_iter().collect();
+        let consumers_drained = watch::Sender::new(consuming.is_empty());
+
+        Drain {
+            readiness,
+            source_pauses,
+            started: Arc::new(watch::Sender::new(false)),
+            consuming: Arc::new(Mutex::new(consuming)),
+            consumers_drained: Arc::new(consumers_drained),
+        }
+    }
+
+    /// Start draining, returning false when the drain was already started
+    pub fn start(&self, trigger: &str) -> bool {
+        if self.started.send_replace(true) {
+            return false;
+        }
+
+        info!("Draining dispatcher on {trigger}: no new commands are consumed, work in progress is completed before stopping");
+
+        self.readiness.set("drain", ComponentState::NotReady);
+
+        for status in self.source_pauses.statuses() {
+            // The names come from the pauses themselves
+            let _ = self.source_pauses.set_paused(&status.name, true);
+        }
+
+        true
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.started.borrow()
+    }
+
+    /// Wait until the drain is started
+    pub async fn started(&self) {
+        let mut started = self.started.subscribe();
+
+        // The sender is kept by self, so waiting cannot fail
+        let _ = started.wait_for(|started| *started).await;
+    }
+
+    /// Register that the consumer of an SFTP source has no commands in
+    /// progress anymore
+    pub fn consumer_drained(&self, sftp_source: &str) {
+        let mut consuming = self.consuming.lock().unwrap();
+
+        if consuming.remove(sftp_source) {
+            info!("Drained SFTP command consumer of source '{sftp_source}'");
+        }
+
+        if consuming.is_empty() {
+            self.consumers_drained.send_replace(true);
+        }
+    }
+
+    /// Wait until the drain is started and the consumers of all SFTP sources
+    /// are drained
+    pub async fn consumers_drained(&self) {
+        self.started().await;
+
+        let mut consumers_drained = self.consumers_drained.subscribe();
+
+        let _ = consumers_drained.wait_for(|drained| *drained).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::settings::Settings;
+
+    #[tokio::test]
+    async fn drain_pauses_sources_and_waits_for_consumers() {
+        let settings = Settings::default();
+        let readiness = Readiness::default();
+        let source_pauses = SourcePauses::new(&settings);
+        let sftp_sources: Vec<String> = settings
+            .sftp_sources
+            .iter()
+            .map(|sftp_source| sftp_source.name.clone())
+            .collect();
+
+        let drain = Drain::new(
+            readiness.clone(),
+            source_pauses.clone(),
+            sftp_sources.clone(),
+        );
+
+        let consumers_drained = tokio::spawn({
+            let drain = drain.clone();
+
+            async move { drain.consumers_drained().await }
+        });
+
+        assert!(drain.start("test"));
+        assert!(!drain.start("test"));
+        assert!(!readiness.is_ready());
+        assert!(source_pauses.statuses().iter().all(|status| status.paused));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(!consumers_drained.is_finished());
+
+        for sftp_source in &sftp_sources {
+            drain.consumer_drained(sftp_source);
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), consumers_drained)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,18 +1,237 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::sync::mpsc::UnboundedSender;
+use async_channel::TrySendError;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use cortex_core::filter::Filterable;
+use log::warn;
+
+use crate::delivery::{Confirmation, PendingAck};
+use crate::metrics;
+use crate::persistence::FileRecord;
+use crate::settings::{Channels, Overflow};
+
+/// Only every so many dropped events of a channel are logged
+const DROP_LOG_SAMPLING: u64 = 100;
 
 #[derive(Debug, Clone)]
 pub struct FileEvent {
     pub file_id: i64,
     pub source_name: String,
     pub path: PathBuf,
+    /// Path relative to the directory of the source in local storage, or to
+    /// the directory of the target for placed files
+    pub relative_path: Option<PathBuf>,
+    /// SHA-256 hash that is registered for the file
     pub hash: String,
+    /// Whether the hash is over the content of the file at the path, and not
+    /// over the file before it was decompressed
+    pub content_hash: bool,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Modified time of the file at its source
+    pub modified: DateTime<Utc>,
+    /// When the event was created by its source
+    pub created: DateTime<Utc>,
+    /// When the scanner discovered the file on its SFTP server, by the clock
+    /// of the scanner host
+    pub discovered: Option<DateTime<Utc>>,
+    /// Held back acknowledgment of the download command of the file, for
+    /// connections that require delivery
+    pub ack: Option<PendingAck>,
+    /// Confirmation for the target that handles the file, when its connection
+    /// requires delivery
+    pub confirmation: Option<Confirmation>,
+}
+
+/// Filters are evaluated on the attributes of the event, without reading
+/// from the file system
+impl Filterable for FileEvent {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.size)
+    }
+}
+
+impl FileEvent {
+    /// Time since the event was created
+    pub fn age(&self) -> Duration {
+        elapsed_since(self.created)
+    }
+
+    /// Time since the scanner discovered the file, which is zero when the
+    /// clock of the scanner host is ahead
+    pub fn since_discovery(&self) -> Option<Duration> {
+        self.discovered.map(elapsed_since)
+    }
+}
+
+/// Time since the timestamp, clamped to zero for timestamps in the future
+pub fn elapsed_since(timestamp: DateTime<Utc>) -> Duration {
+    (Utc::now() - timestamp).to_std().unwrap_or_default()
+}
+
+/// Event for dispatching a file from internal storage again
+impl From<&FileRecord> for FileEvent {
+    fn from(file: &FileRecord) -> Self {
+        FileEvent {
+            file_id: file.id,
+            source_name: file.source.clone(),
+            path: PathBuf::from(&file.path),
+            // Not registered, so targets place the file by its name
+            relative_path: None,
+            hash: file.hash.clone().unwrap_or_default(),
+            // The stored hash may be over the file before it was decompressed
+            content_hash: false,
+            size: file.size as u64,
+            modified: NaiveDateTime::parse_from_str(&file.modified, "%Y-%m-%d %H:%M:%S")
+                .map(|modified| modified.and_utc())
+                .unwrap_or_default(),
+            created: Utc::now(),
+            discovered: file.discovered.as_deref().and_then(|discovered| {
+                DateTime::parse_from_rfc3339(discovered)
+                    .map(|discovered| discovered.to_utc())
+                    .ok()
+            }),
+            ack: None,
+            confirmation: None,
+        }
+    }
+}
+
+pub type FileEventReceiver = async_channel::Receiver<FileEvent>;
+
+/// Sending side of a file event channel, applying the overflow policy when
+/// the channel is bounded and full
+#[derive(Debug, Clone)]
+pub struct FileEventSender {
+    name: String,
+    sender: async_channel::Sender<FileEvent>,
+    /// For dropping the oldest event
+    receiver: async_channel::Receiver<FileEvent>,
+    overflow: Overflow,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Create a file event channel with the capacity and overflow policy of the
+/// settings
+pub fn file_event_channel(name: &str, channels: &Channels) -> (FileEventSender, FileEventReceiver) {
+    let (sender, receiver) = match channels.file_event_capacity {
+        Some(capacity) => async_channel::bounded(capacity),
+        None => async_channel::unbounded(),
+    };
+
+    let file_event_sender = FileEventSender {
+        name: name.to_string(),
+        sender,
+        receiver: receiver.clone(),
+        overflow: channels.overflow,
+        dropped: Arc::new(AtomicU64::new(0)),
+    };
+
+    (file_event_sender, receiver)
+}
+
+impl FileEventSender {
+    /// Send from async code
+    pub async fn send(&self, file_event: FileEvent) -> Result<(), String> {
+        match self.overflow {
+            Overflow::Block => self
+                .sender
+                .send(file_event)
+                .await
+                .map_err(|e| e.to_string()),
+            _ => self.try_send(file_event),
+        }
+    }
+
+    /// Send from async code, waiting for room in a full channel whatever the
+    /// overflow policy
+    pub async fn send_waiting(&self, file_event: FileEvent) -> Result<(), String> {
+        self.sender
+            .send(file_event)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.sender.is_full()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sender.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sender.len()
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    /// Send from a thread outside of the async runtime
+    pub fn send_blocking(&self, file_event: FileEvent) -> Result<(), String> {
+        match self.overflow {
+            Overflow::Block => self
+                .sender
+                .send_blocking(file_event)
+                .map_err(|e| e.to_string()),
+            _ => self.try_send(file_event),
+        }
+    }
+
+    fn try_send(&self, file_event: FileEvent) -> Result<(), String> {
+        let mut file_event = file_event;
+
+        loop {
+            match self.sender.try_send(file_event) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed(_)) => return Err("Channel closed".to_string()),
+                Err(TrySendError::Full(rejected)) => {
+                    if self.overflow != Overflow::DropOldest {
+                        self.count_dropped(&rejected);
+
+                        return Err(format!("Channel '{}' is full", self.name));
+                    }
+
+                    // Make room, unless the receiver did in the meantime
+                    if let Ok(oldest) = self.receiver.try_recv() {
+                        self.count_dropped(&oldest);
+                    }
+
+                    file_event = rejected;
+                }
+            }
+        }
+    }
+
+    fn count_dropped(&self, file_event: &FileEvent) {
+        metrics::FILE_EVENTS_DROPPED_COUNTER
+            .with_label_values(&[&self.name])
+            .inc();
+
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if dropped % DROP_LOG_SAMPLING == 1 {
+            warn!(
+                "Channel '{}' is full, dropped file event for '{}' ({} dropped in total)",
+                self.name,
+                file_event.path.to_string_lossy(),
+                dropped
+            );
+        }
+    }
 }
 
 pub struct EventDispatcher {
-    pub senders: HashMap<String, UnboundedSender<FileEvent>>,
+    pub senders: HashMap<String, FileEventSender>,
 }
 
 impl EventDispatcher {
@@ -28,11 +247,82 @@ impl EventDispatcher {
             }
         };
 
-        let send_result = sender.send(file_event.clone());
+        sender.send_blocking(file_event.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        match send_result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("{}", e)),
+    fn file_event(file_id: i64) -> FileEvent {
+        FileEvent {
+            file_id,
+            source_name: "red".to_string(),
+            path: PathBuf::from(format!("/data/{file_id}.xml")),
+            relative_path: None,
+            hash: String::new(),
+            content_hash: true,
+            size: 0,
+            modified: Utc::now(),
+            created: Utc::now(),
+            discovered: None,
+            ack: None,
+            confirmation: None,
         }
     }
+
+    fn channels(overflow: Overflow) -> Channels {
+        Channels {
+            sftp_command_capacity: 10,
+            ack_capacity: 100,
+            file_event_capacity: Some(2),
+            overflow,
+        }
+    }
+
+    fn received(receiver: &FileEventReceiver) -> Vec<i64> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|file_event| file_event.file_id)
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest_keeps_newest_events() {
+        let (sender, receiver) = file_event_channel("source:red", &channels(Overflow::DropOldest));
+
+        for file_id in 1..=4 {
+            sender.send_blocking(file_event(file_id)).unwrap();
+        }
+
+        assert_eq!(received(&receiver), vec![3, 4]);
+    }
+
+    #[test]
+    fn error_rejects_new_events() {
+        let (sender, receiver) = file_event_channel("source:red", &channels(Overflow::Error));
+
+        sender.send_blocking(file_event(1)).unwrap();
+        sender.send_blocking(file_event(2)).unwrap();
+
+        assert_eq!(
+            sender.send_blocking(file_event(3)).unwrap_err(),
+            "Channel 'source:red' is full"
+        );
+        assert_eq!(received(&receiver), vec![1, 2]);
+    }
+
+    #[test]
+    fn discovery_after_now_is_clamped_to_zero() {
+        let mut file_event = file_event(1);
+
+        assert_eq!(file_event.since_discovery(), None);
+
+        // The clock of the scanner host is ahead
+        file_event.discovered = Some(Utc::now() + chrono::Duration::seconds(30));
+        assert_eq!(file_event.since_discovery(), Some(Duration::ZERO));
+
+        file_event.discovered = Some(Utc::now() - chrono::Duration::seconds(30));
+        assert!(file_event.since_discovery().unwrap() >= Duration::from_secs(30));
+    }
 }
@@ -0,0 +1,207 @@
+//! Publishing of the file events of all sources to RabbitMQ, independent of
+//! the connections and targets
+//!
+//! Every publisher has its own bounded channel, to which the dispatch streams
+//! only send without waiting, so that a publisher that cannot reach its
+//! broker drops file events instead of holding up the dispatching.
+
+use log::{debug, info};
+use serde_json::json;
+use tera::{Context, Tera};
+use tokio::sync::watch;
+
+use cortex_core::error_code::ErrorCode;
+use cortex_core::filter::Filter;
+use cortex_core::throttled_error;
+
+use crate::event::{file_event_channel, FileEvent, FileEventReceiver, FileEventSender};
+use crate::metrics;
+use crate::notifier::{deduplication_id, RabbitMQNotifier};
+use crate::settings;
+
+/// Dispatching side of an event publisher
+pub struct EventPublisher {
+    pub name: String,
+    pub filter: Option<Filter>,
+    pub sender: FileEventSender,
+}
+
+impl EventPublisher {
+    /// Queue the file event for publishing when it passes the filter, dropping
+    /// it when the channel of the publisher is full
+    pub async fn dispatch(&self, file_event: &FileEvent) {
+        if !self.filter.as_ref().is_none_or(|f| f.matches(file_event)) {
+            return;
+        }
+
+        if let Err(e) = self.sender.send(file_event.clone()).await {
+            debug!(
+                "File event not queued for event publisher '{}': {e}",
+                self.name
+            );
+        }
+    }
+}
+
+/// Start publishing the file events sent to the returned publisher
+///
+/// In a dry run, the messages are logged instead of published.
+pub fn start_event_publisher(
+    publisher_conf: settings::EventPublisher,
+    channels: &settings::Channels,
+    mut stop_receiver: watch::Receiver<()>,
+    dry_run: bool,
+) -> (EventPublisher, tokio::task::JoinHandle<()>) {
+    let (sender, receiver) = file_event_channel(
+        &format!("event_publisher:{}", publisher_conf.name),
+        &settings::Channels {
+            file_event_capacity: Some(publisher_conf.capacity),
+            overflow: settings::Overflow::Error,
+            ..channels.clone()
+        },
+    );
+
+    let mut notifier = RabbitMQNotifier::from(&publisher_conf);
+    notifier.dry_run = dry_run;
+
+    let publisher = EventPublisher {
+        name: publisher_conf.name.clone(),
+        filter: publisher_conf.filter.clone(),
+        sender,
+    };
+
+    let join_handle = tokio::spawn(async move {
+        tokio::select!(
+            _a = publish_events(publisher_conf.name.clone(), receiver, notifier) => info!("Event publisher '{}' stopped", publisher_conf.name),
+            _b = stop_receiver.changed() => ()
+        );
+    });
+
+    (publisher, join_handle)
+}
+
+async fn publish_events(name: String, receiver: FileEventReceiver, mut notifier: RabbitMQNotifier) {
+    while let Ok(file_event) = receiver.recv().await {
+        match publish_event(&name, &mut notifier, &file_event).await {
+            Ok(()) => metrics::EVENTS_PUBLISHED_COUNTER
+                .with_label_values(&[&name])
+                .inc(),
+            Err(e) => {
+                throttled_error!(
+                    ErrorCode::EventPublish,
+                    &name,
+                    "Could not publish file event of '{}': {e}",
+                    file_event.path.to_string_lossy()
+                );
+            }
+        }
+    }
+}
+
+async fn publish_event(
+    name: &str,
+    notifier: &mut RabbitMQNotifier,
+    file_event: &FileEvent,
+) -> Result<(), String> {
+    let message = event_message(file_event);
+
+    let context =
+        Context::from_serialize(&message).map_err(|e| format!("Could not create context: {e}"))?;
+
+    let routing_key = Tera::one_off(&notifier.routing_key, &context, false)
+        .map_err(|e| format!("Error rendering routing key template: {e}"))?;
+
+    notifier
+        .publish_message(
+            &routing_key,
+            &message.to_string(),
+            &deduplication_id(file_event, name),
+        )
+        .await
+}
+
+/// JSON message of a file event, of which the fields are also the variables
+/// of the routing key template
+fn event_message(file_event: &FileEvent) -> serde_json::Value {
+    json!({
+        "file_id": file_event.file_id,
+        "source": &file_event.source_name,
+        "file_path": &file_event.path,
+        "relative_path": &file_event.relative_path,
+        "hash": &file_event.hash,
+        "size": file_event.size,
+        "modified": file_event.modified.to_rfc3339(),
+        "created": file_event.created.to_rfc3339(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use chrono::Utc;
+    use cortex_core::filter::SizeFilter;
+
+    fn file_event(path: &str, size: u64) -> FileEvent {
+        FileEvent {
+            file_id: 1,
+            source_name: "red".to_string(),
+            path: PathBuf::from(path),
+            relative_path: None,
+            hash: String::new(),
+            content_hash: false,
+            size,
+            modified: Utc::now(),
+            created: Utc::now(),
+            discovered: None,
+            ack: None,
+            confirmation: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn full_publisher_channel_drops_instead_of_waiting() {
+        let (sender, receiver) = file_event_channel(
+            "event_publisher:test",
+            &settings::Channels {
+                file_event_capacity: Some(1),
+                overflow: settings::Overflow::Error,
+                sftp_command_capacity: 1,
+                ack_capacity: 1,
+            },
+        );
+
+        let publisher = EventPublisher {
+            name: "test".to_string(),
+            filter: Some(Filter::Size(SizeFilter {
+                min_bytes: Some(10),
+                max_bytes: None,
+            })),
+            sender,
+        };
+
+        publisher.dispatch(&file_event("/data/a.xml", 10)).await;
+        publisher.dispatch(&file_event("/data/b.xml", 5)).await;
+        // Returns right away although nobody is receiving
+        publisher.dispatch(&file_event("/data/c.xml", 10)).await;
+
+        assert_eq!(receiver.len(), 1);
+        assert_eq!(
+            receiver.recv().await.unwrap().path,
+            PathBuf::from("/data/a.xml")
+        );
+    }
+
+    #[test]
+    fn routing_key_is_rendered_from_the_event() {
+        let message = event_message(&file_event("/data/a.xml", 10));
+        let context = Context::from_serialize(&message).unwrap();
+
+        assert_eq!(
+            Tera::one_off("events.{{ source }}", &context, false).unwrap(),
+            "events.red"
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use crate::directory_source::sha256_hash_file;
+use crate::persistence::{FileRecord, SqlitePersistence};
+use crate::rate_limit::TokenBucket;
+use crate::reconcile::hash_is_verifiable;
+use crate::settings::{RateLimit, Settings};
+
+/// Result of backfilling the hash of a file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backfilled {
+    Hashed(String),
+    /// The file no longer exists in internal storage
+    Missing,
+    /// The hash that the source registers cannot be calculated from the
+    /// stored file
+    Unverifiable,
+    Failed(String),
+}
+
+/// Limits the number of bytes read per second, shared by the hashing threads
+pub struct ReadRateLimit(Mutex<TokenBucket>);
+
+impl ReadRateLimit {
+    pub fn new(bytes_per_second: f64) -> ReadRateLimit {
+        ReadRateLimit(Mutex::new(TokenBucket::new(&RateLimit {
+            events_per_second: bytes_per_second,
+            // One second of reading after a quiet period
+            burst: bytes_per_second.min(f64::from(u32::MAX)) as u32,
+        })))
+    }
+
+    /// Wait until the number of bytes may be read
+    fn acquire(&self, bytes: u64) {
+        let delay = self
+            .0
+            .lock()
+            .unwrap()
+            .take_tokens(Instant::now(), bytes as f64);
+
+        thread::sleep(delay);
+    }
+}
+
+/// Calculate and register the hashes of files, hashing at most `concurrency`
+/// files at a time
+///
+/// The results are in the order of the files.
+pub fn backfill_hashes(
+    persistence: &SqlitePersistence,
+    settings: &Settings,
+    files: Vec<FileRecord>,
+    concurrency: usize,
+    read_rate_limit: Option<&ReadRateLimit>,
+) -> Vec<(FileRecord, Backfilled)> {
+    let pending = Mutex::new(files.into_iter().enumerate());
+    let results = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..concurrency.max(1) {
+            scope.spawn(|| loop {
+                let next = pending.lock().unwrap().next();
+
+                let Some((index, file)) = next else {
+                    break;
+                };
+
+                let backfilled = backfill_hash(persistence, settings, &file, read_rate_limit);
+
+                results.lock().unwrap().push((index, file, backfilled));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+
+    results.sort_by_key(|(index, _, _)| *index);
+
+    results
+        .into_iter()
+        .map(|(_, file, backfilled)| (file, backfilled))
+        .collect()
+}
+
+fn backfill_hash(
+    persistence: &SqlitePersistence,
+    settings: &Settings,
+    file: &FileRecord,
+    read_rate_limit: Option<&ReadRateLimit>,
+) -> Backfilled {
+    if !hash_is_verifiable(settings, &file.source) {
+        return Backfilled::Unverifiable;
+    }
+
+    let path = Path::new(&file.path);
+
+    if !path.exists() {
+        return Backfilled::Missing;
+    }
+
+    if let Some(read_rate_limit) = read_rate_limit {
+        read_rate_limit.acquire(file.size.max(0) as u64);
+    }
+
+    // Directory sources can register the hash of the decompressed content of
+    // .gz files
+    let unpack = settings
+        .directory_sources
+        .iter()
+        .any(|source| source.name == file.source && source.unpack_before_hash);
+
+    let hash = match sha256_hash_file(path, unpack) {
+        Ok(hash) => hash,
+        Err(e) => return Backfilled::Failed(format!("Error calculating hash: {e}")),
+    };
+
+    match persistence.set_file_hash(file.id, &hash) {
+        Ok(()) => Backfilled::Hashed(hash),
+        Err(e) => Backfilled::Failed(e.to_string()),
+    }
+}
+
+/// Id of the last file of which the hash was backfilled, 0 when the state
+/// file does not exist yet
+pub fn read_last_id(state_file: &Path) -> Result<i64, String> {
+    match std::fs::read_to_string(state_file) {
+        Ok(content) => content.trim().parse().map_err(|e| {
+            format!(
+                "Invalid state file '{}': {}",
+                state_file.to_string_lossy(),
+                e
+            )
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(format!(
+            "Error reading state file '{}': {}",
+            state_file.to_string_lossy(),
+            e
+        )),
+    }
+}
+
+/// Replace the state file, so that an interrupted backfill continues after
+/// this file
+pub fn write_last_id(state_file: &Path, last_id: i64) -> Result<(), String> {
+    let part_path = state_file.with_extension("part");
+
+    std::fs::write(&part_path, format!("{last_id}\n"))
+        .and_then(|_| std::fs::rename(&part_path, state_file))
+        .map_err(|e| {
+            format!(
+                "Error writing state file '{}': {}",
+                state_file.to_string_lossy(),
+                e
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use chrono::Utc;
+
+    use crate::persistence::{FileQuery, Persistence};
+    use crate::settings::{Decompress, StoredHash};
+
+    #[test]
+    fn hashes_are_backfilled_and_resumed() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-hash-backfill-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let persistence = SqlitePersistence::from_arc(Arc::new(Mutex::new(conn)));
+
+        let mut settings = Settings::default();
+        settings.sftp_sources[1].decompress = Decompress::Gzip;
+        settings.sftp_sources[1].stored_hash = StoredHash::Original;
+
+        let stored = directory.join("stored.csv");
+        std::fs::write(&stored, "a,b\n").unwrap();
+
+        let register = |source: &str, path: &Path| {
+            persistence
+                .insert_file(source, &path.to_string_lossy(), &Utc::now(), 4, None)
+                .unwrap()
+        };
+
+        let stored_id = register("red", &stored);
+        register("red", &directory.join("missing.csv"));
+        register("blue", &stored);
+
+        let query = FileQuery {
+            without_hash: true,
+            ..Default::default()
+        };
+
+        let results = backfill_hashes(
+            &persistence,
+            &settings,
+            persistence.query_files(&query).unwrap(),
+            2,
+            Some(&ReadRateLimit::new(1_000_000.0)),
+        );
+
+        let outcomes: Vec<Backfilled> = results.into_iter().map(|(_, b)| b).collect();
+        let remaining = persistence.count_files(&query).unwrap();
+
+        let state_file = directory.join("backfill.state");
+        let first_last_id = read_last_id(&state_file).unwrap();
+        write_last_id(&state_file, stored_id).unwrap();
+        let resumed_last_id = read_last_id(&state_file).unwrap();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            outcomes,
+            vec![
+                Backfilled::Hashed(
+                    "5be08c9684a1d25efcee09318204824278b08bbfb4aef973ffefd0b9d7478313".to_string()
+                ),
+                Backfilled::Missing,
+                Backfilled::Unverifiable,
+            ]
+        );
+        assert_eq!(remaining, 2);
+        assert_eq!(first_last_id, 0);
+        assert_eq!(resumed_last_id, stored_id);
+    }
+}
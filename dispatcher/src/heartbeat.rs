@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use log::warn;
+use tokio::time::MissedTickBehavior;
+
+use crate::persistence::SqliteAsyncPersistence;
+
+/// Writes the heartbeats of the components of the dispatcher to the database
+#[derive(Clone)]
+pub struct Heartbeats {
+    /// None when the heartbeats are disabled
+    persistence: Option<SqliteAsyncPersistence>,
+    instance: String,
+    interval: Duration,
+}
+
+impl Heartbeats {
+    /// Heartbeats every interval milliseconds, disabled when it is 0
+    pub fn new(persistence: SqliteAsyncPersistence, interval: u64) -> Heartbeats {
+        Heartbeats {
+            persistence: (interval > 0).then_some(persistence),
+            instance: cortex_core::heartbeat::instance_name(),
+            interval: Duration::from_millis(interval),
+        }
+    }
+
+    /// Write the heartbeat of the component every interval, with its details
+    /// at that time
+    ///
+    /// Never ends, so it is meant to be selected together with the component
+    /// itself. Failures are only logged, so that a briefly unavailable
+    /// database does not stop the component.
+    pub async fn keep<F>(self, component: String, details: F)
+    where
+        F: Fn() -> serde_json::Value,
+    {
+        let persistence = match self.persistence {
+            Some(persistence) => persistence,
+            None => return std::future::pending().await,
+        };
+
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+
+            if let Err(e) = persistence
+                .upsert_heartbeat(&component, &self.instance, details())
+                .await
+            {
+                warn!("{e}");
+            }
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use log::error;
+
+use actix_web::{
+    http::header::ContentType, middleware, web, App, HttpResponse, HttpServer, Responder,
+};
+
+use chrono::{DateTime, Utc};
+use prometheus::{Encoder, TextEncoder};
+use serde::Deserialize;
+
+use crate::drain::Drain;
+use crate::pause::SourcePauses;
+use crate::persistence::{FileQuery, SqliteAsyncPersistence};
+use crate::readiness::Readiness;
+use crate::runtime_targets::{RuntimeTargetError, RuntimeTargets};
+use crate::settings;
+use crate::source_activity::SourceActivities;
+use crate::systemd::Startup;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn start_http_server(
+    addr: std::net::SocketAddr,
+    source_pauses: SourcePauses,
+    source_activities: SourceActivities,
+    readiness: Readiness,
+    persistence: SqliteAsyncPersistence,
+    runtime_targets: RuntimeTargets,
+    drain: Drain,
+    startup: Startup,
+) -> std::io::Result<()> {
+    let source_pauses = web::Data::new(source_pauses);
+    let source_activities = web::Data::new(source_activities);
+    let readiness = web::Data::new(readiness);
+    let persistence = web::Data::new(persistence);
+    let runtime_targets = web::Data::new(runtime_targets);
+    let drain = web::Data::new(drain);
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(middleware::Logger::default())
+            .app_data(source_pauses.clone())
+            .app_data(source_activities.clone())
+            .app_data(readiness.clone())
+            .app_data(persistence.clone())
+            .app_data(runtime_targets.clone())
+            .app_data(drain.clone())
+            .service(web::resource("/api/metrics").to(metrics))
+            .service(web::resource("/readyz").to(readyz))
+            .service(web::resource("/api/drain").route(web::post().to(start_drain)))
+            .service(web::resource("/api/sources").route(web::get().to(sources)))
+            .service(web::resource("/api/sources/{name}").route(web::get().to(source)))
+            .service(web::resource("/api/sources/{name}/pause").route(web::post().to(pause)))
+            .service(web::resource("/api/sources/{name}/resume").route(web::post().to(resume)))
+            .service(web::resource("/api/files/{id}").route(web::get().to(file)))
+            .service(web::resource("/api/stats").route(web::get().to(stats)))
+            .service(web::resource("/api/targets").route(web::post().to(add_target)))
+            .service(web::resource("/api/targets/{name}").route(web::delete().to(remove_target)))
+            .service(web::resource("/api/connections").route(web::post().to(add_connection)))
+    })
+    .bind(addr)?
+    // Stopping on signals is up to the dispatcher
+    .disable_signals()
+    .run();
+
+    startup.http_server_started();
+
+    server.await
+}
+
+async fn metrics() -> impl Responder {
+    let metric_families = prometheus::gather();
+
+    let encoder = TextEncoder::new();
+
+    let mut buffer = Vec::new();
+
+    let encode_result = encoder.encode(&metric_families, &mut buffer);
+
+    match encode_result {
+        Ok(_) => {}
+        Err(e) => error!("Error encoding metrics: {}", e),
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(String::from_utf8(buffer).unwrap())
+}
+
+async fn readyz(readiness: web::Data<Readiness>) -> impl Responder {
+    let body = serde_json::json!({
+        "ready": readiness.is_ready(),
+        "components": readiness.components(),
+    });
+
+    if readiness.is_ready() {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// Start a drain, after which the dispatcher stops by itself
+async fn start_drain(drain: web::Data<Drain>) -> HttpResponse {
+    drain.start("request");
+
+    HttpResponse::Accepted().json(serde_json::json!({ "draining": true }))
+}
+
+async fn sources(
+    source_pauses: web::Data<SourcePauses>,
+    source_activities: web::Data<SourceActivities>,
+) -> impl Responder {
+    let sources: Vec<_> = source_pauses
+        .statuses()
+        .into_iter()
+        .map(|status| source_activities.health(status))
+        .collect();
+
+    HttpResponse::Ok().json(sources)
+}
+
+async fn source(
+    source_pauses: web::Data<SourcePauses>,
+    source_activities: web::Data<SourceActivities>,
+    name: web::Path<String>,
+) -> HttpResponse {
+    match source_pauses.status(&name) {
+        Some(status) => HttpResponse::Ok().json(source_activities.health(status)),
+        None => HttpResponse::NotFound()
+            .content_type(ContentType::plaintext())
+            .body(format!("No source named '{name}'")),
+    }
+}
+
+async fn pause(source_pauses: web::Data<SourcePauses>, name: web::Path<String>) -> HttpResponse {
+    set_paused(&source_pauses, &name, true)
+}
+
+async fn resume(
+    source_pauses: web::Data<SourcePauses>,
+    drain: web::Data<Drain>,
+    name: web::Path<String>,
+) -> HttpResponse {
+    // Sources stay paused until the drained dispatcher stops
+    if drain.is_started() {
+        return HttpResponse::Conflict()
+            .content_type(ContentType::plaintext())
+            .body("The dispatcher is draining");
+    }
+
+    set_paused(&source_pauses, &name, false)
+}
+
+fn set_paused(source_pauses: &SourcePauses, name: &str, paused: bool) -> HttpResponse {
+    match source_pauses.set_paused(name, paused) {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => HttpResponse::NotFound()
+            .content_type(ContentType::plaintext())
+            .body(e),
+    }
+}
+
+async fn add_target(
+    runtime_targets: web::Data<RuntimeTargets>,
+    target_conf: web::Json<settings::DirectoryTarget>,
+) -> HttpResponse {
+    let target_conf = target_conf.into_inner();
+
+    match runtime_targets.add_directory_target(target_conf.clone()) {
+        Ok(()) => HttpResponse::Created().json(target_conf),
+        Err(e) => runtime_target_error(e),
+    }
+}
+
+async fn remove_target(
+    runtime_targets: web::Data<RuntimeTargets>,
+    name: web::Path<String>,
+) -> HttpResponse {
+    match runtime_targets.remove_directory_target(&name) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => runtime_target_error(e),
+    }
+}
+
+async fn add_connection(
+    runtime_targets: web::Data<RuntimeTargets>,
+    conn_conf: web::Json<settings::Connection>,
+) -> HttpResponse {
+    let conn_conf = conn_conf.into_inner();
+
+    match runtime_targets.add_connection(conn_conf.clone()) {
+        Ok(()) => HttpResponse::Created().json(conn_conf),
+        Err(e) => runtime_target_error(e),
+    }
+}
+
+fn runtime_target_error(e: RuntimeTargetError) -> HttpResponse {
+    let mut response = match &e {
+        RuntimeTargetError::Invalid(_) => HttpResponse::BadRequest(),
+        RuntimeTargetError::NotFound(_) => HttpResponse::NotFound(),
+        RuntimeTargetError::Conflict(_) => HttpResponse::Conflict(),
+        RuntimeTargetError::Persist(_) => {
+            error!("{e}");
+            HttpResponse::InternalServerError()
+        }
+    };
+
+    response
+        .content_type(ContentType::plaintext())
+        .body(e.to_string())
+}
+
+/// A file in storage with the decisions on dispatching it, when auditing is
+/// enabled
+async fn file(persistence: web::Data<SqliteAsyncPersistence>, id: web::Path<i64>) -> HttpResponse {
+    let file_id = id.into_inner();
+
+    let query = FileQuery {
+        ids: Some(vec![file_id]),
+        include_deleted: true,
+        ..Default::default()
+    };
+
+    let file = match persistence.query_files(query).await {
+        Ok(files) => files.into_iter().next(),
+        Err(e) => {
+            error!("Error querying file {file_id}: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let file = match file {
+        Some(file) => file,
+        None => {
+            return HttpResponse::NotFound()
+                .content_type(ContentType::plaintext())
+                .body(format!("No file with id {file_id}"))
+        }
+    };
+
+    match persistence.dispatch_decisions(file_id).await {
+        Ok(decisions) => HttpResponse::Ok().json(serde_json::json!({
+            "file": file,
+            "decisions": decisions,
+        })),
+        Err(e) => {
+            error!("Error querying dispatch decisions of file {file_id}: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    since: Option<DateTime<Utc>>,
+}
+
+/// Stored files and failed downloads per source since the given time, the
+/// last day by default
+async fn stats(
+    persistence: web::Data<SqliteAsyncPersistence>,
+    query: web::Query<StatsQuery>,
+) -> HttpResponse {
+    let since = query
+        .since
+        .unwrap_or_else(|| Utc::now() - chrono::Duration::days(1));
+
+    let files = match persistence.file_stats_since(since).await {
+        Ok(files) => files,
+        Err(e) => {
+            error!("Error querying file statistics: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match persistence.failure_stats_since(since).await {
+        Ok(failures) => HttpResponse::Ok().json(serde_json::json!({
+            "since": since,
+            "files": files,
+            "failures": failures,
+        })),
+        Err(e) => {
+            error!("Error querying failure statistics: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::directory_source::LocalFileEvent;
+
+/// Number of records written since the last compaction at which the journal
+/// is compacted, when most of them are completed
+const COMPACTION_RECORDS: usize = 1000;
+
+/// Line in the journal
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Pending {
+        id: u64,
+        source_name: String,
+        path: PathBuf,
+        prefix: PathBuf,
+    },
+    Done {
+        id: u64,
+    },
+}
+
+/// Write-ahead journal of the local file events that are detected by inotify
+/// but not yet ingested, so that they are replayed after a crash instead of
+/// waiting for the next sweep
+///
+/// Pending events are synced to disk before they are sent to the intake
+/// thread. Completions are not, because a lost completion only leads to an
+/// event being ingested twice, which deduplication and the upsert of the file
+/// handle.
+#[derive(Debug, Clone)]
+pub struct IntakeJournal {
+    inner: Arc<Mutex<JournalFile>>,
+}
+
+#[derive(Debug)]
+struct JournalFile {
+    path: PathBuf,
+    file: File,
+    next_id: u64,
+    pending: BTreeMap<u64, LocalFileEvent>,
+    records: usize,
+}
+
+/// Path of the journal, next to the database
+pub fn journal_path(sqlite_path: &Path) -> PathBuf {
+    let mut path = OsString::from(sqlite_path);
+    path.push(".intake-journal");
+
+    PathBuf::from(path)
+}
+
+impl IntakeJournal {
+    /// Open the journal, keeping the unfinished events of a previous run
+    pub fn open(path: &Path) -> Result<IntakeJournal, String> {
+        let mut pending = BTreeMap::new();
+        let mut next_id = 1;
+
+        if path.exists() {
+            let file = File::open(path)
+                .map_err(|e| format!("Error opening intake journal '{}': {e}", path.display()))?;
+
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(|e| {
+                    format!("Error reading intake journal '{}': {e}", path.display())
+                })?;
+
+                // The last line is incomplete when the dispatcher crashed while
+                // writing it
+                let record = match serde_json::from_str(&line) {
+                    Ok(record) => record,
+                    Err(e) => {
+                        warn!("Skipping invalid intake journal record '{line}': {e}");
+                        continue;
+                    }
+                };
+
+                match record {
+                    Record::Pending {
+                        id,
+                        source_name,
+                        path,
+                        prefix,
+                    } => {
+                        next_id = next_id.max(id + 1);
+                        pending.insert(
+                            id,
+                            LocalFileEvent {
+                                source_name,
+                                path,
+                                prefix,
+                                journal_id: Some(id),
+                            },
+                        );
+                    }
+                    Record::Done { id } => {
+                        pending.remove(&id);
+                    }
+                }
+            }
+        }
+
+        let mut journal_file = JournalFile {
+            path: path.to_path_buf(),
+            file: open_append(path)?,
+            next_id,
+            pending,
+            records: 0,
+        };
+
+        journal_file.compact()?;
+
+        Ok(IntakeJournal {
+            inner: Arc::new(Mutex::new(journal_file)),
+        })
+    }
+
+    /// Record an event before it is sent to the intake thread, returning the
+    /// event with its journal id
+    pub fn record(&self, file_event: LocalFileEvent) -> Result<LocalFileEvent, String> {
+        let mut journal_file = self.inner.lock().unwrap();
+
+        let id = journal_file.next_id;
+
+        journal_file.append(
+            &Record::Pending {
+                id,
+                source_name: file_event.source_name.clone(),
+                path: file_event.path.clone(),
+                prefix: file_event.prefix.clone(),
+            },
+            true,
+        )?;
+
+        journal_file.next_id += 1;
+
+        let file_event = LocalFileEvent {
+            journal_id: Some(id),
+            ..file_event
+        };
+
+        journal_file.pending.insert(id, file_event.clone());
+
+        Ok(file_event)
+    }
+
+    /// Mark an event as handled by the intake thread
+    pub fn complete(&self, id: u64) -> Result<(), String> {
+        let mut journal_file = self.inner.lock().unwrap();
+
+        if journal_file.pending.remove(&id).is_none() {
+            return Ok(());
+        }
+
+        journal_file.append(&Record::Done { id }, false)?;
+
+        if journal_file.records >= COMPACTION_RECORDS
+            && journal_file.records > 2 * journal_file.pending.len()
+        {
+            journal_file.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Send the unfinished events of a previous run to the intake thread,
+    /// completing the events of which the file is gone
+    pub fn replay(&self, sender: &Sender<LocalFileEvent>) -> Result<usize, String> {
+        let pending: Vec<LocalFileEvent> = self
+            .inner
+            .lock()
+            .unwrap()
+            .pending
+            .values()
+            .cloned()
+            .collect();
+
+        let mut replayed = 0;
+
+        for file_event in pending {
+            let id = file_event.journal_id.unwrap_or_default();
+
+            if !file_event.path.exists() {
+                debug!(
+                    "Not replaying intake of '{}', which no longer exists",
+                    file_event.path.to_string_lossy()
+                );
+
+                self.complete(id)?;
+                continue;
+            }
+
+            sender
+                .send(file_event)
+                .map_err(|e| format!("Error sending replayed file event: {e}"))?;
+
+            replayed += 1;
+        }
+
+        if replayed > 0 {
+            info!("Replaying the intake of {replayed} files from the intake journal");
+        }
+
+        Ok(replayed)
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Error opening intake journal '{}': {e}", path.display()))
+}
+
+impl JournalFile {
+    fn append(&mut self, record: &Record, sync: bool) -> Result<(), String> {
+        let mut line = serde_json::to_vec(record)
+            .map_err(|e| format!("Error serializing intake journal record: {e}"))?;
+        line.push(b'\n');
+
+        self.file
+            .write_all(&line)
+            .and_then(|_| match sync {
+                true => self.file.sync_data(),
+                false => Ok(()),
+            })
+            .map_err(|e| {
+                format!(
+                    "Error writing intake journal '{}': {e}",
+                    self.path.display()
+                )
+            })?;
+
+        self.records += 1;
+
+        Ok(())
+    }
+
+    /// Rewrite the journal with only the pending events
+    fn compact(&mut self) -> Result<(), String> {
+        let mut compacted_path = OsString::from(&self.path);
+        compacted_path.push(".tmp");
+        let compacted_path = PathBuf::from(compacted_path);
+
+        let mut content = Vec::new();
+
+        for (id, file_event) in &self.pending {
+            let record = Record::Pending {
+                id: *id,
+                source_name: file_event.source_name.clone(),
+                path: file_event.path.clone(),
+                prefix: file_event.prefix.clone(),
+            };
+
+            serde_json::to_writer(&mut content, &record)
+                .map_err(|e| format!("Error serializing intake journal record: {e}"))?;
+            content.push(b'\n');
+        }
+
+        File::create(&compacted_path)
+            .and_then(|mut file| {
+                file.write_all(&content)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&compacted_path, &self.path))
+            .map_err(|e| {
+                format!(
+                    "Error compacting intake journal '{}': {e}",
+                    self.path.display()
+                )
+            })?;
+
+        self.file = open_append(&self.path)?;
+        self.records = self.pending.len();
+
+        debug!(
+            "Compacted intake journal to {} pending events",
+            self.pending.len()
+        );
+
+        Ok(())
+    }
+}
@@ -1,33 +1,238 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::error;
+use std::ffi::OsString;
 use std::fmt;
-use std::fs::{hard_link, remove_file};
-use std::path::{Path, PathBuf};
+use std::fs::{hard_link, remove_file, rename, set_permissions, File, Permissions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::os::unix::fs::{chown, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use chrono::{DateTime, Utc};
-use log::{debug, info};
+use log::{debug, info, warn};
+use nix::unistd::Group;
+
+use cortex_core::error::DispatcherError;
+use cortex_core::error_code::ErrorCode;
 
 use crate::base_types::FileInfo;
 use crate::persistence::{Persistence, PersistenceError};
+use crate::readiness::Readiness;
+use crate::settings::{
+    parse_file_permissions, ConflictPolicy, Fsync, Settings, StorageLayout, OBJECTS_DIRECTORY,
+};
+use crate::storage_usage::StorageUsage;
 
 #[derive(Debug, Clone)]
 pub struct LocalStorage<T>
 where
     T: Persistence,
 {
-    directory: PathBuf,
+    /// Storage areas, of which the first is used by sources without one
+    areas: Arc<Vec<StorageArea>>,
     persistence: T,
+    dry_run: bool,
+    placements: Arc<HashMap<String, SourcePlacement>>,
+    flattened: FlattenedPaths,
 }
 
+/// Directory of the local storage with its own layout and size accounting
 #[derive(Debug, Clone)]
-pub struct LocalStorageError {
-    message: String,
+pub struct StorageArea {
+    pub name: String,
+    pub directory: PathBuf,
+    pub layout: StorageLayout,
+    pub usage: StorageUsage,
+    pub fsync: Fsync,
+}
+
+impl StorageArea {
+    pub fn new<P: AsRef<Path>>(
+        name: &str,
+        directory: P,
+        layout: StorageLayout,
+        usage: StorageUsage,
+    ) -> StorageArea {
+        StorageArea {
+            name: name.to_string(),
+            directory: directory.as_ref().to_path_buf(),
+            layout,
+            usage,
+            fsync: Fsync::None,
+        }
+    }
+
+    /// Area that syncs downloaded files to disk before storing them under
+    /// their regular name
+    pub fn with_fsync(self, fsync: Fsync) -> StorageArea {
+        StorageArea { fsync, ..self }
+    }
+
+    /// Path of the object with the specified hash in the content-addressed
+    /// layout
+    pub fn object_path(&self, hash: &str) -> PathBuf {
+        object_path(&self.directory, hash)
+    }
+}
+
+/// Storage areas of the settings, which report a full area to the readiness
+pub fn storage_areas(settings: &Settings, readiness: &Readiness) -> Vec<StorageArea> {
+    settings
+        .storage
+        .iter()
+        .map(|area| {
+            StorageArea::new(
+                &area.name,
+                &area.directory,
+                area.layout,
+                StorageUsage::new(area, readiness.clone()),
+            )
+            .with_fsync(area.fsync)
+        })
+        .collect()
+}
+
+/// Where the files of a source are placed in local storage, and with which
+/// permissions and group
+#[derive(Debug, Clone, Default)]
+pub struct SourcePlacement {
+    /// Storage area of the files, the first one when not set
+    pub area: Option<String>,
+    /// Directory instead of the one with the name of the source, relative to
+    /// the storage directory
+    pub subdirectory: Option<PathBuf>,
+    pub flatten: bool,
+    pub permissions: Option<u32>,
+    pub group: Option<String>,
+    pub on_conflict: ConflictPolicy,
+    pub max_versions: u32,
+}
+
+/// Placements of the sources in the settings, with the file permissions and
+/// group of their storage area unless the source overrides them
+pub fn source_placements(settings: &Settings) -> HashMap<String, SourcePlacement> {
+    let placement = |name: &str,
+                     subdirectory: &Option<PathBuf>,
+                     flatten: bool,
+                     permissions: &Option<String>,
+                     group: &Option<String>,
+                     on_conflict: ConflictPolicy,
+                     max_versions: u32| {
+        let area = settings.storage_area(name);
+
+        SourcePlacement {
+            area: Some(area.name.clone()),
+            subdirectory: subdirectory.clone(),
+            flatten,
+            // Invalid permissions are rejected by the validation of the settings
+            permissions: permissions
+                .as_ref()
+                .or(area.file_permissions.as_ref())
+                .and_then(|p| parse_file_permissions(p).ok()),
+            group: group.clone().or(area.group.clone()),
+            on_conflict,
+            max_versions,
+        }
+    };
+
+    let directory_sources = settings.directory_sources.iter().map(|source| {
+        (
+            source.name.clone(),
+            placement(
+                &source.name,
+                &source.storage_subdirectory,
+                source.flatten,
+                &source.file_permissions,
+                &source.group,
+                source.on_conflict,
+                source.max_versions,
+            ),
+        )
+    });
+
+    let sftp_sources = settings.sftp_sources.iter().map(|source| {
+        (
+            source.name.clone(),
+            placement(
+                &source.name,
+                &source.storage_subdirectory,
+                source.flatten,
+                &source.file_permissions,
+                &source.group,
+                source.on_conflict,
+                source.max_versions,
+            ),
+        )
+    });
+
+    directory_sources.chain(sftp_sources).collect()
+}
+
+/// The paths from which flattened paths were made, for detecting different
+/// paths that are flattened to the same path
+///
+/// Only hashes of the paths are kept, to limit the memory used for sources
+/// with many files.
+#[derive(Debug, Clone, Default)]
+struct FlattenedPaths(Arc<Mutex<HashMap<u64, u64>>>);
+
+impl FlattenedPaths {
+    /// Register the path from which a flattened path was made, returning
+    /// whether it was made from another path before
+    fn register(&self, flattened: &Path, relative_file_path: &Path) -> bool {
+        let origin = path_hash(relative_file_path);
+
+        let mut origins = self.0.lock().unwrap_or_else(|e| e.into_inner());
+
+        origins
+            .insert(path_hash(flattened), origin)
+            .is_some_and(|previous| previous != origin)
+    }
+}
+
+fn path_hash(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A file that was stored in local storage
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    pub file_id: i64,
+    pub path: PathBuf,
+    /// Path relative to the directory of the source in local storage
+    pub relative_path: Option<PathBuf>,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub enum LocalStorageError {
+    /// A path that would be placed outside the directory of its source
+    UnsafePath(String),
+    Other(String),
 }
 
 impl fmt::Display for LocalStorageError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", &self.message)
+        match self {
+            LocalStorageError::UnsafePath(message) => write!(f, "Unsafe path: {message}"),
+            LocalStorageError::Other(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl LocalStorageError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            LocalStorageError::UnsafePath(_) => ErrorCode::UnsafePath,
+            LocalStorageError::Other(_) => ErrorCode::Storage,
+        }
     }
 }
 
@@ -39,17 +244,13 @@ impl error::Error for LocalStorageError {
 
 impl From<PersistenceError> for LocalStorageError {
     fn from(e: PersistenceError) -> Self {
-        LocalStorageError {
-            message: format!("{}", e),
-        }
+        LocalStorageError::Other(format!("{}", e))
     }
 }
 
 impl From<std::io::Error> for LocalStorageError {
     fn from(e: std::io::Error) -> Self {
-        LocalStorageError {
-            message: format!("{}", e),
-        }
+        LocalStorageError::Other(format!("{}", e))
     }
 }
 
@@ -57,35 +258,131 @@ impl<T> LocalStorage<T>
 where
     T: Persistence,
 {
-    pub fn new<P: AsRef<Path>>(directory: P, persistence: T) -> LocalStorage<T> {
+    /// Storage in the areas, of which there must be at least one
+    pub fn new(areas: Vec<StorageArea>, persistence: T) -> LocalStorage<T> {
+        assert!(!areas.is_empty(), "Local storage needs at least one area");
+
         LocalStorage {
-            directory: directory.as_ref().to_path_buf(),
+            areas: Arc::new(areas),
             persistence,
+            dry_run: false,
+            placements: Arc::new(HashMap::new()),
+            flattened: FlattenedPaths::default(),
+        }
+    }
+
+    /// Storage that places the files of sources as specified instead of in
+    /// a directory with the name of the source
+    pub fn with_placements(self, placements: HashMap<String, SourcePlacement>) -> LocalStorage<T> {
+        LocalStorage {
+            placements: Arc::new(placements),
+            ..self
+        }
+    }
+
+    /// Storage for a dry run, which registers ingested files where they are
+    /// instead of linking them, and discards downloaded files
+    pub fn into_dry_run(self) -> LocalStorage<T> {
+        LocalStorage {
+            dry_run: true,
+            ..self
         }
     }
 
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Storage area in which the files of a source are placed
+    ///
+    /// A source that selects an unknown area, e.g. after it was removed from
+    /// the settings, uses the first one.
+    pub fn area(&self, source_name: &str) -> &StorageArea {
+        self.placements
+            .get(source_name)
+            .and_then(|p| p.area.as_deref())
+            .and_then(|name| self.areas.iter().find(|area| area.name == name))
+            .unwrap_or(&self.areas[0])
+    }
+
+    /// Fail when the storage area of the source is full, before a file is
+    /// stored
+    pub fn check_space(&self, source_name: &str) -> Result<(), DispatcherError> {
+        self.area(source_name).usage.check_space()
+    }
+
+    /// Account for a file that was stored without `ingest`
+    pub fn add_usage(&self, source_name: &str, size: u64) {
+        self.area(source_name).usage.add(source_name, size)
+    }
+
+    /// Path in local storage of a file of a source, relative to the prefix
+    /// when the file is in it
+    ///
+    /// Paths with `..` are rejected, so that no file is placed outside the
+    /// directory of the source.
     pub fn local_path<P: AsRef<Path>>(
         &self,
         source_name: &str,
         file_path: P,
         prefix: P,
     ) -> Result<PathBuf, LocalStorageError> {
-        if file_path.as_ref().starts_with(&prefix) {
-            let strip_result = file_path.as_ref().strip_prefix(&prefix);
+        let file_path = file_path.as_ref();
 
-            let relative_file_path = match strip_result {
-                Ok(path) => path,
-                Err(e) => {
-                    return Err(LocalStorageError {
-                        message: format!("Error stripping file path: {}", e),
-                    })
-                }
-            };
+        let relative_file_path = normalize(
+            file_path.strip_prefix(prefix.as_ref()).unwrap_or(file_path),
+        )
+        .ok_or_else(|| {
+            LocalStorageError::UnsafePath(format!(
+                "'{}' contains '..'",
+                file_path.to_string_lossy()
+            ))
+        })?;
 
-            Ok(self.directory.join(source_name).join(relative_file_path))
-        } else {
-            Ok(self.directory.join(source_name).join(file_path))
+        let placement = self.placements.get(source_name);
+        let source_directory = self.source_directory(source_name);
+
+        if !placement.is_some_and(|p| p.flatten) {
+            let local_path = source_directory.join(relative_file_path);
+
+            check_no_symlinks(&source_directory, &local_path)?;
+
+            return Ok(local_path);
         }
+
+        let local_path = source_directory.join(flatten(&relative_file_path));
+
+        if self.flattened.register(&local_path, &relative_file_path) {
+            warn!(
+                "Files of source '{}' collide at '{}', '{}' replaces another file",
+                source_name,
+                local_path.to_string_lossy(),
+                file_path.to_string_lossy()
+            );
+        }
+
+        Ok(local_path)
+    }
+
+    /// Directory in local storage with the files of a source
+    fn source_directory(&self, source_name: &str) -> PathBuf {
+        match self
+            .placements
+            .get(source_name)
+            .and_then(|p| p.subdirectory.as_ref())
+        {
+            Some(subdirectory) => self.area(source_name).directory.join(subdirectory),
+            None => self.area(source_name).directory.join(source_name),
+        }
+    }
+
+    /// Path of a file in local storage relative to the directory of its
+    /// source, `None` when it is not in that directory
+    pub fn relative_path(&self, source_name: &str, local_path: &Path) -> Option<PathBuf> {
+        local_path
+            .strip_prefix(self.source_directory(source_name))
+            .ok()
+            .map(Path::to_path_buf)
     }
 
     /// Return information of the specified file if it has been previously
@@ -105,8 +402,8 @@ where
 
         self.persistence
             .get_file(source_name, &local_path_str)
-            .map_err(|e| LocalStorageError {
-                message: format!("Error retrieving file information: {}", e),
+            .map_err(|e| {
+                LocalStorageError::Other(format!("Error retrieving file information: {}", e))
             })
     }
 
@@ -114,6 +411,9 @@ where
     /// specified file_path and will be stored in a directory with the name of
     /// the source. The prefix will be stripped from the file path.
     /// Finally, the source will be removed.
+    ///
+    /// Returns None when the file is skipped, because a stored file with the
+    /// same path has different content and the source skips conflicts.
     pub fn ingest<P>(
         &self,
         source_name: &str,
@@ -121,7 +421,7 @@ where
         prefix: P,
         hash: Option<String>,
         delete: bool,
-    ) -> Result<(i64, PathBuf), LocalStorageError>
+    ) -> Result<Option<StoredFile>, LocalStorageError>
     where
         P: AsRef<Path>,
     {
@@ -131,7 +431,12 @@ where
 
         let local_path_str = local_path.to_string_lossy();
 
-        if let Some(local_path_parent) = local_path.parent() {
+        if self.dry_run {
+            info!(
+                "Would store '{}' as '{}'",
+                &source_path_str, &local_path_str
+            );
+        } else if let Some(local_path_parent) = local_path.parent() {
             if !local_path_parent.exists() {
                 let local_path_parent_str = local_path_parent.to_string_lossy();
                 let create_dir_result = std::fs::create_dir_all(local_path_parent);
@@ -139,35 +444,77 @@ where
                 match create_dir_result {
                     Ok(_) => info!("Created containing directory '{}'", local_path_parent_str),
                     Err(e) => {
-                        return Err(LocalStorageError {
-                            message: format!(
-                                "Error creating containing directory '{}': {}",
-                                local_path_parent_str, e
-                            ),
-                        })
+                        return Err(LocalStorageError::Other(format!(
+                            "Error creating containing directory '{}': {}",
+                            local_path_parent_str, e
+                        )))
                     }
                 }
-            } else if local_path.is_file() {
-                // Remove existing file before creating new hardlink
-                std::fs::remove_file(&local_path)?;
             }
         };
 
-        hard_link(&file_path, &local_path).map_err(|e| LocalStorageError {
-            message: format!(
-                "[E?????] Error hardlinking '{}' to '{}': {}",
-                &source_path_str, &local_path_str, &e
-            ),
-        })?;
+        if !self.resolve_conflict(source_name, file_path.as_ref(), &local_path)? {
+            if delete && !self.dry_run {
+                remove_file(&file_path)?;
+            }
+
+            return Ok(None);
+        }
+
+        let area = self.area(source_name);
+
+        if !self.dry_run && area.layout == StorageLayout::PerSource && local_path.is_file() {
+            // Remove existing file before creating new hardlink
+            std::fs::remove_file(&local_path)?;
+        }
+
+        let stored = match area.layout {
+            _ if self.dry_run => false,
+            StorageLayout::PerSource => {
+                hard_link(&file_path, &local_path).map_err(|e| {
+                    LocalStorageError::Other(format!(
+                        "Error hardlinking '{}' to '{}': {}",
+                        &source_path_str, &local_path_str, &e
+                    ))
+                })?;
+
+                true
+            }
+            StorageLayout::ContentAddressed => {
+                let hash = hash.as_deref().ok_or_else(|| {
+                    LocalStorageError::Other(format!(
+                        "No hash of '{}' for content-addressed storage",
+                        &source_path_str
+                    ))
+                })?;
+
+                self.link_object(area, file_path.as_ref(), &local_path, hash)?
+            }
+        };
+
+        // The hardlink shares its inode with the ingested file, which
+        // therefore gets the same permissions and group
+        self.set_file_mode(source_name, &local_path)?;
+
+        let stored_path = if self.dry_run {
+            file_path.as_ref().to_path_buf()
+        } else {
+            local_path.clone()
+        };
+
+        let metadata = std::fs::metadata(&stored_path)?;
 
-        let metadata = std::fs::metadata(&local_path)?;
+        if stored {
+            area.usage.add(source_name, metadata.len());
+        }
         let modified = system_time_to_date_time(metadata.modified()?);
         let size = match i64::try_from(metadata.len()) {
             Ok(s) => s,
             Err(e) => {
-                return Err(LocalStorageError {
-                    message: format!("Error converting file size to i64: {}", e),
-                })
+                return Err(LocalStorageError::Other(format!(
+                    "Error converting file size to i64: {}",
+                    e
+                )))
             }
         };
 
@@ -177,16 +524,392 @@ where
 
         debug!("Stored '{}' to '{}'", &source_path_str, &local_path_str);
 
-        if delete {
+        if delete && !self.dry_run {
             remove_file(&file_path)?;
 
             debug!("Removed '{}'", &source_path_str);
         }
 
-        Ok((file_id, local_path))
+        Ok(Some(StoredFile {
+            file_id,
+            path: stored_path,
+            relative_path: self.relative_path(source_name, &local_path),
+            size: metadata.len(),
+            modified,
+        }))
+    }
+
+    /// Apply the conflict policy of the source when the file is to be stored
+    /// at the path of a stored file with different content. Returns false
+    /// when the file is to be skipped.
+    pub fn resolve_conflict(
+        &self,
+        source_name: &str,
+        file_path: &Path,
+        local_path: &Path,
+    ) -> Result<bool, LocalStorageError> {
+        if self.dry_run || !local_path.is_file() {
+            return Ok(true);
+        }
+
+        let Some(placement) = self.placements.get(source_name) else {
+            return Ok(true);
+        };
+
+        if placement.on_conflict == ConflictPolicy::Overwrite
+            || same_content(file_path, local_path)?
+        {
+            return Ok(true);
+        }
+
+        match placement.on_conflict {
+            ConflictPolicy::Overwrite => Ok(true),
+            ConflictPolicy::Version => {
+                self.keep_version(source_name, local_path, placement.max_versions)?;
+
+                Ok(true)
+            }
+            ConflictPolicy::Skip => {
+                warn!(
+                    "Skipping '{}' of source '{}', which differs from the stored file",
+                    local_path.to_string_lossy(),
+                    source_name
+                );
+
+                Ok(false)
+            }
+        }
+    }
+
+    /// Move a stored file to the first free path of `name.1` up to
+    /// `name.<max_versions>`, registering it under that path
+    fn keep_version(
+        &self,
+        source_name: &str,
+        local_path: &Path,
+        max_versions: u32,
+    ) -> Result<(), LocalStorageError> {
+        let version_path = (1..=max_versions)
+            .map(|version| {
+                let mut version_path = local_path.as_os_str().to_os_string();
+                version_path.push(format!(".{version}"));
+                PathBuf::from(version_path)
+            })
+            .find(|version_path| !version_path.exists())
+            .ok_or_else(|| {
+                LocalStorageError::Other(format!(
+                    "All {} versions of '{}' are in use",
+                    max_versions,
+                    local_path.to_string_lossy()
+                ))
+            })?;
+
+        rename(local_path, &version_path)?;
+
+        let local_path_str = local_path.to_string_lossy();
+        let version_path_str = version_path.to_string_lossy();
+
+        let registered =
+            self.persistence
+                .rename_file(source_name, &local_path_str, &version_path_str)?;
+
+        // Files placed in storage by other means get a record of their own
+        if !registered {
+            let metadata = std::fs::metadata(&version_path)?;
+
+            self.persistence.insert_file(
+                source_name,
+                &version_path_str,
+                &system_time_to_date_time(metadata.modified()?),
+                i64::try_from(metadata.len()).unwrap_or(i64::MAX),
+                None,
+            )?;
+        }
+
+        info!(
+            "Kept previous version of '{}' as '{}'",
+            local_path_str, version_path_str
+        );
+
+        Ok(())
+    }
+
+    /// Set the permissions and group of a stored file of the source, when
+    /// configured
+    ///
+    /// Failing to change the group is only logged, because the file is
+    /// stored regardless.
+    pub fn set_file_mode(&self, source_name: &str, path: &Path) -> Result<(), LocalStorageError> {
+        let Some(placement) = self.placements.get(source_name) else {
+            return Ok(());
+        };
+
+        if self.dry_run {
+            return Ok(());
+        }
+
+        if let Some(permissions) = placement.permissions {
+            set_permissions(path, Permissions::from_mode(permissions)).map_err(|e| {
+                LocalStorageError::Other(format!(
+                    "Error setting permissions {:o} of '{}': {}",
+                    permissions,
+                    path.to_string_lossy(),
+                    e
+                ))
+            })?;
+        }
+
+        if let Some(group) = &placement.group {
+            if let Err(e) = group_id(group)
+                .and_then(|gid| chown(path, None, Some(gid)).map_err(|e| e.to_string()))
+            {
+                warn!(
+                    "Could not set group of '{}' to '{}': {}",
+                    path.to_string_lossy(),
+                    group,
+                    e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Store a downloaded part file under its regular name, returning whether
+    /// its content was added to the storage. In the content-addressed layout,
+    /// the content is not added when an object with the same hash exists.
+    pub fn store_part(
+        &self,
+        source_name: &str,
+        part_path: &Path,
+        local_path: &Path,
+        hash: &str,
+    ) -> Result<bool, LocalStorageError> {
+        let area = self.area(source_name);
+
+        if area.fsync != Fsync::None && !self.dry_run {
+            File::open(part_path)?.sync_all()?;
+        }
+
+        let stored = self.place_part(area, part_path, local_path, hash)?;
+
+        if area.fsync == Fsync::FileAndDir && !self.dry_run {
+            sync_parent_directory(local_path)?;
+
+            if area.layout == StorageLayout::ContentAddressed {
+                sync_parent_directory(&area.object_path(hash))?;
+            }
+        }
+
+        Ok(stored)
+    }
+
+    fn place_part(
+        &self,
+        area: &StorageArea,
+        part_path: &Path,
+        local_path: &Path,
+        hash: &str,
+    ) -> Result<bool, LocalStorageError> {
+        match area.layout {
+            _ if self.dry_run => {
+                remove_file(part_path)?;
+
+                info!("Would store '{}'", local_path.to_string_lossy());
+
+                Ok(false)
+            }
+            StorageLayout::PerSource => {
+                rename(part_path, local_path)?;
+
+                Ok(true)
+            }
+            StorageLayout::ContentAddressed => {
+                let stored = self.link_object(area, part_path, local_path, hash)?;
+
+                remove_file(part_path)?;
+
+                Ok(stored)
+            }
+        }
+    }
+
+    /// Link the local path to the object with the hash, storing the file as
+    /// that object when it does not exist yet. Returns whether the object was
+    /// created.
+    fn link_object(
+        &self,
+        area: &StorageArea,
+        file_path: &Path,
+        local_path: &Path,
+        hash: &str,
+    ) -> Result<bool, LocalStorageError> {
+        let object_path = area.object_path(hash);
+
+        let created = if object_path.exists() {
+            false
+        } else {
+            if let Some(object_parent) = object_path.parent() {
+                std::fs::create_dir_all(object_parent)?;
+            }
+
+            match hard_link(file_path, &object_path) {
+                Ok(()) => true,
+                // Another source stored the same content in the meantime
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => false,
+                Err(e) => {
+                    return Err(LocalStorageError::Other(format!(
+                        "Error storing object '{}': {}",
+                        object_path.to_string_lossy(),
+                        e
+                    )))
+                }
+            }
+        };
+
+        // Link under a temporary name first, so that an existing file at the
+        // local path is replaced atomically
+        let mut link_path = local_path.as_os_str().to_os_string();
+        link_path.push(".link");
+
+        hard_link(&object_path, &link_path).map_err(|e| {
+            LocalStorageError::Other(format!(
+                "Error hardlinking object '{}' to '{}': {}",
+                object_path.to_string_lossy(),
+                local_path.to_string_lossy(),
+                e
+            ))
+        })?;
+
+        rename(&link_path, local_path)?;
+
+        Ok(created)
     }
 }
 
+/// Whether the files have the same content
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    if std::fs::metadata(a)?.len() != std::fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+
+    let mut a = File::open(a)?;
+    let mut b = File::open(b)?;
+
+    let mut a_buf = vec![0u8; 64 * 1024];
+    let mut b_buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = a.read(&mut a_buf)?;
+
+        if read == 0 {
+            return Ok(true);
+        }
+
+        b.read_exact(&mut b_buf[..read])?;
+
+        if a_buf[..read] != b_buf[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Id of a group by its name or id
+fn group_id(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+
+    match Group::from_name(group) {
+        Ok(Some(group)) => Ok(group.gid.as_raw()),
+        Ok(None) => Err("No such group".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Relative path without root and `.` components, or None when it has `..`
+/// components
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => return None,
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+        }
+    }
+
+    Some(normalized)
+}
+
+/// Sync the directory containing the path to disk, so that an entry that was
+/// added or renamed in it survives a power loss
+pub fn sync_parent_directory(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(directory) => File::open(directory)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Fail when a directory between the source directory and the path is a
+/// symbolic link, through which the file would be placed outside of it
+///
+/// The source directory itself may be a symbolic link.
+pub fn check_no_symlinks(
+    source_directory: &Path,
+    local_path: &Path,
+) -> Result<(), LocalStorageError> {
+    let relative_directory = local_path
+        .strip_prefix(source_directory)
+        .ok()
+        .and_then(Path::parent)
+        .unwrap_or(Path::new(""));
+
+    let mut directory = source_directory.to_path_buf();
+
+    for name in relative_directory {
+        directory.push(name);
+
+        match std::fs::symlink_metadata(&directory) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(LocalStorageError::UnsafePath(format!(
+                    "'{}' is a symbolic link",
+                    directory.to_string_lossy()
+                )))
+            }
+            Ok(_) => (),
+            // Directories that do not exist yet are created when storing
+            Err(_) => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// File name of a relative path with its directories joined by underscores
+fn flatten(path: &Path) -> OsString {
+    let mut flattened = OsString::new();
+
+    for (index, name) in path.iter().enumerate() {
+        if index > 0 {
+            flattened.push("_");
+        }
+
+        flattened.push(name);
+    }
+
+    flattened
+}
+
+/// Path of the object with the specified hash in the content-addressed layout
+/// of the storage directory
+pub fn object_path(directory: &Path, hash: &str) -> PathBuf {
+    let (prefix, rest) = hash.split_at(hash.len().min(2));
+
+    directory.join(OBJECTS_DIRECTORY).join(prefix).join(rest)
+}
+
 fn system_time_to_date_time(t: SystemTime) -> DateTime<Utc> {
     let (sec, nsec) = match t.duration_since(UNIX_EPOCH) {
         Ok(dur) => (dur.as_secs() as i64, dur.subsec_nanos()),
@@ -203,3 +926,460 @@ fn system_time_to_date_time(t: SystemTime) -> DateTime<Utc> {
 
     DateTime::from_timestamp(sec, nsec).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use crate::persistence::{DryRunPersistence, SqlitePersistence};
+    use crate::readiness::Readiness;
+    use crate::settings::{Settings, DEFAULT_STORAGE_AREA};
+
+    #[test]
+    fn sources_delivering_same_content_share_object() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-local-storage-{}", std::process::id()));
+        let incoming = directory.join("incoming");
+        let storage_directory = directory.join("storage");
+        std::fs::create_dir_all(incoming.join("red")).unwrap();
+        std::fs::create_dir_all(incoming.join("green")).unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+
+        let local_storage = LocalStorage::new(
+            vec![StorageArea::new(
+                DEFAULT_STORAGE_AREA,
+                &storage_directory,
+                StorageLayout::ContentAddressed,
+                StorageUsage::new(&Settings::default().storage[0], Readiness::default()),
+            )],
+            SqlitePersistence::from_arc(Arc::new(Mutex::new(conn))),
+        );
+
+        let hash = "ab12cd34".to_string();
+
+        let handles: Vec<_> = ["red", "green"]
+            .into_iter()
+            .map(|source_name| {
+                let local_storage = local_storage.clone();
+                let file_path = incoming.join(source_name).join("data.csv");
+                let prefix = incoming.join(source_name);
+                let hash = hash.clone();
+
+                std::fs::write(&file_path, "same content").unwrap();
+
+                thread::spawn(move || {
+                    local_storage
+                        .ingest(source_name, &file_path, &prefix, Some(hash), true)
+                        .unwrap()
+                        .unwrap()
+                        .path
+                })
+            })
+            .collect();
+
+        let local_paths: Vec<PathBuf> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        let object = std::fs::metadata(local_storage.area("red").object_path(&hash)).unwrap();
+        let object_count = std::fs::read_dir(storage_directory.join(OBJECTS_DIRECTORY))
+            .unwrap()
+            .count();
+        let inodes: Vec<u64> = local_paths
+            .iter()
+            .map(|path| std::fs::metadata(path).unwrap().ino())
+            .collect();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(object_count, 1);
+        assert_eq!(object.nlink(), 3);
+        assert_eq!(inodes, vec![object.ino(), object.ino()]);
+    }
+
+    #[test]
+    fn ingested_files_get_permissions_of_source() {
+        let directory = std::env::temp_dir().join(format!(
+            "cortex-local-storage-permissions-{}",
+            std::process::id()
+        ));
+        let incoming = directory.join("incoming");
+        std::fs::create_dir_all(&incoming).unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+
+        let local_storage = LocalStorage::new(
+            vec![StorageArea::new(
+                DEFAULT_STORAGE_AREA,
+                directory.join("storage"),
+                StorageLayout::PerSource,
+                StorageUsage::new(&Settings::default().storage[0], Readiness::default()),
+            )],
+            SqlitePersistence::from_arc(Arc::new(Mutex::new(conn))),
+        )
+        .with_placements(HashMap::from([(
+            "red".to_string(),
+            SourcePlacement {
+                permissions: Some(0o640),
+                group: Some("no-such-cortex-group".to_string()),
+                ..Default::default()
+            },
+        )]));
+
+        let file_path = incoming.join("data.csv");
+        std::fs::write(&file_path, "content").unwrap();
+        std::fs::set_permissions(&file_path, Permissions::from_mode(0o600)).unwrap();
+
+        // Failing to change the group does not fail the ingest
+        let stored = local_storage
+            .ingest("red", &file_path, &incoming, None, false)
+            .unwrap()
+            .unwrap();
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().mode() & 0o7777;
+        let stored_mode = mode(&stored.path);
+        // The hardlink shares the inode of the ingested file
+        let ingested_mode = mode(&file_path);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(stored_mode, 0o640);
+        assert_eq!(ingested_mode, 0o640);
+    }
+
+    #[test]
+    fn dry_run_registers_files_where_they_are() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-dry-run-storage-{}", std::process::id()));
+        let incoming = directory.join("incoming");
+        let storage_directory = directory.join("storage");
+        std::fs::create_dir_all(&incoming).unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+
+        let local_storage = LocalStorage::new(
+            vec![StorageArea::new(
+                DEFAULT_STORAGE_AREA,
+                &storage_directory,
+                StorageLayout::PerSource,
+                StorageUsage::new(&Settings::default().storage[0], Readiness::default()),
+            )],
+            DryRunPersistence::new(SqlitePersistence::from_arc(conn.clone())),
+        )
+        .into_dry_run();
+
+        let file_path = incoming.join("data.csv");
+        std::fs::write(&file_path, "content").unwrap();
+
+        let stored_file = local_storage
+            .ingest("red", &file_path, &incoming, None, true)
+            .unwrap()
+            .unwrap();
+
+        let file_info = local_storage
+            .get_file_info("red", &file_path, &incoming)
+            .unwrap();
+        let registered: i64 = conn
+            .lock()
+            .unwrap()
+            .query_row("select count(*) from file", [], |row| row.get(0))
+            .unwrap();
+
+        let storage_exists = storage_directory.exists();
+        let file_exists = file_path.exists();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(stored_file.file_id, -1);
+        assert_eq!(stored_file.path, file_path);
+        assert!(file_exists);
+        assert!(!storage_exists);
+        assert_eq!(file_info.map(|f| f.size), Some(7));
+        assert_eq!(registered, 0);
+    }
+
+    #[test]
+    fn conflicting_files_are_versioned_or_skipped() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-conflict-storage-{}", std::process::id()));
+        let incoming = directory.join("incoming");
+        let storage_directory = directory.join("storage");
+        std::fs::create_dir_all(&incoming).unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+
+        let placement = |on_conflict| SourcePlacement {
+            on_conflict,
+            max_versions: 2,
+            ..Default::default()
+        };
+
+        let local_storage = LocalStorage::new(
+            vec![StorageArea::new(
+                DEFAULT_STORAGE_AREA,
+                &storage_directory,
+                StorageLayout::PerSource,
+                StorageUsage::new(&Settings::default().storage[0], Readiness::default()),
+            )],
+            SqlitePersistence::from_arc(conn.clone()),
+        )
+        .with_placements(HashMap::from([
+            ("red".to_string(), placement(ConflictPolicy::Version)),
+            ("blue".to_string(), placement(ConflictPolicy::Skip)),
+        ]));
+
+        let ingest = |source_name: &str, content: &str| {
+            let file_path = incoming.join("data.csv");
+            std::fs::write(&file_path, content).unwrap();
+
+            local_storage
+                .ingest(source_name, &file_path, &incoming, None, true)
+                .map(|stored| stored.is_some())
+                .map_err(|e| e.to_string())
+        };
+
+        let red = storage_directory.join("red/data.csv");
+        let content = |path: &str| std::fs::read_to_string(storage_directory.join(path)).ok();
+
+        let red_results = ["first", "first", "second", "third", "fourth"].map(|c| ingest("red", c));
+        let blue_results = ["first", "first", "second"].map(|c| ingest("blue", c));
+
+        let red_contents = ["red/data.csv", "red/data.csv.1", "red/data.csv.2"].map(content);
+        let blue_content = content("blue/data.csv");
+        let registered: i64 = conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "select count(*) from file where path like ?1",
+                [format!("{}%", red.to_string_lossy())],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            red_results,
+            [
+                Ok(true),
+                Ok(true),
+                Ok(true),
+                Ok(true),
+                Err(format!(
+                    "All 2 versions of '{}' are in use",
+                    red.to_string_lossy()
+                ))
+            ]
+        );
+        assert_eq!(
+            red_contents,
+            [
+                Some("third".to_string()),
+                Some("first".to_string()),
+                Some("second".to_string())
+            ]
+        );
+        assert_eq!(registered, 3);
+        assert_eq!(blue_results, [Ok(true), Ok(true), Ok(false)]);
+        assert_eq!(blue_content, Some("first".to_string()));
+    }
+
+    fn storage_with_placements(
+        placements: HashMap<String, SourcePlacement>,
+    ) -> LocalStorage<SqlitePersistence> {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+
+        LocalStorage::new(
+            vec![StorageArea::new(
+                DEFAULT_STORAGE_AREA,
+                "/storage",
+                StorageLayout::PerSource,
+                StorageUsage::new(&Settings::default().storage[0], Readiness::default()),
+            )],
+            SqlitePersistence::from_arc(Arc::new(Mutex::new(conn))),
+        )
+        .with_placements(placements)
+    }
+
+    #[test]
+    fn sources_are_stored_in_their_area() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-storage-areas-{}", std::process::id()));
+        let incoming = directory.join("incoming");
+        std::fs::create_dir_all(&incoming).unwrap();
+
+        let mut settings = Settings::default();
+        settings.storage[0].max_bytes = Some(4);
+
+        let area = |name: &str| {
+            StorageArea::new(
+                name,
+                directory.join(name),
+                StorageLayout::PerSource,
+                StorageUsage::new(&settings.storage[0], Readiness::default()),
+            )
+        };
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+
+        let local_storage = LocalStorage::new(
+            vec![area("fast"), area("slow")],
+            SqlitePersistence::from_arc(Arc::new(Mutex::new(conn))),
+        )
+        .with_placements(HashMap::from([(
+            "blue".to_string(),
+            SourcePlacement {
+                area: Some("slow".to_string()),
+                ..Default::default()
+            },
+        )]));
+
+        let ingest = |source_name: &str| {
+            let file_path = incoming.join("data.csv");
+            std::fs::write(&file_path, "content").unwrap();
+
+            local_storage
+                .ingest(source_name, &file_path, &incoming, None, true)
+                .unwrap()
+                .unwrap()
+        };
+
+        let red = ingest("red");
+        let red_space = local_storage.check_space("red");
+        let blue_space = local_storage.check_space("blue");
+        let blue = ingest("blue");
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(red.path, directory.join("fast/red/data.csv"));
+        assert_eq!(blue.path, directory.join("slow/blue/data.csv"));
+        assert!(red_space.is_err());
+        assert!(blue_space.is_ok());
+    }
+
+    #[test]
+    fn local_paths_stay_in_source_directory() {
+        let local_storage = storage_with_placements(HashMap::from([(
+            "legacy".to_string(),
+            SourcePlacement {
+                subdirectory: Some(PathBuf::from("/data/legacy")),
+                flatten: false,
+                ..Default::default()
+            },
+        )]));
+
+        let local_path = |source_name, file_path, prefix| {
+            local_storage
+                .local_path(source_name, Path::new(file_path), Path::new(prefix))
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(
+            local_path("red", "/upload/2026/data.csv", "/"),
+            Ok(PathBuf::from("/storage/red/upload/2026/data.csv"))
+        );
+        assert_eq!(
+            local_path("red", "upload/./data.csv", "/"),
+            Ok(PathBuf::from("/storage/red/upload/data.csv"))
+        );
+        assert_eq!(
+            local_path("red", "/incoming/red/data.csv", "/incoming/red"),
+            Ok(PathBuf::from("/storage/red/data.csv"))
+        );
+        assert_eq!(
+            local_path("red", "/elsewhere/data.csv", "/incoming/red"),
+            Ok(PathBuf::from("/storage/red/elsewhere/data.csv"))
+        );
+        assert_eq!(
+            local_path("legacy", "/upload/data.csv", "/"),
+            Ok(PathBuf::from("/data/legacy/upload/data.csv"))
+        );
+        assert_eq!(
+            local_path("red", "/upload/../../etc/passwd", "/"),
+            Err("Unsafe path: '/upload/../../etc/passwd' contains '..'".to_string())
+        );
+        assert_eq!(
+            local_path("red", "../data.csv", "/"),
+            Err("Unsafe path: '../data.csv' contains '..'".to_string())
+        );
+    }
+
+    #[test]
+    fn symlinks_in_source_directory_are_unsafe() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-symlinked-storage-{}", std::process::id()));
+        let storage_directory = directory.join("storage");
+        let outside = directory.join("outside");
+        std::fs::create_dir_all(directory.join("real/red")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(directory.join("real"), &storage_directory).unwrap();
+        std::os::unix::fs::symlink(&outside, directory.join("real/red/upload")).unwrap();
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+
+        let local_storage = LocalStorage::new(
+            vec![StorageArea::new(
+                DEFAULT_STORAGE_AREA,
+                &storage_directory,
+                StorageLayout::PerSource,
+                StorageUsage::new(&Settings::default().storage[0], Readiness::default()),
+            )],
+            SqlitePersistence::from_arc(Arc::new(Mutex::new(conn))),
+        );
+
+        let stored = local_storage.local_path("red", Path::new("/other/data.csv"), Path::new("/"));
+        let escaped =
+            local_storage.local_path("red", Path::new("/upload/data.csv"), Path::new("/"));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            stored.unwrap(),
+            storage_directory.join("red/other/data.csv")
+        );
+        assert!(matches!(escaped, Err(LocalStorageError::UnsafePath(_))));
+    }
+
+    #[test]
+    fn flattened_paths_collide() {
+        let local_storage = storage_with_placements(HashMap::from([(
+            "red".to_string(),
+            SourcePlacement {
+                subdirectory: Some(PathBuf::from("flat")),
+                flatten: true,
+                ..Default::default()
+            },
+        )]));
+
+        let first = local_storage
+            .local_path("red", Path::new("/a/b_c.csv"), Path::new("/"))
+            .unwrap();
+        let again = local_storage
+            .local_path("red", Path::new("/a/b_c.csv"), Path::new("/"))
+            .unwrap();
+        let second = local_storage
+            .local_path("red", Path::new("/a_b/c.csv"), Path::new("/"))
+            .unwrap();
+
+        assert_eq!(first, PathBuf::from("/storage/flat/a_b_c.csv"));
+        assert_eq!(again, first);
+        assert_eq!(second, first);
+        // The last path flattened to the collision is the one from /a_b
+        assert!(local_storage
+            .flattened
+            .register(&first, Path::new("a/b_c.csv")));
+        assert!(!local_storage
+            .flattened
+            .register(&first, Path::new("a/b_c.csv")));
+    }
+}
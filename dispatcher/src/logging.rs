@@ -0,0 +1,242 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use log::{error, info};
+
+use crate::settings::{LogFile, LogTarget, Logging};
+
+/// The log file of the process, kept for reopening it on SIGHUP
+static LOG_FILE: OnceLock<Arc<Mutex<RotatingFile>>> = OnceLock::new();
+
+/// A log file that is rotated when it would grow beyond its maximum size
+///
+/// Rotated files get a numeric suffix, where `<path>.1` is the most recent.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    /// Reopen the file at its path, e.g. after it has been moved by logrotate
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.file = open_append(&self.path)?;
+        self.size = self.file.metadata()?.len();
+
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.reopen()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Writes log records to stderr and/or the log file
+struct LogWriter {
+    stderr: bool,
+    file: Option<Arc<Mutex<RotatingFile>>>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.stderr {
+            io::stderr().write_all(buf)?;
+        }
+
+        if let Some(file) = &self.file {
+            if let Err(e) = file.lock().unwrap().write_all(buf) {
+                // The logger itself cannot be used to report this
+                eprintln!("Could not write to log file: {e}");
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            file.lock().unwrap().flush()?;
+        }
+
+        io::stderr().flush()
+    }
+}
+
+fn open_log_file(log_file: &LogFile) -> io::Result<RotatingFile> {
+    RotatingFile::open(
+        &log_file.path,
+        log_file.max_size_mb * 1024 * 1024,
+        log_file.max_files,
+    )
+}
+
+/// Initialize the logger with the configured targets
+///
+/// When the log file cannot be opened, logging falls back to stderr. In a dry
+/// run, every line is marked with `dry_run=true`.
+pub fn init(logging: &Logging, dry_run: bool) {
+    let mut open_error: Option<String> = None;
+
+    let file = match &logging.file {
+        Some(log_file) if logging.targets.contains(&LogTarget::File) => {
+            match open_log_file(log_file) {
+                Ok(file) => Some(Arc::new(Mutex::new(file))),
+                Err(e) => {
+                    open_error = Some(format!(
+                        "Could not open log file '{}', logging to stderr: {}",
+                        log_file.path.to_string_lossy(),
+                        e
+                    ));
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let writer = LogWriter {
+        stderr: logging.targets.contains(&LogTarget::Stderr) || file.is_none(),
+        file: file.clone(),
+    };
+
+    // A log file has no journal to add timestamps
+    let timestamps = file.is_some();
+
+    let marker = if dry_run { "  dry_run=true" } else { "" };
+
+    let mut env_logger_builder = env_logger::builder();
+
+    env_logger_builder
+        .format(move |buf, record| {
+            if timestamps {
+                writeln!(
+                    buf,
+                    "{}  {}{}  {}",
+                    chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                    record.level(),
+                    marker,
+                    record.args()
+                )
+            } else {
+                writeln!(buf, "{}{}  {}", record.level(), marker, record.args())
+            }
+        })
+        .target(env_logger::Target::Pipe(Box::new(writer)));
+
+    env_logger_builder.init();
+
+    if let Some(file) = file {
+        let _ = LOG_FILE.set(file);
+    }
+
+    if let Some(e) = open_error {
+        error!("{e}");
+    }
+}
+
+/// Reopen the log file, if logging to a file
+pub fn reopen() {
+    if let Some(file) = LOG_FILE.get() {
+        // Release the lock before logging the result, the logger needs it
+        let result = file.lock().unwrap().reopen();
+
+        match result {
+            Ok(()) => info!("Log file reopened"),
+            Err(e) => error!("Could not reopen log file: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_on_max_size() {
+        let dir = std::env::temp_dir().join(format!("cortex-logging-{}", std::process::id()));
+        let path = dir.join("dispatcher.log");
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("dispatcher.log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("dispatcher.log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!dir.join("dispatcher.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,19 +1,55 @@
 use std::process::ExitCode;
 
-use commands::{dev_stack::DevStackOpt, service::ServiceOpt, DispatcherError};
+use commands::{
+    backfill_hashes::BackfillHashesOpt, check_config::CheckConfigOpt, dev_stack::DevStackOpt,
+    doctor::DoctorOpt, errors::ErrorsOpt, example_config::ExampleConfigOpt,
+    failed_commands::FailedCommandsOpt, files::FilesOpt, init_database::InitDatabaseOpt,
+    reconcile::ReconcileOpt, run_once::RunOnceOpt, service::ServiceOpt,
+    sftp_downloads::SftpDownloadsOpt, sources::SourcesOpt, spool::SpoolOpt, DispatcherError,
+};
 
+mod amqp;
+mod archive_target;
+mod audit;
 mod base_types;
 mod commands;
+mod control;
+mod delivery;
 mod directory_source;
 mod directory_target;
 mod dispatcher;
+mod drain;
 mod event;
+mod event_publisher;
+mod hash_backfill;
+mod heartbeat;
+mod http_server;
+mod intake_journal;
 mod local_storage;
+mod logging;
 mod metrics;
+mod notification_spool;
+mod notifier;
+mod path_lock;
+mod pause;
 mod persistence;
+mod probe;
+mod rate_limit;
+mod readiness;
+mod reconcile;
+mod remote_removal;
+mod report;
+mod retention;
+mod run_once;
+mod runtime_targets;
+mod seed;
 mod settings;
 mod sftp_command_consumer;
 mod sftp_downloader;
+mod source_activity;
+mod storage_usage;
+mod systemd;
+mod write_behind;
 
 use clap::{Parser, Subcommand};
 
@@ -32,6 +68,36 @@ enum Command {
     Service(ServiceOpt),
     #[command(about = "Start development containers")]
     DevStack(DevStackOpt),
+    #[command(about = "Validate configuration and optionally probe connectivity")]
+    CheckConfig(CheckConfigOpt),
+    #[command(about = "Create or migrate the database schema")]
+    InitDatabase(InitDatabaseOpt),
+    #[command(about = "Diagnose connectivity to all configured dependencies")]
+    Doctor(DoctorOpt),
+    #[command(about = "Print an annotated example configuration")]
+    ExampleConfig(ExampleConfigOpt),
+    #[command(about = "List and redispatch files in internal storage")]
+    Files(FilesOpt),
+    #[command(about = "Recover SFTP downloads")]
+    SftpDownloads(SftpDownloadsOpt),
+    #[command(about = "List and retry download commands in the dead-letter queue")]
+    FailedCommands(FailedCommandsOpt),
+    #[command(about = "List, pause and resume sources of the running dispatcher")]
+    Sources(SourcesOpt),
+    #[command(about = "Redispatch stored files that were not dispatched to all connected targets")]
+    Reconcile(ReconcileOpt),
+    #[command(about = "Calculate and register the hashes of stored files without one")]
+    BackfillHashes(BackfillHashesOpt),
+    #[command(
+        about = "Download the files of an SFTP source once and dispatch them to its targets"
+    )]
+    RunOnce(RunOnceOpt),
+    #[command(about = "List the codes of the errors in the logs and metrics")]
+    Errors(ErrorsOpt),
+    #[command(
+        about = "Show and publish the notifications spooled while the broker was unreachable"
+    )]
+    Spool(SpoolOpt),
 }
 
 fn main() -> ExitCode {
@@ -40,11 +106,24 @@ fn main() -> ExitCode {
     let result = match cli.command {
         Some(Command::Service(service)) => service.run(),
         Some(Command::DevStack(dev_stack)) => dev_stack.run(),
+        Some(Command::CheckConfig(check_config)) => check_config.run(),
+        Some(Command::InitDatabase(init_database)) => init_database.run(),
+        Some(Command::Doctor(doctor)) => doctor.run(),
+        Some(Command::ExampleConfig(example_config)) => example_config.run(),
+        Some(Command::Files(files)) => files.run(),
+        Some(Command::SftpDownloads(sftp_downloads)) => sftp_downloads.run(),
+        Some(Command::FailedCommands(failed_commands)) => failed_commands.run(),
+        Some(Command::Sources(sources)) => sources.run(),
+        Some(Command::Reconcile(reconcile)) => reconcile.run(),
+        Some(Command::BackfillHashes(backfill_hashes)) => backfill_hashes.run(),
+        Some(Command::RunOnce(run_once)) => run_once.run(),
+        Some(Command::Errors(errors)) => errors.run(),
+        Some(Command::Spool(spool)) => spool.run(),
         None => return ExitCode::FAILURE,
     };
 
     if let Err(e) = result {
-        println!("{}", e);
+        eprintln!("{}", e);
         return ExitCode::FAILURE;
     }
 
@@ -1,5 +1,24 @@
+use std::time::Duration;
+
 use lazy_static::lazy_static;
-use prometheus::{register_int_counter_vec, IntCounterVec};
+use prometheus::{
+    register_counter_vec, register_gauge_vec, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, CounterVec, GaugeVec, HistogramVec, IntCounterVec,
+    IntGauge, IntGaugeVec,
+};
+
+/// Buckets in seconds of the time since file events were created
+const LATENCY_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0,
+];
+
+/// Buckets of latencies that include the scan interval of the scanner
+const DISCOVERY_LATENCY_BUCKETS: &[f64] = &[
+    1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 14400.0,
+];
+
+/// Buckets of the number of files registered in one transaction
+const BATCH_SIZE_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0];
 
 lazy_static! {
     pub static ref FILE_DOWNLOAD_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
@@ -14,10 +33,283 @@ lazy_static! {
         &["source"]
     )
     .unwrap();
+    pub static ref DOWNLOAD_TRUNCATED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "download_truncated_total",
+        "Total number of downloads with a size that differs from the remote size",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref REMOTE_DELETE_FAILURES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "remote_delete_failures_total",
+        "Total number of failed removals of remote files after downloading",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref REMOTE_DELETES_SUPPRESSED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "remote_deletes_suppressed_total",
+        "Total number of removals of remote files asked for by commands that were not done because of remote_remove: never",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref NOTIFICATIONS_SPOOLED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "notifications_spooled_total",
+        "Total number of notifications that could not be published and were spooled",
+        &["target"]
+    )
+    .unwrap();
+    pub static ref NOTIFICATIONS_SPOOL_DROPPED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "notifications_spool_dropped_total",
+        "Total number of spooled notifications dropped because the spool file was full",
+        &["target"]
+    )
+    .unwrap();
+    pub static ref DISPATCHED_RECORD_FAILURES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "dispatched_record_failures_total",
+        "Total number of files placed in a target of which the dispatch could not be registered",
+        &["target"]
+    )
+    .unwrap();
     pub static ref MESSAGES_RECEIVED_COUNTER: IntCounterVec = register_int_counter_vec!(
         "messages_received_total",
         "Total number of messages received",
         &["source"]
     )
     .unwrap();
+    pub static ref SFTP_RECONNECTS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "sftp_reconnects_total",
+        "Total number of reconnects to the SFTP source",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref DOWNLOADER_THREAD_RESTARTS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "downloader_thread_restarts_total",
+        "Total number of restarts of SFTP download threads that stopped",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref SFTP_SOURCE_HEALTHY_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "sftp_source_healthy",
+        "1 when the SFTP source has its download threads, 0 when they could not be restarted",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref SFTP_SESSIONS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "sftp_sessions",
+        "Number of SFTP sessions open to the host",
+        &["host"]
+    )
+    .unwrap();
+    pub static ref SFTP_SESSION_WAITERS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "sftp_session_waiters",
+        "Number of threads waiting for an SFTP session to the host",
+        &["host"]
+    )
+    .unwrap();
+    pub static ref AMQP_RECONNECTS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "amqp_reconnects_total",
+        "Total number of reconnects to the AMQP command queue",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref COMMAND_PARSE_ERRORS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "command_parse_errors_total",
+        "Total number of download commands that could not be parsed",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref DEAD_LETTERED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "dead_lettered_commands_total",
+        "Total number of download commands that were dead-lettered",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref COMMAND_QUEUE_MESSAGES_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "command_queue_messages",
+        "Number of download commands waiting in the AMQP command queue",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref COMMAND_QUEUE_CONSUMERS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "command_queue_consumers",
+        "Number of consumers of the AMQP command queue",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref COMMAND_CHANNEL_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "command_channel_commands",
+        "Number of download commands waiting for a download thread",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref CORRUPTION_DETECTED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "corruption_detected_total",
+        "Total number of stored files that no longer matched their registered hash or size when they were dispatched",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref INVALID_FILENAME_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "invalid_filename_total",
+        "Total number of files of directory sources skipped because their path is not valid UTF-8",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref FILE_EVENTS_DROPPED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "file_events_dropped_total",
+        "Total number of file events dropped because their channel was full",
+        &["channel"]
+    )
+    .unwrap();
+    pub static ref DISPATCH_OVERFLOW_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "dispatch_overflow_total",
+        "Total number of file events dispatched to a full target channel, deferred until there is room or dropped by the overflow policy",
+        &["source", "target", "priority", "outcome"]
+    )
+    .unwrap();
+    pub static ref UNACKED_MESSAGES_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "unacked_messages",
+        "Number of received download commands that are not acknowledged yet",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref STORAGE_BYTES_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "storage_bytes",
+        "Number of bytes in internal storage",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref STORAGE_FILES_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "storage_files",
+        "Number of files in internal storage",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref STORAGE_FILES_REMOVED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "storage_files_removed_total",
+        "Total number of files removed from internal storage after the retention period",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref TARGET_BACKLOG_GAUGE: GaugeVec = register_gauge_vec!(
+        "target_backlog_seconds",
+        "Time that the file event being handled by a directory target was queued",
+        &["target"]
+    )
+    .unwrap();
+    pub static ref DISPATCH_LATENCY_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "dispatch_latency_seconds",
+        "Time from the creation of file events until they are dispatched to targets",
+        &["source"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref TARGET_LATENCY_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "target_latency_seconds",
+        "Time from the creation of file events until they are handled by a directory target",
+        &["target"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref DOWNLOAD_LATENCY_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "download_latency_seconds",
+        "Time from the discovery of remote files by the scanner until they are downloaded",
+        &["source"],
+        DISCOVERY_LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref WRITE_BEHIND_BATCH_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "write_behind_batch_size",
+        "Number of downloaded files registered in one transaction by the write-behind thread",
+        &["source"],
+        BATCH_SIZE_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref DELIVERY_LATENCY_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "delivery_latency_seconds",
+        "Time from the download or intake of files until their dispatch to a directory target is registered",
+        &["source", "target"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref END_TO_END_LATENCY_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "end_to_end_latency_seconds",
+        "Time from the discovery of remote files by the scanner until they are placed and notified in a directory target",
+        &["source", "target"],
+        DISCOVERY_LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref SOURCE_PAUSED_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "source_paused",
+        "1 when intake from the source is paused, 0 otherwise",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref DRY_RUN_GAUGE: IntGauge = register_int_gauge!(
+        "dry_run",
+        "1 when the dispatcher runs without storing, placing, registering or publishing files"
+    )
+    .unwrap();
+    pub static ref EVENTS_PUBLISHED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "events_published_total",
+        "Total number of file events published by an event publisher",
+        &["publisher"]
+    )
+    .unwrap();
+    pub static ref ERRORS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "errors_total",
+        "Total number of errors with a code of which repeated log messages are suppressed, including the suppressed ones, by source, target or SFTP server",
+        &["code", "source"]
+    )
+    .unwrap();
+    pub static ref COMPONENT_SECONDS_COUNTER: CounterVec = register_counter_vec!(
+        "component_seconds_total",
+        "Total wall time spent by a component on files, by source or target",
+        &["component", "name"]
+    )
+    .unwrap();
+    pub static ref COMPONENT_THREADS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "component_threads",
+        "Number of running threads of a component",
+        &["component"]
+    )
+    .unwrap();
+}
+
+/// Add the wall time of an operation of a component on a file, for the
+/// source or target with the name
+///
+/// Called once per operation with the time accumulated over its iterations,
+/// so that the loops themselves are not slowed down by the counter.
+pub fn add_component_time(component: &str, name: &str, elapsed: Duration) {
+    COMPONENT_SECONDS_COUNTER
+        .with_label_values(&[component, name])
+        .inc_by(elapsed.as_secs_f64());
+}
+
+/// Counts a running thread of a component until it is dropped
+pub struct ComponentThread(&'static str);
+
+impl ComponentThread {
+    pub fn start(component: &'static str) -> ComponentThread {
+        COMPONENT_THREADS_GAUGE
+            .with_label_values(&[component])
+            .inc();
+
+        ComponentThread(component)
+    }
+}
+
+impl Drop for ComponentThread {
+    fn drop(&mut self) {
+        COMPONENT_THREADS_GAUGE.with_label_values(&[self.0]).dec();
+    }
+}
+
+#[cfg(feature = "kafka")]
+lazy_static! {
+    pub static ref KAFKA_DELIVERY_FAILURES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "kafka_delivery_failures_total",
+        "Total number of notifications that Kafka producers could not deliver within their delivery timeout",
+        &["target"]
+    )
+    .unwrap();
 }
@@ -0,0 +1,261 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+
+use crate::metrics;
+use crate::settings::{NotificationSpool, Settings};
+
+/// Notification that could not be published, as kept in the spool file of its
+/// target
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpooledNotification {
+    pub routing_key: String,
+    pub message: String,
+    pub deduplication_id: String,
+    pub spooled: DateTime<Utc>,
+}
+
+/// Spool file of the notifications of a target, in the order in which they
+/// are to be published
+#[derive(Debug, Clone)]
+pub struct Spool {
+    pub target: String,
+    pub path: PathBuf,
+    pub publish_attempts: u32,
+    max_bytes: u64,
+}
+
+/// Directory of the spool files, in the first storage area
+pub fn spool_directory(settings: &Settings) -> PathBuf {
+    settings.storage[0].directory.join("spool")
+}
+
+impl Spool {
+    pub fn new(directory: &Path, target: &str, settings: &NotificationSpool) -> Spool {
+        Spool {
+            target: target.to_string(),
+            path: directory.join(format!("{target}.jsonl")),
+            publish_attempts: settings.publish_attempts,
+            max_bytes: settings.max_bytes,
+        }
+    }
+
+    /// Take the lock on the spool of the target, waiting for other writers,
+    /// also those of other processes such as the spool command
+    pub fn lock(&self) -> Result<LockedSpool<'_>, String> {
+        let lock_path = self.path.with_extension("lock");
+
+        if let Some(parent) = lock_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                format!("Error creating spool directory '{}': {e}", parent.display())
+            })?;
+        }
+
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .map_err(|e| format!("Error opening '{}': {e}", lock_path.display()))?;
+
+        let lock = Flock::lock(lock_file, FlockArg::LockExclusive)
+            .map_err(|(_, e)| format!("Error locking '{}': {e}", lock_path.display()))?;
+
+        Ok(LockedSpool {
+            spool: self,
+            _lock: lock,
+        })
+    }
+}
+
+/// Spool of a target that is locked for as long as it is held
+pub struct LockedSpool<'a> {
+    spool: &'a Spool,
+    _lock: Flock<File>,
+}
+
+impl LockedSpool<'_> {
+    /// The spooled notifications, oldest first
+    pub fn notifications(&self) -> Result<Vec<SpooledNotification>, String> {
+        let path = &self.spool.path;
+
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Error opening spool '{}': {e}", path.display())),
+        };
+
+        let mut notifications = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            let line =
+                line.map_err(|e| format!("Error reading spool '{}': {e}", path.display()))?;
+
+            // The last line is incomplete when the dispatcher crashed while
+            // writing it
+            match serde_json::from_str(&line) {
+                Ok(notification) => notifications.push(notification),
+                Err(e) => warn!("Skipping invalid notification in '{}': {e}", path.display()),
+            }
+        }
+
+        Ok(notifications)
+    }
+
+    /// Append a notification, dropping the oldest notifications when the
+    /// spool grows beyond its maximum size
+    pub fn append(&self, notification: &SpooledNotification) -> Result<(), String> {
+        let path = &self.spool.path;
+
+        let mut line = serde_json::to_vec(notification)
+            .map_err(|e| format!("Error serializing notification: {e}"))?;
+        line.push(b'\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| {
+                file.write_all(&line)?;
+                file.sync_data()
+            })
+            .map_err(|e| format!("Error writing spool '{}': {e}", path.display()))?;
+
+        metrics::NOTIFICATIONS_SPOOLED_COUNTER
+            .with_label_values(&[&self.spool.target])
+            .inc();
+
+        let size = fs::metadata(path).map(|m| m.len()).unwrap_or_default();
+
+        if size > self.spool.max_bytes {
+            self.drop_oldest(size)?;
+        }
+
+        Ok(())
+    }
+
+    fn drop_oldest(&self, size: u64) -> Result<(), String> {
+        let mut notifications = self.notifications()?.into_iter();
+        let mut size = size;
+        let mut dropped = 0;
+
+        while size > self.spool.max_bytes {
+            let Some(notification) = notifications.next() else {
+                break;
+            };
+
+            size -= serde_json::to_vec(&notification)
+                .map(|line| line.len() as u64 + 1)
+                .unwrap_or_default()
+                .min(size);
+            dropped += 1;
+        }
+
+        warn!(
+            "Spool of target '{}' is full, dropped the {dropped} oldest notifications",
+            self.spool.target
+        );
+
+        metrics::NOTIFICATIONS_SPOOL_DROPPED_COUNTER
+            .with_label_values(&[&self.spool.target])
+            .inc_by(dropped);
+
+        self.rewrite(notifications)
+    }
+
+    /// Remove the first `count` notifications, after they were published
+    pub fn remove_first(&self, count: usize) -> Result<(), String> {
+        let remaining = self.notifications()?.into_iter().skip(count);
+
+        self.rewrite(remaining)
+    }
+
+    /// Replace the spool file with the notifications
+    fn rewrite(
+        &self,
+        notifications: impl Iterator<Item = SpooledNotification>,
+    ) -> Result<(), String> {
+        let path = &self.spool.path;
+        let rewritten_path = path.with_extension("jsonl.tmp");
+
+        let mut content = Vec::new();
+
+        for notification in notifications {
+            serde_json::to_writer(&mut content, &notification)
+                .map_err(|e| format!("Error serializing notification: {e}"))?;
+            content.push(b'\n');
+        }
+
+        File::create(&rewritten_path)
+            .and_then(|mut file| {
+                file.write_all(&content)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&rewritten_path, path))
+            .map_err(|e| format!("Error rewriting spool '{}': {e}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(n: usize) -> SpooledNotification {
+        SpooledNotification {
+            routing_key: "red".to_string(),
+            message: format!("{{\"file_path\": \"/data/{n}.csv\"}}"),
+            deduplication_id: format!("{n}:red"),
+            spooled: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn full_spools_drop_the_oldest_notifications() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-notification-spool-{}", std::process::id()));
+
+        let line_size = serde_json::to_vec(&notification(0)).unwrap().len() as u64 + 1;
+
+        let spool = Spool::new(
+            &directory,
+            "red",
+            &NotificationSpool {
+                publish_attempts: 1,
+                max_bytes: 5 * line_size,
+            },
+        );
+
+        let locked = spool.lock().unwrap();
+
+        for n in 0..8 {
+            locked.append(&notification(n)).unwrap();
+        }
+
+        let kept = locked.notifications().unwrap();
+
+        locked.remove_first(2).unwrap();
+
+        let remaining = locked.notifications().unwrap();
+
+        drop(locked);
+        fs::remove_dir_all(&directory).unwrap();
+
+        let deduplication_ids = |notifications: &[SpooledNotification]| -> Vec<String> {
+            notifications
+                .iter()
+                .map(|n| n.deduplication_id.clone())
+                .collect()
+        };
+
+        assert_eq!(
+            deduplication_ids(&kept),
+            ["3:red", "4:red", "5:red", "6:red", "7:red"]
+        );
+        assert_eq!(deduplication_ids(&remaining), ["5:red", "6:red", "7:red"]);
+    }
+}
@@ -0,0 +1,722 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tera::{Context, Tera};
+
+use serde_json::json;
+
+use chrono::Utc;
+use log::{debug, error, info, warn};
+
+use cortex_core::secret::Secret;
+
+use crate::amqp;
+use crate::event::FileEvent;
+#[cfg(feature = "kafka")]
+use crate::metrics;
+use crate::notification_spool::{LockedSpool, Spool, SpooledNotification};
+use crate::settings::{
+    AmqpTls, EventPublisher, NotificationSpool, Notify, RabbitMQNotify, TargetNotify,
+};
+#[cfg(feature = "kafka")]
+use crate::settings::{KafkaNotify, KafkaSecurityProtocol};
+use deadpool_lapin::lapin::options::BasicPublishOptions;
+use deadpool_lapin::lapin::types::{AMQPValue, FieldTable};
+use deadpool_lapin::lapin::{BasicProperties, Channel, Connection};
+#[cfg(feature = "kafka")]
+use rdkafka::config::ClientConfig;
+#[cfg(feature = "kafka")]
+use rdkafka::message::{Header, OwnedHeaders};
+#[cfg(feature = "kafka")]
+use rdkafka::producer::{FutureProducer, FutureRecord};
+#[cfg(feature = "kafka")]
+use rdkafka::util::Timeout;
+
+/// Delay between attempts to publish a notification that is spooled when
+/// they all fail
+const PUBLISH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Time after a failed publish during which notifications are spooled
+/// without trying to publish them
+const SPOOL_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of published notifications after which they are removed from the
+/// spool during a replay
+const REPLAY_BATCH: usize = 100;
+
+/// Header of notifications with the id by which consumers can recognize
+/// notifications of the same file that are published again
+pub const DEDUPLICATION_HEADER: &str = "x-deduplication-id";
+
+/// Id that is the same for each notification of a file placed in a target,
+/// from the file id and the name of the target
+pub fn deduplication_id(file_event: &FileEvent, target: &str) -> String {
+    format!("{}:{}", file_event.file_id, target)
+}
+
+/// Transport of the notifications about files placed in a target
+#[async_trait]
+pub trait Notifier {
+    /// Notify about the file of the event, which is placed in the target
+    async fn notify(&mut self, event: &FileEvent, target: &str) -> Result<(), String>;
+}
+
+/// Notifier for the notify settings of a target, which in a dry run logs the
+/// notifications instead of sending them
+pub fn notifier(notify: &Notify, dry_run: bool) -> Box<dyn Notifier + Send> {
+    match notify {
+        Notify::RabbitMQ(notify_conf) => {
+            let mut notifier = RabbitMQNotifier::from(notify_conf);
+            notifier.dry_run = dry_run;
+
+            Box::new(notifier)
+        }
+        #[cfg(feature = "kafka")]
+        Notify::Kafka(notify_conf) => {
+            let mut notifier = KafkaNotifier::from(notify_conf);
+            notifier.dry_run = dry_run;
+
+            Box::new(notifier)
+        }
+        #[cfg(not(feature = "kafka"))]
+        Notify::Kafka(_) => Box::new(UnavailableNotifier("kafka")),
+        Notify::Log => Box::new(LogNotifier),
+    }
+}
+
+/// AMQP connection of a named notifier, shared by the targets that refer to
+/// it with a channel per target
+type SharedConnection = Arc<tokio::sync::Mutex<Option<Connection>>>;
+
+/// Notifiers of the `notifiers` settings, of which each RabbitMQ notifier
+/// keeps one AMQP connection for all targets that refer to it
+#[derive(Clone, Default)]
+pub struct NamedNotifiers {
+    notifiers: Arc<HashMap<String, Notify>>,
+    connections: Arc<HashMap<String, SharedConnection>>,
+    /// Directory and settings of the spools of RabbitMQ notifications
+    spool: Option<(PathBuf, NotificationSpool)>,
+}
+
+impl NamedNotifiers {
+    pub fn new(notifiers: &HashMap<String, Notify>) -> NamedNotifiers {
+        NamedNotifiers {
+            notifiers: Arc::new(notifiers.clone()),
+            connections: Arc::new(
+                notifiers
+                    .keys()
+                    .map(|name| (name.clone(), SharedConnection::default()))
+                    .collect(),
+            ),
+            spool: None,
+        }
+    }
+
+    /// Spool the RabbitMQ notifications that cannot be published in a file
+    /// per target in the directory
+    pub fn with_spool(mut self, directory: PathBuf, settings: NotificationSpool) -> NamedNotifiers {
+        self.spool = Some((directory, settings));
+        self
+    }
+
+    pub fn notifiers(&self) -> &HashMap<String, Notify> {
+        &self.notifiers
+    }
+
+    /// Notifier for the notify settings of a target, which in a dry run logs
+    /// the notifications instead of sending them
+    pub fn notifier(
+        &self,
+        notify: &TargetNotify,
+        target: &str,
+        dry_run: bool,
+    ) -> Box<dyn Notifier + Send> {
+        let Some(resolved) = notify.resolve(&self.notifiers) else {
+            return Box::new(UndefinedNotifier(
+                notify.notifier_name().unwrap_or_default().to_string(),
+            ));
+        };
+
+        let shared_connection = notify
+            .notifier_name()
+            .and_then(|name| self.connections.get(name));
+
+        match &resolved {
+            Notify::RabbitMQ(notify_conf) => {
+                let mut notifier = RabbitMQNotifier::from(notify_conf);
+                notifier.dry_run = dry_run;
+                notifier.shared_connection = shared_connection.cloned();
+                notifier.spool = self.spool(target);
+
+                Box::new(notifier)
+            }
+            _ => notifier(&resolved, dry_run),
+        }
+    }
+
+    /// Spool of the notifications of a target, when spooling is configured
+    pub fn spool(&self, target: &str) -> Option<Spool> {
+        self.spool
+            .as_ref()
+            .map(|(directory, settings)| Spool::new(directory, target, settings))
+    }
+}
+
+/// Variables of the message templates of notifications
+fn message_context(file_event: &FileEvent) -> Result<Context, String> {
+    Context::from_serialize(&json!({
+        "file_path": &file_event.path,
+        "relative_path": &file_event.relative_path,
+        "size": file_event.size,
+        "modified": file_event.modified.to_rfc3339(),
+        "created": file_event.created.to_rfc3339(),
+    }))
+    .map_err(|e| format!("Could not create context: {e}"))
+}
+
+/// Notifier of a transport that is not compiled in, of which every
+/// notification fails
+#[cfg(not(feature = "kafka"))]
+pub struct UnavailableNotifier(&'static str);
+
+#[cfg(not(feature = "kafka"))]
+#[async_trait]
+impl Notifier for UnavailableNotifier {
+    async fn notify(&mut self, _event: &FileEvent, _target: &str) -> Result<(), String> {
+        Err(format!(
+            "Notifications on {} require the {} feature",
+            self.0, self.0
+        ))
+    }
+}
+
+/// Notifier of a target that refers to a notifier that is not defined, of
+/// which every notification fails
+pub struct UndefinedNotifier(String);
+
+#[async_trait]
+impl Notifier for UndefinedNotifier {
+    async fn notify(&mut self, _event: &FileEvent, _target: &str) -> Result<(), String> {
+        Err(format!("Notifier '{}' is not defined", self.0))
+    }
+}
+
+/// Notifier that only logs the notifications
+pub struct LogNotifier;
+
+#[async_trait]
+impl Notifier for LogNotifier {
+    async fn notify(&mut self, event: &FileEvent, target: &str) -> Result<(), String> {
+        info!(
+            "Placed '{}' ({} bytes) in target '{}'",
+            event.path.to_string_lossy(),
+            event.size,
+            target
+        );
+
+        Ok(())
+    }
+}
+
+pub struct RabbitMQNotifier {
+    pub address: Secret,
+    pub amqp_tls: Option<AmqpTls>,
+    pub message_template: String,
+    pub exchange: String,
+    pub routing_key: String,
+    /// Log the notifications instead of publishing them
+    pub dry_run: bool,
+    channel: Option<Channel>,
+    /// Connection on which the channel is created, instead of a connection
+    /// of its own
+    shared_connection: Option<SharedConnection>,
+    /// Spool of the notifications that are not published within the publish
+    /// attempts, instead of retrying until they are published
+    pub spool: Option<Spool>,
+    /// Time until which notifications are spooled without trying to publish
+    /// them, after a failed publish
+    spool_until: Option<Instant>,
+}
+
+impl From<&RabbitMQNotify> for RabbitMQNotifier {
+    fn from(value: &RabbitMQNotify) -> Self {
+        RabbitMQNotifier {
+            address: value.address.clone(),
+            amqp_tls: value.amqp_tls.clone(),
+            message_template: value.message_template.clone(),
+            exchange: value.exchange.clone(),
+            routing_key: value.routing_key.clone(),
+            dry_run: false,
+            channel: None,
+            shared_connection: None,
+            spool: None,
+            spool_until: None,
+        }
+    }
+}
+
+/// Notifier that publishes the file events of an event publisher, with its
+/// routing key as template
+impl From<&EventPublisher> for RabbitMQNotifier {
+    fn from(value: &EventPublisher) -> Self {
+        RabbitMQNotifier {
+            address: value.address.clone(),
+            amqp_tls: value.amqp_tls.clone(),
+            message_template: String::new(),
+            exchange: value.exchange.clone(),
+            routing_key: value.routing_key.clone(),
+            dry_run: false,
+            channel: None,
+            shared_connection: None,
+            spool: None,
+            spool_until: None,
+        }
+    }
+}
+
+async fn create_channel(connection: &Connection) -> Result<Channel, String> {
+    connection
+        .create_channel()
+        .await
+        .map_err(|e| format!("Error creating AMQP channel: {e}"))
+}
+
+impl RabbitMQNotifier {
+    async fn connect(&mut self) -> Result<Channel, String> {
+        let Some(shared_connection) = &self.shared_connection else {
+            let connection = amqp::connect(self.address.expose(), self.amqp_tls.as_ref()).await?;
+
+            return create_channel(&connection).await;
+        };
+
+        let mut shared_connection = shared_connection.lock().await;
+
+        // Connect again when the shared connection is closed, e.g. after a
+        // failed publish on the channel of another target
+        let connection = match shared_connection.take() {
+            Some(connection) if connection.status().connected() => connection,
+            _ => amqp::connect(self.address.expose(), self.amqp_tls.as_ref()).await?,
+        };
+
+        let amqp_channel = create_channel(&connection).await;
+
+        *shared_connection = Some(connection);
+
+        amqp_channel
+    }
+
+    async fn publish(
+        &mut self,
+        routing_key: &str,
+        message: &str,
+        deduplication_id: &str,
+    ) -> Result<(), String> {
+        let mut headers = FieldTable::default();
+        headers.insert(
+            DEDUPLICATION_HEADER.into(),
+            AMQPValue::LongString(deduplication_id.into()),
+        );
+
+        self.channel
+            .as_ref()
+            .unwrap()
+            .basic_publish(
+                &self.exchange.clone(),
+                routing_key,
+                BasicPublishOptions::default(),
+                message.as_bytes(),
+                BasicProperties::default()
+                    .with_message_id(deduplication_id.into())
+                    .with_headers(headers),
+            )
+            .await
+            .map_err(|e| format!("Error publishing notification: {}", e))?;
+
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) -> Result<(), String> {
+        self.channel = None;
+
+        while self.channel.is_none() {
+            match self.connect().await {
+                Ok(channel) => {
+                    self.channel = Some(channel);
+                }
+                Err(e) => {
+                    error!("{e}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publish a message, reconnecting until it is published
+    pub async fn publish_message(
+        &mut self,
+        routing_key: &str,
+        message: &str,
+        deduplication_id: &str,
+    ) -> Result<(), String> {
+        if self.dry_run {
+            info!(
+                "Would publish to exchange '{}' with routing key '{}': {}",
+                &self.exchange, routing_key, message
+            );
+
+            return Ok(());
+        }
+
+        debug!("Notifying with AMQP routing key {}", routing_key);
+
+        if self.channel.is_none() {
+            self.channel = Some(self.connect().await?);
+        }
+
+        let mut published = false;
+
+        while !published {
+            match self.publish(routing_key, message, deduplication_id).await {
+                Ok(_) => {
+                    published = true;
+                }
+                Err(e) => {
+                    error!("{e}");
+                    self.reconnect().await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publish a message within the number of attempts, each on a new
+    /// connection after the first
+    async fn try_publish(
+        &mut self,
+        routing_key: &str,
+        message: &str,
+        deduplication_id: &str,
+        attempts: u32,
+    ) -> Result<(), String> {
+        let mut error = String::new();
+
+        for attempt in 0..attempts.max(1) {
+            if attempt > 0 {
+                tokio::time::sleep(PUBLISH_RETRY_DELAY).await;
+            }
+
+            if self.channel.is_none() {
+                match self.connect().await {
+                    Ok(channel) => self.channel = Some(channel),
+                    Err(e) => {
+                        error = e;
+                        continue;
+                    }
+                }
+            }
+
+            match self.publish(routing_key, message, deduplication_id).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    self.channel = None;
+                    error = e;
+                }
+            }
+        }
+
+        Err(format!("{error} (after {} attempts)", attempts.max(1)))
+    }
+
+    /// Publish the spooled notifications in order, removing them from the
+    /// spool as they are published, and return whether all were published
+    async fn publish_spooled(
+        &mut self,
+        locked: &LockedSpool<'_>,
+        attempts: u32,
+    ) -> Result<bool, String> {
+        let notifications = locked.notifications()?;
+
+        let mut published = 0;
+        let mut removed = 0;
+
+        for notification in &notifications {
+            let publish_result = self
+                .try_publish(
+                    &notification.routing_key,
+                    &notification.message,
+                    &notification.deduplication_id,
+                    attempts,
+                )
+                .await;
+
+            if let Err(e) = publish_result {
+                debug!("Stopped publishing spooled notifications: {e}");
+                break;
+            }
+
+            published += 1;
+
+            if published - removed == REPLAY_BATCH {
+                locked.remove_first(REPLAY_BATCH)?;
+                removed = published;
+            }
+        }
+
+        if published > removed {
+            locked.remove_first(published - removed)?;
+        }
+
+        if published > 0 {
+            info!(
+                "Published {published} of {} spooled notifications",
+                notifications.len()
+            );
+        }
+
+        Ok(published == notifications.len())
+    }
+
+    /// Publish the spooled notifications, returning the number that were
+    /// published and the number left in the spool
+    pub async fn replay_spool(&mut self) -> Result<(usize, usize), String> {
+        let Some(spool) = self.spool.clone() else {
+            return Ok((0, 0));
+        };
+
+        let locked = spool.lock()?;
+
+        let spooled = locked.notifications()?.len();
+
+        self.publish_spooled(&locked, spool.publish_attempts)
+            .await?;
+
+        let left = locked.notifications()?.len();
+
+        Ok((spooled - left, left))
+    }
+
+    /// Publish a message after the spooled notifications, or spool it when
+    /// the broker cannot be reached
+    async fn publish_or_spool(
+        &mut self,
+        spool: &Spool,
+        notification: SpooledNotification,
+    ) -> Result<(), String> {
+        let locked = spool.lock()?;
+
+        let retry = self
+            .spool_until
+            .is_none_or(|spool_until| Instant::now() >= spool_until);
+
+        // Notifications are published in order, so a new notification is
+        // spooled as long as earlier ones are
+        let result = match retry
+            && self
+                .publish_spooled(&locked, spool.publish_attempts)
+                .await?
+        {
+            true => {
+                self.try_publish(
+                    &notification.routing_key,
+                    &notification.message,
+                    &notification.deduplication_id,
+                    spool.publish_attempts,
+                )
+                .await
+            }
+            false => Err("earlier notifications are still spooled".to_string()),
+        };
+
+        match result {
+            Ok(()) => self.spool_until = None,
+            Err(e) => {
+                if retry {
+                    warn!(
+                        "Spooling notifications of target '{}' in '{}': {e}",
+                        spool.target,
+                        spool.path.display()
+                    );
+
+                    self.spool_until = Some(Instant::now() + SPOOL_RETRY_INTERVAL);
+                }
+
+                locked.append(&notification)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for RabbitMQNotifier {
+    async fn notify(&mut self, file_event: &FileEvent, target: &str) -> Result<(), String> {
+        let context = message_context(file_event)?;
+
+        let message = Tera::one_off(&self.message_template, &context, true)
+            .map_err(|e| format!("Error rendering template: {}", e))?;
+
+        let routing_key = self.routing_key.clone();
+
+        match self.spool.clone() {
+            Some(spool) if !self.dry_run => {
+                self.publish_or_spool(
+                    &spool,
+                    SpooledNotification {
+                        routing_key,
+                        message,
+                        deduplication_id: deduplication_id(file_event, target),
+                        spooled: Utc::now(),
+                    },
+                )
+                .await
+            }
+            _ => {
+                self.publish_message(
+                    &routing_key,
+                    &message,
+                    &deduplication_id(file_event, target),
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// Notifier that produces the notifications on a Kafka topic, with an
+/// idempotent producer that retries deliveries until the delivery timeout
+///
+/// The producer, with its background threads, is created on the first
+/// notification.
+#[cfg(feature = "kafka")]
+pub struct KafkaNotifier {
+    pub settings: KafkaNotify,
+    /// Log the notifications instead of producing them
+    pub dry_run: bool,
+    producer: Option<FutureProducer>,
+}
+
+#[cfg(feature = "kafka")]
+impl From<&KafkaNotify> for KafkaNotifier {
+    fn from(value: &KafkaNotify) -> Self {
+        KafkaNotifier {
+            settings: value.clone(),
+            dry_run: false,
+            producer: None,
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaNotifier {
+    fn create_producer(&self) -> Result<FutureProducer, String> {
+        let mut config = ClientConfig::new();
+
+        config
+            .set("bootstrap.servers", &self.settings.brokers)
+            .set("enable.idempotence", "true")
+            .set("acks", "all")
+            .set(
+                "message.timeout.ms",
+                self.settings.delivery_timeout.to_string(),
+            );
+
+        if let Some(security) = &self.settings.security {
+            config.set(
+                "security.protocol",
+                match security.protocol {
+                    KafkaSecurityProtocol::Plaintext => "plaintext",
+                    KafkaSecurityProtocol::Ssl => "ssl",
+                    KafkaSecurityProtocol::SaslPlaintext => "sasl_plaintext",
+                    KafkaSecurityProtocol::SaslSsl => "sasl_ssl",
+                },
+            );
+
+            let paths = [
+                ("ssl.ca.location", &security.ca_cert),
+                ("ssl.certificate.location", &security.client_cert),
+                ("ssl.key.location", &security.client_key),
+            ];
+
+            for (key, path) in paths {
+                if let Some(path) = path {
+                    config.set(key, path.to_string_lossy());
+                }
+            }
+
+            if let Some(sasl_mechanism) = &security.sasl_mechanism {
+                config.set("sasl.mechanism", sasl_mechanism);
+            }
+
+            if let Some(sasl_username) = &security.sasl_username {
+                config.set("sasl.username", sasl_username);
+            }
+
+            if let Some(sasl_password) = &security.sasl_password {
+                config.set("sasl.password", sasl_password.expose());
+            }
+        }
+
+        config
+            .create()
+            .map_err(|e| format!("Error creating Kafka producer: {e}"))
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl Notifier for KafkaNotifier {
+    async fn notify(&mut self, file_event: &FileEvent, target: &str) -> Result<(), String> {
+        let context = message_context(file_event)?;
+
+        let message = Tera::one_off(&self.settings.message_template, &context, true)
+            .map_err(|e| format!("Error rendering template: {}", e))?;
+
+        let key = Tera::one_off(&self.settings.key_template, &context, false)
+            .map_err(|e| format!("Error rendering key template: {}", e))?;
+
+        if self.dry_run {
+            info!(
+                "Would produce to Kafka topic '{}' with key '{}': {}",
+                &self.settings.topic, &key, &message
+            );
+
+            return Ok(());
+        }
+
+        if self.producer.is_none() {
+            self.producer = Some(self.create_producer()?);
+        }
+
+        let deduplication_id = deduplication_id(file_event, target);
+
+        let record = FutureRecord::to(&self.settings.topic)
+            .key(&key)
+            .payload(&message)
+            .headers(OwnedHeaders::new().insert(Header {
+                key: DEDUPLICATION_HEADER,
+                value: Some(&deduplication_id),
+            }));
+
+        // The producer retries until the delivery timeout has passed
+        self.producer
+            .as_ref()
+            .unwrap()
+            .send(record, Timeout::Never)
+            .await
+            .map_err(|(e, _)| {
+                metrics::KAFKA_DELIVERY_FAILURES_COUNTER
+                    .with_label_values(&[target])
+                    .inc();
+
+                format!("Error producing notification: {e}")
+            })?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OwnedMutexGuard;
+
+type PathMutex = Arc<tokio::sync::Mutex<()>>;
+
+/// Locks per path, for serializing the placement of files that end up at the
+/// same path while other files are placed concurrently
+#[derive(Debug, Clone, Default)]
+pub struct PathLocks {
+    locks: Arc<Mutex<HashMap<PathBuf, PathMutex>>>,
+}
+
+impl PathLocks {
+    /// Wait until no other holder of a lock on the path is left
+    pub async fn lock(&self, path: &Path) -> PathGuard {
+        let guard = self.mutex(path).lock_owned().await;
+
+        self.guard(path, guard)
+    }
+
+    /// Block the thread until no other holder of a lock on the path is left,
+    /// for use outside of the async runtime
+    pub fn blocking_lock(&self, path: &Path) -> PathGuard {
+        let guard = self.mutex(path).blocking_lock_owned();
+
+        self.guard(path, guard)
+    }
+
+    fn mutex(&self, path: &Path) -> PathMutex {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone()
+    }
+
+    fn guard(&self, path: &Path, guard: OwnedMutexGuard<()>) -> PathGuard {
+        PathGuard {
+            locks: self.clone(),
+            path: path.to_path_buf(),
+            guard: Some(guard),
+        }
+    }
+}
+
+/// Lock on a path, released when dropped
+pub struct PathGuard {
+    locks: PathLocks,
+    path: PathBuf,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for PathGuard {
+    fn drop(&mut self) {
+        self.guard = None;
+
+        let mut locks = self.locks.locks.lock().unwrap();
+
+        // Only the map itself refers to the lock when nobody is waiting on it
+        if locks
+            .get(&self.path)
+            .is_some_and(|mutex| Arc::strong_count(mutex) == 1)
+        {
+            locks.remove(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn same_path_waits_for_release() {
+        let path_locks = PathLocks::default();
+
+        let guard = path_locks.lock(Path::new("/data/a.csv")).await;
+
+        // Other paths are not blocked
+        let other_guard = path_locks.lock(Path::new("/data/b.csv")).await;
+
+        let waiting_locks = path_locks.clone();
+        let waiting = tokio::spawn(async move {
+            let _guard = waiting_locks.lock(Path::new("/data/a.csv")).await;
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(!waiting.is_finished());
+
+        drop(guard);
+        waiting.await.unwrap();
+        drop(other_guard);
+
+        assert!(path_locks.locks.lock().unwrap().is_empty());
+    }
+}
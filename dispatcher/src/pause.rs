@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use log::info;
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::metrics;
+use crate::settings::Settings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+    Directory,
+    Sftp,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceStatus {
+    pub name: String,
+    pub kind: SourceKind,
+    pub paused: bool,
+}
+
+/// Pause state of all sources, which can be changed at runtime
+///
+/// The state is kept in memory only, so all sources are running again after a
+/// restart.
+#[derive(Debug, Clone)]
+pub struct SourcePauses {
+    sources: Arc<BTreeMap<String, (SourceKind, watch::Sender<bool>)>>,
+}
+
+impl SourcePauses {
+    pub fn new(settings: &Settings) -> SourcePauses {
+        let directory_sources = settings
+            .directory_sources
+            .iter()
+            .map(|source| (source.name.clone(), SourceKind::Directory));
+
+        let sftp_sources = settings
+            .sftp_sources
+            .iter()
+            .map(|source| (source.name.clone(), SourceKind::Sftp));
+
+        let sources = directory_sources
+            .chain(sftp_sources)
+            .map(|(name, kind)| {
+                metrics::SOURCE_PAUSED_GAUGE
+                    .with_label_values(&[&name])
+                    .set(0);
+
+                (name, (kind, watch::Sender::new(false)))
+            })
+            .collect();
+
+        SourcePauses {
+            sources: Arc::new(sources),
+        }
+    }
+
+    /// Receiver of the pause state of a source, that is running for unknown
+    /// sources
+    pub fn subscribe(&self, name: &str) -> watch::Receiver<bool> {
+        match self.sources.get(name) {
+            Some((_, sender)) => sender.subscribe(),
+            None => watch::Sender::new(false).subscribe(),
+        }
+    }
+
+    pub fn is_paused(&self, name: &str) -> bool {
+        self.sources
+            .get(name)
+            .is_some_and(|(_, sender)| *sender.borrow())
+    }
+
+    /// Pause or resume a source
+    pub fn set_paused(&self, name: &str, paused: bool) -> Result<SourceStatus, String> {
+        let (kind, sender) = self
+            .sources
+            .get(name)
+            .ok_or_else(|| format!("No source named '{name}'"))?;
+
+        if sender.send_replace(paused) != paused {
+            info!(
+                "{} source '{}'",
+                if paused { "Paused" } else { "Resumed" },
+                name
+            );
+        }
+
+        metrics::SOURCE_PAUSED_GAUGE
+            .with_label_values(&[name])
+            .set(i64::from(paused));
+
+        Ok(SourceStatus {
+            name: name.to_string(),
+            kind: *kind,
+            paused,
+        })
+    }
+
+    /// Status of a source, None for unknown sources
+    pub fn status(&self, name: &str) -> Option<SourceStatus> {
+        self.sources
+            .get_key_value(name)
+            .map(|(name, (kind, sender))| SourceStatus {
+                name: name.clone(),
+                kind: *kind,
+                paused: *sender.borrow(),
+            })
+    }
+
+    pub fn statuses(&self) -> Vec<SourceStatus> {
+        self.sources
+            .iter()
+            .map(|(name, (kind, sender))| SourceStatus {
+                name: name.clone(),
+                kind: *kind,
+                paused: *sender.borrow(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::settings::{ConflictPolicy, Deduplication, DirectorySource, FileSystemEvent};
+
+    #[test]
+    fn pause_is_signalled_to_subscribers() {
+        let settings = Settings {
+            directory_sources: vec![DirectorySource {
+                name: "red".to_string(),
+                directory: PathBuf::from("/data/red"),
+                recursive: false,
+                events: vec![FileSystemEvent::CloseWrite],
+                filter: None,
+                deduplication: Deduplication::None,
+                unpack_before_hash: false,
+                delete: false,
+                storage_area: None,
+                storage_subdirectory: None,
+                flatten: false,
+                file_permissions: None,
+                group: None,
+                create_missing: false,
+                on_conflict: ConflictPolicy::Overwrite,
+                max_versions: 10,
+            }],
+            ..Settings::default()
+        };
+
+        let pauses = SourcePauses::new(&settings);
+        let receiver = pauses.subscribe("red");
+
+        assert!(!*receiver.borrow());
+
+        pauses.set_paused("red", true).unwrap();
+
+        assert!(*receiver.borrow());
+        assert!(pauses.is_paused("red"));
+        assert_eq!(
+            pauses.set_paused("green", true).unwrap_err(),
+            "No source named 'green'"
+        );
+    }
+}
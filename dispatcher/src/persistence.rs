@@ -1,6 +1,14 @@
 use chrono::prelude::*;
-use rusqlite::{params, Connection, OptionalExtension};
+use log::{debug, error};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cortex_core::error_code::ErrorCode;
 
 use crate::base_types::FileInfo;
 
@@ -10,9 +18,22 @@ pub enum PersistenceError {
     Logical { message: String },
 }
 
+impl PersistenceError {
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::Persistence
+    }
+}
+
 pub trait Persistence {
     fn delete_sftp_download_file(&self, id: i64) -> Result<(), PersistenceError>;
     fn set_sftp_download_file(&self, id: i64, file_id: i64) -> Result<(), PersistenceError>;
+    /// Register when the scanner discovered a file downloaded from an SFTP
+    /// source
+    fn set_file_discovered(
+        &self,
+        id: i64,
+        discovered: &DateTime<Utc>,
+    ) -> Result<(), PersistenceError>;
     fn insert_file(
         &self,
         source: &str,
@@ -22,6 +43,575 @@ pub trait Persistence {
         hash: Option<String>,
     ) -> Result<i64, PersistenceError>;
     fn get_file(&self, source: &str, path: &str) -> Result<Option<FileInfo>, PersistenceError>;
+    /// The stored file with the targets it has been dispatched to
+    fn dispatched_file(
+        &self,
+        source: &str,
+        path: &str,
+    ) -> Result<Option<DispatchedFile>, PersistenceError>;
+    /// Register a downloaded file with its download, returning the id of the
+    /// file
+    fn register_download(&self, download: &DownloadedFile) -> Result<i64, PersistenceError> {
+        let file_id = self.insert_file(
+            &download.source,
+            &download.path,
+            &download.modified,
+            download.size,
+            download.hash.clone(),
+        )?;
+
+        self.set_sftp_download_file(download.sftp_download_id, file_id)?;
+        self.set_file_discovered(file_id, &download.discovered)?;
+
+        Ok(file_id)
+    }
+    /// Register downloaded files in one transaction, returning the result of
+    /// each once the transaction is committed
+    fn register_downloads(
+        &self,
+        downloads: &[DownloadedFile],
+    ) -> Result<Vec<RegistrationResult>, PersistenceError> {
+        Ok(downloads
+            .iter()
+            .map(|download| self.register_download(download).map_err(|e| e.to_string()))
+            .collect())
+    }
+    /// Register a stored file under a new path, returning false when it is
+    /// not registered
+    fn rename_file(
+        &self,
+        source: &str,
+        path: &str,
+        new_path: &str,
+    ) -> Result<bool, PersistenceError>;
+    /// Register that a remote file could not be removed, counting the
+    /// attempts
+    fn register_remote_delete_failure(
+        &self,
+        source: &str,
+        path: &str,
+        error: &str,
+    ) -> Result<(), PersistenceError>;
+    fn clear_remote_delete_failure(&self, source: &str, path: &str)
+        -> Result<(), PersistenceError>;
+    /// Paths of at most `limit` remote files of the source that could not be
+    /// removed, the longest waiting first
+    fn remote_delete_failures(
+        &self,
+        source: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, PersistenceError>;
+    /// Register a download that failed after its retries, for reporting
+    fn register_download_failure(
+        &self,
+        source: &str,
+        path: &str,
+        code: &str,
+        error: &str,
+    ) -> Result<(), PersistenceError>;
+    /// Take the lock of a download, returning false when another holder has
+    /// it
+    fn try_lock_download(&self, key: i64, holder: &str) -> Result<bool, PersistenceError>;
+    fn unlock_download(&self, key: i64, holder: &str) -> Result<(), PersistenceError>;
+}
+
+/// Age at which the lock of a download is taken over, for locks left behind
+/// by an instance that stopped during the download
+const STALE_DOWNLOAD_LOCK: Duration = Duration::from_secs(6 * 3600);
+
+/// Key of the lock of the download of a remote file, which is the same for
+/// all instances and versions of the dispatcher
+pub fn download_lock_key(source: &str, path: &str) -> i64 {
+    let digest = Sha256::new()
+        .chain_update(source)
+        .chain_update([0])
+        .chain_update(path)
+        .finalize();
+
+    i64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Lock of the download of a remote file shared by the dispatcher instances
+/// on the database, released when dropped
+pub struct DownloadLock<T: Persistence> {
+    persistence: T,
+    key: i64,
+    holder: String,
+}
+
+impl<T: Persistence + Clone> DownloadLock<T> {
+    /// Take the lock, returning None when another instance has it
+    pub fn try_acquire(
+        persistence: &T,
+        source: &str,
+        path: &str,
+    ) -> Result<Option<DownloadLock<T>>, PersistenceError> {
+        let key = download_lock_key(source, path);
+        let holder = format!(
+            "{}:{}",
+            cortex_core::heartbeat::instance_name(),
+            std::process::id()
+        );
+
+        let acquired = persistence.try_lock_download(key, &holder)?;
+
+        Ok(acquired.then(|| DownloadLock {
+            persistence: persistence.clone(),
+            key,
+            holder,
+        }))
+    }
+}
+
+impl<T: Persistence> Drop for DownloadLock<T> {
+    fn drop(&mut self) {
+        if let Err(e) = self.persistence.unlock_download(self.key, &self.holder) {
+            error!("Error releasing download lock {}: {}", self.key, e);
+        }
+    }
+}
+
+/// A file registered in internal storage
+/// Id of a registered file, or the error of its registration
+pub type RegistrationResult = Result<i64, String>;
+
+/// File downloaded from an SFTP source, with the id of its download
+#[derive(Debug, Clone)]
+pub struct DownloadedFile {
+    pub source: String,
+    pub path: String,
+    pub modified: DateTime<Utc>,
+    pub size: i64,
+    pub hash: Option<String>,
+    pub sftp_download_id: i64,
+    /// When the scanner discovered the file
+    pub discovered: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileRecord {
+    pub id: i64,
+    pub timestamp: String,
+    pub source: String,
+    pub path: String,
+    pub modified: String,
+    pub size: i64,
+    pub hash: Option<String>,
+    /// When the file was removed from internal storage
+    pub deleted: Option<String>,
+    /// When the scanner discovered the file, for files from SFTP sources
+    pub discovered: Option<String>,
+}
+
+/// Criteria for selecting files from internal storage
+#[derive(Debug, Clone, Default)]
+pub struct FileQuery {
+    pub ids: Option<Vec<i64>>,
+    pub source: Option<String>,
+    /// Only files registered at or after this timestamp
+    pub since: Option<DateTime<Utc>>,
+    /// SQL LIKE pattern for the file path
+    pub path_like: Option<String>,
+    /// Only files that have not been dispatched to this target
+    pub undispatched_to: Option<String>,
+    /// Also files that have been removed from internal storage
+    pub include_deleted: bool,
+    /// Only files registered before this timestamp
+    pub until: Option<DateTime<Utc>>,
+    /// Only files without a hash
+    pub without_hash: bool,
+    /// Only files with an id greater than this, for paging through files
+    pub after_id: Option<i64>,
+    /// At most this number of files
+    pub limit: Option<usize>,
+}
+
+/// A file in internal storage that is older than the retention period, with
+/// the targets it has been dispatched to
+#[derive(Debug, Clone)]
+pub struct ExpiredFile {
+    pub id: i64,
+    pub source: String,
+    pub path: String,
+    pub size: i64,
+    pub hash: Option<String>,
+    pub targets: Vec<String>,
+}
+
+/// A stored file with the targets it has been dispatched to
+#[derive(Debug, Clone)]
+pub struct DispatchedFile {
+    pub id: i64,
+    pub info: FileInfo,
+    pub targets: Vec<String>,
+}
+
+/// Whether a file was dispatched to a target connected to its source, as
+/// decided by the filter of the connection
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DispatchDecision {
+    pub file_id: i64,
+    pub source: String,
+    pub target: String,
+    pub filter_matched: bool,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A download command that has been registered by the SFTP scanner
+#[derive(Debug, Clone, Serialize)]
+pub struct SftpDownloadRecord {
+    pub id: i64,
+    pub timestamp: String,
+    pub source: String,
+    pub path: String,
+    pub size: Option<i64>,
+    pub file_id: Option<i64>,
+}
+
+/// Number and size of the files stored for a source
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileStats {
+    pub source: String,
+    pub files: i64,
+    pub bytes: i64,
+}
+
+/// Number of failed downloads of a source with a class of failure
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FailureStats {
+    pub source: String,
+    pub code: String,
+    pub failures: i64,
+}
+
+/// Format a timestamp the way SQLite's datetime('now') does, so that it can
+/// be compared with the timestamp columns.
+fn sqlite_timestamp(timestamp: &DateTime<Utc>) -> String {
+    timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// Conditions of the query on the file table, with their values
+fn file_conditions(query: &FileQuery) -> (String, Vec<Value>) {
+    let mut sql = " where 1 = 1".to_string();
+    let mut values: Vec<Value> = Vec::new();
+
+    if let Some(ids) = &query.ids {
+        let placeholders: Vec<&str> = ids.iter().map(|_| "?").collect();
+        sql.push_str(&format!(" and id in ({})", placeholders.join(", ")));
+        values.extend(ids.iter().map(|id| Value::Integer(*id)));
+    }
+
+    if !query.include_deleted {
+        sql.push_str(" and deleted is null");
+    }
+
+    if let Some(source) = &query.source {
+        sql.push_str(" and source = ?");
+        values.push(Value::Text(source.clone()));
+    }
+
+    if let Some(since) = &query.since {
+        sql.push_str(" and timestamp >= ?");
+        values.push(Value::Text(sqlite_timestamp(since)));
+    }
+
+    if let Some(path_like) = &query.path_like {
+        sql.push_str(" and path like ?");
+        values.push(Value::Text(path_like.clone()));
+    }
+
+    if let Some(target) = &query.undispatched_to {
+        sql.push_str(
+            " and not exists (select 1 from dispatched d where d.file_id = file.id and d.target = ?)",
+        );
+        values.push(Value::Text(target.clone()));
+    }
+
+    if let Some(until) = &query.until {
+        sql.push_str(" and timestamp < ?");
+        values.push(Value::Text(sqlite_timestamp(until)));
+    }
+
+    if query.without_hash {
+        sql.push_str(" and hash is null");
+    }
+
+    if let Some(after_id) = query.after_id {
+        sql.push_str(" and id > ?");
+        values.push(Value::Integer(after_id));
+    }
+
+    (sql, values)
+}
+
+fn query_files(conn: &Connection, query: &FileQuery) -> Result<Vec<FileRecord>, PersistenceError> {
+    let (conditions, values) = file_conditions(query);
+
+    let mut sql = format!(
+        "select id, timestamp, source, path, modified, size, hash, deleted, discovered from file{conditions} order by id"
+    );
+
+    if let Some(limit) = query.limit {
+        sql.push_str(&format!(" limit {limit}"));
+    }
+
+    let mut stmt = conn.prepare(&sql).map_err(|e| PersistenceError::Logical {
+        message: format!("Prepare select files failed: {e}"),
+    })?;
+
+    let rows = stmt
+        .query_map(params_from_iter(values), |row| {
+            Ok(FileRecord {
+                id: row.get(0)?,
+                timestamp: row.get(1)?,
+                source: row.get(2)?,
+                path: row.get(3)?,
+                modified: row.get(4)?,
+                size: row.get(5)?,
+                hash: row.get(6)?,
+                deleted: row.get(7)?,
+                discovered: row.get(8)?,
+            })
+        })
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Select files failed: {e}"),
+        })?;
+
+    rows.collect::<Result<Vec<FileRecord>, rusqlite::Error>>()
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Error reading file record: {e}"),
+        })
+}
+
+/// Register the dispatch of a file to a target, or update the time of an
+/// earlier dispatch when the file is dispatched again
+fn file_stats_since(
+    conn: &Connection,
+    since: &DateTime<Utc>,
+) -> Result<Vec<FileStats>, PersistenceError> {
+    let mut stmt = conn
+        .prepare(
+            "select source, count(*), coalesce(sum(size), 0) from file
+             where timestamp >= ?1 group by source order by source",
+        )
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Prepare file statistics failed: {e}"),
+        })?;
+
+    let rows = stmt
+        .query_map(params![sqlite_timestamp(since)], |row| {
+            Ok(FileStats {
+                source: row.get(0)?,
+                files: row.get(1)?,
+                bytes: row.get(2)?,
+            })
+        })
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Select file statistics failed: {e}"),
+        })?;
+
+    rows.collect::<Result<Vec<FileStats>, rusqlite::Error>>()
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Error reading file statistics: {e}"),
+        })
+}
+
+fn failure_stats_since(
+    conn: &Connection,
+    since: &DateTime<Utc>,
+) -> Result<Vec<FailureStats>, PersistenceError> {
+    let mut stmt = conn
+        .prepare(
+            "select source, code, count(*) from download_failure
+             where timestamp >= ?1 group by source, code order by source, code",
+        )
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Prepare failure statistics failed: {e}"),
+        })?;
+
+    let rows = stmt
+        .query_map(params![sqlite_timestamp(since)], |row| {
+            Ok(FailureStats {
+                source: row.get(0)?,
+                code: row.get(1)?,
+                failures: row.get(2)?,
+            })
+        })
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Select failure statistics failed: {e}"),
+        })?;
+
+    rows.collect::<Result<Vec<FailureStats>, rusqlite::Error>>()
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Error reading failure statistics: {e}"),
+        })
+}
+
+fn insert_file(
+    conn: &Connection,
+    source: &str,
+    path: &str,
+    modified: &DateTime<Utc>,
+    size: i64,
+    hash: Option<String>,
+) -> Result<i64, PersistenceError> {
+    let modified_str = modified.to_rfc3339();
+    let mut stmt = conn
+        .prepare_cached(
+            "insert into file (source, path, modified, size, hash)
+             values (?1, ?2, ?3, ?4, ?5)
+             on conflict(source, path) where deleted is null do update set
+               modified=excluded.modified, size=excluded.size, hash=excluded.hash
+             returning id",
+        )
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Prepare insert file failed: {e}"),
+        })?;
+
+    let id: i64 = stmt
+        .query_row(params![source, path, modified_str, size, hash], |row| {
+            row.get(0)
+        })
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Insert file failed: {e}"),
+        })?;
+
+    Ok(id)
+}
+
+fn set_sftp_download_file(
+    conn: &Connection,
+    id: i64,
+    file_id: i64,
+) -> Result<(), PersistenceError> {
+    conn.execute(
+        "update sftp_download set file_id = ?2 where id = ?1",
+        params![id, file_id],
+    )
+    .map(|_| ())
+    .map_err(|e| PersistenceError::Logical {
+        message: format!("Error updating sftp_download: {e}"),
+    })
+}
+
+fn set_file_discovered(
+    conn: &Connection,
+    id: i64,
+    discovered: &DateTime<Utc>,
+) -> Result<(), PersistenceError> {
+    conn.execute(
+        "update file set discovered = ?2 where id = ?1",
+        params![id, discovered.to_rfc3339()],
+    )
+    .map(|_| ())
+    .map_err(|e| PersistenceError::Logical {
+        message: format!("Error updating file: {e}"),
+    })
+}
+
+fn register_download(
+    conn: &Connection,
+    download: &DownloadedFile,
+) -> Result<i64, PersistenceError> {
+    let file_id = insert_file(
+        conn,
+        &download.source,
+        &download.path,
+        &download.modified,
+        download.size,
+        download.hash.clone(),
+    )?;
+
+    set_sftp_download_file(conn, download.sftp_download_id, file_id)?;
+    set_file_discovered(conn, file_id, &download.discovered)?;
+
+    Ok(file_id)
+}
+
+fn insert_dispatched(conn: &Connection, dest: &str, file_id: i64) -> Result<(), PersistenceError> {
+    conn.execute(
+        "insert into dispatched (file_id, target, timestamp) values (?1, ?2, datetime('now'))
+         on conflict (file_id, target) do update set timestamp = excluded.timestamp",
+        params![file_id, dest],
+    )
+    .map(|_| ())
+    .map_err(|e| PersistenceError::Logical {
+        message: format!("Error inserting dispatched: {e}"),
+    })
+}
+
+fn insert_dispatch_decisions(
+    conn: &mut Connection,
+    decisions: &[DispatchDecision],
+) -> Result<(), PersistenceError> {
+    let tx = conn.transaction().map_err(|e| PersistenceError::Logical {
+        message: format!("Error starting transaction: {e}"),
+    })?;
+
+    {
+        let mut stmt = tx
+            .prepare(
+                "insert into dispatch_decision (file_id, source, target, filter_matched, timestamp)
+                 values (?1, ?2, ?3, ?4, ?5)",
+            )
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Prepare insert dispatch decision failed: {e}"),
+            })?;
+
+        for decision in decisions {
+            stmt.execute(params![
+                decision.file_id,
+                decision.source,
+                decision.target,
+                decision.filter_matched,
+                sqlite_timestamp(&decision.timestamp),
+            ])
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Error inserting dispatch decision: {e}"),
+            })?;
+        }
+    }
+
+    tx.commit().map_err(|e| PersistenceError::Logical {
+        message: format!("Error committing dispatch decisions: {e}"),
+    })
+}
+
+fn dispatch_decisions(
+    conn: &Connection,
+    file_id: i64,
+) -> Result<Vec<DispatchDecision>, PersistenceError> {
+    let mut stmt = conn
+        .prepare(
+            "select file_id, source, target, filter_matched, timestamp
+             from dispatch_decision where file_id = ?1 order by rowid",
+        )
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Prepare select dispatch decisions failed: {e}"),
+        })?;
+
+    let rows = stmt
+        .query_map(params![file_id], |row| {
+            let timestamp: String = row.get(4)?;
+
+            Ok(DispatchDecision {
+                file_id: row.get(0)?,
+                source: row.get(1)?,
+                target: row.get(2)?,
+                filter_matched: row.get(3)?,
+                timestamp: NaiveDateTime::parse_from_str(&timestamp, "%Y-%m-%d %H:%M:%S")
+                    .map(|timestamp| timestamp.and_utc())
+                    .unwrap_or_default(),
+            })
+        })
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Select dispatch decisions failed: {e}"),
+        })?;
+
+    rows.collect::<Result<Vec<DispatchDecision>, rusqlite::Error>>()
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Error reading dispatch decision: {e}"),
+        })
 }
 
 #[derive(Clone)]
@@ -33,129 +623,864 @@ impl SqlitePersistence {
     pub fn from_arc(conn: Arc<Mutex<Connection>>) -> SqlitePersistence {
         SqlitePersistence { conn }
     }
-}
 
-impl Persistence for SqlitePersistence {
-    fn set_sftp_download_file(&self, id: i64, file_id: i64) -> Result<(), PersistenceError> {
+    pub fn query_files(&self, query: &FileQuery) -> Result<Vec<FileRecord>, PersistenceError> {
         let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "update sftp_download set file_id = ?2 where id = ?1",
-            params![id, file_id],
+        query_files(&conn, query)
+    }
+
+    /// Number of files matching the query, ignoring its limit
+    pub fn count_files(&self, query: &FileQuery) -> Result<i64, PersistenceError> {
+        let (conditions, values) = file_conditions(query);
+
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("select count(*) from file{conditions}"),
+            params_from_iter(values),
+            |row| row.get(0),
         )
-        .map(|_| ())
         .map_err(|e| PersistenceError::Logical {
-            message: format!("Error updating sftp_download: {e}"),
+            message: format!("Error counting files: {e}"),
         })
     }
 
-    fn delete_sftp_download_file(&self, id: i64) -> Result<(), PersistenceError> {
+    /// Files stored per source since the time
+    pub fn file_stats_since(
+        &self,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<FileStats>, PersistenceError> {
         let conn = self.conn.lock().unwrap();
-        conn.execute("delete from sftp_download where id = ?1", params![id])
+        file_stats_since(&conn, since)
+    }
+
+    /// Failed downloads per source and class of failure since the time
+    pub fn failure_stats_since(
+        &self,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<FailureStats>, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        failure_stats_since(&conn, since)
+    }
+
+    pub fn set_file_hash(&self, id: i64, hash: &str) -> Result<(), PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("update file set hash = ?1 where id = ?2", params![hash, id])
             .map(|_| ())
             .map_err(|e| PersistenceError::Logical {
-                message: format!("Error deleting sftp_download: {e}"),
+                message: format!("Error setting file hash: {e}"),
             })
     }
 
-    fn insert_file(
+    pub fn insert_dispatched(&self, dest: &str, file_id: i64) -> Result<(), PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        insert_dispatched(&conn, dest, file_id)
+    }
+
+    /// Return at most `limit` files in internal storage registered before
+    /// `before`, with an id greater than `after_id`
+    pub fn expired_files(
         &self,
-        source: &str,
-        path: &str,
-        modified: &DateTime<Utc>,
-        size: i64,
-        hash: Option<String>,
-    ) -> Result<i64, PersistenceError> {
+        before: &DateTime<Utc>,
+        after_id: i64,
+        limit: usize,
+    ) -> Result<Vec<ExpiredFile>, PersistenceError> {
         let conn = self.conn.lock().unwrap();
-        let modified_str = modified.to_rfc3339();
         let mut stmt = conn
             .prepare(
-                "insert into file (source, path, modified, size, hash)
-                 values (?1, ?2, ?3, ?4, ?5)
-                 on conflict(source, path) do update set
-                   modified=excluded.modified, size=excluded.size, hash=excluded.hash
-                 returning id",
+                "select f.id, f.source, f.path, f.size, f.hash, group_concat(d.target, char(31))
+                 from file f left join dispatched d on d.file_id = f.id
+                 where f.deleted is null and f.timestamp < ?1 and f.id > ?2
+                 group by f.id
+                 order by f.id
+                 limit ?3",
             )
             .map_err(|e| PersistenceError::Logical {
-                message: format!("Prepare insert file failed: {e}"),
+                message: format!("Prepare select expired files failed: {e}"),
             })?;
 
-        let id: i64 = stmt
-            .query_row(params![source, path, modified_str, size, hash], |row| {
-                row.get(0)
-            })
+        let rows = stmt
+            .query_map(
+                params![sqlite_timestamp(before), after_id, limit as i64],
+                |row| {
+                    let targets: Option<String> = row.get(5)?;
+
+                    Ok(ExpiredFile {
+                        id: row.get(0)?,
+                        source: row.get(1)?,
+                        path: row.get(2)?,
+                        size: row.get(3)?,
+                        hash: row.get(4)?,
+                        targets: targets
+                            .map(|t| t.split('\u{1f}').map(str::to_string).collect())
+                            .unwrap_or_default(),
+                    })
+                },
+            )
             .map_err(|e| PersistenceError::Logical {
-                message: format!("Insert file failed: {e}"),
+                message: format!("Select expired files failed: {e}"),
             })?;
 
-        Ok(id)
+        rows.collect::<Result<Vec<ExpiredFile>, rusqlite::Error>>()
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Error reading expired file: {e}"),
+            })
     }
 
-    fn get_file(&self, source: &str, path: &str) -> Result<Option<FileInfo>, PersistenceError> {
+    /// Mark a file as removed from internal storage, keeping its record
+    pub fn mark_file_deleted(&self, id: i64) -> Result<(), PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "update file set deleted = datetime('now') where id = ?1",
+            params![id],
+        )
+        .map(|_| ())
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Error marking file deleted: {e}"),
+        })
+    }
+
+    /// Return the download commands of a source that never resulted in a file
+    /// in internal storage.
+    pub fn failed_sftp_downloads(
+        &self,
+        source: &str,
+        since: Option<&DateTime<Utc>>,
+    ) -> Result<Vec<SftpDownloadRecord>, PersistenceError> {
         let conn = self.conn.lock().unwrap();
+        let since_str = since.map(sqlite_timestamp);
         let mut stmt = conn
-            .prepare("select modified, size, hash from file where source = ?1 and path = ?2")
+            .prepare(
+                "select id, timestamp, source, path, size, file_id from sftp_download
+                 where source = ?1 and file_id is null and (?2 is null or timestamp >= ?2)
+                 order by id",
+            )
             .map_err(|e| PersistenceError::Logical {
-                message: format!("Prepare select file failed: {e}"),
+                message: format!("Prepare select sftp_download failed: {e}"),
             })?;
 
-        let row = stmt
-            .query_row(params![source, path], |row| {
-                let modified_str: String = row.get(0)?;
-                let modified = modified_str.parse::<DateTime<Utc>>().map_err(|e| {
-                    rusqlite::Error::FromSqlConversionFailure(
-                        0,
-                        rusqlite::types::Type::Text,
-                        Box::new(e),
-                    )
-                })?;
-                let size: i64 = row.get(1)?;
-                let hash: Option<String> = row.get(2)?;
-                Ok(FileInfo {
-                    modified,
-                    size,
-                    hash,
+        let rows = stmt
+            .query_map(params![source, since_str], |row| {
+                Ok(SftpDownloadRecord {
+                    id: row.get(0)?,
+                    timestamp: row.get(1)?,
+                    source: row.get(2)?,
+                    path: row.get(3)?,
+                    size: row.get(4)?,
+                    file_id: row.get(5)?,
                 })
             })
-            .optional()
             .map_err(|e| PersistenceError::Logical {
-                message: format!("Select file failed: {e}"),
+                message: format!("Select sftp_download failed: {e}"),
             })?;
 
-        Ok(row)
+        rows.collect::<Result<Vec<SftpDownloadRecord>, rusqlite::Error>>()
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Error reading sftp_download record: {e}"),
+            })
     }
 }
 
-#[derive(Clone)]
-pub struct SqliteAsyncPersistence {
-    conn: Arc<Mutex<Connection>>,
-}
-
-impl SqliteAsyncPersistence {
-    pub fn new(conn: Arc<Mutex<Connection>>) -> SqliteAsyncPersistence {
-        SqliteAsyncPersistence { conn }
+impl Persistence for SqlitePersistence {
+    fn set_sftp_download_file(&self, id: i64, file_id: i64) -> Result<(), PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        set_sftp_download_file(&conn, id, file_id)
     }
 
-    pub async fn insert_dispatched(
+    fn set_file_discovered(
         &self,
-        dest: &str,
-        file_id: i64,
+        id: i64,
+        discovered: &DateTime<Utc>,
     ) -> Result<(), PersistenceError> {
-        let conn = self.conn.clone();
-        let dest = dest.to_string();
-        tokio::task::spawn_blocking(move || {
-            let conn = conn.lock().unwrap();
-            conn.execute(
-                "insert into dispatched (file_id, target, timestamp) values (?1, ?2, datetime('now'))",
-                params![file_id, dest],
-            )
-            .map(|_| ())
-            .map_err(|e| PersistenceError::Logical {
-                message: format!("Error inserting dispatched: {e}"),
-            })
-        })
-        .await
-        .map_err(|e| PersistenceError::Logical {
-            message: format!("Join error inserting dispatched: {e}"),
+        let conn = self.conn.lock().unwrap();
+        set_file_discovered(&conn, id, discovered)
+    }
+
+    fn register_download(&self, download: &DownloadedFile) -> Result<i64, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        register_download(&conn, download)
+    }
+
+    fn register_downloads(
+        &self,
+        downloads: &[DownloadedFile],
+    ) -> Result<Vec<RegistrationResult>, PersistenceError> {
+        let mut conn = self.conn.lock().unwrap();
+
+        let mut transaction = conn.transaction().map_err(|e| PersistenceError::Logical {
+            message: format!("Error starting transaction: {e}"),
+        })?;
+
+        let mut results = Vec::with_capacity(downloads.len());
+
+        for download in downloads {
+            // A failed registration is rolled back without the others
+            let savepoint = transaction
+                .savepoint()
+                .map_err(|e| PersistenceError::Logical {
+                    message: format!("Error creating savepoint: {e}"),
+                })?;
+
+            let result = register_download(&savepoint, download);
+
+            if result.is_ok() {
+                savepoint.commit().map_err(|e| PersistenceError::Logical {
+                    message: format!("Error releasing savepoint: {e}"),
+                })?;
+            }
+
+            results.push(result.map_err(|e| e.to_string()));
+        }
+
+        // The ids are only handed out once they are committed, because a
+        // rolled back id is given to the next file
+        transaction
+            .commit()
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Error committing registrations: {e}"),
+            })?;
+
+        Ok(results)
+    }
+
+    fn delete_sftp_download_file(&self, id: i64) -> Result<(), PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("delete from sftp_download where id = ?1", params![id])
+            .map(|_| ())
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Error deleting sftp_download: {e}"),
+            })
+    }
+
+    fn register_remote_delete_failure(
+        &self,
+        source: &str,
+        path: &str,
+        error: &str,
+    ) -> Result<(), PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "insert into remote_delete_failure (source, path, error) values (?1, ?2, ?3)
+             on conflict (source, path) do update
+             set attempts = attempts + 1, error = excluded.error, timestamp = datetime('now')",
+            params![source, path, error],
+        )
+        .map(|_| ())
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Error registering remote delete failure: {e}"),
+        })
+    }
+
+    fn clear_remote_delete_failure(
+        &self,
+        source: &str,
+        path: &str,
+    ) -> Result<(), PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "delete from remote_delete_failure where source = ?1 and path = ?2",
+            params![source, path],
+        )
+        .map(|_| ())
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Error clearing remote delete failure: {e}"),
+        })
+    }
+
+    fn remote_delete_failures(
+        &self,
+        source: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "select path from remote_delete_failure where source = ?1
+                 order by timestamp limit ?2",
+            )
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Prepare select remote delete failures failed: {e}"),
+            })?;
+
+        let rows = stmt
+            .query_map(params![source, limit as i64], |row| row.get(0))
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Select remote delete failures failed: {e}"),
+            })?;
+
+        rows.collect::<Result<Vec<String>, rusqlite::Error>>()
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Error reading remote delete failure: {e}"),
+            })
+    }
+
+    fn insert_file(
+        &self,
+        source: &str,
+        path: &str,
+        modified: &DateTime<Utc>,
+        size: i64,
+        hash: Option<String>,
+    ) -> Result<i64, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        insert_file(&conn, source, path, modified, size, hash)
+    }
+
+    fn get_file(&self, source: &str, path: &str) -> Result<Option<FileInfo>, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "select modified, size, hash from file
+                 where source = ?1 and path = ?2 and deleted is null",
+            )
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Prepare select file failed: {e}"),
+            })?;
+
+        let row = stmt
+            .query_row(params![source, path], |row| {
+                let modified_str: String = row.get(0)?;
+                let modified = modified_str.parse::<DateTime<Utc>>().map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        0,
+                        rusqlite::types::Type::Text,
+                        Box::new(e),
+                    )
+                })?;
+                let size: i64 = row.get(1)?;
+                let hash: Option<String> = row.get(2)?;
+                Ok(FileInfo {
+                    modified,
+                    size,
+                    hash,
+                })
+            })
+            .optional()
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Select file failed: {e}"),
+            })?;
+
+        Ok(row)
+    }
+
+    fn dispatched_file(
+        &self,
+        source: &str,
+        path: &str,
+    ) -> Result<Option<DispatchedFile>, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+
+        let row = conn
+            .query_row(
+                "select id, modified, size, hash from file
+                 where source = ?1 and path = ?2 and deleted is null",
+                params![source, path],
+                |row| {
+                    let modified_str: String = row.get(1)?;
+                    let modified = modified_str.parse::<DateTime<Utc>>().map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            1,
+                            rusqlite::types::Type::Text,
+                            Box::new(e),
+                        )
+                    })?;
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        FileInfo {
+                            modified,
+                            size: row.get(2)?,
+                            hash: row.get(3)?,
+                        },
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Select file failed: {e}"),
+            })?;
+
+        let Some((id, info)) = row else {
+            return Ok(None);
+        };
+
+        let mut stmt = conn
+            .prepare("select target from dispatched where file_id = ?1")
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Prepare select dispatched failed: {e}"),
+            })?;
+
+        let targets = stmt
+            .query_map(params![id], |row| row.get(0))
+            .and_then(|rows| rows.collect::<Result<Vec<String>, _>>())
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Select dispatched failed: {e}"),
+            })?;
+
+        Ok(Some(DispatchedFile { id, info, targets }))
+    }
+
+    fn rename_file(
+        &self,
+        source: &str,
+        path: &str,
+        new_path: &str,
+    ) -> Result<bool, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+
+        let updated = conn
+            .execute(
+                "update file set path = ?3
+                 where source = ?1 and path = ?2 and deleted is null",
+                params![source, path, new_path],
+            )
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Update file path failed: {e}"),
+            })?;
+
+        Ok(updated > 0)
+    }
+
+    fn register_download_failure(
+        &self,
+        source: &str,
+        path: &str,
+        code: &str,
+        error: &str,
+    ) -> Result<(), PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "insert into download_failure (source, path, code, error) values (?1, ?2, ?3, ?4)",
+            params![source, path, code, error],
+        )
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Insert download failure failed: {e}"),
+        })?;
+
+        Ok(())
+    }
+
+    fn try_lock_download(&self, key: i64, holder: &str) -> Result<bool, PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+
+        let acquired = conn
+            .execute(
+                "insert into download_lock (key, holder) values (?1, ?2)
+                 on conflict(key) do update set
+                   holder = excluded.holder, acquired = excluded.acquired
+                 where download_lock.acquired < datetime('now', ?3)",
+                params![
+                    key,
+                    holder,
+                    format!("-{} seconds", STALE_DOWNLOAD_LOCK.as_secs())
+                ],
+            )
+            .map_err(|e| PersistenceError::Logical {
+                message: format!("Insert download lock failed: {e}"),
+            })?;
+
+        Ok(acquired > 0)
+    }
+
+    fn unlock_download(&self, key: i64, holder: &str) -> Result<(), PersistenceError> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "delete from download_lock where key = ?1 and holder = ?2",
+            params![key, holder],
+        )
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Delete download lock failed: {e}"),
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Files registered in a dry run by source and path, with their ids
+type DryRunFiles = HashMap<(String, String), (i64, FileInfo)>;
+
+/// Persistence for a dry run, which reads from the wrapped persistence but
+/// keeps the files it registers in memory
+///
+/// Registered files get negative ids, so that they cannot be mistaken for
+/// files in the database.
+#[derive(Clone)]
+pub struct DryRunPersistence<T: Persistence> {
+    inner: T,
+    files: Arc<Mutex<DryRunFiles>>,
+}
+
+impl<T: Persistence> DryRunPersistence<T> {
+    pub fn new(inner: T) -> DryRunPersistence<T> {
+        DryRunPersistence {
+            inner,
+            files: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<T: Persistence> Persistence for DryRunPersistence<T> {
+    fn set_sftp_download_file(&self, id: i64, file_id: i64) -> Result<(), PersistenceError> {
+        debug!("Would set file {file_id} of SFTP download {id}");
+
+        Ok(())
+    }
+
+    fn set_file_discovered(
+        &self,
+        id: i64,
+        discovered: &DateTime<Utc>,
+    ) -> Result<(), PersistenceError> {
+        debug!("Would set discovery time {discovered} of file {id}");
+
+        Ok(())
+    }
+
+    fn delete_sftp_download_file(&self, id: i64) -> Result<(), PersistenceError> {
+        debug!("Would delete SFTP download {id}");
+
+        Ok(())
+    }
+
+    fn insert_file(
+        &self,
+        source: &str,
+        path: &str,
+        modified: &DateTime<Utc>,
+        size: i64,
+        hash: Option<String>,
+    ) -> Result<i64, PersistenceError> {
+        let mut files = self.files.lock().unwrap();
+
+        let key = (source.to_string(), path.to_string());
+
+        let id = match files.get(&key) {
+            Some((id, _)) => *id,
+            None => -(files.len() as i64) - 1,
+        };
+
+        let file_info = FileInfo {
+            modified: *modified,
+            size,
+            hash,
+        };
+
+        files.insert(key, (id, file_info));
+
+        debug!("Would register '{path}' of source '{source}'");
+
+        Ok(id)
+    }
+
+    fn get_file(&self, source: &str, path: &str) -> Result<Option<FileInfo>, PersistenceError> {
+        let registered = self
+            .files
+            .lock()
+            .unwrap()
+            .get(&(source.to_string(), path.to_string()))
+            .map(|(_, file_info)| file_info.clone());
+
+        match registered {
+            Some(file_info) => Ok(Some(file_info)),
+            None => self.inner.get_file(source, path),
+        }
+    }
+
+    fn dispatched_file(
+        &self,
+        source: &str,
+        path: &str,
+    ) -> Result<Option<DispatchedFile>, PersistenceError> {
+        let registered = self
+            .files
+            .lock()
+            .unwrap()
+            .get(&(source.to_string(), path.to_string()))
+            .map(|(id, info)| DispatchedFile {
+                id: *id,
+                info: info.clone(),
+                targets: Vec::new(),
+            });
+
+        match registered {
+            Some(file) => Ok(Some(file)),
+            None => self.inner.dispatched_file(source, path),
+        }
+    }
+
+    fn rename_file(
+        &self,
+        source: &str,
+        path: &str,
+        new_path: &str,
+    ) -> Result<bool, PersistenceError> {
+        debug!("Would register '{path}' of source '{source}' as '{new_path}'");
+
+        Ok(true)
+    }
+
+    fn register_remote_delete_failure(
+        &self,
+        source: &str,
+        path: &str,
+        error: &str,
+    ) -> Result<(), PersistenceError> {
+        debug!("Would register failed removal of '{path}' of source '{source}': {error}");
+
+        Ok(())
+    }
+
+    fn clear_remote_delete_failure(
+        &self,
+        source: &str,
+        path: &str,
+    ) -> Result<(), PersistenceError> {
+        debug!("Would clear failed removal of '{path}' of source '{source}'");
+
+        Ok(())
+    }
+
+    fn remote_delete_failures(
+        &self,
+        _source: &str,
+        _limit: usize,
+    ) -> Result<Vec<String>, PersistenceError> {
+        // Files are not removed in a dry run
+        Ok(Vec::new())
+    }
+
+    fn register_download_failure(
+        &self,
+        source: &str,
+        path: &str,
+        code: &str,
+        error: &str,
+    ) -> Result<(), PersistenceError> {
+        debug!("Would register failed download of '{path}' of source '{source}': [{code}] {error}");
+
+        Ok(())
+    }
+
+    fn try_lock_download(&self, _key: i64, _holder: &str) -> Result<bool, PersistenceError> {
+        // Files are not stored in a dry run, so other instances are not
+        // affected by downloading the same file
+        Ok(true)
+    }
+
+    fn unlock_download(&self, _key: i64, _holder: &str) -> Result<(), PersistenceError> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct SqliteAsyncPersistence {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteAsyncPersistence {
+    pub fn new(conn: Arc<Mutex<Connection>>) -> SqliteAsyncPersistence {
+        SqliteAsyncPersistence { conn }
+    }
+
+    /// Check that the database responds
+    pub async fn check(&self) -> Result<(), PersistenceError> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            conn.query_row("select 1", [], |_| Ok(()))
+                .map_err(|e| PersistenceError::Logical {
+                    message: format!("Error checking database: {e}"),
+                })
+        })
+        .await
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Join error checking database: {e}"),
+        })?
+    }
+
+    pub async fn query_files(&self, query: FileQuery) -> Result<Vec<FileRecord>, PersistenceError> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            query_files(&conn, &query)
+        })
+        .await
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Join error querying files: {e}"),
         })?
     }
+
+    pub async fn insert_file(
+        &self,
+        source: &str,
+        path: &str,
+        modified: &DateTime<Utc>,
+        size: i64,
+        hash: Option<String>,
+    ) -> Result<i64, PersistenceError> {
+        let persistence = SqlitePersistence::from_arc(self.conn.clone());
+        let source = source.to_string();
+        let path = path.to_string();
+        let modified = *modified;
+        tokio::task::spawn_blocking(move || {
+            persistence.insert_file(&source, &path, &modified, size, hash)
+        })
+        .await
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Join error inserting file: {e}"),
+        })?
+    }
+
+    pub async fn insert_dispatched(
+        &self,
+        dest: &str,
+        file_id: i64,
+    ) -> Result<(), PersistenceError> {
+        let conn = self.conn.clone();
+        let dest = dest.to_string();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            insert_dispatched(&conn, &dest, file_id)
+        })
+        .await
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Join error inserting dispatched: {e}"),
+        })?
+    }
+
+    pub async fn insert_dispatch_decisions(
+        &self,
+        decisions: Vec<DispatchDecision>,
+    ) -> Result<(), PersistenceError> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            insert_dispatch_decisions(&mut conn, &decisions)
+        })
+        .await
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Join error inserting dispatch decisions: {e}"),
+        })?
+    }
+
+    pub async fn dispatch_decisions(
+        &self,
+        file_id: i64,
+    ) -> Result<Vec<DispatchDecision>, PersistenceError> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            dispatch_decisions(&conn, file_id)
+        })
+        .await
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Join error querying dispatch decisions: {e}"),
+        })?
+    }
+
+    pub async fn file_stats_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<FileStats>, PersistenceError> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            file_stats_since(&conn, &since)
+        })
+        .await
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Join error querying file statistics: {e}"),
+        })?
+    }
+
+    pub async fn failure_stats_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<FailureStats>, PersistenceError> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            failure_stats_since(&conn, &since)
+        })
+        .await
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Join error querying failure statistics: {e}"),
+        })?
+    }
+
+    pub async fn upsert_heartbeat(
+        &self,
+        component: &str,
+        instance: &str,
+        details: serde_json::Value,
+    ) -> Result<(), PersistenceError> {
+        let conn = self.conn.clone();
+        let component = component.to_string();
+        let instance = instance.to_string();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            cortex_core::heartbeat::upsert_heartbeat(&conn, &component, &instance, &details)
+                .map_err(|message| PersistenceError::Logical { message })
+        })
+        .await
+        .map_err(|e| PersistenceError::Logical {
+            message: format!("Join error updating heartbeat: {e}"),
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_locks_are_held_by_one_instance() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let persistence = SqlitePersistence::from_arc(Arc::new(Mutex::new(conn)));
+
+        let key = download_lock_key("red", "upload/data.csv");
+
+        assert_eq!(key, download_lock_key("red", "upload/data.csv"));
+        assert_ne!(key, download_lock_key("red", "upload/other.csv"));
+        assert_ne!(
+            download_lock_key("ab", "c.csv"),
+            download_lock_key("a", "bc.csv")
+        );
+
+        assert!(persistence.try_lock_download(key, "first:1").unwrap());
+        assert!(!persistence.try_lock_download(key, "second:1").unwrap());
+
+        // Only the holder releases the lock
+        persistence.unlock_download(key, "second:1").unwrap();
+        assert!(!persistence.try_lock_download(key, "second:1").unwrap());
+
+        persistence.unlock_download(key, "first:1").unwrap();
+        assert!(persistence.try_lock_download(key, "second:1").unwrap());
+    }
+    #[test]
+    fn dispatched_files_list_their_targets() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let persistence = SqlitePersistence::from_arc(Arc::new(Mutex::new(conn)));
+
+        assert!(persistence
+            .dispatched_file("red", "upload/data.csv")
+            .unwrap()
+            .is_none());
+
+        let file_id = persistence
+            .insert_file("red", "upload/data.csv", &Utc::now(), 12, None)
+            .unwrap();
+        persistence.insert_dispatched("blue", file_id).unwrap();
+
+        let file = persistence
+            .dispatched_file("red", "upload/data.csv")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(file.id, file_id);
+        assert_eq!(file.info.size, 12);
+        assert_eq!(file.targets, vec!["blue".to_string()]);
+    }
 }
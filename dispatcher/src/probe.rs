@@ -0,0 +1,442 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::Duration;
+
+use log::info;
+use serde::Serialize;
+
+use deadpool_lapin::lapin::options::QueueDeclareOptions;
+use deadpool_lapin::lapin::types::FieldTable;
+
+use crate::amqp;
+use crate::settings::{self, AmqpTls, LocalTargetMethod, Settings, DEFAULT_STORAGE_AREA};
+
+/// Outcome of a single configuration or environment check, where a check
+/// that passed can have a message as a warning
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    pub message: Option<String>,
+}
+
+impl Check {
+    pub fn from_result<S: Into<String>>(name: S, result: Result<(), String>) -> Check {
+        match result {
+            Ok(()) => Check {
+                name: name.into(),
+                passed: true,
+                message: None,
+            },
+            Err(e) => Check {
+                name: name.into(),
+                passed: false,
+                message: Some(e),
+            },
+        }
+    }
+
+    pub fn warning<S: Into<String>>(name: S, message: String) -> Check {
+        Check {
+            name: name.into(),
+            passed: true,
+            message: Some(message),
+        }
+    }
+}
+
+/// Check the storage directories and the directories of the directory
+/// sources and targets, creating missing ones with `create_missing` set when
+/// `create` is true
+///
+/// Sources and hardlinking targets on another filesystem than the storage
+/// area they link with get a warning, because files cannot be hardlinked
+/// between them.
+pub fn check_directories(settings: &Settings, create: bool) -> Vec<Check> {
+    let mut checks: Vec<Check> = settings
+        .storage
+        .iter()
+        .map(|area| {
+            let name = match area.name.as_str() {
+                DEFAULT_STORAGE_AREA => "storage directory".to_string(),
+                name => format!("storage area '{name}'"),
+            };
+
+            Check::from_result(
+                name,
+                check_usable_directory(&area.directory, area.create_missing, create),
+            )
+        })
+        .collect();
+
+    // Files are hardlinked between the storage and linked directories
+    let check =
+        |name: String, directory: &Path, create_missing: bool, storage_directories: &[&Path]| {
+            let result = check_usable_directory(directory, create_missing, create);
+            let usable = result.is_ok();
+
+            let mut checks = vec![Check::from_result(name.clone(), result)];
+
+            if usable {
+                for storage_directory in storage_directories {
+                    if let Err(e) = check_same_filesystem(directory, storage_directory) {
+                        checks.push(Check::warning(name.clone(), e));
+                    }
+                }
+            }
+
+            checks
+        };
+
+    for directory_source in &settings.directory_sources {
+        checks.append(&mut check(
+            format!("directory source '{}'", directory_source.name),
+            &directory_source.directory,
+            directory_source.create_missing,
+            &[&settings.storage_area(&directory_source.name).directory],
+        ));
+    }
+
+    for directory_target in &settings.directory_targets {
+        // A target links with the storage areas of the sources connected to it
+        let mut storage_directories: Vec<&Path> = Vec::new();
+
+        if matches!(directory_target.method, LocalTargetMethod::Hardlink) {
+            for connection in &settings.connections {
+                let directory = settings
+                    .storage_area(&connection.source)
+                    .directory
+                    .as_path();
+
+                if connection.target == directory_target.name
+                    && !storage_directories.contains(&directory)
+                {
+                    storage_directories.push(directory);
+                }
+            }
+        }
+
+        checks.append(&mut check(
+            format!("directory target '{}'", directory_target.name),
+            &directory_target.directory,
+            directory_target.create_missing,
+            &storage_directories,
+        ));
+    }
+
+    checks
+}
+
+/// Check that a directory is writable, where a missing directory is created
+/// when `create` is true, or only checked to be creatable otherwise
+fn check_usable_directory(path: &Path, create_missing: bool, create: bool) -> Result<(), String> {
+    if path.exists() {
+        return check_writable_dir(path);
+    }
+
+    if !create_missing {
+        return Err(format!(
+            "'{}' does not exist and create_missing is not set",
+            path.to_string_lossy()
+        ));
+    }
+
+    if !create {
+        return check_directory(path);
+    }
+
+    std::fs::create_dir_all(path)
+        .map_err(|e| format!("Error creating directory '{}': {e}", path.to_string_lossy()))?;
+
+    info!("Created directory '{}'", path.to_string_lossy());
+
+    Ok(())
+}
+
+/// Check that two directories are on the same filesystem, taking the nearest
+/// existing ancestor of directories that do not exist yet
+fn check_same_filesystem(path: &Path, other: &Path) -> Result<(), String> {
+    let device = |path: &Path| {
+        path.ancestors()
+            .find_map(|ancestor| std::fs::metadata(ancestor).ok())
+            .map(|metadata| metadata.dev())
+    };
+
+    match (device(path), device(other)) {
+        (Some(device), Some(other_device)) if device != other_device => Err(format!(
+            "'{}' is on another filesystem than the storage directory '{}', so files cannot be hardlinked between them",
+            path.to_string_lossy(),
+            other.to_string_lossy()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Check that a directory exists, or that it can be created because its
+/// nearest existing ancestor is a writable directory.
+pub fn check_directory(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        return check_writable_dir(path);
+    }
+
+    let mut ancestor = path.parent();
+
+    while let Some(a) = ancestor {
+        let a = if a.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            a
+        };
+
+        if a.exists() {
+            return check_writable_dir(a).map_err(|e| {
+                format!(
+                    "'{}' does not exist and cannot be created: {}",
+                    path.to_string_lossy(),
+                    e
+                )
+            });
+        }
+
+        ancestor = a.parent();
+    }
+
+    Err(format!(
+        "'{}' does not exist and has no existing parent",
+        path.to_string_lossy()
+    ))
+}
+
+fn check_writable_dir(path: &Path) -> Result<(), String> {
+    let metadata = std::fs::metadata(path)
+        .map_err(|e| format!("Could not read '{}': {}", path.to_string_lossy(), e))?;
+
+    if !metadata.is_dir() {
+        return Err(format!("'{}' is not a directory", path.to_string_lossy()));
+    }
+
+    if metadata.permissions().readonly() {
+        return Err(format!("'{}' is read-only", path.to_string_lossy()));
+    }
+
+    Ok(())
+}
+
+/// Run a blocking probe on the blocking thread pool, giving up after the
+/// timeout.
+async fn run_blocking<F>(timeout: Duration, probe: F) -> Result<(), String>
+where
+    F: FnOnce() -> Result<(), String> + Send + 'static,
+{
+    match tokio::time::timeout(timeout, tokio::task::spawn_blocking(probe)).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => Err(format!("Probe task failed: {e}")),
+        Err(_) => Err(format!("Timed out after {} seconds", timeout.as_secs())),
+    }
+}
+
+/// Connect to an AMQP server
+pub async fn probe_amqp(
+    address: &str,
+    tls: Option<&AmqpTls>,
+    timeout: Duration,
+) -> Result<(), String> {
+    tokio::time::timeout(timeout, amqp::connect(address, tls))
+        .await
+        .map_err(|_| format!("Timed out after {} seconds", timeout.as_secs()))?
+        .map(|_| ())
+}
+
+/// Open the SQLite database and run a trivial query
+///
+/// A database file that does not exist yet passes, because it is created on
+/// startup of the service.
+pub async fn probe_sqlite(path: &Path, timeout: Duration) -> Result<(), String> {
+    let path = path.to_path_buf();
+
+    run_blocking(timeout, move || {
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let conn = rusqlite::Connection::open(&path)
+            .map_err(|e| format!("Could not open SQLite database: {e}"))?;
+
+        conn.query_row("select 1", [], |row| row.get::<_, i64>(0))
+            .map(|_| ())
+            .map_err(|e| format!("Could not query SQLite database: {e}"))
+    })
+    .await
+}
+
+/// Connect and authenticate to an SFTP source
+pub async fn probe_sftp(
+    sftp_source: &settings::SftpSource,
+    timeout: Duration,
+) -> Result<(), String> {
+    let sftp_config = sftp_source.sftp_config();
+
+    run_blocking(timeout, move || {
+        sftp_config.connect().map(|_| ()).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+/// Split an AMQP address into host and port, applying the default AMQP port
+/// when none is specified.
+pub fn amqp_host_port(address: &str) -> Result<(String, u16), String> {
+    if let Ok(url) = url::Url::parse(address) {
+        if let Some(host) = url.host_str() {
+            let default_port = match url.scheme() {
+                "amqps" => 5671,
+                _ => 5672,
+            };
+
+            return Ok((host.to_string(), url.port().unwrap_or(default_port)));
+        }
+    }
+
+    // Addresses without a scheme in the form host:port
+    match address.rsplit_once(':') {
+        Some((host, port)) => port
+            .parse::<u16>()
+            .map(|port| (host.to_string(), port))
+            .map_err(|e| format!("Invalid port in address '{address}': {e}")),
+        None => Ok((address.to_string(), 5672)),
+    }
+}
+
+/// Resolve a host name
+pub async fn probe_dns(host: &str, port: u16, timeout: Duration) -> Result<(), String> {
+    let addresses = tokio::time::timeout(timeout, tokio::net::lookup_host((host, port)))
+        .await
+        .map_err(|_| format!("Timed out after {} seconds", timeout.as_secs()))?
+        .map_err(|e| format!("Could not resolve '{host}': {e}"))?;
+
+    match addresses.count() {
+        0 => Err(format!("No addresses found for '{host}'")),
+        _ => Ok(()),
+    }
+}
+
+/// Open a TCP connection
+pub async fn probe_tcp(host: &str, port: u16, timeout: Duration) -> Result<(), String> {
+    tokio::time::timeout(timeout, tokio::net::TcpStream::connect((host, port)))
+        .await
+        .map_err(|_| format!("Timed out after {} seconds", timeout.as_secs()))?
+        .map(|_| ())
+        .map_err(|e| format!("Could not connect to {host}:{port}: {e}"))
+}
+
+/// Connect to an AMQP server and check that a queue exists
+pub async fn probe_amqp_queue(
+    address: &str,
+    tls: Option<&AmqpTls>,
+    queue: &str,
+    timeout: Duration,
+) -> Result<(), String> {
+    let check = async {
+        let connection = amqp::connect(address, tls).await?;
+
+        let channel = connection
+            .create_channel()
+            .await
+            .map_err(|e| format!("Could not create AMQP channel: {e}"))?;
+
+        channel
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    passive: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Queue '{queue}' is not available: {e}"))
+    };
+
+    tokio::time::timeout(timeout, check)
+        .await
+        .map_err(|_| format!("Timed out after {} seconds", timeout.as_secs()))?
+}
+
+/// Connect to an SFTP source and list the home directory
+pub async fn probe_sftp_readdir(
+    sftp_source: &settings::SftpSource,
+    timeout: Duration,
+) -> Result<(), String> {
+    let sftp_config = sftp_source.sftp_config();
+
+    run_blocking(timeout, move || {
+        let session = sftp_config.connect().map_err(|e| e.to_string())?;
+
+        let sftp = session
+            .sftp()
+            .map_err(|e| format!("Could not start SFTP subsystem: {e}"))?;
+
+        sftp.readdir(Path::new("."))
+            .map(|_| ())
+            .map_err(|e| format!("Could not list directory: {e}"))
+    })
+    .await
+}
+
+/// Check write access to a directory by creating and removing a file in it
+pub fn probe_write(directory: &Path) -> Result<(), String> {
+    let probe_path = directory.join(format!(".cortex-probe-{}", std::process::id()));
+
+    std::fs::write(&probe_path, b"").map_err(|e| {
+        format!(
+            "Could not write to '{}': {}",
+            directory.to_string_lossy(),
+            e
+        )
+    })?;
+
+    std::fs::remove_file(&probe_path)
+        .map_err(|e| format!("Could not remove '{}': {}", probe_path.to_string_lossy(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_directories_are_created_when_configured() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-probe-directories-{}", std::process::id()));
+
+        let mut settings = Settings::default();
+        settings.storage[0].directory = directory.join("storage");
+        settings.directory_sources[0].directory = directory.join("incoming");
+        settings.directory_targets[0].directory = directory.join("red");
+
+        let failed = |checks: Vec<Check>| -> Vec<String> {
+            checks
+                .into_iter()
+                .filter(|check| !check.passed)
+                .map(|check| check.name)
+                .collect()
+        };
+
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let probed = failed(check_directories(&settings, false));
+        let storage_probed = settings.storage[0].directory.exists();
+
+        let started = failed(check_directories(&settings, true));
+        let storage_created = settings.storage[0].directory.exists();
+        let target_created = settings.directory_targets[0].directory.exists();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // Directory sources are not created by default
+        assert_eq!(probed, vec!["directory source 'mixed-directory'"]);
+        assert!(!storage_probed);
+        assert_eq!(started, vec!["directory source 'mixed-directory'"]);
+        assert!(storage_created);
+        assert!(target_created);
+    }
+}
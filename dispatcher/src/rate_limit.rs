@@ -0,0 +1,81 @@
+use std::time::{Duration, Instant};
+
+use crate::settings;
+
+/// Token bucket that limits the rate of events, allowing a burst after a
+/// quiet period
+#[derive(Debug)]
+pub struct TokenBucket {
+    events_per_second: f64,
+    burst: f64,
+    /// Negative when events are waiting for tokens
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate_limit: &settings::RateLimit) -> TokenBucket {
+        TokenBucket {
+            events_per_second: rate_limit.events_per_second,
+            burst: f64::from(rate_limit.burst),
+            tokens: f64::from(rate_limit.burst),
+            updated: Instant::now(),
+        }
+    }
+
+    /// Wait until the next event is allowed
+    pub async fn acquire(&mut self) {
+        let delay = self.take(Instant::now());
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Take a token, returning the time until it is available
+    fn take(&mut self, now: Instant) -> Duration {
+        self.take_tokens(now, 1.0)
+    }
+
+    /// Take a number of tokens at once, e.g. one per byte, returning the time
+    /// until they are available
+    pub fn take_tokens(&mut self, now: Instant, tokens: f64) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.events_per_second).min(self.burst) - tokens;
+        self.updated = now;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.events_per_second)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_beyond_burst_are_delayed() {
+        let mut bucket = TokenBucket::new(&settings::RateLimit {
+            events_per_second: 2.0,
+            burst: 2,
+        });
+
+        let start = bucket.updated;
+
+        assert_eq!(bucket.take(start), Duration::ZERO);
+        assert_eq!(bucket.take(start), Duration::ZERO);
+        assert_eq!(bucket.take(start), Duration::from_millis(500));
+        assert_eq!(bucket.take(start), Duration::from_millis(1000));
+
+        // The delayed events have used the tokens that came available
+        assert_eq!(
+            bucket.take(start + Duration::from_secs(1)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(bucket.take(start + Duration::from_secs(10)), Duration::ZERO);
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentState {
+    Ready,
+    /// Working, but needs attention
+    Degraded,
+    NotReady,
+}
+
+/// State of the components that determine whether the dispatcher is ready,
+/// as reported by /readyz
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    components: Arc<RwLock<BTreeMap<String, ComponentState>>>,
+}
+
+impl Readiness {
+    pub fn set(&self, component: &str, state: ComponentState) {
+        self.components
+            .write()
+            .unwrap()
+            .insert(component.to_string(), state);
+    }
+
+    pub fn components(&self) -> BTreeMap<String, ComponentState> {
+        self.components.read().unwrap().clone()
+    }
+
+    /// Ready when no component is not ready, degraded components included
+    pub fn is_ready(&self) -> bool {
+        !self
+            .components
+            .read()
+            .unwrap()
+            .values()
+            .any(|state| *state == ComponentState::NotReady)
+    }
+}
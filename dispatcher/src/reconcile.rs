@@ -0,0 +1,268 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use tokio::sync::watch;
+
+use crate::directory_source::sha256_hash_file;
+use crate::event::{FileEvent, FileEventSender};
+use crate::persistence::{FileQuery, FileRecord, SqlitePersistence};
+use crate::rate_limit::TokenBucket;
+use crate::settings::{self, Decompress, Settings, StoredHash};
+
+/// Files in internal storage that were not dispatched to all targets
+/// connected to their source
+#[derive(Debug, Default)]
+pub struct Reconciliation {
+    /// Files to dispatch again, with the targets they were not dispatched to
+    pub undispatched: Vec<(FileRecord, Vec<String>)>,
+    /// Files that no longer exist or cannot be read
+    pub missing: Vec<FileRecord>,
+    /// Files of which the content no longer matches the registered hash
+    pub mismatched: Vec<FileRecord>,
+}
+
+/// Find the files registered since `since` that lack a dispatch to a target
+/// that is connected to their source, as far as the filter of the connection
+/// matches
+pub fn reconcile(
+    persistence: &SqlitePersistence,
+    settings: &Settings,
+    since: DateTime<Utc>,
+) -> Result<Reconciliation, String> {
+    let mut files: BTreeMap<i64, (FileRecord, Vec<String>)> = BTreeMap::new();
+
+    for connection in &settings.connections {
+        let query = FileQuery {
+            source: Some(connection.source.clone()),
+            since: Some(since),
+            undispatched_to: Some(connection.target.clone()),
+            ..Default::default()
+        };
+
+        let undispatched = persistence
+            .query_files(&query)
+            .map_err(|e| format!("Error querying undispatched files: {e}"))?;
+
+        for file in undispatched {
+            if let Some(filter) = &connection.filter {
+                if !filter.matches(&FileEvent::from(&file)) {
+                    continue;
+                }
+            }
+
+            files
+                .entry(file.id)
+                .or_insert_with(|| (file, Vec::new()))
+                .1
+                .push(connection.target.clone());
+        }
+    }
+
+    let mut reconciliation = Reconciliation::default();
+
+    for (_, (file, targets)) in files {
+        let path = Path::new(&file.path);
+
+        if !path.exists() {
+            reconciliation.missing.push(file);
+            continue;
+        }
+
+        match (&file.hash, hash_is_verifiable(settings, &file.source)) {
+            (Some(hash), true) => match hash_matches(path, hash) {
+                Ok(true) => reconciliation.undispatched.push((file, targets)),
+                Ok(false) => reconciliation.mismatched.push(file),
+                Err(e) => {
+                    error!("Error calculating hash of '{}': {}", &file.path, e);
+                    reconciliation.missing.push(file);
+                }
+            },
+            _ => reconciliation.undispatched.push((file, targets)),
+        }
+    }
+
+    Ok(reconciliation)
+}
+
+/// The registered hash of files that were decompressed on download is over
+/// the compressed file, unless configured otherwise
+pub fn hash_is_verifiable(settings: &Settings, source: &str) -> bool {
+    !settings.sftp_sources.iter().any(|sftp_source| {
+        sftp_source.name == source
+            && sftp_source.decompress != Decompress::None
+            && sftp_source.stored_hash == StoredHash::Original
+    })
+}
+
+/// Directory sources can register the hash of the decompressed content of
+/// .gz files
+fn hash_matches(path: &Path, hash: &str) -> Result<bool, std::io::Error> {
+    if sha256_hash_file(path, false)? == hash {
+        return Ok(true);
+    }
+
+    Ok(path.extension() == Some("gz".as_ref()) && sha256_hash_file(path, true)? == hash)
+}
+
+/// Dispatch the undispatched files of the reconciliation window on startup,
+/// through the file event channels of their sources
+///
+/// The files are dispatched again to all targets connected to their source,
+/// at the configured rate, until the stop signal.
+pub async fn reconcile_on_startup(
+    reconcile_conf: settings::Reconcile,
+    settings: Settings,
+    persistence: SqlitePersistence,
+    senders: HashMap<String, FileEventSender>,
+    mut stop_receiver: watch::Receiver<()>,
+) {
+    let since = Utc::now() - chrono::Duration::hours(reconcile_conf.window_hours as i64);
+
+    let reconcile_result =
+        tokio::task::spawn_blocking(move || reconcile(&persistence, &settings, since)).await;
+
+    let reconciliation = match reconcile_result {
+        Ok(Ok(reconciliation)) => reconciliation,
+        Ok(Err(e)) => {
+            error!("{e}");
+            return;
+        }
+        Err(e) => {
+            error!("Join error reconciling files: {e}");
+            return;
+        }
+    };
+
+    for file in &reconciliation.missing {
+        warn!(
+            "Not dispatching file {}: '{}' no longer exists",
+            file.id, &file.path
+        );
+    }
+
+    for file in &reconciliation.mismatched {
+        error!(
+            "Not dispatching file {}: '{}' does not match its registered hash",
+            file.id, &file.path
+        );
+    }
+
+    info!(
+        "Dispatching {} files that were not dispatched to all targets",
+        reconciliation.undispatched.len()
+    );
+
+    let mut rate_limiter = TokenBucket::new(&settings::RateLimit {
+        events_per_second: reconcile_conf.files_per_second,
+        burst: 1,
+    });
+
+    let dispatch = async {
+        for (file, targets) in &reconciliation.undispatched {
+            let Some(sender) = senders.get(&file.source) else {
+                warn!(
+                    "No source '{}' for dispatching file {}",
+                    &file.source, file.id
+                );
+                continue;
+            };
+
+            rate_limiter.acquire().await;
+
+            match sender.send(FileEvent::from(file)).await {
+                Ok(_) => debug!(
+                    "Dispatched file {} '{}' for targets {}",
+                    file.id,
+                    &file.path,
+                    targets.join(", ")
+                ),
+                Err(e) => error!("Could not dispatch file {}: {}", file.id, e),
+            }
+        }
+    };
+
+    tokio::select!(
+        _a = dispatch => info!("Reconciliation completed"),
+        _b = stop_receiver.changed() => ()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::persistence::Persistence;
+
+    #[test]
+    fn only_intact_undispatched_files_are_dispatched() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-reconcile-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let persistence = SqlitePersistence::from_arc(Arc::new(Mutex::new(conn)));
+
+        let settings = Settings {
+            connections: vec![settings::Connection {
+                source: "mixed-directory".to_string(),
+                target: "red".to_string(),
+                filter: None,
+                priority: 0,
+                require_delivery: false,
+                delivery_timeout_seconds: 300,
+            }],
+            ..Settings::default()
+        };
+
+        let insert = |name: &str, content: Option<&[u8]>, hash: &[u8]| {
+            let path = directory.join(name);
+
+            if let Some(content) = content {
+                std::fs::write(&path, content).unwrap();
+            }
+
+            let hash = hex::encode(<sha2::Sha256 as sha2::Digest>::digest(hash));
+
+            persistence
+                .insert_file(
+                    "mixed-directory",
+                    &path.to_string_lossy(),
+                    &Utc::now(),
+                    1,
+                    Some(hash),
+                )
+                .unwrap()
+        };
+
+        let undispatched = insert("undispatched.csv", Some(b"a"), b"a");
+        let dispatched = insert("dispatched.csv", Some(b"b"), b"b");
+        let missing = insert("missing.csv", None, b"c");
+        let mismatched = insert("mismatched.csv", Some(b"d"), b"changed");
+
+        persistence.insert_dispatched("red", dispatched).unwrap();
+
+        let since = Utc::now() - chrono::Duration::hours(1);
+
+        let reconciliation = reconcile(&persistence, &settings, since).unwrap();
+
+        let ids = |files: Vec<&FileRecord>| files.iter().map(|f| f.id).collect::<Vec<i64>>();
+
+        assert_eq!(
+            ids(reconciliation.undispatched.iter().map(|(f, _)| f).collect()),
+            vec![undispatched]
+        );
+        assert_eq!(reconciliation.undispatched[0].1, vec!["red".to_string()]);
+        assert_eq!(ids(reconciliation.missing.iter().collect()), vec![missing]);
+        assert_eq!(
+            ids(reconciliation.mismatched.iter().collect()),
+            vec![mismatched]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use log::info;
+
+use crate::settings::{RemoteRemove, SftpSource};
+
+/// The `remote_remove` setting of each SFTP source, shared by the download
+/// threads so that a reloaded configuration applies to commands that are
+/// already queued
+#[derive(Debug, Clone, Default)]
+pub struct RemoteRemovals(Arc<RwLock<HashMap<String, RemoteRemove>>>);
+
+impl RemoteRemovals {
+    pub fn new(sftp_sources: &[SftpSource]) -> RemoteRemovals {
+        RemoteRemovals(Arc::new(RwLock::new(
+            sftp_sources
+                .iter()
+                .map(|sftp_source| (sftp_source.name.clone(), sftp_source.remote_remove))
+                .collect(),
+        )))
+    }
+
+    /// Apply the settings of a reloaded configuration, of the sources that
+    /// were configured at startup
+    pub fn update(&self, sftp_sources: &[SftpSource]) {
+        let mut removals = self.0.write().unwrap();
+
+        for sftp_source in sftp_sources {
+            if let Some(remote_remove) = removals.get_mut(&sftp_source.name) {
+                if *remote_remove != sftp_source.remote_remove {
+                    info!(
+                        "Changed remote_remove of <{}> from {:?} to {:?}",
+                        sftp_source.name, remote_remove, sftp_source.remote_remove
+                    );
+
+                    *remote_remove = sftp_source.remote_remove;
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, source: &str) -> RemoteRemove {
+        self.0
+            .read()
+            .unwrap()
+            .get(source)
+            .copied()
+            .unwrap_or_default()
+    }
+}
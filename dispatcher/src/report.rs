@@ -0,0 +1,357 @@
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::Transport;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+
+use crate::persistence::{FailureStats, FileStats, SqlitePersistence};
+use crate::settings::{self, Report, ReportFormat, ReportTransport, SmtpTls};
+
+/// Number of times the delivery of a report is attempted before it is dropped
+const DELIVERY_ATTEMPTS: u32 = 3;
+
+/// Time between the attempts to deliver a report
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Time after which the delivery of a report is abandoned
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Stored files and failed downloads over the period of a report
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub report: String,
+    pub since: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    pub files: Vec<FileStats>,
+    pub failures: Vec<FailureStats>,
+}
+
+/// Sends a report at every interval or scheduled time
+pub struct ReportSender {
+    pub report: Report,
+    schedule: Option<cron::Schedule>,
+    pub persistence: SqlitePersistence,
+}
+
+impl ReportSender {
+    pub fn new(report: Report, persistence: SqlitePersistence) -> Result<ReportSender, String> {
+        let schedule = report
+            .schedule
+            .as_deref()
+            .map(cron::Schedule::from_str)
+            .transpose()
+            .map_err(|e| format!("Error parsing schedule of report '{}': {}", report.name, e))?;
+
+        Ok(ReportSender {
+            report,
+            schedule,
+            persistence,
+        })
+    }
+
+    /// Start the thread that sends the report, which never blocks the
+    /// processing of files
+    pub fn start(self, stop_flag: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            while let Some(next_run) = self.next_run(Utc::now()) {
+                debug!("Next '{}' report at {}", self.report.name, next_run);
+
+                let wait = (next_run - Utc::now()).to_std().unwrap_or_default();
+
+                if !sleep(wait, &stop_flag) {
+                    break;
+                }
+
+                self.send(Utc::now(), &stop_flag);
+            }
+
+            debug!("Report '{}' thread ended", self.report.name)
+        })
+    }
+
+    /// Time of the next report after the given time
+    fn next_run(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match (&self.schedule, self.report.interval_seconds) {
+            (Some(schedule), _) => schedule.after(&after).next(),
+            (None, Some(interval)) => Some(after + chrono::Duration::seconds(interval as i64)),
+            (None, None) => None,
+        }
+    }
+
+    /// Build the report up to the given time and deliver it, dropping it
+    /// when all attempts fail
+    fn send(&self, until: DateTime<Utc>, stop_flag: &AtomicBool) {
+        let body = match self.summary(until) {
+            Ok(summary) => render(&summary, self.report.format),
+            Err(e) => {
+                error!("Error building report '{}': {}", self.report.name, e);
+                return;
+            }
+        };
+
+        for attempt in 1..=DELIVERY_ATTEMPTS {
+            match self.deliver(&body) {
+                Ok(()) => {
+                    info!("Sent report '{}'", self.report.name);
+                    return;
+                }
+                Err(e) if attempt < DELIVERY_ATTEMPTS => {
+                    warn!(
+                        "Error sending report '{}' (attempt {}/{}): {}",
+                        self.report.name, attempt, DELIVERY_ATTEMPTS, e
+                    );
+
+                    if !sleep(RETRY_DELAY, stop_flag) {
+                        return;
+                    }
+                }
+                Err(e) => error!(
+                    "Error sending report '{}', dropping it after {} attempts: {}",
+                    self.report.name, DELIVERY_ATTEMPTS, e
+                ),
+            }
+        }
+    }
+
+    /// Aggregate the stored files and failed downloads of the report period
+    pub fn summary(&self, until: DateTime<Utc>) -> Result<Summary, String> {
+        let since = until - chrono::Duration::seconds(self.report.period_seconds() as i64);
+
+        let mut files = self
+            .persistence
+            .file_stats_since(&since)
+            .map_err(|e| format!("Error querying file statistics: {e}"))?;
+
+        let mut failures = self
+            .persistence
+            .failure_stats_since(&since)
+            .map_err(|e| format!("Error querying failure statistics: {e}"))?;
+
+        if !self.report.sources.is_empty() {
+            files.retain(|stats| self.report.sources.contains(&stats.source));
+            failures.retain(|stats| self.report.sources.contains(&stats.source));
+        }
+
+        Ok(Summary {
+            report: self.report.name.clone(),
+            since,
+            until,
+            files,
+            failures,
+        })
+    }
+
+    fn deliver(&self, body: &str) -> Result<(), String> {
+        let content_type = match self.report.format {
+            ReportFormat::Json => "application/json",
+            ReportFormat::Text => "text/plain; charset=utf-8",
+        };
+
+        match &self.report.transport {
+            ReportTransport::Webhook(webhook) => {
+                let agent = ureq::Agent::new_with_config(
+                    ureq::Agent::config_builder()
+                        .timeout_global(Some(DELIVERY_TIMEOUT))
+                        .build(),
+                );
+
+                agent
+                    .post(webhook.url.expose())
+                    .header("Content-Type", content_type)
+                    .send(body)
+                    .map_err(|e| format!("Error posting to webhook: {e}"))?;
+
+                Ok(())
+            }
+            ReportTransport::Smtp(smtp) => send_mail(smtp, content_type, body),
+        }
+    }
+}
+
+fn send_mail(smtp: &settings::SmtpTransport, content_type: &str, body: &str) -> Result<(), String> {
+    let mut builder = lettre::Message::builder()
+        .from(
+            smtp.from
+                .parse()
+                .map_err(|e| format!("Error parsing sender '{}': {}", smtp.from, e))?,
+        )
+        .subject(&smtp.subject)
+        .header(
+            ContentType::parse(content_type)
+                .map_err(|e| format!("Error parsing content type: {e}"))?,
+        );
+
+    for to in &smtp.to {
+        builder = builder.to(to
+            .parse()
+            .map_err(|e| format!("Error parsing recipient '{to}': {e}"))?);
+    }
+
+    let message = builder
+        .body(body.to_string())
+        .map_err(|e| format!("Error building message: {e}"))?;
+
+    let transport = match smtp.tls {
+        SmtpTls::None => Ok(lettre::SmtpTransport::builder_dangerous(&smtp.server)),
+        SmtpTls::StartTls => lettre::SmtpTransport::starttls_relay(&smtp.server),
+        SmtpTls::Tls => lettre::SmtpTransport::relay(&smtp.server),
+    }
+    .map_err(|e| format!("Error connecting to '{}': {}", smtp.server, e))?
+    .port(smtp.port)
+    .timeout(Some(DELIVERY_TIMEOUT));
+
+    let transport = match (&smtp.username, &smtp.password) {
+        (Some(username), Some(password)) => transport.credentials(Credentials::new(
+            username.clone(),
+            password.expose().to_string(),
+        )),
+        _ => transport,
+    };
+
+    transport
+        .build()
+        .send(&message)
+        .map_err(|e| format!("Error sending mail: {e}"))?;
+
+    Ok(())
+}
+
+/// Sleep for the duration, returning false when stopped before it passed
+fn sleep(duration: Duration, stop_flag: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+
+    while Instant::now() < deadline {
+        if stop_flag.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        thread::sleep(Duration::from_millis(500).min(deadline - Instant::now()));
+    }
+
+    !stop_flag.load(Ordering::Relaxed)
+}
+
+/// Render the summary in the format of the report
+pub fn render(summary: &Summary, format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Json => serde_json::to_string_pretty(summary).unwrap_or_default(),
+        ReportFormat::Text => render_text(summary),
+    }
+}
+
+fn render_text(summary: &Summary) -> String {
+    let mut text = format!(
+        "Report '{}' from {} to {}\n\nFiles stored:\n",
+        summary.report,
+        summary.since.format("%Y-%m-%d %H:%M:%S UTC"),
+        summary.until.format("%Y-%m-%d %H:%M:%S UTC"),
+    );
+
+    if summary.files.is_empty() {
+        text.push_str("  none\n");
+    }
+
+    for stats in &summary.files {
+        let _ = writeln!(
+            text,
+            "  {}: {} files, {} bytes",
+            stats.source, stats.files, stats.bytes
+        );
+    }
+
+    text.push_str("\nFailed downloads:\n");
+
+    if summary.failures.is_empty() {
+        text.push_str("  none\n");
+    }
+
+    for stats in &summary.failures {
+        let _ = writeln!(
+            text,
+            "  {}: {} {}",
+            stats.source, stats.failures, stats.code
+        );
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::persistence::Persistence;
+    use crate::settings::WebhookTransport;
+
+    #[test]
+    fn summary_counts_files_and_failures_of_included_sources() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+
+        let persistence = SqlitePersistence::from_arc(Arc::new(Mutex::new(conn)));
+
+        for (source, path, size) in [("red", "/a", 10), ("red", "/b", 5), ("blue", "/c", 7)] {
+            persistence
+                .insert_file(source, path, &Utc::now(), size, None)
+                .unwrap();
+        }
+
+        persistence
+            .register_download_failure("red", "/d", "timeout", "Timed out")
+            .unwrap();
+        persistence
+            .register_download_failure("blue", "/e", "auth", "Access denied")
+            .unwrap();
+
+        let sender = ReportSender::new(
+            Report {
+                name: "daily".to_string(),
+                interval_seconds: Some(3600),
+                schedule: None,
+                period_seconds: None,
+                format: ReportFormat::Text,
+                sources: vec!["red".to_string()],
+                transport: ReportTransport::Webhook(WebhookTransport {
+                    url: "http://127.0.0.1:1/report".into(),
+                }),
+            },
+            persistence,
+        )
+        .unwrap();
+
+        let summary = sender
+            .summary(Utc::now() + chrono::Duration::seconds(1))
+            .unwrap();
+
+        assert_eq!(
+            summary.files,
+            vec![FileStats {
+                source: "red".to_string(),
+                files: 2,
+                bytes: 15,
+            }]
+        );
+        assert_eq!(summary.failures.len(), 1);
+        assert_eq!(summary.failures[0].code, "timeout");
+
+        let text = render(&summary, ReportFormat::Text);
+
+        assert!(text.contains("  red: 2 files, 15 bytes\n"));
+        assert!(text.contains("  red: 1 timeout\n"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&summary, ReportFormat::Json)).unwrap();
+
+        assert_eq!(json["files"][0]["bytes"], 15);
+    }
+}
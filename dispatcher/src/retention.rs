@@ -0,0 +1,304 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use log::{debug, error, info};
+
+use crate::local_storage::{object_path, StorageArea};
+use crate::metrics;
+use crate::persistence::{ExpiredFile, SqlitePersistence};
+use crate::settings;
+use crate::storage_usage::StorageUsage;
+
+/// Number of expired files that are read from the database at once
+const CLEANUP_BATCH: usize = 1000;
+
+/// Removes files from an area of internal storage after the retention period
+pub struct RetentionCleanup {
+    pub retention: settings::Retention,
+    pub directory: PathBuf,
+    pub layout: settings::StorageLayout,
+    /// Targets connected to each source
+    pub targets: HashMap<String, HashSet<String>>,
+    pub persistence: SqlitePersistence,
+    pub usage: StorageUsage,
+}
+
+impl RetentionCleanup {
+    pub fn new(
+        retention: settings::Retention,
+        area: &StorageArea,
+        connections: &[settings::Connection],
+        persistence: SqlitePersistence,
+    ) -> RetentionCleanup {
+        let mut targets: HashMap<String, HashSet<String>> = HashMap::new();
+
+        for connection in connections {
+            targets
+                .entry(connection.source.clone())
+                .or_default()
+                .insert(connection.target.clone());
+        }
+
+        RetentionCleanup {
+            retention,
+            directory: area.directory.clone(),
+            layout: area.layout,
+            targets,
+            persistence,
+            usage: area.usage.clone(),
+        }
+    }
+
+    /// Start the thread that cleans up at every interval
+    pub fn start(self, stop_flag: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let interval = Duration::from_millis(self.retention.interval);
+            let mut next_cleanup = Instant::now();
+
+            while !stop_flag.load(Ordering::Relaxed) {
+                if Instant::now() < next_cleanup {
+                    thread::sleep(Duration::from_millis(500));
+                    continue;
+                }
+
+                match self.cleanup(&stop_flag) {
+                    Ok(0) => debug!("No files to remove from storage"),
+                    Ok(count) => info!("Removed {count} files from storage after retention"),
+                    Err(e) => error!("Error cleaning up storage: {e}"),
+                }
+
+                next_cleanup = Instant::now() + interval;
+            }
+
+            debug!("Retention cleanup thread ended")
+        })
+    }
+
+    /// Remove the expired files from storage and mark them deleted, returning
+    /// the number of files removed
+    pub fn cleanup(&self, stop_flag: &AtomicBool) -> Result<usize, String> {
+        let before = Utc::now() - chrono::Duration::days(i64::from(self.retention.keep_days));
+        let mut after_id = 0;
+        let mut removed = 0;
+
+        loop {
+            let files = self
+                .persistence
+                .expired_files(&before, after_id, CLEANUP_BATCH)
+                .map_err(|e| format!("Error querying expired files: {e}"))?;
+
+            let last_id = match files.last() {
+                Some(file) => file.id,
+                None => return Ok(removed),
+            };
+
+            for file in files {
+                if stop_flag.load(Ordering::Relaxed) {
+                    return Ok(removed);
+                }
+
+                // Files of other areas have a retention of their own
+                if !Path::new(&file.path).starts_with(&self.directory) {
+                    continue;
+                }
+
+                if self.retention.only_if_dispatched && !self.is_dispatched(&file) {
+                    continue;
+                }
+
+                // Hard links in directory targets keep their content
+                match fs::remove_file(&file.path) {
+                    Ok(()) => self.release(&file),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        error!("Error removing '{}' from storage: {}", &file.path, e);
+                        continue;
+                    }
+                }
+
+                self.persistence
+                    .mark_file_deleted(file.id)
+                    .map_err(|e| format!("Error marking file {} deleted: {}", file.id, e))?;
+
+                metrics::STORAGE_FILES_REMOVED_COUNTER
+                    .with_label_values(&[&file.source])
+                    .inc();
+
+                removed += 1;
+            }
+
+            after_id = last_id;
+        }
+    }
+
+    /// Account for the removal of a file from storage. In the
+    /// content-addressed layout, its object is removed as well when no other
+    /// file links to it anymore.
+    fn release(&self, file: &ExpiredFile) {
+        let hash = match (self.layout, &file.hash) {
+            (settings::StorageLayout::ContentAddressed, Some(hash)) => hash,
+            _ => return self.usage.remove(&file.source, file.size as u64),
+        };
+
+        let object_path = object_path(&self.directory, hash);
+
+        let result = fs::metadata(&object_path).and_then(|metadata| {
+            if metadata.nlink() == 1 {
+                fs::remove_file(&object_path).map(|_| true)
+            } else {
+                Ok(false)
+            }
+        });
+
+        match result {
+            Ok(true) => self.usage.remove(&file.source, file.size as u64),
+            Ok(false) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!(
+                "Error removing object '{}' from storage: {}",
+                object_path.to_string_lossy(),
+                e
+            ),
+        }
+    }
+
+    /// Whether the file has been dispatched to all targets connected to its
+    /// source
+    fn is_dispatched(&self, file: &ExpiredFile) -> bool {
+        self.targets.get(&file.source).is_none_or(|targets| {
+            targets
+                .iter()
+                .all(|target| file.targets.iter().any(|t| t == target))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use crate::persistence::{FileQuery, Persistence};
+    use crate::readiness::Readiness;
+    use crate::settings::Settings;
+
+    #[test]
+    fn cleanup_removes_dispatched_files_and_keeps_records() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-retention-{}", std::process::id()));
+        let area_directory = directory.join("area");
+        fs::create_dir_all(&area_directory).unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+
+        let persistence = SqlitePersistence::from_arc(Arc::new(Mutex::new(conn)));
+
+        let insert = |name: &str, targets: &[&str]| -> PathBuf {
+            let path = directory.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "data").unwrap();
+
+            let file_id = persistence
+                .insert_file("red", &path.to_string_lossy(), &Utc::now(), 4, None)
+                .unwrap();
+
+            for target in targets {
+                persistence.insert_dispatched(target, file_id).unwrap();
+            }
+
+            path
+        };
+
+        let dispatched = insert("area/dispatched.csv", &["blue", "green"]);
+        let undispatched = insert("area/undispatched.csv", &["blue"]);
+        let other_area = insert("other/dispatched.csv", &["blue", "green"]);
+
+        let settings = Settings::default();
+
+        let area = StorageArea::new(
+            settings::DEFAULT_STORAGE_AREA,
+            &area_directory,
+            settings::StorageLayout::PerSource,
+            StorageUsage::new(&settings.storage[0], Readiness::default()),
+        );
+
+        let cleanup = RetentionCleanup::new(
+            settings::Retention {
+                keep_days: 0,
+                only_if_dispatched: true,
+                interval: 1000,
+            },
+            &area,
+            &[
+                settings::Connection {
+                    source: "red".to_string(),
+                    target: "blue".to_string(),
+                    filter: None,
+                    priority: 0,
+                    require_delivery: false,
+                    delivery_timeout_seconds: 300,
+                },
+                settings::Connection {
+                    source: "red".to_string(),
+                    target: "green".to_string(),
+                    filter: None,
+                    priority: 0,
+                    require_delivery: false,
+                    delivery_timeout_seconds: 300,
+                },
+            ],
+            persistence.clone(),
+        );
+
+        // The files are registered with a timestamp in whole seconds
+        thread::sleep(Duration::from_millis(1100));
+
+        let removed = cleanup.cleanup(&AtomicBool::new(false));
+
+        let dispatched_exists = dispatched.exists();
+        let undispatched_exists = undispatched.exists();
+        let other_area_exists = other_area.exists();
+
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(removed, Ok(1));
+        assert!(!dispatched_exists);
+        assert!(undispatched_exists);
+        assert!(other_area_exists);
+
+        let files = persistence
+            .query_files(&FileQuery {
+                include_deleted: true,
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert!(files[0].deleted.is_some());
+        assert!(files[1].deleted.is_none());
+
+        // Ingesting the same path again registers a new file
+        let dispatched_path = dispatched.to_string_lossy();
+
+        assert!(persistence
+            .get_file("red", &dispatched_path)
+            .unwrap()
+            .is_none());
+
+        let file_id = persistence
+            .insert_file("red", &dispatched_path, &Utc::now(), 4, None)
+            .unwrap();
+
+        assert!(file_id > files[1].id);
+    }
+}
@@ -0,0 +1,349 @@
+//! One-shot download of the files of an SFTP source, which are dispatched to
+//! the connected directory targets right away, without the command queue
+//!
+//! Files are downloaded the same way as by the service, so that the
+//! deduplication of the source skips the files of an earlier run.
+
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use log::warn;
+
+use cortex_core::error::DispatcherError;
+use cortex_core::filter::Filter;
+use cortex_core::remote_fs::RemoteFs;
+use cortex_core::SftpDownload;
+
+use crate::directory_target::{handle_file_event, record_dispatched};
+use crate::event::FileEvent;
+use crate::notifier::{NamedNotifiers, Notifier};
+use crate::persistence::{Persistence, SqliteAsyncPersistence};
+use crate::settings;
+use crate::sftp_downloader::SftpDownloader;
+
+/// Number of files per outcome of a one-shot run
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Paths and sizes of the files in the remote directory and its
+/// subdirectories
+pub fn remote_files<R: RemoteFs>(
+    fs: &R,
+    directory: &Path,
+) -> Result<Vec<(PathBuf, Option<u64>)>, String> {
+    let mut dir = fs.opendir(directory).map_err(|e| {
+        format!(
+            "Error listing remote directory '{}': {}",
+            directory.to_string_lossy(),
+            e
+        )
+    })?;
+
+    let mut files = Vec::new();
+
+    while let Some((name, stat)) = fs.readdir(&mut dir).map_err(|e| {
+        format!(
+            "Error listing remote directory '{}': {}",
+            directory.to_string_lossy(),
+            e
+        )
+    })? {
+        if name == Path::new(".") || name == Path::new("..") {
+            continue;
+        }
+
+        let path = if directory == Path::new(".") {
+            name
+        } else {
+            directory.join(name)
+        };
+
+        if stat.is_dir() {
+            files.extend(remote_files(fs, &path)?);
+        } else {
+            files.push((path, stat.size));
+        }
+    }
+
+    Ok(files)
+}
+
+/// Directory target connected to the source, with the filter of the
+/// connection
+struct ConnectedTarget {
+    settings: settings::DirectoryTarget,
+    filter: Option<Filter>,
+    notifier: Option<Box<dyn Notifier + Send>>,
+}
+
+/// Dispatches the downloaded files of a source to the directory targets
+/// connected to it
+pub struct RunOnce<T>
+where
+    T: Persistence,
+{
+    downloader: SftpDownloader<T>,
+    persistence: SqliteAsyncPersistence,
+    targets: Vec<ConnectedTarget>,
+}
+
+impl<T> RunOnce<T>
+where
+    T: Persistence + Send + Clone + 'static,
+{
+    /// Archive targets are not dispatched to, because their archives are only
+    /// completed by the service
+    pub fn new(
+        settings: &settings::Settings,
+        downloader: SftpDownloader<T>,
+        persistence: SqliteAsyncPersistence,
+    ) -> RunOnce<T> {
+        let source_name = downloader.sftp_source.name.clone();
+        let notifiers = NamedNotifiers::new(&settings.notifiers);
+
+        let targets = settings
+            .connections
+            .iter()
+            .filter(|connection| connection.source == source_name)
+            .filter_map(|connection| {
+                let target = settings
+                    .directory_targets
+                    .iter()
+                    .find(|target| target.name == connection.target);
+
+                if target.is_none() {
+                    warn!(
+                        "Target '{}' is skipped, only directory targets are dispatched to",
+                        connection.target
+                    );
+                }
+
+                target.map(|target| ConnectedTarget {
+                    settings: target.clone(),
+                    filter: connection.filter.clone(),
+                    notifier: target
+                        .notify
+                        .as_ref()
+                        .map(|notify| notifiers.notifier(notify, &target.name, false)),
+                })
+            })
+            .collect();
+
+        RunOnce {
+            downloader,
+            persistence,
+            targets,
+        }
+    }
+
+    /// Download and dispatch the files, printing the outcome of every file
+    pub fn run<R: RemoteFs>(
+        &mut self,
+        fs: &R,
+        files: Vec<(PathBuf, Option<u64>)>,
+        remove: bool,
+        runtime: &tokio::runtime::Runtime,
+    ) -> Summary {
+        let mut summary = Summary::default();
+
+        for (path, size) in files {
+            // Commands of a one-shot run are not registered as SFTP downloads
+            let command = SftpDownload {
+                version: cortex_core::COMMAND_VERSION,
+                id: 0,
+                created: Utc::now(),
+                size,
+                sftp_source: self.downloader.sftp_source.name.clone(),
+                path: path.to_string_lossy().to_string(),
+                remove,
+                local_subpath: None,
+            };
+
+            let handled = match self.downloader.handle(fs, &command) {
+                Ok(handled) => handled,
+                Err(e) => {
+                    summary.failed += 1;
+                    print_file("failed", &command.path, &e.to_string());
+
+                    // Later downloads fail the same way without a connection
+                    if let DispatcherError::DisconnectedError(_) = e {
+                        break;
+                    }
+
+                    continue;
+                }
+            };
+
+            let Some(file_event) = handled.file_event else {
+                let note = if handled.locked {
+                    "downloaded by another instance"
+                } else {
+                    ""
+                };
+
+                summary.skipped += 1;
+                print_file("skipped", &command.path, note);
+                continue;
+            };
+
+            match runtime.block_on(self.dispatch(&file_event)) {
+                Ok(targets) => {
+                    summary.downloaded += 1;
+                    print_file("downloaded", &command.path, &targets.join(", "));
+                }
+                Err(e) => {
+                    summary.failed += 1;
+                    print_file("failed", &command.path, &e);
+                }
+            }
+        }
+
+        summary
+    }
+
+    /// Place, register and notify the file in all connected targets of which
+    /// the filter matches, returning the names of those targets
+    async fn dispatch(&mut self, file_event: &FileEvent) -> Result<Vec<String>, String> {
+        let mut dispatched = Vec::new();
+
+        for target in self.targets.iter_mut() {
+            if !target
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches(file_event))
+            {
+                continue;
+            }
+
+            let name = target.settings.name.clone();
+
+            let placed = handle_file_event(&target.settings, file_event.clone())
+                .await
+                .map_err(|e| format!("Error placing file in target '{name}': {e}"))?;
+
+            record_dispatched(&self.persistence, &name, placed.file_id)
+                .await
+                .map_err(|e| format!("Error registering dispatch to target '{name}': {e}"))?;
+
+            if let Some(notifier) = target.notifier.as_mut() {
+                notifier
+                    .notify(&placed, &name)
+                    .await
+                    .map_err(|e| format!("Error notifying for target '{name}': {e}"))?;
+            }
+
+            dispatched.push(name);
+        }
+
+        Ok(dispatched)
+    }
+}
+
+fn print_file(status: &str, path: &str, message: &str) {
+    println!("{:<12}  {}  {}", status, path, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use cortex_core::remote_fs::MemoryFs;
+
+    use crate::local_storage::{LocalStorage, StorageArea};
+    use crate::path_lock::PathLocks;
+    use crate::persistence::SqlitePersistence;
+    use crate::readiness::Readiness;
+    use crate::remote_removal::RemoteRemovals;
+    use crate::settings::{Settings, DEFAULT_STORAGE_AREA};
+    use crate::storage_usage::StorageUsage;
+
+    #[test]
+    fn second_run_skips_downloaded_files() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-run-once-{}", std::process::id()));
+        let target_directory = directory.join("target");
+        std::fs::create_dir_all(&target_directory).unwrap();
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+        let persistence = SqlitePersistence::from_arc(conn.clone());
+
+        let mut settings = Settings::default();
+        settings.directory_targets[0].directory = target_directory.clone();
+        settings.directory_targets[0].notify = None;
+        settings.connections = vec![settings::Connection {
+            source: "red".to_string(),
+            target: settings.directory_targets[0].name.clone(),
+            filter: None,
+            priority: 0,
+            require_delivery: false,
+            delivery_timeout_seconds: 300,
+        }];
+
+        let fs = MemoryFs::default();
+        fs.add_file(Path::new("upload/a.csv"), b"a", 1_700_000_000);
+        fs.add_file(Path::new("upload/2024/b.csv"), b"b", 1_700_000_000);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let files = remote_files(&fs, Path::new("upload")).unwrap();
+
+        let summaries: Vec<Summary> = (0..2)
+            .map(|_| {
+                let downloader = SftpDownloader {
+                    sftp_source: settings.sftp_sources[0].clone(),
+                    persistence: persistence.clone(),
+                    local_storage: LocalStorage::new(
+                        vec![StorageArea::new(
+                            DEFAULT_STORAGE_AREA,
+                            directory.join("storage"),
+                            settings::StorageLayout::PerSource,
+                            StorageUsage::new(&settings.storage[0], Readiness::default()),
+                        )],
+                        persistence.clone(),
+                    ),
+                    path_locks: PathLocks::default(),
+                    remote_removals: RemoteRemovals::default(),
+                    write_behind: None,
+                    connections: None,
+                };
+
+                RunOnce::new(
+                    &settings,
+                    downloader,
+                    SqliteAsyncPersistence::new(conn.clone()),
+                )
+                .run(&fs, files.clone(), false, &runtime)
+            })
+            .collect();
+
+        let placed = std::fs::read_dir(&target_directory).unwrap().count();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            summaries,
+            vec![
+                Summary {
+                    downloaded: 2,
+                    skipped: 0,
+                    failed: 0
+                },
+                Summary {
+                    downloaded: 0,
+                    skipped: 2,
+                    failed: 0
+                }
+            ]
+        );
+        assert_eq!(placed, 2);
+    }
+}
@@ -0,0 +1,576 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+
+use cortex_core::secret::serialize_secrets;
+
+use crate::base_types::{Connection, Connections, Target};
+use crate::dispatcher::start_directory_target;
+use crate::heartbeat::Heartbeats;
+use crate::notifier::NamedNotifiers;
+use crate::persistence::SqliteAsyncPersistence;
+use crate::settings::{self, Channels, DirectoryTarget, Settings};
+
+#[derive(thiserror::Error, Debug)]
+pub enum RuntimeTargetError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Persist(String),
+}
+
+/// Changes to the targets and connections of the configuration file, made
+/// through the HTTP API
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RuntimeOverrides {
+    /// Added directory targets, as they were posted
+    #[serde(default)]
+    pub directory_targets: Vec<DirectoryTarget>,
+    /// Names of the removed targets
+    #[serde(default)]
+    pub removed_targets: Vec<String>,
+    #[serde(default)]
+    pub connections: Vec<settings::Connection>,
+}
+
+impl RuntimeOverrides {
+    /// Read the overrides, which are empty when the file does not exist yet
+    pub fn load(path: &Path) -> Result<RuntimeOverrides, String> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(RuntimeOverrides::default())
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Could not read runtime overrides '{}': {}",
+                    path.to_string_lossy(),
+                    e
+                ))
+            }
+        };
+
+        serde_yaml_ng::from_str(&content).map_err(|e| {
+            format!(
+                "Could not parse runtime overrides '{}': {}",
+                path.to_string_lossy(),
+                e
+            )
+        })
+    }
+
+    /// Replace the file with the overrides, including the secrets in them
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let content = serialize_secrets(|| serde_yaml_ng::to_string(self))
+            .map_err(|e| format!("Error serializing runtime overrides: {e}"))?;
+
+        let part_path = path.with_extension("part");
+
+        let write_result = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&part_path)
+            .and_then(|mut file| {
+                file.write_all(content.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&part_path, path));
+
+        write_result.map_err(|e| {
+            format!(
+                "Error writing runtime overrides '{}': {}",
+                path.to_string_lossy(),
+                e
+            )
+        })
+    }
+
+    /// Apply the overrides to the settings from the configuration file
+    ///
+    /// Added targets replace targets with the same name in the configuration
+    /// file, and the connections to removed targets are dropped.
+    pub fn apply(&self, settings: &mut Settings) -> Result<(), String> {
+        let replaced: HashSet<&str> = self
+            .removed_targets
+            .iter()
+            .map(String::as_str)
+            .chain(self.directory_targets.iter().map(|t| t.name.as_str()))
+            .collect();
+
+        settings
+            .directory_targets
+            .retain(|t| !replaced.contains(t.name.as_str()));
+
+        settings
+            .connections
+            .retain(|c| !self.removed_targets.contains(&c.target));
+
+        for (index, directory_target) in self.directory_targets.iter().enumerate() {
+            let mut directory_target = directory_target.clone();
+
+            directory_target
+                .resolve_secret_files(&format!("runtime_overrides.directory_targets[{index}]"))?;
+
+            settings.directory_targets.push(directory_target);
+        }
+
+        settings
+            .connections
+            .extend(self.connections.iter().cloned());
+
+        Ok(())
+    }
+}
+
+/// Targets and connections that are added and removed while the dispatcher
+/// runs
+///
+/// Changes are kept in the runtime overrides file when one is configured,
+/// except in a dry run. Only directory targets can be added and removed.
+#[derive(Clone)]
+pub struct RuntimeTargets {
+    targets: Arc<Mutex<HashMap<String, Arc<Target>>>>,
+    directory_targets: Arc<Mutex<HashSet<String>>>,
+    connections: Connections,
+    /// Connections of the configuration to targets that do not exist (yet)
+    pending_connections: Arc<Mutex<Vec<settings::Connection>>>,
+    source_names: Arc<HashSet<String>>,
+    /// Also serializes the changes
+    overrides: Arc<Mutex<RuntimeOverrides>>,
+    overrides_path: Option<PathBuf>,
+    persistence: SqliteAsyncPersistence,
+    heartbeats: Heartbeats,
+    channels: Channels,
+    stop_receiver: watch::Receiver<()>,
+    /// Notifiers that added targets can refer to, sharing their connections
+    /// with the targets of the configuration file
+    notifiers: NamedNotifiers,
+    dry_run: bool,
+    /// Runtime of the dispatcher, on which added targets run instead of on
+    /// the runtime of the HTTP server
+    runtime: Handle,
+}
+
+impl RuntimeTargets {
+    /// Must be called from within the runtime of the dispatcher
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        settings: &Settings,
+        overrides: RuntimeOverrides,
+        targets: Arc<Mutex<HashMap<String, Arc<Target>>>>,
+        connections: Connections,
+        pending_connections: Vec<settings::Connection>,
+        persistence: SqliteAsyncPersistence,
+        stop_receiver: watch::Receiver<()>,
+        notifiers: NamedNotifiers,
+        dry_run: bool,
+    ) -> RuntimeTargets {
+        let source_names = settings
+            .directory_sources
+            .iter()
+            .map(|s| s.name.clone())
+            .chain(settings.sftp_sources.iter().map(|s| s.name.clone()))
+            .chain(settings.archive_targets.iter().map(|t| t.name.clone()))
+            .collect();
+
+        let directory_targets = settings
+            .directory_targets
+            .iter()
+            .map(|t| t.name.clone())
+            .collect();
+
+        RuntimeTargets {
+            targets,
+            directory_targets: Arc::new(Mutex::new(directory_targets)),
+            connections,
+            pending_connections: Arc::new(Mutex::new(pending_connections)),
+            source_names: Arc::new(source_names),
+            overrides: Arc::new(Mutex::new(overrides)),
+            overrides_path: settings.runtime_overrides.clone(),
+            heartbeats: Heartbeats::new(persistence.clone(), settings.heartbeat_interval),
+            persistence,
+            channels: settings.channels.clone(),
+            stop_receiver,
+            notifiers,
+            dry_run,
+            runtime: Handle::current(),
+        }
+    }
+
+    /// Start a directory target, in the same way as the targets of the
+    /// configuration file
+    pub fn add_directory_target(
+        &self,
+        target_conf: DirectoryTarget,
+    ) -> Result<(), RuntimeTargetError> {
+        let mut overrides = self.overrides.lock().unwrap();
+
+        if self.targets.lock().unwrap().contains_key(&target_conf.name) {
+            return Err(RuntimeTargetError::Conflict(format!(
+                "Target '{}' already exists",
+                target_conf.name
+            )));
+        }
+
+        let mut problems = target_conf.validate();
+        problems.extend(target_conf.validate_notifier(self.notifiers.notifiers()));
+
+        if !problems.is_empty() {
+            return Err(RuntimeTargetError::Invalid(problems.join(", ")));
+        }
+
+        let mut resolved_conf = target_conf.clone();
+
+        resolved_conf
+            .resolve_secret_files(&target_conf.name)
+            .map_err(RuntimeTargetError::Invalid)?;
+
+        let mut changed = overrides.clone();
+        changed.directory_targets.push(target_conf);
+        self.save(&changed)?;
+        *overrides = changed;
+
+        let name = resolved_conf.name.clone();
+
+        let (target, _join_handle) = {
+            let _runtime_guard = self.runtime.enter();
+
+            start_directory_target(
+                resolved_conf,
+                self.persistence.clone(),
+                &self.channels,
+                self.stop_receiver.clone(),
+                self.heartbeats.clone(),
+                &self.notifiers,
+                self.dry_run,
+            )
+        };
+
+        self.targets
+            .lock()
+            .unwrap()
+            .insert(name.clone(), target.clone());
+        self.directory_targets.lock().unwrap().insert(name.clone());
+
+        info!("Added directory target '{name}'");
+
+        self.connect_pending(&target);
+
+        Ok(())
+    }
+
+    /// Remove a directory target with its connections
+    ///
+    /// File events that were already sent to the target are still handled.
+    pub fn remove_directory_target(&self, name: &str) -> Result<(), RuntimeTargetError> {
+        let mut overrides = self.overrides.lock().unwrap();
+
+        if !self.directory_targets.lock().unwrap().contains(name) {
+            return Err(if self.targets.lock().unwrap().contains_key(name) {
+                RuntimeTargetError::Invalid(format!("Target '{name}' is not a directory target"))
+            } else {
+                RuntimeTargetError::NotFound(format!("No target named '{name}'"))
+            });
+        }
+
+        let mut changed = overrides.clone();
+        changed.directory_targets.retain(|t| t.name != name);
+        changed.connections.retain(|c| c.target != name);
+
+        if !changed
+            .removed_targets
+            .iter()
+            .any(|removed| removed == name)
+        {
+            changed.removed_targets.push(name.to_string());
+        }
+
+        self.save(&changed)?;
+        *overrides = changed;
+
+        // The target ends when the last connection to it is dropped
+        self.connections
+            .write()
+            .unwrap()
+            .retain(|c| c.target.name != name);
+        self.targets.lock().unwrap().remove(name);
+        self.directory_targets.lock().unwrap().remove(name);
+
+        info!("Removed directory target '{name}'");
+
+        Ok(())
+    }
+
+    /// Connect a source to a target, for the file events that arrive from now
+    /// on
+    pub fn add_connection(
+        &self,
+        conn_conf: settings::Connection,
+    ) -> Result<(), RuntimeTargetError> {
+        let mut overrides = self.overrides.lock().unwrap();
+
+        if !self.source_names.contains(&conn_conf.source) {
+            return Err(RuntimeTargetError::NotFound(format!(
+                "No source named '{}'",
+                conn_conf.source
+            )));
+        }
+
+        let target = self
+            .targets
+            .lock()
+            .unwrap()
+            .get(&conn_conf.target)
+            .cloned()
+            .ok_or_else(|| {
+                RuntimeTargetError::NotFound(format!("No target named '{}'", conn_conf.target))
+            })?;
+
+        if conn_conf.require_delivery
+            && !self
+                .directory_targets
+                .lock()
+                .unwrap()
+                .contains(&conn_conf.target)
+        {
+            return Err(RuntimeTargetError::Invalid(format!(
+                "Connection {} -> {} requires delivery, which is only supported for directory targets",
+                conn_conf.source, conn_conf.target
+            )));
+        }
+
+        if self
+            .connections
+            .read()
+            .unwrap()
+            .iter()
+            .any(|c| c.source_name == conn_conf.source && c.target.name == conn_conf.target)
+        {
+            return Err(RuntimeTargetError::Conflict(format!(
+                "Connection {} -> {} already exists",
+                conn_conf.source, conn_conf.target
+            )));
+        }
+
+        let mut changed = overrides.clone();
+        changed.connections.push(conn_conf.clone());
+        self.save(&changed)?;
+        *overrides = changed;
+
+        self.connections
+            .write()
+            .unwrap()
+            .push(Connection::new(&conn_conf, target));
+
+        info!(
+            "Added connection {} -> {}",
+            conn_conf.source, conn_conf.target
+        );
+
+        Ok(())
+    }
+
+    /// Make the pending connections to a target that was added
+    fn connect_pending(&self, target: &Arc<Target>) {
+        let mut pending_connections = self.pending_connections.lock().unwrap();
+
+        let (connected, pending): (Vec<_>, Vec<_>) = pending_connections
+            .drain(..)
+            .partition(|c| c.target == target.name);
+
+        *pending_connections = pending;
+
+        for conn_conf in connected {
+            self.connections
+                .write()
+                .unwrap()
+                .push(Connection::new(&conn_conf, target.clone()));
+
+            info!(
+                "Made pending connection {} -> {}",
+                conn_conf.source, conn_conf.target
+            );
+        }
+    }
+
+    fn save(&self, overrides: &RuntimeOverrides) -> Result<(), RuntimeTargetError> {
+        match &self.overrides_path {
+            Some(path) if self.dry_run => {
+                info!(
+                    "Would save runtime overrides to '{}'",
+                    path.to_string_lossy()
+                );
+
+                Ok(())
+            }
+            Some(path) => overrides.save(path).map_err(RuntimeTargetError::Persist),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::RwLock;
+    use std::time::Duration;
+
+    fn directory_target(name: &str) -> DirectoryTarget {
+        let mut target = Settings::default().directory_targets[0].clone();
+        target.name = name.to_string();
+        target
+    }
+
+    fn connection(source: &str, target: &str) -> settings::Connection {
+        settings::Connection {
+            source: source.to_string(),
+            target: target.to_string(),
+            filter: None,
+            priority: 0,
+            require_delivery: false,
+            delivery_timeout_seconds: 300,
+        }
+    }
+
+    #[tokio::test]
+    async fn changed_targets_are_kept_for_a_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "cortex-runtime-overrides-{}.yaml",
+            std::process::id()
+        ));
+
+        let settings = Settings {
+            connections: vec![connection("mixed-directory", "red")],
+            runtime_overrides: Some(path.clone()),
+            ..Settings::default()
+        };
+        let source = "mixed-directory";
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let (_stop_sender, stop_receiver) = watch::channel(());
+
+        let runtime_targets = RuntimeTargets::new(
+            &settings,
+            RuntimeOverrides::default(),
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(RwLock::new(Vec::new())),
+            Vec::new(),
+            SqliteAsyncPersistence::new(Arc::new(Mutex::new(conn))),
+            stop_receiver,
+            NamedNotifiers::default(),
+            false,
+        );
+
+        runtime_targets
+            .add_directory_target(directory_target("green"))
+            .unwrap();
+
+        assert!(matches!(
+            runtime_targets.add_directory_target(directory_target("green")),
+            Err(RuntimeTargetError::Conflict(_))
+        ));
+
+        runtime_targets
+            .add_connection(connection(source, "green"))
+            .unwrap();
+
+        assert!(matches!(
+            runtime_targets.add_connection(connection(source, "green")),
+            Err(RuntimeTargetError::Conflict(_))
+        ));
+        assert!(matches!(
+            runtime_targets.add_connection(connection(source, "blue")),
+            Err(RuntimeTargetError::NotFound(_))
+        ));
+
+        runtime_targets.remove_directory_target("red").unwrap();
+
+        let mut restarted = settings.clone();
+
+        RuntimeOverrides::load(&path)
+            .unwrap()
+            .apply(&mut restarted)
+            .unwrap();
+
+        let target_names: Vec<&str> = restarted
+            .directory_targets
+            .iter()
+            .map(|t| t.name.as_str())
+            .collect();
+
+        assert_eq!(target_names, vec!["green"]);
+        assert!(restarted.connections.iter().all(|c| c.target == "green"));
+        assert_eq!(restarted.connections.len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pending_connections_are_made_when_their_target_appears() {
+        let settings = Settings {
+            connections: vec![connection("mixed-directory", "green")],
+            ..Settings::default()
+        };
+
+        let targets = Arc::new(Mutex::new(HashMap::new()));
+
+        let (connections, pending_connections) =
+            crate::dispatcher::resolve_connections(&settings.connections, &targets);
+
+        let connections: Connections = Arc::new(RwLock::new(connections));
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let (_stop_sender, stop_receiver) = watch::channel(());
+
+        let runtime_targets = RuntimeTargets::new(
+            &settings,
+            RuntimeOverrides::default(),
+            targets,
+            connections.clone(),
+            pending_connections,
+            SqliteAsyncPersistence::new(Arc::new(Mutex::new(conn))),
+            stop_receiver,
+            NamedNotifiers::default(),
+            false,
+        );
+
+        assert!(connections.read().unwrap().is_empty());
+
+        // The target shows up late
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            runtime_targets
+                .add_directory_target(directory_target("green"))
+                .unwrap();
+        })
+        .await
+        .unwrap();
+
+        let connected: Vec<(String, String)> = connections
+            .read()
+            .unwrap()
+            .iter()
+            .map(|c| (c.source_name.clone(), c.target.name.clone()))
+            .collect();
+
+        assert_eq!(
+            connected,
+            vec![("mixed-directory".to_string(), "green".to_string())]
+        );
+    }
+}
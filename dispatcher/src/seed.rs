@@ -0,0 +1,105 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::persistence::{Persistence, SqlitePersistence};
+
+/// Database contents for development and testing
+#[derive(Debug, Deserialize)]
+pub struct Fixture {
+    pub files: Vec<FixtureFile>,
+}
+
+/// A file in internal storage, with the targets it has been dispatched to
+#[derive(Debug, Deserialize)]
+pub struct FixtureFile {
+    pub source: String,
+    /// Path relative to the directory of the source in internal storage
+    pub path: PathBuf,
+    pub size: i64,
+    pub hash: Option<String>,
+    pub modified: DateTime<Utc>,
+    #[serde(default)]
+    pub targets: Vec<String>,
+}
+
+/// Read a YAML or JSON fixture file
+pub fn load_fixture(path: &Path) -> Result<Fixture, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read fixture '{}': {}", path.to_string_lossy(), e))?;
+
+    serde_yaml_ng::from_str(&content).map_err(|e| {
+        format!(
+            "Could not parse fixture '{}': {}",
+            path.to_string_lossy(),
+            e
+        )
+    })
+}
+
+/// Insert the files of a fixture and create matching empty files in internal
+/// storage
+///
+/// Returns the number of files seeded.
+pub fn seed(
+    persistence: &SqlitePersistence,
+    storage_dir: &Path,
+    fixture: &Fixture,
+) -> Result<usize, String> {
+    for file in &fixture.files {
+        let local_path = storage_dir.join(&file.source).join(&file.path);
+
+        if let Some(parent) = local_path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                format!(
+                    "Could not create directory '{}': {}",
+                    parent.to_string_lossy(),
+                    e
+                )
+            })?;
+        }
+
+        std::fs::File::create(&local_path).map_err(|e| {
+            format!(
+                "Could not create file '{}': {}",
+                local_path.to_string_lossy(),
+                e
+            )
+        })?;
+
+        let file_id = persistence
+            .insert_file(
+                &file.source,
+                &local_path.to_string_lossy(),
+                &file.modified,
+                file.size,
+                file.hash.clone(),
+            )
+            .map_err(|e| format!("Could not insert file: {e}"))?;
+
+        for target in &file.targets {
+            persistence
+                .insert_dispatched(target, file_id)
+                .map_err(|e| format!("Could not insert dispatched: {e}"))?;
+        }
+    }
+
+    Ok(fixture.files.len())
+}
+
+/// Seed the database at `db_path` from a fixture file
+pub fn seed_from_file(
+    db_path: &Path,
+    storage_dir: &Path,
+    fixture_path: &Path,
+) -> Result<usize, String> {
+    let fixture = load_fixture(fixture_path)?;
+
+    let conn =
+        rusqlite::Connection::open(db_path).map_err(|e| format!("Could not open database: {e}"))?;
+
+    let persistence = SqlitePersistence::from_arc(std::sync::Arc::new(std::sync::Mutex::new(conn)));
+
+    seed(&persistence, storage_dir, &fixture)
+}
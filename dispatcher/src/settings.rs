@@ -1,15 +1,23 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::{Path, PathBuf};
-
-use regex::Regex;
+use std::str::FromStr;
 
 #[cfg(target_os = "linux")]
 use inotify::WatchMask;
 
-use chrono::prelude::{DateTime, Utc};
+use chrono::prelude::Utc;
+
+use log::debug;
 
-use crate::base_types;
+use cortex_core::log_throttle;
+use cortex_core::secret::{resolve_secret, Secret};
+pub use cortex_core::settings::{Deduplication, FileComparison, SftpConnectionSettings};
+use cortex_core::sftp_connection::{HostKeyCheck, SftpConfig};
+use cortex_core::{sftp_source_routing_key, DEFAULT_COMMAND_EXCHANGE};
 
-use serde::{Deserialize, Serialize};
+use serde::de::{self, EnumAccess, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 
 fn default_false() -> bool {
     false
@@ -19,47 +27,32 @@ fn default_true() -> bool {
     true
 }
 
-trait FileFilter {
-    fn file_matches<P: AsRef<Path>>(&self, path: P) -> bool;
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct RegexFilter {
-    #[serde(with = "serde_regex")]
-    pattern: Regex,
-}
-
-impl FileFilter for RegexFilter {
-    fn file_matches<P: AsRef<Path>>(&self, path: P) -> bool {
-        let file_name_result = path.as_ref().file_name();
-
-        file_name_result.map_or_else(
-            || false,
-            |file_name| self.pattern.is_match(file_name.to_str().unwrap()),
-        )
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum Filter {
-    Regex(RegexFilter),
-    All,
-}
-
-impl Filter {
-    pub fn file_matches<P: AsRef<Path>>(&self, path: P) -> bool {
-        match self {
-            Filter::Regex(r) => r.file_matches(path),
-            Filter::All => true,
-        }
-    }
-}
+/// Filters are shared with the other Cortex components, and keep their
+/// configuration format
+pub use cortex_core::filter::Filter;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Connection {
     pub source: String,
     pub target: String,
     pub filter: Option<Filter>,
+    /// Connections with a higher priority get the file events of their source
+    /// first, and wait for room in a full target channel instead of applying
+    /// the overflow policy when above 0
+    #[serde(default)]
+    pub priority: u8,
+    /// Acknowledge the download commands of the files of an SFTP source only
+    /// after the target handled them
+    #[serde(default = "default_false")]
+    pub require_delivery: bool,
+    /// Time after which a download command of which the file was not handled
+    /// by the target is given back to the broker
+    #[serde(default = "default_delivery_timeout_seconds")]
+    pub delivery_timeout_seconds: u64,
+}
+
+fn default_delivery_timeout_seconds() -> u64 {
+    300
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -100,47 +93,6 @@ impl FileSystemEvent {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct FileComparison {
-    pub size: bool,
-    pub modified: bool,
-    pub hash: bool,
-}
-
-impl FileComparison {
-    pub fn equal(
-        &self,
-        file_info: &base_types::FileInfo,
-        size: u64,
-        modified: DateTime<Utc>,
-        hash: Option<String>,
-    ) -> bool {
-        if self.size && (file_info.size as u64) != size {
-            return false;
-        }
-
-        if self.modified && file_info.modified != modified {
-            return false;
-        }
-
-        if self.hash && file_info.hash != hash {
-            return false;
-        }
-
-        true
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum Deduplication {
-    #[serde(rename = "none")]
-    None,
-    #[serde(rename = "check")]
-    Check(FileComparison),
-    #[serde(rename = "name")]
-    Name,
-}
-
 fn default_directory_source_deduplication() -> Deduplication {
     Deduplication::Check(FileComparison {
         size: false,
@@ -175,20 +127,425 @@ pub struct DirectorySource {
     /// Set to true to remove the source file after ingestion
     #[serde(default = "default_true")]
     pub delete: bool,
+    /// Storage area to place the files in, the first one when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_area: Option<String>,
+    /// Directory in the storage to place the files in instead of the one
+    /// with the name of the source, or an absolute path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_subdirectory: Option<PathBuf>,
+    /// Set to true to place the files without their subdirectories, which
+    /// are joined into the file name with underscores
+    #[serde(default = "default_false")]
+    pub flatten: bool,
+    /// Permissions of the stored files, overriding storage.file_permissions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_permissions: Option<String>,
+    /// Group of the stored files, overriding storage.group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Create the directory on startup when it does not exist, instead of
+    /// failing
+    #[serde(default = "default_false")]
+    pub create_missing: bool,
+    /// What to do when a stored file is replaced by one with different
+    /// content
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    /// Maximum number of previous versions kept with `on_conflict: version`
+    #[serde(default = "default_max_versions")]
+    pub max_versions: u32,
+}
+
+/// TLS settings for amqps:// connections
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AmqpTls {
+    /// PEM file with the CA certificates to verify the server with
+    pub ca_cert: PathBuf,
+    /// PEM file with the client certificate, for client authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+    /// Set to false to accept a server certificate issued for another host
+    #[serde(default = "default_true")]
+    pub verify_hostname: bool,
+}
+
+impl AmqpTls {
+    fn validate(&self, name: &str, problems: &mut Vec<String>) {
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            problems.push(format!(
+                "{name} requires both client_cert and client_key for client authentication"
+            ));
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RabbitMQNotify {
     pub message_template: String,
-    pub address: String,
+    #[serde(default)]
+    pub address: Secret,
+    /// File to read the address from, instead of specifying it inline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amqp_tls: Option<AmqpTls>,
     pub exchange: String,
     pub routing_key: String,
 }
 
+/// Notification on a Kafka topic, which requires the `kafka` feature
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KafkaNotify {
+    pub message_template: String,
+    /// Comma separated host:port pairs of the bootstrap brokers
+    pub brokers: String,
+    pub topic: String,
+    /// Tera template of the message key, with the same variables as the
+    /// message
+    #[serde(default = "default_kafka_key_template")]
+    pub key_template: String,
+    /// Milliseconds during which the producer retries the delivery of a
+    /// message before the notification fails
+    #[serde(default = "default_kafka_delivery_timeout")]
+    pub delivery_timeout: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security: Option<KafkaSecurity>,
+}
+
+fn default_kafka_key_template() -> String {
+    "{{ file_path }}".to_string()
+}
+
+fn default_kafka_delivery_timeout() -> u64 {
+    30_000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KafkaSecurityProtocol {
+    Plaintext,
+    Ssl,
+    SaslPlaintext,
+    SaslSsl,
+}
+
+/// Security settings of the connections to the Kafka brokers
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KafkaSecurity {
+    pub protocol: KafkaSecurityProtocol,
+    /// PEM file with the CA certificates to verify the brokers with, instead
+    /// of the system trust store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<PathBuf>,
+    /// PEM file with the client certificate, for client authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+    /// SASL mechanism, e.g. PLAIN, SCRAM-SHA-256 or SCRAM-SHA-512
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sasl_mechanism: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sasl_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sasl_password: Option<Secret>,
+    /// File to read the SASL password from, instead of specifying it inline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sasl_password_file: Option<PathBuf>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Notify {
     #[serde(rename = "rabbitmq")]
     RabbitMQ(RabbitMQNotify),
+    #[serde(rename = "kafka")]
+    Kafka(KafkaNotify),
+    /// Log every placed file instead of sending a notification
+    #[serde(rename = "log")]
+    Log,
+}
+
+impl Notify {
+    /// Read the address or password from its file when it is set through a
+    /// `_file` field, where `name` is used in error messages
+    pub fn resolve_secret_files(&mut self, name: &str) -> Result<(), String> {
+        match self {
+            Notify::RabbitMQ(notify) => {
+                notify.address = resolve_address(
+                    &format!("{name}.rabbitmq.address"),
+                    &notify.address,
+                    notify.address_file.take().as_deref(),
+                )?;
+            }
+            Notify::Kafka(KafkaNotify {
+                security: Some(security),
+                ..
+            }) => {
+                security.sasl_password = resolve_secret(
+                    &format!("{name}.kafka.security.sasl_password"),
+                    security.sasl_password.as_ref(),
+                    security.sasl_password_file.take().as_deref(),
+                )?;
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Check the settings, where `subject` names the owner in the problems
+    /// found
+    fn validate(&self, subject: &str, problems: &mut Vec<String>) {
+        if let Notify::Kafka(kafka) = self {
+            if !cfg!(feature = "kafka") {
+                problems.push(format!(
+                    "{subject} notifies on Kafka, which requires the kafka feature"
+                ));
+            }
+
+            if let Some(security) = &kafka.security {
+                if security.client_cert.is_some() != security.client_key.is_some() {
+                    problems.push(format!(
+                        "{subject} requires both client_cert and client_key for Kafka client authentication"
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Notification settings of a directory target, either inline or as a
+/// reference to one of the `notifiers`
+// Only read on startup, so the size of the inline settings does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum TargetNotify {
+    Inline(Notify),
+    /// Name of a notifier
+    Named(String),
+    Reference(NotifierReference),
+}
+
+/// Reference to one of the `notifiers`, with overrides for the target
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NotifierReference {
+    pub notifier: String,
+    /// Routing key instead of the one of a RabbitMQ notifier
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_template: Option<String>,
+}
+
+/// A string is the name of a notifier, except for `log`, and a map is a
+/// reference when it has a `notifier` key
+///
+/// Inline settings can also be enums, as the YAML tags with which runtime
+/// overrides are written.
+impl<'de> Deserialize<'de> for TargetNotify {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TargetNotifyVisitor;
+
+        impl<'de> Visitor<'de> for TargetNotifyVisitor {
+            type Value = TargetNotify;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("notify settings or the name of a notifier")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<TargetNotify, E> {
+                match value {
+                    "log" => Ok(TargetNotify::Inline(Notify::Log)),
+                    name => Ok(TargetNotify::Named(name.to_string())),
+                }
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<TargetNotify, A::Error> {
+                let value =
+                    serde_json::Value::deserialize(de::value::MapAccessDeserializer::new(map))?;
+
+                if value.get("notifier").is_some() {
+                    serde_json::from_value(value)
+                        .map(TargetNotify::Reference)
+                        .map_err(de::Error::custom)
+                } else {
+                    serde_json::from_value(value)
+                        .map(TargetNotify::Inline)
+                        .map_err(de::Error::custom)
+                }
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<TargetNotify, A::Error> {
+                Notify::deserialize(de::value::EnumAccessDeserializer::new(data))
+                    .map(TargetNotify::Inline)
+            }
+        }
+
+        deserializer.deserialize_any(TargetNotifyVisitor)
+    }
+}
+
+impl TargetNotify {
+    /// Name of the notifier that is referred to, `None` for inline settings
+    pub fn notifier_name(&self) -> Option<&str> {
+        match self {
+            TargetNotify::Inline(_) => None,
+            TargetNotify::Named(name) => Some(name),
+            TargetNotify::Reference(reference) => Some(&reference.notifier),
+        }
+    }
+
+    /// Notification settings with the overrides of the target applied,
+    /// `None` when the notifier that is referred to is not defined
+    pub fn resolve(&self, notifiers: &HashMap<String, Notify>) -> Option<Notify> {
+        let reference = match self {
+            TargetNotify::Inline(notify) => return Some(notify.clone()),
+            TargetNotify::Named(name) => return notifiers.get(name).cloned(),
+            TargetNotify::Reference(reference) => reference,
+        };
+
+        let mut notify = notifiers.get(&reference.notifier)?.clone();
+
+        match &mut notify {
+            Notify::RabbitMQ(rabbitmq) => {
+                if let Some(routing_key) = &reference.routing_key {
+                    rabbitmq.routing_key = routing_key.clone();
+                }
+
+                if let Some(message_template) = &reference.message_template {
+                    rabbitmq.message_template = message_template.clone();
+                }
+            }
+            Notify::Kafka(kafka) => {
+                if let Some(message_template) = &reference.message_template {
+                    kafka.message_template = message_template.clone();
+                }
+            }
+            Notify::Log => (),
+        }
+
+        Some(notify)
+    }
+}
+
+/// Publisher of the file events of all sources to RabbitMQ as JSON,
+/// independent of the connections and targets
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EventPublisher {
+    pub name: String,
+    #[serde(default)]
+    pub address: Secret,
+    /// File to read the address from, instead of specifying it inline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amqp_tls: Option<AmqpTls>,
+    pub exchange: String,
+    /// Template of the routing key, with the same variables as the message
+    pub routing_key: String,
+    pub filter: Option<Filter>,
+    /// Number of file events waiting to be published, after which new file
+    /// events are dropped
+    #[serde(default = "default_event_publisher_capacity")]
+    pub capacity: usize,
+}
+
+/// Summary of the stored files and failed downloads, sent periodically
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Report {
+    pub name: String,
+    /// Seconds between reports, when not sent on a schedule
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_seconds: Option<u64>,
+    /// Cron expression of the times at which the report is sent, e.g.
+    /// "0 0 7 * * *"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    /// Seconds covered by the report, defaults to the interval, or a day
+    /// when sent on a schedule
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub period_seconds: Option<u64>,
+    #[serde(default)]
+    pub format: ReportFormat,
+    /// Sources included in the report, all sources when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    pub transport: ReportTransport,
+}
+
+impl Report {
+    /// Seconds covered by the report
+    pub fn period_seconds(&self) -> u64 {
+        self.period_seconds
+            .or(self.interval_seconds)
+            .unwrap_or(24 * 60 * 60)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Text,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ReportTransport {
+    /// POST the report to the URL
+    #[serde(rename = "webhook")]
+    Webhook(WebhookTransport),
+    /// Send the report by email
+    #[serde(rename = "smtp")]
+    Smtp(SmtpTransport),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookTransport {
+    pub url: Secret,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SmtpTransport {
+    pub server: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
+    /// File to read the password from, instead of specifying it inline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default = "default_report_subject")]
+    pub subject: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    None,
+    #[default]
+    StartTls,
+    Tls,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_report_subject() -> String {
+    "Cortex Dispatcher report".to_string()
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -205,8 +562,224 @@ pub struct DirectoryTarget {
     #[serde(default = "default_local_target_method")]
     pub method: LocalTargetMethod,
     pub overwrite: bool,
-    pub notify: Option<Notify>,
+    pub notify: Option<TargetNotify>,
     pub permissions: u32,
+    /// Checksum file written next to every delivered file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum_sidecar: Option<ChecksumSidecar>,
+    /// Maximum rate at which files are placed in the directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+    /// Number of files placed at the same time, where 1 keeps the order in
+    /// which they arrive
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Create the directory on startup when it does not exist, instead of
+    /// failing
+    #[serde(default = "default_true")]
+    pub create_missing: bool,
+    /// Place files below the directories of their path relative to the
+    /// directory of their source in storage, instead of directly in the
+    /// directory
+    #[serde(default)]
+    pub preserve_structure: bool,
+    /// Syncing of placed files to disk before they appear in the directory
+    #[serde(default)]
+    pub fsync: Fsync,
+    /// Set to true to check that stored files still match their registered
+    /// hash before they are placed, quarantining those that do not
+    #[serde(default = "default_false")]
+    pub verify_hash_on_dispatch: bool,
+    /// Size in bytes above which only the size of stored files is checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_verify_size_bytes: Option<u64>,
+    /// Maximum rate in bytes per second at which stored files are read for
+    /// the check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_bytes_per_second: Option<u64>,
+}
+
+impl DirectoryTarget {
+    /// Read the notification address from its file when it is set through
+    /// `address_file`, where `name` is used in error messages
+    pub fn resolve_secret_files(&mut self, name: &str) -> Result<(), String> {
+        match &mut self.notify {
+            Some(TargetNotify::Inline(notify)) => {
+                notify.resolve_secret_files(&format!("{name}.notify"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the settings of the target, returning the problems found
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+
+        if self.permissions > 0o7777 {
+            problems.push(format!(
+                "Invalid permissions {:o} for directory target '{}'",
+                self.permissions, self.name
+            ));
+        }
+
+        if self.concurrency == 0 {
+            problems.push(format!(
+                "Directory target '{}' has a concurrency of 0",
+                self.name
+            ));
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.events_per_second.is_nan() || rate_limit.events_per_second <= 0.0 {
+                problems.push(format!(
+                    "Directory target '{}' has a rate_limit.events_per_second that is not greater than 0",
+                    self.name
+                ));
+            }
+
+            if rate_limit.burst == 0 {
+                problems.push(format!(
+                    "Directory target '{}' has a rate_limit.burst of 0",
+                    self.name
+                ));
+            }
+        }
+
+        if self.verify_bytes_per_second == Some(0) {
+            problems.push(format!(
+                "Directory target '{}' has a verify_bytes_per_second of 0",
+                self.name
+            ));
+        }
+
+        if let Some(TargetNotify::Inline(notify)) = &self.notify {
+            notify.validate(&format!("Directory target '{}'", self.name), &mut problems);
+        }
+
+        problems
+    }
+
+    /// Check that the notifier that the target refers to is defined, and
+    /// that its overrides apply to it
+    pub fn validate_notifier(&self, notifiers: &HashMap<String, Notify>) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+
+        let Some(name) = self.notify.as_ref().and_then(TargetNotify::notifier_name) else {
+            return problems;
+        };
+
+        match notifiers.get(name) {
+            None => problems.push(format!(
+                "Directory target '{}' refers to undefined notifier '{}'",
+                self.name, name
+            )),
+            Some(notify) => {
+                if let Some(TargetNotify::Reference(reference)) = &self.notify {
+                    let routing_key =
+                        reference.routing_key.is_some() && !matches!(notify, Notify::RabbitMQ(_));
+                    let message_template =
+                        reference.message_template.is_some() && matches!(notify, Notify::Log);
+
+                    if routing_key || message_template {
+                        problems.push(format!(
+                            "Directory target '{}' overrides settings that notifier '{}' does not have",
+                            self.name, name
+                        ));
+                    }
+                }
+            }
+        }
+
+        problems
+    }
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RateLimit {
+    pub events_per_second: f64,
+    /// Number of events that can be handled at once after a quiet period
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+}
+
+fn default_burst() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChecksumSidecar {
+    #[serde(default)]
+    pub algorithm: ChecksumAlgorithm,
+    #[serde(default)]
+    pub format: ChecksumFormat,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl ChecksumAlgorithm {
+    /// Extension of the sidecar file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Sha512 => "sha512",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumFormat {
+    /// Only the checksum
+    #[default]
+    Hex,
+    /// The checksum and file name, as written by sha256sum and sha512sum
+    Coreutils,
+}
+
+/// Target that bundles the files it receives into tar archives, which are in
+/// turn dispatched from a source with the name of the target
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ArchiveTarget {
+    pub name: String,
+    pub directory: PathBuf,
+    /// Time in milliseconds after the first file of an archive at which the
+    /// archive is completed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roll_interval: Option<u64>,
+    /// Number of files at which an archive is completed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<usize>,
+    /// Size of the archived files in bytes at which an archive is completed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// Name of the archives, with strftime placeholders for the time at which
+    /// an archive is started
+    #[serde(default = "default_archive_file_name")]
+    pub file_name: String,
+    #[serde(default)]
+    pub compression: ArchiveCompression,
+}
+
+fn default_archive_file_name() -> String {
+    "%Y%m%d%H%M%S.tar".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveCompression {
+    #[default]
+    None,
+    Gzip,
 }
 
 fn default_local_target_method() -> LocalTargetMethod {
@@ -224,143 +797,887 @@ fn default_sftp_source_deduplication() -> Deduplication {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SftpSource {
     pub name: String,
-    pub address: String,
-    pub username: String,
-    pub password: Option<String>,
-    pub key_file: Option<PathBuf>,
+    #[serde(flatten)]
+    pub connection: SftpConnectionSettings,
+    /// SSH implementation the source is downloaded with
+    #[serde(default)]
+    pub backend: SftpBackend,
+    /// Checking of the host key of the server with the openssh backend
+    #[serde(default)]
+    pub host_key_check: HostKeyCheck,
     #[serde(default = "default_thread_count")]
     pub thread_count: usize,
-    #[serde(default = "default_false")]
-    pub compress: bool,
+    /// Maximum number of unacknowledged download commands, defaults to twice
+    /// the thread count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefetch_count: Option<u16>,
     #[serde(default = "default_sftp_source_deduplication")]
     pub deduplication: Deduplication,
+    /// Accept downloads that are larger than the remote size, for files that
+    /// grow while they are downloaded
+    #[serde(default = "default_false")]
+    pub allow_size_growth: bool,
+    /// Decompression of files while they are downloaded
+    #[serde(default)]
+    pub decompress: Decompress,
+    /// Which content the stored hash of decompressed files is calculated over
+    #[serde(default)]
+    pub stored_hash: StoredHash,
+    /// Maximum number of restarts of download threads that stopped, within
+    /// the restart window
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    #[serde(default = "default_restart_window_minutes")]
+    pub restart_window_minutes: u64,
+    /// Queue with download commands, defaults to source.<name>
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    /// Exchange the command queue is bound to
+    #[serde(default = "default_command_exchange")]
+    pub exchange: String,
+    /// Routing key of download commands, defaults to the queue name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_key: Option<String>,
+    /// Storage area to place the files in, the first one when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_area: Option<String>,
+    /// Directory in the storage to place the files in instead of the one
+    /// with the name of the source, or an absolute path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_subdirectory: Option<PathBuf>,
+    /// Set to true to place the files without their remote directories,
+    /// which are joined into the file name with underscores
+    #[serde(default = "default_false")]
+    pub flatten: bool,
+    /// Permissions of the stored files, overriding storage.file_permissions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_permissions: Option<String>,
+    /// Group of the stored files, overriding storage.group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Size of the buffers in which downloads are hashed and written on
+    /// separate threads, 0 to copy them on a single thread
+    #[serde(default = "default_io_buffer_size")]
+    pub io_buffer_size: usize,
+    /// Whether remote files are removed after downloading, overriding the
+    /// remove flag of the commands, which is applied again on SIGHUP
+    #[serde(default)]
+    pub remote_remove: RemoteRemove,
+    /// What to do when a remote file cannot be removed after downloading
+    #[serde(default)]
+    pub on_delete_failure: DeleteFailurePolicy,
+    /// Interval in seconds at which the removal of remote files that could
+    /// not be removed is retried, 0 to not retry
+    #[serde(default = "default_delete_retry_interval_seconds")]
+    pub delete_retry_interval_seconds: u64,
+    /// What to do when a stored file is replaced by one with different
+    /// content
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    /// Maximum number of previous versions kept with `on_conflict: version`
+    #[serde(default = "default_max_versions")]
+    pub max_versions: u32,
+    /// Set to true to lock downloads in the database, so that dispatcher
+    /// instances sharing it do not download the same file at once
+    #[serde(default = "default_false")]
+    pub distributed_locks: bool,
+    /// Registration of downloaded files in batches on a writer thread of the
+    /// source, instead of by each download thread
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_behind: Option<WriteBehind>,
 }
 
-/// Default Sftp downloader thread count
-fn default_thread_count() -> usize {
-    1
+/// Batching of the database writes of downloaded files
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WriteBehind {
+    /// Maximum number of files registered in one transaction
+    #[serde(default = "default_write_behind_batch_size")]
+    pub batch_size: usize,
+    /// Time in milliseconds that a batch waits for more files
+    #[serde(default = "default_write_behind_batch_interval_ms")]
+    pub batch_interval_ms: u64,
+    /// Set to false to acknowledge the command of a download before its
+    /// registration is committed. The file is dispatched once it is
+    /// committed, so that a crash can lose files of acknowledged commands
+    #[serde(default = "default_true")]
+    pub require_durable_ack: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Storage {
-    pub directory: PathBuf,
+fn default_write_behind_batch_size() -> usize {
+    100
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct CommandQueue {
-    pub address: String,
+fn default_write_behind_batch_interval_ms() -> u64 {
+    10
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Sqlite {
-    pub path: PathBuf,
+/// SSH implementation of an SFTP source
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SftpBackend {
+    #[default]
+    Libssh2,
+    /// The OpenSSH client of the system, which requires the openssh feature
+    /// and only authenticates with a key file without passphrase or the ssh
+    /// agent
+    Openssh,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct HttpServer {
-    pub address: std::net::SocketAddr,
+/// Decompression of files while they are downloaded, which removes the .gz
+/// extension from their name
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Decompress {
+    #[default]
+    None,
+    /// Decompress all files as gzip
+    Gzip,
+    /// Decompress files with a .gz extension as gzip
+    Auto,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Settings {
-    pub storage: Storage,
-    pub command_queue: CommandQueue,
-    #[serde(default = "default_directory_sources")]
-    pub directory_sources: Vec<DirectorySource>,
-    #[serde(default = "default_directory_targets")]
-    pub directory_targets: Vec<DirectoryTarget>,
-    #[serde(default = "default_sftp_sources")]
-    pub sftp_sources: Vec<SftpSource>,
-    pub connections: Vec<Connection>,
-    pub sqlite: Sqlite,
-    pub http_server: HttpServer,
-    #[serde(default = "default_scan_interval")]
-    pub scan_interval: u64,
+impl Decompress {
+    /// Whether the file at the remote path is decompressed
+    pub fn applies_to(&self, path: &str) -> bool {
+        match self {
+            Decompress::None => false,
+            Decompress::Gzip => true,
+            Decompress::Auto => path.ends_with(".gz"),
+        }
+    }
 }
 
-/// Default directory scan (sweep) interval
-fn default_scan_interval() -> u64 {
-    60_000
+/// Removal of remote files after downloading
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteRemove {
+    /// Remove the file when the command asks for it
+    #[default]
+    Inherit,
+    /// Never remove the file, e.g. while the provider investigates an issue
+    Never,
+    /// Always remove the file
+    Always,
 }
 
-fn default_directory_sources() -> Vec<DirectorySource> {
-    vec![]
+impl RemoteRemove {
+    /// Whether to remove a remote file of which the command has `remove`
+    pub fn applies(&self, remove: bool) -> bool {
+        match self {
+            RemoteRemove::Inherit => remove,
+            RemoteRemove::Never => false,
+            RemoteRemove::Always => true,
+        }
+    }
 }
 
-fn default_directory_targets() -> Vec<DirectoryTarget> {
-    vec![]
+/// Handling of remote files that cannot be removed after downloading
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteFailurePolicy {
+    /// Only count the failure
+    Ignore,
+    /// Log a warning and retry the removal later
+    #[default]
+    Warn,
+    /// Log an error, retry the removal later and requeue the command, so
+    /// that it is handled again as a whole
+    Error,
 }
 
-fn default_sftp_sources() -> Vec<SftpSource> {
-    vec![]
+/// Handling of a file that is stored under the path of a stored file with
+/// different content
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Replace the stored file
+    #[default]
+    Overwrite,
+    /// Keep the stored file as `name.1`, `name.2`, ...
+    Version,
+    /// Keep the stored file and drop the new one
+    Skip,
 }
 
-impl Default for Settings {
-    fn default() -> Self {
-        Settings {
-            storage: Storage {
-                directory: PathBuf::from("/cortex/storage"),
-            },
-            command_queue: CommandQueue {
-                address: "127.0.0.1:5672".parse().unwrap(),
-            },
-            directory_sources: vec![DirectorySource {
-                name: "mixed-directory".to_string(),
-                directory: PathBuf::from("/cortex/incoming"),
-                events: vec![FileSystemEvent::MovedTo, FileSystemEvent::CloseWrite],
-                filter: None,
-                recursive: true,
-                deduplication: Deduplication::Check(FileComparison {
-                    size: false,
-                    modified: false,
+/// Content over which the stored hash of decompressed files is calculated
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StoredHash {
+    /// The file as it was downloaded
+    #[default]
+    Original,
+    Decompressed,
+}
+
+/// Where the download commands of an SFTP source are published and consumed
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandRoute {
+    pub queue: String,
+    pub exchange: String,
+    pub routing_key: String,
+}
+
+impl SftpSource {
+    pub fn sftp_config(&self) -> SftpConfig {
+        SftpConfig {
+            // Resolved into key_passphrase when the settings are loaded
+            key_passphrase_file: None,
+            host_key_check: self.host_key_check,
+            ..self.connection.sftp_config()
+        }
+    }
+
+    /// Number of download commands the broker may deliver before they are
+    /// acknowledged
+    pub fn prefetch_count(&self) -> u16 {
+        self.prefetch_count
+            .unwrap_or(u16::try_from(self.thread_count * 2).unwrap_or(u16::MAX))
+    }
+
+    pub fn command_route(&self) -> CommandRoute {
+        let queue = self
+            .queue
+            .clone()
+            .unwrap_or_else(|| sftp_source_routing_key(&self.name));
+
+        CommandRoute {
+            routing_key: self.routing_key.clone().unwrap_or_else(|| queue.clone()),
+            exchange: self.exchange.clone(),
+            queue,
+        }
+    }
+}
+
+fn default_max_restarts() -> u32 {
+    5
+}
+
+fn default_restart_window_minutes() -> u64 {
+    10
+}
+
+fn default_io_buffer_size() -> usize {
+    1024 * 1024
+}
+
+fn default_delete_retry_interval_seconds() -> u64 {
+    600
+}
+
+fn default_max_versions() -> u32 {
+    10
+}
+
+fn default_command_exchange() -> String {
+    DEFAULT_COMMAND_EXCHANGE.to_string()
+}
+
+/// Default Sftp downloader thread count
+fn default_thread_count() -> usize {
+    1
+}
+
+/// Area of internal storage in a directory of its own, e.g. on a faster or
+/// larger disk
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Storage {
+    /// Name by which sources select the area
+    #[serde(default = "default_storage_area_name")]
+    pub name: String,
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub layout: StorageLayout,
+    /// Size in bytes above which no more files are downloaded or ingested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// Fraction of max_bytes above which a warning is logged
+    #[serde(default = "default_warn_ratio")]
+    pub warn_ratio: f64,
+    /// Interval in milliseconds between measurements of the storage usage
+    #[serde(default = "default_usage_interval")]
+    pub usage_interval: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+    /// Permissions of stored files as an octal string, e.g. "0640", instead
+    /// of the ones following from the umask
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_permissions: Option<String>,
+    /// Name or id of the group of stored files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Create the directory on startup when it does not exist, instead of
+    /// failing
+    #[serde(default = "default_true")]
+    pub create_missing: bool,
+    /// Syncing of downloaded files to disk before they are stored under
+    /// their regular name
+    #[serde(default)]
+    pub fsync: Fsync,
+}
+
+/// Name of the storage area when a single one is configured without a name
+pub const DEFAULT_STORAGE_AREA: &str = "default";
+
+fn default_storage_area_name() -> String {
+    DEFAULT_STORAGE_AREA.to_string()
+}
+
+/// Storage configured as a single area or as a list of named areas
+fn deserialize_storage_areas<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Storage>, D::Error> {
+    struct StorageAreasVisitor;
+
+    impl<'de> Visitor<'de> for StorageAreasVisitor {
+        type Value = Vec<Storage>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a storage area or a list of storage areas")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Vec<Storage>, A::Error> {
+            Storage::deserialize(de::value::MapAccessDeserializer::new(map))
+                .map(|storage| vec![storage])
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Vec<Storage>, A::Error> {
+            let areas = Vec::<Storage>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
+
+            if areas.is_empty() {
+                return Err(de::Error::custom("storage needs at least one area"));
+            }
+
+            Ok(areas)
+        }
+    }
+
+    deserializer.deserialize_any(StorageAreasVisitor)
+}
+
+/// Syncing of files to disk when they are placed in a directory
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Fsync {
+    #[default]
+    None,
+    /// Sync the data of the file before it appears under its name
+    File,
+    /// Also sync the directory after the file appeared in it
+    FileAndDir,
+}
+
+/// Parse file permissions written as an octal string, e.g. "0640"
+pub fn parse_file_permissions(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| {
+            format!("Invalid file permissions '{value}', expected an octal value like '0640'")
+        })
+}
+
+/// Name of the directory in internal storage that holds the objects of the
+/// content-addressed layout
+pub const OBJECTS_DIRECTORY: &str = "objects";
+
+/// How files are laid out in internal storage
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageLayout {
+    /// Every file is stored separately in a directory per source
+    #[default]
+    PerSource,
+    /// File contents are stored once under `objects`, named by their hash,
+    /// and hardlinked from the directory per source
+    ContentAddressed,
+}
+
+/// Removal of files from internal storage after a retention period
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Retention {
+    /// Number of days that files are kept after they are registered
+    pub keep_days: u32,
+    /// Only remove files that have been dispatched to all targets connected to
+    /// their source
+    #[serde(default = "default_true")]
+    pub only_if_dispatched: bool,
+    /// Interval in milliseconds between cleanups
+    #[serde(default = "default_retention_interval")]
+    pub interval: u64,
+}
+
+fn default_retention_interval() -> u64 {
+    3_600_000
+}
+
+/// Spool files of notifications, in the spool directory of the first storage
+/// area
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NotificationSpool {
+    /// Number of publish attempts, each on a new connection, after which a
+    /// notification is spooled
+    #[serde(default = "default_spool_publish_attempts")]
+    pub publish_attempts: u32,
+    /// Size in bytes of the spool file of a target above which the oldest
+    /// notifications are dropped
+    #[serde(default = "default_spool_max_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_spool_publish_attempts() -> u32 {
+    3
+}
+
+fn default_spool_max_bytes() -> u64 {
+    100_000_000
+}
+
+/// Dispatching of stored files that were never dispatched, e.g. because the
+/// targets were down while files were downloaded
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Reconcile {
+    /// Number of hours before startup in which the registered files are
+    /// checked
+    #[serde(default = "default_reconcile_window_hours")]
+    pub window_hours: u64,
+    /// Maximum number of files dispatched per second
+    #[serde(default = "default_reconcile_files_per_second")]
+    pub files_per_second: f64,
+}
+
+fn default_reconcile_window_hours() -> u64 {
+    24
+}
+
+fn default_reconcile_files_per_second() -> f64 {
+    10.0
+}
+
+impl Default for Reconcile {
+    fn default() -> Self {
+        Reconcile {
+            window_hours: default_reconcile_window_hours(),
+            files_per_second: default_reconcile_files_per_second(),
+        }
+    }
+}
+
+fn default_warn_ratio() -> f64 {
+    0.9
+}
+
+fn default_usage_interval() -> u64 {
+    300_000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CommandQueue {
+    #[serde(default)]
+    pub address: Secret,
+    /// File to read the address from, instead of specifying it inline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amqp_tls: Option<AmqpTls>,
+    /// Queue on which the service receives control commands, e.g. for
+    /// re-dispatching files
+    #[serde(default = "default_control_queue")]
+    pub control_queue: String,
+    /// Where download commands go that keep failing, when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead_letter: Option<DeadLetter>,
+    /// Interval in milliseconds between reads of the command queue depth
+    #[serde(default = "default_queue_poll_interval")]
+    pub queue_poll_interval: u64,
+}
+
+fn default_queue_poll_interval() -> u64 {
+    30_000
+}
+
+fn default_control_queue() -> String {
+    "cortex-dispatcher.control".to_string()
+}
+
+/// Dead-lettering of SFTP download commands that keep failing
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLetter {
+    /// Exchange to which failed commands are routed, declared as fanout
+    #[serde(default = "default_dead_letter_exchange")]
+    pub exchange: String,
+    /// Queue in which failed commands are kept, bound to the exchange
+    #[serde(default = "default_dead_letter_queue")]
+    pub queue: String,
+    /// Number of times a failed download is retried before it is
+    /// dead-lettered
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+}
+
+fn default_dead_letter_exchange() -> String {
+    "cortex-dispatcher.dead-letter".to_string()
+}
+
+fn default_dead_letter_queue() -> String {
+    "cortex-dispatcher.failed-commands".to_string()
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Sqlite {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpServer {
+    pub address: std::net::SocketAddr,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Settings {
+    /// Storage areas, of which the first is used by sources that select none
+    #[serde(deserialize_with = "deserialize_storage_areas")]
+    pub storage: Vec<Storage>,
+    pub command_queue: CommandQueue,
+    #[serde(default = "default_directory_sources")]
+    pub directory_sources: Vec<DirectorySource>,
+    #[serde(default = "default_directory_targets")]
+    pub directory_targets: Vec<DirectoryTarget>,
+    /// Notification settings by name, which directory targets can refer to
+    /// instead of repeating them
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub notifiers: HashMap<String, Notify>,
+    #[serde(default = "default_sftp_sources")]
+    pub sftp_sources: Vec<SftpSource>,
+    #[serde(default = "default_archive_targets")]
+    pub archive_targets: Vec<ArchiveTarget>,
+    pub connections: Vec<Connection>,
+    pub sqlite: Sqlite,
+    pub http_server: HttpServer,
+    #[serde(default = "default_scan_interval")]
+    pub scan_interval: u64,
+    #[serde(default = "default_logging")]
+    pub logging: Logging,
+    #[serde(default = "default_channels")]
+    pub channels: Channels,
+    /// Record the decision to dispatch or not for every file and connection
+    #[serde(default = "default_false")]
+    pub audit: bool,
+    /// File that keeps the targets and connections changed through the HTTP
+    /// API, so that the changes are applied again after a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime_overrides: Option<PathBuf>,
+    /// Dispatch the files on startup that were stored but not dispatched to
+    /// all targets connected to their source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconcile: Option<Reconcile>,
+    /// Milliseconds between the heartbeats of the components in the
+    /// database, 0 disables them
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    /// Maximum number of SFTP sessions open at the same time per remote host,
+    /// over all SFTP sources
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sftp_connection_limits: HashMap<String, usize>,
+    /// Milliseconds during which repeated errors of the same kind for the
+    /// same source or target are not logged again, 0 logs every error
+    #[serde(default = "default_error_log_window")]
+    pub error_log_window: u64,
+    /// Publishers of the file events of all sources, independent of the
+    /// targets
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub event_publishers: Vec<EventPublisher>,
+    /// Summaries sent periodically by webhook or email
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reports: Vec<Report>,
+    /// Spooling of RabbitMQ notifications of directory targets that cannot
+    /// be published, instead of retrying them until the broker is back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notification_spool: Option<NotificationSpool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    Stderr,
+    File,
+}
+
+/// Log file that is rotated when it reaches its maximum size
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    /// Size in megabytes at which the file is rotated
+    #[serde(default = "default_log_max_size_mb")]
+    pub max_size_mb: u64,
+    /// Number of rotated files to keep
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+}
+
+fn default_log_max_size_mb() -> u64 {
+    100
+}
+
+fn default_log_max_files() -> usize {
+    5
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Logging {
+    /// Where log output is written to, the file target requires a file to be
+    /// configured
+    #[serde(default = "default_log_targets")]
+    pub targets: Vec<LogTarget>,
+    pub file: Option<LogFile>,
+}
+
+fn default_log_targets() -> Vec<LogTarget> {
+    vec![LogTarget::Stderr, LogTarget::File]
+}
+
+fn default_logging() -> Logging {
+    Logging {
+        targets: default_log_targets(),
+        file: None,
+    }
+}
+
+/// What happens to a file event when its channel is full
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Wait until there is room in the channel
+    Block,
+    /// Drop the oldest event in the channel to make room
+    DropOldest,
+    /// Drop the new event and report an error
+    Error,
+}
+
+/// Capacities of the channels between the components of the dispatcher
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Channels {
+    /// Download commands waiting for an SFTP download thread
+    #[serde(default = "default_sftp_command_capacity")]
+    pub sftp_command_capacity: usize,
+    /// Download results waiting to be acknowledged to the broker
+    #[serde(default = "default_ack_capacity")]
+    pub ack_capacity: usize,
+    /// File events of each source and target, unbounded when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_event_capacity: Option<usize>,
+    /// What happens when a file event channel is full
+    #[serde(default = "default_overflow")]
+    pub overflow: Overflow,
+}
+
+fn default_sftp_command_capacity() -> usize {
+    10
+}
+
+fn default_ack_capacity() -> usize {
+    100
+}
+
+fn default_overflow() -> Overflow {
+    Overflow::Block
+}
+
+fn default_channels() -> Channels {
+    Channels {
+        sftp_command_capacity: default_sftp_command_capacity(),
+        ack_capacity: default_ack_capacity(),
+        file_event_capacity: None,
+        overflow: default_overflow(),
+    }
+}
+
+/// Default directory scan (sweep) interval
+fn default_scan_interval() -> u64 {
+    60_000
+}
+
+fn default_heartbeat_interval() -> u64 {
+    30_000
+}
+
+fn default_error_log_window() -> u64 {
+    log_throttle::DEFAULT_WINDOW
+}
+
+fn default_event_publisher_capacity() -> usize {
+    1000
+}
+
+fn default_directory_sources() -> Vec<DirectorySource> {
+    vec![]
+}
+
+fn default_directory_targets() -> Vec<DirectoryTarget> {
+    vec![]
+}
+
+fn default_sftp_sources() -> Vec<SftpSource> {
+    vec![]
+}
+
+fn default_archive_targets() -> Vec<ArchiveTarget> {
+    vec![]
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            storage: vec![Storage {
+                name: default_storage_area_name(),
+                directory: PathBuf::from("/cortex/storage"),
+                layout: StorageLayout::PerSource,
+                max_bytes: None,
+                warn_ratio: default_warn_ratio(),
+                usage_interval: default_usage_interval(),
+                retention: None,
+                file_permissions: None,
+                group: None,
+                create_missing: true,
+                fsync: Fsync::None,
+            }],
+            command_queue: CommandQueue {
+                address: Secret::from("127.0.0.1:5672"),
+                address_file: None,
+                amqp_tls: None,
+                control_queue: default_control_queue(),
+                dead_letter: None,
+                queue_poll_interval: default_queue_poll_interval(),
+            },
+            directory_sources: vec![DirectorySource {
+                name: "mixed-directory".to_string(),
+                directory: PathBuf::from("/cortex/incoming"),
+                events: vec![FileSystemEvent::MovedTo, FileSystemEvent::CloseWrite],
+                filter: None,
+                recursive: true,
+                deduplication: Deduplication::Check(FileComparison {
+                    size: false,
+                    modified: false,
                     hash: true,
                 }),
                 unpack_before_hash: false,
                 delete: true,
+                storage_area: None,
+                storage_subdirectory: None,
+                flatten: false,
+                file_permissions: None,
+                group: None,
+                create_missing: false,
+                on_conflict: ConflictPolicy::Overwrite,
+                max_versions: default_max_versions(),
             }],
             directory_targets: vec![DirectoryTarget {
                 name: "red".to_string(),
                 directory: PathBuf::from("/cortex/storage/red-consumer"),
                 method: LocalTargetMethod::Hardlink,
                 overwrite: true,
-                notify: Some(Notify::RabbitMQ(RabbitMQNotify {
+                notify: Some(TargetNotify::Inline(Notify::RabbitMQ(RabbitMQNotify {
                     message_template: "".to_string(),
-                    address: "127.0.0.1:5672".parse().unwrap(),
+                    address: Secret::from("127.0.0.1:5672"),
+                    address_file: None,
+                    amqp_tls: None,
                     exchange: "".to_string(),
                     routing_key: "red-consumer".to_string(),
-                })),
+                }))),
                 permissions: 100,
+                checksum_sidecar: None,
+                rate_limit: None,
+                concurrency: default_concurrency(),
+                create_missing: true,
+                preserve_structure: false,
+                fsync: Fsync::None,
+                verify_hash_on_dispatch: false,
+                max_verify_size_bytes: None,
+                verify_bytes_per_second: None,
             }],
             sftp_sources: vec![
                 SftpSource {
                     name: "red".to_string(),
-                    address: "127.0.0.1:22".parse().unwrap(),
-                    username: "cortex".to_string(),
-                    password: Some("password".to_string()),
-                    key_file: None,
-                    compress: false,
+                    connection: SftpConnectionSettings::with_password(
+                        "127.0.0.1:22",
+                        "cortex",
+                        "password",
+                    ),
+                    backend: SftpBackend::Libssh2,
+                    host_key_check: HostKeyCheck::Strict,
                     thread_count: 4,
+                    prefetch_count: None,
                     deduplication: Deduplication::Check(FileComparison {
                         size: true,
                         modified: true,
                         hash: false,
                     }),
+                    max_restarts: default_max_restarts(),
+                    restart_window_minutes: default_restart_window_minutes(),
+                    allow_size_growth: false,
+                    decompress: Decompress::None,
+                    stored_hash: StoredHash::Original,
+                    queue: None,
+                    exchange: default_command_exchange(),
+                    routing_key: None,
+                    storage_area: None,
+                    storage_subdirectory: None,
+                    flatten: false,
+                    file_permissions: None,
+                    group: None,
+                    io_buffer_size: default_io_buffer_size(),
+                    remote_remove: RemoteRemove::Inherit,
+                    on_delete_failure: DeleteFailurePolicy::Warn,
+                    delete_retry_interval_seconds: default_delete_retry_interval_seconds(),
+                    on_conflict: ConflictPolicy::Overwrite,
+                    max_versions: default_max_versions(),
+                    distributed_locks: false,
+                    write_behind: None,
                 },
                 SftpSource {
                     name: "blue".to_string(),
-                    address: "127.0.0.1:22".parse().unwrap(),
-                    username: "cortex".to_string(),
-                    password: Some("password".to_string()),
-                    key_file: None,
-                    compress: false,
+                    connection: SftpConnectionSettings::with_password(
+                        "127.0.0.1:22",
+                        "cortex",
+                        "password",
+                    ),
+                    backend: SftpBackend::Libssh2,
+                    host_key_check: HostKeyCheck::Strict,
                     thread_count: 4,
+                    prefetch_count: None,
                     deduplication: Deduplication::Check(FileComparison {
                         size: true,
                         modified: true,
                         hash: false,
                     }),
+                    max_restarts: default_max_restarts(),
+                    restart_window_minutes: default_restart_window_minutes(),
+                    allow_size_growth: false,
+                    decompress: Decompress::None,
+                    stored_hash: StoredHash::Original,
+                    queue: None,
+                    exchange: default_command_exchange(),
+                    routing_key: None,
+                    storage_area: None,
+                    storage_subdirectory: None,
+                    flatten: false,
+                    file_permissions: None,
+                    group: None,
+                    io_buffer_size: default_io_buffer_size(),
+                    remote_remove: RemoteRemove::Inherit,
+                    on_delete_failure: DeleteFailurePolicy::Warn,
+                    delete_retry_interval_seconds: default_delete_retry_interval_seconds(),
+                    on_conflict: ConflictPolicy::Overwrite,
+                    max_versions: default_max_versions(),
+                    distributed_locks: false,
+                    write_behind: None,
                 },
             ],
+            archive_targets: default_archive_targets(),
             connections: vec![],
             sqlite: Sqlite {
                 path: PathBuf::from("cortex.db"),
@@ -369,6 +1686,1397 @@ impl Default for Settings {
                 address: "0.0.0.0:56008".parse().unwrap(),
             },
             scan_interval: 60_000,
+            logging: default_logging(),
+            channels: default_channels(),
+            audit: false,
+            runtime_overrides: None,
+            reconcile: None,
+            heartbeat_interval: default_heartbeat_interval(),
+            sftp_connection_limits: HashMap::new(),
+            notifiers: HashMap::new(),
+            error_log_window: default_error_log_window(),
+            event_publishers: Vec::new(),
+            reports: Vec::new(),
+            notification_spool: None,
+        }
+    }
+}
+
+/// Configuration file used when none is specified on the command line
+pub const DEFAULT_CONFIG_FILE: &str = "/etc/cortex/cortex.yaml";
+
+/// Prefix of environment variables that override configuration values
+///
+/// Path segments are separated by a double underscore and list elements are
+/// addressed by their index, e.g. `CORTEX__SQLITE__PATH` or
+/// `CORTEX__SFTP_SOURCES__0__PASSWORD`.
+pub const ENV_PREFIX: &str = "CORTEX__";
+
+const ENV_SEPARATOR: &str = "__";
+
+/// Lists of which the entries in later configuration files replace the
+/// entries with the same name in earlier files, instead of the whole list
+const MERGED_LISTS: &[&str] = &[
+    "sftp_sources",
+    "directory_sources",
+    "directory_targets",
+    "archive_targets",
+    "connections",
+];
+
+/// Load the settings from YAML configuration files and directories of
+/// `*.yaml` files, merged in order, with overrides from `CORTEX__`
+/// environment variables applied on top
+///
+/// Later files override the scalars of earlier files. The entries of the
+/// source, target and connection lists are merged by name, or by source and
+/// target for connections, so that later files add entries or replace them.
+pub fn load_settings_files(paths: &[String]) -> Result<Settings, String> {
+    let config_files = config_files(paths)?;
+
+    let name = config_files
+        .iter()
+        .map(|path| path.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut merged = serde_json::Map::new();
+
+    for config_file in &config_files {
+        let config: serde_json::Map<String, serde_json::Value> = config::Config::builder()
+            .add_source(config::File::from(config_file.as_path()).format(config::FileFormat::Yaml))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .map_err(|e| {
+                format!(
+                    "Error loading configuration from '{}': {e}",
+                    config_file.to_string_lossy()
+                )
+            })?;
+
+        merge_config(&mut merged, config);
+    }
+
+    load_settings_from(
+        config::File::from_str(
+            &serde_json::Value::Object(merged).to_string(),
+            config::FileFormat::Json,
+        ),
+        &name,
+        std::env::vars(),
+    )
+}
+
+/// Configuration paths given on the command line, or the default
+/// configuration file when none are given
+pub fn config_paths(config: &[String]) -> Vec<String> {
+    if config.is_empty() {
+        vec![DEFAULT_CONFIG_FILE.to_string()]
+    } else {
+        config.to_vec()
+    }
+}
+
+/// Configuration files of the paths, in order, with the `*.yaml` and `*.yml`
+/// files of directories in lexical order
+pub fn config_files(paths: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut config_files = Vec::new();
+
+    for path in paths.iter().map(PathBuf::from) {
+        if !path.is_dir() {
+            config_files.push(path);
+            continue;
+        }
+
+        let entries = std::fs::read_dir(&path).map_err(|e| {
+            format!(
+                "Error reading configuration directory '{}': {e}",
+                path.to_string_lossy()
+            )
+        })?;
+
+        let mut directory_files = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|file| {
+                file.is_file()
+                    && file
+                        .extension()
+                        .is_some_and(|extension| extension == "yaml" || extension == "yml")
+            })
+            .collect::<Vec<PathBuf>>();
+
+        if directory_files.is_empty() {
+            return Err(format!(
+                "No configuration files in directory '{}'",
+                path.to_string_lossy()
+            ));
+        }
+
+        directory_files.sort();
+
+        config_files.append(&mut directory_files);
+    }
+
+    Ok(config_files)
+}
+
+/// Merge a configuration file into the configuration of the files before it
+fn merge_config(
+    merged: &mut serde_json::Map<String, serde_json::Value>,
+    config: serde_json::Map<String, serde_json::Value>,
+) {
+    for (key, value) in config {
+        let merged_value = merged.entry(key.clone()).or_insert(serde_json::Value::Null);
+
+        match (merged_value, value) {
+            (serde_json::Value::Array(entries), serde_json::Value::Array(new_entries))
+                if MERGED_LISTS.contains(&key.as_str()) =>
+            {
+                merge_entries(entries, new_entries)
+            }
+            (serde_json::Value::Object(map), serde_json::Value::Object(new_map)) => {
+                merge_config(map, new_map)
+            }
+            (merged_value, value) => *merged_value = value,
+        }
+    }
+}
+
+/// Replace the entries of earlier files with the same key by the new entries
+/// and append the others
+///
+/// Identical entries are only kept once, so that an entry repeated in
+/// another file does not count as a duplicate. Differing entries with the
+/// same key in one file are kept, for the validation to report.
+fn merge_entries(entries: &mut Vec<serde_json::Value>, new_entries: Vec<serde_json::Value>) {
+    // Entries of earlier files that are replaced, each only once
+    let mut replaced = vec![false; entries.len()];
+
+    for new_entry in new_entries {
+        if entries.contains(&new_entry) {
+            continue;
         }
+
+        let key = entry_key(&new_entry);
+
+        let index = (0..replaced.len())
+            .find(|index| !replaced[*index] && key.is_some() && entry_key(&entries[*index]) == key);
+
+        match index {
+            Some(index) => {
+                entries[index] = new_entry;
+                replaced[index] = true;
+            }
+            None => entries.push(new_entry),
+        }
+    }
+}
+
+/// Name of a list entry, or source and target of a connection
+fn entry_key(entry: &serde_json::Value) -> Option<String> {
+    match entry.get("name") {
+        Some(name) => Some(name.to_string()),
+        None => Some(format!(
+            "{} -> {}",
+            entry.get("source")?,
+            entry.get("target")?
+        )),
+    }
+}
+
+fn load_settings_from<S, I>(source: S, name: &str, vars: I) -> Result<Settings, String>
+where
+    S: config::Source + Send + Sync + 'static,
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut builder = config::Config::builder().add_source(source);
+
+    for (path, value) in env_overrides(vars) {
+        builder = builder
+            .set_override(&path, value)
+            .map_err(|e| format!("Error applying environment override '{path}': {e}"))?;
+    }
+
+    let config = builder
+        .build()
+        .map_err(|e| format!("Error loading configuration from '{name}': {e}"))?;
+
+    let mut settings: Settings = config
+        .try_deserialize()
+        .map_err(|e| format!("Error deserializing configuration from '{name}': {e}"))?;
+
+    settings
+        .resolve_secret_files()
+        .map_err(|e| format!("Error loading configuration from '{name}': {e}"))?;
+
+    Ok(settings)
+}
+
+/// Resolve a required address that can be specified inline or through an
+/// `address_file` field
+fn resolve_address(name: &str, address: &Secret, file: Option<&Path>) -> Result<Secret, String> {
+    let address = (!address.is_empty()).then_some(address);
+
+    resolve_secret(name, address, file)?.ok_or(format!("One of {name} and {name}_file must be set"))
+}
+
+/// Log the configuration values that are set from the environment, with
+/// secrets redacted
+pub fn log_env_overrides() {
+    for (path, value) in env_overrides(std::env::vars()) {
+        debug!(
+            "Configuration value '{}' set from environment: {}",
+            &path,
+            if is_secret(&path) {
+                "<redacted>"
+            } else {
+                &value
+            }
+        );
+    }
+}
+
+/// Translate `CORTEX__` environment variables into configuration paths
+///
+/// Numeric segments become list subscripts, so that
+/// `CORTEX__SFTP_SOURCES__0__PASSWORD` maps to `sftp_sources[0].password`.
+fn env_overrides<I>(vars: I) -> Vec<(String, String)>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut overrides: Vec<(String, String)> = vars
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let key = key.strip_prefix(ENV_PREFIX)?;
+
+            let mut path = String::new();
+
+            for segment in key.split(ENV_SEPARATOR) {
+                if segment.is_empty() {
+                    return None;
+                }
+
+                if segment.chars().all(|c| c.is_ascii_digit()) {
+                    if path.is_empty() {
+                        return None;
+                    }
+
+                    path.push_str(&format!("[{segment}]"));
+                } else {
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+
+                    path.push_str(&segment.to_lowercase());
+                }
+            }
+
+            Some((path, value))
+        })
+        .collect();
+
+    // Apply in a stable order, independent of the environment
+    overrides.sort();
+
+    overrides
+}
+
+/// Configuration values that may contain credentials
+fn is_secret(path: &str) -> bool {
+    let field = path.rsplit('.').next().unwrap_or(path);
+
+    matches!(
+        field,
+        "password" | "sasl_password" | "key_passphrase" | "address" | "url"
+    )
+}
+
+impl Settings {
+    /// Populate fields that are configured through a `_file` variant
+    ///
+    /// The `_file` fields are cleared once their content has been read.
+    fn resolve_secret_files(&mut self) -> Result<(), String> {
+        self.command_queue.address = resolve_address(
+            "command_queue.address",
+            &self.command_queue.address,
+            self.command_queue.address_file.take().as_deref(),
+        )?;
+
+        for (index, sftp_source) in self.sftp_sources.iter_mut().enumerate() {
+            sftp_source
+                .connection
+                .resolve_secret_files(&format!("sftp_sources[{index}]"))?;
+        }
+
+        for (index, directory_target) in self.directory_targets.iter_mut().enumerate() {
+            directory_target.resolve_secret_files(&format!("directory_targets[{index}]"))?;
+        }
+
+        for (name, notify) in self.notifiers.iter_mut() {
+            notify.resolve_secret_files(&format!("notifiers.{name}"))?;
+        }
+
+        for (index, event_publisher) in self.event_publishers.iter_mut().enumerate() {
+            event_publisher.address = resolve_address(
+                &format!("event_publishers[{index}].address"),
+                &event_publisher.address,
+                event_publisher.address_file.take().as_deref(),
+            )?;
+        }
+
+        for (index, report) in self.reports.iter_mut().enumerate() {
+            if let ReportTransport::Smtp(smtp) = &mut report.transport {
+                smtp.password = resolve_secret(
+                    &format!("reports[{index}].transport.smtp.password"),
+                    smtp.password.as_ref(),
+                    smtp.password_file.take().as_deref(),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Storage area in which the files of a source are placed, the first
+    /// one when the source selects none or an unknown one
+    pub fn storage_area(&self, source_name: &str) -> &Storage {
+        let area_name = self
+            .directory_sources
+            .iter()
+            .find(|source| source.name == source_name)
+            .map(|source| &source.storage_area)
+            .or_else(|| {
+                self.sftp_sources
+                    .iter()
+                    .find(|source| source.name == source_name)
+                    .map(|source| &source.storage_area)
+            })
+            .and_then(Option::as_deref);
+
+        area_name
+            .and_then(|name| self.storage.iter().find(|area| area.name == name))
+            .unwrap_or(&self.storage[0])
+    }
+
+    /// Check the consistency of the settings without touching the environment
+    ///
+    /// Returns a list of problems found, which is empty when the settings are
+    /// valid.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+
+        let mut source_names: HashSet<&str> = HashSet::new();
+
+        for name in self
+            .directory_sources
+            .iter()
+            .map(|s| s.name.as_str())
+            .chain(self.sftp_sources.iter().map(|s| s.name.as_str()))
+            .chain(self.archive_targets.iter().map(|t| t.name.as_str()))
+        {
+            if !source_names.insert(name) {
+                problems.push(format!("Duplicate source name '{name}'"));
+            }
+        }
+
+        let mut target_names: HashSet<&str> = HashSet::new();
+
+        for target in &self.directory_targets {
+            if !target_names.insert(target.name.as_str()) {
+                problems.push(format!("Duplicate directory target name '{}'", target.name));
+            }
+
+            problems.extend(target.validate());
+            problems.extend(target.validate_notifier(&self.notifiers));
+        }
+
+        for (name, notify) in &self.notifiers {
+            notify.validate(&format!("Notifier '{name}'"), &mut problems);
+
+            if let Notify::RabbitMQ(RabbitMQNotify {
+                amqp_tls: Some(amqp_tls),
+                ..
+            }) = notify
+            {
+                amqp_tls.validate(
+                    &format!("notifiers.{name}.rabbitmq.amqp_tls"),
+                    &mut problems,
+                );
+            }
+        }
+
+        for target in &self.archive_targets {
+            if !target_names.insert(target.name.as_str()) {
+                problems.push(format!("Duplicate target name '{}'", target.name));
+            }
+
+            if target.roll_interval.is_none()
+                && target.max_files.is_none()
+                && target.max_bytes.is_none()
+            {
+                problems.push(format!(
+                    "Archive target '{}' needs a roll_interval, max_files or max_bytes",
+                    target.name
+                ));
+            }
+
+            if target.roll_interval == Some(0)
+                || target.max_files == Some(0)
+                || target.max_bytes == Some(0)
+            {
+                problems.push(format!(
+                    "Archive target '{}' has a roll_interval, max_files or max_bytes of 0",
+                    target.name
+                ));
+            }
+
+            if fmt::write(
+                &mut String::new(),
+                format_args!("{}", Utc::now().format(&target.file_name)),
+            )
+            .is_err()
+            {
+                problems.push(format!(
+                    "Archive target '{}' has an invalid file_name '{}'",
+                    target.name, target.file_name
+                ));
+            }
+
+            if target.file_name.contains('/') {
+                problems.push(format!(
+                    "Archive target '{}' has a file_name with a directory",
+                    target.name
+                ));
+            }
+        }
+
+        for connection in &self.connections {
+            if !source_names.contains(connection.source.as_str()) {
+                problems.push(format!(
+                    "Connection {} -> {} references unknown source '{}'",
+                    connection.source, connection.target, connection.source
+                ));
+            }
+
+            if !target_names.contains(connection.target.as_str()) {
+                problems.push(format!(
+                    "Connection {} -> {} references unknown target '{}'",
+                    connection.source, connection.target, connection.target
+                ));
+            }
+
+            if connection.require_delivery {
+                if !self
+                    .sftp_sources
+                    .iter()
+                    .any(|source| source.name == connection.source)
+                {
+                    problems.push(format!(
+                        "Connection {} -> {} requires delivery, which is only supported for SFTP sources",
+                        connection.source, connection.target
+                    ));
+                }
+
+                if !self
+                    .directory_targets
+                    .iter()
+                    .any(|target| target.name == connection.target)
+                {
+                    problems.push(format!(
+                        "Connection {} -> {} requires delivery, which is only supported for directory targets",
+                        connection.source, connection.target
+                    ));
+                }
+
+                if connection.delivery_timeout_seconds == 0 {
+                    problems.push(format!(
+                        "Connection {} -> {} has a delivery_timeout_seconds of 0",
+                        connection.source, connection.target
+                    ));
+                }
+            }
+        }
+
+        for sftp_source in &self.sftp_sources {
+            if sftp_source.thread_count == 0 {
+                problems.push(format!(
+                    "SFTP source '{}' has a thread_count of 0",
+                    sftp_source.name
+                ));
+            }
+
+            if sftp_source.connection.key_passphrase.is_some()
+                && sftp_source.connection.key_file.is_none()
+            {
+                problems.push(format!(
+                    "SFTP source '{}' has a key_passphrase but no key_file",
+                    sftp_source.name
+                ));
+            }
+
+            if sftp_source.backend == SftpBackend::Openssh {
+                if !cfg!(feature = "openssh") {
+                    problems.push(format!(
+                        "SFTP source '{}' uses the openssh backend, which requires the openssh feature",
+                        sftp_source.name
+                    ));
+                }
+
+                if sftp_source.connection.password.is_some()
+                    || sftp_source.connection.password_file.is_some()
+                {
+                    problems.push(format!(
+                        "SFTP source '{}' has a password, which the openssh backend does not support",
+                        sftp_source.name
+                    ));
+                }
+
+                if sftp_source.connection.key_passphrase.is_some()
+                    || sftp_source.connection.key_passphrase_file.is_some()
+                {
+                    problems.push(format!(
+                        "SFTP source '{}' has a key passphrase, which the openssh backend does not support",
+                        sftp_source.name
+                    ));
+                }
+            }
+
+            if sftp_source.prefetch_count == Some(0) {
+                problems.push(format!(
+                    "SFTP source '{}' has a prefetch_count of 0, which means unlimited",
+                    sftp_source.name
+                ));
+            }
+        }
+
+        for (name, on_conflict, max_versions) in self
+            .directory_sources
+            .iter()
+            .map(|s| (&s.name, s.on_conflict, s.max_versions))
+            .chain(
+                self.sftp_sources
+                    .iter()
+                    .map(|s| (&s.name, s.on_conflict, s.max_versions)),
+            )
+        {
+            if on_conflict == ConflictPolicy::Version && max_versions == 0 {
+                problems.push(format!(
+                    "Source '{name}' keeps versions on conflict, but has a max_versions of 0"
+                ));
+            }
+        }
+
+        let mut command_queues: HashMap<String, &str> = HashMap::new();
+
+        for sftp_source in &self.sftp_sources {
+            let queue = sftp_source.command_route().queue;
+
+            if let Some(other) = command_queues.get(&queue) {
+                problems.push(format!(
+                    "SFTP sources '{}' and '{}' both consume queue '{}'",
+                    other, sftp_source.name, queue
+                ));
+            } else {
+                command_queues.insert(queue, &sftp_source.name);
+            }
+        }
+
+        for (host, limit) in &self.sftp_connection_limits {
+            if *limit == 0 {
+                problems.push(format!("sftp_connection_limits of '{host}' is 0"));
+            }
+        }
+
+        if self.channels.sftp_command_capacity == 0 {
+            problems.push("channels.sftp_command_capacity must be greater than 0".to_string());
+        }
+
+        if self.channels.ack_capacity == 0 {
+            problems.push("channels.ack_capacity must be greater than 0".to_string());
+        }
+
+        // More unacknowledged commands than the channels and download threads
+        // can hold lets the consumer and the download threads wait for each
+        // other
+        for sftp_source in &self.sftp_sources {
+            let room = self.channels.ack_capacity
+                + self.channels.sftp_command_capacity
+                + sftp_source.thread_count;
+
+            if usize::from(sftp_source.prefetch_count()) >= room {
+                problems.push(format!(
+                    "SFTP source '{}' has a prefetch_count of {}, which must be less than channels.ack_capacity + channels.sftp_command_capacity + thread_count ({room})",
+                    sftp_source.name,
+                    sftp_source.prefetch_count()
+                ));
+            }
+        }
+
+        if self.channels.file_event_capacity == Some(0) {
+            problems.push("channels.file_event_capacity must be greater than 0".to_string());
+        }
+
+        if self
+            .storage
+            .iter()
+            .any(|area| area.layout == StorageLayout::ContentAddressed)
+            && source_names.contains(OBJECTS_DIRECTORY)
+        {
+            problems.push(format!(
+                "Source name '{OBJECTS_DIRECTORY}' is reserved for the content_addressed storage layout"
+            ));
+        }
+
+        let mut area_names: HashSet<&str> = HashSet::new();
+        let mut area_directories: HashSet<&Path> = HashSet::new();
+
+        for area in &self.storage {
+            if !area_names.insert(area.name.as_str()) {
+                problems.push(format!("Duplicate storage area name '{}'", area.name));
+            }
+
+            if !area_directories.insert(area.directory.as_path()) {
+                problems.push(format!(
+                    "Storage area '{}' has the directory of another area",
+                    area.name
+                ));
+            }
+        }
+
+        let source_areas = self
+            .directory_sources
+            .iter()
+            .map(|s| (s.name.as_str(), &s.storage_area))
+            .chain(
+                self.sftp_sources
+                    .iter()
+                    .map(|s| (s.name.as_str(), &s.storage_area)),
+            );
+
+        for (name, storage_area) in source_areas {
+            if let Some(storage_area) = storage_area {
+                if !area_names.contains(storage_area.as_str()) {
+                    problems.push(format!(
+                        "Source '{name}' refers to unknown storage area '{storage_area}'"
+                    ));
+                }
+            }
+        }
+
+        let file_permissions = self
+            .storage
+            .iter()
+            .map(|area| ("storage", &area.file_permissions))
+            .chain(
+                self.directory_sources
+                    .iter()
+                    .map(|s| (s.name.as_str(), &s.file_permissions)),
+            )
+            .chain(
+                self.sftp_sources
+                    .iter()
+                    .map(|s| (s.name.as_str(), &s.file_permissions)),
+            );
+
+        for (name, value) in file_permissions {
+            if let Some(Err(e)) = value.as_deref().map(parse_file_permissions) {
+                problems.push(format!("{e} for '{name}'"));
+            }
+        }
+
+        for (index, area) in self.storage.iter().enumerate() {
+            let subject = match self.storage.len() {
+                1 => "storage".to_string(),
+                _ => format!("storage[{index}]"),
+            };
+
+            if area.max_bytes == Some(0) {
+                problems.push(format!("{subject}.max_bytes must be greater than 0"));
+            }
+
+            if !(area.warn_ratio > 0.0 && area.warn_ratio <= 1.0) {
+                problems.push(format!("{subject}.warn_ratio must be between 0 and 1"));
+            }
+
+            if area.usage_interval == 0 {
+                problems.push(format!("{subject}.usage_interval must be greater than 0"));
+            }
+
+            if let Some(retention) = &area.retention {
+                if retention.interval == 0 {
+                    problems.push(format!(
+                        "{subject}.retention.interval must be greater than 0"
+                    ));
+                }
+            }
+        }
+
+        if let Some(reconcile) = &self.reconcile {
+            if reconcile.files_per_second.is_nan() || reconcile.files_per_second <= 0.0 {
+                problems.push("reconcile.files_per_second must be greater than 0".to_string());
+            }
+        }
+
+        if self.command_queue.queue_poll_interval == 0 {
+            problems.push("command_queue.queue_poll_interval must be greater than 0".to_string());
+        }
+
+        if let Some(amqp_tls) = &self.command_queue.amqp_tls {
+            amqp_tls.validate("command_queue.amqp_tls", &mut problems);
+        }
+
+        for (index, directory_target) in self.directory_targets.iter().enumerate() {
+            if let Some(TargetNotify::Inline(Notify::RabbitMQ(RabbitMQNotify {
+                amqp_tls: Some(amqp_tls),
+                ..
+            }))) = &directory_target.notify
+            {
+                amqp_tls.validate(
+                    &format!("directory_targets[{index}].notify.rabbitmq.amqp_tls"),
+                    &mut problems,
+                );
+            }
+        }
+
+        let mut publisher_names: HashSet<&str> = HashSet::new();
+
+        for (index, event_publisher) in self.event_publishers.iter().enumerate() {
+            if !publisher_names.insert(event_publisher.name.as_str()) {
+                problems.push(format!(
+                    "Duplicate event publisher name '{}'",
+                    event_publisher.name
+                ));
+            }
+
+            if event_publisher.capacity == 0 {
+                problems.push(format!(
+                    "Event publisher '{}' has a capacity of 0",
+                    event_publisher.name
+                ));
+            }
+
+            if let Some(amqp_tls) = &event_publisher.amqp_tls {
+                amqp_tls.validate(
+                    &format!("event_publishers[{index}].amqp_tls"),
+                    &mut problems,
+                );
+            }
+        }
+
+        let mut report_names: HashSet<&str> = HashSet::new();
+
+        for report in &self.reports {
+            if !report_names.insert(report.name.as_str()) {
+                problems.push(format!("Duplicate report name '{}'", report.name));
+            }
+
+            match (report.interval_seconds, &report.schedule) {
+                (Some(0), None) => {
+                    problems.push(format!("Report '{}' has an interval of 0", report.name))
+                }
+                (Some(_), None) => {}
+                (None, Some(schedule)) => {
+                    if let Err(e) = cron::Schedule::from_str(schedule) {
+                        problems.push(format!(
+                            "Report '{}' has an invalid schedule '{}': {}",
+                            report.name, schedule, e
+                        ));
+                    }
+                }
+                _ => problems.push(format!(
+                    "Report '{}' needs exactly one of interval_seconds and schedule",
+                    report.name
+                )),
+            }
+
+            for source in &report.sources {
+                if !source_names.contains(source.as_str()) {
+                    problems.push(format!(
+                        "Report '{}' refers to unknown source '{}'",
+                        report.name, source
+                    ));
+                }
+            }
+
+            if let ReportTransport::Smtp(smtp) = &report.transport {
+                if smtp.to.is_empty() {
+                    problems.push(format!("Report '{}' has no recipients", report.name));
+                }
+            }
+        }
+
+        if self.logging.file.is_none()
+            && self.logging.targets.contains(&LogTarget::File)
+            && self.logging.targets != default_log_targets()
+        {
+            problems.push("Log target 'file' requires logging.file to be set".to_string());
+        }
+
+        if self.logging.targets.is_empty() {
+            problems.push("No log targets configured".to_string());
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+storage:
+  directory: /cortex/storage
+sqlite:
+  path: /cortex/cortex.db
+command_queue:
+  address: amqp://127.0.0.1:5672/%2f
+http_server:
+  address: 0.0.0.0:56008
+connections: []
+sftp_sources:
+  - name: red
+    address: 127.0.0.1:22
+    username: cortex
+    password: red-password
+  - name: blue
+    address: 127.0.0.1:22
+    username: cortex
+    password: blue-password
+"#;
+
+    fn load(vars: &[(&str, &str)]) -> Result<Settings, String> {
+        load_settings_from(
+            config::File::from_str(CONFIG, config::FileFormat::Yaml),
+            "test",
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+        )
+    }
+
+    #[test]
+    fn env_key_to_path() {
+        let overrides = env_overrides(vec![
+            ("CORTEX__SQLITE__PATH".to_string(), "a".to_string()),
+            (
+                "CORTEX__SFTP_SOURCES__1__PASSWORD".to_string(),
+                "b".to_string(),
+            ),
+            ("OTHER__SQLITE__PATH".to_string(), "c".to_string()),
+            ("CORTEX__0__PATH".to_string(), "d".to_string()),
+        ]);
+
+        assert_eq!(
+            overrides,
+            vec![
+                ("sftp_sources[1].password".to_string(), "b".to_string()),
+                ("sqlite.path".to_string(), "a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let settings = load(&[
+            ("CORTEX__SQLITE__PATH", "/data/cortex.db"),
+            ("CORTEX__COMMAND_QUEUE__ADDRESS", "amqp://rabbitmq:5672/%2f"),
+            ("CORTEX__SCAN_INTERVAL", "1000"),
+        ])
+        .unwrap();
+
+        assert_eq!(settings.sqlite.path, PathBuf::from("/data/cortex.db"));
+        assert_eq!(
+            settings.command_queue.address.expose(),
+            "amqp://rabbitmq:5672/%2f"
+        );
+        assert_eq!(settings.scan_interval, 1000);
+    }
+
+    #[test]
+    fn env_overrides_list_element() {
+        let settings = load(&[
+            ("CORTEX__SFTP_SOURCES__1__PASSWORD", "secret"),
+            ("CORTEX__SFTP_SOURCES__0__THREAD_COUNT", "3"),
+        ])
+        .unwrap();
+
+        assert_eq!(settings.sftp_sources.len(), 2);
+        assert_eq!(settings.sftp_sources[0].name, "red");
+        assert_eq!(
+            settings.sftp_sources[0]
+                .connection
+                .password
+                .as_ref()
+                .map(Secret::expose),
+            Some("red-password")
+        );
+        assert_eq!(settings.sftp_sources[0].thread_count, 3);
+        assert_eq!(settings.sftp_sources[1].name, "blue");
+        assert_eq!(
+            settings.sftp_sources[1]
+                .connection
+                .password
+                .as_ref()
+                .map(Secret::expose),
+            Some("secret")
+        );
+    }
+
+    #[test]
+    fn sftp_connection_settings_round_trip() {
+        let settings = load(&[
+            ("CORTEX__SFTP_SOURCES__0__CONNECT_TIMEOUT_SECONDS", "5"),
+            ("CORTEX__SFTP_SOURCES__0__COMPRESS", "true"),
+        ])
+        .unwrap();
+
+        let json =
+            cortex_core::secret::serialize_secrets(|| serde_json::to_string(&settings).unwrap());
+
+        let reloaded = load_settings_from(
+            config::File::from_str(&json, config::FileFormat::Json),
+            "test",
+            Vec::new(),
+        )
+        .unwrap();
+
+        for settings in [&settings, &reloaded] {
+            let connection = &settings.sftp_sources[0].connection;
+
+            assert_eq!(connection.address, "127.0.0.1:22");
+            assert_eq!(connection.username, "cortex");
+            assert_eq!(
+                connection.password.as_ref().map(Secret::expose),
+                Some("red-password")
+            );
+            assert_eq!(connection.connect_timeout_seconds, 5);
+            assert!(connection.compress);
+            assert_eq!(settings.sftp_sources[0].thread_count, 1);
+        }
+    }
+
+    #[test]
+    fn storage_areas_are_selected_by_sources() {
+        let config = CONFIG.replace(
+            "storage:\n  directory: /cortex/storage\n",
+            "storage:\n  - name: fast\n    directory: /nvme/storage\n  - name: slow\n    directory: /hdd/storage\n",
+        );
+
+        let mut settings = load_settings_from(
+            config::File::from_str(&config, config::FileFormat::Yaml),
+            "test",
+            vec![(
+                "CORTEX__SFTP_SOURCES__1__STORAGE_AREA".to_string(),
+                "slow".to_string(),
+            )],
+        )
+        .unwrap();
+
+        assert_eq!(settings.storage_area("red").name, "fast");
+        assert_eq!(
+            settings.storage_area("blue").directory,
+            PathBuf::from("/hdd/storage")
+        );
+        assert!(settings.validate().is_empty());
+
+        settings.sftp_sources[0].storage_area = Some("tape".to_string());
+
+        assert_eq!(
+            settings.validate(),
+            vec!["Source 'red' refers to unknown storage area 'tape'".to_string()]
+        );
+    }
+
+    #[test]
+    fn secret_from_file() {
+        let dir = std::env::temp_dir().join(format!("cortex-settings-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let password_file = dir.join("password");
+        std::fs::write(&password_file, "from-file\n").unwrap();
+
+        let mut settings = load(&[]).unwrap();
+        settings.sftp_sources[0].connection.password = None;
+        settings.sftp_sources[0].connection.password_file = Some(password_file.clone());
+        settings.resolve_secret_files().unwrap();
+
+        assert_eq!(
+            settings.sftp_sources[0]
+                .connection
+                .password
+                .as_ref()
+                .map(Secret::expose),
+            Some("from-file")
+        );
+
+        settings.sftp_sources[1].connection.password_file = Some(password_file);
+
+        assert_eq!(
+            settings.resolve_secret_files().unwrap_err(),
+            "Both sftp_sources[1].password and sftp_sources[1].password_file are set"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_config_files() {
+        let dir =
+            std::env::temp_dir().join(format!("cortex-settings-merge-{}", std::process::id()));
+        let conf_dir = dir.join("conf.d");
+        std::fs::create_dir_all(&conf_dir).unwrap();
+        std::fs::write(dir.join("base.yaml"), CONFIG).unwrap();
+        std::fs::write(
+            conf_dir.join("20-blue.yaml"),
+            r#"
+sftp_sources:
+  - name: blue
+    address: 10.0.0.2:22
+    username: cortex
+    password: blue-password
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            conf_dir.join("10-green.yaml"),
+            r#"
+scan_interval: 5000
+sftp_sources:
+  - name: green
+    address: 10.0.0.3:22
+    username: cortex
+    password: green-password
+  - name: blue
+    address: 10.0.0.9:22
+    username: cortex
+    password: blue-password
+directory_targets:
+  - name: green
+    directory: /cortex/green
+    overwrite: false
+    permissions: 420
+connections:
+  - source: green
+    target: green
+"#,
+        )
+        .unwrap();
+        std::fs::write(conf_dir.join("README"), "not a configuration file").unwrap();
+
+        let paths = vec![
+            dir.join("base.yaml").to_string_lossy().to_string(),
+            conf_dir.to_string_lossy().to_string(),
+        ];
+
+        let settings = load_settings_files(&paths).unwrap();
+
+        assert_eq!(settings.scan_interval, 5000);
+        assert_eq!(
+            settings
+                .sftp_sources
+                .iter()
+                .map(|source| (source.name.as_str(), source.connection.address.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("red", "127.0.0.1:22"),
+                ("blue", "10.0.0.2:22"),
+                ("green", "10.0.0.3:22")
+            ]
+        );
+        assert_eq!(settings.connections.len(), 1);
+        assert!(settings.validate().is_empty());
+
+        // Differing entries with the same name in one file conflict
+        std::fs::write(
+            conf_dir.join("30-red.yaml"),
+            r#"
+sftp_sources:
+  - name: red
+    address: 10.0.0.4:22
+    username: cortex
+  - name: red
+    address: 10.0.0.5:22
+    username: cortex
+"#,
+        )
+        .unwrap();
+
+        let settings = load_settings_files(&paths).unwrap();
+
+        assert!(settings
+            .validate()
+            .contains(&"Duplicate source name 'red'".to_string()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn amqp_tls_from_env() {
+        let settings = load(&[
+            (
+                "CORTEX__COMMAND_QUEUE__AMQP_TLS__CA_CERT",
+                "/etc/cortex/ca.pem",
+            ),
+            (
+                "CORTEX__COMMAND_QUEUE__AMQP_TLS__CLIENT_CERT",
+                "/etc/cortex/client.pem",
+            ),
+        ])
+        .unwrap();
+
+        let amqp_tls = settings.command_queue.amqp_tls.as_ref().unwrap();
+
+        assert_eq!(amqp_tls.ca_cert, PathBuf::from("/etc/cortex/ca.pem"));
+        assert!(amqp_tls.verify_hostname);
+        assert_eq!(
+            settings.validate(),
+            vec![
+                "command_queue.amqp_tls requires both client_cert and client_key for client authentication"
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_file_permissions() {
+        let mut settings = load(&[("CORTEX__STORAGE__FILE_PERMISSIONS", "0640")]).unwrap();
+
+        assert_eq!(parse_file_permissions("0640"), Ok(0o640));
+        assert!(settings.validate().is_empty());
+
+        settings.sftp_sources[1].file_permissions = Some("0980".to_string());
+
+        assert_eq!(
+            settings.validate(),
+            vec!["Invalid file permissions '0980', expected an octal value like '0640' for 'blue'"]
+        );
+    }
+
+    #[test]
+    fn prefetch_count_must_fit_in_channels() {
+        let mut settings = load(&[]).unwrap();
+
+        settings.channels.ack_capacity = 2;
+        settings.channels.sftp_command_capacity = 2;
+        settings.sftp_sources[1].prefetch_count = Some(8);
+
+        assert_eq!(
+            settings.validate(),
+            vec![format!(
+                "SFTP source 'blue' has a prefetch_count of 8, which must be less than channels.ack_capacity + channels.sftp_command_capacity + thread_count ({})",
+                4 + settings.sftp_sources[1].thread_count
+            )]
+        );
+    }
+
+    #[test]
+    fn command_route() {
+        let mut settings = load(&[]).unwrap();
+
+        assert_eq!(
+            settings.sftp_sources[0].command_route(),
+            CommandRoute {
+                queue: "source.red".to_string(),
+                exchange: "amq.direct".to_string(),
+                routing_key: "source.red".to_string(),
+            }
+        );
+
+        settings.sftp_sources[1].queue = Some("source.red".to_string());
+        settings.sftp_sources[1].routing_key = Some("blue".to_string());
+
+        assert_eq!(settings.sftp_sources[1].command_route().routing_key, "blue");
+        assert_eq!(
+            settings.validate(),
+            vec!["SFTP sources 'red' and 'blue' both consume queue 'source.red'"]
+        );
+    }
+
+    #[test]
+    fn default_command_route_matches_client() {
+        let settings = load(&[]).unwrap();
+
+        let route = settings.sftp_sources[0].command_route();
+        let client_route = cortex_core::client::CommandRoute::sftp_source("red");
+
+        assert_eq!(route.exchange, client_route.exchange);
+        assert_eq!(route.routing_key, client_route.routing_key);
+    }
+
+    #[test]
+    fn filters_keep_their_configuration_format() {
+        let config = format!(
+            "{}{}",
+            CONFIG.replace("connections: []\n", ""),
+            r#"
+connections:
+  - source: red
+    target: red
+    filter:
+      Regex:
+        pattern: "^.*\\.csv$"
+  - source: blue
+    target: red
+    filter:
+      Size:
+        max_bytes: 100
+  - source: blue
+    target: red
+    filter: All
+"#
+        );
+
+        let settings = load_settings_from(
+            config::File::from_str(&config, config::FileFormat::Yaml),
+            "test",
+            Vec::new(),
+        )
+        .unwrap();
+
+        let filters: Vec<&Filter> = settings
+            .connections
+            .iter()
+            .filter_map(|connection| connection.filter.as_ref())
+            .collect();
+
+        assert!(matches!(
+            filters[..],
+            [Filter::Regex(_), Filter::Size(_), Filter::All]
+        ));
+        assert!(filters[0].matches(Path::new("/data/red.csv")));
+        assert!(!filters[0].matches(Path::new("/data/red.xml")));
+    }
+
+    #[test]
+    fn kafka_notify() {
+        let target: DirectoryTarget = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+name: red
+directory: /data/red
+overwrite: false
+permissions: 420
+notify:
+  kafka:
+    message_template: '{"path": "{{ file_path }}"}'
+    brokers: kafka-1:9093,kafka-2:9093
+    topic: file-arrivals
+    security:
+      protocol: sasl_ssl
+      sasl_mechanism: SCRAM-SHA-512
+      sasl_username: cortex
+      sasl_password: secret
+      client_cert: /etc/cortex/kafka.pem
+"#,
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .unwrap();
+
+        let Some(TargetNotify::Inline(Notify::Kafka(kafka))) = &target.notify else {
+            panic!("Expected a Kafka notify");
+        };
+
+        assert_eq!(kafka.key_template, "{{ file_path }}");
+        assert_eq!(kafka.delivery_timeout, 30_000);
+
+        let mut problems = vec![
+            "Directory target 'red' requires both client_cert and client_key for Kafka client authentication".to_string(),
+        ];
+
+        if !cfg!(feature = "kafka") {
+            problems.insert(
+                0,
+                "Directory target 'red' notifies on Kafka, which requires the kafka feature"
+                    .to_string(),
+            );
+        }
+
+        assert_eq!(target.validate(), problems);
+    }
+
+    #[test]
+    fn targets_refer_to_notifiers() {
+        let settings: Settings = config::Config::builder()
+            .add_source(config::File::from_str(
+                &serde_json::to_string(&Settings::default()).unwrap(),
+                config::FileFormat::Json,
+            ))
+            .add_source(config::File::from_str(
+                r#"
+notifiers:
+  shared:
+    rabbitmq:
+      message_template: '{"path": "{{ file_path }}"}'
+      address: amqp://127.0.0.1:5672/%2f
+      exchange: ""
+      routing_key: default
+directory_targets:
+  - name: red
+    directory: /data/red
+    overwrite: false
+    permissions: 420
+    notify: shared
+  - name: blue
+    directory: /data/blue
+    overwrite: false
+    permissions: 420
+    notify:
+      notifier: shared
+      routing_key: blue
+  - name: green
+    directory: /data/green
+    overwrite: false
+    permissions: 420
+    notify: missing
+  - name: yellow
+    directory: /data/yellow
+    overwrite: false
+    permissions: 420
+    notify: log
+"#,
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .unwrap();
+
+        let routing_keys: Vec<Option<String>> = settings
+            .directory_targets
+            .iter()
+            .map(
+                |target| match target.notify.as_ref()?.resolve(&settings.notifiers) {
+                    Some(Notify::RabbitMQ(rabbitmq)) => Some(rabbitmq.routing_key),
+                    _ => None,
+                },
+            )
+            .collect();
+
+        assert_eq!(
+            routing_keys,
+            [
+                Some("default".to_string()),
+                Some("blue".to_string()),
+                None,
+                None
+            ]
+        );
+        assert!(matches!(
+            settings.directory_targets[3].notify,
+            Some(TargetNotify::Inline(Notify::Log))
+        ));
+        assert_eq!(
+            settings.directory_targets[2].validate_notifier(&settings.notifiers),
+            ["Directory target 'green' refers to undefined notifier 'missing'"]
+        );
+    }
+
+    #[test]
+    fn secrets_are_recognized() {
+        assert!(is_secret("sftp_sources[0].password"));
+        assert!(is_secret("sftp_sources[0].key_passphrase"));
+        assert!(is_secret("command_queue.address"));
+        assert!(is_secret(
+            "directory_targets[0].notify.kafka.security.sasl_password"
+        ));
+        assert!(!is_secret("sqlite.path"));
     }
 }
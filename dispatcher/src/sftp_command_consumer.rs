@@ -1,22 +1,50 @@
+use std::collections::HashMap;
+use std::time::Duration;
 use std::{fmt, fmt::Display};
 
+use async_trait::async_trait;
 use deadpool_lapin::lapin::message::Delivery;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 use futures::StreamExt;
 
 use deadpool_lapin::lapin;
-use deadpool_lapin::lapin::options::BasicConsumeOptions;
-use deadpool_lapin::lapin::types::FieldTable;
-use deadpool_lapin::lapin::ConnectionProperties;
-use deadpool_lapin::lapin::ErrorKind::{IOError, ProtocolError};
+use deadpool_lapin::lapin::options::{
+    BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions,
+    BasicPublishOptions, BasicQosOptions, BasicRejectOptions, ConfirmSelectOptions,
+    ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+};
+use deadpool_lapin::lapin::types::{AMQPValue, FieldTable};
+use deadpool_lapin::lapin::{BasicProperties, Channel, ExchangeKind};
 
-use crossbeam_channel::{Sender, TrySendError};
-use stream_reconnect::ReconnectStream;
+use crossbeam_channel::{SendError, Sender};
+use tokio::sync::watch;
 
+use crate::amqp;
+use crate::base_types::MessageResponse;
+use crate::drain::Drain;
 use crate::metrics;
+use crate::settings::{CommandQueue, CommandRoute, DeadLetter};
+use crate::source_activity::SourceActivities;
+use crate::systemd::Startup;
 
-use cortex_core::SftpDownload;
+use cortex_core::{parse_command, SftpDownload};
+
+/// Delay before the first reconnect attempt
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+/// Maximum delay between reconnect attempts
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// Maximum time to wait for the depth of the command queue
+const QUEUE_POLL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of bytes of a message body that is logged
+const MAX_LOGGED_BODY: usize = 1024;
+
+/// Header of dead-lettered commands with the reason of the failure
+pub const FAILURE_REASON_HEADER: &str = "cortex-failure-reason";
+
+const CONSUMER_TAG: &str = "cortex-dispatcher";
 
 #[derive(Clone, Debug)]
 pub enum ConsumeError {
@@ -37,148 +65,1242 @@ impl Display for ConsumeError {
     }
 }
 
-#[derive(Clone)]
+/// Publishing of messages that are only settled once the broker confirmed
+/// them
+#[async_trait]
+pub trait ConfirmedPublish {
+    /// Publish a message, failing when the broker does not confirm it
+    async fn publish_confirmed(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        data: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), String>;
+}
+
+/// Publishing on a channel with publisher confirms enabled
+#[async_trait]
+impl ConfirmedPublish for Channel {
+    async fn publish_confirmed(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        data: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), String> {
+        let confirmation = self
+            .basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions::default(),
+                data,
+                properties,
+            )
+            .await
+            .map_err(|e| format!("Error publishing message: {e}"))?
+            .await
+            .map_err(|e| format!("Error publishing message: {e}"))?;
+
+        // Without publisher confirms, nothing is known about the message
+        if !confirmation.is_ack() {
+            return Err("Message was not confirmed by the broker".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Enable publisher confirms, so that published messages are confirmed
+/// before the messages they replace are acknowledged
+pub async fn enable_confirms(channel: &Channel) -> Result<(), String> {
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await
+        .map_err(|e| format!("Error enabling publisher confirms: {e}"))
+}
+
+/// How a rejected command was settled with the broker
+#[derive(Debug, PartialEq)]
+enum Settlement {
+    /// Published to the dead-letter exchange with the failure reason, and
+    /// acknowledged
+    DeadLettered,
+    /// Rejected without requeueing, so that the broker routes it to the
+    /// dead-letter exchange of the queue if there is one
+    Rejected,
+}
+
+/// Properties of a dead-lettered command, with the reason of the failure in a
+/// header
+pub fn dead_letter_properties(delivery: &Delivery, reason: &str) -> BasicProperties {
+    let mut headers = delivery.properties.headers().clone().unwrap_or_default();
+
+    headers.insert(
+        FAILURE_REASON_HEADER.into(),
+        AMQPValue::LongString(reason.into()),
+    );
+
+    delivery.properties.clone().with_headers(headers)
+}
+
+/// Settle a command that will not succeed
+///
+/// With a dead-letter exchange, a copy with the failure reason replaces the
+/// original. When it cannot be published, the original is rejected instead.
+async fn settle_rejected<P: ConfirmedPublish + Sync>(
+    delivery: &Delivery,
+    reason: &str,
+    publisher: Option<&P>,
+    dead_letter: Option<&DeadLetter>,
+    routing_key: &str,
+) -> Result<Settlement, lapin::Error> {
+    if let Some(dead_letter) = dead_letter {
+        // Route by the command queue, so that the command can be retried
+        let published = match publisher {
+            Some(publisher) => {
+                publisher
+                    .publish_confirmed(
+                        &dead_letter.exchange,
+                        routing_key,
+                        &delivery.data,
+                        dead_letter_properties(delivery, reason),
+                    )
+                    .await
+            }
+            None => Err("No dead-letter exchange available".to_string()),
+        };
+
+        match published {
+            Ok(()) => {
+                delivery.acker.ack(BasicAckOptions::default()).await?;
+
+                return Ok(Settlement::DeadLettered);
+            }
+            Err(e) => error!("Could not dead-letter command: {e}"),
+        }
+    }
+
+    // Failed downloads are not retried by requeueing, because they would most
+    // likely fail again
+    delivery
+        .acker
+        .nack(BasicNackOptions {
+            requeue: false,
+            ..Default::default()
+        })
+        .await?;
+
+    Ok(Settlement::Rejected)
+}
+
 struct AMQPQueStreamConfig {
-    pub address: String,
-    pub queue_name: String,
-}
-
-impl
-    stream_reconnect::UnderlyingStream<
-        AMQPQueStreamConfig,
-        Result<Delivery, lapin::Error>,
-        lapin::Error,
-    > for lapin::Consumer
-{
-    fn establish(
-        config: AMQPQueStreamConfig,
-    ) -> std::pin::Pin<Box<dyn futures::Future<Output = Result<Self, lapin::Error>> + Send>> {
-        Box::pin(async move {
-            let amqp_client =
-                lapin::Connection::connect(&config.address, ConnectionProperties::default())
-                    .await?;
-
-            let amqp_channel = amqp_client.create_channel().await?;
-
-            let id = amqp_channel.id();
-            info!("Created SFTP command AMQP channel with id {id}");
-
-            let consumer_tag = "cortex-dispatcher";
-
-            let options = BasicConsumeOptions {
-                no_ack: true,
+    pub command_queue: CommandQueue,
+    pub route: CommandRoute,
+    pub prefetch_count: u16,
+}
+
+/// Declare the dead-letter exchange and the queue in which failed commands
+/// are kept
+pub async fn declare_dead_letter(
+    channel: &Channel,
+    dead_letter: &DeadLetter,
+) -> Result<(), String> {
+    channel
+        .exchange_declare(
+            &dead_letter.exchange,
+            ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
                 ..Default::default()
-            };
-
-            // Setup command consuming stream
-            let consumer = amqp_channel
-                .basic_consume(
-                    &config.queue_name,
-                    consumer_tag,
-                    options,
-                    FieldTable::default(),
-                )
-                .await?;
+            },
+            FieldTable::default(),
+        )
+        .await
+        .map_err(|e| {
+            format!(
+                "Error declaring dead-letter exchange '{}': {}",
+                &dead_letter.exchange, e
+            )
+        })?;
 
-            Ok(consumer)
+    channel
+        .queue_declare(
+            &dead_letter.queue,
+            QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+        .map_err(|e| {
+            format!(
+                "Error declaring dead-letter queue '{}': {}",
+                &dead_letter.queue, e
+            )
+        })?;
+
+    channel
+        .queue_bind(
+            &dead_letter.queue,
+            &dead_letter.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .map_err(|e| {
+            format!(
+                "Error binding dead-letter queue '{}': {}",
+                &dead_letter.queue, e
+            )
         })
+}
+
+/// Connect to the broker, declare the command queue and start consuming
+async fn connect(config: &AMQPQueStreamConfig) -> Result<(Channel, lapin::Consumer), String> {
+    let amqp_client = amqp::connect(
+        config.command_queue.address.expose(),
+        config.command_queue.amqp_tls.as_ref(),
+    )
+    .await?;
+
+    let amqp_channel = amqp_client
+        .create_channel()
+        .await
+        .map_err(|e| format!("Error creating AMQP channel: {e}"))?;
+
+    let id = amqp_channel.id();
+    info!("Created SFTP command AMQP channel with id {id}");
+
+    let mut arguments = FieldTable::default();
+
+    if let Some(dead_letter) = &config.command_queue.dead_letter {
+        declare_dead_letter(&amqp_channel, dead_letter).await?;
+
+        // Confirm publishing of failed commands before they are acknowledged
+        enable_confirms(&amqp_channel).await?;
+
+        // Commands that are rejected end up in the dead-letter queue as well
+        arguments.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString(dead_letter.exchange.as_str().into()),
+        );
     }
 
-    // The following errors are considered disconnect errors.
-    fn is_write_disconnect_error(&self, err: &lapin::Error) -> bool {
-        matches!(err.kind(), IOError(_) | ProtocolError(_))
+    amqp_channel
+        .queue_declare(
+            &config.route.queue,
+            QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            arguments,
+        )
+        .await
+        .map_err(|e| format!("Error declaring queue '{}': {}", &config.route.queue, e))?;
+
+    amqp_channel
+        .queue_bind(
+            &config.route.queue,
+            &config.route.exchange,
+            &config.route.routing_key,
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .map_err(|e| format!("Error binding queue '{}': {}", &config.route.queue, e))?;
+
+    // Limit the number of unacknowledged commands the broker sends, so that
+    // they remain available for other consumers
+    amqp_channel
+        .basic_qos(config.prefetch_count, BasicQosOptions::default())
+        .await
+        .map_err(|e| format!("Error setting prefetch count: {e}"))?;
+
+    let consumer = start_consuming(&amqp_channel, &config.route.queue).await?;
+
+    Ok((amqp_channel, consumer))
+}
+
+/// Setup command consuming stream
+async fn start_consuming(channel: &Channel, queue: &str) -> Result<lapin::Consumer, String> {
+    let options = BasicConsumeOptions {
+        no_ack: false,
+        ..Default::default()
+    };
+
+    channel
+        .basic_consume(queue, CONSUMER_TAG, options, FieldTable::default())
+        .await
+        .map_err(|e| format!("Error consuming queue '{queue}': {e}"))
+}
+
+/// Read the number of messages and consumers of the command queue
+async fn poll_queue(channel: &Channel, queue: &str) -> Result<(u32, u32), String> {
+    let declare = channel.queue_declare(
+        queue,
+        QueueDeclareOptions {
+            passive: true,
+            ..Default::default()
+        },
+        FieldTable::default(),
+    );
+
+    let queue = tokio::time::timeout(QUEUE_POLL_TIMEOUT, declare)
+        .await
+        .map_err(|_| format!("Timeout reading queue '{queue}'"))?
+        .map_err(|e| format!("Error reading queue '{queue}': {e}"))?;
+
+    Ok((queue.message_count(), queue.consumer_count()))
+}
+
+/// Exports the depth of the command queue and of the channel to the download
+/// threads
+///
+/// The poller runs apart from the consumer, with a connection of its own, so
+/// that a slow or failing read does not hold up consuming. A failed read
+/// closes the channel, so the channel, or the connection when it is lost as
+/// well, is opened again for the next read.
+struct QueueDepthPoller {
+    command_queue: CommandQueue,
+    sftp_source_name: String,
+    queue: String,
+    command_sender: Sender<(u64, SftpDownload)>,
+    connection: Option<lapin::Connection>,
+    channel: Option<Channel>,
+}
+
+impl QueueDepthPoller {
+    /// Poll every `queue_poll_interval` until a stop is signalled
+    async fn run(mut self, mut stop_receiver: watch::Receiver<()>) {
+        let mut poll_ticker = tokio::time::interval(Duration::from_millis(
+            self.command_queue.queue_poll_interval,
+        ));
+        poll_ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select!(
+                _ = async {
+                    poll_ticker.tick().await;
+                    self.poll().await;
+                } => (),
+                _ = stop_receiver.changed() => return,
+            )
+        }
     }
 
-    // If an `Err` is read, then there might be an disconnection.
-    fn is_read_disconnect_error(&self, item: &Result<Delivery, lapin::Error>) -> bool {
-        if let Err(e) = item {
-            self.is_write_disconnect_error(e)
-        } else {
-            false
+    async fn poll(&mut self) {
+        metrics::COMMAND_CHANNEL_GAUGE
+            .with_label_values(&[&self.sftp_source_name])
+            .set(self.command_sender.len() as i64);
+
+        let result = match self.channel().await {
+            Ok(channel) => poll_queue(&channel, &self.queue).await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok((messages, consumers)) => {
+                metrics::COMMAND_QUEUE_MESSAGES_GAUGE
+                    .with_label_values(&[&self.sftp_source_name])
+                    .set(i64::from(messages));
+                metrics::COMMAND_QUEUE_CONSUMERS_GAUGE
+                    .with_label_values(&[&self.sftp_source_name])
+                    .set(i64::from(consumers));
+            }
+            Err(e) => {
+                debug!(
+                    "Could not read depth of command queue of source '{}': {}",
+                    &self.sftp_source_name, e
+                );
+
+                self.channel = None;
+
+                if !self
+                    .connection
+                    .as_ref()
+                    .is_some_and(|connection| connection.status().connected())
+                {
+                    self.connection = None;
+                }
+            }
         }
     }
 
-    // Return "Exhausted" if all retry attempts are failed.
-    fn exhaust_err() -> lapin::Error {
-        lapin::Error::from(IOError(std::sync::Arc::new(std::io::Error::other(
-            "Exhausted",
-        ))))
+    /// Channel for reading the queue, opened when there is none
+    async fn channel(&mut self) -> Result<Channel, String> {
+        if let Some(channel) = &self.channel {
+            return Ok(channel.clone());
+        }
+
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => {
+                amqp::connect(
+                    self.command_queue.address.expose(),
+                    self.command_queue.amqp_tls.as_ref(),
+                )
+                .await?
+            }
+        };
+
+        let channel = connection
+            .create_channel()
+            .await
+            .map_err(|e| format!("Error creating AMQP channel: {e}"))?;
+
+        self.connection = Some(connection);
+        self.channel = Some(channel.clone());
+
+        Ok(channel)
     }
 }
 
-type ReconnectMessageStream = ReconnectStream<
-    lapin::Consumer,
-    AMQPQueStreamConfig,
-    Result<Delivery, lapin::Error>,
-    lapin::Error,
->;
+/// Exponential backoff with jitter between reconnect attempts
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff {
+            delay: RECONNECT_DELAY_MIN,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = RECONNECT_DELAY_MIN;
+    }
+
+    /// Return the delay for the next attempt, randomized between half and the
+    /// full delay so that multiple consumers do not reconnect in lockstep
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+
+        self.delay = (self.delay * 2).min(RECONNECT_DELAY_MAX);
+
+        let half = delay / 2;
+
+        half + Duration::from_millis(rand::random_range(0..=half.as_millis() as u64))
+    }
+}
 
-#[derive(Clone)]
 struct MessageProcessor {
     pub command_sender: Sender<(u64, SftpDownload)>,
     pub sftp_source_name: String,
+    /// Routing key of the command queue, for retrying dead-lettered commands
+    pub routing_key: String,
+    pub dead_letter: Option<DeadLetter>,
+    /// Channel of the current connection, for dead-lettering failed commands
+    channel: Option<Channel>,
+    /// Messages that are being handled, by command id
+    deliveries: HashMap<u64, Delivery>,
+    /// Id of the next command, unique over reconnects unlike delivery tags
+    next_id: u64,
 }
 
 impl MessageProcessor {
+    /// Hand a message to the download threads
+    ///
+    /// Acknowledgements are still handled while waiting for room in the
+    /// command channel, because the download threads stop taking commands
+    /// while their acknowledgements are not received.
     pub async fn process_message(
-        &self,
+        &mut self,
         message: Result<Delivery, lapin::Error>,
+        ack_receiver: &async_channel::Receiver<MessageResponse>,
+        ack_open: &mut bool,
     ) -> Result<(), String> {
         let delivery = message.map_err(|e| format!("Could not read AMQP message: {e}"))?;
 
-        let action_command_sender = self.command_sender.clone();
-
         metrics::MESSAGES_RECEIVED_COUNTER
             .with_label_values(&[&self.sftp_source_name])
             .inc();
 
-        let sftp_download: SftpDownload = serde_json::from_slice(delivery.data.as_slice())
-            .map_err(|e| format!("Error deserializing message: {e}"))?;
+        let sftp_download: SftpDownload = match parse_command(&delivery.data) {
+            Ok(sftp_download) => sftp_download,
+            Err(e) => {
+                metrics::COMMAND_PARSE_ERRORS_COUNTER
+                    .with_label_values(&[&self.sftp_source_name])
+                    .inc();
 
-        action_command_sender
-            .try_send((delivery.delivery_tag, sftp_download))
-            .map_err(|e| match e {
-                TrySendError::Disconnected(_) => "Channel disconnected".to_string(),
-                TrySendError::Full(_) => {
-                    "Could not send command on channel: channel full".to_string()
+                // The message will never be valid, so do not requeue it
+                if let Err(e) = delivery
+                    .acker
+                    .reject(BasicRejectOptions { requeue: false })
+                    .await
+                {
+                    error!("Could not reject message: {e}");
                 }
-            })?;
+
+                return Err(format!("{}, message: {}", e, truncate_body(&delivery.data)));
+            }
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.deliveries.insert(id, delivery);
+        self.update_unacked();
+
+        let forward = forward_command(self.command_sender.clone(), (id, sftp_download));
+        tokio::pin!(forward);
+
+        let forwarded = loop {
+            tokio::select!(
+                result = &mut forward => break result,
+                response = ack_receiver.recv(), if *ack_open => {
+                    match response {
+                        Ok(response) => self.acknowledge(response).await,
+                        Err(_) => {
+                            // All download threads have stopped
+                            *ack_open = false;
+                        }
+                    }
+                }
+            )
+        };
+
+        if let Err(e) = forwarded {
+            // Give the command back to the broker for another consumer
+            if let Some(delivery) = self.deliveries.remove(&id) {
+                if let Err(e) = delivery
+                    .acker
+                    .nack(BasicNackOptions {
+                        requeue: true,
+                        ..Default::default()
+                    })
+                    .await
+                {
+                    error!("Could not nack message: {e}");
+                }
+            }
+
+            self.update_unacked();
+
+            return Err(e);
+        }
 
         Ok(())
     }
+
+    /// Acknowledge a message to the broker once the download command has been
+    /// handled
+    pub async fn acknowledge(&mut self, response: MessageResponse) {
+        let id = match &response {
+            MessageResponse::Ack { delivery_tag }
+            | MessageResponse::Nack { delivery_tag, .. }
+            | MessageResponse::Reject { delivery_tag, .. } => *delivery_tag,
+        };
+
+        let delivery = match self.deliveries.remove(&id) {
+            Some(delivery) => delivery,
+            None => {
+                debug!("No unacknowledged message for command {id}");
+                return;
+            }
+        };
+
+        self.update_unacked();
+
+        let result = match response {
+            MessageResponse::Ack { .. } => delivery.acker.ack(BasicAckOptions::default()).await,
+            MessageResponse::Nack { delay, .. } => {
+                // Requeue in the background, so that other commands are
+                // acknowledged in the meantime
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+
+                    if let Err(e) = delivery
+                        .acker
+                        .nack(BasicNackOptions {
+                            requeue: true,
+                            ..Default::default()
+                        })
+                        .await
+                    {
+                        error!("Could not requeue message for command {id}: {e}");
+                    }
+                });
+
+                return;
+            }
+            MessageResponse::Reject { reason, .. } => {
+                if self.dead_letter.is_some() {
+                    metrics::DEAD_LETTERED_COUNTER
+                        .with_label_values(&[&self.sftp_source_name])
+                        .inc();
+                }
+
+                settle_rejected(
+                    &delivery,
+                    &reason,
+                    self.channel.as_ref(),
+                    self.dead_letter.as_ref(),
+                    &self.routing_key,
+                )
+                .await
+                .map(|_| true)
+            }
+        };
+
+        if let Err(e) = result {
+            error!("Could not acknowledge message for command {id}: {e}");
+        }
+    }
+
+    /// Forget the messages of a closed connection, the broker redelivers them
+    fn clear(&mut self) {
+        self.channel = None;
+        self.deliveries.clear();
+        self.update_unacked();
+    }
+
+    fn update_unacked(&self) {
+        metrics::UNACKED_MESSAGES_GAUGE
+            .with_label_values(&[&self.sftp_source_name])
+            .set(self.deliveries.len() as i64);
+    }
 }
 
+/// Send a command to the download threads, waiting for room in the channel
+///
+/// While waiting, no new messages are taken from the consumer, so that
+/// consumption pauses until the download threads catch up.
+async fn forward_command(
+    command_sender: Sender<(u64, SftpDownload)>,
+    command: (u64, SftpDownload),
+) -> Result<(), String> {
+    tokio::task::spawn_blocking(move || {
+        command_sender
+            .send(command)
+            .map_err(|SendError(_)| "Channel disconnected".to_string())
+    })
+    .await
+    .map_err(|e| format!("Could not send command on channel: {e}"))?
+}
+
+/// Message body for logging, truncated to at most 1 KB
+fn truncate_body(data: &[u8]) -> String {
+    if data.len() > MAX_LOGGED_BODY {
+        format!(
+            "{}... ({} bytes)",
+            String::from_utf8_lossy(&data[..MAX_LOGGED_BODY]),
+            data.len()
+        )
+    } else {
+        String::from_utf8_lossy(data).to_string()
+    }
+}
+
+/// Consume download commands until the stream ends, e.g. because the
+/// connection was lost
+///
+/// While the source is paused, the consumer is cancelled so that the commands
+/// stay queued. Acknowledgements of commands in progress are still handled.
+/// During a drain, the consumer reports when no commands are in progress
+/// anymore.
+#[allow(clippy::too_many_arguments)]
+async fn consume(
+    mut consumer: lapin::Consumer,
+    config: &AMQPQueStreamConfig,
+    processor: &mut MessageProcessor,
+    ack_receiver: &async_channel::Receiver<MessageResponse>,
+    ack_open: &mut bool,
+    paused: &mut watch::Receiver<bool>,
+    drain: &Drain,
+) -> Result<(), String> {
+    let channel = processor
+        .channel
+        .clone()
+        .ok_or_else(|| "No AMQP channel".to_string())?;
+
+    // The consumer is cancelled, and delivers only the messages it already
+    // received
+    let mut cancelled = false;
+    // All messages of the cancelled consumer are received
+    let mut drained = false;
+    let mut pause_open = true;
+
+    paused.mark_changed();
+
+    loop {
+        if cancelled && drained && processor.deliveries.is_empty() && drain.is_started() {
+            drain.consumer_drained(&processor.sftp_source_name);
+        }
+
+        tokio::select!(
+            changed = paused.changed(), if pause_open => {
+                if changed.is_err() {
+                    pause_open = false;
+                    continue;
+                }
+
+                let is_paused = *paused.borrow_and_update();
+
+                if is_paused && !cancelled {
+                    channel
+                        .basic_cancel(CONSUMER_TAG, BasicCancelOptions::default())
+                        .await
+                        .map_err(|e| format!("Error cancelling consumer: {e}"))?;
+
+                    cancelled = true;
+
+                    info!("Stopped consuming AMQP queue '{}' of paused source '{}'", &config.route.queue, &processor.sftp_source_name);
+                } else if !is_paused && cancelled {
+                    consumer = start_consuming(&channel, &config.route.queue).await?;
+
+                    cancelled = false;
+                    drained = false;
+
+                    info!("Resumed consuming AMQP queue '{}' of source '{}'", &config.route.queue, &processor.sftp_source_name);
+                }
+            },
+            message = consumer.next(), if !drained => {
+                let message = match message {
+                    Some(message) => message,
+                    None if cancelled => {
+                        drained = true;
+                        continue;
+                    }
+                    None => return Ok(()),
+                };
+
+                let message = match message {
+                    // Hand back what the cancelled consumer still received
+                    Ok(delivery) if cancelled => {
+                        if let Err(e) = delivery
+                            .acker
+                            .nack(BasicNackOptions {
+                                requeue: true,
+                                ..Default::default()
+                            })
+                            .await
+                        {
+                            error!("Could not nack message: {e}");
+                        }
+
+                        continue;
+                    }
+                    Ok(delivery) => Ok(delivery),
+                    Err(e) => return Err(format!("Error reading from AMQP stream: {e}")),
+                };
+
+                match processor.process_message(message, ack_receiver, ack_open).await {
+                    Ok(_) => {
+                        debug!("Received message from AMQP queue '{}'", &consumer.queue());
+                    }
+                    Err(e) => {
+                        error!("Could not process message: {e}")
+                    }
+                }
+            },
+            response = ack_receiver.recv(), if *ack_open => {
+                match response {
+                    Ok(response) => processor.acknowledge(response).await,
+                    Err(_) => {
+                        // All download threads have stopped
+                        *ack_open = false;
+                    }
+                }
+            }
+        )
+    }
+}
+
+/// Consume download commands for an SFTP source, reconnecting with backoff
+/// when the connection is lost, until a stop is signalled
+#[allow(clippy::too_many_arguments)]
 pub async fn start(
-    amqp_address: String,
+    command_queue: CommandQueue,
     sftp_source_name: String,
+    route: CommandRoute,
+    prefetch_count: u16,
     command_sender: Sender<(u64, SftpDownload)>,
+    ack_receiver: async_channel::Receiver<MessageResponse>,
+    mut stop_receiver: watch::Receiver<()>,
+    mut paused: watch::Receiver<bool>,
+    source_activities: SourceActivities,
+    drain: Drain,
+    startup: Startup,
 ) -> Result<(), ConsumeError> {
-    let queue_name = format!("source.{}", &sftp_source_name);
-
-    let amqp_stream_config = AMQPQueStreamConfig {
-        address: amqp_address.clone(),
-        queue_name: queue_name.clone(),
+    let config = AMQPQueStreamConfig {
+        command_queue,
+        route,
+        prefetch_count,
     };
 
-    let mut consumer = ReconnectMessageStream::connect(amqp_stream_config).await?;
+    tokio::spawn(
+        QueueDepthPoller {
+            command_queue: config.command_queue.clone(),
+            sftp_source_name: sftp_source_name.clone(),
+            queue: config.route.queue.clone(),
+            command_sender: command_sender.clone(),
+            connection: None,
+            channel: None,
+        }
+        .run(stop_receiver.clone()),
+    );
 
-    let message_processor = MessageProcessor {
+    let mut processor = MessageProcessor {
         command_sender,
         sftp_source_name: sftp_source_name.clone(),
+        routing_key: config.route.routing_key.clone(),
+        dead_letter: config.command_queue.dead_letter.clone(),
+        channel: None,
+        deliveries: HashMap::new(),
+        next_id: 1,
     };
 
-    let m = message_processor.clone();
+    let mut ack_open = true;
+    let mut backoff = Backoff::new();
+    let mut connected = true;
+    let mut first_attempt = true;
+
+    loop {
+        if !first_attempt {
+            metrics::AMQP_RECONNECTS_COUNTER
+                .with_label_values(&[&sftp_source_name])
+                .inc();
+        }
+
+        first_attempt = false;
+
+        let result = tokio::select!(
+            result = async {
+                let (channel, consumer) = connect(&config).await?;
 
-    while let Some(message) = consumer.next().await {
-        match m.process_message(message).await {
-            Ok(_) => {
-                debug!("Received message from AMQP queue '{}'", &queue_name);
+                processor.channel = Some(channel);
+
+                startup.consumer_started(&sftp_source_name);
+
+                if !connected {
+                    warn!(
+                        "Reconnected to AMQP queue '{}' of source '{}'",
+                        &config.route.queue, &sftp_source_name
+                    );
+                    connected = true;
+                }
+
+                backoff.reset();
+
+                consume(
+                    consumer,
+                    &config,
+                    &mut processor,
+                    &ack_receiver,
+                    &mut ack_open,
+                    &mut paused,
+                    &drain,
+                )
+                .await
+            } => result,
+            _ = stop_receiver.changed() => {
+                debug!("Interrupted SFTP command consumer stream '{}'", &sftp_source_name);
+                return Ok(());
             }
-            Err(e) => {
-                error!("Could not process message: {e}")
+        );
+
+        processor.clear();
+
+        match result {
+            Ok(()) => debug!("AMQP stream of source '{}' ended", &sftp_source_name),
+            Err(e) if connected => {
+                warn!(
+                    "Lost AMQP queue '{}' of source '{}': {}",
+                    &config.route.queue, &sftp_source_name, e
+                );
+                source_activities.set_error(
+                    &sftp_source_name,
+                    format!("Lost AMQP queue '{}': {}", &config.route.queue, e),
+                );
+                connected = false;
             }
+            Err(e) => debug!("Could not reconnect source '{}': {}", &sftp_source_name, e),
         }
+
+        let delay = backoff.next_delay();
+
+        debug!(
+            "Reconnecting source '{}' in {} ms",
+            &sftp_source_name,
+            delay.as_millis()
+        );
+
+        tokio::select!(
+            _ = tokio::time::sleep(delay) => (),
+            _ = stop_receiver.changed() => {
+                debug!("Interrupted SFTP command consumer stream '{}'", &sftp_source_name);
+                return Ok(());
+            }
+        );
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use cortex_core::secret::Secret;
+
+    use crate::pause::SourcePauses;
+    use crate::readiness::Readiness;
+    use crate::settings::Settings;
+
+    fn command(id: i64) -> (u64, SftpDownload) {
+        (
+            id as u64,
+            SftpDownload {
+                version: cortex_core::COMMAND_VERSION,
+                id,
+                created: Utc::now(),
+                size: None,
+                sftp_source: "red".to_string(),
+                path: format!("file_{id}.csv"),
+                remove: false,
+                local_subpath: None,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn forward_waits_for_room_in_channel() {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+
+        forward_command(sender.clone(), command(1)).await.unwrap();
+
+        // The channel is full, so forwarding must wait instead of buffering or
+        // failing
+        let forward = tokio::spawn(forward_command(sender, command(2)));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert!(!forward.is_finished());
+        assert_eq!(receiver.len(), 1);
+
+        assert_eq!(receiver.recv().unwrap().0, 1);
+
+        tokio::time::timeout(Duration::from_secs(5), forward)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(receiver.recv().unwrap().0, 2);
+    }
+
+    #[test]
+    fn backoff_grows_to_maximum() {
+        let mut backoff = Backoff::new();
+
+        let delays: Vec<Duration> = (0..10).map(|_| backoff.next_delay()).collect();
+
+        assert!(delays[0] >= RECONNECT_DELAY_MIN / 2 && delays[0] <= RECONNECT_DELAY_MIN);
+        assert!(delays[9] >= RECONNECT_DELAY_MAX / 2 && delays[9] <= RECONNECT_DELAY_MAX);
 
-    Ok(())
+        backoff.reset();
+
+        assert!(backoff.next_delay() <= RECONNECT_DELAY_MIN);
+    }
+
+    #[tokio::test]
+    async fn stop_interrupts_backoff() {
+        let (command_sender, _command_receiver) = crossbeam_channel::bounded(1);
+        let (_ack_sender, ack_receiver) = async_channel::bounded(1);
+        let (stop_sender, stop_receiver) = watch::channel(());
+
+        // Nothing listens on this port, so the consumer ends up in the backoff
+        let consumer = tokio::spawn(start(
+            CommandQueue {
+                address: Secret::from("amqp://127.0.0.1:1/%2f"),
+                address_file: None,
+                amqp_tls: None,
+                control_queue: "cortex-dispatcher.control".to_string(),
+                dead_letter: None,
+                queue_poll_interval: 30_000,
+            },
+            "red".to_string(),
+            CommandRoute {
+                queue: "source.red".to_string(),
+                exchange: "amq.direct".to_string(),
+                routing_key: "source.red".to_string(),
+            },
+            2,
+            command_sender,
+            ack_receiver,
+            stop_receiver,
+            watch::Sender::new(false).subscribe(),
+            SourceActivities::default(),
+            Drain::new(
+                Readiness::default(),
+                SourcePauses::new(&Settings::default()),
+                Vec::new(),
+            ),
+            Startup::new(["red".to_string()]),
+        ));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        stop_sender.send(()).unwrap();
+
+        tokio::time::timeout(Duration::from_millis(200), consumer)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn queue_depth_is_polled_apart_from_consuming() {
+        let (command_sender, _command_receiver) = crossbeam_channel::bounded(2);
+        let (stop_sender, stop_receiver) = watch::channel(());
+
+        let channel_depth = || {
+            metrics::COMMAND_CHANNEL_GAUGE
+                .with_label_values(&["poller"])
+                .get()
+        };
+
+        command_sender.send(command(1)).unwrap();
+
+        // Nothing listens on this port, so every read of the queue fails
+        let poller = tokio::spawn(
+            QueueDepthPoller {
+                command_queue: CommandQueue {
+                    address: Secret::from("amqp://127.0.0.1:1/%2f"),
+                    address_file: None,
+                    amqp_tls: None,
+                    control_queue: "cortex-dispatcher.control".to_string(),
+                    dead_letter: None,
+                    queue_poll_interval: 20,
+                },
+                sftp_source_name: "poller".to_string(),
+                queue: "source.poller".to_string(),
+                command_sender: command_sender.clone(),
+                connection: None,
+                channel: None,
+            }
+            .run(stop_receiver),
+        );
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(channel_depth(), 1);
+
+        // Polling goes on after failed reads
+        command_sender.send(command(2)).unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(channel_depth(), 2);
+
+        stop_sender.send(()).unwrap();
+
+        tokio::time::timeout(Duration::from_millis(200), poller)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn acknowledgements_are_handled_while_forwarding() {
+        let (command_sender, command_receiver) = crossbeam_channel::bounded(1);
+        let (ack_sender, ack_receiver) = async_channel::bounded(1);
+
+        let delivery = |data: Vec<u8>| Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "".into(),
+            redelivered: false,
+            properties: Default::default(),
+            data,
+            acker: lapin::acker::Acker::mock(),
+        };
+
+        let mut processor = MessageProcessor {
+            command_sender: command_sender.clone(),
+            sftp_source_name: "red".to_string(),
+            routing_key: "source.red".to_string(),
+            dead_letter: None,
+            channel: None,
+            deliveries: HashMap::from([(1, delivery(Vec::new()))]),
+            next_id: 2,
+        };
+
+        command_sender.send(command(1)).unwrap();
+
+        // A download thread that acknowledges command 1 twice, of which the
+        // second waits for room in the ack channel, before it takes the next
+        // command
+        let download_thread = std::thread::spawn(move || {
+            for _ in 0..2 {
+                ack_sender
+                    .send_blocking(MessageResponse::Ack { delivery_tag: 1 })
+                    .unwrap();
+            }
+
+            command_receiver.recv().unwrap();
+            command_receiver.recv().unwrap()
+        });
+
+        let mut ack_open = true;
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            processor.process_message(
+                Ok(delivery(cortex_core::command_payload(&command(2).1))),
+                &ack_receiver,
+                &mut ack_open,
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(download_thread.join().unwrap().0, 2);
+        assert!(!processor.deliveries.contains_key(&1));
+        assert!(processor.deliveries.contains_key(&2));
+    }
+
+    /// Publisher that records the published messages instead of sending them
+    struct RecordingPublisher {
+        fail: bool,
+        published: std::sync::Mutex<Vec<(String, String, BasicProperties)>>,
+    }
+
+    #[async_trait]
+    impl ConfirmedPublish for RecordingPublisher {
+        async fn publish_confirmed(
+            &self,
+            exchange: &str,
+            routing_key: &str,
+            _data: &[u8],
+            properties: BasicProperties,
+        ) -> Result<(), String> {
+            if self.fail {
+                return Err("Message was not confirmed by the broker".to_string());
+            }
+
+            self.published.lock().unwrap().push((
+                exchange.to_string(),
+                routing_key.to_string(),
+                properties,
+            ));
+
+            Ok(())
+        }
+    }
+
+    fn failed_delivery() -> Delivery {
+        let mut headers = FieldTable::default();
+        headers.insert("x-trace".into(), AMQPValue::LongString("abc".into()));
+
+        Delivery {
+            delivery_tag: 1,
+            exchange: "amq.direct".into(),
+            routing_key: "source.red".into(),
+            redelivered: false,
+            properties: BasicProperties::default().with_headers(headers),
+            data: cortex_core::command_payload(&command(1).1),
+            acker: lapin::acker::Acker::mock(),
+        }
+    }
+
+    fn dead_letter() -> DeadLetter {
+        DeadLetter {
+            exchange: "cortex-dispatcher.dead-letter".to_string(),
+            queue: "cortex-dispatcher.failed-commands".to_string(),
+            max_retries: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_commands_are_dead_lettered_with_the_reason() {
+        let publisher = RecordingPublisher {
+            fail: false,
+            published: Default::default(),
+        };
+        let delivery = failed_delivery();
+
+        let settlement = settle_rejected(
+            &delivery,
+            "No such file",
+            Some(&publisher),
+            Some(&dead_letter()),
+            "source.red",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(settlement, Settlement::DeadLettered);
+        assert!(!delivery.acker.usable());
+
+        let published = publisher.published.lock().unwrap();
+        let (exchange, routing_key, properties) = &published[0];
+        let headers = properties.headers().as_ref().unwrap().inner();
+
+        assert_eq!(published.len(), 1);
+        assert_eq!(exchange, "cortex-dispatcher.dead-letter");
+        assert_eq!(routing_key, "source.red");
+        assert_eq!(
+            headers.get(FAILURE_REASON_HEADER),
+            Some(&AMQPValue::LongString("No such file".into()))
+        );
+        assert_eq!(
+            headers.get("x-trace"),
+            Some(&AMQPValue::LongString("abc".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_commands_are_nacked_when_dead_lettering_fails() {
+        let publisher = RecordingPublisher {
+            fail: true,
+            published: Default::default(),
+        };
+
+        for publisher in [Some(&publisher), None] {
+            let delivery = failed_delivery();
+
+            let settlement = settle_rejected(
+                &delivery,
+                "No such file",
+                publisher,
+                Some(&dead_letter()),
+                "source.red",
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(settlement, Settlement::Rejected);
+            assert!(!delivery.acker.usable());
+        }
+
+        let delivery = failed_delivery();
+
+        let settlement = settle_rejected(&delivery, "No such file", Some(&publisher), None, "")
+            .await
+            .unwrap();
+
+        assert_eq!(settlement, Settlement::Rejected);
+        assert!(publisher.published.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn forward_fails_when_disconnected() {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+
+        drop(receiver);
+
+        assert_eq!(
+            forward_command(sender, command(1)).await.unwrap_err(),
+            "Channel disconnected"
+        );
+    }
 }
@@ -1,35 +1,304 @@
 use std::convert::TryFrom;
-use std::fs::{rename, File};
+use std::fs::File;
 use std::io;
 use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::{thread, time};
 
 use crossbeam_channel::{Receiver, RecvTimeoutError};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use tokio::sync::watch;
 
 use retry::{delay::Fixed, retry, OperationResult};
 
 use anyhow::Result;
 
-use crate::base_types::MessageResponse;
-use crate::event::FileEvent;
-use crate::local_storage::LocalStorage;
+use crate::base_types::{Connections, MessageResponse};
+use crate::delivery::{DeliveredRemovals, PendingAck};
+use crate::event::{elapsed_since, FileEvent, FileEventSender};
+use crate::local_storage::{LocalStorage, LocalStorageError};
 use crate::metrics;
-use crate::persistence::Persistence;
+use crate::path_lock::PathLocks;
+use crate::persistence::{DownloadLock, DownloadedFile, Persistence};
+use crate::remote_removal::RemoteRemovals;
 use crate::settings;
+use crate::source_activity::SourceActivities;
+use crate::write_behind::{PendingRegistration, WriteBehind};
 
+use cortex_core::copy::pipelined_copy;
 use cortex_core::error::DispatcherError;
-use cortex_core::sftp_connection::SftpConfig;
-use cortex_core::SftpDownload;
+use cortex_core::error_code::ErrorCode;
+use cortex_core::remote_fs::{RemoteError, RemoteFs, SftpFs};
+use cortex_core::sftp_connection::SessionLimits;
+use cortex_core::{coded_error, throttled_error, SftpDownload};
 
-use digest_io::HashWriter;
+use digest_io::{HashReader, HashWriter};
+use flate2::read::GzDecoder;
 use io_tee::TeeReader;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 
 use chrono::{DateTime, Utc};
 
+/// Delay before a command that failed on the database is delivered again
+const PERSISTENCE_RETRY_DELAY: time::Duration = time::Duration::from_secs(10);
+
+/// Delay before a command of a file that another instance is downloading is
+/// delivered again
+const LOCKED_RETRY_DELAY: time::Duration = time::Duration::from_secs(10);
+
+/// Delay before a command that failed on a full storage is delivered again
+const STORAGE_FULL_RETRY_DELAY: time::Duration = time::Duration::from_secs(60);
+
+/// Delay before a command of which the remote file could not be removed is
+/// delivered again, with the `error` delete failure policy
+const REMOTE_DELETE_RETRY_DELAY: time::Duration = time::Duration::from_secs(60);
+
+/// Maximum number of remote files of which the removal is retried at a time
+const REMOTE_DELETE_RETRY_BATCH: usize = 100;
+
+/// Sequence number of downloads, making the names of their part files unique
+static PART_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// How to acknowledge the message of a failed download command
+///
+/// Failures that may pass are requeued, failures that will not pass are
+/// rejected. Retries within the retry budget are already done at this point.
+fn failure_response(delivery_tag: u64, error: &DispatcherError) -> MessageResponse {
+    match error {
+        DispatcherError::ConnectionError(_)
+        | DispatcherError::DisconnectedError(_)
+        | DispatcherError::ConnectionInterrupted(_) => MessageResponse::Nack {
+            delivery_tag,
+            delay: time::Duration::ZERO,
+        },
+        DispatcherError::PersistenceError(_) | DispatcherError::DatabaseError(_) => {
+            MessageResponse::Nack {
+                delivery_tag,
+                delay: PERSISTENCE_RETRY_DELAY,
+            }
+        }
+        DispatcherError::InsufficientSpace(_) => MessageResponse::Nack {
+            delivery_tag,
+            delay: STORAGE_FULL_RETRY_DELAY,
+        },
+        DispatcherError::NoSuchFile
+        | DispatcherError::FileError(_)
+        | DispatcherError::OtherError(_)
+        | DispatcherError::UnsafePath(_) => MessageResponse::Reject {
+            delivery_tag,
+            reason: error.to_string(),
+        },
+    }
+}
+
+/// Writer that discards the data and counts the bytes written
+#[derive(Default)]
+struct ByteCounter {
+    count: u64,
+}
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.count += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writer that accumulates the time spent in the writer it wraps
+struct TimedWriter<W> {
+    inner: W,
+    elapsed: time::Duration,
+}
+
+impl<W: io::Write> io::Write for TimedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = time::Instant::now();
+        let result = self.inner.write(buf);
+        self.elapsed += start.elapsed();
+
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that records whether reading failed, to tell errors of the remote
+/// file apart from errors of the local file in a copy
+struct RemoteReader<R> {
+    inner: R,
+    failed: bool,
+}
+
+impl<R: io::Read> io::Read for RemoteReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.failed |= result.is_err();
+
+        result
+    }
+}
+
+/// Error of a failed copy of a remote file
+///
+/// Reading the remote file mostly fails on a lost connection, after which the
+/// download can be retried.
+fn copy_error(e: io::Error, remote_failed: bool, decompress: bool) -> DispatcherError {
+    match e.kind() {
+        _ if remote_failed => {
+            DispatcherError::ConnectionInterrupted(format!("Error reading remote file: {}", e))
+        }
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+            if decompress =>
+        {
+            DispatcherError::FileError(format!("Corrupt gzip stream: {}", e))
+        }
+        _ => DispatcherError::OtherError(format!("Error copying file: {}", e)),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Download {
+    bytes_read: u64,
+    bytes_written: u64,
+    /// Hash of the file as it was read
+    hash: String,
+    /// Hash of the decompressed file, when decompressed
+    decompressed_hash: Option<String>,
+    /// Time spent hashing the file as it was read
+    hash_time: time::Duration,
+}
+
+/// Copy a remote file to a local file, decompressing it as gzip if requested
+///
+/// Without decompression, the file is hashed and written on separate threads
+/// in buffers of `io_buffer_size` bytes, unless it is 0.
+fn download<R: io::Read, W: io::Write + Send>(
+    reader: &mut R,
+    writer: &mut W,
+    decompress: bool,
+    io_buffer_size: usize,
+) -> io::Result<Download> {
+    let mut hash_writer = TimedWriter {
+        inner: HashWriter::<Sha256, ByteCounter>::new(ByteCounter::default()),
+        elapsed: time::Duration::ZERO,
+    };
+
+    let (bytes_written, decompressed_hash) = if decompress {
+        let tee_reader = TeeReader::new(reader, &mut hash_writer);
+        let mut decoder = HashReader::<Sha256, _>::new(GzDecoder::new(tee_reader));
+
+        let bytes_written = io::copy(&mut decoder, writer)?;
+
+        (bytes_written, Some(hex::encode(decoder.finalize())))
+    } else if io_buffer_size > 0 {
+        (
+            pipelined_copy(reader, writer, &mut hash_writer, io_buffer_size)?,
+            None,
+        )
+    } else {
+        let mut tee_reader = TeeReader::new(reader, &mut hash_writer);
+
+        (io::copy(&mut tee_reader, writer)?, None)
+    };
+
+    let hash_time = hash_writer.elapsed;
+    let (hasher, counter) = hash_writer.inner.into_parts();
+
+    Ok(Download {
+        bytes_read: counter.count,
+        bytes_written,
+        hash: hex::encode(hasher.finalize()),
+        decompressed_hash,
+        hash_time,
+    })
+}
+
+/// Outcome of a handled download command
+#[derive(Debug)]
+pub struct Handled {
+    /// Event of the downloaded file, None when the download was skipped
+    pub file_event: Option<FileEvent>,
+    /// The remote file could not be removed, and the command is to be handled
+    /// again
+    pub retry_remove: bool,
+    /// Another instance is downloading the file, and the command is to be
+    /// handled again
+    pub locked: bool,
+    /// Registration of the file that is not committed yet, which gives the
+    /// file event the id of the file
+    pub registration: Option<PendingRegistration>,
+    /// The remote file is to be removed once the command is acknowledged
+    pub remove_after_delivery: bool,
+    /// Targets that missed the stored file of a command that is delivered
+    /// again, and that the file event is meant for
+    pub redeliver_to: Option<Vec<String>>,
+}
+
+/// The file event of a handled command, once the registration of the file is
+/// committed
+fn committed_file_event(handled: Handled, source_name: &str) -> Option<FileEvent> {
+    let mut file_event = handled.file_event?;
+
+    if let Some(registration) = handled.registration {
+        match registration.wait() {
+            Ok(file_id) => file_event.file_id = file_id,
+            Err(e) => {
+                // The command is already acknowledged, so the file is only
+                // dispatched again with a new download command
+                error!(
+                    "Not dispatching <{}> '{}', its registration failed: {}",
+                    source_name,
+                    file_event.path.to_string_lossy(),
+                    e
+                );
+
+                return None;
+            }
+        }
+    }
+
+    Some(file_event)
+}
+
+/// Whether a connection of the source requires delivery
+fn requires_delivery(connections: &Connections, source_name: &str) -> bool {
+    connections
+        .read()
+        .unwrap()
+        .iter()
+        .any(|c| c.source_name == source_name && c.require_delivery)
+}
+
+/// Whether the number of bytes downloaded matches the size of the remote file
+fn size_accepted(remote_size: u64, bytes_copied: u64, allow_size_growth: bool) -> bool {
+    bytes_copied == remote_size || (allow_size_growth && bytes_copied > remote_size)
+}
+
+/// Handles that the download threads of an SFTP source share with each other
+/// and with the rest of the dispatcher
+#[derive(Clone)]
+pub struct SourceHandles {
+    /// Locks on local paths, shared by the download threads of the source
+    pub path_locks: PathLocks,
+    pub remote_removals: RemoteRemovals,
+    /// Writer thread that registers the downloads of the source in batches
+    pub write_behind: Option<WriteBehind>,
+    pub source_activities: SourceActivities,
+    /// Limits of the SFTP sessions per host, shared by all sources
+    pub session_limits: SessionLimits,
+    pub connections: Connections,
+    /// Remote files of commands that were acknowledged after delivery, removed
+    /// by the download threads of the source
+    pub delivered_removals: DeliveredRemovals,
+}
+
 pub struct SftpDownloader<T>
 where
     T: Persistence,
@@ -37,6 +306,15 @@ where
     pub sftp_source: settings::SftpSource,
     pub persistence: T,
     pub local_storage: LocalStorage<T>,
+    /// Locks on local paths, shared by the download threads of the source
+    pub path_locks: PathLocks,
+    pub remote_removals: RemoteRemovals,
+    /// Writer thread that registers the downloads of the source in batches,
+    /// instead of registering them on the download thread
+    pub write_behind: Option<WriteBehind>,
+    /// Connections of the source, of which those that require delivery hold
+    /// back the removal of remote files until the command is acknowledged
+    pub connections: Option<Connections>,
 }
 
 impl<T> SftpDownloader<T>
@@ -46,86 +324,199 @@ where
     T: Clone,
     T: 'static,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         stop: Arc<AtomicBool>,
         receiver: Receiver<(u64, SftpDownload)>,
         ack_sender: async_channel::Sender<MessageResponse>,
         config: settings::SftpSource,
-        sender: tokio::sync::mpsc::UnboundedSender<FileEvent>,
+        sender: FileEventSender,
         local_storage: LocalStorage<T>,
         persistence: T,
+        max_retries: u32,
+        paused: watch::Receiver<bool>,
+        handles: SourceHandles,
     ) -> thread::JoinHandle<Result<(), DispatcherError>> {
         thread::spawn(move || -> Result<(), DispatcherError> {
             proctitle::set_title("sftp_dl");
 
-            let sftp_config = SftpConfig {
-                address: config.address.clone(),
-                username: config.username.clone(),
-                password: config.password.clone(),
-                key_file: config.key_file.clone(),
-                compress: config.compress,
+            let _thread = metrics::ComponentThread::start("download");
+
+            let mut sftp_config = config.sftp_config();
+            sftp_config.session_limits = handles.session_limits.clone();
+
+            // Held for the lifetime of the thread, so that reconnects do not
+            // wait for a session of their own
+            let Some(_session_permit) = sftp_config.session_permit(&stop) else {
+                return Err(DispatcherError::ConnectionInterrupted(
+                    "Stopped while waiting for an SFTP session".to_string(),
+                ));
             };
 
-            let mut session = sftp_config
-                .connect_loop(stop.clone())
-                .map_err(|e| DispatcherError::ConnectionError(e.to_string()))?;
+            match config.backend {
+                settings::SftpBackend::Libssh2 => Self::serve(
+                    || sftp_config.connect_loop(stop.clone()).and_then(SftpFs::new),
+                    stop.clone(),
+                    receiver,
+                    ack_sender,
+                    config.clone(),
+                    sender,
+                    local_storage,
+                    persistence,
+                    max_retries,
+                    paused,
+                    handles,
+                ),
+                #[cfg(feature = "openssh")]
+                settings::SftpBackend::Openssh => Self::serve(
+                    || sftp_config.connect_openssh_loop(stop.clone()),
+                    stop.clone(),
+                    receiver,
+                    ack_sender,
+                    config.clone(),
+                    sender,
+                    local_storage,
+                    persistence,
+                    max_retries,
+                    paused,
+                    handles,
+                ),
+                #[cfg(not(feature = "openssh"))]
+                settings::SftpBackend::Openssh => Err(DispatcherError::ConnectionError(
+                    "The openssh backend requires the openssh feature".to_string(),
+                )),
+            }
+        })
+    }
+
+    /// Download the commands from the channel on connections made with
+    /// `connect`, until the stop flag is set and the channel is empty
+    #[allow(clippy::too_many_arguments)]
+    fn serve<R, C>(
+        connect: C,
+        stop: Arc<AtomicBool>,
+        receiver: Receiver<(u64, SftpDownload)>,
+        ack_sender: async_channel::Sender<MessageResponse>,
+        config: settings::SftpSource,
+        sender: FileEventSender,
+        local_storage: LocalStorage<T>,
+        persistence: T,
+        max_retries: u32,
+        paused: watch::Receiver<bool>,
+        handles: SourceHandles,
+    ) -> Result<(), DispatcherError>
+    where
+        R: RemoteFs,
+        C: Fn() -> Result<R>,
+    {
+        let SourceHandles {
+            path_locks,
+            remote_removals,
+            write_behind,
+            source_activities,
+            connections,
+            delivered_removals,
+            ..
+        } = handles;
 
-            let mut sftp = session
-                .sftp()
-                .map_err(|e| DispatcherError::ConnectionError(e.to_string()))?;
+        let mut sftp = connect().map_err(|e| DispatcherError::ConnectionError(e.to_string()))?;
 
-            let mut sftp_downloader = SftpDownloader {
-                sftp_source: config.clone(),
-                persistence,
-                local_storage: local_storage.clone(),
-            };
+        source_activities.set_connected(&config.name, true);
 
-            let timeout = time::Duration::from_millis(500);
-
-            // Take SFTP download commands from the queue until the stop flag is set and
-            // the command channel is empty.
-            while !(stop.load(Ordering::Relaxed) && receiver.is_empty()) {
-                let receive_result = receiver.recv_timeout(timeout);
-
-                match receive_result {
-                    Ok((_delivery_tag, command)) => {
-                        let download_result = retry(Fixed::from_millis(1000), || {
-                            match sftp_downloader.handle(&sftp, &command) {
-                                Ok(file_event) => OperationResult::Ok(file_event),
-                                Err(e) => match e {
-                                    DispatcherError::DisconnectedError(_) => {
-                                        info!("Sftp connection disconnected, reconnecting");
-                                        session = match sftp_config.connect_loop(stop.clone()) {
-                                            Ok(s) => s,
-                                            Err(e) => {
-                                                return OperationResult::Err(
-                                                    DispatcherError::ConnectionInterrupted(
-                                                        e.to_string(),
-                                                    ),
-                                                )
-                                            }
-                                        };
-
-                                        sftp = match session.sftp() {
-                                            Ok(s) => s,
-                                            Err(e) => {
-                                                return OperationResult::Err(
-                                                    DispatcherError::ConnectionError(e.to_string()),
-                                                )
-                                            }
-                                        };
-
-                                        info!("Sftp connection reconnected");
-                                        OperationResult::Retry(e)
-                                    }
-                                    _ => OperationResult::Err(e),
-                                },
+        let mut sftp_downloader = SftpDownloader {
+            sftp_source: config.clone(),
+            persistence,
+            local_storage: local_storage.clone(),
+            path_locks,
+            remote_removals,
+            write_behind,
+            connections: Some(connections.clone()),
+        };
+
+        let timeout = time::Duration::from_millis(500);
+
+        let delete_retry_interval = time::Duration::from_secs(config.delete_retry_interval_seconds);
+        let mut last_delete_retry = time::Instant::now();
+
+        // Take SFTP download commands from the queue until the stop flag is set and
+        // the command channel is empty.
+        while !(stop.load(Ordering::Relaxed) && receiver.is_empty()) {
+            for path in delivered_removals.take() {
+                sftp_downloader.remove_remote(&sftp, &path, false);
+            }
+
+            let receive_result = receiver.recv_timeout(timeout);
+
+            match receive_result {
+                Ok((delivery_tag, _)) if *paused.borrow() => {
+                    // Give the command back, so that it stays queued
+                    // until the source is resumed
+                    if let Err(e) = ack_sender.send_blocking(MessageResponse::Nack {
+                        delivery_tag,
+                        delay: time::Duration::ZERO,
+                    }) {
+                        error!("Error sending message nack to channel: {}", e);
+                    }
+                }
+                Ok((delivery_tag, command)) => {
+                    let download_result = sftp_downloader.handle_with_retry(
+                        &mut sftp,
+                        || {
+                            connect()
+                                .map_err(|e| DispatcherError::ConnectionInterrupted(e.to_string()))
+                        },
+                        &command,
+                        max_retries,
+                    );
+
+                    match download_result {
+                        Ok(handled) => {
+                            source_activities.set_connected(&config.name, true);
+
+                            if let (Some(f), None) = (&handled.file_event, &handled.redeliver_to) {
+                                source_activities.record_file(&config.name, f.size);
                             }
-                        });
 
-                        match download_result {
-                            Ok(file_event) => {
-                                let send_result = ack_sender.try_send(MessageResponse::Ack {});
+                            // With a connection that requires delivery, the
+                            // command is acknowledged once the file is handled
+                            let ack = (handled.file_event.is_some()
+                                && !handled.locked
+                                && !handled.retry_remove
+                                && requires_delivery(&connections, &config.name))
+                            .then(|| {
+                                PendingAck::new(
+                                    delivery_tag,
+                                    &command.path,
+                                    ack_sender.clone(),
+                                    handled
+                                        .remove_after_delivery
+                                        .then(|| delivered_removals.clone()),
+                                    handled.redeliver_to.clone(),
+                                )
+                            });
+
+                            // Connections may have changed since the removal
+                            // was held back
+                            if handled.remove_after_delivery && ack.is_none() {
+                                sftp_downloader.remove_remote(&sftp, &command.path, false);
+                            }
+
+                            let response = if handled.locked {
+                                MessageResponse::Nack {
+                                    delivery_tag,
+                                    delay: LOCKED_RETRY_DELAY,
+                                }
+                            } else if handled.retry_remove {
+                                MessageResponse::Nack {
+                                    delivery_tag,
+                                    delay: REMOTE_DELETE_RETRY_DELAY,
+                                }
+                            } else {
+                                MessageResponse::Ack { delivery_tag }
+                            };
+
+                            if ack.is_none() {
+                                let send_result = ack_sender.send_blocking(response);
 
                                 match send_result {
                                     Ok(_) => {
@@ -135,78 +526,251 @@ where
                                         error!("Error sending message ack to channel: {}", e);
                                     }
                                 }
-
-                                if let Some(f) = file_event {
-                                    // Notify about new data from this SFTP source
-                                    let send_result = sender.send(f);
-
-                                    match send_result {
-                                        Ok(_) => {
-                                            debug!("Sent SFTP FileEvent to channel");
-                                        }
-                                        Err(e) => {
-                                            error!("Error notifying consumers of new file: {}", e);
-                                        }
-                                    }
-                                }
                             }
-                            Err(e) => {
-                                let send_result = ack_sender.try_send(MessageResponse::Nack {});
+
+                            if let Some(mut f) = committed_file_event(handled, &config.name) {
+                                f.ack = ack;
+
+                                // Notify about new data from this SFTP source
+                                let send_result = sender.send_blocking(f);
 
                                 match send_result {
                                     Ok(_) => {
-                                        debug!("Sent message nack to channel");
+                                        debug!("Sent SFTP FileEvent to channel");
                                     }
                                     Err(e) => {
-                                        error!("Error sending message nack to channel: {}", e);
+                                        error!("Error notifying consumers of new file: {}", e);
                                     }
                                 }
+                            }
+                        }
+                        Err(e) => {
+                            if matches!(
+                                e.error,
+                                DispatcherError::ConnectionError(_)
+                                    | DispatcherError::DisconnectedError(_)
+                                    | DispatcherError::ConnectionInterrupted(_)
+                            ) {
+                                source_activities.set_connected(&config.name, false);
+                            }
+
+                            source_activities.set_error(
+                                &config.name,
+                                format!("Error downloading '{}': {}", &command.path, e),
+                            );
+
+                            let send_result =
+                                ack_sender.send_blocking(failure_response(delivery_tag, &e.error));
 
-                                error!("[E01003] Error downloading '{}': {}", &command.path, e);
+                            match send_result {
+                                Ok(_) => {
+                                    debug!("Sent message nack to channel");
+                                }
+                                Err(e) => {
+                                    error!("Error sending message nack to channel: {}", e);
+                                }
+                            }
+
+                            // The class of the failure, or of downloads in
+                            // general when it has none
+                            let code = match e.error.code() {
+                                ErrorCode::Other => ErrorCode::Download,
+                                code => code,
+                            };
+
+                            throttled_error!(
+                                code,
+                                &config.name,
+                                "Error downloading '{}': {}",
+                                &command.path,
+                                e
+                            );
+
+                            if let Err(e) = sftp_downloader.persistence.register_download_failure(
+                                &config.name,
+                                &command.path,
+                                code.code(),
+                                &e.error.to_string(),
+                            ) {
+                                warn!("Error registering failed download: {}", e);
                             }
                         }
                     }
-                    Err(e) => {
-                        match e {
-                            RecvTimeoutError::Timeout => (),
-                            RecvTimeoutError::Disconnected => {
-                                // If the stop flag was set, the other side of the channel was
-                                // dropped because of that, otherwise return an error
-                                if stop.load(Ordering::Relaxed) {
-                                    return Ok(());
-                                } else {
-                                    error!("[E02005] SFTP download command channel receiver disconnected");
-
-                                    return Err(DispatcherError::DisconnectedError(format!(
-                                        "SFTP download command channel receiver disconnected: {}",
-                                        e
-                                    )));
-                                }
+                }
+                Err(e) => {
+                    match e {
+                        RecvTimeoutError::Timeout => {
+                            sftp.keepalive();
+
+                            // Retry the removal of remote files while idle
+                            if !delete_retry_interval.is_zero()
+                                && last_delete_retry.elapsed() >= delete_retry_interval
+                            {
+                                last_delete_retry = time::Instant::now();
+
+                                sftp_downloader.retry_remote_deletes(&sftp);
+                            }
+                        }
+                        RecvTimeoutError::Disconnected => {
+                            // If the stop flag was set, the other side of the channel was
+                            // dropped because of that, otherwise return an error
+                            if stop.load(Ordering::Relaxed) {
+                                return Ok(());
+                            } else {
+                                coded_error!(
+                                    ErrorCode::DownloadChannelClosed,
+                                    "SFTP download command channel receiver disconnected"
+                                );
+
+                                return Err(DispatcherError::DisconnectedError(format!(
+                                    "SFTP download command channel receiver disconnected: {}",
+                                    e
+                                )));
                             }
                         }
                     }
                 }
             }
+        }
+
+        debug!("SFTP source stream '{}' ended", config.name);
 
-            debug!("SFTP source stream '{}' ended", config.name);
+        Ok(())
+    }
+
+    /// Handle the command, reconnecting when the connection fails and
+    /// retrying other failures up to `max_retries` times
+    fn handle_with_retry<R, C>(
+        &mut self,
+        fs: &mut R,
+        mut connect: C,
+        command: &SftpDownload,
+        max_retries: u32,
+    ) -> Result<Handled, retry::Error<DispatcherError>>
+    where
+        R: RemoteFs,
+        C: FnMut() -> Result<R, DispatcherError>,
+    {
+        let mut failures: u32 = 0;
 
-            Ok(())
+        retry(Fixed::from_millis(1000), || {
+            match self.handle(fs, command) {
+                Ok(handled) => OperationResult::Ok(handled),
+                Err(e) => match e {
+                    DispatcherError::DisconnectedError(_) => {
+                        info!("Sftp connection disconnected, reconnecting");
+                        metrics::SFTP_RECONNECTS_COUNTER
+                            .with_label_values(&[&self.sftp_source.name])
+                            .inc();
+
+                        *fs = match connect() {
+                            Ok(fs) => fs,
+                            Err(e) => return OperationResult::Err(e),
+                        };
+
+                        info!("Sftp connection reconnected");
+                        OperationResult::Retry(e)
+                    }
+                    // The file is gone, trying again will not help
+                    DispatcherError::NoSuchFile => OperationResult::Err(e),
+                    DispatcherError::UnsafePath(_) => {
+                        error!(
+                            "Rejected download of '{}' from source '{}': {}",
+                            &command.path, &self.sftp_source.name, e
+                        );
+
+                        OperationResult::Err(e)
+                    }
+                    _ if failures < max_retries => {
+                        failures += 1;
+
+                        warn!(
+                            "Download of '{}' failed (attempt {} of {}): {}",
+                            &command.path,
+                            failures,
+                            max_retries + 1,
+                            e
+                        );
+
+                        OperationResult::Retry(e)
+                    }
+                    _ => OperationResult::Err(e),
+                },
+            }
         })
     }
 
-    pub fn handle(
+    pub fn handle<R: RemoteFs>(
         &mut self,
-        sftp: &ssh2::Sftp,
+        fs: &R,
         msg: &SftpDownload,
-    ) -> Result<Option<FileEvent>, DispatcherError> {
+    ) -> Result<Handled, DispatcherError> {
+        self.local_storage.check_space(&self.sftp_source.name)?;
+
         let remote_path = Path::new(&msg.path);
 
+        let decompress = self.sftp_source.decompress.applies_to(&msg.path);
+
+        // Decompressed files are stored without the .gz extension
+        let local_name = if decompress {
+            Path::new(msg.path.strip_suffix(".gz").unwrap_or(&msg.path))
+        } else {
+            remote_path
+        };
+
+        // Files of commands with a local subpath are placed in it, instead
+        // of in the directory of the remote path
+        let local_name = match msg.local_subpath().map_err(DispatcherError::UnsafePath)? {
+            Some(local_subpath) => local_subpath.join(local_name.file_name().unwrap_or_default()),
+            None => local_name.to_path_buf(),
+        };
+
         let path_prefix = Path::new("");
 
         let local_path = self
             .local_storage
-            .local_path(&self.sftp_source.name, &remote_path, &Path::new("/"))
-            .map_err(|e| DispatcherError::FileError(format!("Could not localize path: {}", e)))?;
+            .local_path(&self.sftp_source.name, local_name.as_path(), Path::new("/"))
+            .map_err(|e| match e {
+                LocalStorageError::UnsafePath(message) => DispatcherError::UnsafePath(message),
+                LocalStorageError::Other(_) => {
+                    DispatcherError::FileError(format!("Could not localize path: {}", e))
+                }
+            })?;
+
+        // Commands for the same file that are handled by multiple threads at
+        // once, e.g. after a redelivery, are handled one after the other
+        let _path_guard = self.path_locks.blocking_lock(&local_path);
+
+        // Instances sharing the database can receive commands for the same
+        // file, of which the one without the lock hands the command back
+        // instead of waiting for the download. The deduplication below runs
+        // after the lock is taken.
+        let _download_lock = if self.sftp_source.distributed_locks {
+            let lock = DownloadLock::try_acquire(&self.persistence, &msg.sftp_source, &msg.path)
+                .map_err(|e| {
+                    DispatcherError::PersistenceError(format!("Error locking download: {}", e))
+                })?;
+
+            if lock.is_none() {
+                info!(
+                    "<{}> '{}' is downloaded by another instance",
+                    self.sftp_source.name, msg.path
+                );
+
+                return Ok(Handled {
+                    file_event: None,
+                    retry_remove: false,
+                    locked: true,
+                    registration: None,
+                    remove_after_delivery: false,
+                    redeliver_to: None,
+                });
+            }
+
+            lock
+        } else {
+            None
+        };
 
         match msg.size {
             Some(size) => {
@@ -226,32 +790,26 @@ where
             }
         }
 
-        let mut remote_file = sftp.open(remote_path).map_err(|e| {
-            match e.code() {
-                ssh2::ErrorCode::Session(_) => {
-                    // Probably a fault in the SFTP connection
-                    DispatcherError::DisconnectedError(e.to_string())
-                }
-                ssh2::ErrorCode::SFTP(2) => {
-                    let delete_result = self.persistence.delete_sftp_download_file(msg.id);
+        let mut remote_file = fs.open(remote_path).map_err(|e| match e {
+            RemoteError::Disconnected(e) => DispatcherError::DisconnectedError(e),
+            RemoteError::NoSuchFile => {
+                let delete_result = self.persistence.delete_sftp_download_file(msg.id);
 
-                    match delete_result {
-                        Ok(_) => DispatcherError::NoSuchFile,
-                        Err(e) => DispatcherError::PersistenceError(format!(
-                            "Error removing record of non-existent remote file: {}",
-                            e
-                        )),
-                    }
+                match delete_result {
+                    Ok(_) => DispatcherError::NoSuchFile,
+                    Err(e) => DispatcherError::PersistenceError(format!(
+                        "Error removing record of non-existent remote file: {}",
+                        e
+                    )),
                 }
-                _ => DispatcherError::FileError(format!("Error opening remote file: {}", e)),
+            }
+            RemoteError::Other(e) => {
+                DispatcherError::FileError(format!("Error opening remote file: {}", e))
             }
         })?;
 
-        let stat = remote_file.stat().map_err(|e| match e.code() {
-            ssh2::ErrorCode::Session(_) => {
-                // Probably a fault in the SFTP connection
-                DispatcherError::DisconnectedError(e.to_string())
-            }
+        let stat = fs.stat(&mut remote_file).map_err(|e| match e {
+            RemoteError::Disconnected(e) => DispatcherError::DisconnectedError(e),
             _ => {
                 DispatcherError::FileError(format!("Error retrieving stat for remote file: {}", e))
             }
@@ -268,7 +826,7 @@ where
 
         let file_info_result = self
             .local_storage
-            .get_file_info(&msg.sftp_source, &remote_path, &path_prefix)
+            .get_file_info(&msg.sftp_source, local_name.as_path(), path_prefix)
             .map_err(|e| {
                 DispatcherError::OtherError(format!(
                     "Could not get file information from internal storage: {}",
@@ -276,16 +834,27 @@ where
                 ))
             })?;
 
+        // The stored size of decompressed files differs from the remote size
+        let deduplication_check = match &self.sftp_source.deduplication {
+            settings::Deduplication::Check(check) => Some(settings::FileComparison {
+                size: check.size && !decompress,
+                ..check.clone()
+            }),
+            _ => None,
+        };
+
         // Opportunity for duplicate check without hash check
         if let Some(file_info) = &file_info_result {
             // See if a deduplication check is configured
-            if let settings::Deduplication::Check(check) = &self.sftp_source.deduplication {
+            if let Some(check) = &deduplication_check {
                 // Only check now if no hash check is required, because that is not calculated
                 // yet
                 if !check.hash && check.equal(file_info, stat.size.unwrap(), modified, None) {
                     // A file with the same name, modified timestamp and/or size was already
                     // downloaded, so assume that it is the same and skip.
-                    return Ok(None);
+                    drop(remote_file);
+
+                    return self.skipped(fs, msg, &local_path, decompress);
                 }
             }
         }
@@ -307,9 +876,14 @@ where
             }
         }
 
-        // Construct a temporary file name with the extension '.part'
+        // Construct a temporary file name with the extension '.part' that is
+        // unique per attempt, also across dispatcher processes
         let mut local_path_part = local_path.as_os_str().to_os_string();
-        local_path_part.push(".part");
+        local_path_part.push(format!(
+            ".{}-{}.part",
+            std::process::id(),
+            PART_SEQUENCE.fetch_add(1, Ordering::Relaxed)
+        ));
 
         let mut local_file_part = File::create(&local_path_part).map_err(|e| {
             DispatcherError::FileError(format!(
@@ -319,105 +893,1224 @@ where
             ))
         })?;
 
-        let temp_file = File::create("temp_file.txt").map_err(|e| {
-            DispatcherError::FileError(format!(
-                "Error creating temporary file '{}': {}",
-                local_path.to_string_lossy(),
-                e
-            ))
-        })?;
+        let download_start = time::Instant::now();
 
-        let mut writer = HashWriter::<Sha256, File>::new(temp_file);
+        let mut remote_reader = RemoteReader {
+            inner: &mut remote_file,
+            failed: false,
+        };
 
-        let mut tee_reader = TeeReader::new(&mut remote_file, &mut writer);
+        let download_result = download(
+            &mut remote_reader,
+            &mut local_file_part,
+            decompress,
+            self.sftp_source.io_buffer_size,
+        );
+
+        metrics::add_component_time("download", &self.sftp_source.name, download_start.elapsed());
+
+        let download = match download_result {
+            Ok(download) => {
+                metrics::add_component_time("hash", &self.sftp_source.name, download.hash_time);
+
+                download
+            }
+            Err(e) => {
+                // Leave no partial file behind
+                if let Err(e) = std::fs::remove_file(&local_path_part) {
+                    error!("Error removing local file part: {}", e);
+                }
 
-        let copy_result = io::copy(&mut tee_reader, &mut local_file_part);
-        let hash = hex::encode(writer.finalize());
+                return Err(copy_error(e, remote_reader.failed, decompress));
+            }
+        };
+
+        let (hash, content_hash) = match (self.sftp_source.stored_hash, download.decompressed_hash)
+        {
+            (settings::StoredHash::Decompressed, Some(decompressed_hash)) => {
+                (decompressed_hash, true)
+            }
+            (_, decompressed_hash) => (download.hash, decompressed_hash.is_none()),
+        };
+
+        let bytes_copied = download.bytes_written;
 
         if let Some(file_info) = &file_info_result {
             // See if a deduplication check is configured
-            if let settings::Deduplication::Check(check) = &self.sftp_source.deduplication {
+            if let Some(check) = &deduplication_check {
                 if check.equal(file_info, stat.size.unwrap(), modified, Some(hash.clone())) {
                     // A file with the same name, modified timestamp, size and/or hash was already
                     // downloaded, so assume that it is the same and skip.
-                    return Ok(None);
+                    std::fs::remove_file(&local_path_part).map_err(|e| {
+                        DispatcherError::OtherError(format!(
+                            "Error removing local file part: {}",
+                            e
+                        ))
+                    })?;
+
+                    drop(remote_file);
+
+                    return self.skipped(fs, msg, &local_path, decompress);
                 }
             }
         }
 
-        let bytes_copied = copy_result
-            .map_err(|e| DispatcherError::OtherError(format!("Error copying file: {}", e)))?;
-
         info!(
             "Downloaded <{}> '{}' {} bytes",
-            self.sftp_source.name, msg.path, bytes_copied
+            self.sftp_source.name, msg.path, download.bytes_read
         );
 
-        std::fs::remove_file("temp_file.txt").map_err(|e| {
-            DispatcherError::OtherError(format!("Error removing temporary file: {}", e))
-        })?;
+        if let Some(size) = stat.size {
+            if !size_accepted(
+                size,
+                download.bytes_read,
+                self.sftp_source.allow_size_growth,
+            ) {
+                metrics::DOWNLOAD_TRUNCATED_COUNTER
+                    .with_label_values(&[&self.sftp_source.name])
+                    .inc();
 
-        // Rename the file to its regular name
-        rename(&local_path_part, &local_path).map_err(|e| {
-            DispatcherError::OtherError(format!("Error renaming part to its regular name: {}", e))
-        })?;
+                std::fs::remove_file(&local_path_part).map_err(|e| {
+                    DispatcherError::OtherError(format!("Error removing local file part: {}", e))
+                })?;
 
-        let file_size = i64::try_from(bytes_copied).map_err(|e| {
-            DispatcherError::OtherError(format!("Error converting bytes copied to i64: {}", e))
-        })?;
+                return Err(DispatcherError::ConnectionInterrupted(format!(
+                    "Downloaded {} bytes of '{}' with a remote size of {} bytes",
+                    download.bytes_read, msg.path, size
+                )));
+            }
+        }
 
-        let file_id = self
-            .persistence
-            .insert_file(
+        let store = self
+            .local_storage
+            .resolve_conflict(
                 &self.sftp_source.name,
-                &local_path.to_string_lossy(),
-                &modified,
-                file_size,
-                Some(hash.clone()),
+                Path::new(&local_path_part),
+                &local_path,
             )
-            .map_err(|_| {
-                DispatcherError::PersistenceError(
-                    "Error inserting file into persistence".to_string(),
-                )
+            .map_err(|e| {
+                DispatcherError::FileError(format!(
+                    "Error resolving conflict with stored file: {}",
+                    e
+                ))
+            })?;
+
+        if !store {
+            std::fs::remove_file(&local_path_part).map_err(|e| {
+                DispatcherError::OtherError(format!("Error removing local file part: {}", e))
             })?;
 
-        self.persistence
-            .set_sftp_download_file(msg.id, file_id)
+            drop(remote_file);
+
+            return self.skipped(fs, msg, &local_path, decompress);
+        }
+
+        // Store the file under its regular name
+        let stored = self
+            .local_storage
+            .store_part(
+                &self.sftp_source.name,
+                Path::new(&local_path_part),
+                &local_path,
+                &hash,
+            )
             .map_err(|e| {
                 DispatcherError::OtherError(format!(
-                    "Error updating SFTP download information: {}",
+                    "Error storing part under its regular name: {}",
                     e
                 ))
             })?;
 
+        self.local_storage
+            .set_file_mode(&self.sftp_source.name, &local_path)
+            .map_err(|e| DispatcherError::FileError(e.to_string()))?;
+
+        if stored {
+            self.local_storage
+                .add_usage(&self.sftp_source.name, bytes_copied);
+        }
+
+        let file_size = i64::try_from(bytes_copied).map_err(|e| {
+            DispatcherError::OtherError(format!("Error converting bytes copied to i64: {}", e))
+        })?;
+
+        let downloaded_file = DownloadedFile {
+            source: self.sftp_source.name.clone(),
+            path: local_path.to_string_lossy().to_string(),
+            modified,
+            size: file_size,
+            hash: Some(hash.clone()),
+            sftp_download_id: msg.id,
+            discovered: msg.created,
+        };
+
+        // Without durable acknowledgements, the file event gets the id of the
+        // file once the registration is committed, after the command is
+        // acknowledged
+        let mut registration = None;
+
+        let file_id = match &self.write_behind {
+            Some(write_behind) if !write_behind.durable() => {
+                write_behind.queue(downloaded_file).map(|pending| {
+                    registration = Some(pending);
+                    0
+                })
+            }
+            Some(write_behind) => write_behind.register(downloaded_file),
+            None => self
+                .persistence
+                .register_download(&downloaded_file)
+                .map_err(|e| e.to_string()),
+        }
+        .map_err(|e| {
+            DispatcherError::PersistenceError(format!("Error registering download: {e}"))
+        })?;
+
+        metrics::DOWNLOAD_LATENCY_HISTOGRAM
+            .with_label_values(&[&self.sftp_source.name])
+            .observe(elapsed_since(msg.created).as_secs_f64());
+
         metrics::FILE_DOWNLOAD_COUNTER_VEC
             .with_label_values(&[&self.sftp_source.name])
             .inc();
         metrics::BYTES_DOWNLOADED_COUNTER_VEC
             .with_label_values(&[&self.sftp_source.name])
-            .inc_by(bytes_copied);
+            .inc_by(download.bytes_read);
+
+        let remove = self.removes_remote(msg);
+
+        // With a connection that requires delivery, the remote file is only
+        // removed once the targets handled the file
+        let remove_after_delivery =
+            remove && !self.local_storage.is_dry_run() && self.requires_delivery();
+
+        let retry_remove = if remove && self.local_storage.is_dry_run() {
+            info!("Would remove <{}> '{}'", self.sftp_source.name, msg.path);
+            false
+        } else if remove_after_delivery {
+            debug!(
+                "Removing <{}> '{}' once it is delivered",
+                self.sftp_source.name, msg.path
+            );
+            false
+        } else if remove {
+            drop(remote_file);
+
+            !self.remove_remote(fs, &msg.path, false)
+        } else {
+            false
+        };
+
+        Ok(Handled {
+            file_event: Some(FileEvent {
+                file_id,
+                source_name: self.sftp_source.name.clone(),
+                relative_path: self
+                    .local_storage
+                    .relative_path(&self.sftp_source.name, &local_path),
+                path: local_path,
+                hash,
+                content_hash,
+                size: bytes_copied,
+                modified,
+                created: Utc::now(),
+                discovered: Some(msg.created),
+                ack: None,
+                confirmation: None,
+            }),
+            retry_remove,
+            locked: false,
+            registration,
+            remove_after_delivery,
+            redeliver_to: None,
+        })
+    }
+
+    /// Outcome of a command of which the download was skipped, because the
+    /// file was downloaded before
+    ///
+    /// A stored file that targets requiring delivery have not handled yet is
+    /// delivered again to those targets, which is how a command that was given
+    /// back after a failed delivery reaches them. Otherwise the remote file is
+    /// removed when the command asks for it, which retries a removal that
+    /// failed after the earlier download.
+    fn skipped<R: RemoteFs>(
+        &self,
+        fs: &R,
+        msg: &SftpDownload,
+        local_path: &Path,
+        decompress: bool,
+    ) -> Result<Handled, DispatcherError> {
+        let remove = self.removes_remote(msg) && !self.local_storage.is_dry_run();
+
+        if !self.local_storage.is_dry_run() {
+            if let Some((file_event, targets)) = self.redelivery(msg, local_path, decompress)? {
+                info!(
+                    "Delivering <{}> '{}' again to {}",
+                    self.sftp_source.name,
+                    msg.path,
+                    targets.join(", ")
+                );
+
+                return Ok(Handled {
+                    file_event: Some(file_event),
+                    retry_remove: false,
+                    locked: false,
+                    registration: None,
+                    remove_after_delivery: remove,
+                    redeliver_to: Some(targets),
+                });
+            }
+        }
+
+        let retry_remove = remove && !self.remove_remote(fs, &msg.path, true);
+
+        Ok(Handled {
+            file_event: None,
+            retry_remove,
+            locked: false,
+            registration: None,
+            remove_after_delivery: false,
+            redeliver_to: None,
+        })
+    }
+
+    /// Whether a connection of the source requires delivery
+    fn requires_delivery(&self) -> bool {
+        self.connections
+            .as_ref()
+            .is_some_and(|connections| requires_delivery(connections, &self.sftp_source.name))
+    }
+
+    /// Event of the stored file of the command, with the targets requiring
+    /// delivery that the file was not dispatched to
+    fn redelivery(
+        &self,
+        msg: &SftpDownload,
+        local_path: &Path,
+        decompress: bool,
+    ) -> Result<Option<(FileEvent, Vec<String>)>, DispatcherError> {
+        let Some(connections) = &self.connections else {
+            return Ok(None);
+        };
+
+        if !requires_delivery(connections, &self.sftp_source.name) {
+            return Ok(None);
+        }
+
+        let stored = self
+            .persistence
+            .dispatched_file(&self.sftp_source.name, &local_path.to_string_lossy())
+            .map_err(|e| {
+                DispatcherError::PersistenceError(format!("Error looking up stored file: {e}"))
+            })?;
+
+        let Some(stored) = stored else {
+            return Ok(None);
+        };
+
+        let file_event = FileEvent {
+            file_id: stored.id,
+            source_name: self.sftp_source.name.clone(),
+            relative_path: self
+                .local_storage
+                .relative_path(&self.sftp_source.name, local_path),
+            path: local_path.to_path_buf(),
+            hash: stored.info.hash.unwrap_or_default(),
+            content_hash: !decompress
+                || self.sftp_source.stored_hash == settings::StoredHash::Decompressed,
+            size: u64::try_from(stored.info.size).unwrap_or_default(),
+            modified: stored.info.modified,
+            created: Utc::now(),
+            discovered: Some(msg.created),
+            ack: None,
+            confirmation: None,
+        };
 
-        if msg.remove {
-            let unlink_result = sftp.unlink(remote_path);
+        let targets: Vec<String> = connections
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|c| c.source_name == self.sftp_source.name && c.require_delivery)
+            .filter(|c| c.filter.as_ref().is_none_or(|f| f.matches(&file_event)))
+            .filter(|c| !stored.targets.contains(&c.target.name))
+            .map(|c| c.target.name.clone())
+            .collect();
 
-            match unlink_result {
-                Ok(_) => {
-                    debug!("Removed <{}> '{}'", self.sftp_source.name, msg.path);
+        if targets.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some((file_event, targets)))
+    }
+
+    /// Whether the remote file of the command is to be removed, following the
+    /// remote_remove setting of the source
+    fn removes_remote(&self, msg: &SftpDownload) -> bool {
+        let remove = self
+            .remote_removals
+            .get(&self.sftp_source.name)
+            .applies(msg.remove);
+
+        if msg.remove && !remove {
+            debug!(
+                "Not removing <{}> '{}', because of remote_remove: never",
+                self.sftp_source.name, msg.path
+            );
+
+            metrics::REMOTE_DELETES_SUPPRESSED_COUNTER
+                .with_label_values(&[&self.sftp_source.name])
+                .inc();
+        }
+
+        remove
+    }
+
+    /// Remove a remote file, returning false when it failed and the command is
+    /// to be handled again
+    ///
+    /// Failures are registered for retrying the removal later, unless the
+    /// delete failure policy of the source ignores them. With `clear`, an
+    /// earlier failure is cleared when the file is removed.
+    fn remove_remote<R: RemoteFs>(&self, fs: &R, path: &str, clear: bool) -> bool {
+        let error = match fs.unlink(Path::new(path)) {
+            Ok(()) | Err(RemoteError::NoSuchFile) => {
+                debug!("Removed <{}> '{}'", self.sftp_source.name, path);
+
+                if clear {
+                    self.clear_remote_delete_failure(path);
+                }
+
+                return true;
+            }
+            Err(e) => e,
+        };
+
+        metrics::REMOTE_DELETE_FAILURES_COUNTER
+            .with_label_values(&[&self.sftp_source.name])
+            .inc();
+
+        let message = format!(
+            "Error removing <{}> '{}': {}",
+            self.sftp_source.name, path, error
+        );
+
+        match self.sftp_source.on_delete_failure {
+            settings::DeleteFailurePolicy::Ignore => {
+                debug!("{message}");
+                return true;
+            }
+            settings::DeleteFailurePolicy::Warn => warn!("{message}"),
+            settings::DeleteFailurePolicy::Error => error!("{message}"),
+        }
+
+        if let Err(e) = self.persistence.register_remote_delete_failure(
+            &self.sftp_source.name,
+            path,
+            &error.to_string(),
+        ) {
+            error!("{e}");
+        }
+
+        self.sftp_source.on_delete_failure != settings::DeleteFailurePolicy::Error
+    }
+
+    fn clear_remote_delete_failure(&self, path: &str) {
+        if let Err(e) = self
+            .persistence
+            .clear_remote_delete_failure(&self.sftp_source.name, path)
+        {
+            error!("{e}");
+        }
+    }
+
+    /// Retry removing remote files of which the removal failed before, unless
+    /// removals are disabled with remote_remove: never
+    pub fn retry_remote_deletes<R: RemoteFs>(&self, fs: &R) {
+        if self.remote_removals.get(&self.sftp_source.name) == settings::RemoteRemove::Never {
+            return;
+        }
+
+        let paths = match self
+            .persistence
+            .remote_delete_failures(&self.sftp_source.name, REMOTE_DELETE_RETRY_BATCH)
+        {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("{e}");
+                return;
+            }
+        };
+
+        for path in paths {
+            match fs.unlink(Path::new(&path)) {
+                Ok(()) | Err(RemoteError::NoSuchFile) => {
+                    info!("Removed <{}> '{}' on retry", self.sftp_source.name, path);
+
+                    self.clear_remote_delete_failure(&path);
+                }
+                // Retried on the next interval
+                Err(RemoteError::Disconnected(e)) => {
+                    debug!(
+                        "Could not retry removals of <{}>: {}",
+                        self.sftp_source.name, e
+                    );
+                    return;
                 }
                 Err(e) => {
-                    error!(
-                        "Error removing <{}> '{}': {}",
-                        self.sftp_source.name, msg.path, e
+                    metrics::REMOTE_DELETE_FAILURES_COUNTER
+                        .with_label_values(&[&self.sftp_source.name])
+                        .inc();
+
+                    debug!(
+                        "Retry of removing <{}> '{}' failed: {}",
+                        self.sftp_source.name, path, e
                     );
+
+                    if let Err(e) = self.persistence.register_remote_delete_failure(
+                        &self.sftp_source.name,
+                        &path,
+                        &e.to_string(),
+                    ) {
+                        error!("{e}");
+                    }
                 }
             }
         }
+    }
+}
 
-        Ok(Some(FileEvent {
-            file_id,
-            source_name: self.sftp_source.name.clone(),
-            path: local_path,
-            hash,
-        }))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    use cortex_core::remote_fs::MemoryFs;
+
+    use crate::base_types::{Connection, Target};
+    use crate::event::file_event_channel;
+    use crate::local_storage::StorageArea;
+    use crate::persistence::SqlitePersistence;
+    use crate::readiness::Readiness;
+    use crate::settings::{Settings, DEFAULT_STORAGE_AREA};
+    use crate::storage_usage::StorageUsage;
+
+    fn is_requeued(error: DispatcherError) -> bool {
+        matches!(failure_response(1, &error), MessageResponse::Nack { .. })
+    }
+
+    #[test]
+    fn truncated_download_is_not_accepted() {
+        assert!(size_accepted(100, 100, false));
+        assert!(!size_accepted(100, 60, false));
+        assert!(!size_accepted(100, 120, false));
+        assert!(!size_accepted(100, 60, true));
+        assert!(size_accepted(100, 120, true));
+        assert!(is_requeued(DispatcherError::ConnectionInterrupted(
+            "Downloaded 60 bytes".to_string()
+        )));
+    }
+
+    #[test]
+    fn download_decompresses_gzip() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        io::Write::write_all(&mut encoder, b"a,b\n1,2\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut plain = Vec::new();
+        let result = download(&mut compressed.as_slice(), &mut plain, true, 0).unwrap();
+
+        assert_eq!(plain, b"a,b\n1,2\n");
+        assert_eq!(result.bytes_read, compressed.len() as u64);
+        assert_eq!(result.bytes_written, 8);
+        assert_eq!(result.hash, hex::encode(Sha256::digest(&compressed)));
+        assert_eq!(
+            result.decompressed_hash,
+            Some(hex::encode(Sha256::digest(b"a,b\n1,2\n")))
+        );
+
+        let corrupt = download(&mut &compressed[..12], &mut Vec::new(), true, 0);
+
+        assert!(corrupt.is_err());
+    }
+
+    #[test]
+    fn remote_copy_errors_are_requeued() {
+        struct DroppedConnection;
+
+        impl io::Read for DroppedConnection {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("channel closed"))
+            }
+        }
+
+        struct FailingDisk;
+
+        impl io::Write for FailingDisk {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("write failed"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for decompress in [false, true] {
+            let mut remote_reader = RemoteReader {
+                inner: DroppedConnection,
+                failed: false,
+            };
+
+            let e = download(&mut remote_reader, &mut Vec::new(), decompress, 0).unwrap_err();
+
+            assert!(is_requeued(copy_error(e, remote_reader.failed, decompress)));
+        }
+
+        let mut remote_reader = RemoteReader {
+            inner: &b"a,b\n"[..],
+            failed: false,
+        };
+
+        let e = download(&mut remote_reader, &mut FailingDisk, false, 0).unwrap_err();
+
+        assert!(!remote_reader.failed);
+        assert!(!is_requeued(copy_error(e, remote_reader.failed, false)));
+    }
+
+    #[test]
+    fn missing_file_is_rejected() {
+        assert_eq!(
+            failure_response(7, &DispatcherError::NoSuchFile),
+            MessageResponse::Reject {
+                delivery_tag: 7,
+                reason: "No such file".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn connection_errors_are_requeued() {
+        assert_eq!(
+            failure_response(7, &DispatcherError::DisconnectedError("eof".to_string())),
+            MessageResponse::Nack {
+                delivery_tag: 7,
+                delay: time::Duration::ZERO,
+            }
+        );
+        assert!(is_requeued(DispatcherError::ConnectionInterrupted(
+            "stopped".to_string()
+        )));
+        assert!(is_requeued(DispatcherError::ConnectionError(
+            "refused".to_string()
+        )));
+    }
+
+    #[test]
+    fn persistence_errors_are_requeued_after_delay() {
+        assert_eq!(
+            failure_response(7, &DispatcherError::PersistenceError("locked".to_string())),
+            MessageResponse::Nack {
+                delivery_tag: 7,
+                delay: PERSISTENCE_RETRY_DELAY,
+            }
+        );
+        assert!(is_requeued(DispatcherError::DatabaseError(
+            "locked".to_string()
+        )));
+    }
+
+    fn test_persistence() -> (
+        Arc<std::sync::Mutex<rusqlite::Connection>>,
+        SqlitePersistence,
+    ) {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = Arc::new(std::sync::Mutex::new(conn));
+
+        (conn.clone(), SqlitePersistence::from_arc(conn))
+    }
+
+    fn test_downloader(
+        directory: &Path,
+        persistence: &SqlitePersistence,
+        deduplication: settings::Deduplication,
+    ) -> SftpDownloader<SqlitePersistence> {
+        let mut sftp_source = Settings::default().sftp_sources[0].clone();
+        sftp_source.deduplication = deduplication;
+
+        SftpDownloader {
+            sftp_source,
+            persistence: persistence.clone(),
+            local_storage: LocalStorage::new(
+                vec![StorageArea::new(
+                    DEFAULT_STORAGE_AREA,
+                    directory,
+                    settings::StorageLayout::PerSource,
+                    StorageUsage::new(&Settings::default().storage[0], Readiness::default()),
+                )],
+                persistence.clone(),
+            ),
+            path_locks: PathLocks::default(),
+            remote_removals: RemoteRemovals::default(),
+            write_behind: None,
+            connections: None,
+        }
+    }
+
+    fn test_command(id: i64) -> SftpDownload {
+        SftpDownload {
+            version: cortex_core::COMMAND_VERSION,
+            id,
+            created: Utc::now(),
+            size: Some(64),
+            sftp_source: "red".to_string(),
+            path: "upload/red/data.csv".to_string(),
+            remove: false,
+            local_subpath: None,
+        }
+    }
+
+    fn test_directory(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "cortex-sftp-downloader-{}-{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn duplicate_commands_are_handled_one_at_a_time() {
+        let directory = test_directory("duplicates");
+
+        let (_, persistence) = test_persistence();
+
+        let content: Vec<u8> = (0..64).collect();
+
+        let mut fs = MemoryFs::default();
+        fs.add_file(Path::new("upload/red/data.csv"), &content, 1_700_000_000);
+        fs.set_read_delay(time::Duration::from_millis(1));
+
+        let sftp_downloader =
+            test_downloader(&directory, &persistence, settings::Deduplication::None);
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let mut sftp_downloader = SftpDownloader {
+                    sftp_source: sftp_downloader.sftp_source.clone(),
+                    persistence: persistence.clone(),
+                    local_storage: sftp_downloader.local_storage.clone(),
+                    path_locks: sftp_downloader.path_locks.clone(),
+                    remote_removals: sftp_downloader.remote_removals.clone(),
+                    write_behind: None,
+                    connections: None,
+                };
+                let fs = fs.clone();
+
+                thread::spawn(move || {
+                    (1..=5).all(|id| sftp_downloader.handle(&fs, &test_command(id)).is_ok())
+                })
+            })
+            .collect();
+
+        let all_ok = handles.into_iter().all(|h| h.join().unwrap());
+
+        let source_directory = directory.join("red/upload/red");
+        let stored = std::fs::read(source_directory.join("data.csv")).unwrap();
+        let entries = std::fs::read_dir(&source_directory).unwrap().count();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(all_ok);
+        assert_eq!(fs.max_open_files(), 1);
+        assert_eq!(stored, content);
+        // No part files are left behind
+        assert_eq!(entries, 1);
+    }
+
+    #[test]
+    fn non_durable_file_events_get_committed_ids() {
+        let directory = test_directory("write-behind-non-durable");
+
+        let (conn, persistence) = test_persistence();
+
+        let fs = MemoryFs::default();
+
+        conn.lock()
+            .unwrap()
+            .execute(
+                "insert into sftp_download (id, source, path, size) values (1, 'red', 'upload/red/1.csv', 64)",
+                [],
+            )
+            .unwrap();
+
+        fs.add_file(Path::new("upload/red/1.csv"), &[1; 64], 1_700_000_000);
+
+        // Another label than the other tests, which count the batches
+        let (write_behind, writer_handle) = WriteBehind::start(
+            persistence.clone(),
+            "red-non-durable",
+            &settings::WriteBehind {
+                batch_size: 8,
+                batch_interval_ms: 10,
+                require_durable_ack: false,
+            },
+        );
+
+        let mut sftp_downloader =
+            test_downloader(&directory, &persistence, settings::Deduplication::None);
+        sftp_downloader.write_behind = Some(write_behind);
+
+        let handled = sftp_downloader
+            .handle(
+                &fs,
+                &SftpDownload {
+                    path: "upload/red/1.csv".to_string(),
+                    ..test_command(1)
+                },
+            )
+            .unwrap();
+
+        let pending = handled.registration.is_some();
+        let file_event = committed_file_event(handled, "red").unwrap();
+
+        drop(sftp_downloader);
+        writer_handle.join().unwrap();
+
+        let file_id: i64 = conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "select file_id from sftp_download where id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert!(pending);
+        assert_eq!(file_event.file_id, file_id);
+    }
+
+    #[test]
+    fn downloads_are_registered_in_batches() {
+        let directory = test_directory("write-behind");
+
+        let (conn, persistence) = test_persistence();
+
+        let fs = MemoryFs::default();
+
+        for id in 1..=8 {
+            let path = format!("upload/red/{id}.csv");
+
+            conn.lock()
+                .unwrap()
+                .execute(
+                    "insert into sftp_download (id, source, path, size) values (?1, 'red', ?2, 64)",
+                    rusqlite::params![id, path],
+                )
+                .unwrap();
+
+            fs.add_file(Path::new(&path), &[id as u8; 64], 1_700_000_000);
+        }
+
+        let (write_behind, writer_handle) = WriteBehind::start(
+            persistence.clone(),
+            "red",
+            &settings::WriteBehind {
+                batch_size: 8,
+                batch_interval_ms: 5_000,
+                require_durable_ack: true,
+            },
+        );
+
+        let sftp_downloader =
+            test_downloader(&directory, &persistence, settings::Deduplication::None);
+
+        let handles: Vec<_> = (1..=8)
+            .map(|id| {
+                let mut sftp_downloader = SftpDownloader {
+                    sftp_source: sftp_downloader.sftp_source.clone(),
+                    persistence: persistence.clone(),
+                    local_storage: sftp_downloader.local_storage.clone(),
+                    path_locks: sftp_downloader.path_locks.clone(),
+                    remote_removals: sftp_downloader.remote_removals.clone(),
+                    write_behind: Some(write_behind.clone()),
+                    connections: None,
+                };
+                let fs = fs.clone();
+
+                thread::spawn(move || {
+                    let command = SftpDownload {
+                        path: format!("upload/red/{id}.csv"),
+                        ..test_command(id)
+                    };
+
+                    sftp_downloader.handle(&fs, &command).is_ok()
+                })
+            })
+            .collect();
+
+        let all_ok = handles.into_iter().all(|h| h.join().unwrap());
+
+        drop(write_behind);
+        writer_handle.join().unwrap();
+
+        let registered: i64 = conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "select count(*) from sftp_download d join file f on f.id = d.file_id where f.discovered is not null",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        let batches = metrics::WRITE_BEHIND_BATCH_HISTOGRAM.with_label_values(&["red"]);
+
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert!(all_ok);
+        assert_eq!(registered, 8);
+        // The batch is full before the interval has passed
+        assert_eq!(batches.get_sample_count(), 1);
+        assert_eq!(batches.get_sample_sum(), 8.0);
+    }
+
+    #[test]
+    fn remote_errors_are_mapped() {
+        let directory = test_directory("errors");
+
+        let (conn, persistence) = test_persistence();
+
+        conn.lock()
+            .unwrap()
+            .execute(
+                "insert into sftp_download (id, source, path, size) values (7, 'red', 'upload/red/data.csv', 64)",
+                [],
+            )
+            .unwrap();
+
+        let fs = MemoryFs::default();
+
+        let mut sftp_downloader =
+            test_downloader(&directory, &persistence, settings::Deduplication::None);
+
+        let missing = sftp_downloader.handle(&fs, &test_command(7));
+
+        let remaining: i64 = conn
+            .lock()
+            .unwrap()
+            .query_row("select count(*) from sftp_download", [], |row| row.get(0))
+            .unwrap();
+
+        fs.add_file(Path::new("upload/red/data.csv"), &[0; 64], 1_700_000_000);
+
+        fs.fail_next(RemoteError::Disconnected("socket closed".to_string()));
+        let disconnected = sftp_downloader.handle(&fs, &test_command(8));
+
+        fs.fail_next(RemoteError::Other("permission denied".to_string()));
+        let denied = sftp_downloader.handle(&fs, &test_command(8));
+
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert!(matches!(missing, Err(DispatcherError::NoSuchFile)));
+        assert_eq!(remaining, 0);
+        assert!(matches!(
+            disconnected,
+            Err(DispatcherError::DisconnectedError(_))
+        ));
+        assert!(matches!(denied, Err(DispatcherError::FileError(_))));
+    }
+
+    #[test]
+    fn files_are_placed_in_local_subpath() {
+        let directory = test_directory("subpath");
+
+        let (_, persistence) = test_persistence();
+
+        let fs = MemoryFs::default();
+        fs.add_file(Path::new("upload/red/data.csv"), &[1; 64], 1_700_000_000);
+
+        let mut sftp_downloader =
+            test_downloader(&directory, &persistence, settings::Deduplication::None);
+
+        let command = SftpDownload {
+            local_subpath: Some("batch/42".to_string()),
+            ..test_command(1)
+        };
+
+        let handled = sftp_downloader.handle(&fs, &command).unwrap();
+
+        let escaping = SftpDownload {
+            local_subpath: Some("batch/../../blue".to_string()),
+            ..test_command(2)
+        };
+
+        let rejected = sftp_downloader.handle(&fs, &escaping);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            handled.file_event.unwrap().path,
+            directory.join("red/batch/42/data.csv")
+        );
+        assert!(matches!(rejected, Err(DispatcherError::UnsafePath(_))));
+    }
+
+    #[test]
+    fn unchanged_files_are_skipped() {
+        let directory = test_directory("deduplication");
+
+        let (_, persistence) = test_persistence();
+
+        let fs = MemoryFs::default();
+        fs.add_file(Path::new("upload/red/data.csv"), &[1; 64], 1_700_000_000);
+
+        let results: Vec<(bool, bool)> = [false, true]
+            .into_iter()
+            .map(|hash| {
+                let mut sftp_downloader = test_downloader(
+                    &directory,
+                    &persistence,
+                    settings::Deduplication::Check(settings::FileComparison {
+                        size: true,
+                        modified: true,
+                        hash,
+                    }),
+                );
+
+                let first = sftp_downloader.handle(&fs, &test_command(1)).unwrap();
+                let second = sftp_downloader.handle(&fs, &test_command(2)).unwrap();
+
+                (first.file_event.is_some(), second.file_event.is_some())
+            })
+            .collect();
+
+        let entries = std::fs::read_dir(directory.join("red/upload/red"))
+            .unwrap()
+            .count();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // Without a hash check, the second download is skipped before it starts
+        assert_eq!(results[0], (true, false));
+        // The hash check skips the second download, once the hash is known
+        assert_eq!(results[1], (false, false));
+        // No part files are left behind
+        assert_eq!(entries, 1);
+    }
+
+    #[test]
+    fn failed_removals_are_retried() {
+        let directory = test_directory("removal");
+
+        let (_, persistence) = test_persistence();
+
+        let path = Path::new("upload/red/data.csv");
+
+        let fs = MemoryFs::default();
+        fs.add_file(path, &[2; 64], 1_700_000_000);
+        fs.set_protected(path, true);
+
+        let mut sftp_downloader = test_downloader(
+            &directory,
+            &persistence,
+            settings::Deduplication::Check(settings::FileComparison {
+                size: true,
+                modified: true,
+                hash: false,
+            }),
+        );
+        sftp_downloader.sftp_source.on_delete_failure = settings::DeleteFailurePolicy::Error;
+
+        let mut command = test_command(1);
+        command.remove = true;
+
+        let downloaded = sftp_downloader.handle(&fs, &command).unwrap();
+        let failures = persistence.remote_delete_failures("red", 10).unwrap();
+
+        // The redelivered command skips the download and retries the removal
+        let redelivered = sftp_downloader.handle(&fs, &command).unwrap();
+
+        fs.set_protected(path, false);
+        sftp_downloader.retry_remote_deletes(&fs);
+
+        let remaining = persistence.remote_delete_failures("red", 10).unwrap();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(downloaded.file_event.is_some());
+        assert!(downloaded.retry_remove);
+        assert_eq!(failures, vec!["upload/red/data.csv".to_string()]);
+        assert!(redelivered.file_event.is_none());
+        assert!(redelivered.retry_remove);
+        assert!(!fs.exists(path));
+        assert!(remaining.is_empty());
+    }
+
+    #[test]
+    fn stored_files_are_delivered_again_to_targets_that_missed_them() {
+        let directory = test_directory("redelivery");
+
+        let (_, persistence) = test_persistence();
+
+        let path = Path::new("upload/red/data.csv");
+
+        let fs = MemoryFs::default();
+        fs.add_file(path, &[3; 64], 1_700_000_000);
+
+        let channels = Settings::default().channels;
+
+        let connection = |name: &str| Connection {
+            source_name: "red".to_string(),
+            target: Arc::new(Target {
+                name: name.to_string(),
+                sender: file_event_channel(&format!("target:{name}"), &channels).0,
+                in_progress: Arc::default(),
+            }),
+            filter: None,
+            priority: 0,
+            require_delivery: true,
+            delivery_timeout: time::Duration::from_secs(10),
+        };
+
+        let mut sftp_downloader = test_downloader(
+            &directory,
+            &persistence,
+            settings::Deduplication::Check(settings::FileComparison {
+                size: true,
+                modified: true,
+                hash: false,
+            }),
+        );
+        sftp_downloader.connections = Some(Arc::new(std::sync::RwLock::new(vec![
+            connection("archive"),
+            connection("critical"),
+        ])));
+
+        let mut command = test_command(1);
+        command.remove = true;
+
+        // The remote file is kept until the command is acknowledged
+        let downloaded = sftp_downloader.handle(&fs, &command).unwrap();
+        let file_id = downloaded.file_event.as_ref().unwrap().file_id;
+        let kept = fs.exists(path);
+
+        // The archive handled the file, the critical target failed and the
+        // command was given back
+        persistence.insert_dispatched("archive", file_id).unwrap();
+
+        let redelivered = sftp_downloader.handle(&fs, &command).unwrap();
+
+        persistence.insert_dispatched("critical", file_id).unwrap();
+
+        // Once all targets handled the file, a redelivery is a plain skip
+        let delivered = sftp_downloader.handle(&fs, &command).unwrap();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(downloaded.remove_after_delivery);
+        assert!(downloaded.redeliver_to.is_none());
+        assert!(kept);
+        assert_eq!(redelivered.file_event.unwrap().file_id, file_id);
+        assert_eq!(redelivered.redeliver_to, Some(vec!["critical".to_string()]));
+        assert!(redelivered.remove_after_delivery);
+        assert!(delivered.file_event.is_none());
+        assert!(!delivered.retry_remove);
+        assert!(!fs.exists(path));
+    }
+
+    #[test]
+    fn remote_remove_overrides_commands() {
+        let directory = test_directory("remote-remove");
+
+        let (_, persistence) = test_persistence();
+
+        let path = Path::new("upload/red/data.csv");
+
+        let fs = MemoryFs::default();
+        fs.add_file(path, &[3; 64], 1_700_000_000);
+
+        let mut sftp_downloader =
+            test_downloader(&directory, &persistence, settings::Deduplication::None);
+
+        let mut frozen_source = sftp_downloader.sftp_source.clone();
+        frozen_source.remote_remove = settings::RemoteRemove::Never;
+        sftp_downloader.remote_removals = RemoteRemovals::new(&[frozen_source.clone()]);
+
+        let mut command = test_command(1);
+        command.remove = true;
+
+        let suppressed = metrics::REMOTE_DELETES_SUPPRESSED_COUNTER.with_label_values(&["red"]);
+        let suppressed_before = suppressed.get();
+
+        let frozen = sftp_downloader.handle(&fs, &command).unwrap();
+        let kept = fs.exists(path);
+
+        // A reload lifts the freeze for commands that are already queued
+        frozen_source.remote_remove = settings::RemoteRemove::Inherit;
+        sftp_downloader.remote_removals.update(&[frozen_source]);
+
+        sftp_downloader.handle(&fs, &command).unwrap();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(!frozen.retry_remove);
+        assert!(kept);
+        assert_eq!(suppressed.get(), suppressed_before + 1);
+        assert!(!fs.exists(path));
+    }
+
+    #[test]
+    fn file_errors_are_rejected() {
+        assert!(!is_requeued(DispatcherError::FileError(
+            "permission denied".to_string()
+        )));
+        assert!(!is_requeued(DispatcherError::OtherError(
+            "unknown".to_string()
+        )));
+        assert!(!is_requeued(DispatcherError::UnsafePath(
+            "'../etc/cron.d/evil' contains '..'".to_string()
+        )));
+    }
+
+    #[test]
+    fn unsafe_paths_are_not_retried() {
+        let directory = test_directory("unsafe");
+
+        let (_, persistence) = test_persistence();
+
+        let mut fs = MemoryFs::default();
+
+        let mut sftp_downloader =
+            test_downloader(&directory, &persistence, settings::Deduplication::None);
+
+        let mut command = test_command(1);
+        command.path = "upload/../../../etc/cron.d/evil".to_string();
+
+        let started = Instant::now();
+
+        let result = sftp_downloader.handle_with_retry(
+            &mut fs,
+            || Err(DispatcherError::ConnectionError("unused".to_string())),
+            &command,
+            3,
+        );
+
+        let created = directory.exists();
+
+        assert!(matches!(
+            result,
+            Err(retry::Error {
+                error: DispatcherError::UnsafePath(_),
+                ..
+            })
+        ));
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+        assert!(!created);
     }
 }
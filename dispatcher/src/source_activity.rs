@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::pause::SourceStatus;
+
+/// Number of minutes over which the throughput of a source is reported
+const WINDOW_MINUTES: usize = 60;
+
+/// Files and bytes taken in during one minute
+#[derive(Debug, Clone, Copy, Default)]
+struct MinuteCount {
+    /// Minutes since the Unix epoch
+    minute: i64,
+    files: u64,
+    bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+struct SourceActivity {
+    connected: bool,
+    last_activity: Option<DateTime<Utc>>,
+    error: Option<String>,
+    /// Counts of the last hour, by minute modulo the window
+    minutes: [MinuteCount; WINDOW_MINUTES],
+}
+
+impl Default for SourceActivity {
+    fn default() -> SourceActivity {
+        SourceActivity {
+            connected: false,
+            last_activity: None,
+            error: None,
+            minutes: [MinuteCount::default(); WINDOW_MINUTES],
+        }
+    }
+}
+
+impl SourceActivity {
+    fn record_file(&mut self, now: DateTime<Utc>, bytes: u64) {
+        let minute = now.timestamp().div_euclid(60);
+        let count = &mut self.minutes[minute.rem_euclid(WINDOW_MINUTES as i64) as usize];
+
+        if count.minute != minute {
+            *count = MinuteCount {
+                minute,
+                files: 0,
+                bytes: 0,
+            };
+        }
+
+        count.files += 1;
+        count.bytes += bytes;
+
+        self.last_activity = Some(now);
+        self.error = None;
+    }
+
+    /// Files and bytes taken in during the window up to now
+    fn window_counts(&self, now: DateTime<Utc>) -> (u64, u64) {
+        let minute = now.timestamp().div_euclid(60);
+
+        self.minutes
+            .iter()
+            .filter(|count| (0..WINDOW_MINUTES as i64).contains(&(minute - count.minute)))
+            .fold((0, 0), |(files, bytes), count| {
+                (files + count.files, bytes + count.bytes)
+            })
+    }
+}
+
+/// Status of a source as reported by /api/sources
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceHealth {
+    #[serde(flatten)]
+    pub status: SourceStatus,
+    /// Connected to the SFTP server, or watching the directory
+    pub connected: bool,
+    /// When the last file was taken in
+    pub last_activity: Option<DateTime<Utc>>,
+    pub files_last_hour: u64,
+    pub bytes_last_hour: u64,
+    /// The last error, until the source takes in a file again
+    pub error: Option<String>,
+}
+
+/// Activity of all sources, updated by the threads and tasks that take in
+/// their files
+#[derive(Debug, Clone, Default)]
+pub struct SourceActivities {
+    sources: Arc<RwLock<HashMap<String, SourceActivity>>>,
+}
+
+impl SourceActivities {
+    fn update<F: FnOnce(&mut SourceActivity)>(&self, name: &str, update: F) {
+        let mut sources = self.sources.write().unwrap_or_else(|e| e.into_inner());
+
+        match sources.get_mut(name) {
+            Some(activity) => update(activity),
+            None => update(sources.entry(name.to_string()).or_default()),
+        }
+    }
+
+    pub fn set_connected(&self, name: &str, connected: bool) {
+        self.update(name, |activity| activity.connected = connected);
+    }
+
+    pub fn set_error(&self, name: &str, error: String) {
+        self.update(name, |activity| activity.error = Some(error));
+    }
+
+    /// Register a file taken in from the source, which clears its error
+    pub fn record_file(&self, name: &str, bytes: u64) {
+        let now = Utc::now();
+
+        self.update(name, |activity| activity.record_file(now, bytes));
+    }
+
+    /// Health of a source, with its pause status
+    pub fn health(&self, status: SourceStatus) -> SourceHealth {
+        let sources = self.sources.read().unwrap_or_else(|e| e.into_inner());
+        let activity = sources.get(&status.name).cloned().unwrap_or_default();
+        let (files_last_hour, bytes_last_hour) = activity.window_counts(Utc::now());
+
+        SourceHealth {
+            status,
+            connected: activity.connected,
+            last_activity: activity.last_activity,
+            files_last_hour,
+            bytes_last_hour,
+            error: activity.error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    #[test]
+    fn throughput_covers_the_last_hour() {
+        let now = Utc::now();
+        let mut activity = SourceActivity {
+            error: Some("Connection refused".to_string()),
+            ..Default::default()
+        };
+
+        activity.record_file(now - Duration::minutes(90), 1000);
+        activity.record_file(now - Duration::minutes(30), 100);
+        activity.record_file(now - Duration::minutes(30), 20);
+        activity.record_file(now, 3);
+
+        assert_eq!(activity.window_counts(now), (3, 123));
+        assert_eq!(activity.window_counts(now + Duration::minutes(45)), (1, 3));
+        assert_eq!(activity.last_activity, Some(now));
+        assert_eq!(activity.error, None);
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+
+use cortex_core::error::DispatcherError;
+
+use crate::metrics;
+use crate::readiness::{ComponentState, Readiness};
+use crate::settings;
+
+/// Number of directory entries visited before the walk pauses
+const WALK_BATCH: usize = 1000;
+/// Pause of the walk after each batch, to limit the load on the file system
+const WALK_PAUSE: Duration = Duration::from_millis(10);
+
+/// Readiness component of the storage, followed by the name of the area
+/// unless it is the default one
+const COMPONENT: &str = "storage";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Level {
+    Normal,
+    Warning,
+    Full,
+}
+
+/// Size of an area of the internal storage, compared against its configured
+/// maximum
+///
+/// The size is measured periodically by walking the storage directory, and
+/// files added in between are accounted for when they are stored.
+#[derive(Debug, Clone)]
+pub struct StorageUsage {
+    area: String,
+    component: String,
+    max_bytes: Option<u64>,
+    warn_ratio: f64,
+    bytes: Arc<AtomicU64>,
+    level: Arc<Mutex<Level>>,
+    readiness: Readiness,
+}
+
+impl StorageUsage {
+    pub fn new(storage: &settings::Storage, readiness: Readiness) -> StorageUsage {
+        let component = match storage.name.as_str() {
+            settings::DEFAULT_STORAGE_AREA => COMPONENT.to_string(),
+            name => format!("{COMPONENT}:{name}"),
+        };
+
+        readiness.set(&component, ComponentState::Ready);
+
+        StorageUsage {
+            area: storage.name.clone(),
+            component,
+            max_bytes: storage.max_bytes,
+            warn_ratio: storage.warn_ratio,
+            bytes: Arc::new(AtomicU64::new(0)),
+            level: Arc::new(Mutex::new(Level::Normal)),
+            readiness,
+        }
+    }
+
+    /// Fail when the storage has reached its maximum size
+    pub fn check_space(&self) -> Result<(), DispatcherError> {
+        match (*self.level.lock().unwrap(), self.max_bytes) {
+            (Level::Full, Some(max_bytes)) => Err(DispatcherError::InsufficientSpace(format!(
+                "storage area '{}' holds {} of at most {} bytes",
+                self.area,
+                self.bytes.load(Ordering::Relaxed),
+                max_bytes
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Account for a file that was added to the storage of a source
+    pub fn add(&self, source_name: &str, size: u64) {
+        metrics::STORAGE_BYTES_GAUGE
+            .with_label_values(&[source_name])
+            .add(size as i64);
+        metrics::STORAGE_FILES_GAUGE
+            .with_label_values(&[source_name])
+            .inc();
+
+        let bytes = self.bytes.fetch_add(size, Ordering::Relaxed) + size;
+
+        self.update(bytes);
+    }
+
+    /// Account for a file that was removed from the storage of a source
+    pub fn remove(&self, source_name: &str, size: u64) {
+        metrics::STORAGE_BYTES_GAUGE
+            .with_label_values(&[source_name])
+            .sub(size as i64);
+        metrics::STORAGE_FILES_GAUGE
+            .with_label_values(&[source_name])
+            .dec();
+
+        let previous = self
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bytes| {
+                Some(bytes.saturating_sub(size))
+            })
+            .unwrap();
+
+        self.update(previous.saturating_sub(size));
+    }
+
+    fn set_measured(&self, bytes: u64) {
+        self.bytes.store(bytes, Ordering::Relaxed);
+
+        self.update(bytes);
+    }
+
+    fn update(&self, bytes: u64) {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return,
+        };
+
+        let level = if bytes >= max_bytes {
+            Level::Full
+        } else if bytes as f64 >= max_bytes as f64 * self.warn_ratio {
+            Level::Warning
+        } else {
+            Level::Normal
+        };
+
+        let previous = std::mem::replace(&mut *self.level.lock().unwrap(), level);
+
+        if previous == level {
+            return;
+        }
+
+        let state = match level {
+            Level::Normal => {
+                info!(
+                    "Usage of storage area '{}' of {} bytes is below the warning threshold again",
+                    self.area, bytes
+                );
+                ComponentState::Ready
+            }
+            Level::Warning => {
+                warn!(
+                    "Usage of storage area '{}' of {} bytes crossed {}% of the maximum of {} bytes",
+                    self.area,
+                    bytes,
+                    self.warn_ratio * 100.0,
+                    max_bytes
+                );
+                ComponentState::Degraded
+            }
+            Level::Full => {
+                error!(
+                    "Usage of storage area '{}' of {} bytes reached the maximum of {} bytes, no more files are downloaded or ingested into it",
+                    self.area, bytes, max_bytes
+                );
+                ComponentState::NotReady
+            }
+        };
+
+        self.readiness.set(&self.component, state);
+    }
+}
+
+/// Start the thread that measures the storage usage per source at every
+/// interval
+pub fn start_storage_usage_walker(
+    directory: PathBuf,
+    usage: StorageUsage,
+    interval: Duration,
+    stop_flag: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut next_walk = Instant::now();
+
+        while !stop_flag.load(Ordering::Relaxed) {
+            if Instant::now() < next_walk {
+                thread::sleep(Duration::from_millis(500));
+                continue;
+            }
+
+            match measure(&directory, &stop_flag) {
+                Ok((sources, total)) => {
+                    for (source_name, (bytes, files)) in sources {
+                        metrics::STORAGE_BYTES_GAUGE
+                            .with_label_values(&[&source_name])
+                            .set(bytes as i64);
+                        metrics::STORAGE_FILES_GAUGE
+                            .with_label_values(&[&source_name])
+                            .set(files as i64);
+                    }
+
+                    debug!(
+                        "Usage of storage '{}' is {} bytes",
+                        directory.to_string_lossy(),
+                        total
+                    );
+
+                    usage.set_measured(total);
+                }
+                Err(_) if stop_flag.load(Ordering::Relaxed) => break,
+                Err(e) => error!(
+                    "Error measuring storage usage of '{}': {}",
+                    directory.to_string_lossy(),
+                    e
+                ),
+            }
+
+            next_walk = Instant::now() + interval;
+        }
+
+        debug!("Storage usage thread ended")
+    })
+}
+
+/// Bytes and number of files per source directory of the storage, and the
+/// total bytes in which files that are hardlinked are counted once
+type Measurement = (Vec<(String, (u64, u64))>, u64);
+
+/// Measure the usage of the source directories of the storage
+fn measure(directory: &Path, stop_flag: &AtomicBool) -> io::Result<Measurement> {
+    let mut sources = Vec::new();
+    let mut walk_state = WalkState::default();
+
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+
+        // Objects of the content-addressed layout are linked from the source
+        // directories
+        if entry.file_type()?.is_dir() && entry.file_name() != settings::OBJECTS_DIRECTORY {
+            let mut usage = (0, 0);
+
+            walk(&entry.path(), &mut usage, &mut walk_state, stop_flag)?;
+
+            sources.push((entry.file_name().to_string_lossy().to_string(), usage));
+        }
+    }
+
+    Ok((sources, walk_state.total))
+}
+
+#[derive(Default)]
+struct WalkState {
+    visited: usize,
+    total: u64,
+    /// Device and inode of the visited files that have multiple links
+    linked: HashSet<(u64, u64)>,
+}
+
+fn walk(
+    directory: &Path,
+    usage: &mut (u64, u64),
+    state: &mut WalkState,
+    stop_flag: &AtomicBool,
+) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        if stop_flag.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "stopped"));
+        }
+
+        state.visited += 1;
+
+        if state.visited.is_multiple_of(WALK_BATCH) {
+            thread::sleep(WALK_PAUSE);
+        }
+
+        let entry = entry?;
+
+        // Files may be moved or removed during the walk
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        if metadata.is_dir() {
+            walk(&entry.path(), usage, state, stop_flag)?;
+        } else if metadata.is_file() {
+            usage.0 += metadata.len();
+            usage.1 += 1;
+
+            if metadata.nlink() == 1 || state.linked.insert((metadata.dev(), metadata.ino())) {
+                state.total += metadata.len();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(max_bytes: Option<u64>) -> settings::Storage {
+        settings::Storage {
+            name: settings::DEFAULT_STORAGE_AREA.to_string(),
+            directory: PathBuf::from("/cortex/storage"),
+            layout: settings::StorageLayout::PerSource,
+            max_bytes,
+            warn_ratio: 0.5,
+            usage_interval: 1000,
+            retention: None,
+            file_permissions: None,
+            group: None,
+            create_missing: true,
+            fsync: settings::Fsync::None,
+        }
+    }
+
+    #[test]
+    fn full_storage_has_insufficient_space() {
+        let readiness = Readiness::default();
+        let usage = StorageUsage::new(&storage(Some(100)), readiness.clone());
+
+        usage.add("red", 60);
+
+        assert!(usage.check_space().is_ok());
+        assert_eq!(readiness.components()["storage"], ComponentState::Degraded);
+
+        usage.add("red", 40);
+
+        assert!(matches!(
+            usage.check_space(),
+            Err(DispatcherError::InsufficientSpace(_))
+        ));
+        assert!(!readiness.is_ready());
+
+        usage.set_measured(10);
+
+        assert!(usage.check_space().is_ok());
+        assert!(readiness.is_ready());
+    }
+
+    #[test]
+    fn measure_per_source() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-storage-usage-{}", std::process::id()));
+
+        fs::create_dir_all(directory.join("red/sub")).unwrap();
+        fs::create_dir_all(directory.join("blue")).unwrap();
+        fs::write(directory.join("red/a.csv"), "12345").unwrap();
+        fs::write(directory.join("red/sub/b.csv"), "123").unwrap();
+        fs::hard_link(directory.join("red/a.csv"), directory.join("blue/a.csv")).unwrap();
+
+        let (mut sources, total) = measure(&directory, &AtomicBool::new(false)).unwrap();
+        sources.sort();
+
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            sources,
+            vec![("blue".to_string(), (5, 1)), ("red".to_string(), (8, 2))]
+        );
+        assert_eq!(total, 8);
+    }
+}
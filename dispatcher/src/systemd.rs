@@ -0,0 +1,176 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::persistence::SqliteAsyncPersistence;
+
+/// Interval between status updates when the watchdog is not enabled
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// State sent to systemd
+#[cfg_attr(not(feature = "systemd"), allow(dead_code))]
+enum Notification<'a> {
+    Ready,
+    Stopping,
+    Watchdog,
+    Status(&'a str),
+}
+
+/// Whether systemd started the dispatcher as a notify service
+pub fn is_enabled() -> bool {
+    cfg!(feature = "systemd") && std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+#[cfg(feature = "systemd")]
+fn notify(notification: Notification) {
+    use sd_notify::NotifyState;
+
+    let state = match notification {
+        Notification::Ready => NotifyState::Ready,
+        Notification::Stopping => NotifyState::Stopping,
+        Notification::Watchdog => NotifyState::Watchdog,
+        Notification::Status(status) => NotifyState::Status(status),
+    };
+
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        warn!("Error notifying systemd: {e}");
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+fn notify(_notification: Notification) {}
+
+/// Timeout of the watchdog of the service, when enabled
+#[cfg(feature = "systemd")]
+fn watchdog_timeout() -> Option<Duration> {
+    let mut usec = 0;
+
+    sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(not(feature = "systemd"))]
+fn watchdog_timeout() -> Option<Duration> {
+    None
+}
+
+/// Tell systemd that the dispatcher is stopping
+pub fn notify_stopping() {
+    if is_enabled() {
+        notify(Notification::Stopping);
+    }
+}
+
+/// Startup of the dispatcher, which is complete once the HTTP server listens
+/// and the command consumers of all SFTP sources are established
+#[derive(Debug, Clone)]
+pub struct Startup {
+    pending: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl Startup {
+    pub fn new(sftp_sources: impl IntoIterator<Item = String>) -> Startup {
+        let mut pending: BTreeSet<String> = sftp_sources
+            .into_iter()
+            .map(|name| format!("consumer:{name}"))
+            .collect();
+
+        pending.insert("http_server".to_string());
+
+        Startup {
+            pending: Arc::new(Mutex::new(pending)),
+        }
+    }
+
+    /// Register that the HTTP server listens
+    pub fn http_server_started(&self) {
+        self.started("http_server");
+    }
+
+    /// Register that the command consumer of an SFTP source is established
+    pub fn consumer_started(&self, sftp_source: &str) {
+        self.started(&format!("consumer:{sftp_source}"));
+    }
+
+    /// Register that a component started, telling systemd that the
+    /// dispatcher is ready after the last one
+    fn started(&self, component: &str) {
+        let mut pending = self.pending.lock().unwrap();
+
+        if pending.remove(component) && pending.is_empty() {
+            info!("Dispatcher started");
+
+            if is_enabled() {
+                notify(Notification::Ready);
+            }
+        }
+    }
+}
+
+/// Send the status of the dispatcher to systemd, and notify its watchdog at
+/// half the watchdog timeout as long as the database responds within that
+/// time, which never ends
+///
+/// Runs in the main supervision loop, so that a stalled runtime or a database
+/// connection that stays locked gets the dispatcher restarted.
+pub async fn supervise<F>(persistence: SqliteAsyncPersistence, status: F)
+where
+    F: Fn() -> String,
+{
+    if !is_enabled() {
+        return std::future::pending().await;
+    }
+
+    let watchdog_timeout = watchdog_timeout();
+
+    let interval = watchdog_timeout.map_or(STATUS_INTERVAL, |timeout| timeout / 2);
+
+    debug!(
+        "Notifying systemd every {} ms, with the watchdog {}",
+        interval.as_millis(),
+        if watchdog_timeout.is_some() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+
+    loop {
+        notify(Notification::Status(&status()));
+
+        if watchdog_timeout.is_some() {
+            match tokio::time::timeout(interval, persistence.check()).await {
+                Ok(Ok(())) => notify(Notification::Watchdog),
+                Ok(Err(e)) => warn!("Not notifying the systemd watchdog: {e}"),
+                Err(_) => warn!(
+                    "Not notifying the systemd watchdog: the database did not respond within {} ms",
+                    interval.as_millis()
+                ),
+            }
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn startup_completes_after_all_components() {
+        let startup = Startup::new(["red".to_string(), "blue".to_string()]);
+
+        startup.consumer_started("red");
+        startup.http_server_started();
+        // Reconnects of a consumer do not count twice
+        startup.consumer_started("red");
+
+        assert!(!startup.pending.lock().unwrap().is_empty());
+
+        startup.consumer_started("blue");
+
+        assert!(startup.pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+
+use crate::metrics;
+use crate::persistence::{DownloadedFile, Persistence, RegistrationResult};
+use crate::settings;
+
+/// Downloaded file waiting for registration, with the channel on which the
+/// download thread waits for the id of the file
+struct Registration {
+    download: DownloadedFile,
+    completion: SyncSender<RegistrationResult>,
+}
+
+/// Registration of the downloaded files of a source in batches, on a writer
+/// thread that is shared by the download threads of the source
+///
+/// The writer thread ends when all handles are dropped.
+#[derive(Clone)]
+pub struct WriteBehind {
+    sender: Sender<Registration>,
+    durable: bool,
+}
+
+/// Registration that is waiting for its batch to be committed
+#[derive(Debug)]
+pub struct PendingRegistration {
+    result: Receiver<RegistrationResult>,
+}
+
+impl PendingRegistration {
+    /// Wait until the batch of the registration is committed, returning the
+    /// id of the file
+    pub fn wait(self) -> RegistrationResult {
+        self.result
+            .recv()
+            .map_err(|_| "Write-behind thread ended before the registration".to_string())?
+    }
+}
+
+impl WriteBehind {
+    pub fn start<T>(
+        persistence: T,
+        source_name: &str,
+        settings: &settings::WriteBehind,
+    ) -> (WriteBehind, thread::JoinHandle<()>)
+    where
+        T: Persistence + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let durable = settings.require_durable_ack;
+
+        let source_name = source_name.to_string();
+        let settings = settings.clone();
+
+        let join_handle = thread::spawn(move || {
+            let _thread = metrics::ComponentThread::start("write_behind");
+
+            write_batches(&persistence, &source_name, &settings, &receiver);
+
+            debug!("Write-behind thread of <{source_name}> ended");
+        });
+
+        (WriteBehind { sender, durable }, join_handle)
+    }
+
+    /// Whether the command of a download is only acknowledged once its
+    /// registration is committed
+    pub fn durable(&self) -> bool {
+        self.durable
+    }
+
+    /// Register a downloaded file, returning the id of the file once the
+    /// batch it is part of is committed
+    pub fn register(&self, download: DownloadedFile) -> RegistrationResult {
+        self.queue(download)?.wait()
+    }
+
+    /// Add a downloaded file to the next batch, without waiting for the batch
+    /// to be committed
+    pub fn queue(&self, download: DownloadedFile) -> Result<PendingRegistration, String> {
+        let (completion, result) = mpsc::sync_channel(1);
+
+        self.sender
+            .send(Registration {
+                download,
+                completion,
+            })
+            .map_err(|_| "Write-behind thread has ended".to_string())?;
+
+        Ok(PendingRegistration { result })
+    }
+}
+
+fn write_batches<T: Persistence>(
+    persistence: &T,
+    source_name: &str,
+    settings: &settings::WriteBehind,
+    receiver: &Receiver<Registration>,
+) {
+    let batch_interval = Duration::from_millis(settings.batch_interval_ms);
+
+    // Wait for the first registration of a batch, and then for more until the
+    // batch is full or the interval has passed
+    while let Ok(first) = receiver.recv() {
+        let deadline = Instant::now() + batch_interval;
+        let mut batch = vec![first];
+
+        while batch.len() < settings.batch_size.max(1) {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(registration) => batch.push(registration),
+                Err(_) => break,
+            }
+        }
+
+        let downloads: Vec<DownloadedFile> = batch
+            .iter()
+            .map(|registration| registration.download.clone())
+            .collect();
+
+        let write_result = persistence.register_downloads(&downloads);
+
+        metrics::WRITE_BEHIND_BATCH_HISTOGRAM
+            .with_label_values(&[source_name])
+            .observe(batch.len() as f64);
+
+        match write_result {
+            Ok(results) => complete(&batch, results),
+            Err(e) => {
+                error!(
+                    "Error registering {} downloads of <{source_name}>: {e}",
+                    batch.len()
+                );
+
+                complete(&batch, vec![Err(e.to_string()); batch.len()]);
+            }
+        }
+    }
+}
+
+fn complete(batch: &[Registration], results: Vec<RegistrationResult>) {
+    for (registration, result) in batch.iter().zip(results) {
+        // The download thread is gone when it stopped while waiting
+        let _ = registration.completion.send(result);
+    }
+}
@@ -0,0 +1,144 @@
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use assert_cmd::cmd::Command;
+    use predicates::prelude::*;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+
+    use dev_stack::dev_stack::DevStack;
+
+    /// Generate a CA with a server certificate for localhost and a client
+    /// certificate, readable by the RabbitMQ user in the container
+    fn generate_certificates(tls_dir: &Path) {
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca)
+            .unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let client_cert = CertificateParams::new(vec!["cortex-dispatcher".to_string()])
+            .unwrap()
+            .signed_by(&client_key, &ca)
+            .unwrap();
+
+        let files = [
+            ("ca.pem", ca.pem()),
+            ("server.pem", server_cert.pem()),
+            ("server-key.pem", server_key.serialize_pem()),
+            ("client.pem", client_cert.pem()),
+            ("client-key.pem", client_key.serialize_pem()),
+        ];
+
+        for (name, content) in files {
+            let path = tls_dir.join(name);
+
+            std::fs::write(&path, content).unwrap();
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            std::fs::set_permissions(tls_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+
+    fn render_cortex_config(root_dir: &Path, port: u16, amqp_tls: &str) -> String {
+        let root_dir = root_dir.to_string_lossy();
+
+        format!(
+            r###"
+storage:
+  directory: {root_dir}/storage
+
+command_queue:
+  address: "amqps://localhost:{port}/%2f"
+{amqp_tls}
+
+connections: []
+
+sqlite:
+  path: {root_dir}/cortex.db
+
+http_server:
+  address: "0.0.0.0:56008"
+"###
+        )
+    }
+
+    fn dispatcher_bin() -> PathBuf {
+        std::env::current_dir()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("target")
+            .join("debug")
+            .join("cortex-dispatcher")
+    }
+
+    fn check_config(config_path: &Path) -> assert_cmd::assert::Assert {
+        Command::new(dispatcher_bin())
+            .arg("check-config")
+            .arg("--config")
+            .arg(config_path)
+            .arg("--probe")
+            .assert()
+    }
+
+    #[tokio::test]
+    async fn connect_with_private_ca() {
+        let root_dir = tempfile::tempdir().unwrap();
+        let tls_dir = root_dir.path().join("tls");
+
+        std::fs::create_dir_all(root_dir.path().join("storage")).unwrap();
+        std::fs::create_dir_all(&tls_dir).unwrap();
+
+        generate_certificates(&tls_dir);
+
+        let dev_stack = DevStack::start_tls(true, &tls_dir).await.unwrap();
+        let port = dev_stack.rabbitmq_tls_port().await.unwrap();
+
+        let config_path = root_dir.path().join("cortex-dispatcher.yml");
+
+        let amqp_tls = format!(
+            r###"  amqp_tls:
+    ca_cert: {tls_dir}/ca.pem
+    client_cert: {tls_dir}/client.pem
+    client_key: {tls_dir}/client-key.pem"###,
+            tls_dir = tls_dir.to_string_lossy()
+        );
+
+        std::fs::write(
+            &config_path,
+            render_cortex_config(root_dir.path(), port, &amqp_tls),
+        )
+        .unwrap();
+
+        check_config(&config_path).success();
+
+        // The private CA is not among the system roots
+        std::fs::write(
+            &config_path,
+            render_cortex_config(root_dir.path(), port, ""),
+        )
+        .unwrap();
+
+        check_config(&config_path)
+            .failure()
+            .stdout(predicate::str::contains("amqp command queue"));
+    }
+}
@@ -0,0 +1,202 @@
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    use chrono::Utc;
+    use lapin::options::{BasicAckOptions, BasicGetOptions, QueueDeclareOptions};
+    use lapin::types::FieldTable;
+    use lapin::{Channel, Connection, ConnectionProperties};
+
+    use cortex_core::client::CommandPublisher;
+    use cortex_core::secret::Secret;
+    use cortex_core::{parse_command, SftpDownload, COMMAND_VERSION};
+
+    use dev_stack::dev_stack::DevStack;
+
+    const SOURCE: &str = "local-red";
+    const QUEUE: &str = "source.local-red";
+    const COMMANDS: i64 = 25;
+
+    fn render_cortex_config(root_dir: &Path, amqp_address: &str) -> String {
+        let root_dir = root_dir.to_string_lossy();
+
+        // Nothing listens on the SFTP address, so the download thread never
+        // takes a command and all of them are in progress at the drain
+        format!(
+            r###"
+storage:
+  directory: {root_dir}/storage
+
+command_queue:
+  address: "{amqp_address}"
+
+sftp_sources:
+  - name: {SOURCE}
+    address: 127.0.0.1:1
+    username: cortex
+    password: secret
+    thread_count: 1
+    prefetch_count: 10
+
+connections: []
+
+scan_interval: 1000
+
+sqlite:
+  path: {root_dir}/cortex.db
+
+http_server:
+  address: "127.0.0.1:56009"
+"###
+        )
+    }
+
+    fn dispatcher_bin() -> PathBuf {
+        std::env::current_dir()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("target")
+            .join("debug")
+            .join("cortex-dispatcher")
+    }
+
+    async fn ready_messages(channel: &Channel) -> u32 {
+        channel
+            .queue_declare(
+                QUEUE.into(),
+                QueueDeclareOptions {
+                    passive: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .unwrap()
+            .message_count()
+    }
+
+    #[tokio::test]
+    async fn drain_hands_back_all_commands() {
+        let dev_stack = DevStack::start(false).await.unwrap();
+
+        let amqp_address = format!(
+            "amqp://{}:{}/%2f",
+            dev_stack.rabbitmq_host().await.unwrap(),
+            dev_stack.rabbitmq_port().await.unwrap()
+        );
+
+        let root_dir = tempfile::tempdir().unwrap();
+        let config_path = root_dir.path().join("cortex-dispatcher.yml");
+        let log_path = root_dir.path().join("cortex-dispatcher.log");
+
+        std::fs::write(
+            &config_path,
+            render_cortex_config(root_dir.path(), &amqp_address),
+        )
+        .unwrap();
+
+        let mut publisher = CommandPublisher::connect(Secret::from(amqp_address.as_str()))
+            .await
+            .unwrap();
+
+        for id in 1..=COMMANDS {
+            publisher
+                .publish_sftp_download(&SftpDownload {
+                    version: COMMAND_VERSION,
+                    id,
+                    created: Utc::now(),
+                    size: None,
+                    sftp_source: SOURCE.to_string(),
+                    path: format!("upload/red/file_{id}.csv"),
+                    remove: false,
+                    local_subpath: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let connection = Connection::connect(&amqp_address, ConnectionProperties::default())
+            .await
+            .unwrap();
+        let channel = connection.create_channel().await.unwrap();
+
+        let mut dispatcher = Command::new(dispatcher_bin())
+            .env("RUST_LOG", "info")
+            .arg("service")
+            .arg("--config")
+            .arg(&config_path)
+            .stderr(Stdio::from(std::fs::File::create(&log_path).unwrap()))
+            .spawn()
+            .unwrap();
+
+        // Wait for the dispatcher to take commands from the queue
+        let deadline = Instant::now() + Duration::from_secs(30);
+
+        while ready_messages(&channel).await == COMMANDS as u32 {
+            assert!(Instant::now() < deadline, "No commands were consumed");
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+
+        // Give the dispatcher time to install its signal handlers
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let kill_status = Command::new("kill")
+            .arg("-USR1")
+            .arg(dispatcher.id().to_string())
+            .status()
+            .unwrap();
+
+        assert!(kill_status.success());
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+
+        let exit_status = loop {
+            if let Some(status) = dispatcher.try_wait().unwrap() {
+                break status;
+            }
+
+            if Instant::now() > deadline {
+                dispatcher.kill().unwrap();
+                panic!("Dispatcher did not stop after the drain");
+            }
+
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        };
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+
+        assert!(exit_status.success(), "{log}");
+        assert!(log.contains("Draining dispatcher on SIGUSR1"), "{log}");
+        assert!(
+            log.contains("Drain completed, stopping dispatcher"),
+            "{log}"
+        );
+
+        // A new instance gets every command, which is taken here directly
+        // from the queue
+        let mut ids = Vec::new();
+
+        while let Some(message) = channel
+            .basic_get(QUEUE.into(), BasicGetOptions::default())
+            .await
+            .unwrap()
+        {
+            let command: SftpDownload = parse_command(&message.delivery.data).unwrap();
+
+            message
+                .delivery
+                .ack(BasicAckOptions::default())
+                .await
+                .unwrap();
+
+            ids.push(command.id);
+        }
+
+        ids.sort();
+
+        assert_eq!(ids, (1..=COMMANDS).collect::<Vec<i64>>());
+    }
+}
@@ -0,0 +1,90 @@
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use assert_cmd::cmd::Command;
+    use predicates::prelude::*;
+
+    fn render_cortex_config(root_dir: &Path) -> String {
+        let root_dir = root_dir.to_string_lossy();
+
+        format!(
+            r###"
+storage:
+  directory: {root_dir}/storage
+
+command_queue:
+  address: "amqp://127.0.0.1:5672/%2f"
+
+connections: []
+
+sqlite:
+  path: {root_dir}/cortex.db
+
+http_server:
+  address: "0.0.0.0:56008"
+"###
+        )
+    }
+
+    fn dispatcher_bin() -> PathBuf {
+        std::env::current_dir()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("target")
+            .join("debug")
+            .join("cortex-dispatcher")
+    }
+
+    #[test]
+    fn list_seeded_files() {
+        let root_dir = tempfile::tempdir().unwrap();
+        let config_path = root_dir.path().join("cortex-dispatcher.yml");
+
+        std::fs::write(&config_path, render_cortex_config(root_dir.path())).unwrap();
+
+        let fixture_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../dev-stack/seed.yaml");
+
+        Command::new(dispatcher_bin())
+            .arg("init-database")
+            .arg("--config")
+            .arg(&config_path)
+            .arg("--seed")
+            .arg(fixture_path)
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("Seeded 5 files"));
+
+        assert!(root_dir
+            .path()
+            .join("storage/mixed-directory/2024/01/02/test_file_20240102_080000_v6.csv")
+            .is_file());
+
+        Command::new(dispatcher_bin())
+            .arg("files")
+            .arg("--config")
+            .arg(&config_path)
+            .arg("list")
+            .arg("--json")
+            .arg("--source")
+            .arg("mixed-directory")
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("test_file_20240101_120000_v5.csv"))
+            .stdout(predicate::str::contains("red_20240102_090000.csv").not());
+
+        Command::new(dispatcher_bin())
+            .arg("files")
+            .arg("--config")
+            .arg(&config_path)
+            .arg("list")
+            .arg("--undispatched-to")
+            .arg("v5")
+            .arg("--path-like")
+            .arg("%_v5.csv")
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("_v5.csv").not());
+    }
+}
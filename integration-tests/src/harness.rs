@@ -0,0 +1,591 @@
+//! Harness that runs the dispatcher binary against the dev stack, with all
+//! directories in a temporary directory
+//!
+//! A scenario configures the targets and connections, starts the pipeline,
+//! drops files on the sources and asserts what arrives:
+//!
+//! ```ignore
+//! let pipeline = Scenario::new()
+//!     .target(Target::new("red").notify("red-queue"))
+//!     .connection(Connection::new(INCOMING, "red"))
+//!     .start()
+//!     .await;
+//!
+//! pipeline.drop_incoming("a.csv", "1,2\n");
+//! pipeline.wait_for_target_file("red", "a.csv", "1,2\n").await;
+//! ```
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use lapin::options::{BasicAckOptions, BasicGetOptions, QueueDeclareOptions};
+use lapin::types::FieldTable;
+use lapin::{Channel, Connection as AmqpConnection, ConnectionProperties};
+use tempfile::TempDir;
+use testcontainers::ContainerAsync;
+
+use cortex_core::client::CommandPublisher;
+use cortex_core::secret::Secret;
+use cortex_core::{SftpDownload, COMMAND_VERSION};
+
+use dev_stack::dev_stack::{start_sftp_server, DevStack, SftpServer, SFTP_USER};
+
+/// Name of the directory source, which monitors [`Pipeline::incoming_dir`]
+pub const INCOMING: &str = "incoming";
+
+/// Name of the SFTP source, of which the commands are consumed from the
+/// `source.local-red` queue of the dev stack
+pub const SFTP_SOURCE: &str = "local-red";
+
+/// Time within which the dispatcher must start and files must arrive
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Directory target of a scenario, placed in `targets/<name>`
+#[derive(Debug, Clone)]
+pub struct Target {
+    name: String,
+    overwrite: bool,
+    notify_queue: Option<String>,
+}
+
+impl Target {
+    pub fn new(name: &str) -> Target {
+        Target {
+            name: name.to_string(),
+            overwrite: true,
+            notify_queue: None,
+        }
+    }
+
+    pub fn overwrite(mut self, overwrite: bool) -> Target {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Publish a notification of every placed file on `queue`, through the
+    /// default exchange
+    ///
+    /// The message is `{"file_path": "<target path>", "size": <size>}`.
+    pub fn notify(mut self, queue: &str) -> Target {
+        self.notify_queue = Some(queue.to_string());
+        self
+    }
+}
+
+/// Connection of a scenario from a source to a target
+#[derive(Debug, Clone)]
+pub struct Connection {
+    source: String,
+    target: String,
+    filter: Option<String>,
+}
+
+impl Connection {
+    pub fn new(source: &str, target: &str) -> Connection {
+        Connection {
+            source: source.to_string(),
+            target: target.to_string(),
+            filter: None,
+        }
+    }
+
+    /// Only dispatch files with a name matching the regular expression
+    pub fn filter(mut self, pattern: &str) -> Connection {
+        self.filter = Some(pattern.to_string());
+        self
+    }
+}
+
+/// Configuration of the dispatcher for a test, of which the directories are
+/// filled in when the pipeline starts
+#[derive(Debug, Clone, Default)]
+pub struct Scenario {
+    targets: Vec<Target>,
+    connections: Vec<Connection>,
+    deduplication: Option<String>,
+    sftp: bool,
+}
+
+impl Scenario {
+    pub fn new() -> Scenario {
+        Scenario::default()
+    }
+
+    pub fn target(mut self, target: Target) -> Scenario {
+        self.targets.push(target);
+        self
+    }
+
+    pub fn connection(mut self, connection: Connection) -> Scenario {
+        self.connections.push(connection);
+        self
+    }
+
+    /// Deduplication of the directory source as inline YAML, e.g. `none`
+    pub fn deduplication(mut self, deduplication: &str) -> Scenario {
+        self.deduplication = Some(deduplication.to_string());
+        self
+    }
+
+    /// Also start an SFTP server and configure the SFTP source
+    pub fn with_sftp(mut self) -> Scenario {
+        self.sftp = true;
+        self
+    }
+
+    fn render_config(
+        &self,
+        root_dir: &Path,
+        amqp_address: &str,
+        http_port: u16,
+        sftp: Option<(&str, &Path)>,
+    ) -> String {
+        let root_dir = root_dir.to_string_lossy();
+
+        let mut config = format!(
+            r###"
+storage:
+  directory: {root_dir}/storage
+
+command_queue:
+  address: "{amqp_address}"
+
+directory_sources:
+  - name: {INCOMING}
+    directory: {root_dir}/incoming
+    create_missing: true
+    events:
+      - CloseWrite
+      - MovedTo
+"###
+        );
+
+        if let Some(deduplication) = &self.deduplication {
+            writeln!(config, "    deduplication: {deduplication}").unwrap();
+        }
+
+        config.push_str("\nsftp_sources:");
+
+        match sftp {
+            Some((address, key_file)) => write!(
+                config,
+                r###"
+  - name: {SFTP_SOURCE}
+    address: {address}
+    username: {SFTP_USER}
+    key_file: {}
+    thread_count: 1
+"###,
+                key_file.to_string_lossy()
+            )
+            .unwrap(),
+            None => config.push_str(" []\n"),
+        }
+
+        config.push_str("\ndirectory_targets:");
+
+        if self.targets.is_empty() {
+            config.push_str(" []");
+        }
+
+        for target in &self.targets {
+            write!(
+                config,
+                r###"
+  - name: {name}
+    directory: {root_dir}/targets/{name}
+    overwrite: {overwrite}
+    permissions: 420"###,
+                name = target.name,
+                overwrite = target.overwrite,
+            )
+            .unwrap();
+
+            if let Some(queue) = &target.notify_queue {
+                write!(
+                    config,
+                    r###"
+    notify:
+      rabbitmq:
+        message_template: '{{"file_path": "{{{{ file_path }}}}", "size": {{{{ size }}}}}}'
+        address: "{amqp_address}"
+        exchange: ""
+        routing_key: "{queue}""###
+                )
+                .unwrap();
+            }
+        }
+
+        config.push_str("\n\nconnections:");
+
+        if self.connections.is_empty() {
+            config.push_str(" []");
+        }
+
+        for connection in &self.connections {
+            write!(
+                config,
+                "\n  - source: {}\n    target: {}",
+                connection.source, connection.target
+            )
+            .unwrap();
+
+            if let Some(pattern) = &connection.filter {
+                write!(
+                    config,
+                    "\n    filter:\n      Regex:\n        pattern: '{pattern}'"
+                )
+                .unwrap();
+            }
+        }
+
+        write!(
+            config,
+            r###"
+
+scan_interval: 1000
+
+sqlite:
+  path: {root_dir}/cortex.db
+
+http_server:
+  address: "127.0.0.1:{http_port}"
+"###
+        )
+        .unwrap();
+
+        config
+    }
+
+    /// Start the dev stack and the dispatcher, and wait until the dispatcher
+    /// reports that it started
+    pub async fn start(self) -> Pipeline {
+        let dev_stack = DevStack::start(false).await.unwrap();
+
+        let amqp_address = format!(
+            "amqp://{}:{}/%2f",
+            dev_stack.rabbitmq_host().await.unwrap(),
+            dev_stack.rabbitmq_port().await.unwrap()
+        );
+
+        let root_dir = tempfile::tempdir().unwrap();
+
+        for dir in ["staging", "upload"] {
+            std::fs::create_dir_all(root_dir.path().join(dir)).unwrap();
+        }
+
+        let sftp_server = match self.sftp {
+            true => Some(start_sftp(root_dir.path()).await),
+            false => None,
+        };
+
+        let sftp_address = match &sftp_server {
+            Some(container) => Some(format!(
+                "{}:{}",
+                container.get_host().await.unwrap(),
+                container.get_host_port_ipv4(22).await.unwrap()
+            )),
+            None => None,
+        };
+
+        let key_file = root_dir.path().join("id_ed25519");
+
+        let config = self.render_config(
+            root_dir.path(),
+            &amqp_address,
+            free_port(),
+            sftp_address
+                .as_deref()
+                .map(|address| (address, key_file.as_path())),
+        );
+
+        let config_path = root_dir.path().join("cortex-dispatcher.yml");
+        let log_path = root_dir.path().join("cortex-dispatcher.log");
+
+        std::fs::write(&config_path, config).unwrap();
+
+        let connection = AmqpConnection::connect(&amqp_address, ConnectionProperties::default())
+            .await
+            .unwrap();
+        let channel = connection.create_channel().await.unwrap();
+
+        for queue in self.targets.iter().filter_map(|t| t.notify_queue.as_ref()) {
+            channel
+                .queue_declare(
+                    queue.as_str().into(),
+                    QueueDeclareOptions::default(),
+                    FieldTable::default(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let dispatcher = Command::new(dispatcher_bin())
+            .env("RUST_LOG", "info")
+            .arg("service")
+            .arg("--config")
+            .arg(&config_path)
+            .stderr(Stdio::from(std::fs::File::create(&log_path).unwrap()))
+            .spawn()
+            .unwrap();
+
+        let mut pipeline = Pipeline {
+            root_dir,
+            log_path,
+            amqp_address,
+            dispatcher,
+            channel,
+            _connection: connection,
+            _sftp_server: sftp_server,
+            _dev_stack: dev_stack,
+        };
+
+        pipeline.wait_for_start().await;
+
+        pipeline
+    }
+}
+
+/// Generate a key pair for the SFTP user and start the SFTP server on the
+/// `upload` directory
+async fn start_sftp(root_dir: &Path) -> ContainerAsync<SftpServer> {
+    let key_file = root_dir.join("id_ed25519");
+
+    let status = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(&key_file)
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+
+    // The user in the container must be able to read the uploaded files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(
+            root_dir.join("upload"),
+            std::fs::Permissions::from_mode(0o777),
+        )
+        .unwrap();
+    }
+
+    start_sftp_server(&root_dir.join("upload"), &key_file.with_extension("pub"))
+        .await
+        .unwrap()
+}
+
+fn dispatcher_bin() -> PathBuf {
+    std::env::current_dir()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .join("target")
+        .join("debug")
+        .join("cortex-dispatcher")
+}
+
+/// Port on which nothing listens, for the HTTP server of the dispatcher
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Running dispatcher with its dev stack, which is stopped when dropped
+pub struct Pipeline {
+    root_dir: TempDir,
+    log_path: PathBuf,
+    amqp_address: String,
+    dispatcher: Child,
+    channel: Channel,
+    _connection: AmqpConnection,
+    _sftp_server: Option<ContainerAsync<SftpServer>>,
+    _dev_stack: DevStack,
+}
+
+impl Pipeline {
+    pub fn incoming_dir(&self) -> PathBuf {
+        self.root_dir.path().join("incoming")
+    }
+
+    pub fn target_dir(&self, target: &str) -> PathBuf {
+        self.root_dir.path().join("targets").join(target)
+    }
+
+    /// Everything the dispatcher logged so far
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(&self.log_path).unwrap()
+    }
+
+    async fn wait_for_start(&mut self) {
+        let deadline = Instant::now() + TIMEOUT;
+
+        while !self.log().contains("Dispatcher started") {
+            if let Some(status) = self.dispatcher.try_wait().unwrap() {
+                panic!("Dispatcher stopped with {status}:\n{}", self.log());
+            }
+
+            assert!(
+                Instant::now() < deadline,
+                "Dispatcher did not start:\n{}",
+                self.log()
+            );
+
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Write a file and move it into the incoming directory, so that it is
+    /// taken in complete
+    pub fn drop_incoming(&self, name: &str, content: &str) {
+        let staging_path = self.root_dir.path().join("staging").join(name);
+
+        std::fs::write(&staging_path, content).unwrap();
+        std::fs::rename(&staging_path, self.incoming_dir().join(name)).unwrap();
+    }
+
+    /// Place a file in the upload directory of the SFTP server and publish the
+    /// command to download it
+    pub async fn drop_sftp(&self, id: i64, name: &str, content: &str) {
+        std::fs::write(self.root_dir.path().join("upload").join(name), content).unwrap();
+
+        let mut publisher = CommandPublisher::connect(Secret::from(self.amqp_address.as_str()))
+            .await
+            .unwrap();
+
+        publisher
+            .publish_sftp_download(&SftpDownload {
+                version: COMMAND_VERSION,
+                id,
+                created: Utc::now(),
+                size: Some(content.len() as u64),
+                sftp_source: SFTP_SOURCE.to_string(),
+                path: format!("upload/{name}"),
+                remove: false,
+                local_subpath: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    /// Wait until the file is placed in the target directory with the
+    /// expected content
+    pub async fn wait_for_target_file(&self, target: &str, name: &str, content: &str) {
+        let path = self.target_dir(target).join(name);
+        let deadline = Instant::now() + TIMEOUT;
+
+        while std::fs::read_to_string(&path).ok().as_deref() != Some(content) {
+            assert!(
+                Instant::now() < deadline,
+                "'{}' did not arrive with {content:?}:\n{}",
+                path.to_string_lossy(),
+                self.log()
+            );
+
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Wait until the dispatcher logged a line containing `text`
+    pub async fn wait_for_log(&self, text: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+
+        while !self.log().contains(text) {
+            assert!(
+                Instant::now() < deadline,
+                "{text:?} was not logged:\n{}",
+                self.log()
+            );
+
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Targets to which the file with the name from the source is registered
+    /// as dispatched, in the `file` and `dispatched` tables
+    pub fn dispatched_targets(&self, source: &str, name: &str) -> Vec<String> {
+        let connection = rusqlite::Connection::open_with_flags(
+            self.root_dir.path().join("cortex.db"),
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )
+        .unwrap();
+
+        connection.busy_timeout(Duration::from_secs(5)).unwrap();
+
+        let mut stmt = connection
+            .prepare(
+                "select d.target from dispatched d
+                 join file f on f.id = d.file_id
+                 where f.source = ?1 and (f.path = ?2 or f.path like '%/' || ?2)
+                 order by d.target",
+            )
+            .unwrap();
+
+        stmt.query_map([source, name], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<String>, _>>()
+            .unwrap()
+    }
+
+    /// Take `count` messages from the queue, waiting for them to be published
+    pub async fn notifications(&self, queue: &str, count: usize) -> Vec<String> {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut messages = Vec::new();
+
+        while messages.len() < count {
+            let message = self
+                .channel
+                .basic_get(queue.into(), BasicGetOptions::default())
+                .await
+                .unwrap();
+
+            match message {
+                Some(message) => {
+                    message
+                        .delivery
+                        .ack(BasicAckOptions::default())
+                        .await
+                        .unwrap();
+
+                    messages.push(String::from_utf8(message.delivery.data.clone()).unwrap());
+                }
+                None => {
+                    assert!(
+                        Instant::now() < deadline,
+                        "{} of {count} notifications on '{queue}' were published:\n{}",
+                        messages.len(),
+                        self.log()
+                    );
+
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+
+        messages
+    }
+
+    /// Expected notification of a file placed in the target
+    pub fn notification(&self, target: &str, name: &str, content: &str) -> String {
+        format!(
+            r#"{{"file_path": "{}", "size": {}}}"#,
+            self.target_dir(target).join(name).to_string_lossy(),
+            content.len()
+        )
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        let _ = self.dispatcher.kill();
+        let _ = self.dispatcher.wait();
+    }
+}
@@ -0,0 +1,131 @@
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    use dev_stack::dev_stack::{start_sftp_server, SFTP_USER};
+
+    /// Number of files in the scanned directory
+    const ENTRIES: usize = 500_000;
+
+    /// Peak resident memory the scanner may use, far less than a listing of
+    /// all entries of the directory takes
+    const MAX_PEAK_RSS_KB: u64 = 128 * 1024;
+
+    const SCAN_TIMEOUT: Duration = Duration::from_secs(600);
+
+    fn scanner_bin() -> PathBuf {
+        std::env::current_dir()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("target")
+            .join("debug")
+            .join("cortex-sftp-scanner")
+    }
+
+    /// Peak resident memory of a process in kB
+    fn peak_rss_kb(pid: u32) -> u64 {
+        std::fs::read_to_string(format!("/proc/{pid}/status"))
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap()
+    }
+
+    fn line_count(path: &Path) -> usize {
+        std::fs::read(path)
+            .map(|content| content.iter().filter(|b| **b == b'\n').count())
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn large_directory_is_scanned_in_bounded_memory() {
+        let root_dir = tempfile::tempdir().unwrap();
+        let upload_dir = root_dir.path().join("upload");
+        let large_dir = upload_dir.join("large");
+        let key_file = root_dir.path().join("id_ed25519");
+        let config_file = root_dir.path().join("sftp-scanner.yaml");
+        let report_file = root_dir.path().join("report.jsonl");
+
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(&key_file)
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+
+        std::fs::create_dir_all(&large_dir).unwrap();
+
+        for n in 0..ENTRIES {
+            std::fs::File::create(large_dir.join(format!("file-{n:07}.csv"))).unwrap();
+        }
+
+        let container = start_sftp_server(&upload_dir, &key_file.with_extension("pub"))
+            .await
+            .unwrap();
+
+        let host = container.get_host().await.unwrap();
+        let port = container.get_host_port_ipv4(22).await.unwrap();
+
+        // Nothing is sent in a dry run, so no broker is needed
+        std::fs::write(
+            &config_file,
+            format!(
+                r#"command_queue:
+  address: amqp://127.0.0.1:1/%2f
+sqlite:
+  path: {}
+sftp_sources:
+- name: large
+  address: {host}:{port}
+  username: {SFTP_USER}
+  key_file: {}
+  regex: '^.*\.csv$'
+  directory: upload/large
+  recurse: true
+  scan_interval: 3600000
+"#,
+                root_dir.path().join("scanner.db").to_string_lossy(),
+                key_file.to_string_lossy()
+            ),
+        )
+        .unwrap();
+
+        let mut scanner = Command::new(scanner_bin())
+            .arg("--config")
+            .arg(&config_file)
+            .arg("--dry-run")
+            .arg("--report-file")
+            .arg(&report_file)
+            .spawn()
+            .unwrap();
+
+        let start = Instant::now();
+
+        while line_count(&report_file) < ENTRIES {
+            assert!(
+                start.elapsed() < SCAN_TIMEOUT,
+                "Scan did not complete within {} seconds",
+                SCAN_TIMEOUT.as_secs()
+            );
+
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        let peak_rss = peak_rss_kb(scanner.id());
+
+        scanner.kill().unwrap();
+        scanner.wait().unwrap();
+
+        println!(
+            "Scanned {ENTRIES} entries in {} s with a peak resident memory of {peak_rss} kB",
+            start.elapsed().as_secs()
+        );
+
+        assert!(peak_rss < MAX_PEAK_RSS_KB);
+    }
+}
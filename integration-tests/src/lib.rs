@@ -1 +1,9 @@
+pub mod amqp_tls;
+pub mod drain;
+pub mod files_list;
+pub mod harness;
+pub mod large_scan;
+pub mod pipeline;
+#[cfg(feature = "openssh")]
+pub mod sftp_backends;
 pub mod smoke;
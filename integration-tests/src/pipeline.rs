@@ -0,0 +1,100 @@
+#[cfg(test)]
+mod tests {
+    use crate::harness::{Connection, Scenario, Target, INCOMING, SFTP_SOURCE};
+
+    #[tokio::test]
+    async fn files_from_all_sources_reach_their_targets() {
+        let pipeline = Scenario::new()
+            .with_sftp()
+            .target(Target::new("red").notify("processing-node-red"))
+            .target(Target::new("blue").notify("processing-node-blue"))
+            .connection(Connection::new(INCOMING, "red"))
+            .connection(Connection::new(SFTP_SOURCE, "blue"))
+            .start()
+            .await;
+
+        pipeline.drop_incoming("a.csv", "a,b\n1,2\n");
+        pipeline.drop_sftp(1, "b.csv", "c,d\n3,4\n").await;
+
+        pipeline
+            .wait_for_target_file("red", "a.csv", "a,b\n1,2\n")
+            .await;
+        pipeline
+            .wait_for_target_file("blue", "b.csv", "c,d\n3,4\n")
+            .await;
+
+        assert_eq!(
+            pipeline.notifications("processing-node-red", 1).await,
+            [pipeline.notification("red", "a.csv", "a,b\n1,2\n")]
+        );
+        assert_eq!(
+            pipeline.notifications("processing-node-blue", 1).await,
+            [pipeline.notification("blue", "b.csv", "c,d\n3,4\n")]
+        );
+
+        assert_eq!(pipeline.dispatched_targets(INCOMING, "a.csv"), ["red"]);
+        assert_eq!(pipeline.dispatched_targets(SFTP_SOURCE, "b.csv"), ["blue"]);
+    }
+
+    #[tokio::test]
+    async fn connection_filters_route_files() {
+        let pipeline = Scenario::new()
+            .target(Target::new("v5"))
+            .target(Target::new("v6"))
+            .connection(Connection::new(INCOMING, "v5").filter(r"^.*-v5\.xml$"))
+            .connection(Connection::new(INCOMING, "v6").filter(r"^.*-v6\.xml$"))
+            .start()
+            .await;
+
+        pipeline.drop_incoming("a-v5.xml", "<v5/>");
+        pipeline.drop_incoming("b-v6.xml", "<v6/>");
+
+        pipeline
+            .wait_for_target_file("v5", "a-v5.xml", "<v5/>")
+            .await;
+        pipeline
+            .wait_for_target_file("v6", "b-v6.xml", "<v6/>")
+            .await;
+
+        assert!(!pipeline.target_dir("v6").join("a-v5.xml").exists());
+        assert!(!pipeline.target_dir("v5").join("b-v6.xml").exists());
+        assert_eq!(pipeline.dispatched_targets(INCOMING, "a-v5.xml"), ["v5"]);
+    }
+
+    #[tokio::test]
+    async fn unchanged_files_are_taken_in_once() {
+        let pipeline = Scenario::new()
+            .target(Target::new("red"))
+            .connection(Connection::new(INCOMING, "red"))
+            .start()
+            .await;
+
+        pipeline.drop_incoming("a.csv", "1\n");
+        pipeline.wait_for_target_file("red", "a.csv", "1\n").await;
+
+        pipeline.drop_incoming("a.csv", "1\n");
+        pipeline.wait_for_log("already processed").await;
+
+        // A changed file with the same name is taken in again
+        pipeline.drop_incoming("a.csv", "2\n");
+        pipeline.wait_for_target_file("red", "a.csv", "2\n").await;
+    }
+
+    #[tokio::test]
+    async fn existing_files_are_kept_without_overwrite() {
+        let pipeline = Scenario::new()
+            .deduplication("none")
+            .target(Target::new("red").overwrite(false))
+            .connection(Connection::new(INCOMING, "red"))
+            .start()
+            .await;
+
+        pipeline.drop_incoming("a.csv", "1\n");
+        pipeline.wait_for_target_file("red", "a.csv", "1\n").await;
+
+        pipeline.drop_incoming("a.csv", "2\n");
+        pipeline.wait_for_log("Could not hardlink").await;
+
+        pipeline.wait_for_target_file("red", "a.csv", "1\n").await;
+    }
+}
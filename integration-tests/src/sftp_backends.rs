@@ -0,0 +1,148 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::path::Path;
+    use std::process::Command;
+
+    use cortex_core::remote_fs::{RemoteError, RemoteFs, SftpFs};
+    use cortex_core::sftp_connection::{
+        default_connect_timeout_seconds, default_handshake_timeout_seconds,
+        default_keepalive_interval_seconds, HostKeyCheck, SessionLimits, SftpConfig,
+    };
+
+    use dev_stack::dev_stack::{start_sftp_server, SFTP_USER};
+
+    fn write_upload_files(upload_dir: &Path) {
+        std::fs::create_dir_all(upload_dir.join("2024")).unwrap();
+        std::fs::write(upload_dir.join("a.csv"), "a,b\n1,2\n").unwrap();
+        std::fs::write(upload_dir.join("2024/b.csv"), "c,d\n3,4\n").unwrap();
+
+        // The user in the container must be able to remove and rename
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            for dir in [upload_dir.to_path_buf(), upload_dir.join("2024")] {
+                std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+            }
+        }
+    }
+
+    /// Everything the dispatcher observes of the remote files, as text
+    fn observe<R: RemoteFs>(fs: &R) -> Vec<String> {
+        let mut observations = Vec::new();
+
+        for directory in ["upload", "upload/2024"] {
+            let mut dir = fs.opendir(Path::new(directory)).unwrap();
+            let mut entries = Vec::new();
+
+            while let Some((name, stat)) = fs.readdir(&mut dir).unwrap() {
+                if name == Path::new(".") || name == Path::new("..") {
+                    continue;
+                }
+
+                let size = if stat.is_dir() { None } else { stat.size };
+
+                entries.push(format!(
+                    "{directory}/{} dir={} size={:?}",
+                    name.to_string_lossy(),
+                    stat.is_dir(),
+                    size
+                ));
+            }
+
+            entries.sort();
+            observations.extend(entries);
+        }
+
+        for path in ["upload/a.csv", "upload/2024/b.csv"] {
+            let mut file = fs.open(Path::new(path)).unwrap();
+            let stat = fs.stat(&mut file).unwrap();
+            let mut content = String::new();
+            file.read_to_string(&mut content).unwrap();
+
+            observations.push(format!(
+                "{path} size={:?} file={} content={content:?}",
+                stat.size,
+                stat.file_type().is_file()
+            ));
+        }
+
+        observations.push(format!(
+            "missing open={:?}",
+            fs.open(Path::new("upload/missing.csv")).err()
+        ));
+        observations.push(format!(
+            "missing unlink={:?}",
+            fs.unlink(Path::new("upload/missing.csv")).err()
+        ));
+
+        fs.rename(Path::new("upload/a.csv"), Path::new("upload/a.csv.done"))
+            .unwrap();
+        fs.unlink(Path::new("upload/a.csv.done")).unwrap();
+
+        observations.push(format!(
+            "removed open={:?}",
+            fs.open(Path::new("upload/a.csv.done")).err()
+        ));
+
+        observations
+    }
+
+    #[tokio::test]
+    async fn backends_observe_the_same() {
+        let root_dir = tempfile::tempdir().unwrap();
+        let upload_dir = root_dir.path().join("upload");
+        let key_file = root_dir.path().join("id_ed25519");
+
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(&key_file)
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+
+        write_upload_files(&upload_dir);
+
+        let container = start_sftp_server(&upload_dir, &key_file.with_extension("pub"))
+            .await
+            .unwrap();
+
+        let host = container.get_host().await.unwrap();
+        let port = container.get_host_port_ipv4(22).await.unwrap();
+
+        let config = SftpConfig {
+            address: format!("{host}:{port}"),
+            username: SFTP_USER.to_string(),
+            password: None,
+            key_file: Some(key_file.clone()),
+            key_passphrase: None,
+            key_passphrase_file: None,
+            compress: false,
+            connect_timeout_seconds: default_connect_timeout_seconds(),
+            handshake_timeout_seconds: default_handshake_timeout_seconds(),
+            keepalive_interval_seconds: default_keepalive_interval_seconds(),
+            max_attempts: Some(1),
+            session_limits: SessionLimits::default(),
+            // The container has a new host key on every run
+            host_key_check: HostKeyCheck::AcceptNew,
+        };
+
+        // Both backends block on their connections
+        let (libssh2, openssh) = tokio::task::spawn_blocking(move || {
+            let libssh2 = observe(&config.connect().and_then(SftpFs::new).unwrap());
+
+            write_upload_files(&upload_dir);
+
+            let openssh = observe(&config.connect_openssh().unwrap());
+
+            (libssh2, openssh)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(libssh2, openssh);
+        assert!(libssh2.contains(&format!("missing open={:?}", Some(RemoteError::NoSuchFile))));
+    }
+}
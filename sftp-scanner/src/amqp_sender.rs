@@ -1,49 +1,62 @@
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use lapin::{options::BasicPublishOptions, BasicProperties};
-
 use crossbeam_channel::{Receiver, RecvTimeoutError};
 
-use cortex_core::SftpDownload;
+use cortex_core::client::CommandPublisher;
+use cortex_core::error_code::ErrorCode;
+use cortex_core::secret::Secret;
+use cortex_core::{throttled_error, SftpDownload};
+
+use log::{debug, error};
 
-use log::{debug, error, info};
+use crate::metrics;
+use crate::settings::CommandRoute;
+
+/// Time between attempts to connect to the AMQP server
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
 
 pub async fn start_sender(
     stop: Arc<AtomicBool>,
     receiver: Receiver<SftpDownload>,
-    address: String,
+    address: Secret,
+    routes: HashMap<String, CommandRoute>,
 ) {
-    let amqp_conn = lapin::Connection::connect(&address, lapin::ConnectionProperties::default())
-        .await
-        .expect("connection error");
+    let mut publisher = loop {
+        match CommandPublisher::connect(address.clone()).await {
+            Ok(publisher) => break publisher,
+            Err(e) => {
+                error!("{e}");
+
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
 
-    let channel = amqp_conn.create_channel().await.expect("create_channel");
-    info!("Created channel with id {}", channel.id());
+                tokio::time::sleep(CONNECT_RETRY_INTERVAL).await;
+            }
+        }
+    };
 
-    let exchange = "amq.direct";
+    for (sftp_source, route) in routes {
+        publisher = publisher.with_route(&sftp_source, route);
+    }
 
     while !stop.load(Ordering::Relaxed) {
         let receive_result = receiver.recv_timeout(Duration::from_millis(100));
 
         match receive_result {
             Ok(command) => {
-                let command_str = serde_json::to_string(&command).unwrap();
-                let routing_key = format!("source.{}", &command.sftp_source);
-
-                channel
-                    .basic_publish(
-                        exchange.into(),
-                        routing_key.clone().into(),
-                        BasicPublishOptions::default(),
-                        command_str.as_bytes(),
-                        BasicProperties::default(),
-                    )
-                    .await
-                    .expect("basic_publish");
-
-                debug!("Sent on AMQP with routing key '{}'", &routing_key);
+                metrics::COMMAND_CHANNEL_GAUGE.set(receiver.len() as i64);
+
+                if let Err(e) = publisher.publish_sftp_download(&command).await {
+                    throttled_error!(
+                        ErrorCode::CommandPublish,
+                        &command.sftp_source,
+                        "Could not publish {command}: {e}"
+                    );
+                }
             }
             Err(e) => match e {
                 RecvTimeoutError::Timeout => (),
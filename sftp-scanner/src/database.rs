@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use rusqlite::Connection;
+
+/// SQLite connections of the scanners, shared by sources that use the same
+/// database file
+#[derive(Debug, Clone, Default)]
+pub struct Databases {
+    connections: Arc<Mutex<HashMap<PathBuf, Arc<Mutex<Connection>>>>>,
+}
+
+impl Databases {
+    /// Connection to the database at the path, which is opened on first use,
+    /// creating its directory when missing
+    pub fn connection(&self, path: &Path) -> Result<Arc<Mutex<Connection>>> {
+        let path = resolve(path)?;
+
+        let mut connections = self.connections.lock().unwrap();
+
+        if let Some(conn) = connections.get(&path) {
+            return Ok(conn.clone());
+        }
+
+        let conn = Connection::open(&path).map_err(|e| {
+            anyhow!(
+                "Error connecting to SQLite database '{}': {}",
+                path.to_string_lossy(),
+                e
+            )
+        })?;
+
+        info!("Connected to SQLite database '{}'", path.to_string_lossy());
+
+        let conn = Arc::new(Mutex::new(conn));
+
+        connections.insert(path, conn.clone());
+
+        Ok(conn)
+    }
+}
+
+/// Absolute path of a database file, after creating its directory
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("No file name in database path '{}'", path.to_string_lossy()))?;
+
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    fs::create_dir_all(directory).map_err(|e| {
+        anyhow!(
+            "Error creating database directory '{}': {}",
+            directory.to_string_lossy(),
+            e
+        )
+    })?;
+
+    let directory = fs::canonicalize(directory).map_err(|e| {
+        anyhow!(
+            "Error resolving database directory '{}': {}",
+            directory.to_string_lossy(),
+            e
+        )
+    })?;
+
+    Ok(directory.join(file_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_to_same_file_share_connection() {
+        let directory =
+            std::env::temp_dir().join(format!("cortex-scanner-databases-{}", std::process::id()));
+
+        let databases = Databases::default();
+
+        let red = databases.connection(&directory.join("sub/cortex.db"));
+        let blue = databases.connection(&directory.join("sub/../sub/./cortex.db"));
+        let green = databases.connection(&directory.join("other.db"));
+
+        let created = directory.join("sub").is_dir();
+
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(created);
+        assert!(Arc::ptr_eq(&red.unwrap(), &blue.unwrap()));
+        assert_eq!(databases.connections.lock().unwrap().len(), 2);
+        assert!(green.is_ok());
+    }
+}
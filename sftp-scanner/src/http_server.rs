@@ -1,3 +1,7 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
 use log::error;
 
 use actix_web::{
@@ -6,15 +10,42 @@ use actix_web::{
 
 use prometheus::{Encoder, TextEncoder};
 
-pub async fn start_http_server(addr: std::net::SocketAddr) -> std::io::Result<()> {
-    let server = HttpServer::new(|| {
+use crate::status::ScannerStatus;
+
+/// Interval at which the stop flag is checked
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Run the HTTP server until the stop flag is set
+pub async fn start_http_server(
+    addr: std::net::SocketAddr,
+    status: ScannerStatus,
+    stop: Arc<AtomicBool>,
+) -> std::io::Result<()> {
+    let status = web::Data::new(status);
+
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
+            .app_data(status.clone())
+            .service(web::resource("/metrics").to(metrics))
             .service(web::resource("/api/metrics").to(metrics))
+            .service(web::resource("/status").to(scanner_status))
     })
     .bind(addr)?
+    // Stopping on signals is up to the scanner
+    .disable_signals()
     .run();
 
+    let server_handle = server.handle();
+
+    tokio::spawn(async move {
+        while !stop.load(Ordering::Relaxed) {
+            tokio::time::sleep(STOP_POLL_INTERVAL).await;
+        }
+
+        server_handle.stop(true).await;
+    });
+
     server.await
 }
 
@@ -36,3 +67,9 @@ async fn metrics() -> impl Responder {
         .content_type(ContentType::plaintext())
         .body(String::from_utf8(buffer).unwrap())
 }
+
+async fn scanner_status(status: web::Data<ScannerStatus>) -> impl Responder {
+    let sources = status.read().unwrap().clone();
+
+    HttpResponse::Ok().json(sources)
+}
@@ -1,4 +1,5 @@
 use std::io::Write;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -12,17 +13,26 @@ use signal_hook_tokio::Signals;
 
 use clap::Parser;
 
-use cortex_core::wait_for;
-
 mod amqp_sender;
+mod database;
 mod http_server;
 mod metrics;
+mod notify;
+mod report;
+mod schedule;
 mod settings;
 mod sftp_scanner;
+mod status;
 
 use anyhow::Result;
+use cortex_core::log_throttle;
+use cortex_core::sftp_connection::{HostSessions, SessionLimits};
+use report::Report;
 use settings::Settings;
 
+/// Number of download commands buffered for the AMQP sender
+const COMMAND_CHANNEL_CAPACITY: usize = 4096;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
@@ -37,6 +47,16 @@ pub struct Args {
     /// Run in service mode
     #[arg(long)]
     service: bool,
+
+    /// Scan all sources without registering or sending download commands,
+    /// only reporting the files that would be downloaded
+    #[arg(long)]
+    dry_run: bool,
+
+    /// File to write the report of dry runs to as JSON lines, instead of
+    /// stdout
+    #[arg(long)]
+    report_file: Option<PathBuf>,
 }
 
 fn main() {
@@ -65,7 +85,29 @@ fn main() {
         .config
         .unwrap_or("/etc/cortex/sftp-scanner.yaml".to_string());
 
-    let settings = load_settings(&config_file);
+    let mut settings = load_settings(&config_file);
+
+    if args.dry_run {
+        settings
+            .sftp_sources
+            .iter_mut()
+            .for_each(|sftp_source| sftp_source.dry_run = true);
+    }
+
+    let report = match &args.report_file {
+        Some(path) => match Report::create(path) {
+            Ok(report) => report,
+            Err(e) => {
+                error!(
+                    "Error creating report file '{}': {}",
+                    path.to_string_lossy(),
+                    e
+                );
+                ::std::process::exit(1);
+            }
+        },
+        None => Report::stdout(),
+    };
 
     let runtime = tokio::runtime::Runtime::new().unwrap();
 
@@ -74,16 +116,46 @@ fn main() {
 
     // Setup the channel that connects to the RabbitMQ queue for SFTP download
     // commands.
-    let (cmd_sender, cmd_receiver) = bounded(4096);
+    let (cmd_sender, cmd_receiver) = bounded(COMMAND_CHANNEL_CAPACITY);
+
+    metrics::COMMAND_CHANNEL_CAPACITY_GAUGE.set(COMMAND_CHANNEL_CAPACITY as i64);
+
+    // Errors are counted before repeated log messages are suppressed
+    log_throttle::set_window(settings.error_log_window);
+
+    if let Err(e) = log_throttle::set_observer(Box::new(|code, source| {
+        metrics::ERRORS_COUNTER
+            .with_label_values(&[code, source])
+            .inc()
+    })) {
+        error!("{e}");
+    }
 
     let stop = Arc::new(AtomicBool::new(false));
 
     let stop_clone = stop.clone();
 
+    let scanner_status = status::ScannerStatus::default();
+
+    // Sources with the same database share its connection
+    let databases = database::Databases::default();
+
     stop_commands.push(Box::new(move || {
         stop_clone.swap(true, Ordering::Relaxed);
     }));
 
+    // Shared by the scanners of all SFTP sources
+    let session_limits = SessionLimits::new(settings.sftp_connection_limits.clone()).with_observer(
+        Arc::new(|sessions: &HostSessions| {
+            metrics::SFTP_SESSIONS_GAUGE
+                .with_label_values(&[&sessions.host])
+                .set(sessions.open as i64);
+            metrics::SFTP_SESSION_WAITERS_GAUGE
+                .with_label_values(&[&sessions.host])
+                .set(sessions.waiting as i64);
+        }),
+    );
+
     // Start every configured scanner in it's own thread and have them send commands
     // to the command channel.
     let scanner_threads: Vec<(String, thread::JoinHandle<Result<()>>)> = settings
@@ -93,38 +165,78 @@ fn main() {
         .map(|sftp_source| {
             let name = sftp_source.name.clone();
 
-            // For development we share the same SQLite database as the dispatcher dev stack.
-            // This matches the path used in dev-stack/tmp/cortex-dispatcher.yml.
-            let sqlite_path = "dev-stack/tmp/cortex.db".to_string();
+            let sqlite_path = sftp_source
+                .sqlite_path
+                .clone()
+                .unwrap_or_else(|| settings.sqlite.path.clone());
+
+            let notifier = sftp_source.notify.clone().map(|notify| match notify {
+                settings::Notify::RabbitMQ(notify) => {
+                    notify::ScanNotifier::new(notify, runtime.handle().clone())
+                }
+            });
 
             let join_handle = sftp_scanner::start_scanner(
                 stop.clone(),
                 cmd_sender.clone(),
+                databases.clone(),
                 sqlite_path,
                 sftp_source,
+                scanner_status.clone(),
+                report.clone(),
+                notifier,
+                settings.heartbeat_interval,
+                session_limits.clone(),
             );
 
             (name, join_handle)
         })
         .collect();
 
+    let routes = settings
+        .sftp_sources
+        .iter()
+        .map(|sftp_source| (sftp_source.name.clone(), sftp_source.command_route()))
+        .collect();
+
     runtime.block_on(async {
-        // Start the built-in web server that currently only serves metrics.
-        tokio::spawn(http_server::start_http_server(settings.http_server.address));
+        // Start the built-in web server that serves metrics and status
+        let http_server_handle = settings.http_server.map(|http_server| {
+            tokio::spawn(http_server::start_http_server(
+                http_server.address,
+                scanner_status,
+                stop.clone(),
+            ))
+        });
 
         tokio::spawn(amqp_sender::start_sender(
             stop,
             cmd_receiver,
             settings.command_queue.address,
+            routes,
         ));
 
         setup_signal_handler(stop_commands).await;
+
+        if let Some(join_handle) = http_server_handle {
+            match join_handle.await {
+                Ok(Ok(())) => info!("HTTP server stopped"),
+                Ok(Err(e)) => error!("Error running HTTP server: {e}"),
+                Err(e) => error!("Error waiting for HTTP server: {e}"),
+            }
+        }
     });
 
     for (source_name, scanner_thread) in scanner_threads {
         info!("Waiting for scanner thread '{}' to stop", &source_name);
 
-        wait_for(scanner_thread, "Scanner");
+        // A failing source does not stop the other sources, so its error is
+        // only reported here
+        match scanner_thread.join() {
+            Ok(Ok(())) => info!("Scanner thread '{source_name}' stopped"),
+            Ok(Err(e)) => error!("Scanner thread '{source_name}' failed: {e}"),
+            Err(e) => error!("Scanner thread '{source_name}' panicked: {e:?}"),
+        }
     }
 }
 
@@ -171,7 +283,7 @@ fn load_settings(config_file: &str) -> Settings {
 
     let into_result = settings.try_deserialize();
 
-    let settings: Settings = match into_result {
+    let mut settings: Settings = match into_result {
         Ok(s) => s,
         Err(e) => {
             error!("Error loading configuration: {}", e);
@@ -179,6 +291,21 @@ fn load_settings(config_file: &str) -> Settings {
         }
     };
 
+    if let Err(e) = settings.resolve_secret_files() {
+        error!("Error loading configuration: {}", e);
+        ::std::process::exit(1);
+    }
+
+    let problems = settings.validate();
+
+    if !problems.is_empty() {
+        for problem in problems {
+            error!("Invalid configuration: {}", problem);
+        }
+
+        ::std::process::exit(1);
+    }
+
     info!("Configuration loaded");
 
     settings
@@ -1,5 +1,10 @@
 use lazy_static::lazy_static;
-use prometheus::{register_int_counter_vec, IntCounterVec};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+const SEND_DURATION_BUCKETS: &[f64] = &[0.001, 0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
 
 lazy_static! {
     pub static ref DIR_SCAN_COUNTER: IntCounterVec = register_int_counter_vec!(
@@ -14,4 +19,69 @@ lazy_static! {
         &["source"]
     )
     .unwrap();
+    pub static ref SCAN_FILES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "scan_files_total",
+        "Total number of files encountered by scans, per decision",
+        &["source", "decision"]
+    )
+    .unwrap();
+    pub static ref SCAN_SKIPPED_FILES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "scan_skipped_files_total",
+        "Total number of matching files skipped by scans because of their size, per reason",
+        &["source", "reason"]
+    )
+    .unwrap();
+    pub static ref INVALID_FILENAME_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "invalid_filename_total",
+        "Total number of files and directories skipped by scans because their name is not valid UTF-8",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref SFTP_RECONNECTS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "sftp_reconnects_total",
+        "Total number of reconnects to the SFTP source",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref SEND_TIMEOUTS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "scan_send_timeouts_total",
+        "Total number of times a download command could not be sent because the command channel was full",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref SEND_DURATION_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "scan_send_duration_seconds",
+        "Time to send a download command on the command channel, including retries",
+        &["source"],
+        SEND_DURATION_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref COMMAND_CHANNEL_GAUGE: IntGauge = register_int_gauge!(
+        "scan_command_channel_messages",
+        "Number of download commands in the command channel to the AMQP sender"
+    )
+    .unwrap();
+    pub static ref COMMAND_CHANNEL_CAPACITY_GAUGE: IntGauge = register_int_gauge!(
+        "scan_command_channel_capacity",
+        "Capacity of the command channel to the AMQP sender"
+    )
+    .unwrap();
+    pub static ref SFTP_SESSIONS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "sftp_sessions",
+        "Number of SFTP sessions open to the host",
+        &["host"]
+    )
+    .unwrap();
+    pub static ref SFTP_SESSION_WAITERS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "sftp_session_waiters",
+        "Number of scanners waiting for an SFTP session to the host",
+        &["host"]
+    )
+    .unwrap();
+    pub static ref ERRORS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "errors_total",
+        "Total number of errors with a code of which repeated log messages are suppressed, including the suppressed ones, by source, target or SFTP server",
+        &["code", "source"]
+    )
+    .unwrap();
 }
@@ -0,0 +1,185 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use lapin::{options::BasicPublishOptions, BasicProperties};
+use log::{debug, warn};
+use serde::Serialize;
+use tokio::runtime::Handle;
+
+use cortex_core::error_code::ErrorCode;
+use cortex_core::throttled_error;
+
+use crate::settings::RabbitMQNotify;
+use crate::sftp_scanner::ScanResult;
+
+/// Maximum number of summaries kept while publishing fails, after which the
+/// oldest are dropped
+const MAX_PENDING_SUMMARIES: usize = 100;
+
+/// Maximum time for connecting and publishing a summary
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Summary of a completed scan
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanSummary {
+    pub source: String,
+    pub scan_start: DateTime<Utc>,
+    pub scan_end: DateTime<Utc>,
+    pub encountered_files: u64,
+    pub matching_files: u64,
+    pub dispatched_files: u64,
+    pub dispatched_paths: Vec<String>,
+    /// Not all dispatched paths are listed
+    pub truncated: bool,
+}
+
+impl ScanSummary {
+    pub fn new(
+        source: &str,
+        scan_start: DateTime<Utc>,
+        scan_end: DateTime<Utc>,
+        scan_result: &ScanResult,
+    ) -> ScanSummary {
+        ScanSummary {
+            source: source.to_string(),
+            scan_start,
+            scan_end,
+            encountered_files: scan_result.encountered_files,
+            matching_files: scan_result.matching_files,
+            dispatched_files: scan_result.dispatched_files,
+            dispatched_paths: scan_result.dispatched_paths.clone(),
+            truncated: scan_result.dispatched_files > scan_result.dispatched_paths.len() as u64,
+        }
+    }
+}
+
+/// Publishes scan summaries to RabbitMQ from a scanner thread
+///
+/// Summaries that fail to publish are kept and published again after the
+/// next scan, so that a failure does not fail the scan.
+pub struct ScanNotifier {
+    settings: RabbitMQNotify,
+    runtime: Handle,
+    connection: Option<(lapin::Connection, lapin::Channel)>,
+    pending: VecDeque<ScanSummary>,
+}
+
+impl ScanNotifier {
+    pub fn new(settings: RabbitMQNotify, runtime: Handle) -> ScanNotifier {
+        ScanNotifier {
+            settings,
+            runtime,
+            connection: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Publish the summary after any summaries left by earlier failures
+    pub fn notify(&mut self, summary: ScanSummary) {
+        if self.pending.len() == MAX_PENDING_SUMMARIES {
+            if let Some(dropped) = self.pending.pop_front() {
+                warn!(
+                    "Dropped scan summary of {} started at {}",
+                    dropped.source, dropped.scan_start
+                );
+            }
+        }
+
+        self.pending.push_back(summary);
+
+        while let Some(summary) = self.pending.front() {
+            let payload = serde_json::to_string(summary).unwrap();
+            let source = summary.source.clone();
+
+            let runtime = self.runtime.clone();
+
+            let result = runtime.block_on(async {
+                tokio::time::timeout(PUBLISH_TIMEOUT, self.publish(&payload))
+                    .await
+                    .unwrap_or_else(|_| Err("Timeout publishing scan summary".to_string()))
+            });
+
+            match result {
+                Ok(()) => {
+                    debug!(
+                        "Published scan summary with routing key '{}'",
+                        &self.settings.routing_key
+                    );
+
+                    self.pending.pop_front();
+                }
+                Err(e) => {
+                    throttled_error!(
+                        ErrorCode::ScanNotify,
+                        &source,
+                        "{e}, {} summaries are kept for the next scan",
+                        self.pending.len()
+                    );
+
+                    // Reconnect on the next attempt
+                    self.connection = None;
+
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn publish(&mut self, payload: &str) -> Result<(), String> {
+        if self.connection.is_none() {
+            let connection = lapin::Connection::connect(
+                self.settings.address.expose(),
+                lapin::ConnectionProperties::default(),
+            )
+            .await
+            .map_err(|e| format!("Error connecting to AMQP server: {e}"))?;
+
+            let channel = connection
+                .create_channel()
+                .await
+                .map_err(|e| format!("Error creating AMQP channel: {e}"))?;
+
+            self.connection = Some((connection, channel));
+        }
+
+        let (_, channel) = self.connection.as_ref().unwrap();
+
+        channel
+            .basic_publish(
+                self.settings.exchange.as_str().into(),
+                self.settings.routing_key.as_str().into(),
+                BasicPublishOptions::default(),
+                payload.as_bytes(),
+                BasicProperties::default().with_content_type("application/json".into()),
+            )
+            .await
+            .map_err(|e| format!("Error publishing scan summary: {e}"))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_is_truncated_when_paths_are_dropped() {
+        let mut scan_result = ScanResult::new();
+        scan_result.dispatched("upload/red/a.xml", 1);
+        scan_result.dispatched("upload/red/b.xml", 1);
+
+        let now = Utc::now();
+
+        let summary =
+            serde_json::to_value(ScanSummary::new("red", now, now, &scan_result)).unwrap();
+
+        assert_eq!(summary["dispatched_files"], 2);
+        assert_eq!(
+            summary["dispatched_paths"],
+            serde_json::json!(["upload/red/a.xml"])
+        );
+        assert_eq!(summary["truncated"], true);
+    }
+}
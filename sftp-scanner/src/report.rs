@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::Serialize;
+
+use crate::metrics;
+
+/// What a scan does with a file it encounters
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    /// Matches the regex of the source and is downloaded
+    Match,
+    /// Matches the regex of the source, but was downloaded before
+    SkipDuplicate,
+    /// Matches the regex of the source, but was modified before the
+    /// previous incremental scan
+    SkipOld,
+    /// Does not match the regex of the source
+    Excluded,
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Match => "match",
+            Decision::SkipDuplicate => "skip_duplicate",
+            Decision::SkipOld => "skip_old",
+            Decision::Excluded => "excluded",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReportLine<'a> {
+    source: &'a str,
+    path: &'a str,
+    size: Option<u64>,
+    modified: Option<DateTime<Utc>>,
+    decision: Decision,
+}
+
+/// Report of the decisions of dry runs, written as JSON lines
+#[derive(Clone)]
+pub struct Report {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Report {
+    pub fn stdout() -> Report {
+        Report {
+            writer: Arc::new(Mutex::new(Box::new(io::stdout()))),
+        }
+    }
+
+    pub fn create(path: &Path) -> io::Result<Report> {
+        let file = File::create(path)?;
+
+        Ok(Report {
+            writer: Arc::new(Mutex::new(Box::new(LineWriter::new(file)))),
+        })
+    }
+
+    pub fn write(&self, source: &str, path: &str, stat: &ssh2::FileStat, decision: Decision) {
+        let line = ReportLine {
+            source,
+            path,
+            size: stat.size,
+            modified: stat
+                .mtime
+                .and_then(|mtime| DateTime::from_timestamp(mtime as i64, 0)),
+            decision,
+        };
+
+        let result = serde_json::to_string(&line)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(self.writer.lock().unwrap(), "{line}"));
+
+        if let Err(e) = result {
+            error!("Error writing dry run report: {e}");
+        }
+    }
+}
+
+/// Count the decision on a file, also in dry runs
+pub fn count_decision(source: &str, decision: Decision) {
+    metrics::SCAN_FILES_COUNTER
+        .with_label_values(&[source, decision.as_str()])
+        .inc();
+}
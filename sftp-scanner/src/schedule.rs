@@ -0,0 +1,106 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use crate::settings::SftpSource;
+
+/// When the next scan of a source is due, at a fixed interval or on a cron
+/// schedule
+pub enum NextScan {
+    Interval {
+        interval: Duration,
+        next: Instant,
+    },
+    Schedule {
+        schedule: Box<cron::Schedule>,
+        next: Option<DateTime<Utc>>,
+    },
+}
+
+impl NextScan {
+    /// The first scan is due right away with an interval, and at the first
+    /// occurrence with a schedule
+    pub fn new(sftp_source: &SftpSource) -> Result<NextScan, String> {
+        match (&sftp_source.scan_schedule, sftp_source.scan_interval) {
+            (Some(expression), _) => {
+                let schedule = cron::Schedule::from_str(expression)
+                    .map_err(|e| format!("Invalid scan_schedule '{expression}': {e}"))?;
+
+                let next = schedule.after(&Utc::now()).next();
+
+                Ok(NextScan::Schedule {
+                    schedule: Box::new(schedule),
+                    next,
+                })
+            }
+            (None, Some(interval)) => Ok(NextScan::Interval {
+                interval: Duration::from_millis(interval),
+                next: Instant::now(),
+            }),
+            (None, None) => Err("No scan_interval or scan_schedule".to_string()),
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        match self {
+            NextScan::Interval { next, .. } => Instant::now() >= *next,
+            NextScan::Schedule { next, .. } => next.is_some_and(|next| Utc::now() >= next),
+        }
+    }
+
+    /// Move to the first scan after the current time, so that scans that were
+    /// missed while a scan overran or the connection stalled are skipped
+    pub fn advance(&mut self) {
+        match self {
+            NextScan::Interval { interval, next } => {
+                while *next <= Instant::now() {
+                    *next += *interval;
+                }
+            }
+            NextScan::Schedule { schedule, next } => {
+                *next = schedule.after(&Utc::now()).next();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::settings::Settings;
+
+    #[test]
+    fn missed_scans_are_skipped() {
+        let mut sftp_source = Settings::default().sftp_sources[0].clone();
+        sftp_source.scan_interval = Some(1000);
+
+        let mut next_scan = NextScan::new(&sftp_source).unwrap();
+
+        assert!(next_scan.is_due());
+
+        // A scan that overran several intervals
+        if let NextScan::Interval { next, .. } = &mut next_scan {
+            *next -= Duration::from_millis(3500);
+        }
+
+        next_scan.advance();
+
+        assert!(!next_scan.is_due());
+
+        sftp_source.scan_schedule = Some("0 15 6,18 * * *".to_string());
+
+        let next_scan = NextScan::new(&sftp_source).unwrap();
+
+        match next_scan {
+            NextScan::Schedule {
+                next: Some(next), ..
+            } => {
+                assert!(next > Utc::now());
+                assert_eq!(next.format("%M:%S").to_string(), "15:00");
+            }
+            _ => panic!("expected a scheduled scan"),
+        }
+    }
+}
@@ -1,20 +1,29 @@
 use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
 
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
+pub use cortex_core::client::CommandRoute;
+use cortex_core::log_throttle;
+use cortex_core::secret::Secret;
+pub use cortex_core::settings::SftpConnectionSettings;
+use cortex_core::sftp_connection::SftpConfig;
+use cortex_core::{check_local_subpath, sftp_source_routing_key, DEFAULT_COMMAND_EXCHANGE};
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CommandQueue {
-    pub address: String,
+    pub address: Secret,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SftpSource {
     pub name: String,
-    pub address: String,
-    pub username: String,
-    pub password: Option<String>,
-    pub key_file: Option<PathBuf>,
+    #[serde(flatten)]
+    pub connection: SftpConnectionSettings,
     #[serde(with = "serde_regex")]
     pub regex: Regex,
     pub directory: String,
@@ -22,15 +31,158 @@ pub struct SftpSource {
     pub deduplicate: bool,
     #[serde(default = "default_false")]
     pub remove: bool,
-    pub scan_interval: u64,
+    /// Interval in milliseconds between scans
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scan_interval: Option<u64>,
+    /// Cron expression with seconds of the times to scan at, instead of at an
+    /// interval
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scan_schedule: Option<String>,
     #[serde(default = "default_false")]
     pub recurse: bool,
+    /// SQLite database of this source, instead of the global one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sqlite_path: Option<PathBuf>,
+    /// Only report the files that would be downloaded, without registering
+    /// or sending download commands
+    #[serde(default = "default_false")]
+    pub dry_run: bool,
+    /// Files smaller than this are skipped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_size_bytes: Option<u64>,
+    /// Files larger than this are skipped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size_bytes: Option<u64>,
+    /// Skip files of which the server does not report the size, instead of
+    /// passing them
+    #[serde(default = "default_false")]
+    pub reject_unknown_size: bool,
+    /// Number of connection attempts before a scan is given up, unlimited
+    /// when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    /// Milliseconds to wait before sending a download command again when the
+    /// command channel stays full
+    #[serde(default = "default_send_retry_delay")]
+    pub send_retry_delay: u64,
+    /// Number of attempts to send a download command before the rest of the
+    /// scan is not dispatched, unlimited when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_max_attempts: Option<u32>,
+    /// Exchange to publish download commands to
+    #[serde(default = "default_command_exchange")]
+    pub exchange: String,
+    /// Routing key of download commands, defaults to source.<name>
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_key: Option<String>,
+    /// Publishes a summary after every completed scan
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify: Option<Notify>,
+    /// Directory under the storage directory of the source in which the
+    /// dispatcher places the files, with `{dir}` for the remote directory
+    /// relative to the scanned directory, `{dir1}`, `{dir2}`, ... for its
+    /// components and strftime placeholders for the time of the scan
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_subpath_template: Option<String>,
+    /// Skip the files modified before the previous completed scan, for
+    /// directories to which files are only added
+    #[serde(default = "default_false")]
+    pub incremental: bool,
+    /// Seconds before the newest modification time of the previous scan
+    /// from which incremental scans check files again
+    #[serde(default = "default_incremental_margin")]
+    pub incremental_margin: u64,
+    /// Every how many scans an incremental source scans all files
+    #[serde(default = "default_full_scan_every")]
+    pub full_scan_every: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RabbitMQNotify {
+    pub address: Secret,
+    pub exchange: String,
+    pub routing_key: String,
+    /// Maximum number of dispatched paths listed in a summary
+    #[serde(default = "default_max_paths")]
+    pub max_paths: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Notify {
+    #[serde(rename = "rabbitmq")]
+    RabbitMQ(RabbitMQNotify),
+}
+
+fn default_max_paths() -> usize {
+    1000
+}
+
+impl SftpSource {
+    pub fn sftp_config(&self) -> SftpConfig {
+        SftpConfig {
+            max_attempts: self.max_attempts,
+            ..self.connection.sftp_config()
+        }
+    }
+
+    /// Maximum number of dispatched paths to keep for the scan summary
+    pub fn max_notify_paths(&self) -> usize {
+        match &self.notify {
+            Some(Notify::RabbitMQ(notify)) => notify.max_paths,
+            None => 0,
+        }
+    }
+
+    pub fn command_route(&self) -> CommandRoute {
+        CommandRoute {
+            exchange: self.exchange.clone(),
+            routing_key: self
+                .routing_key
+                .clone()
+                .unwrap_or_else(|| sftp_source_routing_key(&self.name)),
+        }
+    }
 }
 
 fn default_false() -> bool {
     false
 }
 
+fn default_incremental_margin() -> u64 {
+    3600
+}
+
+fn default_full_scan_every() -> u32 {
+    24
+}
+
+fn default_send_retry_delay() -> u64 {
+    100
+}
+
+fn default_command_exchange() -> String {
+    DEFAULT_COMMAND_EXCHANGE.to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Sqlite {
+    pub path: PathBuf,
+}
+
+fn default_error_log_window() -> u64 {
+    log_throttle::DEFAULT_WINDOW
+}
+
+fn default_heartbeat_interval() -> u64 {
+    30000
+}
+
+fn default_sqlite() -> Sqlite {
+    Sqlite {
+        path: PathBuf::from("/var/lib/cortex/cortex.db"),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct HttpServer {
     pub address: std::net::SocketAddr,
@@ -40,46 +192,231 @@ pub struct HttpServer {
 pub struct Settings {
     pub command_queue: CommandQueue,
     pub sftp_sources: Vec<SftpSource>,
-    pub http_server: HttpServer,
+    #[serde(default = "default_sqlite")]
+    pub sqlite: Sqlite,
+    /// Serves metrics and the status of the scanners, not started when not
+    /// set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_server: Option<HttpServer>,
+    /// Milliseconds between the heartbeats of the sources in the database,
+    /// 0 disables them
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    /// Maximum number of SFTP sessions open at the same time per remote host,
+    /// over all SFTP sources
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sftp_connection_limits: HashMap<String, usize>,
+    /// Milliseconds during which repeated errors of the same kind for the
+    /// same source are not logged again, 0 logs every error
+    #[serde(default = "default_error_log_window")]
+    pub error_log_window: u64,
+}
+
+impl Settings {
+    /// Populate fields that are configured through a `_file` variant
+    pub fn resolve_secret_files(&mut self) -> Result<(), String> {
+        for (index, sftp_source) in self.sftp_sources.iter_mut().enumerate() {
+            sftp_source
+                .connection
+                .resolve_secret_files(&format!("sftp_sources[{index}]"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Problems in the configuration that prevent the scanner from running
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for sftp_source in &self.sftp_sources {
+            let name = &sftp_source.name;
+
+            match (sftp_source.scan_interval, &sftp_source.scan_schedule) {
+                (Some(_), Some(_)) => problems.push(format!(
+                    "SFTP source '{name}' has both a scan_interval and a scan_schedule"
+                )),
+                (None, None) => problems.push(format!(
+                    "SFTP source '{name}' has no scan_interval or scan_schedule"
+                )),
+                (Some(0), None) => {
+                    problems.push(format!("SFTP source '{name}' has a scan_interval of 0"))
+                }
+                (None, Some(expression)) => {
+                    if let Err(e) = cron::Schedule::from_str(expression) {
+                        problems.push(format!(
+                            "SFTP source '{name}' has an invalid scan_schedule '{expression}': {e}"
+                        ))
+                    }
+                }
+                (Some(_), None) => {}
+            }
+
+            if sftp_source.incremental && sftp_source.full_scan_every == 0 {
+                problems.push(format!("SFTP source '{name}' has a full_scan_every of 0"))
+            }
+
+            if sftp_source.send_max_attempts == Some(0) {
+                problems.push(format!("SFTP source '{name}' has a send_max_attempts of 0"))
+            }
+
+            if let Some(template) = &sftp_source.local_subpath_template {
+                let formatted = fmt::write(
+                    &mut String::new(),
+                    format_args!("{}", Utc::now().format(template)),
+                );
+
+                if formatted.is_err() || check_local_subpath(template).is_err() {
+                    problems.push(format!(
+                        "SFTP source '{name}' has an invalid local_subpath_template '{template}'"
+                    ))
+                }
+            }
+        }
+
+        for (host, limit) in &self.sftp_connection_limits {
+            if *limit == 0 {
+                problems.push(format!("sftp_connection_limits of '{host}' is 0"));
+            }
+        }
+
+        problems
+    }
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Settings {
             command_queue: CommandQueue {
-                address: "127.0.0.1:5672".parse().unwrap(),
+                address: Secret::from("127.0.0.1:5672"),
             },
             sftp_sources: vec![
                 SftpSource {
                     name: "red".to_string(),
-                    address: "127.0.0.1:22".parse().unwrap(),
-                    username: "cortex".to_string(),
-                    password: Some("password".to_string()),
-                    key_file: None,
+                    connection: SftpConnectionSettings::with_password(
+                        "127.0.0.1:22",
+                        "cortex",
+                        "password",
+                    ),
                     regex: Regex::new("^.*\\.xml$").unwrap(),
                     directory: "upload/red".to_string(),
                     deduplicate: false,
                     remove: true,
-                    scan_interval: 3000,
+                    scan_interval: Some(3000),
+                    scan_schedule: None,
                     recurse: false,
+                    sqlite_path: None,
+                    dry_run: false,
+                    min_size_bytes: None,
+                    max_size_bytes: None,
+                    reject_unknown_size: false,
+                    max_attempts: None,
+                    send_retry_delay: default_send_retry_delay(),
+                    send_max_attempts: None,
+                    exchange: default_command_exchange(),
+                    routing_key: None,
+                    notify: None,
+                    local_subpath_template: None,
+                    incremental: false,
+                    incremental_margin: default_incremental_margin(),
+                    full_scan_every: default_full_scan_every(),
                 },
                 SftpSource {
                     name: "blue".to_string(),
-                    address: "127.0.0.1:22".parse().unwrap(),
-                    username: "cortex".to_string(),
-                    password: Some("password".to_string()),
-                    key_file: None,
+                    connection: SftpConnectionSettings::with_password(
+                        "127.0.0.1:22",
+                        "cortex",
+                        "password",
+                    ),
                     regex: Regex::new("^.*\\.xml$").unwrap(),
                     directory: "upload/blue".to_string(),
                     deduplicate: false,
                     remove: true,
-                    scan_interval: 2000,
+                    scan_interval: Some(2000),
+                    scan_schedule: None,
                     recurse: true,
+                    sqlite_path: None,
+                    dry_run: false,
+                    min_size_bytes: None,
+                    max_size_bytes: None,
+                    reject_unknown_size: false,
+                    max_attempts: None,
+                    send_retry_delay: default_send_retry_delay(),
+                    send_max_attempts: None,
+                    exchange: default_command_exchange(),
+                    routing_key: None,
+                    notify: None,
+                    local_subpath_template: None,
+                    incremental: false,
+                    incremental_margin: default_incremental_margin(),
+                    full_scan_every: default_full_scan_every(),
                 },
             ],
-            http_server: HttpServer {
+            sqlite: default_sqlite(),
+            http_server: Some(HttpServer {
                 address: "0.0.0.0:56008".parse().unwrap(),
-            },
+            }),
+            heartbeat_interval: default_heartbeat_interval(),
+            sftp_connection_limits: HashMap::new(),
+            error_log_window: default_error_log_window(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+command_queue:
+  address: amqp://127.0.0.1:5672/%2f
+sftp_sources:
+  - name: red
+    address: sftp.example.com:2222
+    username: cortex
+    key_file: /home/cortex/.ssh/id_ed25519
+    keepalive_interval_seconds: 0
+    regex: "^.*\\.csv$"
+    directory: upload/red
+    scan_interval: 60000
+    max_attempts: 3
+"#;
+
+    fn deserialize(content: &str, format: config::FileFormat) -> Settings {
+        config::Config::builder()
+            .add_source(config::File::from_str(content, format))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn sftp_connection_settings_round_trip() {
+        let settings = deserialize(CONFIG, config::FileFormat::Yaml);
+
+        let json =
+            cortex_core::secret::serialize_secrets(|| serde_json::to_string(&settings).unwrap());
+
+        let reloaded = deserialize(&json, config::FileFormat::Json);
+
+        for settings in [&settings, &reloaded] {
+            let sftp_source = &settings.sftp_sources[0];
+            let sftp_config = sftp_source.sftp_config();
+
+            assert_eq!(sftp_config.address, "sftp.example.com:2222");
+            assert_eq!(sftp_config.username, "cortex");
+            assert!(sftp_config.password.is_none());
+            assert_eq!(
+                sftp_config.key_file,
+                Some(PathBuf::from("/home/cortex/.ssh/id_ed25519"))
+            );
+            assert_eq!(sftp_config.keepalive_interval_seconds, 0);
+            assert_eq!(
+                sftp_config.connect_timeout_seconds,
+                cortex_core::sftp_connection::default_connect_timeout_seconds()
+            );
+            assert_eq!(sftp_config.max_attempts, Some(3));
+            assert_eq!(sftp_source.directory, "upload/red");
         }
     }
 }
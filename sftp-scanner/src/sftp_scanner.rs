@@ -1,12 +1,14 @@
 use std::convert::TryFrom;
 use std::fmt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::{thread, time};
 
 use crossbeam_channel::{SendTimeoutError, Sender};
-use log::{debug, error, info};
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use regex::{Captures, Regex};
 
 use retry::{delay::Fixed, retry, OperationResult};
 
@@ -15,14 +17,32 @@ use chrono::prelude::*;
 use anyhow::{anyhow, Result};
 
 use cortex_core::error::DispatcherError;
-use cortex_core::sftp_connection::SftpConfig;
-use cortex_core::SftpDownload;
+use cortex_core::error_code::ErrorCode;
+use cortex_core::heartbeat::Heartbeat;
+use cortex_core::remote_fs::{RemoteError, RemoteFs, SftpFs};
+use cortex_core::sftp_connection::SessionLimits;
+use cortex_core::{check_local_subpath, throttled_error, SftpDownload, COMMAND_VERSION};
 
+use crate::database::Databases;
 use crate::metrics;
+use crate::notify::{ScanNotifier, ScanSummary};
+use crate::report::{count_decision, Decision, Report};
+use crate::schedule::NextScan;
 use crate::settings::SftpSource;
-use rusqlite::{params, Connection};
+use crate::status::{self, ConnectionState, ScannerStatus};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use serde_json::json;
 use std::sync::Mutex;
 
+/// Time to wait for room in the channel before checking the stop flag again
+const SEND_TIMEOUT: time::Duration = time::Duration::from_millis(250);
+
+lazy_static! {
+    /// Placeholders of the remote directory in local subpath templates
+    static ref DIRECTORY_PLACEHOLDER: Regex = Regex::new(r"\{dir(\d*)\}").unwrap();
+}
+
 /// Starts a new thread with an SFTP scanner for the specified source.
 ///
 /// For encountered files to be downloaded, a message is placed on a channel
@@ -30,74 +50,118 @@ use std::sync::Mutex;
 ///
 /// A thread is used instead of an async Tokio future because the library used
 /// for the SFTP connection is not thread safe.
+#[allow(clippy::too_many_arguments)]
 pub fn start_scanner(
     stop: Arc<AtomicBool>,
     mut sender: Sender<SftpDownload>,
-    sqlite_path: String,
+    databases: Databases,
+    sqlite_path: PathBuf,
     sftp_source: SftpSource,
+    scanner_status: ScannerStatus,
+    report: Report,
+    mut notifier: Option<ScanNotifier>,
+    heartbeat_interval: u64,
+    session_limits: SessionLimits,
 ) -> thread::JoinHandle<Result<()>> {
     thread::spawn(move || {
         proctitle::set_title(format!("sftp-scanner {}", &sftp_source.name));
 
-        let db_path = if sqlite_path.is_empty() {
-            "/var/lib/cortex/cortex.db"
-        } else {
-            &sqlite_path
-        };
-
-        let conn_result = Connection::open(db_path);
-
-        let conn = match conn_result {
-            Ok(c) => {
-                info!("Connected to SQLite database");
-                c
-            }
+        let conn = match databases.connection(&sqlite_path) {
+            Ok(conn) => conn,
             Err(e) => {
-                error!("Error connecting to SQLite database: {}", e);
-                ::std::process::exit(2);
+                status::update(&scanner_status, &sftp_source.name, |status| {
+                    status.last_error = Some(e.to_string())
+                });
+
+                return Err(e);
             }
         };
 
-        let conn = Arc::new(Mutex::new(conn));
+        let mut sftp_config = sftp_source.sftp_config();
+        sftp_config.session_limits = session_limits;
 
-        let sftp_config = SftpConfig {
-            address: sftp_source.address.clone(),
-            username: sftp_source.username.clone(),
-            password: sftp_source.password.clone(),
-            key_file: sftp_source.key_file.clone(),
-            compress: false,
+        let set_status = |f: &dyn Fn(&mut status::SourceStatus)| {
+            status::update(&scanner_status, &sftp_source.name, f)
         };
 
-        let mut session = sftp_config
+        // Held for the lifetime of the scanner, so that reconnects do not
+        // wait for a session of their own
+        let Some(_session_permit) = sftp_config.session_permit(&stop) else {
+            return Err(anyhow!("Stopped while waiting for an SFTP session"));
+        };
+
+        let connect_result = sftp_config
             .connect_loop(stop.clone())
-            .map_err(|e| anyhow!("SFTP connect failed: {}", e))?;
+            .and_then(SftpFs::new)
+            .map_err(|e| anyhow!("SFTP connect failed: {}", e));
 
-        let mut sftp = session
-            .sftp()
-            .map_err(|e| anyhow!("SFTP connect failed: {}", e))?;
+        let mut sftp = match connect_result {
+            Ok(connection) => connection,
+            Err(e) => {
+                set_status(&|status| {
+                    status.connection = ConnectionState::Disconnected;
+                    status.last_error = Some(e.to_string());
+                });
+
+                return Err(e);
+            }
+        };
 
-        let scan_interval = time::Duration::from_millis(sftp_source.scan_interval);
-        let mut next_scan = time::Instant::now();
+        let compression = sftp.compressed();
+        set_status(&|status| {
+            status.connection = ConnectionState::Connected;
+            status.compression = compression;
+        });
+
+        let mut next_scan = NextScan::new(&sftp_source).map_err(|e| anyhow!(e))?;
+
+        let mut heartbeat = (heartbeat_interval > 0).then(|| {
+            Heartbeat::new(
+                &format!("sftp_scanner:{}", &sftp_source.name),
+                time::Duration::from_millis(heartbeat_interval),
+            )
+        });
+
+        let mut last_scan_end: Option<DateTime<Utc>> = None;
 
         while !stop.load(Ordering::Relaxed) {
-            if time::Instant::now() > next_scan {
-                // Increase next_scan until it is past now, because it can
-                // happen that the process has stalled on SFTP reconnect,
-                // causing a number of scheduled scan misses.
-                while next_scan < time::Instant::now() {
-                    next_scan += scan_interval;
-                }
+            if let Some(heartbeat) = heartbeat.as_mut() {
+                // A scan that panicked while holding the connection does not
+                // stop the heartbeat
+                let conn = conn.lock().unwrap_or_else(|e| e.into_inner());
 
+                heartbeat.beat(&conn, &json!({ "last_scan_end": last_scan_end }));
+            }
+
+            if next_scan.is_due() {
                 let scan_start = time::Instant::now();
                 info!("Started scanning {}", &sftp_source.name);
 
+                let scan_start_time = Utc::now();
+
+                set_status(&|status| status.last_scan_start = Some(scan_start_time));
+
                 let scan_result = retry(Fixed::from_millis(1000), || {
-                    match scan_source(&stop, &sftp_source, &sftp, &conn, &mut sender) {
+                    // Do not keep reconnecting after a stop
+                    if stop.load(Ordering::Relaxed) {
+                        return OperationResult::Err(DispatcherError::ConnectionInterrupted(
+                            "scanner stopped".to_string(),
+                        ));
+                    }
+
+                    match scan_source(&stop, &sftp_source, &sftp, &conn, &mut sender, &report) {
                         Ok(v) => OperationResult::Ok(v),
                         Err(e) => match e {
                             DispatcherError::DisconnectedError(_) => {
                                 info!("Sftp connection disconnected, reconnecting");
-                                session = match sftp_config.connect_loop(stop.clone()) {
+                                set_status(&|status| {
+                                    status.connection = ConnectionState::Disconnected;
+                                    status.last_error = Some(e.to_string());
+                                });
+                                metrics::SFTP_RECONNECTS_COUNTER
+                                    .with_label_values(&[&sftp_source.name])
+                                    .inc();
+                                let session = match sftp_config.connect_loop(stop.clone()) {
                                     Ok(s) => s,
                                     Err(e) => {
                                         return OperationResult::Err(
@@ -106,7 +170,7 @@ pub fn start_scanner(
                                     }
                                 };
 
-                                sftp = match session.sftp() {
+                                sftp = match SftpFs::new(session) {
                                     Ok(s) => s,
                                     Err(e) => {
                                         return OperationResult::Err(
@@ -119,6 +183,11 @@ pub fn start_scanner(
                                 };
 
                                 info!("Sftp connection reconnected");
+                                let compression = sftp.compressed();
+                                set_status(&|status| {
+                                    status.connection = ConnectionState::Connected;
+                                    status.compression = compression;
+                                });
                                 OperationResult::Retry(e)
                             }
                             _ => OperationResult::Err(e),
@@ -133,7 +202,8 @@ pub fn start_scanner(
                         let scan_duration = scan_end.duration_since(scan_start);
 
                         info!(
-                            "Finished scanning {} in {} ms - {}",
+                            "{}Finished scanning {} in {} ms - {}",
+                            if sftp_source.dry_run { "DRY RUN: " } else { "" },
                             &sftp_source.name,
                             scan_duration.as_millis(),
                             &sr
@@ -145,12 +215,48 @@ pub fn start_scanner(
                         metrics::DIR_SCAN_DURATION
                             .with_label_values(&[&sftp_source.name])
                             .inc_by(scan_duration.as_millis() as u64);
+
+                        let scan_end_time = Utc::now();
+
+                        last_scan_end = Some(scan_end_time);
+
+                        set_status(&|status| {
+                            status.last_scan_end = Some(scan_end_time);
+                            status.last_scan_result = Some(sr.clone());
+                        });
+
+                        // Dry runs dispatch nothing to announce
+                        if let Some(notifier) = notifier.as_mut().filter(|_| !sftp_source.dry_run) {
+                            notifier.notify(ScanSummary::new(
+                                &sftp_source.name,
+                                scan_start_time,
+                                scan_end_time,
+                                &sr,
+                            ));
+                        }
+                    }
+                    Err(_) if stop.load(Ordering::Relaxed) => {
+                        info!("Stopped scanning {}", &sftp_source.name);
                     }
                     Err(e) => {
-                        error!("Error scanning {}: {}", &sftp_source.name, e);
+                        throttled_error!(
+                            ErrorCode::Scan,
+                            &sftp_source.name,
+                            "Error scanning {}: {}",
+                            &sftp_source.name,
+                            e
+                        );
+
+                        set_status(&|status| status.last_error = Some(e.error.to_string()));
                     }
                 }
+
+                // Advance past the time at which the scan ended, because the
+                // scan can overrun or the process can stall on SFTP reconnect,
+                // causing a number of scheduled scan misses.
+                next_scan.advance();
             } else {
+                sftp.keepalive();
                 thread::sleep(time::Duration::from_millis(200));
             }
         }
@@ -159,28 +265,85 @@ pub fn start_scanner(
     })
 }
 
-struct ScanResult {
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanResult {
     /// Number of files encountered during the scan
     pub encountered_files: u64,
     /// Number of files that matched the criteria of the source
     pub matching_files: u64,
     /// Number of files dispatched on the channel
     pub dispatched_files: u64,
+    /// Number of matching files skipped for being smaller than the minimum
+    pub too_small_files: u64,
+    /// Number of matching files skipped for being larger than the maximum
+    pub too_large_files: u64,
+    /// Number of matching files skipped for having an unknown size
+    pub unknown_size_files: u64,
+    /// Paths of the dispatched files, up to the maximum of the scan summary
+    #[serde(skip)]
+    pub dispatched_paths: Vec<String>,
+    /// Whether the scan stopped dispatching because the command channel
+    /// stayed full
+    pub dispatch_stopped: bool,
+    /// Whether only the files modified since the previous scan were checked
+    pub incremental: bool,
+    /// Number of matching files skipped by an incremental scan for being
+    /// older than the previous scan
+    pub fast_skipped_files: u64,
+    /// Newest modification time of the matching files, in seconds since the
+    /// epoch
+    #[serde(skip)]
+    pub max_mtime: Option<u64>,
 }
 
 impl ScanResult {
-    fn new() -> ScanResult {
+    pub fn new() -> ScanResult {
         ScanResult {
             encountered_files: 0,
             matching_files: 0,
             dispatched_files: 0,
+            too_small_files: 0,
+            too_large_files: 0,
+            unknown_size_files: 0,
+            dispatched_paths: Vec::new(),
+            dispatch_stopped: false,
+            incremental: false,
+            fast_skipped_files: 0,
+            max_mtime: None,
         }
     }
 
-    fn add(&mut self, other: &ScanResult) {
+    fn add(&mut self, other: ScanResult, max_paths: usize) {
         self.encountered_files += other.encountered_files;
         self.matching_files += other.encountered_files;
         self.dispatched_files += other.dispatched_files;
+        self.too_small_files += other.too_small_files;
+        self.too_large_files += other.too_large_files;
+        self.unknown_size_files += other.unknown_size_files;
+        self.dispatch_stopped |= other.dispatch_stopped;
+        self.fast_skipped_files += other.fast_skipped_files;
+        self.max_mtime = self.max_mtime.max(other.max_mtime);
+
+        let room = max_paths.saturating_sub(self.dispatched_paths.len());
+        self.dispatched_paths
+            .extend(other.dispatched_paths.into_iter().take(room));
+    }
+
+    /// Count a dispatched file, keeping its path while there is room
+    pub fn dispatched(&mut self, path: &str, max_paths: usize) {
+        self.dispatched_files += 1;
+
+        if self.dispatched_paths.len() < max_paths {
+            self.dispatched_paths.push(path.to_string());
+        }
+    }
+
+    fn count_skipped(&mut self, size_skip: SizeSkip) {
+        match size_skip {
+            SizeSkip::TooSmall => self.too_small_files += 1,
+            SizeSkip::TooLarge => self.too_large_files += 1,
+            SizeSkip::UnknownSize => self.unknown_size_files += 1,
+        }
     }
 }
 
@@ -188,93 +351,235 @@ impl fmt::Display for ScanResult {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "encountered: {}, matching: {}, dispatched: {}",
-            self.encountered_files, self.matching_files, self.dispatched_files
+            "mode: {}, encountered: {}, matching: {}, dispatched: {}, too small: {}, too large: {}, unknown size: {}, fast skipped: {}",
+            if self.incremental { "incremental" } else { "full" },
+            self.encountered_files,
+            self.matching_files,
+            self.dispatched_files,
+            self.too_small_files,
+            self.too_large_files,
+            self.unknown_size_files,
+            self.fast_skipped_files
         )
     }
 }
 
-fn scan_source(
+fn scan_source<R: RemoteFs>(
     stop: &Arc<AtomicBool>,
     sftp_source: &SftpSource,
-    sftp: &ssh2::Sftp,
+    sftp: &R,
     conn: &Arc<Mutex<Connection>>,
     sender: &mut Sender<SftpDownload>,
+    report: &Report,
 ) -> Result<ScanResult, DispatcherError> {
-    scan_directory(
+    let cursor = match sftp_source.incremental {
+        true => load_scan_cursor(conn, &sftp_source.name)?,
+        false => None,
+    };
+
+    // Every full_scan_every-th scan checks all files, to catch files that
+    // were added with an older modification time
+    let modified_after = cursor
+        .as_ref()
+        .filter(|cursor| cursor.incremental_scans + 1 < sftp_source.full_scan_every)
+        .map(|cursor| {
+            cursor
+                .max_mtime
+                .saturating_sub(sftp_source.incremental_margin)
+        });
+
+    // Subdirectories are scanned from a stack after the directory they are
+    // in, instead of recursively, so that only one directory is open at a
+    // time
+    let mut directories: Vec<PathBuf> = Vec::new();
+
+    let mut scan_result = scan_directory(
         stop,
         sftp_source,
         Path::new(&sftp_source.directory),
+        modified_after,
         sftp,
         conn,
         sender,
+        report,
+        &mut directories,
+    )?;
+
+    scan_result.incremental = modified_after.is_some();
+
+    let mut complete = true;
+
+    while !stop.load(Ordering::Relaxed) && !scan_result.dispatch_stopped {
+        let Some(directory) = directories.pop() else {
+            break;
+        };
+
+        let result = scan_directory(
+            stop,
+            sftp_source,
+            &directory,
+            modified_after,
+            sftp,
+            conn,
+            sender,
+            report,
+            &mut directories,
+        );
+
+        match result {
+            Ok(sr) => {
+                scan_result.add(sr, sftp_source.max_notify_paths());
+            }
+            Err(e) => {
+                if let DispatcherError::DisconnectedError(_) = e {
+                    return Err(e);
+                }
+
+                complete = false;
+            }
+        }
+    }
+
+    // Only a scan that saw all files moves the cursor, so that the files it
+    // missed are not skipped by the next scans
+    complete &= !stop.load(Ordering::Relaxed) && !scan_result.dispatch_stopped;
+
+    if sftp_source.incremental && complete && !sftp_source.dry_run {
+        let previous_mtime = cursor.as_ref().map(|cursor| cursor.max_mtime);
+
+        if let Some(max_mtime) = scan_result.max_mtime.max(previous_mtime) {
+            let incremental_scans = match (&cursor, scan_result.incremental) {
+                (Some(cursor), true) => cursor.incremental_scans + 1,
+                _ => 0,
+            };
+
+            save_scan_cursor(
+                conn,
+                &sftp_source.name,
+                &ScanCursor {
+                    max_mtime,
+                    incremental_scans,
+                },
+            )?;
+        }
+    }
+
+    Ok(scan_result)
+}
+
+/// Position of the incremental scans of a source
+#[derive(Debug, Clone, PartialEq)]
+struct ScanCursor {
+    /// Newest modification time of a matching file in the last completed
+    /// scan, in seconds since the epoch
+    max_mtime: u64,
+    /// Number of incremental scans since the last full scan
+    incremental_scans: u32,
+}
+
+fn load_scan_cursor(
+    conn: &Arc<Mutex<Connection>>,
+    source_name: &str,
+) -> Result<Option<ScanCursor>, DispatcherError> {
+    let conn = conn.lock().unwrap();
+
+    conn.query_row(
+        "select max_mtime, incremental_scans from scan_cursor where source = ?1",
+        params![source_name],
+        |row| {
+            Ok(ScanCursor {
+                max_mtime: row.get::<_, i64>(0)? as u64,
+                incremental_scans: row.get(1)?,
+            })
+        },
     )
+    .optional()
+    .map_err(|e| DispatcherError::DatabaseError(format!("Error querying scan cursor: {}", e)))
 }
 
-fn scan_directory(
+fn save_scan_cursor(
+    conn: &Arc<Mutex<Connection>>,
+    source_name: &str,
+    cursor: &ScanCursor,
+) -> Result<(), DispatcherError> {
+    let conn = conn.lock().unwrap();
+
+    conn.execute(
+        "insert into scan_cursor (source, max_mtime, incremental_scans) values (?1, ?2, ?3)
+         on conflict (source) do update set max_mtime = excluded.max_mtime,
+         incremental_scans = excluded.incremental_scans, modified = datetime('now')",
+        params![
+            source_name,
+            cursor.max_mtime as i64,
+            cursor.incremental_scans
+        ],
+    )
+    .map_err(|e| DispatcherError::DatabaseError(format!("Error saving scan cursor: {}", e)))?;
+
+    Ok(())
+}
+
+/// Scan the files of a directory, pushing its subdirectories onto
+/// `directories` when recursing
+///
+/// Matching files modified before `modified_after` are skipped without
+/// checking whether they were downloaded before.
+#[allow(clippy::too_many_arguments)]
+fn scan_directory<R: RemoteFs>(
     stop: &Arc<AtomicBool>,
     sftp_source: &SftpSource,
     directory: &Path,
-    sftp: &ssh2::Sftp,
+    modified_after: Option<u64>,
+    sftp: &R,
     conn: &Arc<Mutex<Connection>>,
     sender: &mut Sender<SftpDownload>,
+    report: &Report,
+    directories: &mut Vec<PathBuf>,
 ) -> Result<ScanResult, DispatcherError> {
-    debug!(
-        "Directory scan started for {}",
-        &directory.to_str().unwrap()
-    );
+    debug!("Directory scan started for {}", directory.to_string_lossy());
     let mut scan_result = ScanResult::new();
+    let first_subdirectory = directories.len();
 
-    let read_result = sftp.readdir(directory);
+    let mut dir = sftp.opendir(directory).map_err(read_error)?;
 
-    let paths = match read_result {
-        Ok(paths) => paths,
-        Err(e) => match e.code() {
-            ssh2::ErrorCode::Session(_) => {
-                return Err(DispatcherError::DisconnectedError(format!(
-                    "SFTP connection failed: {}",
-                    e
-                )))
-            }
-            _ => {
-                return Err(DispatcherError::FileError(format!(
-                    "Could not read directory: {}",
-                    e
-                )))
-            }
-        },
-    };
+    // Entries are read one at a time instead of listing the whole directory
+    // first, so that a stop does not wait for the listing of a large directory
+    while !stop.load(Ordering::Relaxed) && !scan_result.dispatch_stopped {
+        let (entry_name, stat) = match sftp.readdir(&mut dir).map_err(read_error)? {
+            Some(entry) => entry,
+            None => break,
+        };
 
-    for (path, stat) in paths {
-        if stop.load(Ordering::Relaxed) {
-            break;
+        if entry_name == Path::new(".") || entry_name == Path::new("..") {
+            continue;
         }
 
-        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let path = directory.join(&entry_name);
 
-        if stat.is_dir() && sftp_source.recurse {
-            let mut dir = PathBuf::from(directory);
-            dir.push(file_name);
-            let result = scan_directory(stop, sftp_source, &dir, sftp, conn, sender);
+        // Download commands and the database hold paths as strings, so names
+        // that are not valid UTF-8 cannot be downloaded
+        let Some(file_name) = entry_name.to_str() else {
+            warn!(
+                "Skipping '{}' of <{}>, its name is not valid UTF-8",
+                path.to_string_lossy(),
+                sftp_source.name
+            );
 
-            match result {
-                Ok(sr) => {
-                    scan_result.add(&sr);
-                }
-                Err(e) => {
-                    if let DispatcherError::DisconnectedError(_) = e {
-                        return Err(e);
-                    }
-                }
-            }
+            metrics::INVALID_FILENAME_COUNTER
+                .with_label_values(&[&sftp_source.name])
+                .inc();
+
+            continue;
+        };
+
+        if stat.is_dir() && sftp_source.recurse {
+            directories.push(path);
         } else {
             scan_result.encountered_files += 1;
 
-            let file_size: u64 = stat.size.unwrap();
+            let cast_result = stat.size.map(i64::try_from).transpose();
 
-            let cast_result = i64::try_from(file_size);
-
-            let file_size_db: i64 = match cast_result {
+            let file_size_db: Option<i64> = match cast_result {
                 Ok(size) => size,
                 Err(e) => {
                     error!(
@@ -285,17 +590,55 @@ fn scan_directory(
                 }
             };
 
-            let path_str = path.to_str().unwrap().to_string();
+            let path_str = path.to_string_lossy().to_string();
 
             if sftp_source.regex.is_match(file_name) {
                 scan_result.matching_files += 1;
                 debug!("'{}' - matches", path_str);
 
+                scan_result.max_mtime = scan_result.max_mtime.max(stat.mtime);
+
+                if stat
+                    .mtime
+                    .zip(modified_after)
+                    .is_some_and(|(mtime, after)| mtime < after)
+                {
+                    debug!("'{}' - skipped, older than the previous scan", path_str);
+
+                    scan_result.fast_skipped_files += 1;
+
+                    count_decision(&sftp_source.name, Decision::SkipOld);
+
+                    if sftp_source.dry_run {
+                        report.write(&sftp_source.name, &path_str, &stat, Decision::SkipOld);
+                    }
+
+                    continue;
+                }
+
+                if let Some(size_skip) = size_skip(sftp_source, stat.size) {
+                    debug!("'{}' - skipped, {}", path_str, size_skip.as_str());
+
+                    scan_result.count_skipped(size_skip);
+
+                    metrics::SCAN_SKIPPED_FILES_COUNTER
+                        .with_label_values(&[&sftp_source.name, size_skip.as_str()])
+                        .inc();
+
+                    count_decision(&sftp_source.name, Decision::Excluded);
+
+                    if sftp_source.dry_run {
+                        report.write(&sftp_source.name, &path_str, &stat, Decision::Excluded);
+                    }
+
+                    continue;
+                }
+
                 let file_requires_download = if sftp_source.deduplicate {
                     let conn = conn.lock().unwrap();
                     let mut stmt = conn
                         .prepare(
-                            "select count(*) from sftp_download where source = ?1 and path = ?2 and size = ?3",
+                            "select count(*) from sftp_download where source = ?1 and path = ?2 and size is ?3",
                         )
                         .map_err(|e| {
                             DispatcherError::DatabaseError(format!(
@@ -321,7 +664,31 @@ fn scan_directory(
                     true
                 };
 
-                if file_requires_download {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                let decision = if file_requires_download {
+                    Decision::Match
+                } else {
+                    Decision::SkipDuplicate
+                };
+
+                count_decision(&sftp_source.name, decision);
+
+                if sftp_source.dry_run {
+                    report.write(&sftp_source.name, &path_str, &stat, decision);
+                } else if file_requires_download {
+                    let created = Utc::now();
+
+                    let local_subpath = match local_subpath(sftp_source, &path, created) {
+                        Ok(local_subpath) => local_subpath,
+                        Err(e) => {
+                            error!("Not dispatching '{path_str}': {e}");
+                            continue;
+                        }
+                    };
+
                     let mut conn = conn.lock().unwrap();
                     let tx = conn.transaction().map_err(|e| {
                         DispatcherError::DatabaseError(format!("Error starting transaction: {}", e))
@@ -348,49 +715,510 @@ fn scan_directory(
                     })?;
 
                     let command = SftpDownload {
+                        version: COMMAND_VERSION,
                         id: sftp_download_id,
-                        created: Utc::now(),
+                        created,
                         size: stat.size,
                         sftp_source: sftp_source.name.clone(),
                         path: path_str.clone(),
                         remove: sftp_source.remove,
+                        local_subpath,
                     };
 
-                    let retry_policy = Fixed::from_millis(100);
-                    let send_timeout = time::Duration::from_millis(1000);
-
-                    let send_result = retry(retry_policy, || {
-                        let result = sender.send_timeout(command.clone(), send_timeout);
+                    match send_command(stop, sftp_source, sender, command) {
+                        Ok(()) => scan_result.dispatched(&path_str, sftp_source.max_notify_paths()),
+                        Err(e) => {
+                            if let SendError::GaveUp { attempts } = e {
+                                error!(
+                                    "Command channel stayed full for {} attempts, not dispatching the rest of the scan of {}",
+                                    attempts, sftp_source.name
+                                );
 
-                        match result {
-                            Ok(()) => {
-                                scan_result.dispatched_files += 1;
-                                debug!("Sent message {} on channel", command);
-                                OperationResult::Ok(())
+                                scan_result.dispatch_stopped = true;
+                            } else {
+                                error!("Error sending download message on channel: {e}");
                             }
-                            Err(e) => match e {
-                                SendTimeoutError::Timeout(timeout) => {
-                                    OperationResult::Retry(timeout)
-                                }
-                                SendTimeoutError::Disconnected(timeout) => {
-                                    OperationResult::Err(timeout)
-                                }
-                            },
-                        }
-                    });
 
-                    match send_result {
-                        Ok(_) => (),
-                        Err(e) => error!("Error sending download message on channel: {:?}", e),
+                            // The file is encountered again on the next scan
+                            conn.execute(
+                                "delete from sftp_download where id = ?1",
+                                params![sftp_download_id],
+                            )
+                            .map_err(|e| {
+                                DispatcherError::DatabaseError(format!(
+                                    "Error deleting record: {}",
+                                    e
+                                ))
+                            })?;
+                        }
                     }
                 } else {
                     debug!("{} already encountered {}", sftp_source.name, path_str);
                 }
             } else {
                 debug!(" - {} - no match", path_str);
+
+                count_decision(&sftp_source.name, Decision::Excluded);
+
+                if sftp_source.dry_run {
+                    report.write(&sftp_source.name, &path_str, &stat, Decision::Excluded);
+                }
             }
         }
     }
 
+    // Scanned in the order in which they were listed
+    directories[first_subdirectory..].reverse();
+
     Ok(scan_result)
 }
+
+/// Reason for skipping a matching file because of its size
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SizeSkip {
+    TooSmall,
+    TooLarge,
+    UnknownSize,
+}
+
+impl SizeSkip {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SizeSkip::TooSmall => "too_small",
+            SizeSkip::TooLarge => "too_large",
+            SizeSkip::UnknownSize => "unknown_size",
+        }
+    }
+}
+
+/// Reason for skipping a file of the size reported by the server, if any
+fn size_skip(sftp_source: &SftpSource, size: Option<u64>) -> Option<SizeSkip> {
+    match size {
+        None if sftp_source.reject_unknown_size => Some(SizeSkip::UnknownSize),
+        None => None,
+        Some(size) if sftp_source.min_size_bytes.is_some_and(|min| size < min) => {
+            Some(SizeSkip::TooSmall)
+        }
+        Some(size) if sftp_source.max_size_bytes.is_some_and(|max| size > max) => {
+            Some(SizeSkip::TooLarge)
+        }
+        Some(_) => None,
+    }
+}
+
+/// Local subpath of a file from the template of the source, if it has one
+fn local_subpath(
+    sftp_source: &SftpSource,
+    path: &Path,
+    time: DateTime<Utc>,
+) -> Result<Option<String>, String> {
+    let Some(template) = &sftp_source.local_subpath_template else {
+        return Ok(None);
+    };
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    let components: Vec<String> = directory
+        .strip_prefix(&sftp_source.directory)
+        .unwrap_or(directory)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+
+    let mut formatted = String::new();
+
+    fmt::write(&mut formatted, format_args!("{}", time.format(template)))
+        .map_err(|_| format!("Invalid local_subpath_template '{template}'"))?;
+
+    // The directory is filled in after the time, so that a % in its name is
+    // not taken for a strftime placeholder
+    let local_subpath = DIRECTORY_PLACEHOLDER
+        .replace_all(&formatted, |captures: &Captures| match &captures[1] {
+            "" => components.join("/"),
+            index => index
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| index.checked_sub(1))
+                .and_then(|index| components.get(index).cloned())
+                .unwrap_or_default(),
+        })
+        .to_string();
+
+    check_local_subpath(&local_subpath)?;
+
+    Ok(Some(local_subpath))
+}
+
+fn read_error(e: RemoteError) -> DispatcherError {
+    match e {
+        RemoteError::Disconnected(e) => {
+            DispatcherError::DisconnectedError(format!("SFTP connection failed: {}", e))
+        }
+        _ => DispatcherError::FileError(format!("Could not read directory: {}", e)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SendError {
+    Stopped,
+    Disconnected,
+    /// The channel stayed full for the maximum number of attempts
+    GaveUp {
+        attempts: u32,
+    },
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Stopped => write!(f, "scanner stopped"),
+            SendError::Disconnected => write!(f, "channel is disconnected"),
+            SendError::GaveUp { attempts } => {
+                write!(f, "channel is still full after {attempts} attempts")
+            }
+        }
+    }
+}
+
+/// Send a download command, waiting for room in the channel until the scanner
+/// is stopped or the maximum number of attempts of the source is reached
+fn send_command(
+    stop: &AtomicBool,
+    sftp_source: &SftpSource,
+    sender: &Sender<SftpDownload>,
+    command: SftpDownload,
+) -> Result<(), SendError> {
+    let send_start = time::Instant::now();
+    let mut attempts: u32 = 0;
+
+    let result = retry(Fixed::from_millis(sftp_source.send_retry_delay), || {
+        if stop.load(Ordering::Relaxed) {
+            return OperationResult::Err(SendError::Stopped);
+        }
+
+        attempts += 1;
+
+        match sender.send_timeout(command.clone(), SEND_TIMEOUT) {
+            Ok(()) => {
+                debug!("Sent message {} on channel", command);
+                OperationResult::Ok(())
+            }
+            Err(SendTimeoutError::Timeout(_)) => {
+                metrics::SEND_TIMEOUTS_COUNTER
+                    .with_label_values(&[&sftp_source.name])
+                    .inc();
+
+                if sftp_source
+                    .send_max_attempts
+                    .is_some_and(|max_attempts| attempts >= max_attempts)
+                {
+                    OperationResult::Err(SendError::GaveUp { attempts })
+                } else {
+                    OperationResult::Retry(SendError::GaveUp { attempts })
+                }
+            }
+            Err(SendTimeoutError::Disconnected(_)) => OperationResult::Err(SendError::Disconnected),
+        }
+    })
+    .map_err(|e| e.error);
+
+    metrics::SEND_DURATION_HISTOGRAM
+        .with_label_values(&[&sftp_source.name])
+        .observe(send_start.elapsed().as_secs_f64());
+    metrics::COMMAND_CHANNEL_GAUGE.set(sender.len() as i64);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cortex_core::remote_fs::MemoryFs;
+
+    #[test]
+    fn size_limits_skip_files() {
+        let mut sftp_source = crate::settings::Settings::default().sftp_sources[0].clone();
+        sftp_source.min_size_bytes = Some(1);
+        sftp_source.max_size_bytes = Some(1000);
+
+        assert_eq!(size_skip(&sftp_source, Some(0)), Some(SizeSkip::TooSmall));
+        assert_eq!(size_skip(&sftp_source, Some(1)), None);
+        assert_eq!(size_skip(&sftp_source, Some(1000)), None);
+        assert_eq!(
+            size_skip(&sftp_source, Some(1001)),
+            Some(SizeSkip::TooLarge)
+        );
+        assert_eq!(size_skip(&sftp_source, None), None);
+
+        sftp_source.reject_unknown_size = true;
+
+        assert_eq!(size_skip(&sftp_source, None), Some(SizeSkip::UnknownSize));
+    }
+
+    #[test]
+    fn local_subpath_from_template() {
+        let mut sftp_source = crate::settings::Settings::default().sftp_sources[0].clone();
+        let time = Utc.with_ymd_and_hms(2026, 10, 1, 12, 0, 0).unwrap();
+        let path = Path::new("upload/red/batch-7/zone/a.xml");
+
+        assert_eq!(local_subpath(&sftp_source, path, time), Ok(None));
+
+        sftp_source.local_subpath_template = Some("{dir1}/%Y%m%d/{dir}{dir3}".to_string());
+
+        assert_eq!(
+            local_subpath(&sftp_source, path, time),
+            Ok(Some("batch-7/20261001/batch-7/zone".to_string()))
+        );
+
+        sftp_source.local_subpath_template = Some("/{dir1}".to_string());
+
+        assert!(local_subpath(&sftp_source, path, time).is_err());
+    }
+
+    #[test]
+    fn scan_dispatches_new_matching_files() {
+        let mut sftp_source = crate::settings::Settings::default().sftp_sources[0].clone();
+        sftp_source.recurse = true;
+        sftp_source.deduplicate = true;
+
+        let fs = MemoryFs::default();
+        fs.add_file(Path::new("upload/red/a.xml"), b"a", 1);
+        fs.add_file(Path::new("upload/red/b.csv"), b"b", 1);
+        fs.add_file(Path::new("upload/red/sub/c.xml"), b"c", 1);
+        fs.add_file(Path::new("upload/red/sub/deeper/d.xml"), b"d", 1);
+        fs.add_file(Path::new("upload/red/tub/e.xml"), b"e", 1);
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+
+        let (mut sender, receiver) = crossbeam_channel::unbounded();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let report = Report::stdout();
+
+        let first = scan_source(&stop, &sftp_source, &fs, &conn, &mut sender, &report).unwrap();
+        let second = scan_source(&stop, &sftp_source, &fs, &conn, &mut sender, &report).unwrap();
+
+        let paths: Vec<String> = receiver.try_iter().map(|command| command.path).collect();
+
+        assert_eq!(first.encountered_files, 5);
+        assert_eq!(first.dispatched_files, 4);
+        assert_eq!(
+            paths,
+            vec![
+                "upload/red/a.xml",
+                "upload/red/sub/c.xml",
+                "upload/red/sub/deeper/d.xml",
+                "upload/red/tub/e.xml"
+            ]
+        );
+        // Files that were dispatched before are skipped
+        assert_eq!(second.dispatched_files, 0);
+
+        fs.fail_next(RemoteError::Disconnected("socket closed".to_string()));
+
+        let disconnected = scan_source(&stop, &sftp_source, &fs, &conn, &mut sender, &report);
+
+        assert!(matches!(
+            disconnected,
+            Err(DispatcherError::DisconnectedError(_))
+        ));
+    }
+
+    #[test]
+    fn incremental_scan_skips_files_older_than_the_previous_scan() {
+        let mut sftp_source = crate::settings::Settings::default().sftp_sources[0].clone();
+        sftp_source.deduplicate = true;
+        sftp_source.incremental = true;
+        sftp_source.incremental_margin = 100;
+        sftp_source.full_scan_every = 2;
+
+        let fs = MemoryFs::default();
+        fs.add_file(Path::new("upload/red/a.xml"), b"a", 10_000);
+        fs.add_file(Path::new("upload/red/b.xml"), b"b", 20_000);
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+
+        let (mut sender, receiver) = crossbeam_channel::unbounded();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let report = Report::stdout();
+
+        let first = scan_source(&stop, &sftp_source, &fs, &conn, &mut sender, &report).unwrap();
+
+        assert!(!first.incremental);
+        assert_eq!(first.dispatched_files, 2);
+
+        fs.add_file(Path::new("upload/red/c.xml"), b"c", 25_000);
+        // Added late with an old modification time
+        fs.add_file(Path::new("upload/red/d.xml"), b"d", 5_000);
+
+        let second = scan_source(&stop, &sftp_source, &fs, &conn, &mut sender, &report).unwrap();
+
+        assert!(second.incremental);
+        assert_eq!(second.fast_skipped_files, 2);
+        assert_eq!(second.dispatched_files, 1);
+
+        let third = scan_source(&stop, &sftp_source, &fs, &conn, &mut sender, &report).unwrap();
+
+        assert!(!third.incremental);
+        assert_eq!(third.fast_skipped_files, 0);
+
+        let paths: Vec<String> = receiver.try_iter().map(|command| command.path).collect();
+
+        assert_eq!(
+            paths,
+            vec![
+                "upload/red/a.xml",
+                "upload/red/b.xml",
+                "upload/red/c.xml",
+                "upload/red/d.xml"
+            ]
+        );
+        assert_eq!(
+            load_scan_cursor(&conn, &sftp_source.name).unwrap(),
+            Some(ScanCursor {
+                max_mtime: 25_000,
+                incremental_scans: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn full_channel_stops_dispatching_for_the_rest_of_the_scan() {
+        let mut sftp_source = crate::settings::Settings::default().sftp_sources[0].clone();
+        sftp_source.send_retry_delay = 10;
+        sftp_source.send_max_attempts = Some(2);
+
+        let fs = MemoryFs::default();
+        fs.add_file(Path::new("upload/red/a.xml"), b"a", 1);
+        fs.add_file(Path::new("upload/red/b.xml"), b"b", 1);
+        fs.add_file(Path::new("upload/red/c.xml"), b"c", 1);
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+
+        // Nobody receives, so the channel stays full
+        let (mut sender, _receiver) = crossbeam_channel::bounded(0);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let report = Report::stdout();
+
+        let scan_result =
+            scan_source(&stop, &sftp_source, &fs, &conn, &mut sender, &report).unwrap();
+
+        let registered: i64 = conn
+            .lock()
+            .unwrap()
+            .query_row("select count(*) from sftp_download", [], |row| row.get(0))
+            .unwrap();
+
+        assert!(scan_result.dispatch_stopped);
+        assert_eq!(scan_result.encountered_files, 1);
+        assert_eq!(scan_result.dispatched_files, 0);
+        // The file is encountered again on the next scan
+        assert_eq!(registered, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn names_that_are_not_utf8_are_skipped() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut sftp_source = crate::settings::Settings::default().sftp_sources[0].clone();
+        sftp_source.recurse = true;
+
+        // "é.xml" and "sé" in Latin-1
+        let fs = MemoryFs::default();
+        fs.add_file(
+            &Path::new("upload/red").join(OsStr::from_bytes(b"\xe9.xml")),
+            b"a",
+            1,
+        );
+        fs.add_file(
+            &Path::new("upload/red")
+                .join(OsStr::from_bytes(b"s\xe9"))
+                .join("b.xml"),
+            b"b",
+            1,
+        );
+        fs.add_file(Path::new("upload/red/c.xml"), b"c", 1);
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        cortex_core::run_migrations(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+
+        let (mut sender, receiver) = crossbeam_channel::unbounded();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let report = Report::stdout();
+
+        let skipped_before = metrics::INVALID_FILENAME_COUNTER
+            .with_label_values(&[&sftp_source.name])
+            .get();
+
+        let scan_result =
+            scan_source(&stop, &sftp_source, &fs, &conn, &mut sender, &report).unwrap();
+
+        let paths: Vec<String> = receiver.try_iter().map(|command| command.path).collect();
+
+        assert_eq!(paths, ["upload/red/c.xml"]);
+        assert_eq!(scan_result.encountered_files, 1);
+        assert_eq!(
+            metrics::INVALID_FILENAME_COUNTER
+                .with_label_values(&[&sftp_source.name])
+                .get()
+                - skipped_before,
+            2
+        );
+    }
+
+    #[test]
+    fn send_command_ends_on_stop() {
+        let (sender, _receiver) = crossbeam_channel::bounded(1);
+
+        let command = SftpDownload {
+            version: COMMAND_VERSION,
+            id: 1,
+            created: Utc::now(),
+            size: Some(4),
+            sftp_source: "red".to_string(),
+            path: "/upload/a.csv".to_string(),
+            remove: false,
+            local_subpath: None,
+        };
+
+        // Nobody receives, so the channel stays full after the first command
+        sender.send(command.clone()).unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let stopper = {
+            let stop = stop.clone();
+
+            thread::spawn(move || {
+                thread::sleep(time::Duration::from_millis(200));
+                stop.store(true, Ordering::Relaxed);
+            })
+        };
+
+        let sftp_source = crate::settings::Settings::default().sftp_sources[0].clone();
+
+        let start = time::Instant::now();
+        let result = send_command(&stop, &sftp_source, &sender, command);
+
+        stopper.join().unwrap();
+
+        assert_eq!(result, Err(SendError::Stopped));
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+    }
+}
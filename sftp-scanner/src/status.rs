@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::sftp_scanner::ScanResult;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+/// State of the scanner of a source, as reported by /status
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SourceStatus {
+    pub connection: ConnectionState,
+    /// Whether the server accepted compression of the current connection
+    pub compression: bool,
+    pub last_scan_start: Option<DateTime<Utc>>,
+    pub last_scan_end: Option<DateTime<Utc>>,
+    pub last_scan_result: Option<ScanResult>,
+    pub last_error: Option<String>,
+}
+
+/// Status of the scanners per source, updated by the scanner threads at scan
+/// boundaries
+pub type ScannerStatus = Arc<RwLock<HashMap<String, SourceStatus>>>;
+
+/// Change the status of a source
+pub fn update<F: FnOnce(&mut SourceStatus)>(status: &ScannerStatus, source_name: &str, f: F) {
+    f(status
+        .write()
+        .unwrap()
+        .entry(source_name.to_string())
+        .or_default());
+}